@@ -68,7 +68,11 @@ fn main() {
         "x86_64" => {
             println!("cargo:rerun-if-changed=src/asm/x86_64/trampoline.asm");
 
-            let status = Command::new("nasm")
+            let mut nasm = Command::new("nasm");
+            if env::var("CARGO_FEATURE_LA57").is_ok() {
+                nasm.arg("-DLA57");
+            }
+            let status = nasm
                 .arg("-f")
                 .arg("bin")
                 .arg("-o")
@@ -0,0 +1,203 @@
+use core::{mem, ptr};
+
+use spin::Once;
+
+use crate::memory::KernelMapper;
+
+use super::{find_sdt, get_sdt, sdt::Sdt, GenericAddressStructure};
+
+/// The Fixed ACPI Description Table following the SDT header, up to X_DSDT. Tables of older
+/// revisions are shorter, in which case the missing fields read as zero.
+#[derive(Clone, Copy, Debug, Default)]
+#[repr(C, packed)]
+pub struct FadtData {
+    pub firmware_ctrl: u32,
+    pub dsdt: u32,
+    _reserved: u8,
+    pub preferred_pm_profile: u8,
+    pub sci_interrupt: u16,
+    pub smi_command_port: u32,
+    pub acpi_enable: u8,
+    pub acpi_disable: u8,
+    pub s4bios_req: u8,
+    pub pstate_control: u8,
+    pub pm1a_event_block: u32,
+    pub pm1b_event_block: u32,
+    pub pm1a_control_block: u32,
+    pub pm1b_control_block: u32,
+    pub pm2_control_block: u32,
+    pub pm_timer_block: u32,
+    pub gpe0_block: u32,
+    pub gpe1_block: u32,
+    pub pm1_event_length: u8,
+    pub pm1_control_length: u8,
+    pub pm2_control_length: u8,
+    pub pm_timer_length: u8,
+    pub gpe0_length: u8,
+    pub gpe1_length: u8,
+    pub gpe1_base: u8,
+    pub c_state_control: u8,
+    pub worst_c2_latency: u16,
+    pub worst_c3_latency: u16,
+    pub flush_size: u16,
+    pub flush_stride: u16,
+    pub duty_offset: u8,
+    pub duty_width: u8,
+    pub day_alarm: u8,
+    pub month_alarm: u8,
+    pub century: u8,
+    pub boot_architecture_flags: u16,
+    _reserved2: u8,
+    pub flags: u32,
+    pub reset_reg: GenericAddressStructure,
+    pub reset_value: u8,
+    pub arm_boot_architecture_flags: u16,
+    pub minor_version: u8,
+    pub x_firmware_control: u64,
+    pub x_dsdt: u64,
+}
+
+/// The reset register is supported.
+const FLAG_RESET_REG_SUP: u32 = 1 << 10;
+/// GenericAddressStructure address space of port I/O.
+const ADDRESS_SPACE_IO: u8 = 1;
+
+/// The Firmware ACPI Control Structure, up to the fields the kernel uses.
+#[derive(Clone, Copy, Debug)]
+#[repr(C, packed)]
+pub struct Facs {
+    pub signature: [u8; 4],
+    pub length: u32,
+    pub hardware_signature: u32,
+    /// Real mode address the firmware jumps to when waking up from a sleep state.
+    pub firmware_waking_vector: u32,
+    pub global_lock: u32,
+    pub flags: u32,
+    /// Used instead of `firmware_waking_vector` if not zero.
+    pub x_firmware_waking_vector: u64,
+}
+
+/// Power management information from the FADT and DSDT, needed to reset, power off and suspend
+/// without a userspace ACPI driver.
+#[derive(Clone, Copy, Debug)]
+pub struct Fadt {
+    /// Port and value of the reset register.
+    pub reset: Option<(u16, u8)>,
+    pub pm1a_event: u16,
+    pub pm1b_event: u16,
+    pub pm1a_control: u16,
+    pub pm1b_control: u16,
+    /// SLP_TYPa and SLP_TYPb values of the S3 (suspend to RAM) state, from the `\_S3_` object.
+    pub s3_sleep_types: Option<(u8, u8)>,
+    /// SLP_TYPa and SLP_TYPb values of the S5 (soft off) state, from the `\_S5_` object.
+    pub s5_sleep_types: Option<(u8, u8)>,
+    /// Kernel address of the FACS.
+    pub facs: Option<usize>,
+    pub smi_command_port: u16,
+    pub acpi_enable: u8,
+}
+
+pub static FADT: Once<Fadt> = Once::new();
+
+impl Fadt {
+    pub fn init() {
+        let fadt_sdt = find_sdt("FACP");
+        if fadt_sdt.len() != 1 {
+            println!("Unable to find FADT");
+            return;
+        }
+        let fadt = Fadt::new(fadt_sdt[0]);
+        println!("  FADT: {:?}", fadt);
+        FADT.call_once(|| fadt);
+    }
+
+    fn new(sdt: &'static Sdt) -> Fadt {
+        let mut data = FadtData::default();
+        unsafe {
+            ptr::copy_nonoverlapping(
+                sdt.data_address() as *const u8,
+                ptr::addr_of_mut!(data).cast::<u8>(),
+                sdt.data_len().min(mem::size_of::<FadtData>()),
+            );
+        }
+
+        let reset_reg = data.reset_reg;
+        let reset = (data.flags & FLAG_RESET_REG_SUP != 0
+            && reset_reg.address_space == ADDRESS_SPACE_IO)
+            .then(|| (reset_reg.address as u16, data.reset_value));
+
+        let dsdt_address = match data.x_dsdt {
+            0 => data.dsdt as usize,
+            x_dsdt => x_dsdt as usize,
+        };
+        let dsdt = (dsdt_address != 0).then(|| get_sdt(dsdt_address, &mut KernelMapper::lock()));
+        let s3_sleep_types = dsdt.and_then(|dsdt| find_sleep_types(dsdt, b"_S3_"));
+        let s5_sleep_types = dsdt.and_then(|dsdt| find_sleep_types(dsdt, b"_S5_"));
+
+        // The FACS starts like an SDT, with its signature and length.
+        let facs_address = match data.x_firmware_control {
+            0 => data.firmware_ctrl as usize,
+            x_firmware_control => x_firmware_control as usize,
+        };
+        let facs = (facs_address != 0)
+            .then(|| get_sdt(facs_address, &mut KernelMapper::lock()))
+            .filter(|facs| &facs.signature == b"FACS")
+            .map(|facs| facs as *const Sdt as usize);
+
+        Fadt {
+            reset,
+            pm1a_event: data.pm1a_event_block as u16,
+            pm1b_event: data.pm1b_event_block as u16,
+            pm1a_control: data.pm1a_control_block as u16,
+            pm1b_control: data.pm1b_control_block as u16,
+            s3_sleep_types,
+            s5_sleep_types,
+            facs,
+            smi_command_port: data.smi_command_port as u16,
+            acpi_enable: data.acpi_enable,
+        }
+    }
+}
+
+/// Find the sleep types of the sleep state object `name`, such as `_S5_`, in the DSDT, without an
+/// AML interpreter. This relies on the object being a plain package of constants, which it is in
+/// practice.
+fn find_sleep_types(dsdt: &'static Sdt, name: &[u8; 4]) -> Option<(u8, u8)> {
+    const NAME_OP: u8 = 0x08;
+    const PACKAGE_OP: u8 = 0x12;
+    const BYTE_PREFIX: u8 = 0x0A;
+
+    let aml =
+        unsafe { core::slice::from_raw_parts(dsdt.data_address() as *const u8, dsdt.data_len()) };
+    let name_pos = aml.windows(4).position(|window| window == name)?;
+
+    let is_name = match name_pos {
+        0 => false,
+        1 => aml[0] == NAME_OP,
+        _ => aml[name_pos - 1] == NAME_OP || aml[name_pos - 2..name_pos] == [NAME_OP, b'\\'],
+    };
+    if !is_name {
+        return None;
+    }
+
+    let mut bytes = aml[name_pos + 4..].iter().copied();
+    if bytes.next()? != PACKAGE_OP {
+        return None;
+    }
+    // PkgLength, where the top two bits of the lead byte count the bytes that follow.
+    let pkg_length_extra = bytes.next()? >> 6;
+    for _ in 0..pkg_length_extra {
+        bytes.next()?;
+    }
+    let _num_elements = bytes.next()?;
+
+    let mut next_value = || match bytes.next()? {
+        BYTE_PREFIX => bytes.next(),
+        // ZeroOp and OneOp encode their value directly.
+        value @ (0 | 1) => Some(value),
+        _ => None,
+    };
+    let slp_typ_a = next_value()?;
+    let slp_typ_b = next_value()?;
+    Some((slp_typ_a, slp_typ_b))
+}
@@ -1,11 +1,7 @@
-use alloc::boxed::Box;
 use core::mem;
 
 use super::{find_sdt, sdt::Sdt};
-use crate::{
-    device::generic_timer::GenericTimer,
-    dtb::irqchip::{register_irq, IRQ_CHIP},
-};
+use crate::device::generic_timer::{self, GenericTimer};
 
 #[derive(Clone, Copy, Debug)]
 #[repr(C, packed)]
@@ -47,15 +43,11 @@ impl Gtdt {
             return;
         };
 
-        let gsiv = gtdt.non_secure_el1_timer_gsiv;
+        let gsiv = gtdt.virtual_el1_timer_gsiv;
         log::info!("generic_timer gsiv = {}", gsiv);
-        let mut timer = GenericTimer {
-            clk_freq: 0,
-            reload_count: 0,
-        };
+        let mut timer = GenericTimer { clk_freq: 0 };
         timer.init();
-        register_irq(gsiv, Box::new(timer));
-        unsafe { IRQ_CHIP.irq_enable(gsiv as u32) };
+        unsafe { generic_timer::register(gsiv, timer) };
     }
 
     pub fn new(sdt: &'static Sdt) -> Option<&'static Gtdt> {
@@ -54,6 +54,7 @@ pub(super) fn init(madt: Madt) {
                     gic_dist_if,
                     gic_cpu_if,
                     irq_range: (0, 0),
+                    saved: Vec::new(),
                 };
                 let chip = IrqChipItem {
                     phandle: 0,
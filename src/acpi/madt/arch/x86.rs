@@ -1,160 +1,223 @@
 use core::sync::atomic::{AtomicU8, Ordering};
 
 use crate::{
-    device::local_apic::the_local_apic,
+    cpu_set::{LogicalCpuId, MAX_CPU_COUNT},
+    device::local_apic::{self, the_local_apic, LocalApic},
     interrupt,
     memory::{allocate_p2frame, Frame, KernelMapper},
+    numa,
     paging::{Page, PageFlags, PhysicalAddress, RmmA, RmmArch, VirtualAddress, PAGE_SIZE},
     start::{kstart_ap, AP_READY, CPU_COUNT},
 };
 
 use super::{Madt, MadtEntry};
 
-const TRAMPOLINE: usize = 0x8000;
+/// Physical address of the trampoline, which starts in real mode.
+pub const TRAMPOLINE: usize = 0x8000;
 static TRAMPOLINE_DATA: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/trampoline"));
 
 pub(super) fn init(madt: Madt) {
     let local_apic = unsafe { the_local_apic() };
-    let me = local_apic.id() as u8;
+    let me = local_apic.id();
 
     if local_apic.x2 {
         println!("    X2APIC {}", me);
     } else {
         println!("    XAPIC {}: {:>08X}", me, local_apic.address);
     }
+    local_apic::set_apic_id(LogicalCpuId::BSP, me);
+    numa::set_cpu_node(LogicalCpuId::BSP, numa::hw_cpu_node(me));
 
     if cfg!(feature = "multi_core") {
-        // Map trampoline
-        let trampoline_frame = Frame::containing(PhysicalAddress::new(TRAMPOLINE));
-        let trampoline_page = Page::containing_address(VirtualAddress::new(TRAMPOLINE));
-        let (result, page_table_physaddr) = unsafe {
-            //TODO: do not have writable and executable!
-            let mut mapper = KernelMapper::lock();
-
-            let result = mapper
-                .get_mut()
-                .expect("expected kernel page table not to be recursively locked while initializing MADT")
-                .map_phys(trampoline_page.start_address(), trampoline_frame.base(), PageFlags::new().execute(true).write(true))
-                .expect("failed to map trampoline");
-
-            (result, mapper.table().phys().data())
-        };
-        result.flush();
-
-        // Write trampoline, make sure TRAMPOLINE page is free for use
-        for i in 0..TRAMPOLINE_DATA.len() {
-            unsafe {
-                (*((TRAMPOLINE as *mut u8).add(i) as *const AtomicU8))
-                    .store(TRAMPOLINE_DATA[i], Ordering::SeqCst);
-            }
-        }
+        let page_table_physaddr = unsafe { install_trampoline() };
 
         for madt_entry in madt.iter() {
             println!("      {:#x?}", madt_entry);
-            match madt_entry {
+            // Processors with APIC IDs of 255 and above are only described by x2APIC entries.
+            let (apic_id, flags) = match madt_entry {
                 MadtEntry::LocalApic(ap_local_apic) => {
-                    if ap_local_apic.id == me {
-                        println!("        This is my local APIC");
-                    } else {
-                        if ap_local_apic.flags & 1 == 1 {
-                            // Increase CPU ID
-                            CPU_COUNT.fetch_add(1, Ordering::SeqCst);
-
-                            // Allocate a stack
-                            let stack_start = allocate_p2frame(4)
-                                .expect("no more frames in acpi stack_start")
-                                .base()
-                                .data()
-                                + crate::PHYS_OFFSET;
-                            let stack_end = stack_start + (PAGE_SIZE << 4);
-
-                            let ap_ready = (TRAMPOLINE + 8) as *mut u64;
-                            let ap_cpu_id = unsafe { ap_ready.add(1) };
-                            let ap_page_table = unsafe { ap_ready.add(2) };
-                            let ap_stack_start = unsafe { ap_ready.add(3) };
-                            let ap_stack_end = unsafe { ap_ready.add(4) };
-                            let ap_code = unsafe { ap_ready.add(5) };
-
-                            // Set the ap_ready to 0, volatile
-                            unsafe {
-                                ap_ready.write(0);
-                                ap_cpu_id.write(ap_local_apic.processor.into());
-                                ap_page_table.write(page_table_physaddr as u64);
-                                ap_stack_start.write(stack_start as u64);
-                                ap_stack_end.write(stack_end as u64);
-                                ap_code.write(kstart_ap as u64);
-
-                                // TODO: Is this necessary (this fence)?
-                                core::arch::asm!("");
-                            };
-                            AP_READY.store(false, Ordering::SeqCst);
-
-                            print!(
-                                "        AP {} APIC {}:",
-                                ap_local_apic.processor, ap_local_apic.id
-                            );
-
-                            // Send INIT IPI
-                            {
-                                let mut icr = 0x4500;
-                                if local_apic.x2 {
-                                    icr |= (ap_local_apic.id as u64) << 32;
-                                } else {
-                                    icr |= (ap_local_apic.id as u64) << 56;
-                                }
-                                print!(" IPI...");
-                                local_apic.set_icr(icr);
-                            }
-
-                            // Send START IPI
-                            {
-                                //Start at 0x0800:0000 => 0x8000. Hopefully the bootloader code is still there
-                                let ap_segment = (TRAMPOLINE >> 12) & 0xFF;
-                                let mut icr = 0x4600 | ap_segment as u64;
-
-                                if local_apic.x2 {
-                                    icr |= (ap_local_apic.id as u64) << 32;
-                                } else {
-                                    icr |= (ap_local_apic.id as u64) << 56;
-                                }
-
-                                print!(" SIPI...");
-                                local_apic.set_icr(icr);
-                            }
-
-                            // Wait for trampoline ready
-                            print!(" Wait...");
-                            while unsafe { (*ap_ready.cast::<AtomicU8>()).load(Ordering::SeqCst) }
-                                == 0
-                            {
-                                interrupt::pause();
-                            }
-                            print!(" Trampoline...");
-                            while !AP_READY.load(Ordering::SeqCst) {
-                                interrupt::pause();
-                            }
-                            println!(" Ready");
-
-                            unsafe {
-                                RmmA::invalidate_all();
-                            }
-                        } else {
-                            println!("        CPU Disabled");
-                        }
-                    }
+                    (u32::from(ap_local_apic.id), ap_local_apic.flags)
+                }
+                MadtEntry::LocalX2Apic(ap_local_x2apic) => {
+                    (ap_local_x2apic.x2apic_id, ap_local_x2apic.flags)
                 }
-                _ => (),
+                _ => continue,
+            };
+
+            if apic_id == me {
+                println!("        This is my local APIC");
+                continue;
+            }
+            if flags & 1 != 1 {
+                println!("        CPU Disabled");
+                continue;
+            }
+            if !local_apic.x2 && apic_id > 0xFE {
+                println!("        Unreachable without x2APIC");
+                continue;
+            }
+            let cpu_count = CPU_COUNT.load(Ordering::SeqCst);
+            if (0..cpu_count).any(|id| local_apic::apic_id(LogicalCpuId::new(id)) == apic_id) {
+                println!("        Already started");
+                continue;
+            }
+            if cpu_count >= MAX_CPU_COUNT {
+                println!("        More than {} CPUs", MAX_CPU_COUNT);
+                continue;
+            }
+
+            unsafe {
+                start_ap(
+                    local_apic,
+                    LogicalCpuId::new(cpu_count),
+                    apic_id,
+                    page_table_physaddr,
+                );
             }
         }
 
-        // Unmap trampoline
-        let (_frame, _, flush) = unsafe {
-            KernelMapper::lock()
-                .get_mut()
-                .expect("expected kernel page table not to be recursively locked while initializing MADT")
-                .unmap_phys(trampoline_page.start_address(), true)
-                .expect("failed to unmap trampoline page")
-        };
-        flush.flush();
+        unsafe { remove_trampoline() };
+    }
+}
+
+/// Identity map the trampoline page and copy the trampoline there, returning the physical address
+/// of the kernel page table, for the trampoline to load.
+pub unsafe fn install_trampoline() -> usize {
+    let trampoline_frame = Frame::containing(PhysicalAddress::new(TRAMPOLINE));
+    let trampoline_page = Page::containing_address(VirtualAddress::new(TRAMPOLINE));
+    let (result, page_table_physaddr) = {
+        //TODO: do not have writable and executable!
+        let mut mapper = KernelMapper::lock();
+
+        let result = mapper
+            .get_mut()
+            .expect(
+                "expected kernel page table not to be recursively locked while mapping trampoline",
+            )
+            .map_phys(
+                trampoline_page.start_address(),
+                trampoline_frame.base(),
+                PageFlags::new().execute(true).write(true),
+            )
+            .expect("failed to map trampoline");
+
+        (result, mapper.table().phys().data())
+    };
+    result.flush();
+
+    // Write trampoline, make sure TRAMPOLINE page is free for use
+    for i in 0..TRAMPOLINE_DATA.len() {
+        (*((TRAMPOLINE as *mut u8).add(i) as *const AtomicU8))
+            .store(TRAMPOLINE_DATA[i], Ordering::SeqCst);
+    }
+
+    page_table_physaddr
+}
+
+pub unsafe fn remove_trampoline() {
+    let trampoline_page = Page::containing_address(VirtualAddress::new(TRAMPOLINE));
+    let (_frame, _, flush) = KernelMapper::lock()
+        .get_mut()
+        .expect(
+            "expected kernel page table not to be recursively locked while unmapping trampoline",
+        )
+        .unmap_phys(trampoline_page.start_address(), true)
+        .expect("failed to unmap trampoline page");
+    flush.flush();
+}
+
+/// Fill in the arguments of the trampoline, which enters protected or long mode with the page
+/// table at `page_table_physaddr`, and jumps to `code` on the stack ending at `stack_end`, with
+/// the address of its copy of `arg` in the first argument register.
+pub unsafe fn set_trampoline_args(
+    arg: u64,
+    page_table_physaddr: usize,
+    stack_start: usize,
+    stack_end: usize,
+    code: usize,
+) {
+    let ap_ready = (TRAMPOLINE + 8) as *mut u64;
+    let ap_cpu_id = ap_ready.add(1);
+    let ap_page_table = ap_ready.add(2);
+    let ap_stack_start = ap_ready.add(3);
+    let ap_stack_end = ap_ready.add(4);
+    let ap_code = ap_ready.add(5);
+
+    // Set the ap_ready to 0, volatile
+    ap_ready.write(0);
+    ap_cpu_id.write(arg);
+    ap_page_table.write(page_table_physaddr as u64);
+    ap_stack_start.write(stack_start as u64);
+    ap_stack_end.write(stack_end as u64);
+    ap_code.write(code as u64);
+
+    // TODO: Is this necessary (this fence)?
+    core::arch::asm!("");
+}
+
+/// Send the INIT and START IPIs starting the trampoline to the CPU with the given APIC ID, and
+/// wait until it left the trampoline.
+pub unsafe fn start_trampoline(local_apic: &mut LocalApic, apic_id: u32) {
+    let ap_ready = (TRAMPOLINE + 8) as *mut u64;
+
+    // Send INIT IPI
+    {
+        let icr = 0x4500 | local_apic.icr_destination(apic_id);
+        local_apic.set_icr(icr);
     }
+
+    // Send START IPI
+    {
+        //Start at 0x0800:0000 => 0x8000. Hopefully the bootloader code is still there
+        let ap_segment = (TRAMPOLINE >> 12) & 0xFF;
+        let icr = 0x4600 | ap_segment as u64 | local_apic.icr_destination(apic_id);
+        local_apic.set_icr(icr);
+    }
+
+    // Wait for trampoline ready
+    while (*ap_ready.cast::<AtomicU8>()).load(Ordering::SeqCst) == 0 {
+        interrupt::pause();
+    }
+}
+
+/// Start the AP with the given APIC ID through the trampoline, as logical CPU `cpu_id`, and wait
+/// until it is ready.
+unsafe fn start_ap(
+    local_apic: &mut LocalApic,
+    cpu_id: LogicalCpuId,
+    apic_id: u32,
+    page_table_physaddr: usize,
+) {
+    // Increase CPU ID
+    CPU_COUNT.fetch_add(1, Ordering::SeqCst);
+
+    local_apic::set_apic_id(cpu_id, apic_id);
+    numa::set_cpu_node(cpu_id, numa::hw_cpu_node(apic_id));
+
+    // Allocate a stack
+    let stack_start = allocate_p2frame(4)
+        .expect("no more frames in acpi stack_start")
+        .base()
+        .data()
+        + crate::PHYS_OFFSET;
+    let stack_end = stack_start + (PAGE_SIZE << 4);
+
+    set_trampoline_args(
+        cpu_id.get().into(),
+        page_table_physaddr,
+        stack_start,
+        stack_end,
+        kstart_ap as usize,
+    );
+    AP_READY.store(false, Ordering::SeqCst);
+
+    print!("        AP {} APIC {}: IPI, SIPI...", cpu_id.get(), apic_id);
+    start_trampoline(local_apic, apic_id);
+    print!(" Trampoline...");
+    while !AP_READY.load(Ordering::SeqCst) {
+        interrupt::pause();
+    }
+    println!(" Ready");
+
+    RmmA::invalidate_all();
 }
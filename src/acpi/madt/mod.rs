@@ -16,7 +16,7 @@ mod arch;
 
 #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
 #[path = "arch/x86.rs"]
-mod arch;
+pub mod arch;
 
 #[cfg(not(any(target_arch = "aarch64", target_arch = "x86", target_arch = "x86_64")))]
 #[path = "arch/other.rs"]
@@ -85,6 +85,19 @@ pub struct MadtLocalApic {
     pub flags: u32,
 }
 
+/// MADT Local x2APIC, used for processors with APIC IDs that do not fit in [`MadtLocalApic`]
+#[derive(Clone, Copy, Debug)]
+#[repr(C, packed)]
+pub struct MadtLocalX2Apic {
+    _reserved: u16,
+    /// Local x2APIC ID
+    pub x2apic_id: u32,
+    /// Flags. 1 means that the processor is enabled
+    pub flags: u32,
+    /// ACPI processor UID
+    pub processor_uid: u32,
+}
+
 /// MADT I/O APIC
 #[derive(Clone, Copy, Debug)]
 #[repr(C, packed)]
@@ -157,6 +170,8 @@ pub enum MadtEntry {
     InvalidIoApic(usize),
     IntSrcOverride(&'static MadtIntSrcOverride),
     InvalidIntSrcOverride(usize),
+    LocalX2Apic(&'static MadtLocalX2Apic),
+    InvalidLocalX2Apic(usize),
     Gicc(&'static MadtGicc),
     InvalidGicc(usize),
     Gicd(&'static MadtGicd),
@@ -207,6 +222,15 @@ impl Iterator for MadtIter {
                             MadtEntry::InvalidIntSrcOverride(entry_len)
                         }
                     }
+                    0x9 => {
+                        if entry_len == mem::size_of::<MadtLocalX2Apic>() + 2 {
+                            MadtEntry::LocalX2Apic(unsafe {
+                                &*((self.sdt.data_address() + self.i + 2) as *const MadtLocalX2Apic)
+                            })
+                        } else {
+                            MadtEntry::InvalidLocalX2Apic(entry_len)
+                        }
+                    }
                     0xB => {
                         if entry_len >= mem::size_of::<MadtGicc>() + 2 {
                             MadtEntry::Gicc(unsafe {
@@ -4,17 +4,20 @@
 use alloc::{boxed::Box, string::String, vec::Vec};
 
 use hashbrown::HashMap;
-use spin::{Once, RwLock};
+use spin::Once;
 
 use log::info;
 
 use crate::{
     memory::KernelMapper,
     paging::{PageFlags, PhysicalAddress, RmmA, RmmArch},
+    sync::RwLock,
 };
 
 use self::{hpet::Hpet, madt::Madt, rsdp::RSDP, rsdt::Rsdt, rxsdt::Rxsdt, sdt::Sdt, xsdt::Xsdt};
 
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub mod fadt;
 #[cfg(target_arch = "aarch64")]
 mod gtdt;
 pub mod hpet;
@@ -25,6 +28,7 @@ mod rxsdt;
 pub mod sdt;
 #[cfg(target_arch = "aarch64")]
 mod spcr;
+mod srat;
 mod xsdt;
 
 unsafe fn map_linearly(addr: PhysicalAddress, len: usize, mapper: &mut crate::paging::PageMapper) {
@@ -160,12 +164,16 @@ pub unsafe fn init(already_supplied_rsdp: Option<*const u8>) {
         //TODO: support this on any arch
         #[cfg(target_arch = "aarch64")]
         spcr::Spcr::init();
+        // The NUMA topology must be known before the APs are started.
+        srat::Srat::init();
         // TODO: Enumerate processors in userspace, and then provide an ACPI-independent interface
         // to initialize enumerated processors to userspace?
         Madt::init();
         // TODO: Let userspace setup HPET, and then provide an interface to specify which timer to
         // use?
         Hpet::init();
+        #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+        fadt::Fadt::init();
         #[cfg(target_arch = "aarch64")]
         gtdt::Gtdt::init();
     } else {
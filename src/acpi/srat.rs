@@ -0,0 +1,211 @@
+use alloc::vec::Vec;
+use core::{mem, ptr};
+
+use crate::{
+    numa::{self, CpuAffinity, MemoryAffinity, NodeId, MAX_NODE_COUNT},
+    paging::PhysicalAddress,
+};
+
+use super::{find_sdt, sdt::Sdt};
+
+/// The System Resource Affinity Table
+#[derive(Clone, Copy, Debug)]
+pub struct Srat {
+    sdt: &'static Sdt,
+}
+
+/// SRAT Processor Local APIC/SAPIC Affinity
+#[derive(Clone, Copy, Debug)]
+#[repr(C, packed)]
+pub struct SratLocalApic {
+    pub proximity_domain_lo: u8,
+    pub apic_id: u8,
+    pub flags: u32,
+    pub sapic_eid: u8,
+    pub proximity_domain_hi: [u8; 3],
+    pub clock_domain: u32,
+}
+
+/// SRAT Memory Affinity
+#[derive(Clone, Copy, Debug)]
+#[repr(C, packed)]
+pub struct SratMemory {
+    pub proximity_domain: u32,
+    _reserved: u16,
+    pub base: u64,
+    pub length: u64,
+    _reserved2: u32,
+    pub flags: u32,
+    _reserved3: u64,
+}
+
+/// SRAT Processor Local x2APIC Affinity
+#[derive(Clone, Copy, Debug)]
+#[repr(C, packed)]
+pub struct SratX2Apic {
+    _reserved: u16,
+    pub proximity_domain: u32,
+    pub x2apic_id: u32,
+    pub flags: u32,
+    pub clock_domain: u32,
+    _reserved2: u32,
+}
+
+/// SRAT GICC Affinity
+#[derive(Clone, Copy, Debug)]
+#[repr(C, packed)]
+pub struct SratGicc {
+    pub proximity_domain: u32,
+    pub acpi_processor_uid: u32,
+    pub flags: u32,
+    pub clock_domain: u32,
+}
+
+/// Flag bit 0 of all entries; disabled entries are to be ignored.
+const SRAT_ENABLED: u32 = 1;
+
+#[derive(Debug)]
+pub enum SratEntry {
+    LocalApic(SratLocalApic),
+    Memory(SratMemory),
+    X2Apic(SratX2Apic),
+    Gicc(SratGicc),
+    Unknown(u8),
+}
+
+impl Srat {
+    pub fn init() {
+        let srat_sdt = find_sdt("SRAT");
+        let srat = if srat_sdt.len() == 1 {
+            Srat::new(srat_sdt[0])
+        } else {
+            return;
+        };
+        let Some(srat) = srat else {
+            return;
+        };
+
+        // Proximity domains are arbitrary 32-bit numbers, so assign node IDs in order of
+        // appearance.
+        let mut domains = Vec::new();
+        let mut node_of = |domain: u32| -> Option<NodeId> {
+            let idx = match domains.iter().position(|d| *d == domain) {
+                Some(idx) => idx,
+                None if domains.len() < MAX_NODE_COUNT => {
+                    domains.push(domain);
+                    domains.len() - 1
+                }
+                None => {
+                    log::warn!("SRAT: ignoring proximity domain {}", domain);
+                    return None;
+                }
+            };
+            Some(NodeId::new(idx as u8))
+        };
+
+        let mut memory = Vec::new();
+        let mut cpus = Vec::new();
+
+        for entry in srat.iter() {
+            match entry {
+                SratEntry::LocalApic(lapic) if lapic.flags & SRAT_ENABLED != 0 => {
+                    let [hi0, hi1, hi2] = lapic.proximity_domain_hi;
+                    let domain = u32::from_le_bytes([lapic.proximity_domain_lo, hi0, hi1, hi2]);
+                    if let Some(node) = node_of(domain) {
+                        cpus.push(CpuAffinity {
+                            hw_id: lapic.apic_id.into(),
+                            node,
+                        });
+                    }
+                }
+                SratEntry::X2Apic(x2apic) if x2apic.flags & SRAT_ENABLED != 0 => {
+                    if let Some(node) = node_of(x2apic.proximity_domain) {
+                        cpus.push(CpuAffinity {
+                            hw_id: x2apic.x2apic_id,
+                            node,
+                        });
+                    }
+                }
+                SratEntry::Gicc(gicc) if gicc.flags & SRAT_ENABLED != 0 => {
+                    if let Some(node) = node_of(gicc.proximity_domain) {
+                        cpus.push(CpuAffinity {
+                            hw_id: gicc.acpi_processor_uid,
+                            node,
+                        });
+                    }
+                }
+                SratEntry::Memory(mem) if mem.flags & SRAT_ENABLED != 0 && mem.length != 0 => {
+                    if let Some(node) = node_of(mem.proximity_domain) {
+                        memory.push(MemoryAffinity {
+                            base: PhysicalAddress::new(mem.base as usize),
+                            size: mem.length as usize,
+                            node,
+                        });
+                    }
+                }
+                _ => (),
+            }
+        }
+
+        if domains.len() > 1 {
+            numa::init(domains.len(), memory, cpus);
+        }
+    }
+
+    pub fn new(sdt: &'static Sdt) -> Option<Srat> {
+        // Skip the reserved fields before the entries
+        if &sdt.signature == b"SRAT" && sdt.data_len() >= 12 {
+            Some(Srat { sdt })
+        } else {
+            None
+        }
+    }
+
+    pub fn iter(&self) -> SratIter {
+        SratIter {
+            sdt: self.sdt,
+            i: 12,
+        }
+    }
+}
+
+pub struct SratIter {
+    sdt: &'static Sdt,
+    i: usize,
+}
+
+impl SratIter {
+    fn read<T: Copy>(&self, entry_len: usize) -> Option<T> {
+        (entry_len >= mem::size_of::<T>() + 2).then(|| unsafe {
+            ptr::read_unaligned((self.sdt.data_address() + self.i + 2) as *const T)
+        })
+    }
+}
+
+impl Iterator for SratIter {
+    type Item = SratEntry;
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.i + 1 >= self.sdt.data_len() {
+            return None;
+        }
+        let entry_type = unsafe { *(self.sdt.data_address() as *const u8).add(self.i) };
+        let entry_len = unsafe { *(self.sdt.data_address() as *const u8).add(self.i + 1) } as usize;
+
+        if entry_len < 2 || self.i + entry_len > self.sdt.data_len() {
+            return None;
+        }
+
+        let item = match entry_type {
+            0x0 => self.read(entry_len).map(SratEntry::LocalApic),
+            0x1 => self.read(entry_len).map(SratEntry::Memory),
+            0x2 => self.read(entry_len).map(SratEntry::X2Apic),
+            0x3 => self.read(entry_len).map(SratEntry::Gicc),
+            _ => None,
+        }
+        .unwrap_or(SratEntry::Unknown(entry_type));
+
+        self.i += entry_len;
+
+        Some(item)
+    }
+}
@@ -1,10 +1,10 @@
-use crate::memory::KernelMapper;
+use crate::memory::{slab, KernelMapper};
+use crate::sync::Mutex;
 use core::{
     alloc::{GlobalAlloc, Layout},
     ptr::{self, NonNull},
 };
 use linked_list_allocator::Heap;
-use spin::Mutex;
 
 static HEAP: Mutex<Option<Heap>> = Mutex::new(None);
 
@@ -17,7 +17,51 @@ impl Allocator {
 }
 
 unsafe impl GlobalAlloc for Allocator {
+    #[cfg(debug_assertions)]
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        if let Some(object) = slab::alloc(layout) {
+            return object;
+        }
+        let Some(outer_layout) = super::redzone::outer_layout(layout) else {
+            return ptr::null_mut();
+        };
+        let outer = self.alloc_inner(outer_layout);
+        if outer.is_null() {
+            return outer;
+        }
+        super::redzone::on_alloc(outer, layout)
+    }
+    #[cfg(not(debug_assertions))]
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        if let Some(object) = slab::alloc(layout) {
+            return object;
+        }
+        self.alloc_inner(layout)
+    }
+
+    #[cfg(debug_assertions)]
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        if slab::free(ptr, layout) {
+            return;
+        }
+        // Checked before locking the heap, as reporting corruption may allocate.
+        let outer = super::redzone::on_dealloc(ptr, layout);
+        let outer_layout =
+            super::redzone::outer_layout(layout).expect("layout was accepted when allocating");
+        self.dealloc_inner(outer, outer_layout)
+    }
+    #[cfg(not(debug_assertions))]
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        if slab::free(ptr, layout) {
+            return;
+        }
+        self.dealloc_inner(ptr, layout)
+    }
+}
+
+impl Allocator {
+    #[inline(always)]
+    unsafe fn alloc_inner(&self, layout: Layout) -> *mut u8 {
         while let Some(ref mut heap) = *HEAP.lock() {
             match heap.allocate_first_fit(layout) {
                 Err(()) => {
@@ -39,7 +83,7 @@ unsafe impl GlobalAlloc for Allocator {
         panic!("__rust_allocate: heap not initialized");
     }
 
-    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+    unsafe fn dealloc_inner(&self, ptr: *mut u8, layout: Layout) {
         if let Some(ref mut heap) = *HEAP.lock() {
             heap.deallocate(NonNull::new_unchecked(ptr), layout)
         } else {
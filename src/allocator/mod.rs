@@ -16,6 +16,9 @@ mod linked_list;
 #[cfg(feature = "slab")]
 mod slab;
 
+#[cfg(all(debug_assertions, not(feature = "slab")))]
+mod redzone;
+
 unsafe fn map_heap(mapper: &mut KernelMapper, offset: usize, size: usize) {
     let mapper = mapper
         .get_mut()
@@ -0,0 +1,141 @@
+//! Heap redzones and double-free detection, used by debug builds.
+//!
+//! Every allocation is surrounded by guard bytes, and preceded by a header recording its size,
+//! its state and where it was allocated from. The guards and the header are checked when freeing,
+//! and an allocation bitmap, indexed by the address of each allocation, catches double and invalid
+//! frees even after the header has been reused. Freed memory is poisoned to make use-after-free
+//! bugs visible.
+
+use core::{
+    alloc::Layout,
+    mem::size_of,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+/// Alignment of allocations, and granularity of the allocation bitmap.
+const MIN_ALIGN: usize = 16;
+/// Minimum number of guard bytes before and after each allocation.
+const GUARD_SIZE: usize = 16;
+const GUARD_BYTE: u8 = 0xFD;
+const POISON_BYTE: u8 = 0x6B;
+
+const STATE_ALLOCATED: usize = 0xA110_CA7E;
+const STATE_FREED: usize = 0xF4EE_D000;
+
+/// Number of return addresses recorded for each allocation.
+const ALLOC_PCS: usize = 6;
+
+/// Size of the heap covered by the allocation bitmap. Allocations above it are only checked using
+/// their header.
+const TRACKED_SIZE: usize = 64 * 1024 * 1024;
+
+#[repr(C)]
+struct Header {
+    state: usize,
+    size: usize,
+    pcs: [usize; ALLOC_PCS],
+}
+
+static ALLOCATED: [AtomicU64; TRACKED_SIZE / MIN_ALIGN / 64] = {
+    const ZERO: AtomicU64 = AtomicU64::new(0);
+    [ZERO; TRACKED_SIZE / MIN_ALIGN / 64]
+};
+
+/// Offset of the returned pointer from the start of the underlying allocation.
+fn data_offset(layout: Layout) -> usize {
+    (size_of::<Header>() + GUARD_SIZE).next_multiple_of(layout.align().max(MIN_ALIGN))
+}
+
+/// The layout of the underlying allocation, including the header and guards.
+pub fn outer_layout(layout: Layout) -> Option<Layout> {
+    let size = data_offset(layout)
+        .checked_add(layout.size())?
+        .checked_add(GUARD_SIZE)?;
+    Layout::from_size_align(size, layout.align().max(MIN_ALIGN)).ok()
+}
+
+/// Returns the bitmap word and bit tracking the allocation at `ptr`, if covered.
+fn bitmap_slot(ptr: *mut u8) -> Option<(&'static AtomicU64, u64)> {
+    let index = (ptr as usize).checked_sub(crate::KERNEL_HEAP_OFFSET)? / MIN_ALIGN;
+    let word = ALLOCATED.get(index / 64)?;
+    Some((word, 1 << (index % 64)))
+}
+
+/// Initialize the header and guards of a new allocation, given the start of the underlying
+/// allocation, and return the pointer handed out.
+#[inline(always)]
+pub unsafe fn on_alloc(outer: *mut u8, layout: Layout) -> *mut u8 {
+    let offset = data_offset(layout);
+    let ptr = outer.add(offset);
+
+    let mut pcs = [0; ALLOC_PCS];
+    crate::unwind::return_addresses(&mut pcs);
+    outer.cast::<Header>().write(Header {
+        state: STATE_ALLOCATED,
+        size: layout.size(),
+        pcs,
+    });
+    let front = outer.add(size_of::<Header>());
+    front.write_bytes(GUARD_BYTE, offset - size_of::<Header>());
+    ptr.add(layout.size()).write_bytes(GUARD_BYTE, GUARD_SIZE);
+
+    if let Some((word, bit)) = bitmap_slot(ptr) {
+        word.fetch_or(bit, Ordering::Relaxed);
+    }
+
+    ptr
+}
+
+/// Check an allocation being freed, panicking if it is not currently allocated or its guards
+/// were overwritten, and return the start of the underlying allocation.
+pub unsafe fn on_dealloc(ptr: *mut u8, layout: Layout) -> *mut u8 {
+    let offset = data_offset(layout);
+    let outer = ptr.sub(offset);
+    let header = &mut *outer.cast::<Header>();
+
+    if let Some((word, bit)) = bitmap_slot(ptr)
+        && word.fetch_and(!bit, Ordering::Relaxed) & bit == 0
+    {
+        report(ptr, header, "double free or free of unallocated pointer");
+    }
+    match header.state {
+        STATE_ALLOCATED => (),
+        STATE_FREED => report(ptr, header, "double free"),
+        _ => report(ptr, header, "free of corrupted or unallocated pointer"),
+    }
+    if header.size != layout.size() {
+        report(ptr, header, "free with wrong size");
+    }
+
+    let front =
+        core::slice::from_raw_parts(outer.add(size_of::<Header>()), offset - size_of::<Header>());
+    if front.iter().any(|&byte| byte != GUARD_BYTE) {
+        report(ptr, header, "heap buffer underflow");
+    }
+    let back = core::slice::from_raw_parts(ptr.add(layout.size()), GUARD_SIZE);
+    if back.iter().any(|&byte| byte != GUARD_BYTE) {
+        report(ptr, header, "heap buffer overflow");
+    }
+
+    header.state = STATE_FREED;
+    ptr.write_bytes(POISON_BYTE, layout.size());
+
+    outer
+}
+
+#[cold]
+fn report(ptr: *mut u8, header: &Header, what: &str) -> ! {
+    println!(
+        "HEAP CORRUPTION: {} at {:p}, size {}",
+        what, ptr, header.size
+    );
+    for &pc in &header.pcs {
+        if pc != 0 {
+            println!("  ALLOCATED AT {:>016x}", pc);
+            unsafe {
+                crate::panic::symbol_trace(pc);
+            }
+        }
+    }
+    panic!("heap corruption: {} at {:p}", what, ptr);
+}
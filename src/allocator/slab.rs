@@ -1,6 +1,6 @@
+use crate::sync::Mutex;
 use core::alloc::{Alloc, AllocErr, Layout};
 use slab_allocator::Heap;
-use spin::Mutex;
 
 static HEAP: Mutex<Option<Heap>> = Mutex::new(None);
 
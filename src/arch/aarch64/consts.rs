@@ -4,6 +4,10 @@
 // The lower 256 PML4 entries are reserved for userspace
 // Each PML4 entry references up to 512 GB of memory
 // The second from the top (510) PML4 is reserved for the kernel
+// With the 16 KiB and 64 KiB granules, tables hold more entries and the top level fewer, but the
+// regions below keep the same size and place. With the 16 KiB granule, virtual addresses are 47
+// bits wide, so that three levels translate them, and with 52-bit virtual addresses both halves
+// are 16 times larger. Either way, only the user end and the physmap move.
 /// The size of a single PML4
 pub const PML4_SIZE: usize = 0x0000_0080_0000_0000;
 pub const PML4_MASK: usize = 0x0000_ff80_0000_0000;
@@ -32,10 +36,18 @@ pub const KERNEL_PERCPU_PML4: usize = (KERNEL_PERCPU_OFFSET & PML4_MASK) / PML4_
 pub const KERNEL_PERCPU_SHIFT: u8 = 16; // 2^16 = 64 KiB
 pub const KERNEL_PERCPU_SIZE: usize = 1_usize << KERNEL_PERCPU_SHIFT;
 
+/// Bits of a virtual address translated by either half
+#[cfg(not(any(feature = "aarch64_16k_pages", feature = "aarch64_lva")))]
+pub const VIRT_ADDR_BITS: u32 = 48;
+#[cfg(feature = "aarch64_16k_pages")]
+pub const VIRT_ADDR_BITS: u32 = 47;
+#[cfg(feature = "aarch64_lva")]
+pub const VIRT_ADDR_BITS: u32 = 52;
+
 /// Offset of physmap
 // This needs to match RMM's PHYS_OFFSET
-pub const PHYS_OFFSET: usize = 0xFFFF_8000_0000_0000;
+pub const PHYS_OFFSET: usize = (1_usize << (VIRT_ADDR_BITS - 1)).wrapping_neg();
 pub const PHYS_PML4: usize = (PHYS_OFFSET & PML4_MASK) / PML4_SIZE;
 
 /// End offset of the user image, i.e. kernel start
-pub const USER_END_OFFSET: usize = 256 * PML4_SIZE;
+pub const USER_END_OFFSET: usize = 1 << (VIRT_ADDR_BITS - 1);
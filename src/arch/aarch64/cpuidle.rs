@@ -0,0 +1,36 @@
+//! Idle states: WFE for short idle periods, and WFI. Unlike WFI, WFE is entered with interrupts
+//! unmasked, and sleeps even if an interrupt was taken right before it, so it relies on the event
+//! stream of the generic timer to return within tens of microseconds.
+
+use core::arch::asm;
+
+use crate::idle::IdleState;
+
+const WFE: u32 = 0;
+const WFI: u32 = 1;
+
+static STATES: [IdleState; 2] = [
+    IdleState {
+        name: "wfe",
+        target_residency: 0,
+        hint: WFE,
+    },
+    IdleState {
+        name: "wfi",
+        // Longer than the period of the event stream.
+        target_residency: 50_000,
+        hint: WFI,
+    },
+];
+
+pub fn states() -> &'static [IdleState] {
+    &STATES
+}
+
+/// Enter `state` until the next interrupt, with interrupts disabled, which are enabled on return.
+pub unsafe fn enter(state: &IdleState) {
+    match state.hint {
+        WFE => asm!("msr daifclr, #2", "wfe"),
+        _ => crate::interrupt::enable_and_halt(),
+    }
+}
@@ -1,12 +1,17 @@
 use core::fmt;
 use spin::MutexGuard;
 
-use crate::log::{Log, LOG};
+use crate::{
+    devices::console::{self, Output},
+    log::{Log, LOG},
+};
 
 #[cfg(feature = "serial_debug")]
 use super::device::serial::{SerialKind, COM1};
 #[cfg(feature = "graphical_debug")]
 use crate::devices::graphical_debug::{DebugDisplay, DEBUG_DISPLAY};
+#[cfg(feature = "virtio")]
+use crate::devices::virtio::console::{Console, VIRTIO_CONSOLE};
 
 pub struct Writer<'a> {
     log: MutexGuard<'a, Option<Log>>,
@@ -14,6 +19,8 @@ pub struct Writer<'a> {
     display: MutexGuard<'a, Option<DebugDisplay>>,
     #[cfg(feature = "serial_debug")]
     serial: MutexGuard<'a, Option<SerialKind>>,
+    #[cfg(feature = "virtio")]
+    virtio_console: MutexGuard<'a, Option<Console>>,
 }
 
 impl<'a> Writer<'a> {
@@ -24,6 +31,8 @@ impl<'a> Writer<'a> {
             display: DEBUG_DISPLAY.lock(),
             #[cfg(feature = "serial_debug")]
             serial: COM1.lock(),
+            #[cfg(feature = "virtio")]
+            virtio_console: VIRTIO_CONSOLE.lock(),
         }
     }
 
@@ -36,17 +45,31 @@ impl<'a> Writer<'a> {
 
         #[cfg(feature = "graphical_debug")]
         {
-            if let Some(ref mut display) = *self.display {
+            if let Some(ref mut display) = *self.display
+                && console::enabled(Output::Display)
+            {
                 let _ = display.write(buf);
             }
         }
 
+        // The serial ports, and any other debug port.
+        if !console::enabled(Output::Serial) {
+            return;
+        }
+
         #[cfg(feature = "serial_debug")]
         {
             if let Some(ref mut serial) = *self.serial {
                 serial.write(buf);
             }
         }
+
+        #[cfg(feature = "virtio")]
+        {
+            if let Some(ref mut console) = *self.virtio_console {
+                console.write(buf);
+            }
+        }
     }
 }
 
@@ -0,0 +1,106 @@
+//! Per-context hardware breakpoints and watchpoints.
+//!
+//! The values of a context are kept in its [`DebugRegisters`], which are written by tracers
+//! through the `regs/debug` proc: handle. Userspace cannot access the debug registers itself, so
+//! they never need to be saved, and are only loaded when switching to or from a context using
+//! them.
+
+use core::arch::asm;
+
+use crate::syscall::error::{Error, Result, EINVAL};
+
+/// Number of breakpoint and watchpoint slots that can be stored; the CPU may implement fewer.
+pub const MAX_SLOTS: usize = 16;
+
+/// Enable bit of DBGBCR and DBGWCR.
+const CTRL_E: u64 = 1;
+/// Privilege mode control (PMC/PAC) field, set to match EL0 only.
+const CTRL_EL0_ONLY: u64 = 0b10 << 1;
+/// DBGBCR fields user breakpoints may set: E and BAS. Other fields select higher exception
+/// levels, security states or linked context matches.
+const BCR_USER_MASK: u64 = CTRL_E | (0b1111 << 5);
+/// DBGWCR fields user watchpoints may set: E, LSC, BAS and MASK.
+const WCR_USER_MASK: u64 = CTRL_E | (0b11 << 3) | (0xFF << 5) | (0b11111 << 24);
+
+/// Single step enable.
+const MDSCR_SS: u64 = 1 << 0;
+/// Monitor debug events, enabling breakpoint and watchpoint exceptions.
+const MDSCR_MDE: u64 = 1 << 15;
+
+#[derive(Clone, Copy, Debug, Default)]
+#[repr(C)]
+pub struct DebugRegisters {
+    pub bvr: [u64; MAX_SLOTS],
+    pub bcr: [u64; MAX_SLOTS],
+    pub wvr: [u64; MAX_SLOTS],
+    pub wcr: [u64; MAX_SLOTS],
+    pub mdscr: u64,
+}
+
+impl DebugRegisters {
+    /// Validate registers written by userspace, and restrict them to userspace addresses.
+    pub fn sanitize(&mut self) -> Result<()> {
+        let (brps, wrps) = slot_counts();
+
+        for (i, (bvr, bcr)) in self.bvr.iter().zip(&mut self.bcr).enumerate() {
+            *bcr = (*bcr & BCR_USER_MASK) | CTRL_EL0_ONLY;
+            if *bcr & CTRL_E != 0 && (i >= brps || *bvr as usize >= crate::USER_END_OFFSET) {
+                return Err(Error::new(EINVAL));
+            }
+        }
+        for (i, (wvr, wcr)) in self.wvr.iter().zip(&mut self.wcr).enumerate() {
+            *wcr = (*wcr & WCR_USER_MASK) | CTRL_EL0_ONLY;
+            if *wcr & CTRL_E != 0 && (i >= wrps || *wvr as usize >= crate::USER_END_OFFSET) {
+                return Err(Error::new(EINVAL));
+            }
+        }
+        self.mdscr &= MDSCR_MDE | MDSCR_SS;
+
+        Ok(())
+    }
+}
+
+/// Returns the number of breakpoint and watchpoint slots implemented by the CPU.
+fn slot_counts() -> (usize, usize) {
+    let dfr0: u64;
+    unsafe {
+        asm!("mrs {}, id_aa64dfr0_el1", out(reg) dfr0);
+    }
+    let brps = ((dfr0 >> 12) & 0xF) as usize + 1;
+    let wrps = ((dfr0 >> 20) & 0xF) as usize + 1;
+    (brps.min(MAX_SLOTS), wrps.min(MAX_SLOTS))
+}
+
+macro_rules! write_slot {
+    ($reg:literal, $index:expr, $value:expr) => {
+        write_slot!(@ $reg, $index, $value, 0 1 2 3 4 5 6 7 8 9 10 11 12 13 14 15)
+    };
+    (@ $reg:literal, $index:expr, $value:expr, $($n:literal)*) => {
+        match $index {
+            $($n => asm!(concat!("msr ", $reg, stringify!($n), "_el1, {}"), in(reg) $value),)*
+            _ => unreachable!(),
+        }
+    };
+}
+
+/// Load the breakpoints and watchpoints of the next context, or disable them if it has none.
+pub unsafe fn load(regs: Option<&DebugRegisters>) {
+    let empty = DebugRegisters::default();
+    let regs = regs.unwrap_or(&empty);
+    let (brps, wrps) = slot_counts();
+
+    // Disable debug exceptions while the slots are inconsistent, and clear the OS lock, which
+    // firmware may leave set and which masks all debug exceptions.
+    asm!("msr mdscr_el1, xzr", "msr oslar_el1, xzr", "isb");
+
+    for i in 0..brps {
+        write_slot!("dbgbvr", i, regs.bvr[i]);
+        write_slot!("dbgbcr", i, regs.bcr[i]);
+    }
+    for i in 0..wrps {
+        write_slot!("dbgwvr", i, regs.wvr[i]);
+        write_slot!("dbgwcr", i, regs.wcr[i]);
+    }
+
+    asm!("msr mdscr_el1, {}", "isb", in(reg) regs.mdscr);
+}
@@ -9,6 +9,7 @@ bitflags! {
         const DEVICE_MEMORY = 0x00 << 16;
         const NORMAL_UNCACHED_MEMORY = 0x44 << 8;
         const NORMAL_WRITEBACK_MEMORY = 0xff;
+        const NORMAL_TAGGED_MEMORY = 0xf0 << 24;
     }
 }
 
@@ -84,24 +85,44 @@ pub unsafe fn cntfreq_el0() -> u32 {
     ret as u32
 }
 
+pub unsafe fn cntvct_el0() -> u64 {
+    let ret: u64;
+    asm!("isb", "mrs {}, cntvct_el0", out(reg) ret);
+    ret
+}
+
+pub unsafe fn cntkctl_el1() -> u64 {
+    let ret: u64;
+    asm!("mrs {}, cntkctl_el1", out(reg) ret);
+    ret
+}
+
+pub unsafe fn cntkctl_el1_write(val: u64) {
+    asm!("msr cntkctl_el1, {}", in(reg) val);
+}
+
 pub unsafe fn tmr_ctrl() -> u32 {
     let ret: usize;
-    asm!("mrs {}, cntp_ctl_el0", out(reg) ret);
+    asm!("mrs {}, cntv_ctl_el0", out(reg) ret);
     ret as u32
 }
 
 pub unsafe fn tmr_ctrl_write(val: u32) {
-    asm!("msr cntp_ctl_el0, {}", in(reg) val as usize);
+    asm!("msr cntv_ctl_el0, {}", in(reg) val as usize);
 }
 
 pub unsafe fn tmr_tval() -> u32 {
     let ret: usize;
-    asm!("mrs {0}, cntp_tval_el0", out(reg) ret);
+    asm!("mrs {0}, cntv_tval_el0", out(reg) ret);
     ret as u32
 }
 
 pub unsafe fn tmr_tval_write(val: u32) {
-    asm!("msr cntp_tval_el0, {}", in(reg) val as usize);
+    asm!("msr cntv_tval_el0, {}", in(reg) val as usize);
+}
+
+pub unsafe fn tmr_cval_write(val: u64) {
+    asm!("msr cntv_cval_el0, {}", in(reg) val);
 }
 
 pub unsafe fn midr() -> u32 {
@@ -1,10 +1,9 @@
 use alloc::boxed::Box;
+use core::sync::atomic::{AtomicU32, Ordering};
 use log::{error, info};
 
 use super::ic_for_chip;
 use crate::{
-    context,
-    context::timeout,
     device::cpu::registers::control_regs,
     dtb::irqchip::{register_irq, InterruptHandler, IRQ_CHIP},
     interrupt::irq::trigger,
@@ -21,11 +20,19 @@ bitflags! {
     }
 }
 
+/// Allows EL0 to read the virtual count, which the vDSO clock is computed from.
+const CNTKCTL_EL0VCTEN: u64 = 1 << 1;
+/// Enables the event stream, which wakes up WFE in the idle loop.
+const CNTKCTL_EVNTEN: u64 = 1 << 2;
+/// The event stream triggers on transitions of bit 9 of the virtual count, giving an event every
+/// 1024 ticks, which is between 16 and 43 microseconds at the usual frequencies.
+const CNTKCTL_EVNTI: u64 = 9 << 4;
+
+/// The timer PPI, which each CPU must enable for itself.
+static TIMER_VIRQ: AtomicU32 = AtomicU32::new(u32::MAX);
+
 pub unsafe fn init(fdt: &Fdt) {
-    let mut timer = GenericTimer {
-        clk_freq: 0,
-        reload_count: 0,
-    };
+    let mut timer = GenericTimer { clk_freq: 0 };
     timer.init();
     if let Some(node) = fdt.find_compatible(&["arm,armv7-timer"]) {
         let interrupts = node.property("interrupts").unwrap();
@@ -33,40 +40,78 @@ pub unsafe fn init(fdt: &Fdt) {
             .value
             .array_chunks::<4>()
             .map(|f| BE::read_u32(f))
-            .skip(3)
+            .skip(6)
             .next_chunk::<3>()
             .unwrap();
         if let Some(ic_idx) = ic_for_chip(&fdt, &node) {
-            //PHYS_NONSECURE_PPI only
+            //VIRT_PPI only
             let virq = IRQ_CHIP.irq_chip_list.chips[ic_idx]
                 .ic
                 .irq_xlate(&irq)
                 .unwrap();
-            info!("generic_timer virq = {}", virq);
-            register_irq(virq as u32, Box::new(timer));
-            IRQ_CHIP.irq_enable(virq as u32);
+            register(virq as u32, timer);
         } else {
             error!("Failed to find irq parent for generic timer");
         }
     }
 }
 
+/// Register the interrupt of the virtual timer, which is then used in one-shot mode.
+pub unsafe fn register(virq: u32, timer: GenericTimer) {
+    info!("generic_timer virq = {}", virq);
+    register_irq(virq, "generic timer", Box::new(timer));
+    IRQ_CHIP.irq_enable(virq);
+    TIMER_VIRQ.store(virq, Ordering::Relaxed);
+    crate::timer::enable_oneshot();
+}
+
+/// Start the timer of a secondary CPU.
+pub unsafe fn init_ap() {
+    GenericTimer { clk_freq: 0 }.init();
+
+    let virq = TIMER_VIRQ.load(Ordering::Relaxed);
+    if virq != u32::MAX {
+        IRQ_CHIP.irq_enable(virq);
+    }
+}
+
+/// Reset the timer of this CPU after resuming from suspend.
+pub unsafe fn resume() {
+    GenericTimer { clk_freq: 0 }.init();
+}
+
+/// Arm the timer of this CPU to fire at `deadline`, in nanoseconds of the monotonic clock.
+pub unsafe fn arm(deadline: u128) {
+    let freq = u128::from(control_regs::cntfreq_el0());
+    // Converted relative to now, as the monotonic clock continues from before suspending.
+    let delta = deadline.saturating_sub(time::monotonic());
+    let ticks = u64::try_from(delta * freq / time::NANOS_PER_SEC).unwrap_or(u64::MAX);
+    control_regs::tmr_cval_write(control_regs::cntvct_el0().saturating_add(ticks));
+    control_regs::tmr_ctrl_write(TimerCtrlFlags::ENABLE.bits());
+}
+
+/// Mask the interrupt of the timer of this CPU until it is armed again.
+pub unsafe fn disarm() {
+    control_regs::tmr_ctrl_write((TimerCtrlFlags::ENABLE | TimerCtrlFlags::IMASK).bits());
+}
+
 pub struct GenericTimer {
     pub clk_freq: u32,
-    pub reload_count: u32,
 }
 
 impl GenericTimer {
+    /// Enable the timer of this CPU with its interrupt masked, until it is armed.
     pub fn init(&mut self) {
-        let clk_freq = unsafe { control_regs::cntfreq_el0() };
-        self.clk_freq = clk_freq;
-        self.reload_count = clk_freq / 100;
+        self.clk_freq = unsafe { control_regs::cntfreq_el0() };
 
-        unsafe { control_regs::tmr_tval_write(self.reload_count) };
+        unsafe {
+            control_regs::cntkctl_el1_write(
+                control_regs::cntkctl_el1() | CNTKCTL_EL0VCTEN | CNTKCTL_EVNTEN | CNTKCTL_EVNTI,
+            );
+        }
 
         let mut ctrl = TimerCtrlFlags::from_bits_truncate(unsafe { control_regs::tmr_ctrl() });
-        ctrl.insert(TimerCtrlFlags::ENABLE);
-        ctrl.remove(TimerCtrlFlags::IMASK);
+        ctrl.insert(TimerCtrlFlags::ENABLE | TimerCtrlFlags::IMASK);
         unsafe {
             control_regs::tmr_ctrl_write(ctrl.bits());
         }
@@ -79,13 +124,6 @@ impl GenericTimer {
         unsafe { control_regs::tmr_ctrl_write(ctrl.bits()) };
     }
 
-    #[allow(unused)]
-    pub fn set_irq(&mut self) {
-        let mut ctrl = TimerCtrlFlags::from_bits_truncate(unsafe { control_regs::tmr_ctrl() });
-        ctrl.remove(TimerCtrlFlags::IMASK);
-        unsafe { control_regs::tmr_ctrl_write(ctrl.bits()) };
-    }
-
     pub fn clear_irq(&mut self) {
         let mut ctrl = TimerCtrlFlags::from_bits_truncate(unsafe { control_regs::tmr_ctrl() });
 
@@ -94,30 +132,17 @@ impl GenericTimer {
             unsafe { control_regs::tmr_ctrl_write(ctrl.bits()) };
         }
     }
-
-    pub fn reload_count(&mut self) {
-        let mut ctrl = TimerCtrlFlags::from_bits_truncate(unsafe { control_regs::tmr_ctrl() });
-        ctrl.insert(TimerCtrlFlags::ENABLE);
-        ctrl.remove(TimerCtrlFlags::IMASK);
-        unsafe { control_regs::tmr_tval_write(self.reload_count) };
-        unsafe { control_regs::tmr_ctrl_write(ctrl.bits()) };
-    }
 }
 
 impl InterruptHandler for GenericTimer {
     fn irq_handler(&mut self, irq: u32) {
+        // Stays masked until armed for the next deadline.
         self.clear_irq();
-        {
-            *time::OFFSET.lock() += self.clk_freq as u128;
-        }
-
-        timeout::trigger();
-
-        context::switch::tick();
 
         unsafe {
             trigger(irq);
         }
-        self.reload_count();
+
+        crate::timer::interrupt();
     }
 }
@@ -1,5 +1,6 @@
-use super::InterruptController;
+use super::{gicv2m, InterruptController};
 use crate::dtb::irqchip::{InterruptHandler, IrqDesc};
+use alloc::vec::Vec;
 use core::ptr::{read_volatile, write_volatile};
 use fdt::{node::FdtNode, Fdt};
 use log::info;
@@ -25,6 +26,8 @@ pub struct GenericInterruptController {
     pub gic_dist_if: GicDistIf,
     pub gic_cpu_if: GicCpuIf,
     pub irq_range: (usize, usize),
+    /// Configuration and enable registers, saved while the system is suspended.
+    pub saved: Vec<u32>,
 }
 
 impl GenericInterruptController {
@@ -36,6 +39,7 @@ impl GenericInterruptController {
             gic_dist_if,
             gic_cpu_if,
             irq_range: (0, 0),
+            saved: Vec::new(),
         }
     }
     pub fn parse(fdt: &Fdt) -> Result<(usize, usize, usize, usize)> {
@@ -114,6 +118,21 @@ impl InterruptController for GenericInterruptController {
         info!("gic irq_range = ({}, {})", idx, idx + cnt);
         self.irq_range = (idx, idx + cnt);
         *irq_idx = idx + cnt;
+
+        if let Some(node) =
+            fdt_opt.and_then(|fdt| fdt.find_compatible(&["arm,cortex-a15-gic", "arm,gic-400"]))
+        {
+            for child in node.children() {
+                let Some(frame) = gicv2m::MsiFrame::probe(&child, idx) else {
+                    continue;
+                };
+                // MSIs are edge triggered.
+                for spi in frame.spis() {
+                    unsafe { self.gic_dist_if.irq_set_edge_triggered(spi) }
+                }
+                gicv2m::register(frame);
+            }
+        }
         Ok(())
     }
     fn irq_ack(&mut self) -> u32 {
@@ -144,6 +163,44 @@ impl InterruptController for GenericInterruptController {
             Some(self.irq_range.0 + hwirq as usize)
         }
     }
+    fn irq_init_ap(&mut self) {
+        if self.gic_cpu_if.address != 0 {
+            unsafe { self.gic_cpu_if.init(self.gic_cpu_if.address) }
+        }
+    }
+    fn suspend(&mut self) {
+        let dist = &self.gic_dist_if;
+        self.saved.clear();
+        unsafe {
+            // The enables of the SGIs and PPIs are those of this CPU.
+            for irq in (32..dist.nirqs).step_by(16) {
+                self.saved.push(dist.read(GICD_ICFGR + ((irq / 16) * 4)));
+            }
+            for irq in (0..dist.nirqs).step_by(32) {
+                self.saved
+                    .push(dist.read(GICD_ISENABLER + ((irq / 32) * 4)));
+            }
+        }
+    }
+    fn resume(&mut self) {
+        let saved = core::mem::take(&mut self.saved);
+        let mut saved = saved.into_iter();
+        unsafe {
+            self.gic_dist_if.init(self.gic_dist_if.address);
+            let dist = &mut self.gic_dist_if;
+            for irq in (32..dist.nirqs).step_by(16) {
+                if let Some(value) = saved.next() {
+                    dist.write(GICD_ICFGR + ((irq / 16) * 4), value);
+                }
+            }
+            for irq in (0..dist.nirqs).step_by(32) {
+                if let Some(value) = saved.next() {
+                    dist.write(GICD_ISENABLER + ((irq / 32) * 4), value);
+                }
+            }
+            self.gic_cpu_if.init(self.gic_cpu_if.address);
+        }
+    }
 }
 
 #[derive(Debug, Default)]
@@ -207,6 +264,14 @@ impl GicDistIf {
         self.write(offset, val);
     }
 
+    pub unsafe fn irq_set_edge_triggered(&mut self, irq: u32) {
+        let offset = GICD_ICFGR + (4 * (irq / 16));
+        let shift = 2 * (irq % 16) + 1;
+        let mut val = self.read(offset);
+        val |= 1 << shift;
+        self.write(offset, val);
+    }
+
     pub unsafe fn irq_disable(&mut self, irq: u32) {
         let offset = GICD_ICENABLER + (4 * (irq / 32));
         let shift = 1 << (irq % 32);
@@ -0,0 +1,138 @@
+//! GICv2m MSI frames, which turn writes to a doorbell register into SPIs of the parent GIC, for
+//! systems with MSI capable devices but without an ITS.
+
+use crate::sync::Mutex;
+use alloc::vec::Vec;
+use core::ptr::read_volatile;
+use fdt::node::{FdtNode, NodeProperty};
+use log::info;
+
+use super::MsiMessage;
+use crate::dtb::irqchip::IRQ_CHIP;
+
+static V2M_MSI_TYPER: usize = 0x008;
+static V2M_MSI_SETSPI_NS: usize = 0x040;
+
+/// SPIs are numbered starting at interrupt ID 32, and end before the special IDs at 1020.
+const SPI_RANGE: core::ops::Range<u32> = 32..1020;
+
+static FRAMES: Mutex<Vec<MsiFrame>> = Mutex::new(Vec::new());
+
+#[derive(Debug)]
+pub struct MsiFrame {
+    /// Physical base address of the frame.
+    base: usize,
+    spi_base: u32,
+    /// Virtual IRQ of the first SPI in the frame.
+    virq_base: usize,
+    used: Vec<bool>,
+}
+
+impl MsiFrame {
+    /// Parse a `arm,gic-v2m-frame` child node of the GIC, where `virq_base` is the virtual IRQ
+    /// of interrupt ID 0 of the GIC.
+    pub fn probe(node: &FdtNode, virq_base: usize) -> Option<Self> {
+        if !node
+            .compatible()
+            .is_some_and(|c| c.all().any(|c| c == "arm,gic-v2m-frame"))
+            || node.property("msi-controller").is_none()
+        {
+            return None;
+        }
+        let base = node.reg()?.next()?.starting_address as usize;
+
+        // The DT may override the SPI range, for implementations with a broken MSI_TYPER.
+        let (spi_base, spi_count) = match (
+            node.property("arm,msi-base-spi")
+                .and_then(NodeProperty::as_usize),
+            node.property("arm,msi-num-spis")
+                .and_then(NodeProperty::as_usize),
+        ) {
+            (Some(spi_base), Some(spi_count)) => (spi_base as u32, spi_count as u32),
+            _ => {
+                let typer = unsafe {
+                    read_volatile((crate::PHYS_OFFSET + base + V2M_MSI_TYPER) as *const u32)
+                };
+                ((typer >> 16) & 0x3ff, typer & 0x3ff)
+            }
+        };
+        if spi_count == 0
+            || !SPI_RANGE.contains(&spi_base)
+            || !SPI_RANGE.contains(&(spi_base + spi_count - 1))
+        {
+            log::warn!(
+                "gicv2m: frame at {:#x} has invalid SPI range {}+{}",
+                base,
+                spi_base,
+                spi_count
+            );
+            return None;
+        }
+        info!(
+            "gicv2m: frame at {:#x} with SPIs {}..{}",
+            base,
+            spi_base,
+            spi_base + spi_count
+        );
+
+        Some(Self {
+            base,
+            spi_base,
+            virq_base: virq_base + spi_base as usize,
+            used: vec![false; spi_count as usize],
+        })
+    }
+    /// The SPIs, as interrupt IDs, that this frame can raise.
+    pub fn spis(&self) -> core::ops::Range<u32> {
+        self.spi_base..self.spi_base + self.used.len() as u32
+    }
+}
+
+pub fn register(frame: MsiFrame) {
+    FRAMES.lock().push(frame);
+}
+
+pub fn has_msi() -> bool {
+    !FRAMES.lock().is_empty()
+}
+
+/// Allocate an SPI for an MSI capable device, and enable it.
+pub fn allocate_msi() -> Option<MsiMessage> {
+    let mut frames = FRAMES.lock();
+
+    let message = frames.iter_mut().find_map(|frame| {
+        let index = frame.used.iter().position(|used| !used)?;
+        frame.used[index] = true;
+
+        Some(MsiMessage {
+            address: (frame.base + V2M_MSI_SETSPI_NS) as u64,
+            data: frame.spi_base + index as u32,
+            virq: frame.virq_base + index,
+        })
+    })?;
+    drop(frames);
+
+    unsafe {
+        IRQ_CHIP.irq_enable(message.virq as u32);
+    }
+    Some(message)
+}
+
+/// Disable and free an SPI previously returned by [`allocate_msi`].
+pub fn free_msi(virq: usize) {
+    let mut frames = FRAMES.lock();
+
+    let Some(frame) = frames
+        .iter_mut()
+        .find(|frame| (frame.virq_base..frame.virq_base + frame.used.len()).contains(&virq))
+    else {
+        log::warn!("gicv2m: freeing unknown MSI {}", virq);
+        return;
+    };
+    frame.used[virq - frame.virq_base] = false;
+    drop(frames);
+
+    unsafe {
+        IRQ_CHIP.irq_disable(virq as u32);
+    }
+}
@@ -1,30 +1,145 @@
-use alloc::vec::Vec;
-use core::arch::asm;
+//! GICv3 and GICv4 interrupt controllers.
+//!
+//! The distributor routes SPIs by affinity, all of them to the BSP. Every CPU has a redistributor
+//! for its SGIs and PPIs, found by the affinity of the CPU and woken up as it is brought up, and
+//! uses the system register interface of the GIC. CPUs send each other SGIs through
+//! ICC_SGI1R_EL1, which carry the IPIs. If the GIC has an ITS, MSIs are LPIs translated by it, see
+//! [`super::gicv3_its`].
+//!
+//! GICv4 only adds virtual LPIs, for guests, which are not used besides making redistributors
+//! larger.
+
+use alloc::{boxed::Box, vec::Vec};
+use core::{
+    arch::asm,
+    ptr::{read_volatile, write_volatile},
+    sync::atomic::{AtomicBool, Ordering},
+};
 use fdt::{node::NodeProperty, Fdt};
 
-use super::{gic::GicDistIf, InterruptController};
-use crate::dtb::irqchip::{InterruptHandler, IrqDesc};
+use super::{gicv3_its, InterruptController};
+use crate::{
+    arch::smp::mpidr,
+    dtb::irqchip::{InterruptHandler, IrqDesc},
+    ipi::{IpiHandler, IpiKind},
+    percpu::PercpuBlock,
+};
 use syscall::{
     error::{Error, EINVAL},
     Result,
 };
 
+const GICD_CTLR: usize = 0x0000;
+const GICD_TYPER: usize = 0x0004;
+const GICD_IGROUPR: usize = 0x0080;
+const GICD_ISENABLER: usize = 0x0100;
+const GICD_ICENABLER: usize = 0x0180;
+const GICD_IPRIORITYR: usize = 0x0400;
+const GICD_ICFGR: usize = 0x0c00;
+const GICD_IROUTER: usize = 0x6000;
+
+const GICD_CTLR_RWP: u32 = 1 << 31;
+const GICD_CTLR_ARE_NS: u32 = 1 << 4;
+const GICD_CTLR_ENABLE_G1A: u32 = 1 << 1;
+const GICD_CTLR_ENABLE_G1: u32 = 1 << 0;
+const GICD_TYPER_LPIS: u32 = 1 << 17;
+
+pub(super) const GICR_CTLR: usize = 0x0000;
+const GICR_TYPER: usize = 0x0008;
+const GICR_WAKER: usize = 0x0014;
+/// The SGI and PPI registers are in the second frame of a redistributor.
+const GICR_SGI_BASE: usize = 0x1_0000;
+const GICR_IGROUPR0: usize = GICR_SGI_BASE + 0x0080;
+const GICR_ISENABLER0: usize = GICR_SGI_BASE + 0x0100;
+const GICR_ICENABLER0: usize = GICR_SGI_BASE + 0x0180;
+const GICR_IPRIORITYR: usize = GICR_SGI_BASE + 0x0400;
+
+const GICR_CTLR_RWP: u32 = 1 << 3;
+const GICR_TYPER_PLPIS: u64 = 1 << 0;
+const GICR_TYPER_VLPIS: u64 = 1 << 1;
+const GICR_TYPER_LAST: u64 = 1 << 4;
+const GICR_WAKER_PROCESSOR_SLEEP: u32 = 1 << 1;
+const GICR_WAKER_CHILDREN_ASLEEP: u32 = 1 << 2;
+
+/// Size of a redistributor, with two more frames for virtual LPIs if it supports them.
+const GICR_SIZE: usize = 0x2_0000;
+const GICR_SIZE_VLPIS: usize = 0x4_0000;
+
+const ICC_SRE_SRE: usize = 1 << 0;
+const ICC_SRE_DFB: usize = 1 << 1;
+const ICC_SRE_DIB: usize = 1 << 2;
+const ICC_CTLR_RSS: usize = 1 << 18;
+const ICC_SGI1R_IRM: u64 = 1 << 40;
+
+/// Priority of all interrupts, below the priority mask.
+pub(super) const DEFAULT_PRIORITY: u8 = 0xa0;
+/// INTIDs from 1020 are special, such as 1023 for spurious interrupts.
+const SPECIAL_INTIDS: u32 = 1020;
+/// INTID of the first LPI.
+pub(super) const LPI_BASE: u32 = 8192;
+/// Number of LPIs given a virtual IRQ, between those of the PPIs and the SPIs.
+const MAX_LPIS: usize = 64;
+
+/// Set once the BSP can send SGIs.
+static SGIS_READY: AtomicBool = AtomicBool::new(false);
+
+/// Memory-mapped registers of a part of the GIC, at a kernel address.
+#[derive(Clone, Copy, Debug, Default)]
+pub(super) struct GicRegs(pub usize);
+
+impl GicRegs {
+    pub unsafe fn read32(self, reg: usize) -> u32 {
+        read_volatile((self.0 + reg) as *const u32)
+    }
+    pub unsafe fn write32(self, reg: usize, value: u32) {
+        write_volatile((self.0 + reg) as *mut u32, value)
+    }
+    pub unsafe fn read64(self, reg: usize) -> u64 {
+        read_volatile((self.0 + reg) as *const u64)
+    }
+    pub unsafe fn write64(self, reg: usize, value: u64) {
+        write_volatile((self.0 + reg) as *mut u64, value)
+    }
+    /// Wait until the register write pending bit `rwp` of the control register is clear.
+    unsafe fn wait_rwp(self, rwp: u32) {
+        while self.read32(GICD_CTLR) & rwp != 0 {
+            core::hint::spin_loop();
+        }
+    }
+}
+
+/// The affinity of a CPU as reported by GICR_TYPER, Aff3.Aff2.Aff1.Aff0.
+fn gicr_affinity(mpidr: usize) -> u32 {
+    ((((mpidr >> 32) & 0xff) << 24) | (mpidr & 0xff_ffff)) as u32
+}
+
 #[derive(Debug)]
 pub struct GicV3 {
-    pub gic_dist_if: GicDistIf,
-    pub gic_cpu_if: GicV3CpuIf,
-    pub gicrs: Vec<(usize, usize)>,
-    //TODO: GICC, GICH, GICV?
-    pub irq_range: (usize, usize),
+    dist: GicRegs,
+    /// Physical address and size of the redistributor regions.
+    gicrs: Vec<(usize, usize)>,
+    /// Distance between the redistributors, if the devicetree sets it.
+    gicr_stride: Option<usize>,
+    /// Number of INTIDs up to the last SPI.
+    nirqs: u32,
+    irq_range: (usize, usize),
+    /// Number of LPIs, which shift the virtual IRQs of the SPIs.
+    lpi_count: usize,
+    /// SPI configuration and enable registers, and the PPI enables of the BSP, saved while the
+    /// system is suspended.
+    saved: Vec<u32>,
 }
 
 impl GicV3 {
     pub fn new() -> Self {
         GicV3 {
-            gic_dist_if: GicDistIf::default(),
-            gic_cpu_if: GicV3CpuIf,
+            dist: GicRegs::default(),
             gicrs: Vec::new(),
+            gicr_stride: None,
+            nirqs: 0,
             irq_range: (0, 0),
+            lpi_count: 0,
+            saved: Vec::new(),
         }
     }
 
@@ -35,7 +150,7 @@ impl GicV3 {
 
         // Clear current registers
         //TODO: deinit?
-        self.gic_dist_if.address = 0;
+        self.dist = GicRegs::default();
         self.gicrs.clear();
 
         // Get number of GICRs
@@ -43,14 +158,15 @@ impl GicV3 {
             .property("#redistributor-regions")
             .and_then(NodeProperty::as_usize)
             .unwrap_or(1);
+        self.gicr_stride = node
+            .property("redistributor-stride")
+            .and_then(NodeProperty::as_usize)
+            .filter(|&stride| stride != 0);
 
         // Read registers
         let mut chunks = node.reg().unwrap();
         if let Some(gicd) = chunks.next() {
-            unsafe {
-                self.gic_dist_if
-                    .init(crate::PHYS_OFFSET + gicd.starting_address as usize);
-            }
+            self.dist = GicRegs(crate::PHYS_OFFSET + gicd.starting_address as usize);
         }
         for _ in 0..gicrs {
             if let Some(gicr) = chunks.next() {
@@ -59,12 +175,125 @@ impl GicV3 {
             }
         }
 
-        if self.gic_dist_if.address == 0 || self.gicrs.is_empty() {
+        if self.dist.0 == 0 || self.gicrs.is_empty() {
             Err(Error::new(EINVAL))
         } else {
             Ok(())
         }
     }
+
+    unsafe fn init_dist(&mut self) {
+        let dist = self.dist;
+        dist.write32(GICD_CTLR, 0);
+        dist.wait_rwp(GICD_CTLR_RWP);
+
+        let typer = dist.read32(GICD_TYPER);
+        self.nirqs = (((typer & 0x1f) + 1) * 32).min(SPECIAL_INTIDS);
+        log::info!(
+            "gicv3: Distributor supports {} IRQs{}",
+            self.nirqs,
+            if typer & GICD_TYPER_LPIS != 0 {
+                " and LPIs"
+            } else {
+                ""
+            }
+        );
+
+        // All SPIs are disabled, level triggered and in group 1 non-secure, and go to the BSP.
+        let route = mpidr() as u64;
+        for irq in (32..self.nirqs as usize).step_by(32) {
+            dist.write32(GICD_ICENABLER + irq / 8, !0);
+            dist.write32(GICD_IGROUPR + irq / 8, !0);
+        }
+        for irq in (32..self.nirqs as usize).step_by(16) {
+            dist.write32(GICD_ICFGR + irq / 4, 0);
+        }
+        for irq in (32..self.nirqs as usize).step_by(4) {
+            dist.write32(
+                GICD_IPRIORITYR + irq,
+                u32::from_ne_bytes([DEFAULT_PRIORITY; 4]),
+            );
+        }
+        for irq in 32..self.nirqs as usize {
+            dist.write64(GICD_IROUTER + irq * 8, route);
+        }
+        dist.wait_rwp(GICD_CTLR_RWP);
+
+        dist.write32(
+            GICD_CTLR,
+            GICD_CTLR_ARE_NS | GICD_CTLR_ENABLE_G1A | GICD_CTLR_ENABLE_G1,
+        );
+        dist.wait_rwp(GICD_CTLR_RWP);
+    }
+
+    /// The redistributor of the CPU with `affinity`, and its physical address.
+    fn find_redistributor(&self, affinity: u32) -> Option<(GicRegs, usize)> {
+        for &(base, size) in &self.gicrs {
+            let mut offset = 0;
+            while offset + GICR_SIZE <= size {
+                let gicr = GicRegs(crate::PHYS_OFFSET + base + offset);
+                let typer = unsafe { gicr.read64(GICR_TYPER) };
+                if (typer >> 32) as u32 == affinity {
+                    return Some((gicr, base + offset));
+                }
+                if typer & GICR_TYPER_LAST != 0 {
+                    break;
+                }
+                offset += self
+                    .gicr_stride
+                    .unwrap_or(if typer & GICR_TYPER_VLPIS != 0 {
+                        GICR_SIZE_VLPIS
+                    } else {
+                        GICR_SIZE
+                    });
+            }
+        }
+        None
+    }
+
+    /// Wake up the redistributor of this CPU, and enable its SGIs and LPIs.
+    unsafe fn init_redistributor(&self) {
+        let mpidr = mpidr();
+        let Some((gicr, gicr_phys)) = self.find_redistributor(gicr_affinity(mpidr)) else {
+            log::error!("gicv3: no redistributor for MPIDR {:#x}", mpidr);
+            return;
+        };
+
+        gicr.write32(
+            GICR_WAKER,
+            gicr.read32(GICR_WAKER) & !GICR_WAKER_PROCESSOR_SLEEP,
+        );
+        while gicr.read32(GICR_WAKER) & GICR_WAKER_CHILDREN_ASLEEP != 0 {
+            core::hint::spin_loop();
+        }
+
+        // PPIs are enabled by their drivers, and SGIs are only sent by the kernel.
+        gicr.write32(GICR_IGROUPR0, !0);
+        gicr.write32(GICR_ICENABLER0, !0);
+        gicr.wait_rwp(GICR_CTLR_RWP);
+        for irq in (0..32).step_by(4) {
+            gicr.write32(
+                GICR_IPRIORITYR + irq,
+                u32::from_ne_bytes([DEFAULT_PRIORITY; 4]),
+            );
+        }
+        gicr.write32(GICR_ISENABLER0, 0xffff);
+
+        let typer = gicr.read64(GICR_TYPER);
+        if typer & GICR_TYPER_PLPIS != 0 {
+            gicv3_its::enable_lpis(gicr, gicr_phys, (typer >> 8) & 0xffff);
+        }
+
+        PercpuBlock::current().misc_arch_info.gicr.set(gicr.0);
+    }
+
+    /// The redistributor of this CPU, for its SGIs and PPIs.
+    fn local_redistributor() -> Option<GicRegs> {
+        match PercpuBlock::current().misc_arch_info.gicr.get() {
+            0 => None,
+            gicr => Some(GicRegs(gicr)),
+        }
+    }
 }
 
 impl InterruptHandler for GicV3 {
@@ -85,98 +314,199 @@ impl InterruptController for GicV3 {
         log::info!("{:X?}", self);
 
         unsafe {
-            self.gic_cpu_if.init();
+            self.init_dist();
         }
+        // The LPIs of the ITS come right after the PPIs, as the irq scheme can only reach the
+        // lower virtual IRQs.
         let idx = *irq_idx;
-        let cnt = if self.gic_dist_if.nirqs > 1024 {
-            1024
-        } else {
-            self.gic_dist_if.nirqs as usize
-        };
-        let mut i: usize = 0;
-        //only support linear irq map now.
-        while i < cnt && (idx + i < 1024) {
+        let its = fdt_opt
+            .and_then(|fdt| fdt.find_compatible(&["arm,gic-v3"]))
+            .into_iter()
+            .flat_map(|node| node.children())
+            .any(|child| unsafe { gicv3_its::probe(&child, idx + 32, MAX_LPIS) });
+        self.lpi_count = if its { MAX_LPIS } else { 0 };
+
+        let cnt = (self.nirqs as usize + self.lpi_count).min(1024 - idx);
+        for i in 0..cnt {
+            let ic_irq = match i.checked_sub(32) {
+                None => i as u32,
+                Some(lpi) if lpi < self.lpi_count => LPI_BASE + lpi as u32,
+                Some(_) => (i - self.lpi_count) as u32,
+            };
             irq_desc[idx + i].basic.ic_idx = ic_idx;
-            irq_desc[idx + i].basic.ic_irq = i as u32;
+            irq_desc[idx + i].basic.ic_irq = ic_irq;
             irq_desc[idx + i].basic.used = true;
-
-            i += 1;
         }
-
-        log::info!("gic irq_range = ({}, {})", idx, idx + cnt);
+        log::info!("gicv3 irq_range = ({}, {})", idx, idx + cnt);
         self.irq_range = (idx, idx + cnt);
         *irq_idx = idx + cnt;
+
+        // The SGIs carrying IPIs are handled by the kernel.
+        for (kind, name) in IpiKind::ALL {
+            let virq = idx + kind as usize;
+            irq_desc[virq].handler = Some(Box::new(IpiHandler(kind)));
+            if let Ok(irq) = u8::try_from(virq) {
+                crate::irq_stats::set_handler(irq, name);
+            }
+        }
+
+        unsafe {
+            self.init_redistributor();
+            init_cpu_if();
+        }
+        SGIS_READY.store(true, Ordering::Release);
         Ok(())
     }
     fn irq_ack(&mut self) -> u32 {
-        let irq_num = unsafe { self.gic_cpu_if.irq_ack() };
-        irq_num
+        let irq: usize;
+        unsafe { asm!("mrs {}, icc_iar1_el1", out(reg) irq) };
+        // Spurious interrupts have no virtual IRQ, and are not acknowledged.
+        (irq & 0xff_ffff) as u32
     }
     fn irq_eoi(&mut self, irq_num: u32) {
-        unsafe { self.gic_cpu_if.irq_eoi(irq_num) }
+        unsafe { asm!("msr icc_eoir1_el1, {}", in(reg) irq_num as usize) };
     }
     fn irq_enable(&mut self, irq_num: u32) {
-        unsafe { self.gic_dist_if.irq_enable(irq_num) }
+        unsafe {
+            if irq_num >= LPI_BASE {
+                gicv3_its::set_enabled(irq_num, true);
+            } else if irq_num < 32 {
+                if let Some(gicr) = Self::local_redistributor() {
+                    gicr.write32(GICR_ISENABLER0, 1 << irq_num);
+                }
+            } else if irq_num < self.nirqs {
+                let reg = GICD_ISENABLER + 4 * (irq_num as usize / 32);
+                self.dist.write32(reg, 1 << (irq_num % 32));
+            }
+        }
     }
     fn irq_disable(&mut self, irq_num: u32) {
-        unsafe { self.gic_dist_if.irq_disable(irq_num) }
+        unsafe {
+            if irq_num >= LPI_BASE {
+                gicv3_its::set_enabled(irq_num, false);
+            } else if irq_num < 32 {
+                if let Some(gicr) = Self::local_redistributor() {
+                    gicr.write32(GICR_ICENABLER0, 1 << irq_num);
+                    gicr.wait_rwp(GICR_CTLR_RWP);
+                }
+            } else if irq_num < self.nirqs {
+                let reg = GICD_ICENABLER + 4 * (irq_num as usize / 32);
+                self.dist.write32(reg, 1 << (irq_num % 32));
+                self.dist.wait_rwp(GICD_CTLR_RWP);
+            }
+        }
     }
     fn irq_xlate(&self, irq_data: &[u32; 3]) -> Result<usize> {
         let mut off = match irq_data[0] {
-            0 => irq_data[1] as usize + 32, //SPI
-            1 => irq_data[1] as usize + 16, //PPI,
+            0 => irq_data[1] as usize + 32 + self.lpi_count, //SPI
+            1 => irq_data[1] as usize + 16,                  //PPI,
             _ => return Err(Error::new(EINVAL)),
         };
         off += self.irq_range.0;
         return Ok(off);
     }
     fn irq_to_virq(&self, hwirq: u32) -> Option<usize> {
-        if hwirq >= self.gic_dist_if.nirqs {
-            None
+        let off = if hwirq < 32 {
+            hwirq as usize
+        } else if hwirq < self.nirqs {
+            hwirq as usize + self.lpi_count
         } else {
-            Some(self.irq_range.0 + hwirq as usize)
-        }
+            let lpi = hwirq.checked_sub(LPI_BASE)? as usize;
+            if lpi >= self.lpi_count {
+                return None;
+            }
+            32 + lpi
+        };
+        Some(self.irq_range.0 + off).filter(|&virq| virq < self.irq_range.1)
     }
-}
-
-#[derive(Debug)]
-pub struct GicV3CpuIf;
-
-impl GicV3CpuIf {
-    pub unsafe fn init(&mut self) {
-        // Enable system register access
-        {
-            let value = 1_usize;
-            asm!("msr icc_sre_el1, {}", in(reg) value);
-        }
-        // Set control register
-        {
-            let value = 0_usize;
-            asm!("msr icc_ctlr_el1, {}", in(reg) value);
-        }
-        // Enable non-secure group 1
-        {
-            let value = 1_usize;
-            asm!("msr icc_igrpen1_el1, {}", in(reg) value);
+    fn irq_init_ap(&mut self) {
+        unsafe {
+            self.init_redistributor();
+            init_cpu_if();
         }
-        // Set CPU0's Interrupt Priority Mask
-        {
-            let value = 0xFF_usize;
-            asm!("msr icc_pmr_el1, {}", in(reg) value);
+    }
+    fn suspend(&mut self) {
+        let dist = self.dist;
+        self.saved.clear();
+        unsafe {
+            for irq in (32..self.nirqs as usize).step_by(16) {
+                self.saved.push(dist.read32(GICD_ICFGR + irq / 4));
+            }
+            for irq in (32..self.nirqs as usize).step_by(32) {
+                self.saved.push(dist.read32(GICD_ISENABLER + irq / 8));
+            }
+            let ppis = Self::local_redistributor().map_or(0, |gicr| gicr.read32(GICR_ISENABLER0));
+            self.saved.push(ppis);
         }
     }
+    fn resume(&mut self) {
+        let saved = core::mem::take(&mut self.saved);
+        let mut saved = saved.into_iter();
+        unsafe {
+            self.init_dist();
+            let dist = self.dist;
+            for irq in (32..self.nirqs as usize).step_by(16) {
+                if let Some(value) = saved.next() {
+                    dist.write32(GICD_ICFGR + irq / 4, value);
+                }
+            }
+            for irq in (32..self.nirqs as usize).step_by(32) {
+                if let Some(value) = saved.next() {
+                    dist.write32(GICD_ISENABLER + irq / 8, value);
+                }
+            }
+            dist.wait_rwp(GICD_CTLR_RWP);
 
-    unsafe fn irq_ack(&mut self) -> u32 {
-        let mut irq: usize;
-        asm!("mrs {}, icc_iar1_el1", out(reg) irq);
-        irq &= 0x1ff;
-        if irq == 1023 {
-            panic!("irq_ack: got ID 1023!!!");
+            self.init_redistributor();
+            if let (Some(gicr), Some(ppis)) = (Self::local_redistributor(), saved.next()) {
+                gicr.write32(GICR_ISENABLER0, ppis);
+            }
+            init_cpu_if();
         }
-        irq as u32
     }
+}
+
+/// Enable the system register interface of this CPU, and record how other CPUs reach it with
+/// SGIs.
+unsafe fn init_cpu_if() {
+    asm!(
+        "msr icc_sre_el1, {}",
+        "isb",
+        in(reg) ICC_SRE_SRE | ICC_SRE_DFB | ICC_SRE_DIB,
+    );
+    asm!("msr icc_pmr_el1, {}", in(reg) 0xff_usize);
+    asm!("msr icc_bpr1_el1, {}", in(reg) 0_usize);
+    // Acknowledging drops the running priority, and EOI deactivates the interrupt.
+    asm!("msr icc_ctlr_el1, {}", in(reg) 0_usize);
+    asm!("msr icc_igrpen1_el1, {}", "isb", in(reg) 1_usize);
+
+    let ctlr: usize;
+    asm!("mrs {}, icc_ctlr_el1", out(reg) ctlr);
+    let mpidr = mpidr();
+    let aff0 = mpidr & 0xff;
+    // Without range selectors, SGIs can only target CPUs with Aff0 below 16.
+    if aff0 >= 16 && ctlr & ICC_CTLR_RSS == 0 {
+        log::warn!("gicv3: MPIDR {:#x} cannot be sent SGIs", mpidr);
+        return;
+    }
+    let target = ((((mpidr >> 32) & 0xff) as u64) << 48)
+        | (((aff0 >> 4) as u64) << 44)
+        | ((((mpidr >> 16) & 0xff) as u64) << 32)
+        | ((((mpidr >> 8) & 0xff) as u64) << 16)
+        | (1 << (aff0 & 0xf));
+    PercpuBlock::current()
+        .misc_arch_info
+        .sgi_target
+        .store(target, Ordering::Relaxed);
+}
 
-    unsafe fn irq_eoi(&mut self, irq: u32) {
-        asm!("msr icc_eoir1_el1, {}", in(reg) irq as usize);
+/// Send SGI `sgi` to the CPU with `target` as its SGI target, or to all other CPUs if None.
+#[cfg(feature = "multi_core")]
+pub fn send_sgi(sgi: u8, target: Option<u64>) {
+    if !SGIS_READY.load(Ordering::Acquire) {
+        return;
     }
+    let value = target.unwrap_or(ICC_SGI1R_IRM) | (u64::from(sgi) << 24);
+    // Writes before the IPI must be visible to the target when it handles it.
+    unsafe { asm!("dsb ishst", "msr icc_sgi1r_el1, {}", "isb", in(reg) value) };
 }
@@ -0,0 +1,471 @@
+//! GICv3 Interrupt Translation Service, which turns MSIs into LPIs.
+//!
+//! A device signals an MSI by writing an event ID to GITS_TRANSLATER, which the ITS looks up in the
+//! translation table of the device, by its device ID, to find the LPI. The tables are set up with
+//! commands written to a queue in memory. Every device gets a small translation table once it
+//! allocates an MSI, and all LPIs go to collection 0, the BSP, like the SPIs.
+//!
+//! The priority and enable bit of every LPI are in a configuration table in memory, shared by all
+//! redistributors, which also each get a table of pending LPIs as their CPU is brought up.
+
+use alloc::vec::Vec;
+use core::ptr;
+use fdt::node::FdtNode;
+
+use super::{
+    gicv3::{GicRegs, DEFAULT_PRIORITY, GICR_CTLR, LPI_BASE},
+    MsiMessage,
+};
+use crate::{
+    arch::smp::clean_dcache,
+    dtb::irqchip::IRQ_CHIP,
+    memory::{allocate_p2frame, deallocate_p2frame, Frame, PAGE_SIZE},
+    paging::{RmmA, RmmArch},
+    sync::Mutex,
+};
+
+const GITS_CTLR: usize = 0x0000;
+const GITS_TYPER: usize = 0x0008;
+const GITS_CBASER: usize = 0x0080;
+const GITS_CWRITER: usize = 0x0088;
+const GITS_CREADR: usize = 0x0090;
+const GITS_BASER: usize = 0x0100;
+const GITS_TRANSLATER: usize = 0x1_0040;
+
+const GITS_CTLR_ENABLED: u32 = 1 << 0;
+const GITS_CTLR_QUIESCENT: u32 = 1 << 31;
+const GITS_TYPER_VIRTUAL: u64 = 1 << 1;
+const GITS_TYPER_PTA: u64 = 1 << 19;
+
+const GICR_PROPBASER: usize = 0x0070;
+const GICR_PENDBASER: usize = 0x0078;
+const GICR_CTLR_ENABLE_LPIS: u32 = 1 << 0;
+
+/// Valid bit of GITS_BASER<n> and GITS_CBASER, and of the MAPD and MAPC commands.
+const VALID: u64 = 1 << 63;
+/// Inner write-back cacheable, read and write allocate, in GITS_BASER<n> and GITS_CBASER.
+const ITS_INNER_WB: u64 = 7 << 59;
+/// Inner write-back cacheable, read and write allocate, in GICR_PROPBASER and GICR_PENDBASER.
+const GICR_INNER_WB: u64 = 7 << 7;
+/// Inner shareable, in all of the above.
+const INNER_SHAREABLE: u64 = 1 << 10;
+const GICR_PENDBASER_PTZ: u64 = 1 << 62;
+
+const BASER_TYPE_DEVICES: u64 = 1;
+const BASER_TYPE_COLLECTIONS: u64 = 4;
+/// Largest device table, in pages, which limits the device IDs that can be mapped.
+const MAX_DEVICE_TABLE_PAGES: usize = 16;
+
+/// Number of INTID bits, so that LPIs go up to 16383.
+const LPI_ID_BITS: u32 = 14;
+/// Order of the LPI configuration table, one byte per LPI.
+const PROP_TABLE_ORDER: u32 = 1;
+/// Order of the pending tables, one bit per INTID and 64 KiB aligned.
+const PEND_TABLE_ORDER: u32 = 4;
+/// Order of the command queue.
+const CMD_QUEUE_ORDER: u32 = 4;
+const CMD_SIZE: usize = 32;
+/// How long to wait for the ITS to process a command.
+const CMD_SPIN_LIMIT: usize = 1_000_000;
+
+/// Number of event ID bits of a device, so that every device can have 32 MSIs.
+const EVENT_ID_BITS: u64 = 5;
+const EVENTS_PER_DEVICE: usize = 1 << EVENT_ID_BITS;
+
+/// The LPI is enabled, in its configuration byte.
+const LPI_ENABLE: u8 = 1 << 0;
+/// RES1 bit of the configuration bytes.
+const LPI_RES1: u8 = 1 << 1;
+
+const CMD_SYNC: u64 = 0x05;
+const CMD_MAPD: u64 = 0x08;
+const CMD_MAPC: u64 = 0x09;
+const CMD_MAPTI: u64 = 0x0a;
+const CMD_INV: u64 = 0x0c;
+const CMD_DISCARD: u64 = 0x0f;
+
+/// The collection all LPIs are mapped to.
+const COLLECTION: u64 = 0;
+
+struct Device {
+    id: u32,
+    /// Interrupt translation table.
+    itt: Frame,
+    /// The LPI, relative to [`LPI_BASE`], of each event.
+    events: [Option<u32>; EVENTS_PER_DEVICE],
+}
+
+struct Its {
+    regs: GicRegs,
+    /// Physical address of the registers.
+    phys: usize,
+    cmd_queue: *mut [u64; 4],
+    /// Offset of the next command in the queue.
+    cmd_write: usize,
+    /// Target address of the redistributor of collection 0, once its CPU enabled LPIs.
+    rdbase: Option<u64>,
+    max_devices: u32,
+    itt_entry_size: usize,
+    /// Kernel address and physical address of the LPI configuration table.
+    prop_table: (*mut u8, usize),
+    /// Virtual IRQ of the first LPI.
+    virq_base: usize,
+    lpis_used: Vec<bool>,
+    devices: Vec<Device>,
+}
+
+// The tables are only accessed with the lock held.
+unsafe impl Send for Its {}
+
+static ITS: Mutex<Option<Its>> = Mutex::new(None);
+
+fn zeroed_frames(order: u32) -> Option<(usize, usize)> {
+    let frame = allocate_p2frame(order)?;
+    let phys = frame.base().data();
+    let virt = unsafe { RmmA::phys_to_virt(frame.base()).data() };
+    unsafe {
+        ptr::write_bytes(virt as *mut u8, 0, PAGE_SIZE << order);
+        clean_dcache(virt, PAGE_SIZE << order);
+    }
+    Some((virt, phys))
+}
+
+impl Its {
+    /// Post a command, and wait until the ITS has processed it.
+    unsafe fn command(&mut self, cmd: [u64; 4]) {
+        let queue_size = PAGE_SIZE << CMD_QUEUE_ORDER;
+        let next = (self.cmd_write + CMD_SIZE) % queue_size;
+
+        let slot = self.cmd_queue.byte_add(self.cmd_write);
+        ptr::write_volatile(slot, cmd);
+        clean_dcache(slot as usize, CMD_SIZE);
+        self.regs.write64(GITS_CWRITER, next as u64);
+        self.cmd_write = next;
+
+        for _ in 0..CMD_SPIN_LIMIT {
+            if self.regs.read64(GITS_CREADR) as usize & 0xf_ffe0 == next {
+                return;
+            }
+            core::hint::spin_loop();
+        }
+        log::error!(
+            "gicv3-its: command {:#x} timed out, CREADR {:#x}",
+            cmd[0] & 0xff,
+            self.regs.read64(GITS_CREADR)
+        );
+    }
+
+    unsafe fn sync(&mut self) {
+        if let Some(rdbase) = self.rdbase {
+            self.command([CMD_SYNC, 0, rdbase, 0]);
+        }
+    }
+
+    unsafe fn map_device(&mut self, id: u32, itt: Option<usize>) {
+        let dw2 = itt.map_or(0, |itt| VALID | itt as u64);
+        self.command([
+            CMD_MAPD | (u64::from(id) << 32),
+            EVENT_ID_BITS - 1,
+            dw2,
+            0,
+        ]);
+    }
+
+    /// Set up the device and collection tables, and the command queue.
+    unsafe fn init_tables(&mut self, typer: u64) -> Option<()> {
+        for n in 0..8 {
+            let reg = GITS_BASER + n * 8;
+            let baser = self.regs.read64(reg);
+            let entry_size = (((baser >> 48) & 0x1f) + 1) as usize;
+            let pages = match (baser >> 56) & 7 {
+                BASER_TYPE_DEVICES => {
+                    let id_bits = ((typer >> 13) & 0x1f) + 1;
+                    let size = (1_usize << id_bits).saturating_mul(entry_size);
+                    size.div_ceil(PAGE_SIZE).min(MAX_DEVICE_TABLE_PAGES)
+                }
+                BASER_TYPE_COLLECTIONS => 1,
+                _ => continue,
+            };
+
+            let order = pages.next_power_of_two().trailing_zeros();
+            let (_, phys) = zeroed_frames(order)?;
+            self.regs.write64(
+                reg,
+                VALID | ITS_INNER_WB | phys as u64 | INNER_SHAREABLE | (pages as u64 - 1),
+            );
+            // Only 4 KiB pages are used, which not every ITS supports.
+            if (self.regs.read64(reg) >> 8) & 3 != 0 {
+                log::warn!("gicv3-its: table {} does not support 4 KiB pages", n);
+                return None;
+            }
+            if (baser >> 56) & 7 == BASER_TYPE_DEVICES {
+                self.max_devices = (pages * PAGE_SIZE / entry_size) as u32;
+            }
+        }
+
+        let (virt, phys) = zeroed_frames(CMD_QUEUE_ORDER)?;
+        self.cmd_queue = virt as *mut [u64; 4];
+        self.regs.write64(
+            GITS_CBASER,
+            VALID
+                | ITS_INNER_WB
+                | phys as u64
+                | INNER_SHAREABLE
+                | ((1_u64 << CMD_QUEUE_ORDER) - 1),
+        );
+        self.regs.write64(GITS_CWRITER, 0);
+        Some(())
+    }
+}
+
+/// Probe an `arm,gic-v3-its` child node of the GIC, giving its `count` LPIs the virtual IRQs from
+/// `virq_base`.
+pub unsafe fn probe(node: &FdtNode, virq_base: usize, count: usize) -> bool {
+    if !node
+        .compatible()
+        .is_some_and(|c| c.all().any(|c| c == "arm,gic-v3-its"))
+        || node.property("msi-controller").is_none()
+    {
+        return false;
+    }
+    let Some(phys) = node
+        .reg()
+        .and_then(|mut reg| reg.next())
+        .map(|reg| reg.starting_address as usize)
+    else {
+        return false;
+    };
+    let mut guard = ITS.lock();
+    if guard.is_some() {
+        log::warn!("gicv3-its: ignoring ITS at {:#x}, only one is used", phys);
+        return false;
+    }
+
+    let regs = GicRegs(crate::PHYS_OFFSET + phys);
+    regs.write32(GITS_CTLR, regs.read32(GITS_CTLR) & !GITS_CTLR_ENABLED);
+    while regs.read32(GITS_CTLR) & GITS_CTLR_QUIESCENT == 0 {
+        core::hint::spin_loop();
+    }
+
+    let typer = regs.read64(GITS_TYPER);
+    let Some((prop_virt, prop_phys)) = zeroed_frames(PROP_TABLE_ORDER) else {
+        return false;
+    };
+    let mut its = Its {
+        regs,
+        phys,
+        cmd_queue: ptr::null_mut(),
+        cmd_write: 0,
+        rdbase: None,
+        max_devices: 0,
+        itt_entry_size: (((typer >> 4) & 0xf) + 1) as usize,
+        prop_table: (prop_virt as *mut u8, prop_phys),
+        virq_base,
+        lpis_used: vec![false; count],
+        devices: Vec::new(),
+    };
+    for i in 0..count {
+        its.prop_table.0.add(i).write(DEFAULT_PRIORITY | LPI_RES1);
+    }
+    clean_dcache(prop_virt, count);
+
+    if its.init_tables(typer).is_none() {
+        log::error!("gicv3-its: failed to set up the tables of the ITS at {:#x}", phys);
+        return false;
+    }
+    regs.write32(GITS_CTLR, regs.read32(GITS_CTLR) | GITS_CTLR_ENABLED);
+
+    log::info!(
+        "gicv3-its: ITS at {:#x} with {} devices and {} LPIs{}",
+        phys,
+        its.max_devices,
+        count,
+        if typer & GITS_TYPER_VIRTUAL != 0 {
+            ", GICv4"
+        } else {
+            ""
+        }
+    );
+    *guard = Some(its);
+    true
+}
+
+pub fn is_present() -> bool {
+    ITS.lock().is_some()
+}
+
+/// Set up and enable LPIs in the redistributor `gicr` of this CPU, at physical address `phys`
+/// and with the processor number `processor`. LPIs are routed to the first CPU doing so.
+pub unsafe fn enable_lpis(gicr: GicRegs, phys: usize, processor: u64) {
+    let mut guard = ITS.lock();
+    let Some(its) = guard.as_mut() else {
+        return;
+    };
+    if gicr.read32(GICR_CTLR) & GICR_CTLR_ENABLE_LPIS != 0 {
+        log::warn!("gicv3-its: LPIs already enabled in redistributor {:#x}", phys);
+        return;
+    }
+    let Some((_, pend_phys)) = zeroed_frames(PEND_TABLE_ORDER) else {
+        log::error!("gicv3-its: no memory for the pending table of {:#x}", phys);
+        return;
+    };
+
+    gicr.write64(
+        GICR_PROPBASER,
+        its.prop_table.1 as u64 | GICR_INNER_WB | INNER_SHAREABLE | u64::from(LPI_ID_BITS - 1),
+    );
+    gicr.write64(
+        GICR_PENDBASER,
+        pend_phys as u64 | GICR_INNER_WB | INNER_SHAREABLE | GICR_PENDBASER_PTZ,
+    );
+    gicr.write32(GICR_CTLR, gicr.read32(GICR_CTLR) | GICR_CTLR_ENABLE_LPIS);
+
+    if its.rdbase.is_none() {
+        let rdbase = if its.regs.read64(GITS_TYPER) & GITS_TYPER_PTA != 0 {
+            phys as u64
+        } else {
+            processor << 16
+        };
+        its.rdbase = Some(rdbase);
+        its.command([CMD_MAPC, 0, VALID | rdbase | COLLECTION, 0]);
+        its.sync();
+    }
+}
+
+/// Allocate an LPI for MSIs of the device with ID `device_id`, and enable it.
+pub fn allocate_msi(device_id: u32) -> Option<MsiMessage> {
+    let mut guard = ITS.lock();
+    let its = guard.as_mut()?;
+    if device_id >= its.max_devices || its.rdbase.is_none() {
+        return None;
+    }
+    let lpi = its.lpis_used.iter().position(|used| !used)?;
+
+    let device = match its.devices.iter().position(|dev| dev.id == device_id) {
+        Some(device) => device,
+        None => {
+            // Translation tables need 256 byte alignment, so a frame is used.
+            debug_assert!(EVENTS_PER_DEVICE * its.itt_entry_size <= PAGE_SIZE);
+            let itt = allocate_p2frame(0)?;
+            unsafe {
+                let virt = RmmA::phys_to_virt(itt.base()).data();
+                ptr::write_bytes(virt as *mut u8, 0, PAGE_SIZE);
+                clean_dcache(virt, PAGE_SIZE);
+                its.map_device(device_id, Some(itt.base().data()));
+            }
+            its.devices.push(Device {
+                id: device_id,
+                itt,
+                events: [None; EVENTS_PER_DEVICE],
+            });
+            its.devices.len() - 1
+        }
+    };
+    let event = its.devices[device].events.iter().position(Option::is_none)?;
+    its.devices[device].events[event] = Some(lpi as u32);
+    its.lpis_used[lpi] = true;
+
+    let intid = LPI_BASE + lpi as u32;
+    unsafe {
+        its.command([
+            CMD_MAPTI | (u64::from(device_id) << 32),
+            event as u64 | (u64::from(intid) << 32),
+            COLLECTION,
+            0,
+        ]);
+        its.sync();
+    }
+    let message = MsiMessage {
+        address: (its.phys + GITS_TRANSLATER) as u64,
+        data: event as u32,
+        virq: its.virq_base + lpi,
+    };
+    drop(guard);
+
+    unsafe {
+        IRQ_CHIP.irq_enable(message.virq as u32);
+    }
+    Some(message)
+}
+
+/// Disable and free an LPI previously returned by [`allocate_msi`], returning false if `virq` is
+/// not one of the LPIs.
+pub fn free_msi(virq: usize) -> bool {
+    let Some(lpi) = ITS
+        .lock()
+        .as_ref()
+        .and_then(|its| {
+            virq.checked_sub(its.virq_base)
+                .filter(|&lpi| lpi < its.lpis_used.len())
+        })
+    else {
+        return false;
+    };
+    unsafe {
+        IRQ_CHIP.irq_disable(virq as u32);
+    }
+
+    let mut guard = ITS.lock();
+    let Some(its) = guard.as_mut() else {
+        return false;
+    };
+    let Some((device, event)) = its.devices.iter().enumerate().find_map(|(i, dev)| {
+        let event = dev.events.iter().position(|&e| e == Some(lpi as u32))?;
+        Some((i, event))
+    }) else {
+        log::warn!("gicv3-its: freeing unknown MSI {}", virq);
+        return true;
+    };
+
+    let device_id = its.devices[device].id;
+    unsafe {
+        its.command([
+            CMD_DISCARD | (u64::from(device_id) << 32),
+            event as u64,
+            0,
+            0,
+        ]);
+        its.sync();
+    }
+    its.devices[device].events[event] = None;
+    its.lpis_used[lpi] = false;
+
+    if its.devices[device].events.iter().all(Option::is_none) {
+        let device = its.devices.swap_remove(device);
+        unsafe {
+            its.map_device(device_id, None);
+            its.sync();
+            deallocate_p2frame(device.itt, 0);
+        }
+    }
+    true
+}
+
+/// Enable or disable the LPI `intid`, in the configuration table.
+pub unsafe fn set_enabled(intid: u32, enabled: bool) {
+    let mut guard = ITS.lock();
+    let Some(its) = guard.as_mut() else {
+        return;
+    };
+    let lpi = (intid - LPI_BASE) as usize;
+    if lpi >= its.lpis_used.len() {
+        return;
+    }
+    let config = its.prop_table.0.add(lpi);
+    if enabled {
+        config.write_volatile(config.read_volatile() | LPI_ENABLE);
+    } else {
+        config.write_volatile(config.read_volatile() & !LPI_ENABLE);
+    }
+    clean_dcache(config as usize, 1);
+
+    // The redistributor caches the configuration, and is told through the ITS to reload it.
+    let Some((device_id, event)) = its.devices.iter().find_map(|dev| {
+        let event = dev.events.iter().position(|&e| e == Some(lpi as u32))?;
+        Some((dev.id, event))
+    }) else {
+        return;
+    };
+    its.command([CMD_INV | (u64::from(device_id) << 32), event as u64, 0, 0]);
+    its.sync();
+}
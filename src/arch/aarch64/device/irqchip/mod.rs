@@ -3,7 +3,9 @@ use alloc::boxed::Box;
 use fdt::{node::FdtNode, Fdt};
 
 pub(crate) mod gic;
+pub(crate) mod gicv2m;
 pub(crate) mod gicv3;
+pub(crate) mod gicv3_its;
 mod irq_bcm2835;
 mod irq_bcm2836;
 mod null;
@@ -39,3 +41,35 @@ pub(crate) fn ic_for_chip(fdt: &Fdt, node: &FdtNode) -> Option<usize> {
         None
     }
 }
+
+/// The message an MSI capable device writes to signal an allocated interrupt.
+#[derive(Clone, Copy, Debug)]
+pub struct MsiMessage {
+    /// Physical address of the doorbell register.
+    pub address: u64,
+    /// Value to write, the interrupt ID of the SPI, or the event ID for an ITS.
+    pub data: u32,
+    /// The interrupt as seen by the rest of the kernel.
+    pub virq: usize,
+}
+
+pub fn has_msi() -> bool {
+    gicv3_its::is_present() || gicv2m::has_msi()
+}
+
+/// Allocate an interrupt for MSIs of the device with ID `device_id`, and enable it. The device ID
+/// only matters with an ITS, which translates the MSIs of every device separately.
+pub fn allocate_msi(device_id: u32) -> Option<MsiMessage> {
+    if gicv3_its::is_present() {
+        gicv3_its::allocate_msi(device_id)
+    } else {
+        gicv2m::allocate_msi()
+    }
+}
+
+/// Disable and free an interrupt previously returned by [`allocate_msi`].
+pub fn free_msi(virq: usize) {
+    if !gicv3_its::free_msi(virq) {
+        gicv2m::free_msi(virq);
+    }
+}
@@ -1,5 +1,8 @@
-use crate::info;
-use core::sync::atomic::{AtomicUsize, Ordering};
+use crate::{info, mte::MteState, pauth::PacKeys};
+use core::{
+    cell::Cell,
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+};
 use fdt::Fdt;
 
 pub mod cpu;
@@ -37,7 +40,35 @@ pub unsafe fn init_devicetree(fdt: &Fdt) {
     serial::init(fdt);
     info!("RTC INIT");
     rtc::init(fdt);
+    crate::stop::init_psci(fdt);
+    crate::dvfs::init(fdt);
+}
+
+/// Initialize the per-CPU parts of the devices on a secondary CPU.
+pub unsafe fn init_ap() {
+    IRQ_CHIP.init_ap();
+    generic_timer::init_ap();
+}
+
+/// Save the state of the interrupt controllers, which they lose while the system is suspended.
+pub unsafe fn suspend() {
+    IRQ_CHIP.suspend();
+}
+
+/// Restore the interrupt controllers and the serial port on the BSP after resuming from suspend.
+pub unsafe fn resume() {
+    IRQ_CHIP.resume();
+    serial::resume();
 }
 
 #[derive(Default)]
-pub struct ArchPercpuMisc;
+pub struct ArchPercpuMisc {
+    /// Kernel address of the GICv3 redistributor of this CPU, or 0.
+    pub gicr: Cell<usize>,
+    /// Affinity fields of ICC_SGI1R_EL1 to send this CPU an SGI, or 0 if it cannot be sent any.
+    pub sgi_target: AtomicU64,
+    /// Pointer authentication keys of the current userspace context.
+    pub user_keys: Cell<PacKeys>,
+    /// Tag checking of the current userspace context, as loaded in SCTLR_EL1 and GCR_EL1.
+    pub mte: Cell<MteState>,
+}
@@ -1,9 +1,15 @@
-use core::ptr::read_volatile;
+use core::{
+    ptr::read_volatile,
+    sync::atomic::{AtomicUsize, Ordering},
+};
 
 use crate::time;
 
 static RTC_DR: usize = 0x000;
 
+/// Physical address of the PL031 registers, or zero if there is none.
+static PL031_PHYS: AtomicUsize = AtomicUsize::new(0);
+
 pub unsafe fn init(fdt: &fdt::Fdt) {
     if let Some(node) = fdt.find_compatible(&["arm,pl031"]) {
         match node.reg().and_then(|mut iter| iter.next()) {
@@ -12,6 +18,7 @@ pub unsafe fn init(fdt: &fdt::Fdt) {
                     phys: reg.starting_address as usize,
                 };
                 log::info!("PL031 RTC at {:#x}", rtc.phys);
+                PL031_PHYS.store(rtc.phys, Ordering::Relaxed);
                 *time::START.lock() = (rtc.time() as u128) * time::NANOS_PER_SEC;
             }
             None => {
@@ -23,6 +30,15 @@ pub unsafe fn init(fdt: &fdt::Fdt) {
     }
 }
 
+/// Read the RTC, in nanoseconds since the Unix epoch.
+pub fn read() -> Option<u128> {
+    let phys = PL031_PHYS.load(Ordering::Relaxed);
+    if phys == 0 {
+        return None;
+    }
+    Some(u128::from(Pl031rtc { phys }.time()) * time::NANOS_PER_SEC)
+}
+
 struct Pl031rtc {
     pub phys: usize,
 }
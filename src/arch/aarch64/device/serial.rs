@@ -52,6 +52,25 @@ impl SerialKind {
         }
     }
 
+    /// Read a received byte, if any, without passing it on.
+    #[cfg(feature = "debugger")]
+    pub fn receive_byte(&mut self) -> Option<u8> {
+        match self {
+            Self::Ns16550u8(inner) => inner.receive(),
+            Self::Ns16550u32(inner) => inner.receive(),
+            Self::Pl011(inner) => inner.receive_byte(),
+        }
+    }
+
+    /// Initialize the port again after it was powered off while the system was suspended.
+    pub fn reinit(&mut self) {
+        match self {
+            Self::Ns16550u8(inner) => inner.init(),
+            Self::Ns16550u32(inner) => inner.init(),
+            Self::Pl011(inner) => inner.init(true),
+        }
+    }
+
     pub fn write(&mut self, buf: &[u8]) {
         match self {
             Self::Ns16550u8(inner) => inner.write(buf),
@@ -117,6 +136,13 @@ pub unsafe fn init_early(dtb: &Fdt) {
     }
 }
 
+/// Initialize COM1 again after resuming from suspend.
+pub unsafe fn resume() {
+    if let Some(ref mut serial_port) = *COM1.lock() {
+        serial_port.reinit();
+    }
+}
+
 pub unsafe fn init(fdt: &Fdt) {
     //TODO: find actual serial device, not just any PL011
     if let Some(node) = fdt.find_compatible(&["arm,pl011"]) {
@@ -133,7 +159,7 @@ pub unsafe fn init(fdt: &Fdt) {
                 .irq_xlate(&irq)
                 .unwrap();
             info!("serial_port virq = {}", virq);
-            register_irq(virq as u32, Box::new(Com1Irq {}));
+            register_irq(virq as u32, "serial", Box::new(Com1Irq {}));
             IRQ_CHIP.irq_enable(virq as u32);
         } else {
             error!("serial port irq parent not found");
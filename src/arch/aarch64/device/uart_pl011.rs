@@ -210,6 +210,16 @@ impl SerialPort {
         debug_notify();
     }
 
+    /// Read a received byte, if any, without passing it on.
+    #[cfg(feature = "debugger")]
+    pub fn receive_byte(&mut self) -> Option<u8> {
+        if self.line_sts().contains(UartFrFlags::RXFE) {
+            None
+        } else {
+            Some(self.read_reg(self.data_reg) as u8)
+        }
+    }
+
     pub fn send(&mut self, data: u8) {
         while !self.line_sts().contains(UartFrFlags::TXFE) {}
         self.write_reg(self.data_reg, data as u32);
@@ -0,0 +1,222 @@
+//! Frequency scaling through the performance domain management protocol of SCMI, the System
+//! Control and Management Interface of the firmware, using the SMC transport described by an
+//! `arm,scmi-smc` devicetree node. Each CPU node refers to its performance domain with its
+//! `clocks` property. Messages are sent through a single shared memory channel, and completion
+//! is polled for. PSCI has no frequency control, so without SCMI there is no driver.
+
+use alloc::vec::Vec;
+use byteorder::{ByteOrder, BE};
+use core::{arch::asm, hint, ptr};
+use fdt::Fdt;
+use spin::Once;
+
+use super::smp::{mpidr, MPIDR_AFFINITY_MASK};
+use crate::{
+    cpufreq::{CpufreqDriver, EnergyBias},
+    memory::map_device_memory,
+    paging::PhysicalAddress,
+    sync::Mutex,
+};
+
+const PROTOCOL_PERF: u32 = 0x13;
+const PERFORMANCE_DESCRIBE_LEVELS: u32 = 0x4;
+const PERFORMANCE_LEVEL_SET: u32 = 0x7;
+
+/// Offsets in the shared memory channel.
+const SHMEM_STATUS: usize = 0x04;
+const SHMEM_FLAGS: usize = 0x10;
+const SHMEM_LENGTH: usize = 0x14;
+const SHMEM_HEADER: usize = 0x18;
+const SHMEM_PAYLOAD: usize = 0x1c;
+
+const STATUS_FREE: u32 = 1 << 0;
+const STATUS_ERROR: u32 = 1 << 1;
+
+/// Words of each performance level returned by PERFORMANCE_DESCRIBE_LEVELS, before version 4 of
+/// the protocol: the level, its power cost and its attributes.
+const LEVEL_WORDS: usize = 3;
+
+struct Cpu {
+    mpidr: usize,
+    domain: u32,
+    min: u32,
+    max: u32,
+}
+
+struct Scmi {
+    smc_id: u32,
+    /// Kernel address of the shared memory channel, locked for the duration of a message.
+    channel: Mutex<usize>,
+    cpus: Vec<Cpu>,
+}
+
+static SCMI: Once<Scmi> = Once::new();
+
+pub fn driver() -> Option<&'static dyn CpufreqDriver> {
+    SCMI.get()
+        .filter(|scmi| !scmi.cpus.is_empty())
+        .map(|scmi| scmi as &dyn CpufreqDriver)
+}
+
+impl Scmi {
+    /// Send a message of the performance protocol, and read the response into `response`,
+    /// returning its length in words if the status is success.
+    unsafe fn call(&self, message_id: u32, payload: &[u32], response: &mut [u32]) -> Option<usize> {
+        let channel = self.channel.lock();
+        let reg = |offset: usize| (*channel + offset) as *mut u32;
+
+        // Messages are serialized by the lock, so the channel is free.
+        ptr::write_volatile(reg(SHMEM_FLAGS), 0);
+        ptr::write_volatile(reg(SHMEM_LENGTH), 4 + 4 * payload.len() as u32);
+        ptr::write_volatile(reg(SHMEM_HEADER), PROTOCOL_PERF << 10 | message_id);
+        for (i, &word) in payload.iter().enumerate() {
+            ptr::write_volatile(reg(SHMEM_PAYLOAD + 4 * i), word);
+        }
+        ptr::write_volatile(reg(SHMEM_STATUS), 0);
+
+        asm!("dsb sy");
+        asm!(
+            "smc #0",
+            inlateout("x0") self.smc_id as usize => _,
+            lateout("x1") _,
+            lateout("x2") _,
+            lateout("x3") _,
+            options(nostack),
+        );
+
+        let mut status;
+        loop {
+            status = ptr::read_volatile(reg(SHMEM_STATUS));
+            if status & STATUS_FREE != 0 {
+                break;
+            }
+            hint::spin_loop();
+        }
+        if status & STATUS_ERROR != 0 {
+            return None;
+        }
+
+        let len = (ptr::read_volatile(reg(SHMEM_LENGTH)) as usize).saturating_sub(4) / 4;
+        for (i, word) in response.iter_mut().enumerate().take(len) {
+            *word = ptr::read_volatile(reg(SHMEM_PAYLOAD + 4 * i));
+        }
+        // The first word is the status.
+        (len > 0 && response[0] == 0).then_some(len)
+    }
+
+    /// The lowest and highest performance level of `domain`.
+    unsafe fn domain_limits(&self, domain: u32) -> Option<(u32, u32)> {
+        let mut limits: Option<(u32, u32)> = None;
+        let mut index = 0;
+        loop {
+            let mut response = [0; 2 + 16 * LEVEL_WORDS];
+            let len = self.call(PERFORMANCE_DESCRIBE_LEVELS, &[domain, index], &mut response)?;
+            let returned = response[1] & 0xfff;
+            let remaining = response[1] >> 16;
+            for level in response[2..len.min(response.len())]
+                .chunks_exact(LEVEL_WORDS)
+                .take(returned as usize)
+                .map(|entry| entry[0])
+            {
+                limits = Some(limits.map_or((level, level), |(min, max)| {
+                    (min.min(level), max.max(level))
+                }));
+            }
+            index += returned;
+            if remaining == 0 || returned == 0 {
+                return limits;
+            }
+        }
+    }
+
+    fn current_cpu(&self) -> Option<&Cpu> {
+        let mpidr = mpidr();
+        self.cpus.iter().find(|cpu| cpu.mpidr == mpidr)
+    }
+}
+
+impl CpufreqDriver for Scmi {
+    fn name(&self) -> &'static str {
+        "scmi"
+    }
+    fn limits(&self) -> (u32, u32) {
+        self.current_cpu().map_or((0, 0), |cpu| (cpu.min, cpu.max))
+    }
+    fn frequency(&self, _level: u32) -> Option<u32> {
+        None
+    }
+    fn set_bias(&self, _bias: EnergyBias) {}
+    fn set_level(&self, level: u32) {
+        if let Some(cpu) = self.current_cpu() {
+            unsafe {
+                let _ = self.call(PERFORMANCE_LEVEL_SET, &[cpu.domain, level], &mut [0]);
+            }
+        }
+    }
+}
+
+/// Find the SCMI channel and the performance domains of the CPUs in the devicetree.
+pub unsafe fn init(fdt: &Fdt) {
+    let Some(node) = fdt.find_compatible(&["arm,scmi-smc"]) else {
+        return;
+    };
+    let Some(smc_id) = node.property("arm,smc-id").and_then(|p| p.as_usize()) else {
+        return;
+    };
+    let Some(shmem) = node
+        .property("shmem")
+        .and_then(|p| p.as_usize())
+        .and_then(|phandle| fdt.find_phandle(phandle as u32))
+        .and_then(|shmem| shmem.reg()?.next())
+    else {
+        log::warn!("SCMI: no shared memory channel");
+        return;
+    };
+    let Some(perf_phandle) = node
+        .children()
+        .find(|child| child.property("reg").and_then(|p| p.as_usize()) == Some(0x13))
+        .and_then(|perf| perf.property("phandle"))
+        .and_then(|p| p.as_usize())
+    else {
+        return;
+    };
+
+    let channel = map_device_memory(
+        PhysicalAddress::new(shmem.starting_address as usize),
+        shmem.size.unwrap_or(0x80),
+    );
+    let mut scmi = Scmi {
+        smc_id: smc_id as u32,
+        channel: Mutex::new(channel.data()),
+        cpus: Vec::new(),
+    };
+
+    for cpu in fdt
+        .find_node("/cpus")
+        .into_iter()
+        .flat_map(|cpus| cpus.children())
+    {
+        let (Some(mpidr), Some(clocks)) = (
+            cpu.property("reg").and_then(|p| p.as_usize()),
+            cpu.property("clocks"),
+        ) else {
+            continue;
+        };
+        if clocks.value.len() < 8 || BE::read_u32(clocks.value) as usize != perf_phandle {
+            continue;
+        }
+        let domain = BE::read_u32(&clocks.value[4..]);
+        match scmi.domain_limits(domain) {
+            Some((min, max)) => scmi.cpus.push(Cpu {
+                mpidr: mpidr & MPIDR_AFFINITY_MASK,
+                domain,
+                min,
+                max,
+            }),
+            None => log::warn!("SCMI: no performance levels for domain {}", domain),
+        }
+    }
+
+    log::info!("SCMI: {} CPUs with performance domains", scmi.cpus.len());
+    SCMI.call_once(|| scmi);
+}
@@ -0,0 +1,181 @@
+//! Kernel entry point, which leaves EL2 if the bootloader did not already drop to EL1.
+//!
+//! When entered at EL2, the hypervisor configuration is reset so that EL1 runs unrestricted AArch64
+//! code, with access to the physical counter and timer, to the GICv3 system registers, to all
+//! performance counters, and without FP/SIMD, SVE or pointer authentication traps. A minimal stub
+//! vector table stays installed at EL2, so that a hypervisor can later be loaded by replacing it.
+//! Only `HVC #0` with `x0 = HVC_SET_VECTORS` and the new VBAR_EL2 value in `x1` is handled; other
+//! calls return `!0` in `x0`.
+//!
+//! The translation regime is left alone. If the bootloader entered with VHE enabled
+//! (HCR_EL2.E2H), which is required to run a higher-half kernel at EL2, the EL1 names of the
+//! translation registers refer to EL2 registers, so their values are copied to the real EL1
+//! registers before returning to EL1.
+
+use core::sync::atomic::AtomicBool;
+
+/// Set if the kernel was entered at EL2, and the stub vectors are installed.
+pub static ENTERED_AT_EL2: AtomicBool = AtomicBool::new(false);
+
+/// Function ID of the stub vector call that replaces VBAR_EL2.
+pub const HVC_SET_VECTORS: usize = 0;
+
+const HCR_EL2_RW: usize = 1 << 31;
+const HCR_EL2_E2H: usize = 1 << 34;
+/// APK and API, not trapping the pointer authentication keys and instructions.
+const HCR_EL2_PAUTH: usize = (1 << 40) | (1 << 41);
+/// EL1PCTEN and EL1PCEN, in both the E2H=0 (bits 0-1) and E2H=1 (bits 10-11) layouts.
+const CNTHCTL_EL2_EL1_ACCESS: usize = 0b11 | (0b11 << 10);
+/// RES1 bits of CPTR_EL2 with all trap bits clear.
+const CPTR_EL2_NO_TRAPS: usize = 0x33FF;
+/// CPTR_EL2.TZ, trapping SVE if it is implemented, and RES1 otherwise.
+const CPTR_EL2_TZ: usize = 1 << 8;
+/// ZCR_EL2.LEN not limiting the SVE vector length of EL1.
+const ZCR_EL2_LEN_MAX: usize = 0xF;
+/// SRE and Enable, giving EL1 access to the GICv3 system registers.
+const ICC_SRE_EL2_SRE_ENABLE: usize = 0b1001;
+/// EL1h with D, A, I and F masked.
+pub(super) const SPSR_EL1H_MASKED: usize = 0x3C5;
+
+core::arch::global_asm!(
+    "
+.globl kstart
+kstart:
+    mrs     x1, CurrentEL
+    lsr     x1, x1, #2
+    cmp     x1, #2
+    b.eq    1f
+    b       {kstart_el1}
+
+1:
+    mrs     x9, hcr_el2
+    and     x9, x9, #{hcr_e2h}
+    cbz     x9, 2f
+
+    // SCTLR_EL12, TCR_EL12, MAIR_EL12, TTBR0_EL12 and TTBR1_EL12, by encoding to not depend on
+    // assembler support for VHE.
+    mrs     x10, sctlr_el1
+    msr     s3_5_c1_c0_0, x10
+    mrs     x10, tcr_el1
+    msr     s3_5_c2_c0_2, x10
+    mrs     x10, mair_el1
+    msr     s3_5_c10_c2_0, x10
+    mrs     x10, ttbr0_el1
+    msr     s3_5_c2_c0_0, x10
+    mrs     x10, ttbr1_el1
+    msr     s3_5_c2_c0_1, x10
+
+2:
+    bl      el2_init
+
+    mov     x9, sp
+    msr     sp_el1, x9
+    adrp    x9, {kstart_el1}
+    add     x9, x9, :lo12:{kstart_el1}
+    msr     elr_el2, x9
+    mov     x9, #{spsr}
+    msr     spsr_el2, x9
+    eret
+
+// Configure EL2 to run EL1 unrestricted, and install the stub vectors. Also used by secondary
+// CPUs, which may start at EL2 with the MMU off. Clobbers x9 and x10.
+.globl el2_init
+el2_init:
+    mrs     x9, hcr_el2
+    and     x9, x9, #{hcr_e2h}
+    orr     x9, x9, #{hcr_rw}
+    orr     x9, x9, #{hcr_pauth}
+    msr     hcr_el2, x9
+
+    mrs     x9, cnthctl_el2
+    mov     x10, #{cnthctl}
+    orr     x9, x9, x10
+    msr     cnthctl_el2, x9
+    msr     cntvoff_el2, xzr
+
+    mov     x9, #{cptr}
+    mrs     x10, id_aa64pfr0_el1
+    ubfx    x10, x10, #32, #4
+    cbz     x10, 6f
+    bic     x9, x9, #{cptr_tz}
+    msr     cptr_el2, x9
+    isb
+    // ZCR_EL2, by encoding to not depend on assembler support for SVE.
+    mov     x10, #{zcr_len_max}
+    msr     s3_4_c1_c2_0, x10
+6:
+    msr     cptr_el2, x9
+    msr     hstr_el2, xzr
+    msr     vttbr_el2, xzr
+
+    mrs     x9, midr_el1
+    msr     vpidr_el2, x9
+    mrs     x9, mpidr_el1
+    msr     vmpidr_el2, x9
+
+    // With a GICv3, EL1 may only use the system register CPU interface once EL2 allows it.
+    mrs     x9, id_aa64pfr0_el1
+    ubfx    x9, x9, #24, #4
+    cbz     x9, 4f
+    mrs     x9, icc_sre_el2
+    orr     x9, x9, #{icc_sre_el2}
+    msr     icc_sre_el2, x9
+    isb
+    msr     ich_hcr_el2, xzr
+4:
+    // Leave every event counter of the PMU, if any, to EL1, without trapping its registers.
+    mov     x10, xzr
+    mrs     x9, id_aa64dfr0_el1
+    ubfx    x9, x9, #8, #4
+    cbz     x9, 5f
+    mrs     x10, pmcr_el0
+    ubfx    x10, x10, #11, #5
+5:
+    msr     mdcr_el2, x10
+
+    adr     x9, el2_stub_vectors
+    msr     vbar_el2, x9
+    ret
+
+    .align 11
+el2_stub_vectors:
+    // Current EL with SP0, and current EL with SPx: the stub never runs at EL2.
+    .rept 8
+    .align 7
+    b       .
+    .endr
+
+    // Lower EL using AArch64, synchronous
+    .align 7
+    mrs     x9, esr_el2
+    lsr     x9, x9, #26
+    cmp     x9, #0x16
+    b.ne    3f
+    cmp     x0, #{hvc_set_vectors}
+    b.ne    3f
+    msr     vbar_el2, x1
+    mov     x0, xzr
+    eret
+3:
+    mvn     x0, xzr
+    eret
+
+    // Lower EL using AArch64, IRQ, FIQ and SError, and lower EL using AArch32. Nothing is routed
+    // to EL2.
+    .rept 7
+    .align 7
+    eret
+    .endr
+    ",
+    kstart_el1 = sym super::start::kstart_el1,
+    hcr_e2h = const HCR_EL2_E2H,
+    hcr_rw = const HCR_EL2_RW,
+    hcr_pauth = const HCR_EL2_PAUTH,
+    cnthctl = const CNTHCTL_EL2_EL1_ACCESS,
+    cptr = const CPTR_EL2_NO_TRAPS,
+    cptr_tz = const CPTR_EL2_TZ,
+    zcr_len_max = const ZCR_EL2_LEN_MAX,
+    icc_sre_el2 = const ICC_SRE_EL2_SRE_ENABLE,
+    spsr = const SPSR_EL1H_MASKED,
+    hvc_set_vectors = const HVC_SET_VECTORS,
+);
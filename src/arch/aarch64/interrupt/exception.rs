@@ -2,9 +2,9 @@ use rmm::VirtualAddress;
 
 use crate::{
     exception_stack,
-    memory::{ArchIntCtx, GenericPfFlags},
+    memory::{ArchIntCtx, FaultSignal, GenericPfFlags},
     panic::stack_trace,
-    syscall,
+    ptrace, syscall,
     syscall::flag::*,
 };
 
@@ -40,6 +40,13 @@ unsafe fn instr_data_abort_inner(
     let fsc = iss & 0x3F;
     //dbg!(fsc);
 
+    // A synchronous tag check fault, on memory that is mapped, delivered as SIGBUS for userspace
+    // to tell it apart from invalid accesses.
+    if from_user && !instr_not_data && fsc == 0b010001 {
+        crate::ksignal(SIGBUS);
+        return true;
+    }
+
     let was_translation_fault = fsc >= 0b000100 && fsc <= 0b000111;
     //let was_permission_fault = fsc >= 0b001101 && fsc <= 0b001111;
     let write_not_read_if_data = iss & (1 << 6) != 0;
@@ -56,10 +63,24 @@ unsafe fn instr_data_abort_inner(
     flags.set(GenericPfFlags::INSTR_NOT_DATA, instr_not_data);
     flags.set(GenericPfFlags::USER_NOT_SUPERVISOR, from_user);
 
-    let faulting_addr = VirtualAddress::new(far_el1());
+    let mut far = far_el1();
+    // The top byte of user addresses is ignored with tagged memory, and holds the tag.
+    if from_user && crate::mte::enabled() {
+        far &= (1 << 56) - 1;
+    }
+    let faulting_addr = VirtualAddress::new(far);
     //dbg!(faulting_addr, flags, from);
 
-    crate::memory::page_fault_handler(stack, flags, faulting_addr).is_ok()
+    match crate::memory::page_fault_handler(stack, flags, faulting_addr) {
+        Ok(()) => true,
+        // Other signals than SIGSEGV are raised here, as the caller raises SIGSEGV for any fault
+        // it was not told was handled.
+        Err(FaultSignal(signal)) if from_user && signal != SIGSEGV => {
+            crate::ksignal(signal);
+            true
+        }
+        Err(_) => false,
+    }
 }
 
 unsafe fn cntfrq_el0() -> usize {
@@ -170,7 +191,7 @@ unsafe fn pf_inner(stack: &mut InterruptStack, ty: u8, from: &str) -> bool {
     }
 }
 
-exception_stack!(synchronous_exception_at_el0, |stack| {
+user_exception_stack!(synchronous_exception_at_el0, |stack| {
     match exception_code(stack.iret.esr_el1) {
         0b010101 => {
             let scratch = &stack.scratch;
@@ -178,6 +199,23 @@ exception_stack!(synchronous_exception_at_el0, |stack| {
                 scratch.x8, scratch.x0, scratch.x1, scratch.x2, scratch.x3, scratch.x4,
             );
             stack.scratch.x0 = ret;
+
+            crate::context::signal::syscall_exit();
+        }
+
+        // Breakpoint, software step and watchpoint exceptions, from the debug registers set up
+        // by a tracer.
+        ty @ (0b110000 | 0b110010 | 0b110100) => {
+            let stop = match ty {
+                0b110010 => PTRACE_STOP_SINGLESTEP,
+                0b110100 => ptrace::PTRACE_STOP_WATCHPOINT,
+                _ => PTRACE_STOP_BREAKPOINT,
+            };
+            if ptrace::breakpoint_callback(stop, None).is_none() {
+                println!("Debug trap");
+                stack.dump();
+                crate::ksignal(SIGTRAP);
+            }
         }
 
         ty => {
@@ -215,3 +253,17 @@ impl ArchIntCtx for InterruptStack {
         self.scratch.x0 = 1;
     }
 }
+
+#[cfg(feature = "debugger")]
+impl crate::debugger::DebuggerArch for InterruptStack {
+    fn user_stack_pointer(&self) -> usize {
+        self.iret.sp_el0
+    }
+
+    fn poll_input() -> Option<u8> {
+        crate::device::serial::COM1
+            .lock()
+            .as_mut()
+            .and_then(|serial_port| serial_port.receive_byte())
+    }
+}
@@ -123,6 +123,9 @@ impl InterruptStack {
     pub fn set_stack_pointer(&mut self, sp: usize) {
         self.iret.sp_el0 = sp;
     }
+    pub fn stack_pointer(&self) -> usize {
+        self.iret.sp_el0
+    }
     pub fn sig_archdep_reg(&self) -> usize {
         self.scratch.x0
     }
@@ -402,17 +405,104 @@ macro_rules! exception_stack {
         }
     };
 }
+/// Replace the instruction key A of userspace by the kernel one, after the registers of userspace
+/// were pushed, see `pauth`.
+#[macro_export]
+macro_rules! pauth_from_user {
+    () => {
+        "
+        adrp    x9, {pauth_enabled}
+        ldrb    w9, [x9, :lo12:{pauth_enabled}]
+        cbz     w9, 8f
+        adrp    x9, {pauth_kernel_key}
+        add     x9, x9, :lo12:{pauth_kernel_key}
+        ldp     x10, x11, [x9]
+        msr     s3_0_c2_c1_0, x10
+        msr     s3_0_c2_c1_1, x11
+        isb
+    8:
+    "
+    };
+}
+
+/// Load the instruction key A of the current context, before the registers of userspace are
+/// popped. Interrupts are masked from then on, so that the kernel signs nothing with it. ERET
+/// synchronizes the key.
+#[macro_export]
+macro_rules! pauth_to_user {
+    () => {
+        "
+        adrp    x9, {pauth_enabled}
+        ldrb    w9, [x9, :lo12:{pauth_enabled}]
+        cbz     w9, 9f
+        msr     daifset, #3
+        mrs     x9, tpidr_el1
+        movz    x10, #{pauth_user_ia_lo}
+        movk    x10, #{pauth_user_ia_hi}, lsl #16
+        add     x9, x9, x10
+        ldp     x10, x11, [x9]
+        msr     s3_0_c2_c1_0, x10
+        msr     s3_0_c2_c1_1, x11
+    9:
+    "
+    };
+}
+
+/// Like `exception_stack`, for exceptions taken from userspace, swapping the pointer
+/// authentication keys.
+#[macro_export]
+macro_rules! user_exception_stack {
+    ($name:ident, |$stack:ident| $code:block) => {
+        #[naked]
+        #[no_mangle]
+        pub unsafe extern "C" fn $name(stack: &mut $crate::arch::aarch64::interrupt::InterruptStack) {
+            unsafe extern "C" fn inner($stack: &mut $crate::arch::aarch64::interrupt::InterruptStack) {
+                $code
+            }
+            core::arch::asm!(concat!(
+                // Backup all userspace registers to stack
+                push_preserved!(),
+                push_scratch!(),
+                push_special!(),
+                pauth_from_user!(),
+
+                // Call inner function with pointer to stack
+                "mov x29, sp\n",
+                "mov x0, sp\n",
+                "bl {inner}",
+
+                // Restore all userspace registers
+                pauth_to_user!(),
+                pop_special!(),
+                pop_scratch!(),
+                pop_preserved!(),
+
+                "eret\n",
+            ),
+            inner = sym inner,
+            pauth_enabled = sym $crate::arch::aarch64::pauth::ENABLED,
+            pauth_kernel_key = sym $crate::arch::aarch64::pauth::KERNEL_KEY,
+            pauth_user_ia_lo = const $crate::arch::aarch64::pauth::USER_IA_OFFSET & 0xFFFF,
+            pauth_user_ia_hi = const $crate::arch::aarch64::pauth::USER_IA_OFFSET >> 16,
+            options(noreturn));
+        }
+    };
+}
 #[naked]
 pub unsafe extern "C" fn enter_usermode() -> ! {
     core::arch::asm!(
         concat!(
             "blr x28\n",
             // Restore all userspace registers
+            pauth_to_user!(),
             pop_special!(),
             pop_scratch!(),
             pop_preserved!(),
             "eret\n",
         ),
+        pauth_enabled = sym crate::pauth::ENABLED,
+        pauth_user_ia_lo = const crate::pauth::USER_IA_OFFSET & 0xFFFF,
+        pauth_user_ia_hi = const crate::pauth::USER_IA_OFFSET >> 16,
         options(noreturn)
     );
 }
@@ -1,4 +1,4 @@
-use crate::{arch::device::ROOT_IC_IDX, dtb::irqchip::IRQ_CHIP};
+use crate::{arch::device::ROOT_IC_IDX, dtb::irqchip::IRQ_CHIP, sync::lockdep::IrqScope};
 use core::sync::atomic::Ordering;
 
 unsafe fn irq_ack() -> (u32, Option<usize>) {
@@ -7,7 +7,8 @@ unsafe fn irq_ack() -> (u32, Option<usize>) {
     (irq, ic.irq_to_virq(irq))
 }
 
-exception_stack!(irq_at_el0, |_stack| {
+user_exception_stack!(irq_at_el0, |_stack| {
+    let _irq = IrqScope::enter();
     let (irq, virq) = irq_ack();
     if let Some(virq) = virq
         && virq < 1024
@@ -19,6 +20,7 @@ exception_stack!(irq_at_el0, |_stack| {
 });
 
 exception_stack!(irq_at_el1, |_stack| {
+    let _irq = IrqScope::enter();
     let (irq, virq) = irq_ack();
     if let Some(virq) = virq
         && virq < 1024
@@ -18,6 +18,17 @@ pub unsafe fn disable() {
     asm!("msr daifset, #2");
 }
 
+/// Whether interrupts are enabled
+#[cfg(debug_assertions)]
+#[inline(always)]
+pub fn enabled() -> bool {
+    let daif: usize;
+    unsafe {
+        asm!("mrs {}, daif", out(reg) daif);
+    }
+    daif & (1 << 7) == 0
+}
+
 /// Set interrupts and halt
 /// This will atomically wait for the next interrupt
 /// Performing enable followed by halt is not guaranteed to be atomic, use this instead!
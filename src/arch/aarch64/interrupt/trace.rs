@@ -1,28 +1,36 @@
-use core::{arch::asm, mem};
+//! Register conventions used by the unwinder in [`crate::unwind`].
 
-pub struct StackTrace {
-    pub fp: usize,
-    pub pc_ptr: *const usize,
-}
+use core::arch::asm;
 
-impl StackTrace {
-    #[inline(always)]
-    pub unsafe fn start() -> Option<Self> {
-        let fp: usize;
-        asm!("mov {}, fp", out(reg) fp);
-        let pc_ptr = fp.checked_add(mem::size_of::<usize>())?;
-        Some(StackTrace {
-            fp,
-            pc_ptr: pc_ptr as *const usize,
-        })
-    }
+use crate::unwind::FrameRecord;
+
+/// Number of DWARF registers tracked by the unwinder, x0 to x30 and sp.
+pub const DWARF_REG_COUNT: usize = 32;
+pub const DWARF_SP: u16 = 31;
+pub const DWARF_FP: u16 = 29;
+pub const DWARF_RA: u16 = 30;
+
+/// The frame pointer points at a frame record of the saved frame pointer and link register.
+pub const FRAME_RECORD: FrameRecord = FrameRecord {
+    saved_fp: 0,
+    ra: 8,
+    cfa: 16,
+};
 
-    pub unsafe fn next(self) -> Option<Self> {
-        let fp = *(self.fp as *const usize);
-        let pc_ptr = fp.checked_add(mem::size_of::<usize>())?;
-        Some(StackTrace {
-            fp: fp,
-            pc_ptr: pc_ptr as *const usize,
-        })
+/// Get the instruction, stack and frame pointers at the point this is inlined into.
+#[inline(always)]
+pub fn current_regs() -> (usize, usize, usize) {
+    let (pc, sp, fp): (usize, usize, usize);
+    unsafe {
+        asm!(
+            "adr {pc}, .",
+            "mov {sp}, sp",
+            "mov {fp}, x29",
+            pc = out(reg) pc,
+            sp = out(reg) sp,
+            fp = out(reg) fp,
+            options(nomem, nostack),
+        );
     }
+    (pc, sp, fp)
 }
@@ -1,8 +1,24 @@
+//! Inter-processor interrupts, sent as SGIs of a GICv3. Other interrupt controllers cannot send
+//! them, and the IPIs are dropped.
+
+use crate::dtb::irqchip::InterruptHandler;
+
+/// The kinds of IPIs, numbered by the SGI carrying them.
 #[derive(Clone, Copy, Debug)]
 #[repr(u8)]
 pub enum IpiKind {
-    Wakeup = 0x40,
-    Tlb = 0x41,
+    Wakeup = 0,
+    Tlb = 1,
+    Switch = 2,
+}
+
+impl IpiKind {
+    /// All kinds, with their names in the IRQ statistics.
+    pub const ALL: [(Self, &'static str); 3] = [
+        (Self::Wakeup, "ipi wakeup"),
+        (Self::Tlb, "ipi tlb shootdown"),
+        (Self::Switch, "ipi switch"),
+    ];
 }
 
 #[derive(Clone, Copy, Debug)]
@@ -17,7 +33,11 @@ pub fn ipi(_kind: IpiKind, _target: IpiTarget) {}
 
 #[cfg(feature = "multi_core")]
 #[inline(always)]
-pub fn ipi(_kind: IpiKind, _target: IpiTarget) {}
+pub fn ipi(kind: IpiKind, target: IpiTarget) {
+    match target {
+        IpiTarget::Other => super::device::irqchip::gicv3::send_sgi(kind as u8, None),
+    }
+}
 
 #[cfg(not(feature = "multi_core"))]
 #[inline(always)]
@@ -25,4 +45,35 @@ pub fn ipi_single(_kind: IpiKind, _target: crate::cpu_set::LogicalCpuId) {}
 
 #[cfg(feature = "multi_core")]
 #[inline(always)]
-pub fn ipi_single(_kind: IpiKind, _target: crate::cpu_set::LogicalCpuId) {}
+pub fn ipi_single(kind: IpiKind, target: crate::cpu_set::LogicalCpuId) {
+    use core::sync::atomic::Ordering;
+
+    let Some(percpu) = crate::percpu::get(target) else {
+        return;
+    };
+    match percpu.misc_arch_info.sgi_target.load(Ordering::Relaxed) {
+        0 => (),
+        sgi_target => super::device::irqchip::gicv3::send_sgi(kind as u8, Some(sgi_target)),
+    }
+}
+
+// No NMIs, so stuck CPUs cannot be interrupted.
+#[cfg(debug_assertions)]
+pub fn ipi_nmi(_target: crate::cpu_set::LogicalCpuId) {}
+
+/// Handles the SGI of an IPI.
+pub struct IpiHandler(pub IpiKind);
+
+impl InterruptHandler for IpiHandler {
+    fn irq_handler(&mut self, irq: u32) {
+        if let IpiKind::Tlb = self.0 {
+            crate::percpu::PercpuBlock::current().maybe_handle_tlb_shootdown();
+        }
+        unsafe {
+            crate::dtb::irqchip::IRQ_CHIP.irq_eoi(irq);
+        }
+        if let IpiKind::Switch = self.0 {
+            let _ = crate::context::switch();
+        }
+    }
+}
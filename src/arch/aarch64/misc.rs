@@ -18,4 +18,54 @@ pub unsafe fn init(cpu_id: LogicalCpuId) {
     virt.write(PercpuBlock::init(cpu_id));
 
     crate::device::cpu::registers::control_regs::tpidr_el1_write(virt as u64);
+
+    if has_pan() {
+        // PAN (Privileged Access Never) forbids the kernel from accessing any
+        // userspace-accessible pages, except with the unprivileged loads and stores of the
+        // usercopy functions. Clearing SCTLR_EL1.SPAN keeps it set on every exception to EL1.
+        let sctlr: u64;
+        core::arch::asm!("mrs {}, sctlr_el1", out(reg) sctlr);
+        core::arch::asm!("msr sctlr_el1, {}", "isb", in(reg) sctlr & !SCTLR_SPAN);
+        // msr pan, #1
+        core::arch::asm!(".inst 0xd500419f");
+    }
+
+    super::sve::init();
+    super::mte::init();
+}
+
+/// SCTLR_EL1.SPAN, set if PAN is left unchanged on exceptions to EL1.
+const SCTLR_SPAN: u64 = 1 << 23;
+
+/// Whether ID_AA64MMFR1_EL1.PAN reports PAN as implemented.
+fn has_pan() -> bool {
+    let mmfr1: u64;
+    unsafe { core::arch::asm!("mrs {}, id_aa64mmfr1_el1", out(reg) mmfr1) };
+    (mmfr1 >> 20) & 0xF != 0
 }
+
+/// Make instructions written through `[start, start + len)` visible to instruction fetches from
+/// any alias of that memory, on every CPU: the data cache is cleaned to the point of unification,
+/// unless CTR_EL0.IDC makes it unnecessary, and the instruction caches are invalidated, unless
+/// CTR_EL0.DIC does.
+pub unsafe fn sync_icache(start: usize, len: usize) {
+    let ctr: usize;
+    core::arch::asm!("mrs {}, ctr_el0", out(reg) ctr);
+
+    if ctr & CTR_IDC == 0 {
+        let line = 4 << ((ctr >> 16) & 0xF);
+        let mut addr = start & !(line - 1);
+        while addr < start + len {
+            core::arch::asm!("dc cvau, {}", in(reg) addr);
+            addr += line;
+        }
+    }
+    core::arch::asm!("dsb ish");
+    if ctr & CTR_DIC == 0 {
+        core::arch::asm!("ic ialluis", "dsb ish");
+    }
+    core::arch::asm!("isb");
+}
+
+const CTR_IDC: usize = 1 << 28;
+const CTR_DIC: usize = 1 << 29;
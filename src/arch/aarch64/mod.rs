@@ -4,12 +4,24 @@ pub mod macros;
 /// Constants like memory locations
 pub mod consts;
 
+/// Idle states
+pub mod cpuidle;
+
 /// Debugging support
 pub mod debug;
 
+/// Hardware breakpoints and watchpoints
+pub mod debug_regs;
+
+/// CPU frequency scaling
+pub mod dvfs;
+
 /// Devices
 pub mod device;
 
+/// Kernel entry and EL2 handling
+pub mod el2;
+
 /// Interrupt instructions
 pub mod interrupt;
 
@@ -19,25 +31,53 @@ pub mod ipi;
 /// Miscellaneous
 pub mod misc;
 
+/// Memory Tagging Extension
+pub mod mte;
+
 /// Paging
 pub mod paging;
 
+/// Pointer authentication and BTI
+pub mod pauth;
+
+/// Performance monitoring counters
+pub mod pmu;
+
 pub mod rmm;
 
+/// Hardware random numbers
+pub mod rng;
+
+/// Suspend to RAM
+pub mod sleep;
+
+/// Secondary CPU startup
+pub mod smp;
+
 /// Initialization and start function
 pub mod start;
 
+/// Speculative execution controls
+pub mod speculation;
+
 /// Stop function
 pub mod stop;
 
+/// Scalable Vector Extension
+pub mod sve;
+
 // Interrupt vectors
 pub mod vectors;
 
 pub mod time;
 
+#[cfg(any(feature = "aarch64_16k_pages", feature = "aarch64_64k_pages"))]
+pub use self::rmm::AArch64GranuleArch as CurrentRmmArch;
+#[cfg(not(any(feature = "aarch64_16k_pages", feature = "aarch64_64k_pages")))]
 pub use ::rmm::AArch64Arch as CurrentRmmArch;
 
-pub use arch_copy_to_user as arch_copy_from_user;
+// The user side of copies uses the unprivileged loads and stores, which are checked against the
+// EL0 permissions and thus allowed while PAN forbids the kernel from accessing user memory.
 
 #[naked]
 #[link_section = ".usercopy-fns"]
@@ -52,6 +92,33 @@ pub unsafe extern "C" fn arch_copy_to_user(dst: usize, src: usize, len: usize) -
         b.eq 3f
 
         ldrb w3, [x1]
+        sttrb w3, [x4]
+
+        add x4, x4, 1
+        add x1, x1, 1
+        sub x2, x2, 1
+
+        b 2b
+    3:
+        ret
+    ",
+        options(noreturn)
+    );
+}
+
+#[naked]
+#[link_section = ".usercopy-fns"]
+pub unsafe extern "C" fn arch_copy_from_user(dst: usize, src: usize, len: usize) -> u8 {
+    // x0, x1, x2
+    core::arch::asm!(
+        "
+        mov x4, x0
+        mov x0, 0
+    2:
+        cmp x2, 0
+        b.eq 3f
+
+        ldtrb w3, [x1]
         strb w3, [x4]
 
         add x4, x4, 1
@@ -68,7 +135,7 @@ pub unsafe extern "C" fn arch_copy_to_user(dst: usize, src: usize, len: usize) -
 
 pub const KFX_SIZE: usize = 1024;
 
-// This function exists as the KFX size is dynamic on x86_64.
+// The SVE registers, if used, are saved after the FP/SIMD ones, with a size only known at boot.
 pub fn kfx_size() -> usize {
-    KFX_SIZE
+    KFX_SIZE + sve::state_size()
 }
@@ -0,0 +1,241 @@
+//! The Memory Tagging Extension, which gives every 16 bytes of memory mapped as tagged a 4-bit tag,
+//! checked against the top byte of the pointers accessing it.
+//!
+//! Grants are only mapped as tagged once their address space asks for it through
+//! [`ADDRSPACE_OP_MTE`](crate::context::memory::ADDRSPACE_OP_MTE), the equivalent of `PROT_MTE`,
+//! and which private anonymous grants support. Userspace then sets and reads the tags itself, with
+//! the IRG, STG and LDG instructions, and pointers it passes to the kernel must be untagged. Tag
+//! checks are enabled per context through its `mte` handle in `proc:`, with the excluded tags
+//! IRG does not generate, and a failed check is delivered as SIGBUS rather than SIGSEGV. They are
+//! reset when executing a new program.
+//!
+//! The linear mapping of physical memory is tagged as well, for the kernel to zero the tags of
+//! every user frame when it is allocated, and copy them along with frames copied on write. The
+//! kernel itself never checks tags. PSTATE.TCO is set on every exception, which suppresses checks
+//! of the unprivileged accesses of the usercopy functions too, and the TCO of userspace is saved
+//! and restored in SPSR_EL1, with the interrupt frame and on context switches.
+
+use core::arch::asm;
+
+use spin::Once;
+
+use crate::{
+    entropy,
+    memory::{the_zeroed_frame, Frame},
+    paging::{entry::EntryFlags, PageFlags, RmmA, RmmArch, PAGE_SIZE},
+    percpu::PercpuBlock,
+    syscall::error::{Error, Result, EINVAL, EOPNOTSUPP},
+};
+
+/// SCTLR_EL1.ATA and ATA0, allowing access to tags at EL1 and EL0.
+const SCTLR_ATA: u64 = (1 << 43) | (1 << 42);
+/// SCTLR_EL1.TCF, for tag checks at EL1, left cleared.
+const SCTLR_TCF: u64 = 0b11 << 40;
+/// SCTLR_EL1.TCF0, for tag checks at EL0.
+const SCTLR_TCF0: u64 = 0b11 << 38;
+/// TCF0 value for synchronous tag check faults.
+const SCTLR_TCF0_SYNC: u64 = 0b01 << 38;
+/// TCR_EL1.TBI0, ignoring the top byte of user addresses during translation.
+const TCR_TBI0: u64 = 1 << 37;
+/// AttrIndx of page table entries.
+const ATTR_INDEX: usize = 0b111 << 2;
+
+/// Tag checks disabled, the default.
+pub const MTE_TCF_NONE: usize = 0;
+/// Tag checks enabled, with a synchronous fault on the instruction that failed them.
+pub const MTE_TCF_SYNC: usize = 1;
+
+/// Whether tagged memory is used, or None before the BSP is initialized.
+static ENABLED: Once<bool> = Once::new();
+
+/// Whether ID_AA64PFR1_EL1.MTE reports MTE with tag storage (FEAT_MTE2) as implemented.
+pub fn implemented() -> bool {
+    let pfr1: u64;
+    unsafe { asm!("mrs {}, id_aa64pfr1_el1", out(reg) pfr1) };
+    (pfr1 >> 8) & 0xF >= 2
+}
+
+pub fn enabled() -> bool {
+    ENABLED.get().copied().unwrap_or(false)
+}
+
+/// The flags of the linear mapping of physical memory, tagged where MTE is implemented.
+pub fn linear_flags<A: RmmArch>(flags: PageFlags<A>) -> PageFlags<A> {
+    flags.custom_flag(EntryFlags::TAGGED.bits(), implemented())
+}
+
+/// Map `flags` as tagged memory if `tagged` is set.
+pub fn tagged_flags(flags: PageFlags<RmmA>, tagged: bool) -> PageFlags<RmmA> {
+    flags.custom_flag(EntryFlags::TAGGED.bits(), tagged)
+}
+
+pub fn is_tagged(flags: PageFlags<RmmA>) -> bool {
+    flags.data() & ATTR_INDEX == EntryFlags::TAGGED.bits()
+}
+
+/// Enable tagged memory on this CPU, using it if the BSP does. Called again when a CPU is powered
+/// on after suspending.
+pub unsafe fn init() {
+    let bsp_enabled = ENABLED.get().copied();
+    if bsp_enabled == Some(false) {
+        return;
+    }
+    if !implemented() {
+        if bsp_enabled.is_some() {
+            log::warn!("MTE is not implemented by this CPU, unlike the BSP");
+        }
+        ENABLED.call_once(|| false);
+        return;
+    }
+
+    let (sctlr, tcr): (u64, u64);
+    asm!("mrs {}, sctlr_el1", out(reg) sctlr);
+    asm!("mrs {}, tcr_el1", out(reg) tcr);
+    asm!(
+        "msr sctlr_el1, {}",
+        "msr tcr_el1, {}",
+        "isb",
+        "tlbi vmalle1",
+        "dsb nsh",
+        "isb",
+        in(reg) (sctlr | SCTLR_ATA) & !(SCTLR_TCF | SCTLR_TCF0),
+        in(reg) tcr | TCR_TBI0,
+    );
+    // GCR_EL1 excluding no tag, and RGSR_EL1 with a nonzero seed for IRG.
+    let seed = (entropy::random_u64() & 0xFFFF) | 1;
+    asm!(
+        "msr s3_0_c1_c0_6, xzr",
+        "msr s3_0_c1_c0_5, {}",
+        "isb",
+        in(reg) seed << 8,
+    );
+    PercpuBlock::current()
+        .misc_arch_info
+        .mte
+        .set(MteState::default());
+
+    if bsp_enabled.is_none() {
+        ENABLED.call_once(|| true);
+        // Tags are unknown at reset, and the zeroed frame is mapped in tagged grants.
+        zero_tags(the_zeroed_frame().0);
+        log::info!("Memory tagging enabled");
+    }
+}
+
+/// Size in bytes of the memory whose tags LDGM and STGM access, from GMID_EL1.BS.
+fn tag_block_size() -> usize {
+    let gmid: u64;
+    unsafe { asm!("mrs {}, s3_1_c0_c0_4", out(reg) gmid) };
+    4 << (gmid & 0xF)
+}
+
+/// Zero the tags of `frame`, if tagged memory is used.
+pub fn zero_tags(frame: Frame) {
+    if enabled() {
+        unsafe { zero_tags_inner(frame) }
+    }
+}
+
+#[target_feature(enable = "mte")]
+unsafe fn zero_tags_inner(frame: Frame) {
+    let base = RmmA::phys_to_virt(frame.base()).data();
+    for addr in (base..base + PAGE_SIZE).step_by(tag_block_size()) {
+        asm!("stgm xzr, [{}]", in(reg) addr);
+    }
+}
+
+/// Copy the tags of `src` to `dst`, if tagged memory is used.
+pub fn copy_tags(dst: Frame, src: Frame) {
+    if enabled() {
+        unsafe { copy_tags_inner(dst, src) }
+    }
+}
+
+#[target_feature(enable = "mte")]
+unsafe fn copy_tags_inner(dst: Frame, src: Frame) {
+    let dst = RmmA::phys_to_virt(dst.base()).data();
+    let src = RmmA::phys_to_virt(src.base()).data();
+    for offset in (0..PAGE_SIZE).step_by(tag_block_size()) {
+        asm!(
+            "ldgm {tags}, [{src}]",
+            "stgm {tags}, [{dst}]",
+            tags = out(reg) _,
+            src = in(reg) src + offset,
+            dst = in(reg) dst + offset,
+        );
+    }
+}
+
+/// Tag checking of a context.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct MteState {
+    /// Whether tag check faults are raised synchronously, or tags are not checked.
+    sync: bool,
+    /// The tags IRG does not generate, one bit per tag.
+    exclude: u16,
+}
+
+impl MteState {
+    pub fn from_user(mode: usize, exclude: usize) -> Result<Self> {
+        if !enabled() {
+            return Err(Error::new(EOPNOTSUPP));
+        }
+        let sync = match mode {
+            MTE_TCF_NONE => false,
+            MTE_TCF_SYNC => true,
+            _ => return Err(Error::new(EINVAL)),
+        };
+        Ok(Self {
+            sync,
+            exclude: u16::try_from(exclude).map_err(|_| Error::new(EINVAL))?,
+        })
+    }
+    /// The mode and excluded tags.
+    pub fn to_user(self) -> [usize; 2] {
+        let mode = if self.sync {
+            MTE_TCF_SYNC
+        } else {
+            MTE_TCF_NONE
+        };
+        [mode, usize::from(self.exclude)]
+    }
+}
+
+/// Apply the tag checking of the userspace context this CPU switches to.
+pub unsafe fn switch_to(state: &MteState) {
+    if !enabled() {
+        return;
+    }
+    let percpu = &PercpuBlock::current().misc_arch_info;
+    if percpu.mte.get() != *state {
+        percpu.mte.set(*state);
+        load(state);
+    }
+}
+
+/// Enable tagged memory again, with the tag checking of the current context, after this CPU was
+/// powered off.
+pub unsafe fn resume() {
+    let state = PercpuBlock::current().misc_arch_info.mte.get();
+    init();
+    if enabled() {
+        PercpuBlock::current().misc_arch_info.mte.set(state);
+        load(&state);
+    }
+}
+
+unsafe fn load(state: &MteState) {
+    let mut sctlr: u64;
+    asm!("mrs {}, sctlr_el1", out(reg) sctlr);
+    sctlr &= !SCTLR_TCF0;
+    if state.sync {
+        sctlr |= SCTLR_TCF0_SYNC;
+    }
+    asm!(
+        "msr sctlr_el1, {}",
+        "msr s3_0_c1_c0_6, {}",
+        "isb",
+        in(reg) sctlr,
+        in(reg) u64::from(state.exclude),
+    );
+}
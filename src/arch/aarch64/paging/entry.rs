@@ -5,5 +5,9 @@ bitflags! {
     pub struct EntryFlags: usize {
         const NO_CACHE = 1 << 2;
         const DEV_MEM = 2 << 2;
+        /// Normal memory with allocation tags, see [`crate::mte`].
+        const TAGGED = 3 << 2;
+        /// Guarded page, on which indirect branches must land on BTI instructions.
+        const GUARDED = 1 << 50;
     }
 }
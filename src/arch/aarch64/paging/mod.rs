@@ -1,7 +1,7 @@
 //! # Paging
 //! Some code was borrowed from [Phil Opp's Blog](http://os.phil-opp.com/modifying-page-tables.html)
 
-use crate::device::cpu::registers::control_regs;
+use crate::{arch::consts::VIRT_ADDR_BITS, device::cpu::registers::control_regs};
 
 pub use super::CurrentRmmArch as RmmA;
 pub use rmm::{Arch as RmmArch, PageFlags, PhysicalAddress, TableKind, VirtualAddress};
@@ -11,10 +11,13 @@ pub type PageMapper = rmm::PageMapper<RmmA, crate::memory::TheFrameAllocator>;
 pub mod entry;
 pub mod mapper;
 
-/// Size of pages
+/// Size of pages, which is the translation granule
 pub const PAGE_SIZE: usize = RmmA::PAGE_SIZE;
 pub const PAGE_MASK: usize = RmmA::PAGE_OFFSET_MASK;
 
+/// Number of entries per page table
+pub const ENTRY_COUNT: usize = RmmA::PAGE_ENTRIES;
+
 /// Setup Memory Access Indirection Register
 #[cold]
 unsafe fn init_mair() {
@@ -23,13 +26,77 @@ unsafe fn init_mair() {
     val.insert(control_regs::MairEl1::DEVICE_MEMORY);
     val.insert(control_regs::MairEl1::NORMAL_UNCACHED_MEMORY);
     val.insert(control_regs::MairEl1::NORMAL_WRITEBACK_MEMORY);
+    if super::mte::implemented() {
+        val.insert(control_regs::MairEl1::NORMAL_TAGGED_MEMORY);
+    }
 
     control_regs::mair_el1_write(val);
 }
 
+/// Whether ID_AA64MMFR0_EL1 reports the granule of [`PAGE_SIZE`] as supported.
+fn granule_supported() -> bool {
+    let mmfr0: u64;
+    unsafe { core::arch::asm!("mrs {}, id_aa64mmfr0_el1", out(reg) mmfr0) };
+    // TGran4 and TGran64 read 0xF when not supported, TGran16 reads 0.
+    match PAGE_SIZE {
+        0x1000 => (mmfr0 >> 28) & 0xF != 0xF,
+        0x4000 => (mmfr0 >> 20) & 0xF != 0,
+        0x10000 => (mmfr0 >> 24) & 0xF != 0xF,
+        _ => false,
+    }
+}
+
+/// Whether ID_AA64MMFR2_EL1.VARange reports 52-bit virtual addresses as supported.
+fn lva_supported() -> bool {
+    let mmfr2: u64;
+    unsafe { core::arch::asm!("mrs {}, id_aa64mmfr2_el1", out(reg) mmfr2) };
+    (mmfr2 >> 16) & 0xF != 0
+}
+
+/// The granules TCR_EL1.TG0 and TG1 select for the user and kernel halves, in bytes, and the
+/// address bits T0SZ and T1SZ leave to them.
+fn translation_control() -> ([usize; 2], [u32; 2]) {
+    let tcr: u64;
+    unsafe { core::arch::asm!("mrs {}, tcr_el1", out(reg) tcr) };
+    let tg0 = match (tcr >> 14) & 0b11 {
+        0b01 => 0x10000,
+        0b10 => 0x4000,
+        _ => 0x1000,
+    };
+    let tg1 = match (tcr >> 30) & 0b11 {
+        0b01 => 0x4000,
+        0b11 => 0x10000,
+        _ => 0x1000,
+    };
+    let bits = |txsz: u64| 64 - (txsz & 0x3F) as u32;
+    ([tg0, tg1], [bits(tcr), bits(tcr >> 16)])
+}
+
+/// Check that the CPU supports the granule and address size the kernel was built for, and that the
+/// bootloader set translation up with them.
+fn check_granule() {
+    if !granule_supported() {
+        panic!("{} KiB translation granule not supported", PAGE_SIZE / 1024);
+    }
+    if cfg!(feature = "aarch64_lva") && !lva_supported() {
+        panic!("52-bit virtual addresses not supported");
+    }
+    let (granules, bits) = translation_control();
+    if granules != [PAGE_SIZE; 2] || bits != [VIRT_ADDR_BITS; 2] {
+        panic!(
+            "bootloader uses {:?} KiB granules and {:?} address bits, but the kernel was built for {} KiB and {}",
+            granules.map(|granule| granule / 1024),
+            bits,
+            PAGE_SIZE / 1024,
+            VIRT_ADDR_BITS
+        );
+    }
+}
+
 /// Initialize MAIR
 #[cold]
 pub unsafe fn init() {
+    check_granule();
     init_mair();
 }
 
@@ -45,8 +112,7 @@ impl Page {
     }
 
     pub fn containing_address(address: VirtualAddress) -> Page {
-        //TODO assert!(address.data() < 0x0000_8000_0000_0000 || address.data() >= 0xffff_8000_0000_0000,
-        //    "invalid address: 0x{:x}", address.data());
+        //TODO assert that the address is canonical, see consts::VIRT_ADDR_BITS
         Page {
             number: address.data() / PAGE_SIZE,
         }
@@ -0,0 +1,201 @@
+//! Pointer authentication and branch target identification.
+//!
+//! With pointer authentication (FEAT_PAuth), every userspace context has keys of its own, loaded
+//! when switching to it. They are generated for new programs when they are executed, and inherited
+//! by threads and forks. The kernel signs its return addresses with the instruction key A, where
+//! it is built with `-Z branch-protection=pac-ret` as the Makefile does, so that key is replaced by
+//! the kernel one on every exception from userspace, and by the user one again before returning.
+//! The kernel key is the same on every CPU, as contexts migrate between them, and is generated by
+//! the BSP before the entropy pool is fully seeded: it relies on the random number generator of the
+//! CPU, where there is one.
+//!
+//! Each CPU enables pointer authentication from a function that never returns, see [`init_cpu`],
+//! as functions already running would otherwise check return addresses they did not sign.
+//!
+//! With branch target identification (FEAT_BTI), executable grants of an address space are mapped
+//! as guarded pages once it is opted in through its `bti` handle in `proc:`, which loaders of
+//! programs built for it do. Indirect branches must then land on BTI instructions. The kernel text
+//! is mapped by the bootloader, and is not guarded.
+
+use core::{
+    arch::asm,
+    mem::offset_of,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use spin::Once;
+
+use crate::{device::ArchPercpuMisc, entropy, percpu::PercpuBlock, rng};
+
+/// SCTLR_EL1.EnIA, EnIB, EnDA and EnDB, enabling the instructions using each key.
+const SCTLR_EN_KEYS: u64 = (1 << 31) | (1 << 30) | (1 << 27) | (1 << 13);
+/// SCTLR_EL1.BT0. Cleared, so that PACIASP and PACIBSP are valid branch targets at EL0.
+const SCTLR_BT0: u64 = 1 << 35;
+
+/// Keys of a context, each as the low and high halves.
+#[derive(Clone, Copy, Debug, Default)]
+#[repr(C)]
+pub struct PacKeys {
+    ia: [u64; 2],
+    ib: [u64; 2],
+    da: [u64; 2],
+    db: [u64; 2],
+    ga: [u64; 2],
+}
+
+impl PacKeys {
+    pub fn generate() -> Self {
+        let mut keys = Self::default();
+        let caps = caps();
+        if caps.address || caps.generic {
+            for key in [
+                &mut keys.ia,
+                &mut keys.ib,
+                &mut keys.da,
+                &mut keys.db,
+                &mut keys.ga,
+            ] {
+                *key = [entropy::random_u64(), entropy::random_u64()];
+            }
+        }
+        keys
+    }
+}
+
+/// Offset of the instruction key A of the current context from TPIDR_EL1, for the entry code.
+pub const USER_IA_OFFSET: usize = offset_of!(PercpuBlock, misc_arch_info)
+    + offset_of!(ArchPercpuMisc, user_keys)
+    + offset_of!(PacKeys, ia);
+
+struct Caps {
+    /// Address authentication, with the A and B keys.
+    address: bool,
+    /// Generic authentication, with PACGA.
+    generic: bool,
+    bti: bool,
+}
+
+static CAPS: Once<Caps> = Once::new();
+
+fn caps() -> &'static Caps {
+    CAPS.call_once(|| {
+        let (isar1, isar2, pfr1): (u64, u64, u64);
+        unsafe {
+            asm!("mrs {}, id_aa64isar1_el1", out(reg) isar1);
+            // ID_AA64ISAR2_EL1, by encoding to not depend on assembler support for it.
+            asm!("mrs {}, s3_0_c0_c6_2", out(reg) isar2);
+            asm!("mrs {}, id_aa64pfr1_el1", out(reg) pfr1);
+        }
+        let field = |reg: u64, shift: u32| (reg >> shift) & 0xF != 0;
+        Caps {
+            // APA, API or APA3.
+            address: field(isar1, 4) || field(isar1, 8) || field(isar2, 12),
+            // GPA, GPI or GPA3.
+            generic: field(isar1, 24) || field(isar1, 28) || field(isar2, 8),
+            bti: field(pfr1, 0),
+        }
+    })
+}
+
+pub fn bti_supported() -> bool {
+    caps().bti
+}
+
+/// Set once the kernel key is generated, for the entry code and [`init_cpu`] to load it.
+pub static ENABLED: AtomicBool = AtomicBool::new(false);
+/// The instruction key A of the kernel.
+pub static mut KERNEL_KEY: [u64; 2] = [0; 2];
+
+/// Generate the kernel key, on the BSP before starting the APs.
+pub fn init() {
+    if !caps().address {
+        return;
+    }
+    entropy::add(&rng::cycles().to_le_bytes());
+    let key = [entropy::random_u64(), entropy::random_u64()];
+    unsafe {
+        KERNEL_KEY = key;
+    }
+    ENABLED.store(true, Ordering::Relaxed);
+    log::info!("Pointer authentication enabled");
+}
+
+/// Load the kernel key and enable pointer authentication on this CPU, if it is enabled. Must be
+/// called from a function that never returns, before it calls anything that is to return to it
+/// later, such as the entry functions of CPUs. Clobbers x9 to x11.
+#[naked]
+pub unsafe extern "C" fn init_cpu() {
+    asm!(
+        "
+        adrp x9, {enabled}
+        ldrb w9, [x9, :lo12:{enabled}]
+        cbz w9, 2f
+
+        adrp x9, {key}
+        add x9, x9, :lo12:{key}
+        ldp x10, x11, [x9]
+        msr s3_0_c2_c1_0, x10
+        msr s3_0_c2_c1_1, x11
+
+        mrs x10, sctlr_el1
+        movz x11, #{keys_lo}
+        movk x11, #{keys_hi}, lsl #16
+        orr x10, x10, x11
+        bic x10, x10, #{bt0}
+        msr sctlr_el1, x10
+        isb
+    2:
+        ret
+        ",
+        enabled = sym ENABLED,
+        key = sym KERNEL_KEY,
+        keys_lo = const SCTLR_EN_KEYS & 0xFFFF,
+        keys_hi = const SCTLR_EN_KEYS >> 16,
+        bt0 = const SCTLR_BT0,
+        options(noreturn)
+    );
+}
+
+/// Load the keys of the userspace context this CPU switches to, apart from the instruction key A,
+/// which is loaded before returning to userspace.
+pub unsafe fn switch_to(keys: &PacKeys) {
+    let caps = caps();
+    if !caps.address && !caps.generic {
+        return;
+    }
+    PercpuBlock::current().misc_arch_info.user_keys.set(*keys);
+    load(keys);
+}
+
+/// Load the keys of the current context again, after this CPU was powered off.
+pub unsafe fn resume() {
+    load(&PercpuBlock::current().misc_arch_info.user_keys.get());
+}
+
+unsafe fn load(keys: &PacKeys) {
+    let caps = caps();
+    if caps.address {
+        asm!(
+            "msr s3_0_c2_c1_2, {}",
+            "msr s3_0_c2_c1_3, {}",
+            "msr s3_0_c2_c2_0, {}",
+            "msr s3_0_c2_c2_1, {}",
+            "msr s3_0_c2_c2_2, {}",
+            "msr s3_0_c2_c2_3, {}",
+            in(reg) keys.ib[0],
+            in(reg) keys.ib[1],
+            in(reg) keys.da[0],
+            in(reg) keys.da[1],
+            in(reg) keys.db[0],
+            in(reg) keys.db[1],
+        );
+    }
+    if caps.generic {
+        asm!(
+            "msr s3_0_c2_c3_0, {}",
+            "msr s3_0_c2_c3_1, {}",
+            in(reg) keys.ga[0],
+            in(reg) keys.ga[1],
+        );
+    }
+}
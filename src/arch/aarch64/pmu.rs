@@ -0,0 +1,125 @@
+//! Performance Monitors Extension (PMUv3) counters, counting the events of [`crate::perf`]. The
+//! cycle counter counts cycles, and the first two event counters the other events.
+
+use core::arch::asm;
+use spin::Once;
+
+use crate::perf::{PerfCounts, PerfEvent};
+
+/// Enable the counters.
+const PMCR_E: u64 = 1 << 0;
+/// Reset the event counters.
+const PMCR_P: u64 = 1 << 1;
+/// Reset the cycle counter.
+const PMCR_C: u64 = 1 << 2;
+/// Make the cycle counter overflow at 64 bits rather than 32.
+const PMCR_LC: u64 = 1 << 6;
+
+/// Bit of the cycle counter in PMCNTENSET_EL0 and PMCNTENCLR_EL0.
+const CYCLE_COUNTER: u64 = 1 << 31;
+
+/// INST_RETIRED, counted by event counter 0.
+const EVENT_INST_RETIRED: u64 = 0x08;
+/// L1D_CACHE_REFILL, counted by event counter 1.
+const EVENT_L1D_CACHE_REFILL: u64 = 0x03;
+
+struct Caps {
+    /// Number of event counters, or `None` without a PMU.
+    counters: Option<u64>,
+    /// Common events implemented, by event number.
+    common_events: u64,
+}
+
+static CAPS: Once<Caps> = Once::new();
+
+fn caps() -> &'static Caps {
+    CAPS.call_once(|| {
+        let dfr0: u64;
+        unsafe {
+            asm!("mrs {}, id_aa64dfr0_el1", out(reg) dfr0);
+        }
+        // Zero means no PMU, and 0xF an implementation defined one.
+        let version = (dfr0 >> 8) & 0xf;
+        if version == 0 || version == 0xf {
+            return Caps {
+                counters: None,
+                common_events: 0,
+            };
+        }
+        let (pmcr, pmceid0): (u64, u64);
+        unsafe {
+            asm!("mrs {}, pmcr_el0", out(reg) pmcr);
+            asm!("mrs {}, pmceid0_el0", out(reg) pmceid0);
+        }
+        Caps {
+            counters: Some((pmcr >> 11) & 0x1f),
+            common_events: pmceid0,
+        }
+    })
+}
+
+/// Whether `event` can be counted.
+pub fn supported(event: PerfEvent) -> bool {
+    let caps = caps();
+    let Some(counters) = caps.counters else {
+        return false;
+    };
+    let (counter, number) = match event {
+        PerfEvent::Cycles => return true,
+        PerfEvent::Instructions => (0, EVENT_INST_RETIRED),
+        PerfEvent::CacheMisses => (1, EVENT_L1D_CACHE_REFILL),
+    };
+    counter < counters && caps.common_events & (1 << number) != 0
+}
+
+fn enable_mask() -> u64 {
+    let mut mask = CYCLE_COUNTER;
+    if supported(PerfEvent::Instructions) {
+        mask |= 1 << 0;
+    }
+    if supported(PerfEvent::CacheMisses) {
+        mask |= 1 << 1;
+    }
+    mask
+}
+
+/// Start counting the supported events from zero, at EL0 and EL1.
+pub unsafe fn start() {
+    asm!("msr pmccfiltr_el0, xzr");
+    if supported(PerfEvent::Instructions) {
+        asm!("msr pmevtyper0_el0, {}", in(reg) EVENT_INST_RETIRED);
+    }
+    if supported(PerfEvent::CacheMisses) {
+        asm!("msr pmevtyper1_el0, {}", in(reg) EVENT_L1D_CACHE_REFILL);
+    }
+    asm!(
+        "msr pmcr_el0, {}",
+        "isb",
+        in(reg) PMCR_E | PMCR_P | PMCR_C | PMCR_LC,
+    );
+    asm!("msr pmcntenset_el0, {}", "isb", in(reg) enable_mask());
+}
+
+pub unsafe fn stop() {
+    asm!("msr pmcntenclr_el0, {}", in(reg) enable_mask());
+    asm!("msr pmcr_el0, xzr", "isb");
+}
+
+/// Add what the counters counted since the last call, or [`start`], to `counts`, and reset them.
+/// The event counters are 32 bits wide, and assumed not to overflow between context switches.
+pub unsafe fn read_and_reset(counts: &mut PerfCounts) {
+    let cycles: u64;
+    asm!("mrs {}, pmccntr_el0", "msr pmccntr_el0, xzr", out(reg) cycles);
+    counts[PerfEvent::Cycles as usize] += cycles;
+
+    if supported(PerfEvent::Instructions) {
+        let instructions: u64;
+        asm!("mrs {}, pmevcntr0_el0", "msr pmevcntr0_el0, xzr", out(reg) instructions);
+        counts[PerfEvent::Instructions as usize] += instructions;
+    }
+    if supported(PerfEvent::CacheMisses) {
+        let misses: u64;
+        asm!("mrs {}, pmevcntr1_el0", "msr pmevcntr1_el0, xzr", out(reg) misses);
+        counts[PerfEvent::CacheMisses as usize] += misses;
+    }
+}
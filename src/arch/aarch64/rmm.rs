@@ -1,4 +1,4 @@
-use rmm::{Arch, PageFlags, VirtualAddress};
+use rmm::{Arch, PageFlags, PhysicalAddress, VirtualAddress};
 
 pub unsafe fn page_flags<A: Arch>(virt: VirtualAddress) -> PageFlags<A> {
     use crate::kernel_executable_offsets::*;
@@ -15,3 +15,123 @@ pub unsafe fn page_flags<A: Arch>(virt: VirtualAddress) -> PageFlags<A> {
         PageFlags::new().write(true)
     }
 }
+
+/// Number of address space IDs the TLB can tag entries with, the 8 bits every implementation
+/// supports. ID 0 is used for page tables loaded without one.
+pub const ASID_COUNT: usize = 256;
+
+/// TCR_EL1.A1, set if the ASID is taken from TTBR1_EL1 rather than TTBR0_EL1.
+const TCR_A1: u64 = 1 << 22;
+
+/// Whether the ASID of TTBR0_EL1 tags the TLB entries. This relies on RMM mapping user pages as
+/// non-global, as only those are tagged.
+pub fn asids_enabled() -> bool {
+    let tcr: u64;
+    unsafe { core::arch::asm!("mrs {}, tcr_el1", out(reg) tcr) };
+    tcr & TCR_A1 == 0
+}
+
+/// log2 of the translation granule the kernel was built for.
+#[cfg(feature = "aarch64_16k_pages")]
+const GRANULE_SHIFT: usize = 14;
+#[cfg(feature = "aarch64_64k_pages")]
+const GRANULE_SHIFT: usize = 16;
+
+/// The AArch64 architecture with the 16 KiB or 64 KiB translation granule, which differs from
+/// [`rmm::AArch64Arch`] in the page and table sizes, and in translating only `VIRT_ADDR_BITS` with
+/// three levels.
+///
+/// RMM indexes a table with all the bits of a level, so it looks up the sign-extended kernel
+/// addresses in the last entries of the top table, while the CPU only walks its first
+/// `TOP_ENTRIES`. The kernel table is therefore loaded into TTBR1_EL1 at the offset of those last
+/// entries, which is zero with the 16 KiB granule, whose three levels translate 47 bits.
+#[cfg(any(feature = "aarch64_16k_pages", feature = "aarch64_64k_pages"))]
+#[derive(Clone, Copy, Debug)]
+pub struct AArch64GranuleArch;
+
+#[cfg(any(feature = "aarch64_16k_pages", feature = "aarch64_64k_pages"))]
+impl AArch64GranuleArch {
+    /// Entries of the top table the CPU walks.
+    const TOP_ENTRIES: usize = 1
+        << (crate::arch::consts::VIRT_ADDR_BITS as usize
+            - 2 * Self::PAGE_ENTRY_SHIFT
+            - Self::PAGE_SHIFT);
+    /// Offset of the entries translating kernel addresses into the top table.
+    const KERNEL_TABLE_OFFSET: usize = (Self::PAGE_ENTRIES - Self::TOP_ENTRIES) * 8;
+}
+
+#[cfg(any(feature = "aarch64_16k_pages", feature = "aarch64_64k_pages"))]
+impl Arch for AArch64GranuleArch {
+    const PAGE_SHIFT: usize = GRANULE_SHIFT;
+    const PAGE_ENTRY_SHIFT: usize = GRANULE_SHIFT - 3;
+    const PAGE_LEVELS: usize = 3;
+
+    // Output addresses are 48 bits wide, as with the 4 KiB granule
+    const ENTRY_ADDRESS_WIDTH: usize = 48 - GRANULE_SHIFT;
+    const ENTRY_FLAG_DEFAULT_PAGE: usize = rmm::AArch64Arch::ENTRY_FLAG_DEFAULT_PAGE;
+    const ENTRY_FLAG_DEFAULT_TABLE: usize = rmm::AArch64Arch::ENTRY_FLAG_DEFAULT_TABLE;
+    const ENTRY_FLAG_PRESENT: usize = rmm::AArch64Arch::ENTRY_FLAG_PRESENT;
+    const ENTRY_FLAG_READONLY: usize = rmm::AArch64Arch::ENTRY_FLAG_READONLY;
+    const ENTRY_FLAG_READWRITE: usize = rmm::AArch64Arch::ENTRY_FLAG_READWRITE;
+    const ENTRY_FLAG_PAGE_USER: usize = rmm::AArch64Arch::ENTRY_FLAG_PAGE_USER;
+    const ENTRY_FLAG_NO_EXEC: usize = rmm::AArch64Arch::ENTRY_FLAG_NO_EXEC;
+    const ENTRY_FLAG_EXEC: usize = rmm::AArch64Arch::ENTRY_FLAG_EXEC;
+    const ENTRY_FLAG_GLOBAL: usize = rmm::AArch64Arch::ENTRY_FLAG_GLOBAL;
+    const ENTRY_FLAG_NO_GLOBAL: usize = rmm::AArch64Arch::ENTRY_FLAG_NO_GLOBAL;
+    const ENTRY_FLAG_WRITE_COMBINING: usize = rmm::AArch64Arch::ENTRY_FLAG_WRITE_COMBINING;
+
+    const PHYS_OFFSET: usize = crate::arch::consts::PHYS_OFFSET;
+
+    unsafe fn init() -> &'static [rmm::MemoryArea] {
+        rmm::AArch64Arch::init()
+    }
+
+    #[inline(always)]
+    unsafe fn invalidate(address: VirtualAddress) {
+        rmm::AArch64Arch::invalidate(address);
+    }
+
+    #[inline(always)]
+    unsafe fn invalidate_all() {
+        rmm::AArch64Arch::invalidate_all();
+    }
+
+    #[inline(always)]
+    unsafe fn table(table_kind: rmm::TableKind) -> PhysicalAddress {
+        let address = rmm::AArch64Arch::table(table_kind);
+        match table_kind {
+            rmm::TableKind::User => address,
+            rmm::TableKind::Kernel => address.sub(Self::KERNEL_TABLE_OFFSET),
+        }
+    }
+
+    #[inline(always)]
+    unsafe fn set_table(table_kind: rmm::TableKind, address: PhysicalAddress) {
+        let address = match table_kind {
+            rmm::TableKind::User => address,
+            rmm::TableKind::Kernel => address.add(Self::KERNEL_TABLE_OFFSET),
+        };
+        rmm::AArch64Arch::set_table(table_kind, address);
+    }
+
+    fn virt_is_valid(address: VirtualAddress) -> bool {
+        const SHIFT: u32 = usize::BITS - crate::arch::consts::VIRT_ADDR_BITS;
+        let address = address.data();
+        (((address << SHIFT) as isize) >> SHIFT) as usize == address
+    }
+}
+
+/// Load the user page table `table` with address space ID `asid`, flushing the TLB entries
+/// tagged with it if `flush` is set.
+pub unsafe fn set_user_table(table: PhysicalAddress, asid: u16, flush: bool) {
+    let asid = u64::from(asid) << 48;
+    core::arch::asm!("msr ttbr0_el1, {}", "isb", in(reg) table.data() as u64 | asid);
+    if flush {
+        core::arch::asm!("tlbi aside1, {}", "dsb nsh", "isb", in(reg) asid);
+    }
+}
+
+/// Flush the TLB entries of all address space IDs.
+pub unsafe fn invalidate_all_asids() {
+    core::arch::asm!("tlbi vmalle1", "dsb nsh", "isb");
+}
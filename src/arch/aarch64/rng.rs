@@ -0,0 +1,47 @@
+//! RNDR and the generic timer counter, for the entropy pool.
+
+use core::arch::asm;
+
+use spin::Once;
+
+static HAS_RNDR: Once<bool> = Once::new();
+
+/// A random number from RNDR, if the CPU implements FEAT_RNG.
+pub fn hardware_random() -> Option<u64> {
+    let has_rndr = *HAS_RNDR.call_once(|| {
+        let isar0: u64;
+        unsafe {
+            asm!("mrs {}, id_aa64isar0_el1", out(reg) isar0);
+        }
+        (isar0 >> 60) & 0xF != 0
+    });
+    if !has_rndr {
+        return None;
+    }
+
+    // RNDR sets the Z flag when it failed to produce a number in reasonable time.
+    for _ in 0..10 {
+        let (value, failed): (u64, u64);
+        unsafe {
+            asm!(
+                "mrs {value}, s3_3_c2_c4_0",
+                "cset {failed}, eq",
+                value = out(reg) value,
+                failed = out(reg) failed,
+            );
+        }
+        if failed == 0 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+/// The virtual counter of the generic timer.
+pub fn cycles() -> u64 {
+    let count: u64;
+    unsafe {
+        asm!("mrs {}, cntvct_el0", out(reg) count);
+    }
+    count
+}
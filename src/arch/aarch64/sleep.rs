@@ -0,0 +1,305 @@
+//! Suspend to RAM through PSCI SYSTEM_SUSPEND.
+//!
+//! Each CPU saves the registers it loses while powered off on its stack, and calls
+//! [`save_and_sleep`]. The APs turn themselves off with CPU_OFF, after which the BSP calls
+//! SYSTEM_SUSPEND. On waking up, the BSP starts at the AP entry code of [`super::smp`], which
+//! enables the MMU and jumps to [`resume_entry`] to restore the saved state and return from
+//! [`save_and_sleep`] a second time. The APs are then turned on with CPU_ON the same way.
+//!
+//! The GICv3 ITS is not saved, so MSIs translated by it are not delivered after waking up.
+
+use core::{
+    arch::asm,
+    mem::size_of,
+    ptr::addr_of_mut,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+};
+
+use crate::{
+    context,
+    cpu_set::{LogicalCpuId, MAX_CPU_COUNT},
+    device,
+    dtb::irqchip::IRQ_CHIP,
+    memory::{allocate_frame, deallocate_p2frame},
+    paging::{PhysicalAddress, RmmA, RmmArch},
+    percpu::PercpuBlock,
+    start::KernelArgsAp,
+    stop::{
+        psci_cpu_is_off, psci_cpu_off, psci_cpu_on, psci_has_system_suspend, psci_system_suspend,
+    },
+    syscall::error::{Error, Result, EIO, ENOMEM, EOPNOTSUPP},
+};
+
+use super::smp::{clean_dcache, mpidr, write_entry_args};
+
+/// Registers lost while the CPU is powered off.
+#[repr(C)]
+struct CpuState {
+    /// x19 to x30, saved by [`save_and_sleep`].
+    regs: [u64; 12],
+    /// Stack pointer, saved by [`save_and_sleep`].
+    sp: u64,
+    /// Affinity of the CPU, to turn it on again.
+    mpidr: usize,
+    tpidr_el1: u64,
+    vbar_el1: u64,
+    ttbr0_el1: u64,
+    mdscr_el1: u64,
+    cntkctl_el1: u64,
+    sp_el0: u64,
+    tpidr_el0: u64,
+    tpidrro_el0: u64,
+}
+
+impl CpuState {
+    unsafe fn current() -> Self {
+        let mut state = Self {
+            regs: [0; 12],
+            sp: 0,
+            mpidr: mpidr(),
+            tpidr_el1: 0,
+            vbar_el1: 0,
+            ttbr0_el1: 0,
+            mdscr_el1: 0,
+            cntkctl_el1: 0,
+            sp_el0: 0,
+            tpidr_el0: 0,
+            tpidrro_el0: 0,
+        };
+        asm!(
+            "mrs {}, tpidr_el1",
+            "mrs {}, vbar_el1",
+            "mrs {}, ttbr0_el1",
+            "mrs {}, mdscr_el1",
+            "mrs {}, cntkctl_el1",
+            "mrs {}, sp_el0",
+            "mrs {}, tpidr_el0",
+            "mrs {}, tpidrro_el0",
+            out(reg) state.tpidr_el1,
+            out(reg) state.vbar_el1,
+            out(reg) state.ttbr0_el1,
+            out(reg) state.mdscr_el1,
+            out(reg) state.cntkctl_el1,
+            out(reg) state.sp_el0,
+            out(reg) state.tpidr_el0,
+            out(reg) state.tpidrro_el0,
+        );
+        state
+    }
+}
+
+/// Stack [`resume_entry`] runs on until it switches back to the saved one.
+#[repr(C, align(4096))]
+struct ResumeStack([u8; 4096]);
+
+static mut RESUME_STACK: ResumeStack = ResumeStack([0; 4096]);
+
+/// The state saved by each AP turned off, to turn it on again with.
+static STATES: [AtomicUsize; MAX_CPU_COUNT as usize] = {
+    const NONE: AtomicUsize = AtomicUsize::new(0);
+    [NONE; MAX_CPU_COUNT as usize]
+};
+
+/// Set by an AP once it restored its state.
+static RESTARTED: AtomicBool = AtomicBool::new(false);
+
+pub fn supported() -> bool {
+    psci_has_system_suspend()
+}
+
+/// Save x19 to x30 and the stack pointer in `state`, and call `sleep` with `state` and `arg`.
+/// Returns false if `sleep` returned, and true when [`resume_entry`] restored `state` after the
+/// CPU was powered off.
+#[naked]
+unsafe extern "C" fn save_and_sleep(
+    _state: *mut CpuState,
+    _sleep: unsafe extern "C" fn(*mut CpuState, usize),
+    _arg: usize,
+) -> bool {
+    core::arch::asm!(
+        "
+        stp x19, x20, [x0, #0]
+        stp x21, x22, [x0, #16]
+        stp x23, x24, [x0, #32]
+        stp x25, x26, [x0, #48]
+        stp x27, x28, [x0, #64]
+        stp x29, x30, [x0, #80]
+        mov x9, sp
+        str x9, [x0, #96]
+
+        mov x19, x0
+        mov x9, x1
+        mov x1, x2
+        blr x9
+
+        mov x0, x19
+        ldp x19, x20, [x0, #0]
+        ldp x29, x30, [x0, #80]
+        mov x0, #0
+        ret
+        ",
+        options(noreturn)
+    );
+}
+
+/// Jumped to by the AP entry code with the MMU on, a temporary stack, and the state in x0.
+#[naked]
+unsafe extern "C" fn resume_entry() {
+    core::arch::asm!(
+        "
+        bl {init_cpu}
+        bl {restore}
+
+        ldp x19, x20, [x0, #0]
+        ldp x21, x22, [x0, #16]
+        ldp x23, x24, [x0, #32]
+        ldp x25, x26, [x0, #48]
+        ldp x27, x28, [x0, #64]
+        ldp x29, x30, [x0, #80]
+        ldr x9, [x0, #96]
+        mov sp, x9
+
+        mov x0, #1
+        ret
+        ",
+        init_cpu = sym super::pauth::init_cpu,
+        restore = sym restore,
+        options(noreturn)
+    );
+}
+
+/// Restore the system registers not already loaded by the AP entry code.
+unsafe extern "C" fn restore(state: &CpuState) -> &CpuState {
+    asm!(
+        "msr tpidr_el1, {}",
+        "msr vbar_el1, {}",
+        "msr mdscr_el1, {}",
+        "msr cntkctl_el1, {}",
+        "msr sp_el0, {}",
+        "msr tpidr_el0, {}",
+        "msr tpidrro_el0, {}",
+        // Replace the identity mapping of the entry code.
+        "msr ttbr0_el1, {}",
+        "isb",
+        "tlbi vmalle1",
+        "dsb nsh",
+        "isb",
+        in(reg) state.tpidr_el1,
+        in(reg) state.vbar_el1,
+        in(reg) state.mdscr_el1,
+        in(reg) state.cntkctl_el1,
+        in(reg) state.sp_el0,
+        in(reg) state.tpidr_el0,
+        in(reg) state.tpidrro_el0,
+        in(reg) state.ttbr0_el1,
+    );
+    super::sve::init();
+    super::pauth::resume();
+    super::mte::resume();
+    state
+}
+
+/// Write the arguments making the AP entry code resume `state` on [`RESUME_STACK`], to the frame
+/// at `args_phys`, and return the physical address of the entry code.
+unsafe fn write_resume_args(state: *mut CpuState, cpu: LogicalCpuId, args_phys: usize) -> usize {
+    let args = RmmA::phys_to_virt(PhysicalAddress::new(args_phys)).data() as *mut KernelArgsAp;
+    let stack_start = addr_of_mut!(RESUME_STACK) as usize;
+    // The state is read with the MMU on, but this CPU may lose its caches first.
+    clean_dcache(state as usize, size_of::<CpuState>());
+    write_entry_args(
+        args,
+        cpu,
+        (stack_start, stack_start + size_of::<ResumeStack>()),
+        state as usize,
+        resume_entry as usize,
+    )
+}
+
+unsafe extern "C" fn sleep_bsp(state: *mut CpuState, args_phys: usize) {
+    let entry_phys = write_resume_args(state, LogicalCpuId::BSP, args_phys);
+    if let Err(err) = psci_system_suspend(entry_phys, args_phys) {
+        log::warn!("PSCI SYSTEM_SUSPEND failed: {}", err);
+    }
+}
+
+/// Suspend the system on the BSP, once the APs called [`suspend_ap`], returning after waking up.
+pub unsafe fn enter() -> Result<()> {
+    if !supported() {
+        return Err(Error::new(EOPNOTSUPP));
+    }
+    // SYSTEM_SUSPEND fails unless all other CPUs are off.
+    for state in STATES.iter() {
+        let state = state.load(Ordering::Acquire) as *const CpuState;
+        if !state.is_null() {
+            while !psci_cpu_is_off((*state).mpidr) {
+                core::hint::spin_loop();
+            }
+        }
+    }
+    let args_frame = allocate_frame().ok_or(Error::new(ENOMEM))?;
+
+    // The FP/SIMD state of the suspending context is lost with the other registers.
+    let context_lock = context::current();
+    context::save_fpu(&mut context_lock.write());
+    device::suspend();
+
+    let mut state = CpuState::current();
+    let resumed = save_and_sleep(&mut state, sleep_bsp, args_frame.base().data());
+
+    device::resume();
+    context::load_fpu(&mut context_lock.write());
+    deallocate_p2frame(args_frame, 0);
+
+    if resumed {
+        Ok(())
+    } else {
+        Err(Error::new(EIO))
+    }
+}
+
+unsafe extern "C" fn power_off_ap(state: *mut CpuState, _arg: usize) {
+    let cpu = PercpuBlock::current().cpu_id;
+    STATES[cpu.get() as usize].store(state as usize, Ordering::Release);
+    crate::suspend::ap_down();
+
+    let err = psci_cpu_off();
+    log::error!("CPU {}: PSCI CPU_OFF failed: {}", cpu, err);
+    loop {
+        asm!("wfi");
+    }
+}
+
+/// Save the state of this AP and turn it off until [`restart_ap`] turns it on again, after waking
+/// up.
+pub unsafe fn suspend_ap() {
+    let mut state = CpuState::current();
+    if save_and_sleep(&mut state, power_off_ap, 0) {
+        IRQ_CHIP.init_ap();
+        RESTARTED.store(true, Ordering::Release);
+    }
+}
+
+/// Turn `cpu`, turned off in [`suspend_ap`], on again, and wait until it restored its state.
+pub unsafe fn restart_ap(cpu: LogicalCpuId) {
+    let state = STATES[cpu.get() as usize].swap(0, Ordering::Acquire) as *mut CpuState;
+    if state.is_null() {
+        return;
+    }
+    let Some(args_frame) = allocate_frame() else {
+        log::error!("CPU {}: no frame to restart it with", cpu);
+        return;
+    };
+    RESTARTED.store(false, Ordering::Relaxed);
+
+    let args_phys = args_frame.base().data();
+    let entry_phys = write_resume_args(state, cpu, args_phys);
+    match psci_cpu_on((*state).mpidr, entry_phys, args_phys) {
+        Ok(()) => {
+            while !RESTARTED.load(Ordering::Acquire) {
+                core::hint::spin_loop();
+            }
+        }
+        Err(err) => log::error!("CPU {}: PSCI CPU_ON failed: {}", cpu, err),
+    }
+    deallocate_p2frame(args_frame, 0);
+}
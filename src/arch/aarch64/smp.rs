@@ -0,0 +1,298 @@
+//! Secondary CPU startup using PSCI CPU_ON.
+//!
+//! Each CPU listed in the devicetree with the `psci` enable method is started at
+//! `kstart_ap_entry`, with the MMU off and the physical address of its `KernelArgsAp` in x0. The
+//! entry code leaves EL2 if needed, loads the translation registers used by the BSP, and enables
+//! the MMU while running from a small page table that identity maps the entry code, before jumping
+//! to `kstart_ap` at its kernel address. Resuming from suspend enters CPUs the same way.
+
+use core::{
+    arch::asm,
+    mem::{offset_of, size_of},
+    sync::atomic::Ordering,
+};
+
+use fdt::Fdt;
+use spin::Once;
+
+use crate::{
+    cpu_set::{LogicalCpuId, MAX_CPU_COUNT},
+    device::cpu::registers::control_regs,
+    memory::{
+        allocate_frame, allocate_p2frame, deallocate_p2frame, KernelMapper, TheFrameAllocator,
+        PAGE_SIZE,
+    },
+    numa,
+    paging::{PageFlags, PageMapper, PhysicalAddress, RmmA, RmmArch, TableKind, VirtualAddress},
+    start::{kstart_ap, KernelArgsAp, AP_READY, CPU_COUNT},
+};
+
+/// The affinity fields of MPIDR_EL1, as used for the `reg` property of CPU nodes.
+pub(crate) const MPIDR_AFFINITY_MASK: usize = 0xFF_00FF_FFFF;
+
+/// Order of the stack allocated for each AP, 64 KiB.
+const AP_STACK_ORDER: u32 = 4;
+
+extern "C" {
+    fn kstart_ap_entry();
+}
+
+core::arch::global_asm!(
+    "
+.globl kstart_ap_entry
+kstart_ap_entry:
+    mov     x19, x0
+
+    mrs     x9, CurrentEL
+    lsr     x9, x9, #2
+    cmp     x9, #2
+    b.ne    1f
+
+    bl      el2_init
+    adr     x9, 1f
+    msr     elr_el2, x9
+    mov     x9, #{spsr}
+    msr     spsr_el2, x9
+    eret
+
+1:
+    msr     spsel, #1
+
+    ldr     x9, [x19, #{mair}]
+    msr     mair_el1, x9
+    ldr     x9, [x19, #{tcr}]
+    msr     tcr_el1, x9
+    ldr     x9, [x19, #{cpacr}]
+    msr     cpacr_el1, x9
+    ldr     x9, [x19, #{identity_table}]
+    msr     ttbr0_el1, x9
+    ldr     x9, [x19, #{page_table}]
+    msr     ttbr1_el1, x9
+
+    // The arguments are only identity mapped with the MMU off.
+    ldr     x20, [x19, #{stack_end}]
+    ldr     x21, [x19, #{args_virt}]
+    ldr     x22, [x19, #{entry}]
+    ldr     x23, [x19, #{sctlr}]
+
+    isb
+    tlbi    vmalle1
+    dsb     nsh
+    isb
+
+    msr     sctlr_el1, x23
+    isb
+
+    mov     sp, x20
+    mov     x0, x21
+    br      x22
+    ",
+    spsr = const super::el2::SPSR_EL1H_MASKED,
+    mair = const offset_of!(KernelArgsAp, mair),
+    tcr = const offset_of!(KernelArgsAp, tcr),
+    cpacr = const offset_of!(KernelArgsAp, cpacr),
+    identity_table = const offset_of!(KernelArgsAp, identity_table),
+    page_table = const offset_of!(KernelArgsAp, page_table),
+    stack_end = const offset_of!(KernelArgsAp, stack_end),
+    args_virt = const offset_of!(KernelArgsAp, args_virt),
+    entry = const offset_of!(KernelArgsAp, entry),
+    sctlr = const offset_of!(KernelArgsAp, sctlr),
+);
+
+/// The affinity fields of MPIDR_EL1 of this CPU.
+pub(crate) fn mpidr() -> usize {
+    let value: usize;
+    unsafe { asm!("mrs {}, mpidr_el1", out(reg) value) };
+    value & MPIDR_AFFINITY_MASK
+}
+
+/// Translation registers of this CPU, to be loaded by the APs.
+struct TranslationRegs {
+    mair: u64,
+    tcr: u64,
+    sctlr: u64,
+    cpacr: u64,
+}
+
+impl TranslationRegs {
+    fn current() -> Self {
+        let (mair, tcr, sctlr, cpacr): (u64, u64, u64, u64);
+        unsafe {
+            asm!(
+                "mrs {}, mair_el1",
+                "mrs {}, tcr_el1",
+                "mrs {}, sctlr_el1",
+                "mrs {}, cpacr_el1",
+                out(reg) mair,
+                out(reg) tcr,
+                out(reg) sctlr,
+                out(reg) cpacr,
+            );
+        }
+        Self {
+            mair,
+            tcr,
+            sctlr,
+            cpacr,
+        }
+    }
+}
+
+/// Clean the data cache lines covering `[start, start + len)` to the point of coherency, so that
+/// a CPU with the MMU and caches off, or a device not snooping the caches, sees the data.
+pub(crate) unsafe fn clean_dcache(start: usize, len: usize) {
+    let ctr: usize;
+    asm!("mrs {}, ctr_el0", out(reg) ctr);
+    let line = 4 << ((ctr >> 16) & 0xF);
+
+    let mut addr = start & !(line - 1);
+    while addr < start + len {
+        asm!("dc cvac, {}", in(reg) addr);
+        addr += line;
+    }
+    asm!("dsb sy");
+}
+
+/// Create a user page table identity mapping `count` pages at `base`, returning its address.
+unsafe fn identity_map(base: PhysicalAddress, count: usize) -> PhysicalAddress {
+    let mut mapper = PageMapper::create(TableKind::User, TheFrameAllocator)
+        .expect("failed to allocate AP identity page table");
+    for i in 0..count {
+        let addr = base.add(i * PAGE_SIZE);
+        mapper
+            .map_phys(
+                VirtualAddress::new(addr.data()),
+                addr,
+                PageFlags::new().execute(true),
+            )
+            .expect("failed to identity map AP entry")
+            .ignore(); // Not the active table
+    }
+    mapper.table().phys()
+}
+
+/// Physical address of `kstart_ap_entry`, and the page table identity mapping it.
+static ENTRY: Once<(usize, PhysicalAddress)> = Once::new();
+
+fn entry() -> (usize, PhysicalAddress) {
+    *ENTRY.call_once(|| unsafe {
+        let entry_virt = kstart_ap_entry as usize;
+        let entry_page = entry_virt & !(PAGE_SIZE - 1);
+        let (entry_page_phys, _) = KernelMapper::lock()
+            .translate(VirtualAddress::new(entry_page))
+            .expect("AP entry not mapped");
+
+        // The entry code is small, but may cross a page boundary.
+        (
+            entry_page_phys.data() + (entry_virt - entry_page),
+            identity_map(entry_page_phys, 2),
+        )
+    })
+}
+
+/// Write the arguments making `kstart_ap_entry` jump to `entry` with `arg` in x0, on the stack
+/// `[stack_start, stack_end)`, to `args`, and return the physical address of `kstart_ap_entry`.
+pub(crate) unsafe fn write_entry_args(
+    args: *mut KernelArgsAp,
+    cpu_id: LogicalCpuId,
+    (stack_start, stack_end): (usize, usize),
+    arg: usize,
+    entry_virt: usize,
+) -> usize {
+    let (entry_phys, identity_table) = entry();
+    let regs = TranslationRegs::current();
+    args.write(KernelArgsAp {
+        cpu_id: cpu_id.get().into(),
+        // The kernel table as loaded, which need not be where it starts, see `AArch64GranuleArch`
+        page_table: control_regs::ttbr1_el1(),
+        stack_start: stack_start as u64,
+        stack_end: stack_end as u64,
+        identity_table: identity_table.data() as u64,
+        mair: regs.mair,
+        tcr: regs.tcr,
+        sctlr: regs.sctlr,
+        cpacr: regs.cpacr,
+        args_virt: arg as u64,
+        entry: entry_virt as u64,
+    });
+    clean_dcache(args as usize, size_of::<KernelArgsAp>());
+    entry_phys
+}
+
+/// Start all other CPUs listed in the devicetree, waiting for each to be ready.
+pub unsafe fn init(fdt: &Fdt) {
+    // CPUs are identified by the low 32 bits of their MPIDR in the NUMA topology.
+    numa::set_cpu_node(LogicalCpuId::BSP, numa::hw_cpu_node(mpidr() as u32));
+
+    if !cfg!(feature = "multi_core") {
+        return;
+    }
+    let Some(cpus) = fdt.find_node("/cpus") else {
+        log::warn!("No /cpus node, not starting APs");
+        return;
+    };
+
+    let me = mpidr();
+
+    for cpu in cpus.children() {
+        if cpu.property("device_type").and_then(|p| p.as_str()) != Some("cpu") {
+            continue;
+        }
+        let Some(ap_mpidr) = cpu.property("reg").and_then(|p| p.as_usize()) else {
+            continue;
+        };
+        if ap_mpidr & MPIDR_AFFINITY_MASK == me {
+            continue;
+        }
+
+        let method = cpu.property("enable-method").and_then(|p| p.as_str());
+        if method != Some("psci") {
+            log::warn!(
+                "CPU {:#x}: unsupported enable method {:?}",
+                ap_mpidr,
+                method
+            );
+            continue;
+        }
+
+        let cpu_id = CPU_COUNT.load(Ordering::SeqCst);
+        if cpu_id >= MAX_CPU_COUNT {
+            log::warn!("CPU {:#x}: more than {} CPUs", ap_mpidr, MAX_CPU_COUNT);
+            break;
+        }
+
+        let stack = allocate_p2frame(AP_STACK_ORDER).expect("no more frames for AP stack");
+        let stack_start = RmmA::phys_to_virt(stack.base()).data();
+        let stack_end = stack_start + (PAGE_SIZE << AP_STACK_ORDER);
+
+        let args_frame = allocate_frame().expect("no more frames for AP arguments");
+        let args = RmmA::phys_to_virt(args_frame.base()).data() as *mut KernelArgsAp;
+        let entry_phys = write_entry_args(
+            args,
+            LogicalCpuId::new(cpu_id),
+            (stack_start, stack_end),
+            args as usize,
+            kstart_ap as usize,
+        );
+
+        AP_READY.store(false, Ordering::SeqCst);
+        match crate::stop::psci_cpu_on(ap_mpidr, entry_phys, args_frame.base().data()) {
+            Ok(()) => {
+                while !AP_READY.load(Ordering::SeqCst) {
+                    core::hint::spin_loop();
+                }
+                CPU_COUNT.fetch_add(1, Ordering::SeqCst);
+                numa::set_cpu_node(
+                    LogicalCpuId::new(cpu_id),
+                    numa::hw_cpu_node(ap_mpidr as u32),
+                );
+                log::info!("AP {}: MPIDR {:#x}", LogicalCpuId::new(cpu_id), ap_mpidr);
+            }
+            Err(err) => {
+                log::warn!("CPU {:#x}: PSCI CPU_ON failed: {}", ap_mpidr, err);
+                deallocate_p2frame(stack, AP_STACK_ORDER);
+            }
+        }
+        deallocate_p2frame(args_frame, 0);
+    }
+}
@@ -0,0 +1,96 @@
+//! Speculative execution vulnerabilities and controls of AArch64 processors. ID_AA64PFR0_EL1
+//! reports processors not affected by Spectre variant 2 (CSV2) and Meltdown (CSV3), while others
+//! may or may not be. Branch predictors are invalidated by the firmware, through
+//! SMCCC_ARCH_WORKAROUND_1, and speculative store bypass is disabled in the kernel by clearing
+//! SCTLR_EL1.DSSBS, the value of PSTATE.SSBS on exception entry, where FEAT_SSBS is implemented.
+//! Userspace keeps its own PSTATE.SSBS. There is no equivalent of IBRS.
+
+use core::arch::asm;
+
+use spin::Once;
+
+use crate::mitigations::{self, Mitigation, Status, Vulnerability};
+
+/// SCTLR_EL1.DSSBS.
+const SCTLR_DSSBS: u64 = 1 << 44;
+
+struct Caps {
+    csv2: bool,
+    csv3: bool,
+    ssbs: bool,
+    workaround_1: bool,
+}
+
+static CAPS: Once<Caps> = Once::new();
+
+fn caps() -> &'static Caps {
+    CAPS.call_once(|| {
+        let (pfr0, pfr1): (u64, u64);
+        unsafe {
+            asm!("mrs {}, id_aa64pfr0_el1", out(reg) pfr0);
+            asm!("mrs {}, id_aa64pfr1_el1", out(reg) pfr1);
+        }
+        let csv2 = (pfr0 >> 56) & 0xF != 0;
+        Caps {
+            csv2,
+            csv3: (pfr0 >> 60) & 0xF != 0,
+            ssbs: (pfr1 >> 4) & 0xF != 0,
+            workaround_1: !csv2 && super::stop::smccc_has_workaround_1(),
+        }
+    })
+}
+
+pub fn supported(mitigation: Mitigation) -> bool {
+    let caps = caps();
+    match mitigation {
+        Mitigation::Ibrs => false,
+        Mitigation::Ibpb => caps.workaround_1,
+        Mitigation::Ssbd => caps.ssbs,
+    }
+}
+
+/// Whether `mitigation` is enabled by default, where supported.
+pub fn recommended(mitigation: Mitigation) -> bool {
+    match mitigation {
+        Mitigation::Ibrs | Mitigation::Ibpb => true,
+        // Slows down every load following a store, and only matters for processes running
+        // untrusted code in the same address space, such as JITs.
+        Mitigation::Ssbd => false,
+    }
+}
+
+pub fn status(vulnerability: Vulnerability) -> Status {
+    let caps = caps();
+    match vulnerability {
+        Vulnerability::Meltdown if caps.csv3 => Status::NotAffected,
+        Vulnerability::SpectreV2 if caps.csv2 => Status::NotAffected,
+        Vulnerability::SpectreV2 if mitigations::enabled(Mitigation::Ibpb) => {
+            Status::Mitigated("ibpb, between processes only")
+        }
+        Vulnerability::SpecStoreBypass if mitigations::enabled(Mitigation::Ssbd) => {
+            Status::Mitigated("ssbd")
+        }
+        _ => Status::Unknown,
+    }
+}
+
+/// Set SCTLR_EL1.DSSBS on this CPU according to the enabled mitigations.
+pub unsafe fn apply() {
+    if !caps().ssbs {
+        return;
+    }
+    let mut sctlr: u64;
+    asm!("mrs {}, sctlr_el1", out(reg) sctlr);
+    if mitigations::enabled(Mitigation::Ssbd) {
+        sctlr &= !SCTLR_DSSBS;
+    } else {
+        sctlr |= SCTLR_DSSBS;
+    }
+    asm!("msr sctlr_el1, {}", "isb", in(reg) sctlr);
+}
+
+pub unsafe fn flush_branch_predictors() {
+    if caps().workaround_1 {
+        super::stop::smccc_workaround_1();
+    }
+}
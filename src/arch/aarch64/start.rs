@@ -12,7 +12,9 @@ use fdt::Fdt;
 use log::info;
 
 use crate::{
-    allocator, device, dtb,
+    allocator,
+    cpu_set::LogicalCpuId,
+    device, dtb,
     dtb::register_dev_memory_ranges,
     paging,
     startup::memory::{register_bootloader_areas, register_memory_region, BootloaderMemoryKind},
@@ -49,9 +51,9 @@ pub struct KernelArgs {
     bootstrap_size: usize,
 }
 
-/// The entry to Rust, all things must be initialized
-#[no_mangle]
-pub unsafe extern "C" fn kstart(args_ptr: *const KernelArgs) -> ! {
+/// The entry to Rust, all things must be initialized. Called at EL1 by the `kstart` entry point,
+/// with the exception level the kernel was originally entered at.
+pub unsafe extern "C" fn kstart_el1(args_ptr: *const KernelArgs, entry_el: usize) -> ! {
     let bootstrap = {
         let args = args_ptr.read();
 
@@ -118,6 +120,10 @@ pub unsafe extern "C" fn kstart(args_ptr: *const KernelArgs) -> ! {
         log::set_max_level(::log::LevelFilter::Debug);
 
         info!("Redox OS starting...");
+        if entry_el == 2 {
+            crate::arch::el2::ENTERED_AT_EL2.store(true, Ordering::SeqCst);
+            info!("Entered at EL2, hypervisor stub installed");
+        }
         info!(
             "Kernel: {:X}:{:X}",
             { args.kernel_base },
@@ -189,12 +195,15 @@ pub unsafe extern "C" fn kstart(args_ptr: *const KernelArgs) -> ! {
             args.bootstrap_size,
             BootloaderMemoryKind::IdentityMap,
         );
-        crate::startup::memory::init(None, None);
-
-        // Initialize paging
+        // Initialize paging, before the kernel page table is built with the memory attributes it
+        // sets up
         paging::init();
 
+        crate::startup::memory::init(None, None);
+
         crate::misc::init(crate::cpu_set::LogicalCpuId::new(0));
+        super::pauth::init();
+        super::pauth::init_cpu();
 
         // Reset AP variables
         CPU_COUNT.store(1, Ordering::SeqCst);
@@ -215,7 +224,11 @@ pub unsafe extern "C" fn kstart(args_ptr: *const KernelArgs) -> ! {
         match dtb_res {
             Ok(dtb) => {
                 dtb::init(hwdesc_data.map(|slice| (slice.as_ptr() as usize, slice.len())));
+                dtb::init_numa(&dtb);
+                #[cfg(feature = "virtio")]
+                crate::devices::virtio::mmio::init(&dtb);
                 device::init_devicetree(&dtb);
+                super::smp::init(&dtb);
             }
             Err(err) => {
                 dtb::init(None);
@@ -243,16 +256,60 @@ pub unsafe extern "C" fn kstart(args_ptr: *const KernelArgs) -> ! {
 }
 
 #[repr(C, packed)]
-#[allow(unused)]
 pub struct KernelArgsAp {
-    cpu_id: u64,
-    page_table: u64,
-    stack_start: u64,
-    stack_end: u64,
+    pub cpu_id: u64,
+    pub page_table: u64,
+    pub stack_start: u64,
+    pub stack_end: u64,
+
+    /// Page table identity mapping the AP entry code, used while enabling the MMU.
+    pub identity_table: u64,
+    pub mair: u64,
+    pub tcr: u64,
+    pub sctlr: u64,
+    pub cpacr: u64,
+
+    /// Passed in x0 to `entry`: the kernel address of these arguments for `kstart_ap`, or the
+    /// saved state when resuming from suspend.
+    pub args_virt: u64,
+    pub entry: u64,
 }
 
 /// Entry to rust for an AP
-#[allow(unused)]
-pub unsafe extern "C" fn kstart_ap(_args_ptr: *const KernelArgsAp) -> ! {
-    loop {}
+pub unsafe extern "C" fn kstart_ap(args_ptr: *const KernelArgsAp) -> ! {
+    let cpu_id = {
+        let args = args_ptr.read();
+        let cpu_id = LogicalCpuId::new(args.cpu_id as u32);
+
+        assert_eq!(BSS_TEST_ZERO, 0);
+        assert_eq!(DATA_TEST_NONZERO, 0xFFFF_FFFF_FFFF_FFFF);
+
+        // Setup interrupt handlers
+        core::arch::asm!(
+            "
+            ldr {tmp}, =exception_vector_base
+            msr vbar_el1, {tmp}
+            ",
+            tmp = out(reg) _,
+        );
+
+        // Initialize paging
+        paging::init();
+
+        crate::misc::init(cpu_id);
+        super::pauth::init_cpu();
+
+        // Initialize devices (for AP)
+        device::init_ap();
+
+        AP_READY.store(true, Ordering::SeqCst);
+
+        cpu_id
+    };
+
+    while !BSP_READY.load(Ordering::SeqCst) {
+        core::hint::spin_loop();
+    }
+
+    crate::kmain_ap(cpu_id);
 }
@@ -1,26 +1,161 @@
-use core::arch::asm;
+use core::{
+    arch::asm,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use fdt::Fdt;
+
+use crate::cpu_set::LogicalCpuId;
+
+const PSCI_CPU_OFF: usize = 0x8400_0002;
+const PSCI_CPU_ON: usize = 0xC400_0003;
+const PSCI_AFFINITY_INFO: usize = 0xC400_0004;
+const PSCI_FEATURES: usize = 0x8400_000A;
+const PSCI_SYSTEM_SUSPEND: usize = 0xC400_000E;
+const PSCI_SYSTEM_OFF: usize = 0x8400_0008;
+const PSCI_SYSTEM_RESET: usize = 0x8400_0009;
+
+const SMCCC_VERSION: usize = 0x8000_0000;
+const SMCCC_ARCH_FEATURES: usize = 0x8000_0001;
+const SMCCC_ARCH_WORKAROUND_1: usize = 0x8000_8000;
+
+/// Whether PSCI is called using SMC rather than HVC, as given by the devicetree.
+static PSCI_USE_SMC: AtomicBool = AtomicBool::new(false);
+
+pub unsafe fn init_psci(fdt: &Fdt) {
+    let Some(node) = fdt.find_compatible(&["arm,psci-1.0", "arm,psci-0.2", "arm,psci"]) else {
+        log::warn!("No PSCI node found, assuming HVC");
+        return;
+    };
+    let method = node.property("method").and_then(|method| method.as_str());
+    log::info!("PSCI method {:?}", method);
+    PSCI_USE_SMC.store(method == Some("smc"), Ordering::Relaxed);
+}
+
+unsafe fn psci_call(function: usize, args: [usize; 3]) -> usize {
+    let ret: usize;
+    // When the kernel was entered at EL2, HVC only reaches its own stub, so the firmware must be
+    // called with SMC.
+    if PSCI_USE_SMC.load(Ordering::Relaxed) || super::el2::ENTERED_AT_EL2.load(Ordering::Relaxed) {
+        asm!(
+            "smc   #0",
+            inlateout("x0") function => ret,
+            inlateout("x1") args[0] => _,
+            inlateout("x2") args[1] => _,
+            inlateout("x3") args[2] => _,
+            options(nostack),
+        );
+    } else {
+        asm!(
+            "hvc   #0",
+            inlateout("x0") function => ret,
+            inlateout("x1") args[0] => _,
+            inlateout("x2") args[1] => _,
+            inlateout("x3") args[2] => _,
+            options(nostack),
+        );
+    }
+    ret
+}
+
+/// Start the CPU with affinity `mpidr` at the physical address `entry`, with the MMU off and
+/// `context` in x0. Returns the PSCI error code on failure.
+pub unsafe fn psci_cpu_on(mpidr: usize, entry: usize, context: usize) -> Result<(), isize> {
+    match psci_call(PSCI_CPU_ON, [mpidr, entry, context]) as isize {
+        0 => Ok(()),
+        err => Err(err),
+    }
+}
+
+/// Turn this CPU off, returning the PSCI error code if it failed.
+pub unsafe fn psci_cpu_off() -> isize {
+    psci_call(PSCI_CPU_OFF, [0; 3]) as isize
+}
+
+/// Whether the CPU with affinity `mpidr` is off, as reported by AFFINITY_INFO.
+pub unsafe fn psci_cpu_is_off(mpidr: usize) -> bool {
+    // 0 is ON, 1 OFF and 2 ON_PENDING.
+    psci_call(PSCI_AFFINITY_INFO, [mpidr, 0, 0]) as isize == 1
+}
+
+/// Whether the firmware implements SYSTEM_SUSPEND, which PSCI versions before 1.0 do not.
+pub fn psci_has_system_suspend() -> bool {
+    unsafe { psci_call(PSCI_FEATURES, [PSCI_SYSTEM_SUSPEND, 0, 0]) as isize >= 0 }
+}
+
+/// Suspend the system to RAM, once all other CPUs are off. On waking up, this CPU starts at the
+/// physical address `entry` like with [`psci_cpu_on`]. Returns the PSCI error code on failure.
+pub unsafe fn psci_system_suspend(entry: usize, context: usize) -> Result<(), isize> {
+    match psci_call(PSCI_SYSTEM_SUSPEND, [entry, context, 0]) as isize {
+        0 => Ok(()),
+        err => Err(err),
+    }
+}
+
+/// Whether the firmware implements SMCCC_ARCH_WORKAROUND_1, which invalidates the branch
+/// predictors of this CPU. It is reached through the same conduit as PSCI, from SMCCC 1.1 on.
+pub fn smccc_has_workaround_1() -> bool {
+    unsafe {
+        psci_call(PSCI_FEATURES, [SMCCC_VERSION, 0, 0]) as isize >= 0
+            && psci_call(SMCCC_VERSION, [0; 3]) as isize >= 0x1_0001
+            && psci_call(SMCCC_ARCH_FEATURES, [SMCCC_ARCH_WORKAROUND_1, 0, 0]) as isize == 0
+    }
+}
+
+/// Invalidate the branch predictors of this CPU, if [`smccc_has_workaround_1`].
+pub unsafe fn smccc_workaround_1() {
+    psci_call(SMCCC_ARCH_WORKAROUND_1, [0; 3]);
+}
+
+/// Stop all other CPUs. Nothing needs to be done, as PSCI SYSTEM_OFF and SYSTEM_RESET stop all
+/// CPUs themselves.
+pub unsafe fn halt_other_cpus() {}
+
+fn halt_forever() -> ! {
+    loop {
+        unsafe {
+            crate::interrupt::disable();
+            asm!("wfi");
+        }
+    }
+}
+
+/// Wait for [`unpark`] on an offline CPU. Interrupts stay masked, as there are no IPIs, and its
+/// timer is disarmed.
+pub unsafe fn park_wait() {
+    asm!("wfe");
+}
+
+/// Wake up the CPUs in [`park_wait`], which check whether they are still offline.
+pub fn unpark(_cpu: LogicalCpuId) {
+    unsafe {
+        asm!("dsb ish", "sev");
+    }
+}
 
 pub unsafe fn kreset() -> ! {
     println!("kreset");
 
-    asm!("hvc   #0",
-         in("x0") 0x8400_0009_usize,
-         options(noreturn),
-    )
+    halt_other_cpus();
+    crate::interrupt::disable();
+
+    let ret = psci_call(PSCI_SYSTEM_RESET, [0; 3]);
+    println!("PSCI SYSTEM_RESET failed: {:#x}", ret);
+    halt_forever();
 }
 
 pub unsafe fn emergency_reset() -> ! {
-    asm!("hvc   #0",
-         in("x0")  0x8400_0009_usize,
-         options(noreturn),
-    )
+    psci_call(PSCI_SYSTEM_RESET, [0; 3]);
+    halt_forever();
 }
 
 pub unsafe fn kstop() -> ! {
     println!("kstop");
 
-    asm!("hvc   #0",
-         in("x0")  0x8400_0008_usize,
-         options(noreturn),
-    )
+    halt_other_cpus();
+    crate::interrupt::disable();
+
+    let ret = psci_call(PSCI_SYSTEM_OFF, [0; 3]);
+    println!("PSCI SYSTEM_OFF failed: {:#x}", ret);
+    halt_forever();
 }
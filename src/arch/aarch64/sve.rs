@@ -0,0 +1,211 @@
+//! The Scalable Vector Extension, whose 32 Z registers extend the FP/SIMD V registers to a vector
+//! length of 128 to 2048 bits chosen by the CPU, with 16 predicate registers and the first-fault
+//! register, each an eighth of that.
+//!
+//! When it is implemented, every CPU uses the largest vector length of the BSP, discovered at boot
+//! with RDVL, and the registers are saved and restored with the FP/SIMD ones, after them in `kfx`.
+
+use core::arch::asm;
+
+use spin::Once;
+
+/// CPACR_EL1.ZEN, not trapping SVE at EL0 and EL1.
+const CPACR_ZEN: u64 = 0b11 << 16;
+/// ZCR_EL1.LEN of the largest vector length, which the CPU lowers to the largest it implements.
+const ZCR_LEN_MAX: u64 = 0xF;
+
+/// Vector length in bytes, or None if SVE is not implemented by the BSP.
+static VECTOR_LENGTH: Once<Option<usize>> = Once::new();
+
+/// Whether ID_AA64PFR0_EL1.SVE reports SVE as implemented.
+fn implemented() -> bool {
+    let pfr0: u64;
+    unsafe { asm!("mrs {}, id_aa64pfr0_el1", out(reg) pfr0) };
+    (pfr0 >> 32) & 0xF != 0
+}
+
+/// Enable SVE on this CPU, discovering the vector length on the BSP and using the same on the
+/// others. Called again when a CPU is powered on after suspending.
+pub unsafe fn init() {
+    let bsp_length = VECTOR_LENGTH.get().copied();
+    if bsp_length == Some(None) {
+        return;
+    }
+    if !implemented() {
+        if bsp_length.is_some() {
+            log::warn!("SVE is not implemented by this CPU, unlike the BSP");
+        }
+        VECTOR_LENGTH.call_once(|| None);
+        return;
+    }
+
+    let cpacr: u64;
+    asm!("mrs {}, cpacr_el1", out(reg) cpacr);
+    asm!("msr cpacr_el1, {}", "isb", in(reg) cpacr | CPACR_ZEN);
+
+    let len = bsp_length
+        .flatten()
+        .map_or(ZCR_LEN_MAX, |length| (length / 16 - 1) as u64);
+    set_len(len);
+    let length = rdvl();
+
+    match bsp_length {
+        None => {
+            VECTOR_LENGTH.call_once(|| Some(length));
+            log::info!("SVE vector length of {} bytes", length);
+        }
+        Some(Some(bsp_length)) if bsp_length != length => log::warn!(
+            "SVE vector length of {} bytes, unlike {} on the BSP",
+            length,
+            bsp_length
+        ),
+        _ => (),
+    }
+}
+
+#[target_feature(enable = "sve")]
+unsafe fn set_len(len: u64) {
+    asm!("msr zcr_el1, {}", "isb", in(reg) len);
+}
+
+#[target_feature(enable = "sve")]
+unsafe fn rdvl() -> usize {
+    let length: usize;
+    asm!("rdvl {}, #1", out(reg) length);
+    length
+}
+
+/// Vector length in bytes, if SVE is used.
+pub fn vector_length() -> Option<usize> {
+    VECTOR_LENGTH.get().copied().flatten()
+}
+
+/// Size of the area the registers are saved to, or zero if SVE is not used.
+pub fn state_size() -> usize {
+    vector_length().map_or(0, |length| 32 * length + 17 * (length / 8))
+}
+
+/// Save the Z registers, followed by the predicate registers and the first-fault register, to an
+/// area of [`state_size`] bytes. Clobbers P0.
+#[target_feature(enable = "sve")]
+#[naked]
+pub unsafe extern "C" fn save(area: *mut u8) {
+    asm!(
+        "str z0, [x0, #0, mul vl]",
+        "str z1, [x0, #1, mul vl]",
+        "str z2, [x0, #2, mul vl]",
+        "str z3, [x0, #3, mul vl]",
+        "str z4, [x0, #4, mul vl]",
+        "str z5, [x0, #5, mul vl]",
+        "str z6, [x0, #6, mul vl]",
+        "str z7, [x0, #7, mul vl]",
+        "str z8, [x0, #8, mul vl]",
+        "str z9, [x0, #9, mul vl]",
+        "str z10, [x0, #10, mul vl]",
+        "str z11, [x0, #11, mul vl]",
+        "str z12, [x0, #12, mul vl]",
+        "str z13, [x0, #13, mul vl]",
+        "str z14, [x0, #14, mul vl]",
+        "str z15, [x0, #15, mul vl]",
+        "str z16, [x0, #16, mul vl]",
+        "str z17, [x0, #17, mul vl]",
+        "str z18, [x0, #18, mul vl]",
+        "str z19, [x0, #19, mul vl]",
+        "str z20, [x0, #20, mul vl]",
+        "str z21, [x0, #21, mul vl]",
+        "str z22, [x0, #22, mul vl]",
+        "str z23, [x0, #23, mul vl]",
+        "str z24, [x0, #24, mul vl]",
+        "str z25, [x0, #25, mul vl]",
+        "str z26, [x0, #26, mul vl]",
+        "str z27, [x0, #27, mul vl]",
+        "str z28, [x0, #28, mul vl]",
+        "str z29, [x0, #29, mul vl]",
+        "str z30, [x0, #30, mul vl]",
+        "str z31, [x0, #31, mul vl]",
+        "addvl x0, x0, #16",
+        "addvl x0, x0, #16",
+        "str p0, [x0, #0, mul vl]",
+        "str p1, [x0, #1, mul vl]",
+        "str p2, [x0, #2, mul vl]",
+        "str p3, [x0, #3, mul vl]",
+        "str p4, [x0, #4, mul vl]",
+        "str p5, [x0, #5, mul vl]",
+        "str p6, [x0, #6, mul vl]",
+        "str p7, [x0, #7, mul vl]",
+        "str p8, [x0, #8, mul vl]",
+        "str p9, [x0, #9, mul vl]",
+        "str p10, [x0, #10, mul vl]",
+        "str p11, [x0, #11, mul vl]",
+        "str p12, [x0, #12, mul vl]",
+        "str p13, [x0, #13, mul vl]",
+        "str p14, [x0, #14, mul vl]",
+        "str p15, [x0, #15, mul vl]",
+        "rdffr p0.b",
+        "str p0, [x0, #16, mul vl]",
+        "ret",
+        options(noreturn),
+    );
+}
+
+/// Load the registers saved by [`save`] from `area`.
+#[target_feature(enable = "sve")]
+#[naked]
+pub unsafe extern "C" fn load(area: *const u8) {
+    asm!(
+        "addvl x9, x0, #16",
+        "addvl x9, x9, #16",
+        "ldr p0, [x9, #16, mul vl]",
+        "wrffr p0.b",
+        "ldr p0, [x9, #0, mul vl]",
+        "ldr p1, [x9, #1, mul vl]",
+        "ldr p2, [x9, #2, mul vl]",
+        "ldr p3, [x9, #3, mul vl]",
+        "ldr p4, [x9, #4, mul vl]",
+        "ldr p5, [x9, #5, mul vl]",
+        "ldr p6, [x9, #6, mul vl]",
+        "ldr p7, [x9, #7, mul vl]",
+        "ldr p8, [x9, #8, mul vl]",
+        "ldr p9, [x9, #9, mul vl]",
+        "ldr p10, [x9, #10, mul vl]",
+        "ldr p11, [x9, #11, mul vl]",
+        "ldr p12, [x9, #12, mul vl]",
+        "ldr p13, [x9, #13, mul vl]",
+        "ldr p14, [x9, #14, mul vl]",
+        "ldr p15, [x9, #15, mul vl]",
+        "ldr z0, [x0, #0, mul vl]",
+        "ldr z1, [x0, #1, mul vl]",
+        "ldr z2, [x0, #2, mul vl]",
+        "ldr z3, [x0, #3, mul vl]",
+        "ldr z4, [x0, #4, mul vl]",
+        "ldr z5, [x0, #5, mul vl]",
+        "ldr z6, [x0, #6, mul vl]",
+        "ldr z7, [x0, #7, mul vl]",
+        "ldr z8, [x0, #8, mul vl]",
+        "ldr z9, [x0, #9, mul vl]",
+        "ldr z10, [x0, #10, mul vl]",
+        "ldr z11, [x0, #11, mul vl]",
+        "ldr z12, [x0, #12, mul vl]",
+        "ldr z13, [x0, #13, mul vl]",
+        "ldr z14, [x0, #14, mul vl]",
+        "ldr z15, [x0, #15, mul vl]",
+        "ldr z16, [x0, #16, mul vl]",
+        "ldr z17, [x0, #17, mul vl]",
+        "ldr z18, [x0, #18, mul vl]",
+        "ldr z19, [x0, #19, mul vl]",
+        "ldr z20, [x0, #20, mul vl]",
+        "ldr z21, [x0, #21, mul vl]",
+        "ldr z22, [x0, #22, mul vl]",
+        "ldr z23, [x0, #23, mul vl]",
+        "ldr z24, [x0, #24, mul vl]",
+        "ldr z25, [x0, #25, mul vl]",
+        "ldr z26, [x0, #26, mul vl]",
+        "ldr z27, [x0, #27, mul vl]",
+        "ldr z28, [x0, #28, mul vl]",
+        "ldr z29, [x0, #29, mul vl]",
+        "ldr z30, [x0, #30, mul vl]",
+        "ldr z31, [x0, #31, mul vl]",
+        "ret",
+        options(noreturn),
+    );
+}
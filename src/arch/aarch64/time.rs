@@ -1,4 +1,43 @@
+use crate::{
+    device::cpu::registers::control_regs,
+    time::NANOS_PER_SEC,
+    vdso::{VdsoClock, VDSO_CLOCK_CNTVCT},
+};
+
 pub fn monotonic_absolute() -> u128 {
-    //TODO: aarch64 generic timer counter
-    *crate::time::OFFSET.lock()
+    let (counter, freq) = unsafe { (control_regs::cntvct_el0(), control_regs::cntfreq_el0()) };
+    if freq == 0 {
+        return 0;
+    }
+    u128::from(counter) * NANOS_PER_SEC / u128::from(freq)
+}
+/// The clock parameters for the vDSO, as userspace can read the virtual count.
+pub fn vdso_clock() -> Option<VdsoClock> {
+    let frequency = unsafe { control_regs::cntfreq_el0() };
+    (frequency != 0).then(|| VdsoClock {
+        mode: VDSO_CLOCK_CNTVCT,
+        base_counter: 0,
+        base_nanos: 0,
+        frequency: frequency.into(),
+    })
+}
+/// Arm the one-shot timer of this CPU.
+pub unsafe fn arm_oneshot(deadline: u128) {
+    super::device::generic_timer::arm(deadline);
+}
+/// Stop the one-shot timer of this CPU, as it goes offline.
+pub unsafe fn disarm_oneshot() {
+    super::device::generic_timer::disarm();
+}
+
+/// Wall clock time from the RTC, which keeps running while suspended, in nanoseconds since the
+/// Unix epoch.
+pub fn persistent_clock() -> Option<u128> {
+    super::device::rtc::read()
+}
+pub unsafe fn resume() {
+    super::device::generic_timer::resume();
+}
+pub unsafe fn resume_ap() {
+    super::device::generic_timer::resume();
 }
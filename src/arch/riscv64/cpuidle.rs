@@ -0,0 +1,18 @@
+//! Idle states: only WFI.
+
+use crate::idle::IdleState;
+
+static STATES: [IdleState; 1] = [IdleState {
+    name: "wfi",
+    target_residency: 0,
+    hint: 0,
+}];
+
+pub fn states() -> &'static [IdleState] {
+    &STATES
+}
+
+/// Enter `state` until the next interrupt, with interrupts disabled, which are enabled on return.
+pub unsafe fn enter(_state: &IdleState) {
+    crate::interrupt::enable_and_halt();
+}
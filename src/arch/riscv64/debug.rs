@@ -1,5 +1,6 @@
 use crate::{
     arch::riscv64::sbi::SBI,
+    devices::console::{self, Output},
     log::{Log, LOG},
 };
 use core::fmt;
@@ -39,11 +40,18 @@ impl<'a> Writer<'a> {
 
         #[cfg(feature = "graphical_debug")]
         {
-            if let Some(ref mut display) = *self.display {
+            if let Some(ref mut display) = *self.display
+                && console::enabled(Output::Display)
+            {
                 let _ = display.write(buf);
             }
         }
 
+        // The serial ports, and any other debug port.
+        if !console::enabled(Output::Serial) {
+            return;
+        }
+
         #[cfg(feature = "serial_debug")]
         {
             if let Some(ref mut serial) = *self.serial {
@@ -1,4 +1,4 @@
-use spin::Mutex;
+use crate::sync::Mutex;
 use syscall::{Io, Mmio};
 use crate::context::switch::tick;
 
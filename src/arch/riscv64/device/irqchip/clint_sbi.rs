@@ -3,12 +3,12 @@ use crate::{
     context,
     context::timeout,
     dtb::irqchip::{register_irq, InterruptHandler, IRQ_CHIP},
+    sync::Mutex,
 };
 use alloc::{boxed::Box, vec::Vec};
 use byteorder::{ByteOrder, BE};
 use core::{arch::asm, cmp::max};
 use fdt::node::FdtNode;
-use spin::Mutex;
 // This is a Core-Local Interruptor (CLINT). A single device directly routed into each HLIC
 // It is responsible for local timer and IPI interrupts
 // An example DTS:
@@ -103,8 +103,16 @@ impl Clint {
                 .ic
                 .irq_xlate(&[irq1, 0, 0])
                 .expect("Couldn't get virq 1 from HLIC");
-            register_irq(virq0 as u32, Box::new(ClintConnector { hart_id, irq: 0 }));
-            register_irq(virq1 as u32, Box::new(ClintConnector { hart_id, irq: 1 }));
+            register_irq(
+                virq0 as u32,
+                "clint ipi",
+                Box::new(ClintConnector { hart_id, irq: 0 }),
+            );
+            register_irq(
+                virq1 as u32,
+                "clint timer",
+                Box::new(ClintConnector { hart_id, irq: 1 }),
+            );
             hart_id += 1;
         }
         me.next_event.resize_with(hart_id, || 0);
@@ -30,6 +30,11 @@ impl SerialPort {
         }
         debug_notify();
     }
+    /// Read a received byte, if any, without passing it on.
+    #[cfg(feature = "debugger")]
+    pub fn receive_byte(&mut self) -> Option<u8> {
+        self.inner.receive()
+    }
 }
 
 pub static COM1: Mutex<Option<SerialPort>> = Mutex::new(None);
@@ -93,7 +98,7 @@ pub unsafe fn init(fdt: &Fdt) -> Option<()> {
             .irq_xlate(&intr_data)
             .unwrap();
         info!("serial_port virq = {}", virq);
-        register_irq(virq as u32, Box::new(Com1Irq {}));
+        register_irq(virq as u32, "serial", Box::new(Com1Irq {}));
         IRQ_CHIP.irq_enable(virq as u32);
     }
     if let Some(ref mut _serial_port) = *COM1.lock() {
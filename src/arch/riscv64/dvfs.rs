@@ -0,0 +1,7 @@
+//! No frequency scaling driver yet.
+
+use crate::cpufreq::CpufreqDriver;
+
+pub fn driver() -> Option<&'static dyn CpufreqDriver> {
+    None
+}
@@ -4,7 +4,7 @@ use rmm::VirtualAddress;
 
 use crate::{
     arch::{device::irqchip, start::BOOT_HART_ID},
-    memory::GenericPfFlags,
+    memory::{FaultSignal, GenericPfFlags},
     panic::stack_trace,
     ptrace, syscall,
     syscall::flag::*,
@@ -137,6 +137,7 @@ unsafe fn handle_system_exception(scause: usize, regs: &InterruptStack) {
 }
 
 unsafe fn handle_interrupt(interrupt: usize) {
+    let _irq = crate::sync::lockdep::IrqScope::enter();
     // FIXME retrieve from percpu area
     // For now all the interrupts go to boot hart so this suffices...
     let hart: usize = BOOT_HART_ID.load(Ordering::Relaxed);
@@ -205,5 +206,14 @@ unsafe fn page_fault(scause: usize, regs: &mut InterruptStack, user_mode: bool)
     generic_flags.set(GenericPfFlags::INVL, false);
     generic_flags.set(GenericPfFlags::PRESENT, false);
 
-    crate::memory::page_fault_handler(regs, generic_flags, address).is_ok()
+    match crate::memory::page_fault_handler(regs, generic_flags, address) {
+        Ok(()) => true,
+        // Other signals than SIGSEGV are raised here, as the caller raises SIGSEGV for any fault
+        // it was not told was handled.
+        Err(FaultSignal(signal)) if user_mode && signal != SIGSEGV => {
+            crate::ksignal(signal);
+            true
+        }
+        Err(_) => false,
+    }
 }
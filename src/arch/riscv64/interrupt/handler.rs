@@ -213,6 +213,20 @@ impl ArchIntCtx for InterruptStack {
     }
 }
 
+#[cfg(feature = "debugger")]
+impl crate::debugger::DebuggerArch for InterruptStack {
+    fn user_stack_pointer(&self) -> usize {
+        self.stack_pointer()
+    }
+
+    fn poll_input() -> Option<u8> {
+        crate::device::serial::COM1
+            .lock()
+            .as_mut()
+            .and_then(|serial_port| serial_port.receive_byte())
+    }
+}
+
 /// Except for sp and tp
 #[macro_export]
 macro_rules! push_registers {
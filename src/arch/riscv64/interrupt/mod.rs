@@ -21,6 +21,17 @@ pub unsafe fn enable() {
     asm!("csrsi sstatus, 1 << 1")
 }
 
+/// Whether interrupts are enabled
+#[cfg(debug_assertions)]
+#[inline(always)]
+pub fn enabled() -> bool {
+    let sstatus: usize;
+    unsafe {
+        asm!("csrr {}, sstatus", out(reg) sstatus);
+    }
+    sstatus & (1 << 1) != 0
+}
+
 /// Set interrupts and halt
 /// This will atomically wait for the next interrupt
 /// Performing enable followed by halt is not guaranteed to be atomic, use this instead!
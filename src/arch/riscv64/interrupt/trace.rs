@@ -1,31 +1,36 @@
-use core::{arch::asm, mem};
+//! Register conventions used by the unwinder in [`crate::unwind`].
 
-pub struct StackTrace {
-    pub fp: usize,
-    pub pc_ptr: *const usize,
-}
+use core::arch::asm;
 
-impl StackTrace {
-    #[inline(always)]
-    pub unsafe fn start() -> Option<Self> {
-        let fp: usize;
-        asm!("mv {}, fp", out(reg) fp);
+use crate::unwind::FrameRecord;
 
-        let pc_ptr = fp.checked_sub(mem::size_of::<usize>())?;
-        let fp = pc_ptr.checked_sub(mem::size_of::<usize>())?;
-        Some(StackTrace {
-            fp,
-            pc_ptr: pc_ptr as *const usize,
-        })
-    }
+/// Number of DWARF registers tracked by the unwinder, x0 to x31.
+pub const DWARF_REG_COUNT: usize = 32;
+pub const DWARF_SP: u16 = 2;
+pub const DWARF_FP: u16 = 8;
+pub const DWARF_RA: u16 = 1;
+
+/// The frame pointer points just above the saved return address and frame pointer.
+pub const FRAME_RECORD: FrameRecord = FrameRecord {
+    saved_fp: -16,
+    ra: -8,
+    cfa: 0,
+};
 
-    pub unsafe fn next(self) -> Option<Self> {
-        let fp = *(self.fp as *const usize);
-        let pc_ptr = fp.checked_sub(mem::size_of::<usize>())?;
-        let fp = pc_ptr.checked_sub(mem::size_of::<usize>())?;
-        Some(StackTrace {
-            fp: fp,
-            pc_ptr: pc_ptr as *const usize,
-        })
+/// Get the instruction, stack and frame pointers at the point this is inlined into.
+#[inline(always)]
+pub fn current_regs() -> (usize, usize, usize) {
+    let (pc, sp, fp): (usize, usize, usize);
+    unsafe {
+        asm!(
+            "auipc {pc}, 0",
+            "mv {sp}, sp",
+            "mv {fp}, fp",
+            pc = out(reg) pc,
+            sp = out(reg) sp,
+            fp = out(reg) fp,
+            options(nomem, nostack),
+        );
     }
+    (pc, sp, fp)
 }
@@ -30,3 +30,7 @@ pub fn ipi_single(_kind: IpiKind, _target: crate::cpu_set::LogicalCpuId) {}
 #[cfg(feature = "multi_core")]
 #[inline(always)]
 pub fn ipi_single(_kind: IpiKind, _target: crate::cpu_set::LogicalCpuId) {}
+
+// No NMIs, so stuck CPUs cannot be interrupted.
+#[cfg(debug_assertions)]
+pub fn ipi_nmi(_target: crate::cpu_set::LogicalCpuId) {}
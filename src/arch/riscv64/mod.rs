@@ -2,14 +2,20 @@
 pub mod macros;
 
 pub mod consts;
+pub mod cpuidle;
 pub mod debug;
 pub mod device;
+pub mod dvfs;
 pub mod interrupt;
 pub mod ipi;
 pub mod misc;
 pub mod paging;
+pub mod pmu;
 pub mod rmm;
+pub mod rng;
 mod sbi;
+pub mod sleep;
+pub mod speculation;
 pub mod start;
 pub mod stop;
 pub mod time;
@@ -0,0 +1,14 @@
+//! Performance counters are not supported yet, as they are only programmable through the SBI PMU
+//! extension, so no events can be counted.
+
+use crate::perf::{PerfCounts, PerfEvent};
+
+pub fn supported(_event: PerfEvent) -> bool {
+    false
+}
+
+pub unsafe fn start() {}
+
+pub unsafe fn stop() {}
+
+pub unsafe fn read_and_reset(_counts: &mut PerfCounts) {}
@@ -1,4 +1,4 @@
-use rmm::{Arch, PageFlags, VirtualAddress};
+use rmm::{Arch, PageFlags, PhysicalAddress, VirtualAddress};
 
 pub struct KernelMapper {
     mapper: crate::paging::PageMapper,
@@ -28,3 +28,17 @@ pub unsafe fn page_flags<A: Arch>(virt: VirtualAddress) -> PageFlags<A> {
         PageFlags::new().write(true)
     }
 }
+
+/// Address space IDs are not supported, so the TLB is flushed whenever the user page table
+/// changes.
+pub const ASID_COUNT: usize = 0;
+
+pub fn asids_enabled() -> bool {
+    false
+}
+pub unsafe fn set_user_table(table: PhysicalAddress, _asid: u16, _flush: bool) {
+    <crate::paging::RmmA as Arch>::set_table(rmm::TableKind::User, table);
+}
+pub unsafe fn invalidate_all_asids() {
+    <crate::paging::RmmA as Arch>::invalidate_all();
+}
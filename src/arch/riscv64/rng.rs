@@ -0,0 +1,15 @@
+//! The time counter, for the entropy pool. The Zkr seed CSR is not used yet.
+
+use core::arch::asm;
+
+pub fn hardware_random() -> Option<u64> {
+    None
+}
+
+pub fn cycles() -> u64 {
+    let time: u64;
+    unsafe {
+        asm!("rdtime {}", out(reg) time);
+    }
+    time
+}
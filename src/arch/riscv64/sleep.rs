@@ -0,0 +1,18 @@
+//! Suspend to RAM, through the SBI system suspend extension, is not implemented on RISC-V yet.
+
+use crate::{
+    cpu_set::LogicalCpuId,
+    syscall::error::{Error, Result, EOPNOTSUPP},
+};
+
+pub fn supported() -> bool {
+    false
+}
+
+pub unsafe fn enter() -> Result<()> {
+    Err(Error::new(EOPNOTSUPP))
+}
+
+pub unsafe fn suspend_ap() {}
+
+pub unsafe fn restart_ap(_cpu: LogicalCpuId) {}
@@ -0,0 +1,19 @@
+//! No speculative execution controls yet.
+
+use crate::mitigations::{Mitigation, Status, Vulnerability};
+
+pub fn supported(_mitigation: Mitigation) -> bool {
+    false
+}
+
+pub fn recommended(_mitigation: Mitigation) -> bool {
+    false
+}
+
+pub fn status(_vulnerability: Vulnerability) -> Status {
+    Status::Unknown
+}
+
+pub unsafe fn apply() {}
+
+pub unsafe fn flush_branch_predictors() {}
@@ -1,3 +1,12 @@
+use crate::cpu_set::LogicalCpuId;
+
+/// Wait for [`unpark`] on an offline CPU, by polling as there are no IPIs yet.
+pub unsafe fn park_wait() {
+    core::hint::spin_loop();
+}
+
+pub fn unpark(_cpu: LogicalCpuId) {}
+
 pub unsafe fn kreset() -> ! {
     println!("kreset");
     unimplemented!()
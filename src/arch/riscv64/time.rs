@@ -24,3 +24,16 @@ pub fn monotonic_absolute() -> u128 {
         0
     }
 }
+
+pub fn persistent_clock() -> Option<u128> {
+    None
+}
+/// Userspace cannot read the counter until `scounteren` is set up, so it uses the syscalls.
+pub fn vdso_clock() -> Option<crate::vdso::VdsoClock> {
+    None
+}
+/// The timer is still periodic, so there is nothing to arm.
+pub unsafe fn arm_oneshot(_deadline: u128) {}
+pub unsafe fn disarm_oneshot() {}
+pub unsafe fn resume() {}
+pub unsafe fn resume_ap() {}
@@ -140,16 +140,6 @@ pub unsafe fn pcr() -> *mut ProcessorControlRegion {
     ret
 }
 
-#[cfg(feature = "pti")]
-pub unsafe fn set_tss_stack(stack: usize) {
-    use super::pti::{PTI_CONTEXT_STACK, PTI_CPU_STACK};
-    addr_of_mut!((*pcr()).tss.0.ss0).write((GDT_KERNEL_DATA << 3) as u16);
-    addr_of_mut!((*pcr()).tss.0.esp0)
-        .write((PTI_CPU_STACK.as_ptr() as usize + PTI_CPU_STACK.len()) as u32);
-    PTI_CONTEXT_STACK = stack;
-}
-
-#[cfg(not(feature = "pti"))]
 pub unsafe fn set_tss_stack(stack: usize) {
     addr_of_mut!((*pcr()).tss.0.ss0).write((GDT_KERNEL_DATA << 3) as u16);
     addr_of_mut!((*pcr()).tss.0.esp0).write(stack as u32);
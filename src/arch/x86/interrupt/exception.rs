@@ -1,8 +1,12 @@
 use x86::irq::PageFaultError;
 
 use crate::{
-    interrupt_error, interrupt_stack, ksignal, memory::GenericPfFlags, paging::VirtualAddress,
-    panic::stack_trace, ptrace, syscall::flag::*,
+    interrupt_error, interrupt_stack, ksignal,
+    memory::{FaultSignal, GenericPfFlags},
+    paging::VirtualAddress,
+    panic::stack_trace,
+    ptrace,
+    syscall::flag::*,
 };
 
 interrupt_stack!(divide_by_zero, |stack| {
@@ -13,6 +17,11 @@ interrupt_stack!(divide_by_zero, |stack| {
 });
 
 interrupt_stack!(debug, @paranoid, |stack| {
+    #[cfg(feature = "debugger")]
+    if crate::arch::watchpoint::handle_debug_trap(stack) {
+        return;
+    }
+
     let mut handled = false;
 
     // Disable singlestep before there is a breakpoint, since the breakpoint
@@ -36,6 +45,11 @@ interrupt_stack!(debug, @paranoid, |stack| {
 });
 
 interrupt_stack!(non_maskable, @paranoid, |stack| {
+    #[cfg(debug_assertions)]
+    if crate::percpu::PercpuBlock::current().maybe_print_backtrace(stack.iret.eip) {
+        return;
+    }
+
     println!("Non-maskable interrupt");
     stack.dump();
 });
@@ -150,11 +164,13 @@ interrupt_error!(page, |stack| {
         arch_flags.contains(PageFaultError::ID),
     );
 
-    if crate::memory::page_fault_handler(&mut stack.inner, generic_flags, cr2).is_err() {
+    if let Err(FaultSignal(signal)) =
+        crate::memory::page_fault_handler(&mut stack.inner, generic_flags, cr2)
+    {
         println!("Page fault: {:>08X} {:#?}", cr2.data(), arch_flags);
         stack.dump();
         stack_trace();
-        ksignal(SIGSEGV);
+        ksignal(signal);
     }
 });
 
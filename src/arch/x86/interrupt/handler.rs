@@ -324,6 +324,7 @@ macro_rules! interrupt {
         #[naked]
         pub unsafe extern "C" fn $name() {
             unsafe extern "C" fn inner() {
+                let _irq = $crate::sync::lockdep::IrqScope::enter();
                 $code
             }
 
@@ -452,6 +453,20 @@ impl ArchIntCtx for InterruptStack {
     }
 }
 
+#[cfg(feature = "debugger")]
+impl crate::debugger::DebuggerArch for InterruptStack {
+    // Two-level page tables.
+    const CHECK_CONSISTENCY: bool = false;
+
+    fn user_stack_pointer(&self) -> usize {
+        self.iret.esp
+    }
+
+    fn poll_input() -> Option<u8> {
+        crate::device::serial::COM1.lock().receive()
+    }
+}
+
 #[naked]
 pub unsafe extern "C" fn enter_usermode() {
     core::arch::asm!(
@@ -1,5 +1,8 @@
 pub use crate::arch::x86_shared::*;
 
+#[cfg(feature = "pti")]
+compile_error!("page table isolation is only supported on x86_64");
+
 #[macro_use]
 pub mod macros;
 
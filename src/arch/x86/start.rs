@@ -285,5 +285,7 @@ pub unsafe extern "C" fn kstart_ap(args_ptr: *const KernelArgsAp) -> ! {
         interrupt::pause();
     }
 
+    device::init_ap_timer();
+
     crate::kmain_ap(cpu_id);
 }
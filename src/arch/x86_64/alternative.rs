@@ -14,6 +14,11 @@ use crate::{
 
 #[cfg(all(cpu_feature_never = "xsave", not(cpu_feature_never = "xsaveopt")))]
 compile_error!("cannot force-disable xsave without force-disabling xsaveopt");
+#[cfg(all(
+    cpu_feature_never = "xsave",
+    any(cpu_feature_always = "xsavec", cpu_feature_auto = "xsavec")
+))]
+compile_error!("cannot force-disable xsave without force-disabling xsavec");
 
 #[repr(C)]
 #[derive(Clone, Copy, Debug)]
@@ -92,26 +97,49 @@ pub unsafe fn early_init(bsp: bool) {
 
         enable |= KcpuFeatures::XSAVE;
         enable.set(KcpuFeatures::XSAVEOPT, ext_state_info.has_xsaveopt());
+        enable.set(
+            KcpuFeatures::XSAVEC,
+            cfg!(not(cpu_feature_never = "xsavec")) && ext_state_info.has_xsavec(),
+        );
+
+        let ymm_upper_offset = feature_info().has_avx().then(|| {
+            xcr0 |= Xcr0::XCR0_AVX_STATE;
+
+            let state = ext_state_info
+                .iter()
+                .find(|state| {
+                    state.register() == ExtendedRegisterType::Avx
+                        && state.location() == ExtendedRegisterStateLocation::Xcr0
+                })
+                .expect("CPUID said AVX was supported but there's no state info");
+
+            if state.size() as usize != 16 * core::mem::size_of::<u128>() {
+                log::warn!("Unusual AVX state size {}", state.size());
+            }
+
+            state.offset()
+        });
+
+        // The opmask registers, the upper halves of ZMM0-15 and ZMM16-31 can only be enabled
+        // together, and only with AVX.
+        if ymm_upper_offset.is_some()
+            && has_ext_feat(|feat| feat.has_avx512f())
+            && ext_state_info.xcr0_supports_avx512_opmask()
+            && ext_state_info.xcr0_supports_avx512_zmm_hi256()
+            && ext_state_info.xcr0_supports_avx512_zmm_hi16()
+        {
+            xcr0 |=
+                Xcr0::XCR0_OPMASK_STATE | Xcr0::XCR0_ZMM_HI256_STATE | Xcr0::XCR0_HI16_ZMM_STATE;
+        }
+        x86::controlregs::xcr0_write(xcr0);
+
+        // The size reported by CPUID depends on the XCR0 it is executed with.
+        let ext_state_info = cpuid()
+            .get_extended_state_info()
+            .expect("must be present if XSAVE is supported");
 
         let info = xsave::XsaveInfo {
-            ymm_upper_offset: feature_info().has_avx().then(|| {
-                xcr0 |= Xcr0::XCR0_AVX_STATE;
-                x86::controlregs::xcr0_write(xcr0);
-
-                let state = ext_state_info
-                    .iter()
-                    .find(|state| {
-                        state.register() == ExtendedRegisterType::Avx
-                            && state.location() == ExtendedRegisterStateLocation::Xcr0
-                    })
-                    .expect("CPUID said AVX was supported but there's no state info");
-
-                if state.size() as usize != 16 * core::mem::size_of::<u128>() {
-                    log::warn!("Unusual AVX state size {}", state.size());
-                }
-
-                state.offset()
-            }),
+            ymm_upper_offset,
             xsave_size: ext_state_info.xsave_area_size_enabled_features(),
         };
         log::debug!("XSAVE: {:?}", info);
@@ -137,6 +165,7 @@ pub unsafe fn early_init(bsp: bool) {
                 && cfg!(not(cpu_feature_auto = "fsgsbase"))
                 && cfg!(not(cpu_feature_auto = "xsave"))
                 && cfg!(not(cpu_feature_auto = "xsaveopt"))
+                && cfg!(not(cpu_feature_auto = "xsavec"))
         );
     }
 
@@ -168,7 +197,10 @@ unsafe fn overwrite(relocs: &[AltReloc], enable: KcpuFeatures) {
                 .unwrap()
                 .remap(
                     page.start_address(),
-                    PageFlags::new().write(true).execute(true).global(true),
+                    PageFlags::new()
+                        .write(true)
+                        .execute(true)
+                        .global(cfg!(not(feature = "pti"))),
                 )
                 .unwrap()
                 .flush();
@@ -188,6 +220,7 @@ unsafe fn overwrite(relocs: &[AltReloc], enable: KcpuFeatures) {
             "fsgsbase" => enable.contains(KcpuFeatures::FSGSBASE),
             "xsave" => enable.contains(KcpuFeatures::XSAVE),
             "xsaveopt" => enable.contains(KcpuFeatures::XSAVEOPT),
+            "xsavec" => enable.contains(KcpuFeatures::XSAVEC),
             //_ => panic!("unknown altcode relocation: {}", name),
             _ => true,
         };
@@ -241,7 +274,10 @@ unsafe fn overwrite(relocs: &[AltReloc], enable: KcpuFeatures) {
                 .unwrap()
                 .remap(
                     page.start_address(),
-                    PageFlags::new().write(false).execute(true).global(true),
+                    PageFlags::new()
+                        .write(false)
+                        .execute(true)
+                        .global(cfg!(not(feature = "pti"))),
                 )
                 .unwrap()
                 .flush();
@@ -256,6 +292,7 @@ bitflags! {
         const FSGSBASE = 2;
         const XSAVE = 4;
         const XSAVEOPT = 8;
+        const XSAVEC = 16;
     }
 }
 
@@ -272,6 +309,8 @@ mod xsave {
     #[derive(Debug)]
     pub struct XsaveInfo {
         pub ymm_upper_offset: Option<u32>,
+        /// Size of the XSAVE area in the standard format, including the legacy region and the
+        /// header, which is never smaller than in the compacted format of XSAVEC.
         pub xsave_size: u32,
     }
     pub(super) static XSAVE_INFO: Once<XsaveInfo> = Once::new();
@@ -285,7 +324,7 @@ pub fn kfx_size() -> usize {
     #[cfg(not(cpu_feature_never = "xsave"))]
     {
         match xsave::info() {
-            Some(info) => FXSAVE_SIZE + XSAVE_HEADER_SIZE + info.xsave_size as usize,
+            Some(info) => info.xsave_size as usize,
             None => FXSAVE_SIZE,
         }
     }
@@ -0,0 +1,285 @@
+//! Control-flow Enforcement Technology, for userspace.
+//!
+//! With shadow stacks, CALL also pushes the return address to a second stack, which RET checks it
+//! against, and a mismatch raises a control protection fault, delivered as SIGSEGV. Shadow stacks
+//! are mapped read-only and dirty, which no instruction but those accessing the shadow stack, and
+//! WRSS where it is enabled, can write to. With indirect branch tracking, indirect calls and jumps
+//! must land on ENDBR64 instructions.
+//!
+//! Both are enabled per context by userspace runtimes, with the `ARCH_SHSTK_*` codes written to its
+//! `arch-prctl` handle in `proc:`, as for `arch_prctl` on Linux, which has no indirect branch
+//! tracking in userspace. The kernel maps a shadow stack of [`SHADOW_STACK_PAGES`] when it is
+//! enabled, pointing SSP to its top, so it must be enabled from a function that never returns, such
+//! as the entry point of a program or thread. It is unmapped when disabled and when the context
+//! exits. Features can be locked, after which they can be neither enabled nor disabled.
+//!
+//! Threads and forks inherit indirect branch tracking and the locked features, but not the shadow
+//! stack: a fork does not copy it, and the runtime enables one for a new thread through the handle
+//! of that thread. Executing a new program disables both, leaving the shadow stack to the old
+//! address space.
+//!
+//! Only U_CET and PL3_SSP are switched between contexts, and the kernel itself uses neither shadow
+//! stacks nor indirect branch tracking.
+
+use core::arch::{
+    asm,
+    x86_64::{__cpuid, __cpuid_count},
+};
+
+use spin::Once;
+use x86::msr::{rdmsr, wrmsr};
+
+use crate::{
+    context::{
+        memory::PageSpan, ARCH_SHSTK_DISABLE, ARCH_SHSTK_ENABLE, ARCH_SHSTK_IBT, ARCH_SHSTK_LOCK,
+        ARCH_SHSTK_SHSTK, ARCH_SHSTK_WRSS,
+    },
+    paging::{entry::EntryFlags, PageFlags, RmmA},
+    syscall::error::{Error, Result, EAGAIN, EINVAL, EOPNOTSUPP, EPERM},
+};
+
+const IA32_U_CET: u32 = 0x6A0;
+const IA32_PL3_SSP: u32 = 0x6A7;
+
+/// CR4.CET, bit 23.
+const CR4_CET_BIT: u32 = 23;
+
+const U_CET_SH_STK_EN: u64 = 1;
+const U_CET_WR_SHSTK_EN: u64 = 1 << 1;
+const U_CET_ENDBR_EN: u64 = 1 << 2;
+/// Allows indirect branches with the NOTRACK prefix, which compilers emit for jump tables.
+const U_CET_NO_TRACK_EN: u64 = 1 << 4;
+/// SUPPRESS and TRACKER, the state of indirect branch tracking.
+const U_CET_IBT_STATE: u64 = (1 << 10) | (1 << 11);
+
+/// Size of the shadow stacks mapped by the kernel, for 16384 nested calls.
+pub const SHADOW_STACK_PAGES: usize = 32;
+
+struct Caps {
+    shstk: bool,
+    ibt: bool,
+}
+
+static CAPS: Once<Caps> = Once::new();
+
+fn caps() -> &'static Caps {
+    CAPS.call_once(|| {
+        if unsafe { __cpuid(0) }.eax < 7 {
+            return Caps {
+                shstk: false,
+                ibt: false,
+            };
+        }
+        let leaf7 = unsafe { __cpuid_count(7, 0) };
+        Caps {
+            shstk: leaf7.ecx & (1 << 7) != 0,
+            ibt: leaf7.edx & (1 << 20) != 0,
+        }
+    })
+}
+
+fn supported_features() -> usize {
+    let caps = caps();
+    let mut features = 0;
+    if caps.shstk {
+        features |= ARCH_SHSTK_SHSTK | ARCH_SHSTK_WRSS;
+    }
+    if caps.ibt {
+        features |= ARCH_SHSTK_IBT;
+    }
+    features
+}
+
+/// Set CR4.CET on this CPU, if it supports shadow stacks or indirect branch tracking. Called again
+/// when a CPU is powered on after suspending.
+pub unsafe fn init() {
+    if supported_features() == 0 {
+        return;
+    }
+    // Requires CR0.WP, which is always set.
+    asm!(
+        "mov {0}, cr4",
+        "bts {0}, {bit}",
+        "mov cr4, {0}",
+        out(reg) _,
+        bit = const CR4_CET_BIT,
+    );
+    wrmsr(IA32_U_CET, 0);
+}
+
+/// The flags of a shadow stack grant, read-only and dirty.
+pub fn shadow_stack_flags(flags: PageFlags<RmmA>) -> PageFlags<RmmA> {
+    flags
+        .write(false)
+        .execute(false)
+        .custom_flag(EntryFlags::DIRTY.bits(), true)
+}
+
+pub fn is_shadow_stack(flags: PageFlags<RmmA>) -> bool {
+    !flags.has_write() && flags.data() & EntryFlags::DIRTY.bits() != 0
+}
+
+/// The U_CET bits of `features`.
+fn u_cet_bits(features: usize) -> u64 {
+    let mut bits = 0;
+    if features & ARCH_SHSTK_SHSTK != 0 {
+        bits |= U_CET_SH_STK_EN;
+    }
+    if features & ARCH_SHSTK_WRSS != 0 {
+        bits |= U_CET_WR_SHSTK_EN;
+    }
+    if features & ARCH_SHSTK_IBT != 0 {
+        bits |= U_CET_ENDBR_EN | U_CET_NO_TRACK_EN;
+    }
+    bits
+}
+
+/// CET state of a context.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CetState {
+    /// IA32_U_CET, which also holds the state of indirect branch tracking while the context is
+    /// switched out.
+    u_cet: u64,
+    /// IA32_PL3_SSP while the context is switched out.
+    ssp: u64,
+    shadow_stack: Option<PageSpan>,
+    /// Features that can no longer be enabled or disabled.
+    locked: usize,
+}
+
+impl CetState {
+    /// The state a new thread or child starts with.
+    pub fn inherit(&self) -> Self {
+        Self {
+            u_cet: self.u_cet & (U_CET_ENDBR_EN | U_CET_NO_TRACK_EN),
+            ssp: 0,
+            shadow_stack: None,
+            locked: self.locked,
+        }
+    }
+
+    /// The enabled features, as `ARCH_SHSTK_STATUS` reports them.
+    pub fn features(&self) -> usize {
+        let mut features = 0;
+        if self.u_cet & U_CET_SH_STK_EN != 0 {
+            features |= ARCH_SHSTK_SHSTK;
+        }
+        if self.u_cet & U_CET_WR_SHSTK_EN != 0 {
+            features |= ARCH_SHSTK_WRSS;
+        }
+        if self.u_cet & U_CET_ENDBR_EN != 0 {
+            features |= ARCH_SHSTK_IBT;
+        }
+        features
+    }
+
+    pub fn take_shadow_stack(&mut self) -> Option<PageSpan> {
+        self.shadow_stack.take()
+    }
+
+    fn check(&self, code: usize, features: usize) -> Result<()> {
+        let supported = supported_features();
+        if supported == 0 {
+            return Err(Error::new(EOPNOTSUPP));
+        }
+        if features & !supported != 0 {
+            return Err(Error::new(EINVAL));
+        }
+        match code {
+            ARCH_SHSTK_LOCK => Ok(()),
+            ARCH_SHSTK_ENABLE | ARCH_SHSTK_DISABLE if features & self.locked != 0 => {
+                Err(Error::new(EPERM))
+            }
+            ARCH_SHSTK_ENABLE | ARCH_SHSTK_DISABLE => Ok(()),
+            _ => Err(Error::new(EINVAL)),
+        }
+    }
+
+    /// Whether `code` enables a shadow stack the context does not have yet, which the caller then
+    /// maps before calling [`Self::with`].
+    pub fn needs_shadow_stack(&self, code: usize, features: usize) -> Result<bool> {
+        self.check(code, features)?;
+        Ok(code == ARCH_SHSTK_ENABLE
+            && features & ARCH_SHSTK_SHSTK != 0
+            && self.shadow_stack.is_none())
+    }
+
+    /// The state after applying `code` to `features`, using `shadow_stack` if it enables one, and
+    /// the shadow stack to unmap, which is `shadow_stack` if it turned out not to be needed.
+    pub fn with(
+        &self,
+        code: usize,
+        features: usize,
+        shadow_stack: Option<PageSpan>,
+    ) -> Result<(Self, Option<PageSpan>)> {
+        self.check(code, features)?;
+        let mut new = *self;
+        let mut unused = shadow_stack;
+        match code {
+            ARCH_SHSTK_LOCK => new.locked |= features,
+            ARCH_SHSTK_ENABLE => {
+                new.u_cet |= u_cet_bits(features);
+                if new.u_cet & U_CET_WR_SHSTK_EN != 0 && new.u_cet & U_CET_SH_STK_EN == 0 {
+                    return Err(Error::new(EINVAL));
+                }
+                if new.u_cet & U_CET_SH_STK_EN != 0 && new.shadow_stack.is_none() {
+                    // Disabled again since the caller checked whether one was needed.
+                    let span = unused.take().ok_or(Error::new(EAGAIN))?;
+                    new.shadow_stack = Some(span);
+                    new.ssp = span.end().start_address().data() as u64;
+                }
+            }
+            _ => {
+                let mut bits = u_cet_bits(features);
+                if features & ARCH_SHSTK_SHSTK != 0 {
+                    bits |= U_CET_WR_SHSTK_EN;
+                    new.ssp = 0;
+                    unused = new.shadow_stack.take();
+                }
+                if features & ARCH_SHSTK_IBT != 0 {
+                    bits |= U_CET_IBT_STATE;
+                }
+                new.u_cet &= !bits;
+            }
+        }
+        Ok((new, unused))
+    }
+}
+
+/// Save the CET state of the current context from the MSRs, if it uses any.
+pub unsafe fn save(state: &mut CetState) {
+    if state.u_cet != 0 {
+        state.u_cet = rdmsr(IA32_U_CET);
+        state.ssp = rdmsr(IA32_PL3_SSP);
+    }
+}
+
+/// Load the CET state of the context this CPU switches to.
+pub unsafe fn load(state: &CetState) {
+    if supported_features() != 0 {
+        wrmsr(IA32_U_CET, state.u_cet);
+        wrmsr(IA32_PL3_SSP, state.ssp);
+    }
+}
+
+/// Switch the CET state from `prev` to `next`, unless neither uses any.
+pub unsafe fn switch_to(prev: &mut CetState, next: &CetState) {
+    if prev.u_cet != 0 || next.u_cet != 0 {
+        save(prev);
+        load(next);
+    }
+}
+
+/// The MSRs of the current context, saved before suspending.
+pub unsafe fn save_msrs() -> Option<[u64; 2]> {
+    (supported_features() != 0).then(|| [rdmsr(IA32_U_CET), rdmsr(IA32_PL3_SSP)])
+}
+
+/// Enable CET again after this CPU was powered off, with the MSRs saved before.
+pub unsafe fn restore_msrs(msrs: Option<[u64; 2]>) {
+    init();
+    if let Some([u_cet, ssp]) = msrs {
+        wrmsr(IA32_U_CET, u_cet);
+        wrmsr(IA32_PL3_SSP, ssp);
+    }
+}
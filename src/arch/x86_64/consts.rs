@@ -7,11 +7,26 @@
 //   switches.
 //
 // Each PML4 entry references 512 GiB of virtual memory.
+//
+// With the `la57` feature, addresses are 57 bits wide and translated through a PML5 first, each
+// entry of which references 256 TiB. The lower half then is 256 PML5 entries, or 64 PiB, and the
+// kernel image and heap stay in the last PML4, referenced by the last PML5 entry, so that the
+// PML4 indices below are the same in both layouts.
 
 /// The size of a single PML4
 pub const PML4_SIZE: usize = 0x0000_0080_0000_0000;
 pub const PML4_MASK: usize = 0x0000_ff80_0000_0000;
 
+/// The size of a single PML5
+#[cfg(feature = "la57")]
+pub const PML5_SIZE: usize = 0x0001_0000_0000_0000;
+
+/// Bits of a canonical virtual address, above which all bits are copies of the highest one
+#[cfg(not(feature = "la57"))]
+pub const VIRT_ADDR_BITS: u32 = 48;
+#[cfg(feature = "la57")]
+pub const VIRT_ADDR_BITS: u32 = 57;
+
 /// Offset of kernel
 pub const KERNEL_MAX_SIZE: usize = 1_usize << 31;
 pub const KERNEL_OFFSET: usize = KERNEL_MAX_SIZE.wrapping_neg();
@@ -25,10 +40,16 @@ pub const KERNEL_HEAP_SIZE: usize = 1 * 1024 * 1024; // 1 MB
 
 /// Offset of physmap
 // This needs to match RMM's PHYS_OFFSET
+#[cfg(not(feature = "la57"))]
 pub const PHYS_OFFSET: usize = 0xFFFF_8000_0000_0000;
+#[cfg(feature = "la57")]
+pub const PHYS_OFFSET: usize = 0xFF00_0000_0000_0000;
 pub const PHYS_PML4: usize = (PHYS_OFFSET & PML4_MASK) / PML4_SIZE;
 
 /// End offset of the user image, i.e. kernel start
 // TODO: Make this offset at least PAGE_SIZE less? There are known hardware bugs on some arches,
 // for example on x86 if instructions execute near the 48-bit canonical address boundary.
+#[cfg(not(feature = "la57"))]
 pub const USER_END_OFFSET: usize = 256 * PML4_SIZE;
+#[cfg(feature = "la57")]
+pub const USER_END_OFFSET: usize = 256 * PML5_SIZE;
@@ -0,0 +1,144 @@
+//! Per-context data watchpoints, backed by the DR0-DR3 debug registers.
+//!
+//! The values of a context are kept in its [`DebugRegisters`], which are written by tracers
+//! through the `regs/debug` proc: handle. Userspace cannot access the debug registers itself, so
+//! they never need to be saved, and are only loaded when switching to or from a context using
+//! them. User watchpoints only use the local enable bits of DR7, leaving the global ones to the
+//! kernel watchpoints of the `debugger` feature, which take precedence while armed.
+
+use core::arch::asm;
+
+use crate::syscall::error::{Error, Result, EBUSY, EINVAL};
+
+/// Number of watchpoint slots.
+pub const SLOT_COUNT: usize = 4;
+
+/// Local exact data breakpoint matching, recommended by both Intel and AMD.
+const DR7_LE: u64 = 1 << 8;
+/// B0-B3 bits of DR6, set for the slots that were hit.
+const DR6_HITS: u64 = 0b1111;
+
+fn dr7_enable_bit(slot: usize) -> u64 {
+    1 << (slot * 2)
+}
+fn dr7_control_shift(slot: usize) -> usize {
+    16 + slot * 4
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+#[repr(C)]
+pub struct DebugRegisters {
+    /// Watched addresses, loaded into DR0-DR3.
+    pub dr: [u64; SLOT_COUNT],
+    /// Enable bits (L0-L3) and the RW and LEN fields of each slot.
+    pub dr7: u64,
+    /// The B0-B3 bits of DR6 at the last watchpoint hit, ignored when written.
+    pub dr6: u64,
+}
+
+impl DebugRegisters {
+    /// Validate registers written by userspace, and restrict them to data watchpoints on
+    /// userspace addresses.
+    pub fn sanitize(&mut self) -> Result<()> {
+        let mut dr7 = 0;
+
+        for slot in 0..SLOT_COUNT {
+            if self.dr7 & dr7_enable_bit(slot) == 0 {
+                continue;
+            }
+            let shift = dr7_control_shift(slot);
+            let control = (self.dr7 >> shift) & 0b1111;
+
+            // Only data writes (01) and data reads or writes (11). Execution breakpoints would
+            // need RF handling, and I/O breakpoints are never enabled in CR4.
+            if control & 0b01 == 0 {
+                return Err(Error::new(EINVAL));
+            }
+            let len = match control >> 2 {
+                0b00 => 1,
+                0b01 => 2,
+                0b11 => 4,
+                _ => 8,
+            };
+            let addr = self.dr[slot] as usize;
+            if addr % len != 0
+                || addr
+                    .checked_add(len)
+                    .map_or(true, |end| end > crate::USER_END_OFFSET)
+            {
+                return Err(Error::new(EINVAL));
+            }
+
+            dr7 |= dr7_enable_bit(slot) | (control << shift);
+        }
+
+        self.dr7 = if dr7 != 0 { dr7 | DR7_LE } else { 0 };
+        self.dr6 = 0;
+
+        Ok(())
+    }
+
+    pub fn in_use(&self) -> bool {
+        self.dr7 != 0
+    }
+}
+
+/// Whether kernel watchpoints currently own the debug registers.
+fn kernel_watchpoints_armed() -> bool {
+    #[cfg(feature = "debugger")]
+    {
+        crate::arch::watchpoint::armed()
+    }
+    #[cfg(not(feature = "debugger"))]
+    {
+        false
+    }
+}
+
+/// Returns an error if the debug registers cannot currently be used for user watchpoints.
+pub fn check_available() -> Result<()> {
+    if kernel_watchpoints_armed() {
+        return Err(Error::new(EBUSY));
+    }
+    Ok(())
+}
+
+/// Load the watchpoints of the next context, or disable them if it has none.
+pub unsafe fn load(regs: Option<&DebugRegisters>) {
+    if kernel_watchpoints_armed() {
+        return;
+    }
+    let empty = DebugRegisters::default();
+    let regs = regs.unwrap_or(&empty);
+
+    // Disable all watchpoints first, so that no stale address is ever enabled.
+    asm!("mov dr7, {}", in(reg) 0_u64);
+    asm!("mov dr0, {}", in(reg) regs.dr[0]);
+    asm!("mov dr1, {}", in(reg) regs.dr[1]);
+    asm!("mov dr2, {}", in(reg) regs.dr[2]);
+    asm!("mov dr3, {}", in(reg) regs.dr[3]);
+    asm!("mov dr7, {}", in(reg) regs.dr7);
+}
+
+/// Called from the debug trap handler. Returns the B0-B3 bits of the user watchpoints that were
+/// hit, after clearing them from DR6, or None if the trap was not caused by one.
+pub fn take_hits() -> Option<u64> {
+    let (dr6, dr7): (u64, u64);
+    unsafe {
+        asm!("mov {}, dr6", out(reg) dr6);
+        asm!("mov {}, dr7", out(reg) dr7);
+    }
+    let hits = (0..SLOT_COUNT)
+        .filter(|&slot| dr6 & (1 << slot) != 0 && dr7 & dr7_enable_bit(slot) != 0)
+        .fold(0, |acc, slot| acc | 1 << slot);
+
+    if hits == 0 {
+        return None;
+    }
+
+    // The processor never clears the B0-B3 status bits by itself.
+    unsafe {
+        asm!("mov dr6, {}", in(reg) dr6 & !DR6_HITS);
+    }
+    Some(hits)
+}
@@ -1,11 +1,17 @@
 //! Global descriptor table
 
-use core::{convert::TryInto, mem::size_of};
+use alloc::sync::Arc;
+use core::{
+    convert::TryInto,
+    mem::size_of,
+    sync::atomic::{AtomicU64, Ordering},
+};
 
 use crate::{
     cpu_set::LogicalCpuId,
     paging::{RmmA, RmmArch, PAGE_SIZE},
     percpu::PercpuBlock,
+    syscall::error::{Error, Result, ENOMEM},
 };
 
 use x86::{
@@ -44,6 +50,81 @@ pub const GDT_F_LONG_MODE: u8 = 1 << 5;
 
 const IOBITMAP_SIZE: u32 = 65536 / 8;
 
+/// A TSS I/O permission bitmap, where a set bit denies access to the corresponding port.
+#[derive(Clone)]
+pub struct IoBitmap {
+    /// Changed whenever the bitmap is modified, so that CPUs know when to reload it.
+    id: u64,
+    bits: [u8; IOBITMAP_SIZE as usize],
+}
+
+impl core::fmt::Debug for IoBitmap {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        f.debug_struct("IoBitmap")
+            .field("id", &self.id)
+            .finish_non_exhaustive()
+    }
+}
+
+static NEXT_IOBITMAP_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Grants access to all ports, as with IOPL 3.
+pub static IOBITMAP_ALL: IoBitmap = IoBitmap {
+    id: 0,
+    bits: [0; IOBITMAP_SIZE as usize],
+};
+
+/// Loaded into `iobitmap_id` when no bitmap has been loaded.
+const IOBITMAP_NONE: u64 = u64::MAX;
+
+impl IoBitmap {
+    /// Allocate a bitmap denying access to all ports.
+    pub fn new_denied() -> Result<Arc<Self>> {
+        Self::allocate(|bits| unsafe { bits.write_bytes(0xFF, 1) })
+    }
+
+    /// Allocate a copy of this bitmap, for a context sharing it to modify.
+    pub fn try_clone(&self) -> Result<Arc<Self>> {
+        Self::allocate(|bits| unsafe { bits.copy_from_nonoverlapping(&self.bits, 1) })
+    }
+
+    fn allocate(init: impl FnOnce(*mut [u8; IOBITMAP_SIZE as usize])) -> Result<Arc<Self>> {
+        let mut bitmap = Arc::<Self>::try_new_uninit().map_err(|_| Error::new(ENOMEM))?;
+        let ptr = Arc::get_mut(&mut bitmap)
+            .expect("bitmap was just allocated")
+            .as_mut_ptr();
+        unsafe {
+            core::ptr::addr_of_mut!((*ptr).id)
+                .write(NEXT_IOBITMAP_ID.fetch_add(1, Ordering::Relaxed));
+            init(core::ptr::addr_of_mut!((*ptr).bits));
+            Ok(bitmap.assume_init())
+        }
+    }
+
+    /// Allow or deny access to `count` ports starting at `from`. The range must be within the 16-bit
+    /// port space.
+    pub fn set(&mut self, from: usize, count: usize, allowed: bool) {
+        for port in from..from + count {
+            let (byte, bit) = (port / 8, 1 << (port % 8));
+            if allowed {
+                self.bits[byte] &= !bit;
+            } else {
+                self.bits[byte] |= bit;
+            }
+        }
+        self.id = NEXT_IOBITMAP_ID.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn bits(&self) -> &[u8] {
+        &self.bits
+    }
+
+    /// Whether access to all ports is denied, in which case the bitmap is not needed.
+    pub fn is_all_denied(&self) -> bool {
+        self.bits.iter().all(|&byte| byte == 0xFF)
+    }
+}
+
 static mut INIT_GDT: [GdtEntry; 3] = [
     // Null
     GdtEntry::new(0, 0, 0, 0),
@@ -125,11 +206,19 @@ pub struct ProcessorControlRegion {
     _rsvd: Align,
     pub tss: TaskStateSegment,
 
-    // These two fields are read by the CPU, but not currently modified by the kernel. Instead, the
-    // kernel sets the `iomap_base` field in the TSS, to either point to this bitmap, or outside
-    // the TSS, in which case userspace is not granted port IO access.
-    pub _iobitmap: [u8; IOBITMAP_SIZE as usize],
+    // These two fields are read by the CPU. The bitmap of the running context is copied into
+    // `iobitmap` when it differs from the one last loaded, and the `iomap_base` field in the TSS
+    // is set to either point to it, or outside the TSS, in which case userspace is not granted
+    // port IO access.
+    pub iobitmap: [u8; IOBITMAP_SIZE as usize],
     pub _all_ones: u8,
+
+    /// ID of the bitmap currently in `iobitmap`.
+    pub iobitmap_id: u64,
+
+    /// State of the entry code, which is only mapped along with the PCR in user page tables.
+    #[cfg(feature = "pti")]
+    pub pti: super::pti::PtiState,
 }
 
 const _: () = {
@@ -141,6 +230,13 @@ const _: () = {
     }
 };
 
+/// Offset of the state of the entry code in the PCR, see `pti`. The entry code does not use it
+/// without page table isolation.
+#[cfg(feature = "pti")]
+pub const PTI_OFFSET: usize = core::mem::offset_of!(ProcessorControlRegion, pti);
+#[cfg(not(feature = "pti"))]
+pub const PTI_OFFSET: usize = 0;
+
 pub unsafe fn pcr() -> *mut ProcessorControlRegion {
     // Primitive benchmarking of RDFSBASE and RDGSBASE in userspace, appears to indicate that
     // obtaining FSBASE/GSBASE using mov gs:[gs_self_ref] is faster than using the (probably
@@ -152,10 +248,10 @@ pub unsafe fn pcr() -> *mut ProcessorControlRegion {
 
 #[cfg(feature = "pti")]
 pub unsafe fn set_tss_stack(pcr: *mut ProcessorControlRegion, stack: usize) {
-    use super::pti::{PTI_CONTEXT_STACK, PTI_CPU_STACK};
-    core::ptr::addr_of_mut!((*pcr).tss.rsp[0])
-        .write_unaligned((PTI_CPU_STACK.as_ptr() as usize + PTI_CPU_STACK.len()) as u64);
-    PTI_CONTEXT_STACK = stack;
+    // Interrupts from userspace arrive on the entry stack, and the entry code moves them to the
+    // kernel stack once it has loaded the kernel page table.
+    let entry_stack = (*pcr).pti.set_kernel_stack(stack);
+    core::ptr::addr_of_mut!((*pcr).tss.rsp[0]).write_unaligned(entry_stack as u64);
 }
 
 #[cfg(not(feature = "pti"))]
@@ -164,11 +260,17 @@ pub unsafe fn set_tss_stack(pcr: *mut ProcessorControlRegion, stack: usize) {
     core::ptr::addr_of_mut!((*pcr).tss.rsp[0]).write_unaligned(stack as u64);
 }
 
-pub unsafe fn set_userspace_io_allowed(pcr: *mut ProcessorControlRegion, allowed: bool) {
-    let offset = if allowed {
-        u16::try_from(size_of::<TaskStateSegment>()).unwrap()
-    } else {
-        0xFFFF
+/// Set the ports userspace may access, loading `bitmap` into the TSS if it is not already there.
+pub unsafe fn set_userspace_io(pcr: *mut ProcessorControlRegion, bitmap: Option<&IoBitmap>) {
+    let offset = match bitmap {
+        Some(bitmap) => {
+            if (*pcr).iobitmap_id != bitmap.id {
+                (*pcr).iobitmap = bitmap.bits;
+                (*pcr).iobitmap_id = bitmap.id;
+            }
+            u16::try_from(size_of::<TaskStateSegment>()).unwrap()
+        }
+        None => 0xFFFF,
     };
     core::ptr::addr_of_mut!((*pcr).tss.iomap_base).write(offset);
 }
@@ -224,6 +326,7 @@ pub unsafe fn init_paging(stack_offset: usize, cpu_id: LogicalCpuId) {
     {
         pcr.tss.iomap_base = 0xFFFF;
         pcr._all_ones = 0xFF;
+        pcr.iobitmap_id = IOBITMAP_NONE;
 
         let tss = &mut pcr.tss as *mut TaskStateSegment as usize as u64;
         let tss_lo = (tss & 0xFFFF_FFFF) as u32;
@@ -264,6 +367,23 @@ pub unsafe fn init_paging(stack_offset: usize, cpu_id: LogicalCpuId) {
 
     crate::percpu::init_tlb_shootdown(cpu_id, &mut pcr.percpu);
 }
+/// Load the GDT in `pcr` and the task register again, after the CPU lost them while the system was
+/// suspended. This resets FSBASE and GSBASE.
+#[cfg(feature = "acpi")]
+pub unsafe fn reload(pcr: *mut ProcessorControlRegion) {
+    let gdt = &mut (*pcr).gdt;
+    dtables::lgdt(&DescriptorTablePointer {
+        limit: (gdt.len() * size_of::<GdtEntry>() - 1) as u16,
+        base: gdt.as_ptr() as *const SegmentDescriptor,
+    });
+
+    load_segments();
+
+    // Loading the task register marked the TSS busy, and it cannot be loaded again while it is.
+    gdt[GDT_TSS].access = gdt[GDT_TSS].access & !0xF | GDT_A_TSS_AVAIL;
+    task::load_tr(SegmentSelector::new(GDT_TSS as u16, Ring::Ring0));
+}
+
 #[derive(Copy, Clone, Debug)]
 #[repr(C, packed)]
 pub struct GdtEntry {
@@ -303,4 +423,20 @@ impl PercpuBlock {
     pub fn current() -> &'static Self {
         unsafe { &*core::ptr::addr_of!((*pcr()).percpu) }
     }
+
+    /// The `vars_offset` of this CPU, with a single GS-relative load.
+    #[inline(always)]
+    pub fn current_vars_offset() -> usize {
+        let offset: usize;
+        unsafe {
+            core::arch::asm!(
+                "mov {}, gs:[{}]",
+                out(reg) offset,
+                const core::mem::offset_of!(ProcessorControlRegion, percpu)
+                    + core::mem::offset_of!(PercpuBlock, vars_offset),
+                options(nostack, readonly, preserves_flags),
+            );
+        }
+        offset
+    }
 }
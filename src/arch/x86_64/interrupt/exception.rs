@@ -1,8 +1,12 @@
 use x86::irq::PageFaultError;
 
 use crate::{
-    interrupt_error, interrupt_stack, ksignal, memory::GenericPfFlags, paging::VirtualAddress,
-    panic::stack_trace, ptrace, syscall::flag::*,
+    interrupt_error, interrupt_stack, ksignal,
+    memory::{FaultSignal, GenericPfFlags},
+    paging::VirtualAddress,
+    panic::stack_trace,
+    ptrace,
+    syscall::flag::*,
 };
 
 interrupt_stack!(divide_by_zero, |stack| {
@@ -13,6 +17,31 @@ interrupt_stack!(divide_by_zero, |stack| {
 });
 
 interrupt_stack!(debug, @paranoid, |stack| {
+    #[cfg(feature = "debugger")]
+    if crate::arch::watchpoint::handle_debug_trap(stack) {
+        return;
+    }
+
+    #[cfg(feature = "gdbstub")]
+    if crate::gdbstub::handle_debug(stack) {
+        return;
+    }
+
+    if let Some(hits) = crate::arch::debug_regs::take_hits() {
+        // Watched user memory can also be accessed by the kernel, when copying to or from
+        // userspace. Those accesses are not reported.
+        if stack.iret.cs & 3 != 3 {
+            return;
+        }
+        crate::context::current().write().record_watchpoint_hits(hits);
+        if ptrace::breakpoint_callback(ptrace::PTRACE_STOP_WATCHPOINT, None).is_none() {
+            println!("Watchpoint trap");
+            stack.dump();
+            ksignal(SIGTRAP);
+        }
+        return;
+    }
+
     let mut handled = false;
 
     // Disable singlestep before there is a breakpoint, since the breakpoint
@@ -36,8 +65,17 @@ interrupt_stack!(debug, @paranoid, |stack| {
 });
 
 interrupt_stack!(non_maskable, @paranoid, |stack| {
+    #[cfg(debug_assertions)]
+    if crate::percpu::PercpuBlock::current().maybe_print_backtrace(stack.iret.rip) {
+        return;
+    }
+
+    // The NMIs of the sampling counter and of the profiling CPU both record a sample.
     #[cfg(feature = "profiling")]
-    crate::profiling::nmi_handler(stack);
+    {
+        crate::perf::handle_sample_nmi();
+        crate::profiling::nmi_handler(stack);
+    }
 
     #[cfg(not(feature = "profiling"))]
     {
@@ -61,6 +99,11 @@ interrupt_stack!(breakpoint, |stack| {
     // int3 instruction. After all, it's the sanest thing to do.
     stack.iret.rip -= 1;
 
+    #[cfg(feature = "gdbstub")]
+    if crate::gdbstub::handle_breakpoint(stack) {
+        return;
+    }
+
     if ptrace::breakpoint_callback(PTRACE_STOP_BREAKPOINT, None).is_none() {
         println!("Breakpoint trap");
         stack.dump();
@@ -90,6 +133,9 @@ interrupt_stack!(invalid_opcode, |stack| {
 });
 
 interrupt_stack!(device_not_available, |stack| {
+    if crate::context::fpu_trap() {
+        return;
+    }
     println!("Device not available fault");
     stack.dump();
     stack_trace();
@@ -134,6 +180,13 @@ interrupt_error!(protection, |stack, code| {
 interrupt_error!(page, |stack, code| {
     let cr2 = VirtualAddress::new(unsafe { x86::controlregs::cr2() });
     let arch_flags = PageFaultError::from_bits_truncate(code as u32);
+
+    // The page table userspace runs with may only lack the top-level entry.
+    #[cfg(feature = "pti")]
+    if arch_flags.contains(PageFaultError::US) && crate::arch::pti::sync_fault(cr2.data()) {
+        return;
+    }
+
     let mut generic_flags = GenericPfFlags::empty();
 
     generic_flags.set(
@@ -157,11 +210,11 @@ interrupt_error!(page, |stack, code| {
         arch_flags.contains(PageFaultError::ID),
     );
 
-    if crate::memory::page_fault_handler(stack, generic_flags, cr2).is_err() {
+    if let Err(FaultSignal(signal)) = crate::memory::page_fault_handler(stack, generic_flags, cr2) {
         println!("Page fault: {:>016X} {:#?}", cr2.data(), arch_flags);
         stack.dump();
         stack_trace();
-        ksignal(SIGSEGV);
+        ksignal(signal);
     }
 });
 
@@ -203,6 +256,13 @@ interrupt_stack!(virtualization, |stack| {
     ksignal(SIGBUS);
 });
 
+interrupt_error!(control_protection, |stack, code| {
+    println!("Control protection fault code={:#0x}", code);
+    stack.dump();
+    stack_trace();
+    ksignal(SIGSEGV);
+});
+
 interrupt_error!(security, |stack, _code| {
     println!("Security exception");
     stack.dump();
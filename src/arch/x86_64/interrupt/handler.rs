@@ -96,6 +96,28 @@ pub struct InterruptStack {
 }
 
 impl InterruptStack {
+    /// The interrupted registers by DWARF register number, with RIP as the return address column.
+    pub fn dwarf_regs(&self) -> [usize; 17] {
+        [
+            self.scratch.rax,
+            self.scratch.rdx,
+            self.scratch.rcx,
+            self.preserved.rbx,
+            self.scratch.rsi,
+            self.scratch.rdi,
+            self.preserved.rbp,
+            self.iret.rsp,
+            self.scratch.r8,
+            self.scratch.r9,
+            self.scratch.r10,
+            self.scratch.r11,
+            self.preserved.r12,
+            self.preserved.r13,
+            self.preserved.r14,
+            self.preserved.r15,
+            self.iret.rip,
+        ]
+    }
     pub fn init(&mut self) {
         // Always enable interrupts!
         self.iret.rflags = x86::bits64::rflags::RFlags::FLAGS_IF.bits() as usize;
@@ -105,6 +127,9 @@ impl InterruptStack {
     pub fn set_stack_pointer(&mut self, rsp: usize) {
         self.iret.rsp = rsp;
     }
+    pub fn stack_pointer(&self) -> usize {
+        self.iret.rsp
+    }
     pub fn instr_pointer(&self) -> usize {
         self.iret.rip
     }
@@ -187,6 +212,21 @@ impl InterruptStack {
     }
 }
 
+/// Record the return address of the preceding call to an inner handler, for the unwinder to find
+/// the [`InterruptStack`] it was passed.
+#[macro_export]
+macro_rules! interrupt_frame {
+    () => {
+        "
+        91:
+        .pushsection .interrupt_frames, \"a\"
+        .balign 8
+        .quad 91b
+        .popsection
+    "
+    };
+}
+
 #[macro_export]
 macro_rules! push_scratch {
     () => {
@@ -274,6 +314,219 @@ macro_rules! swapgs_iff_ring3_fast_errorcode {
     };
 }
 
+// With page table isolation, interrupts from userspace arrive on the entry stack with the user page
+// table loaded, see `pti`. The entry code saves RDI in the scratch field (offset 0) of the PTI
+// state, loads the kernel page table (offset 8), and moves the interrupt frame to the kernel stack
+// (offset 32). Before returning, it moves the frame back to the entry stack (offset 40) and loads
+// the user page table (offset 16), replacing it with the one to load next time (offset 24).
+#[cfg(feature = "pti")]
+macro_rules! pti_enter_iff_ring3 {
+    () => {
+        concat!(
+            "
+            test QWORD PTR [rsp + 8], 0x3
+            jz 3f
+            swapgs
+            mov gs:[{pti}], rdi
+            mov rdi, gs:[{pti} + 8]
+            mov cr3, rdi
+            mov rdi, gs:[{pti} + 32]
+            sub rdi, 40
+            ",
+            pti_copy_frame!(5),
+            "
+            mov rsp, rdi
+            mov rdi, gs:[{pti}]
+            swapgs
+            3:
+        "
+        )
+    };
+}
+#[cfg(feature = "pti")]
+macro_rules! pti_enter_iff_ring3_errorcode {
+    () => {
+        concat!(
+            "
+            test QWORD PTR [rsp + 16], 0x3
+            jz 3f
+            swapgs
+            mov gs:[{pti}], rdi
+            mov rdi, gs:[{pti} + 8]
+            mov cr3, rdi
+            mov rdi, gs:[{pti} + 32]
+            sub rdi, 48
+            ",
+            pti_copy_frame!(6),
+            "
+            mov rsp, rdi
+            mov rdi, gs:[{pti}]
+            swapgs
+            3:
+        "
+        )
+    };
+}
+// Expects the frame at RSP, the kernel GSBASE and the user RDI in the scratch field, and leaves RSP
+// pointing to the frame on the entry stack. Shared with the syscall handler.
+#[cfg(feature = "pti")]
+macro_rules! pti_exit {
+    () => {
+        concat!(
+            "
+            mov rdi, gs:[{pti} + 40]
+            sub rdi, 40
+            ",
+            pti_copy_frame!(5),
+            "
+            mov rsp, rdi
+            mov rdi, gs:[{pti} + 16]
+            mov cr3, rdi
+            mov rdi, gs:[{pti} + 24]
+            mov gs:[{pti} + 16], rdi
+            mov rdi, gs:[{pti}]
+        "
+        )
+    };
+}
+#[cfg(feature = "pti")]
+macro_rules! pti_exit_iff_ring3 {
+    () => {
+        concat!(
+            "
+            test QWORD PTR [rsp + 8], 0x3
+            jz 3f
+            swapgs
+            mov gs:[{pti}], rdi
+            ",
+            pti_exit!(),
+            "
+            swapgs
+            3:
+        "
+        )
+    };
+}
+// Copies the frame of 5 or 6 QWORDs at RSP to RDI, using the stack at RSP.
+#[cfg(feature = "pti")]
+macro_rules! pti_copy_frame {
+    (5) => {
+        "
+        push QWORD PTR [rsp + 32]
+        pop QWORD PTR [rdi + 32]
+        push QWORD PTR [rsp + 24]
+        pop QWORD PTR [rdi + 24]
+        push QWORD PTR [rsp + 16]
+        pop QWORD PTR [rdi + 16]
+        push QWORD PTR [rsp + 8]
+        pop QWORD PTR [rdi + 8]
+        push QWORD PTR [rsp]
+        pop QWORD PTR [rdi]
+        "
+    };
+    (6) => {
+        concat!(
+            "
+            push QWORD PTR [rsp + 40]
+            pop QWORD PTR [rdi + 40]
+            ",
+            pti_copy_frame!(5),
+        )
+    };
+}
+#[cfg(feature = "pti")]
+macro_rules! pti_enter_syscall {
+    // RSP is still the user stack pointer, which has been saved already.
+    () => {
+        "
+        mov rsp, gs:[{pti} + 8]
+        mov cr3, rsp
+        "
+    };
+}
+#[cfg(feature = "pti")]
+macro_rules! pti_exit_syscall {
+    () => {
+        concat!("mov gs:[{pti}], rdi\n", pti_exit!())
+    };
+}
+#[cfg(feature = "pti")]
+macro_rules! pti_switch_cr3_paranoid {
+    // NMIs and machine checks can also arrive in the kernel while the user page table is loaded,
+    // in the entry code. Compare CR3 against it, ignoring the PCID and NOFLUSH bits, and switch to
+    // the kernel page table if needed, saving the previous CR3 in R12, or 0.
+    () => {
+        "
+        xor r12d, r12d
+        mov rax, cr3
+        mov rdx, gs:[{pti} + 16]
+        xor rdx, rax
+        shl rdx, 1
+        shr rdx, 13
+        jnz 3f
+        mov r12, rax
+        mov rax, gs:[{pti} + 8]
+        mov cr3, rax
+        3:
+        "
+    };
+}
+#[cfg(feature = "pti")]
+macro_rules! pti_restore_cr3_paranoid {
+    () => {
+        "
+        test r12, r12
+        jz 3f
+        mov cr3, r12
+        3:
+        "
+    };
+}
+#[cfg(not(feature = "pti"))]
+macro_rules! pti_enter_iff_ring3 {
+    () => {
+        "
+        // Unused: {pti}
+        "
+    };
+}
+#[cfg(not(feature = "pti"))]
+macro_rules! pti_enter_iff_ring3_errorcode {
+    () => {
+        pti_enter_iff_ring3!()
+    };
+}
+#[cfg(not(feature = "pti"))]
+macro_rules! pti_exit_iff_ring3 {
+    () => {
+        pti_enter_iff_ring3!()
+    };
+}
+#[cfg(not(feature = "pti"))]
+macro_rules! pti_enter_syscall {
+    () => {
+        pti_enter_iff_ring3!()
+    };
+}
+#[cfg(not(feature = "pti"))]
+macro_rules! pti_exit_syscall {
+    () => {
+        ""
+    };
+}
+#[cfg(not(feature = "pti"))]
+macro_rules! pti_switch_cr3_paranoid {
+    () => {
+        ""
+    };
+}
+#[cfg(not(feature = "pti"))]
+macro_rules! pti_restore_cr3_paranoid {
+    () => {
+        ""
+    };
+}
+
 macro_rules! conditional_swapgs_paranoid {
     // For regular interrupt handlers and the syscall handler, managing IA32_GS_BASE and
     // IA32_KERNEL_GS_BASE (the "GSBASE registers") is more or less trivial when using the SWAPGS
@@ -336,16 +589,21 @@ macro_rules! conditional_swapgs_paranoid {
         swapgs
         2:
         ",
+
+        pti_switch_cr3_paranoid!(),
     ) }
 }
 macro_rules! conditional_swapgs_back_paranoid {
     () => {
+        concat!(
+            pti_restore_cr3_paranoid!(),
+            "
+            test bl, bl
+            jnz 2f
+            swapgs
+            2:
         "
-        test bl, bl
-        jnz 2f
-        swapgs
-        2:
-    "
+        )
     };
 }
 macro_rules! nop {
@@ -362,6 +620,7 @@ macro_rules! interrupt_stack {
     // use idents directly instead.
     ($name:ident, $save1:ident!, $save2:ident!, $rstor2:ident!, $rstor1:ident!, is_paranoid: $is_paranoid:expr, |$stack:ident| $code:block) => {
         #[naked]
+        #[link_section = ".entry-text"]
         pub unsafe extern "C" fn $name() {
             unsafe extern "C" fn inner($stack: &mut $crate::arch::x86_64::interrupt::InterruptStack) {
                 #[allow(unused_unsafe)]
@@ -373,6 +632,9 @@ macro_rules! interrupt_stack {
                 // Clear direction flag, required by ABI when running any Rust code in the kernel.
                 "cld;",
 
+                // Load the kernel page table, if coming from userspace with PTI.
+                pti_enter_iff_ring3!(),
+
                 // Backup all userspace registers to stack
                 $save1!(),
                 "push rax\n",
@@ -381,17 +643,12 @@ macro_rules! interrupt_stack {
 
                 $save2!(),
 
-                // TODO: Map PTI
-                // $crate::arch::x86_64::pti::map();
-
                 // Call inner function with pointer to stack
                 "
                 mov rdi, rsp
                 call {inner}
                 ",
-
-                // TODO: Unmap PTI
-                // $crate::arch::x86_64::pti::unmap();
+                interrupt_frame!(),
 
                 $rstor2!(),
 
@@ -400,6 +657,7 @@ macro_rules! interrupt_stack {
                 pop_scratch!(),
 
                 $rstor1!(),
+                pti_exit_iff_ring3!(),
                 "iretq\n",
             ),
 
@@ -407,6 +665,7 @@ macro_rules! interrupt_stack {
             IA32_GS_BASE = const(x86::msr::IA32_GS_BASE),
 
             PCR_GDT_OFFSET = const(core::mem::offset_of!(crate::gdt::ProcessorControlRegion, gdt)),
+            pti = const(crate::gdt::PTI_OFFSET),
 
             options(noreturn),
 
@@ -421,8 +680,10 @@ macro_rules! interrupt_stack {
 macro_rules! interrupt {
     ($name:ident, || $code:block) => {
         #[naked]
+        #[link_section = ".entry-text"]
         pub unsafe extern "C" fn $name() {
             unsafe extern "C" fn inner() {
+                let _irq = $crate::sync::lockdep::IrqScope::enter();
                 $code
             }
 
@@ -430,28 +691,27 @@ macro_rules! interrupt {
                 // Clear direction flag, required by ABI when running any Rust code in the kernel.
                 "cld;",
 
+                // Load the kernel page table, if coming from userspace with PTI.
+                pti_enter_iff_ring3!(),
+
                 // Backup all userspace registers to stack
                 swapgs_iff_ring3_fast!(),
                 "push rax\n",
                 push_scratch!(),
 
-                // TODO: Map PTI
-                // $crate::arch::x86_64::pti::map();
-
                 // Call inner function with pointer to stack
                 "call {inner}\n",
 
-                // TODO: Unmap PTI
-                // $crate::arch::x86_64::pti::unmap();
-
                 // Restore all userspace registers
                 pop_scratch!(),
 
                 swapgs_iff_ring3_fast!(),
+                pti_exit_iff_ring3!(),
                 "iretq\n",
             ),
 
             inner = sym inner,
+            pti = const(crate::gdt::PTI_OFFSET),
 
             options(noreturn),
             );
@@ -463,6 +723,7 @@ macro_rules! interrupt {
 macro_rules! interrupt_error {
     ($name:ident, |$stack:ident, $error_code:ident| $code:block) => {
         #[naked]
+        #[link_section = ".entry-text"]
         pub unsafe extern "C" fn $name() {
             unsafe extern "C" fn inner($stack: &mut $crate::arch::x86_64::interrupt::handler::InterruptStack, $error_code: usize) {
                 #[allow(unused_unsafe)]
@@ -475,6 +736,9 @@ macro_rules! interrupt_error {
                 // Clear direction flag, required by ABI when running any Rust code in the kernel.
                 "cld;",
 
+                // Load the kernel page table, if coming from userspace with PTI.
+                pti_enter_iff_ring3_errorcode!(),
+
                 swapgs_iff_ring3_fast_errorcode!(),
 
                 // Don't push RAX yet, as the error code is already stored in RAX's position.
@@ -489,26 +753,23 @@ macro_rules! interrupt_error {
                 "mov rsi, [rsp + {rax_offset}];",
                 "mov [rsp + {rax_offset}], rax;",
 
-                // TODO: Map PTI
-                // $crate::arch::x86_64::pti::map();
-
                 // Call inner function with pointer to stack, and error code.
                 "mov rdi, rsp;",
                 "call {inner};",
-
-                // TODO: Unmap PTI
-                // $crate::arch::x86_64::pti::unmap();
+                interrupt_frame!(),
 
                 // Restore all userspace registers
                 pop_preserved!(),
                 pop_scratch!(),
 
-                // The error code has already been popped, so use the regular macro.
+                // The error code has already been popped, so use the regular macros.
                 swapgs_iff_ring3_fast!(),
+                pti_exit_iff_ring3!(),
                 "iretq;",
             ),
 
             inner = sym inner,
+            pti = const(crate::gdt::PTI_OFFSET),
             rax_offset = const(::core::mem::size_of::<$crate::interrupt::handler::PreservedRegisters>() + ::core::mem::size_of::<$crate::interrupt::handler::ScratchRegisters>() - 8),
 
             options(noreturn));
@@ -530,3 +791,21 @@ impl ArchIntCtx for InterruptStack {
         self.iret.rflags &= !(1 << 18);
     }
 }
+
+#[cfg(feature = "debugger")]
+impl crate::debugger::DebuggerArch for InterruptStack {
+    fn user_stack_pointer(&self) -> usize {
+        self.iret.rsp
+    }
+
+    fn poll_input() -> Option<u8> {
+        crate::device::serial::COM1.lock().receive()
+    }
+
+    fn dump_saved_kernel_stack(context: &crate::context::Context) {
+        let (sp, fp) = context.arch.saved_stack();
+        println!("kernel stack:");
+        let mapper = crate::memory::KernelMapper::lock();
+        crate::panic::print_frames(crate::unwind::Unwinder::from_call(&mapper, sp, fp));
+    }
+}
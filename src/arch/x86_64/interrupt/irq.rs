@@ -1,20 +1,28 @@
-use core::sync::atomic::{AtomicUsize, Ordering};
+use core::{
+    fmt::Write,
+    sync::atomic::{AtomicUsize, Ordering},
+};
 
-use alloc::vec::Vec;
+use alloc::{string::String, vec::Vec};
 
 use crate::{
     context,
     context::timeout,
+    cpu_set::LogicalCpuId,
     device::{
-        ioapic, local_apic, pic, pit,
+        ioapic,
+        local_apic::{self, SPURIOUS_VECTOR},
+        pic, pit,
         serial::{COM1, COM2},
     },
     interrupt, interrupt_stack,
     ipi::{ipi, IpiKind, IpiTarget},
+    irq_stats,
     scheme::{
         debug::{debug_input, debug_notify},
         serio::serio_input,
     },
+    sync::lockdep::IrqScope,
     time,
 };
 
@@ -39,7 +47,29 @@ pub fn spurious_count() -> usize {
 }
 pub fn spurious_irq_resource() -> syscall::Result<Vec<u8>> {
     match irq_method() {
-        IrqMethod::Apic => Ok(Vec::from(&b"(not implemented for APIC yet)"[..])),
+        IrqMethod::Apic => {
+            let mut data = String::new();
+            for id in 0..crate::cpu_count() {
+                let id = LogicalCpuId::new(id);
+                let Some(percpu) = crate::percpu::get(id) else {
+                    continue;
+                };
+                let stats = &percpu.misc_arch_info.lapic_stats;
+                let esr = stats.esr.load(Ordering::Relaxed);
+                writeln!(
+                    data,
+                    "{}\tCPU {} spurious\n{}\tCPU {} errors (ESR {:#x}: {})",
+                    stats.spurious.load(Ordering::Relaxed),
+                    id,
+                    stats.errors.load(Ordering::Relaxed),
+                    id,
+                    esr,
+                    local_apic::Esr(esr),
+                )
+                .unwrap();
+            }
+            Ok(data.into_bytes())
+        }
         IrqMethod::Pic => Ok(format!(
             "{}\tIRQ7\n{}\tIRQ15\n{}\ttotal\n",
             spurious_count_irq7(),
@@ -154,6 +184,8 @@ unsafe fn ioapic_unmask(irq: usize) {
 }
 
 interrupt_stack!(pit_stack, |_stack| {
+    let _irq = IrqScope::enter();
+    irq_stats::count(0);
     // Saves CPU time by not sending IRQ event irq_trigger(0);
 
     {
@@ -173,6 +205,7 @@ interrupt_stack!(pit_stack, |_stack| {
 });
 
 interrupt!(keyboard, || {
+    irq_stats::count(1);
     let data: u8;
     core::arch::asm!("in al, 0x60", out("al") data);
 
@@ -182,12 +215,18 @@ interrupt!(keyboard, || {
 });
 
 interrupt!(cascade, || {
+    irq_stats::count(2);
     // No need to do any operations on cascade
     eoi(2);
 });
 
 interrupt!(com2, || {
+    irq_stats::count(3);
     while let Some(c) = COM2.lock().receive() {
+        #[cfg(feature = "gdbstub")]
+        if crate::gdbstub::serial_input(crate::gdbstub::Port::Com2, c) {
+            continue;
+        }
         debug_input(c);
     }
     debug_notify();
@@ -195,7 +234,12 @@ interrupt!(com2, || {
 });
 
 interrupt!(com1, || {
+    irq_stats::count(4);
     while let Some(c) = COM1.lock().receive() {
+        #[cfg(feature = "gdbstub")]
+        if crate::gdbstub::serial_input(crate::gdbstub::Port::Com1, c) {
+            continue;
+        }
         debug_input(c);
     }
     debug_notify();
@@ -203,16 +247,19 @@ interrupt!(com1, || {
 });
 
 interrupt!(lpt2, || {
+    irq_stats::count(5);
     trigger(5);
     eoi(5);
 });
 
 interrupt!(floppy, || {
+    irq_stats::count(6);
     trigger(6);
     eoi(6);
 });
 
 interrupt!(lpt1, || {
+    irq_stats::count(7);
     if irq_method() == IrqMethod::Pic && pic::MASTER.isr() & (1 << 7) == 0 {
         // the IRQ was spurious, ignore it but increment a counter.
         SPURIOUS_COUNT_IRQ7.fetch_add(1, Ordering::Relaxed);
@@ -223,26 +270,31 @@ interrupt!(lpt1, || {
 });
 
 interrupt!(rtc, || {
+    irq_stats::count(8);
     trigger(8);
     eoi(8);
 });
 
 interrupt!(pci1, || {
+    irq_stats::count(9);
     trigger(9);
     eoi(9);
 });
 
 interrupt!(pci2, || {
+    irq_stats::count(10);
     trigger(10);
     eoi(10);
 });
 
 interrupt!(pci3, || {
+    irq_stats::count(11);
     trigger(11);
     eoi(11);
 });
 
 interrupt!(mouse, || {
+    irq_stats::count(12);
     let data: u8;
     core::arch::asm!("in al, 0x60", out("al") data);
 
@@ -252,16 +304,19 @@ interrupt!(mouse, || {
 });
 
 interrupt!(fpu, || {
+    irq_stats::count(13);
     trigger(13);
     eoi(13);
 });
 
 interrupt!(ata1, || {
+    irq_stats::count(14);
     trigger(14);
     eoi(14);
 });
 
 interrupt!(ata2, || {
+    irq_stats::count(15);
     if irq_method() == IrqMethod::Pic && pic::SLAVE.isr() & (1 << 7) == 0 {
         SPURIOUS_COUNT_IRQ15.fetch_add(1, Ordering::Relaxed);
         pic::MASTER.ack();
@@ -272,33 +327,44 @@ interrupt!(ata2, || {
 });
 
 interrupt!(lapic_timer, || {
-    println!("Local apic timer interrupt");
+    irq_stats::count_vector(48);
     lapic_eoi();
+
+    crate::timer::interrupt();
 });
 #[cfg(feature = "profiling")]
 interrupt!(aux_timer, || {
+    irq_stats::count_vector(32);
     lapic_eoi();
     crate::ipi::ipi(IpiKind::Profile, IpiTarget::Other);
 });
 
 interrupt!(lapic_error, || {
-    log::error!(
-        "Local apic internal error: ESR={:#0x}",
-        local_apic::the_local_apic().esr()
-    );
+    irq_stats::count_vector(49);
+    local_apic::handle_error();
     lapic_eoi();
 });
 
+interrupt!(lapic_spurious, || {
+    irq_stats::count_vector(SPURIOUS_VECTOR);
+    // Spurious interrupts must not be acknowledged.
+    local_apic::handle_spurious();
+});
+
 interrupt_error!(generic_irq, |_stack, code| {
     // The reason why 128 is subtracted and added from the code, is that PUSH imm8 sign-extends the
     // value, and the longer PUSH imm32 would make the generic_interrupts table twice as large
     // (containing lots of useless NOPs).
-    irq_trigger((code as i32).wrapping_add(128) as u8);
+    let _irq = IrqScope::enter();
+    let irq = (code as i32).wrapping_add(128) as u8;
+    irq_stats::count(irq);
+    irq_trigger(irq);
 
     lapic_eoi();
 });
 
 core::arch::global_asm!("
+    .pushsection .entry-text, \"ax\"
     .globl __generic_interrupts_start
     .globl __generic_interrupts_end
     .p2align 3
@@ -311,6 +377,7 @@ __generic_interrupts_start:
     n = n + 1
     .endr
 __generic_interrupts_end:
+    .popsection
 ", sym generic_irq);
 
 extern "C" {
@@ -1,5 +1,5 @@
 use crate::{
-    arch::{gdt, interrupt::InterruptStack},
+    arch::{consts::VIRT_ADDR_BITS, gdt, interrupt::InterruptStack},
     ptrace, syscall,
     syscall::flag::{PTRACE_FLAG_IGNORE, PTRACE_STOP_POST_SYSCALL, PTRACE_STOP_PRE_SYSCALL},
 };
@@ -10,6 +10,14 @@ use x86::{
     segmentation::SegmentSelector,
 };
 
+/// Where the kernel stack pointer is loaded from. With PTI, the TSS points to the entry stack
+/// instead, and the kernel stack is in the PTI state, see `pti`.
+#[cfg(not(feature = "pti"))]
+const KERNEL_STACK_OFFSET: usize =
+    offset_of!(gdt::ProcessorControlRegion, tss) + offset_of!(TaskStateSegment, rsp);
+#[cfg(feature = "pti")]
+const KERNEL_STACK_OFFSET: usize = gdt::PTI_OFFSET + 32;
+
 pub unsafe fn init() {
     // IA32_STAR[31:0] are reserved.
 
@@ -80,15 +88,19 @@ pub unsafe extern "C" fn __inner_syscall_instruction(stack: *mut InterruptStack)
     }
 
     ptrace::breakpoint_callback(PTRACE_STOP_POST_SYSCALL, None);
+
+    crate::context::signal::syscall_exit();
 }
 
 #[naked]
 #[allow(named_asm_labels)]
+#[link_section = ".entry-text"]
 pub unsafe extern "C" fn syscall_instruction() {
     core::arch::asm!(concat!(
     // Yes, this is magic. No, you don't need to understand
     "swapgs;",                    // Swap KGSBASE with GSBASE, allowing fast TSS access.
     "mov gs:[{sp}], rsp;",        // Save userspace stack pointer
+    pti_enter_syscall!(),         // Load the kernel page table, with PTI
     "mov rsp, gs:[{ksp}];",       // Load kernel stack pointer
     "push QWORD PTR {ss_sel};",   // Push fake userspace SS (resembling iret frame)
     "push QWORD PTR gs:[{sp}];",  // Push userspace rsp
@@ -101,16 +113,10 @@ pub unsafe extern "C" fn syscall_instruction() {
     push_scratch!(),
     push_preserved!(),
 
-    // TODO: Map PTI
-    // $crate::arch::x86_64::pti::map();
-
     // Call inner funtion
     "mov rdi, rsp;",
     "call __inner_syscall_instruction;",
 
-    // TODO: Unmap PTI
-    // $crate::arch::x86_64::pti::unmap();
-
     "
     .globl enter_usermode
     enter_usermode:
@@ -120,6 +126,9 @@ pub unsafe extern "C" fn syscall_instruction() {
     pop_preserved!(),
     pop_scratch!(),
 
+    // Move the frame to the entry stack and load the user page table, with PTI.
+    pti_exit_syscall!(),
+
     // Restore user GSBASE by swapping GSBASE and KGSBASE.
     "swapgs;",
 
@@ -166,10 +175,11 @@ pub unsafe extern "C" fn syscall_instruction() {
     // While we could also conditionally IRETQ here, an easier method is to simply sign-extend RCX:
 
     // Shift away the upper 16 bits (0xBAAD_8000_DEAD_BEEF => 0x8000_DEAD_BEEF_XXXX).
-    "shl rcx, 16;",
+    "shl rcx, {canonical_shift};",
     // Shift arithmetically right by 16 bits, effectively extending the 47th sign bit to bits
-    // 63:48 (0x8000_DEAD_BEEF_XXXX => 0xFFFF_8000_DEAD_BEEF).
-    "sar rcx, 16;",
+    // 63:48 (0x8000_DEAD_BEEF_XXXX => 0xFFFF_8000_DEAD_BEEF). With 5-level paging, these are 7
+    // bits instead, extending the 56th sign bit.
+    "sar rcx, {canonical_shift};",
 
     "add rsp, 8;",              // Pop fake userspace CS
     "pop r11;",                 // Pop rflags
@@ -186,9 +196,11 @@ pub unsafe extern "C" fn syscall_instruction() {
     "),
 
     sp = const(offset_of!(gdt::ProcessorControlRegion, user_rsp_tmp)),
-    ksp = const(offset_of!(gdt::ProcessorControlRegion, tss) + offset_of!(TaskStateSegment, rsp)),
+    ksp = const(KERNEL_STACK_OFFSET),
+    pti = const(gdt::PTI_OFFSET),
     ss_sel = const(SegmentSelector::new(gdt::GDT_USER_DATA as u16, x86::Ring::Ring3).bits()),
     cs_sel = const(SegmentSelector::new(gdt::GDT_USER_CODE as u16, x86::Ring::Ring3).bits()),
+    canonical_shift = const(u64::BITS - VIRT_ADDR_BITS),
 
     options(noreturn),
     );
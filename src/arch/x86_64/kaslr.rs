@@ -0,0 +1,237 @@
+//! Early relocation of the kernel image to a random address, see [`crate::kaslr`].
+//!
+//! This runs at the link-time address, on the page table of the bootloader, before the heap,
+//! logging or even the GDT are set up, so it must not allocate, print or panic. Whenever something
+//! it relies on is missing, the kernel stays where it is.
+//!
+//! The new address is a multiple of 2 MiB in the top 2 GiB, as required by the kernel code model,
+//! so that the new range can be mapped by pointing its page directory entries to the page tables
+//! the bootloader uses for the link-time range. The loaded image is flat, with each address at the
+//! same offset from `KERNEL_OFFSET` as from the start of the file, which is also how it is found
+//! in the physmap to be patched.
+
+use core::{
+    arch::x86_64::{__cpuid, _rdrand64_step, _rdtsc},
+    mem, ptr, slice,
+};
+
+use goblin::elf::{
+    reloc::{
+        R_X86_64_32, R_X86_64_32S, R_X86_64_64, R_X86_64_DTPOFF32, R_X86_64_DTPOFF64,
+        R_X86_64_NONE, R_X86_64_PC32, R_X86_64_PC64, R_X86_64_PLT32, R_X86_64_TPOFF32,
+        R_X86_64_TPOFF64,
+    },
+    section_header::{SHF_ALLOC, SHN_ABS, SHN_UNDEF, SHT_RELA},
+};
+use goblin::elf64::reloc::Rela;
+
+use super::consts::KERNEL_MAX_SIZE;
+use crate::{
+    elf::{header, sym::Sym, Elf},
+    kaslr,
+    paging::{PhysicalAddress, RmmA, RmmArch, TableKind},
+    KERNEL_OFFSET,
+};
+
+const ALIGN: usize = 2 * 1024 * 1024;
+
+const ENTRY_ADDRESS_MASK: u64 = 0x000F_FFFF_FFFF_F000;
+const ENTRY_PRESENT: u64 = 1 << 0;
+const ENTRY_HUGE: u64 = 1 << 7;
+
+#[repr(C, align(4096))]
+struct Table([u64; 512]);
+
+/// Page directories for the new range, if it is in a gigabyte the bootloader does not map.
+static mut NEW_DIRECTORIES: [Table; 2] = [const { Table([0; 512]) }; 2];
+
+/// Move the kernel image, loaded at `phys_base`, to a random address, returning the slide, or
+/// zero if it was not moved.
+pub unsafe fn relocate(phys_base: usize, size: usize, bootloader_seed: u64) -> usize {
+    let image = slice::from_raw_parts(KERNEL_OFFSET as *const u8, size);
+    // Elf::from formats its errors, which requires the heap.
+    if image.len() < header::SIZEOF_EHDR
+        || &image[..header::SELFMAG] != header::ELFMAG
+        || image[header::EI_CLASS] != header::ELFCLASS
+    {
+        return 0;
+    }
+    let Ok(elf) = Elf::from(image) else {
+        return 0;
+    };
+
+    let slide = kaslr::choose_slide(seed(bootloader_seed), size, ALIGN, KERNEL_MAX_SIZE - ALIGN);
+    if slide == 0 {
+        return 0;
+    }
+
+    let patched = |offset: usize, width: usize, delta: i64| -> Option<u64> {
+        let addr = image.as_ptr().wrapping_add(offset);
+        Some(match width {
+            8 => (ptr::read_unaligned(addr.cast::<i64>()).wrapping_add(delta)) as u64,
+            _ => i32::try_from(i64::from(ptr::read_unaligned(addr.cast::<i32>())) + delta).ok()?
+                as u32 as u64,
+        })
+    };
+    // Check everything can be adjusted, before changing anything.
+    if !adjustments(&elf, slide, |offset, width, delta| {
+        patched(offset, width, delta).is_some()
+    }) {
+        return 0;
+    }
+    if !map_slid(phys_base, size, slide) {
+        return 0;
+    }
+    // Through the physmap, as the image may be mapped read-only.
+    let physmap = RmmA::phys_to_virt(PhysicalAddress::new(phys_base)).data() as *mut u8;
+    adjustments(&elf, slide, |offset, width, delta| {
+        let Some(value) = patched(offset, width, delta) else {
+            return false;
+        };
+        let addr = physmap.add(offset);
+        match width {
+            8 => ptr::write_unaligned(addr.cast::<u64>(), value),
+            _ => ptr::write_unaligned(addr.cast::<u32>(), value as u32),
+        }
+        true
+    });
+    // Serialize, as the code running was just modified.
+    __cpuid(0);
+
+    kaslr::set_slide(slide);
+    slide
+}
+
+fn seed(bootloader_seed: u64) -> u64 {
+    let mut seed = bootloader_seed ^ unsafe { _rdtsc() };
+    let has_rdrand = crate::arch::cpuid::cpuid()
+        .get_feature_info()
+        .map_or(false, |info| info.has_rdrand());
+    if has_rdrand {
+        if let Some(random) = unsafe { rdrand() } {
+            seed ^= random;
+        }
+    }
+    seed
+}
+
+#[target_feature(enable = "rdrand")]
+unsafe fn rdrand() -> Option<u64> {
+    // RDRAND may fail transiently when the entropy source is drained.
+    for _ in 0..10 {
+        let mut value = 0;
+        if _rdrand64_step(&mut value) == 1 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+/// Call `f` with the image offset, width and adjustment of each location to patch for `slide`,
+/// stopping at the first it returns false for. Returns false if that happened, or if a relocation
+/// cannot be adjusted.
+fn adjustments(elf: &Elf, slide: usize, mut f: impl FnMut(usize, usize, i64) -> bool) -> bool {
+    let image_len = elf.data.len();
+    for rela_section in elf.sections().filter(|section| section.sh_type == SHT_RELA) {
+        let Some(target) = elf.sections().nth(rela_section.sh_info as usize) else {
+            return false;
+        };
+        if target.sh_flags & u64::from(SHF_ALLOC) == 0 {
+            continue;
+        }
+        let symtab = elf.sections().nth(rela_section.sh_link as usize);
+        let (Some(relas), Some(symbols)) = (
+            elf.section_data(rela_section),
+            symtab.and_then(|symtab| elf.section_data(symtab)),
+        ) else {
+            return false;
+        };
+
+        for rela in relas.chunks_exact(mem::size_of::<Rela>()) {
+            let rela = unsafe { ptr::read_unaligned(rela.as_ptr().cast::<Rela>()) };
+            let sym_index = (rela.r_info >> 32) as usize;
+
+            // Whether the target moves with the image. Undefined symbols are weak ones, resolved
+            // to zero.
+            let moves = sym_index != 0 && {
+                let start = sym_index * mem::size_of::<Sym>();
+                let Some(sym) = symbols.get(start..start + mem::size_of::<Sym>()) else {
+                    return false;
+                };
+                let sym = unsafe { ptr::read_unaligned(sym.as_ptr().cast::<Sym>()) };
+                !matches!(u32::from(sym.st_shndx), SHN_ABS | SHN_UNDEF)
+            };
+            let slide = slide as i64;
+            let (width, delta) = match rela.r_info as u32 {
+                R_X86_64_64 => (8, if moves { slide } else { 0 }),
+                R_X86_64_32S => (4, if moves { slide } else { 0 }),
+                R_X86_64_PC64 => (8, if moves { 0 } else { -slide }),
+                R_X86_64_PC32 | R_X86_64_PLT32 => (4, if moves { 0 } else { -slide }),
+                R_X86_64_32 if !moves => continue,
+                R_X86_64_NONE | R_X86_64_TPOFF32 | R_X86_64_TPOFF64 | R_X86_64_DTPOFF32
+                | R_X86_64_DTPOFF64 => continue,
+                _ => return false,
+            };
+            if delta == 0 {
+                continue;
+            }
+            let Some(offset) = (rela.r_offset as usize)
+                .checked_sub(KERNEL_OFFSET)
+                .filter(|offset| offset + width <= image_len)
+            else {
+                return false;
+            };
+            if !f(offset, width, delta) {
+                return false;
+            }
+        }
+    }
+    true
+}
+
+unsafe fn table(entry: u64) -> *mut [u64; 512] {
+    RmmA::phys_to_virt(PhysicalAddress::new((entry & ENTRY_ADDRESS_MASK) as usize)).data()
+        as *mut [u64; 512]
+}
+
+/// Map the image at `KERNEL_OFFSET + slide` in the current page table as well.
+unsafe fn map_slid(phys_base: usize, size: usize, slide: usize) -> bool {
+    let pml4 = table(RmmA::table(TableKind::Kernel).data() as u64);
+    let pml4_entry = (*pml4)[511];
+    if pml4_entry & ENTRY_PRESENT == 0 {
+        return false;
+    }
+    let pdpt = table(pml4_entry);
+    let mut new_directories = (*ptr::addr_of_mut!(NEW_DIRECTORIES)).iter_mut();
+
+    for chunk in 0..size.div_ceil(ALIGN) {
+        let old = KERNEL_OFFSET + chunk * ALIGN;
+        let new = old + slide;
+
+        let old_pdpt_entry = (*pdpt)[(old >> 30) & 511];
+        if old_pdpt_entry & ENTRY_PRESENT == 0 || old_pdpt_entry & ENTRY_HUGE != 0 {
+            return false;
+        }
+        let new_pdpt_entry = &mut (*pdpt)[(new >> 30) & 511];
+        if *new_pdpt_entry & ENTRY_PRESENT == 0 {
+            let Some(directory) = new_directories.next() else {
+                return false;
+            };
+            // Not yet relocated, so this is the link-time address.
+            let phys = phys_base + (directory as *mut Table as usize - KERNEL_OFFSET);
+            *new_pdpt_entry = phys as u64 | (old_pdpt_entry & !ENTRY_ADDRESS_MASK);
+        } else if *new_pdpt_entry & ENTRY_HUGE != 0 {
+            return false;
+        }
+
+        let new_entry = &mut (*table(*new_pdpt_entry))[(new >> 21) & 511];
+        if *new_entry & ENTRY_PRESENT != 0 {
+            return false;
+        }
+        *new_entry = (*table(old_pdpt_entry))[(old >> 21) & 511];
+    }
+
+    // Flush the TLB.
+    RmmA::set_table(TableKind::Kernel, RmmA::table(TableKind::Kernel));
+    true
+}
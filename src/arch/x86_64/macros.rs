@@ -31,15 +31,20 @@ macro_rules! saturating_sub(
         "((", $lhs, ")>(", $rhs, "))*((", $lhs, ")-(", $rhs, "))",
     ) }
 );
-// Use feature1 if present, otherwise try using feature2, otherwise use default.
+macro_rules! alternative2(
+    (feature1: $feature1:literal, then1: [$($then1:expr),*], feature2: $feature2:literal, then2: [$($then2:expr),*], default: [$($default:expr),*]) => {
+        alternative3!(feature1: $feature1, then1: [$($then1),*], feature2: $feature2, then2: [$($then2),*], feature3: "", then3: [""], default: [$($default),*])
+    }
+);
+// Use feature1 if present, otherwise try using feature2, then feature3, otherwise use default.
 //
 // cpu_feature_always simply means it is always enabled. Thus, if feature2, which has lower
 // priority, is "always" but feature1 is "auto", feature2 will still be checked for, and feature2
 // will become the fallback code.
 //
 // An empty string as feature is equivalent with "never".
-macro_rules! alternative2(
-    (feature1: $feature1:literal, then1: [$($then1:expr),*], feature2: $feature2:literal, then2: [$($then2:expr),*], default: [$($default:expr),*]) => {
+macro_rules! alternative3(
+    (feature1: $feature1:literal, then1: [$($then1:expr),*], feature2: $feature2:literal, then2: [$($then2:expr),*], feature3: $feature3:literal, then3: [$($then3:expr),*], default: [$($default:expr),*]) => {
         concat!("
             .set true, 1
             .set false, 0
@@ -48,6 +53,8 @@ macro_rules! alternative2(
             ", $($then1,)* "
             .elseif ", expand_bool!(cfg!(cpu_feature_always = $feature2)), "
             ", $($then2,)* "
+            .elseif ", expand_bool!(cfg!(cpu_feature_always = $feature3)), "
+            ", $($then3,)* "
             .else
             ", $($default,)* "
             .endif
@@ -58,12 +65,16 @@ macro_rules! alternative2(
             .if ", expand_bool!(cfg!(cpu_feature_auto = $feature2)), "
             .skip -", saturating_sub!("61f - 60f", "42b - 40b"), ", 0x90
             .endif
+            .if ", expand_bool!(cfg!(cpu_feature_auto = $feature3)), "
+            .skip -", saturating_sub!("81f - 80f", "42b - 40b"), ", 0x90
+            .endif
             41:
             ",
             // FIXME: The assembler apparently complains "invalid number of bytes" despite it being
             // quite obvious what saturating_sub does.
 
             // Declare them in reverse order. Last relocation wins!
+            alternative_auto!("8", $feature3, [$($then3),*]),
             alternative_auto!("6", $feature2, [$($then2),*]),
             alternative_auto!("5", $feature1, [$($then1),*]),
         )
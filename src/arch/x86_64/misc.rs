@@ -19,9 +19,20 @@ pub unsafe fn init(cpu_id: LogicalCpuId) {
         x86::controlregs::cr4_write(x86::controlregs::cr4() | Cr4::CR4_ENABLE_SMEP);
     }
 
+    if cpuid()
+        .get_feature_info()
+        .map_or(false, |info| info.has_pcid())
+    {
+        // Tags TLB entries with the ID of their address space, see memory::asid. Only allowed
+        // while the current PCID is 0, which is the case until the first context switch.
+        x86::controlregs::cr4_write(x86::controlregs::cr4() | Cr4::CR4_ENABLE_PCID);
+    }
+
     if let Some(feats) = cpuid().get_extended_processor_and_feature_identifiers()
         && feats.has_rdtscp()
     {
         x86::msr::wrmsr(x86::msr::IA32_TSC_AUX, cpu_id.get().into());
     }
+
+    super::cet::init();
 }
@@ -5,12 +5,18 @@ pub mod alternative;
 #[macro_use]
 pub mod macros;
 
+/// Control-flow enforcement for userspace
+pub mod cet;
+
 /// Constants like memory locations
 pub mod consts;
 
 /// CPUID wrapper
 pub mod cpuid;
 
+/// Per-context hardware watchpoints
+pub mod debug_regs;
+
 /// Global descriptor table
 pub mod gdt;
 
@@ -18,17 +24,28 @@ pub mod gdt;
 #[macro_use]
 pub mod interrupt;
 
+/// Kernel address space layout randomization
+#[cfg(feature = "kaslr")]
+pub mod kaslr;
+
 /// Miscellaneous processor features
 pub mod misc;
 
 /// Paging
 pub mod paging;
 
+/// Page table isolation
+#[cfg(feature = "pti")]
+pub mod pti;
+
 pub mod rmm;
 
 /// Initialization and start function
 pub mod start;
 
+#[cfg(feature = "la57")]
+pub use self::rmm::X8664La57Arch as CurrentRmmArch;
+#[cfg(not(feature = "la57"))]
 pub use ::rmm::X8664Arch as CurrentRmmArch;
 
 // Flags
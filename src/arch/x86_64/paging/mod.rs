@@ -14,6 +14,8 @@ pub mod entry {
     bitflags! {
         pub struct EntryFlags: usize {
             const NO_CACHE =        1 << 4;
+            const ACCESSED =        1 << 5;
+            const DIRTY =           1 << 6;
             const HUGE_PAGE =       1 << 7;
             const GLOBAL =          1 << 8;
             const DEV_MEM =         0;
@@ -80,8 +82,7 @@ impl Page {
     }
 
     pub fn containing_address(address: VirtualAddress) -> Page {
-        //TODO assert!(address.data() < 0x0000_8000_0000_0000 || address.data() >= 0xffff_8000_0000_0000,
-        //    "invalid address: 0x{:x}", address.data());
+        //TODO assert that the address is canonical, see consts::VIRT_ADDR_BITS
         Page {
             number: address.data() / PAGE_SIZE,
         }
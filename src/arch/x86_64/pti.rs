@@ -0,0 +1,303 @@
+//! # Page table isolation
+//!
+//! Userspace runs with a page table of its own, the shadow of the one the kernel uses for its
+//! address space. It maps the same user memory, but of the kernel only what is needed to enter and
+//! leave it: the code in `.entry-text`, and for every CPU its PCR, which holds the GDT, the TSS and
+//! a small entry stack, its backup interrupt stack and its IDT. Speculative reads from userspace
+//! (Meltdown) thus cannot reach anything else.
+//!
+//! Interrupts and exceptions from userspace arrive on the entry stack, which the TSS points to. The
+//! entry code loads the kernel page table, copies the interrupt frame to the kernel stack of the
+//! context and continues there, and does the reverse before returning. Syscalls load the kernel
+//! page table right after SWAPGS, before touching the kernel stack. NMIs and machine checks can also
+//! interrupt the kernel while the user page table is loaded, on the way in or out, so their
+//! handlers compare CR3 against it and switch if needed.
+//!
+//! The kernel half of every shadow table is copied from a template, whose next-level tables are
+//! shared by all of them and only ever gain mappings. The user half is copied from the full table
+//! when switching to the address space, and then kept in sync lazily: a top-level entry missing or
+//! outdated in the shadow table causes a page fault from userspace, which copies it, and invalidating
+//! the entire TLB of an address space, as happens before its page tables are freed, copies the whole
+//! user half again.
+//!
+//! With PCIDs, shadow tables are tagged with the ID of their address space plus [`USER_PCID`], so
+//! that switching between the two tables does not flush the TLB. INVLPG only reaches the current
+//! PCID however, so invalidating TLB entries of an address space flushes the user PCID entirely the
+//! next time the shadow table is loaded.
+
+use core::{
+    mem::size_of,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use x86::dtables::{self, DescriptorTablePointer};
+
+use crate::{
+    context::memory::Table,
+    gdt::{self, ProcessorControlRegion},
+    idt::BACKUP_STACK_SIZE,
+    memory::{allocate_frame, Frame, KernelMapper},
+    paging::{PhysicalAddress, RmmA, RmmArch, VirtualAddress, PAGE_SIZE},
+    sync::Mutex,
+    syscall::error::{Error, Result, ENOMEM},
+};
+
+use super::rmm::{ASID_COUNT, CR3_NOFLUSH};
+
+/// Added to the ID of an address space, to tag the entries of its shadow table.
+pub const USER_PCID: usize = ASID_COUNT;
+
+const PRESENT: u64 = 1;
+const WRITABLE: u64 = 1 << 1;
+const ACCESSED: u64 = 1 << 5;
+const NO_EXECUTE: u64 = 1 << 63;
+const ADDRESS_MASK: u64 = 0x000F_FFFF_FFFF_F000;
+
+/// The entry stack only holds an interrupt frame while it is copied.
+const ENTRY_STACK_SIZE: usize = 256;
+
+/// The per-CPU state of the entry code. The offsets of the fields are used by the entry code, see
+/// `interrupt::handler`.
+#[repr(C, align(16))]
+pub struct PtiState {
+    /// Saves a register while the entry code has none left.
+    scratch: usize,
+    /// CR3 of the full page table of the current address space.
+    kernel_cr3: usize,
+    /// CR3 of its shadow table, loaded when returning to userspace.
+    user_cr3: usize,
+    /// Replaces `user_cr3` once loaded, so that the TLB entries of the user PCID are only flushed
+    /// once.
+    user_cr3_next: usize,
+    /// Top of the kernel stack of the current context.
+    kernel_stack: usize,
+    /// Top of `entry_stack`.
+    entry_stack_top: usize,
+    entry_stack: [u8; ENTRY_STACK_SIZE],
+}
+
+const _: () = {
+    use core::mem::offset_of;
+
+    if offset_of!(PtiState, scratch) != 0
+        || offset_of!(PtiState, kernel_cr3) != 8
+        || offset_of!(PtiState, user_cr3) != 16
+        || offset_of!(PtiState, user_cr3_next) != 24
+        || offset_of!(PtiState, kernel_stack) != 32
+        || offset_of!(PtiState, entry_stack_top) != 40
+    {
+        panic!("PTI state is incorrectly defined, the entry code depends on its offsets");
+    }
+};
+
+impl PtiState {
+    /// Set the kernel stack of the current context, returning the top of the entry stack, which
+    /// the TSS must point to instead.
+    pub fn set_kernel_stack(&mut self, stack: usize) -> usize {
+        self.kernel_stack = stack;
+        self.entry_stack_top = self.entry_stack.as_ptr_range().end as usize;
+        self.entry_stack_top
+    }
+}
+
+/// The top-level table whose kernel half is copied to every shadow table.
+static TEMPLATE: Mutex<Option<Frame>> = Mutex::new(None);
+
+unsafe fn entries(table: usize) -> &'static [AtomicU64] {
+    let virt = RmmA::phys_to_virt(PhysicalAddress::new(table & ADDRESS_MASK as usize));
+    core::slice::from_raw_parts(virt.data() as *const AtomicU64, RmmA::PAGE_ENTRIES)
+}
+
+/// The index of the entry covering `address` in a table at `level`, 0 being the leaf level.
+fn index(address: usize, level: usize) -> usize {
+    (address >> (RmmA::PAGE_SHIFT + RmmA::PAGE_ENTRY_SHIFT * level)) % RmmA::PAGE_ENTRIES
+}
+
+/// The next-level table `entry` points to, allocated if there is none yet.
+fn next_table(entry: &AtomicU64) -> usize {
+    let mut value = entry.load(Ordering::Relaxed);
+    if value & PRESENT == 0 {
+        let table = allocate_frame().expect("failed to allocate PTI page table");
+        // Not user accessible, so that none of the kernel half is.
+        value = table.base().data() as u64 | PRESENT | WRITABLE;
+        entry.store(value, Ordering::Relaxed);
+    }
+    (value & ADDRESS_MASK) as usize
+}
+
+/// Map the pages covering `len` bytes at `start` in the template, which must be locked, to the same
+/// frames as in the kernel page table.
+unsafe fn map_range(template: Frame, start: usize, len: usize, flags: u64) {
+    let mapper = KernelMapper::lock();
+    for page in (start & !(PAGE_SIZE - 1)..start + len).step_by(PAGE_SIZE) {
+        let (phys, _) = mapper
+            .translate(VirtualAddress::new(page))
+            .unwrap_or_else(|| panic!("PTI: {:#x} is not mapped", page));
+
+        let mut table = template.base().data();
+        for level in (1..RmmA::PAGE_LEVELS).rev() {
+            table = next_table(&entries(table)[index(page, level)]);
+        }
+        entries(table)[index(page, 0)].store(phys.data() as u64 | flags, Ordering::Relaxed);
+    }
+}
+
+/// Allocate the template, with the top-level entries covering the kernel image, the heap and the
+/// linear mapping of physical memory, so that every shadow table sees what is mapped later, and
+/// map the entry code. Called once, on the BSP.
+pub unsafe fn init() {
+    use crate::kernel_executable_offsets::{__entry_text_end, __entry_text_start};
+
+    let template = allocate_frame().expect("failed to allocate PTI template");
+    let top_level = RmmA::PAGE_LEVELS - 1;
+    for address in [
+        crate::KERNEL_OFFSET,
+        crate::KERNEL_HEAP_OFFSET,
+        crate::PHYS_OFFSET,
+    ] {
+        next_table(&entries(template.base().data())[index(address, top_level)]);
+    }
+
+    map_range(
+        template,
+        __entry_text_start(),
+        __entry_text_end() - __entry_text_start(),
+        PRESENT,
+    );
+    *TEMPLATE.lock() = Some(template);
+
+    log::info!("Page table isolation enabled");
+}
+
+/// Map the PCR, the backup interrupt stack and the IDT of this CPU in the template, once they are
+/// set up.
+pub unsafe fn init_cpu() {
+    let guard = TEMPLATE.lock();
+    let template = guard.expect("PTI template not yet allocated");
+    let pcr = gdt::pcr();
+
+    map_range(
+        template,
+        pcr as usize,
+        size_of::<ProcessorControlRegion>(),
+        PRESENT | WRITABLE | NO_EXECUTE,
+    );
+
+    let ist = (*pcr).tss.ist[0] as usize;
+    map_range(
+        template,
+        ist - BACKUP_STACK_SIZE,
+        BACKUP_STACK_SIZE,
+        PRESENT | WRITABLE | NO_EXECUTE,
+    );
+
+    let mut idtr = DescriptorTablePointer {
+        limit: 0,
+        base: core::ptr::null::<u64>(),
+    };
+    dtables::sidt(&mut idtr);
+    map_range(
+        template,
+        idtr.base as usize,
+        usize::from(idtr.limit) + 1,
+        PRESENT | NO_EXECUTE,
+    );
+}
+
+/// Allocate the shadow of a new user page table, with the kernel half of the template and an
+/// empty user half.
+pub fn new_shadow() -> Result<Frame> {
+    let shadow = allocate_frame().ok_or(Error::new(ENOMEM))?;
+    let template = TEMPLATE.lock().expect("PTI template not yet allocated");
+    let user_entries = RmmA::PAGE_ENTRIES / 2;
+    unsafe {
+        let from = entries(template.base().data());
+        let to = entries(shadow.base().data());
+        for (to, from) in to.iter().zip(from).skip(user_entries) {
+            to.store(from.load(Ordering::Relaxed), Ordering::Relaxed);
+        }
+    }
+    Ok(shadow)
+}
+
+unsafe fn state() -> &'static mut PtiState {
+    &mut (*gdt::pcr()).pti
+}
+
+/// Whether the shadow entry `shadow` is missing or outdated, given the entry `full` of the full
+/// table. Only the full table has its accessed bits set by the kernel and vice versa.
+fn outdated(shadow: u64, full: u64) -> bool {
+    (shadow ^ full) & !ACCESSED != 0
+}
+
+/// Copy the user half of the current full table to its shadow. CPUs running the address space may
+/// be walking the shadow table meanwhile, so entries are only written if they changed.
+unsafe fn sync_user(pti: &PtiState) {
+    let full = entries(pti.kernel_cr3);
+    let shadow = entries(pti.user_cr3);
+    for (shadow, full) in shadow.iter().zip(full).take(RmmA::PAGE_ENTRIES / 2) {
+        let entry = full.load(Ordering::Relaxed);
+        if outdated(shadow.load(Ordering::Relaxed), entry) {
+            shadow.store(entry, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Called after loading the page table `table` of an address space, with `id` being its PCID and
+/// whether its TLB entries were flushed, if PCIDs are enabled.
+pub unsafe fn switch_to(table: &Table, id: Option<(u16, bool)>) {
+    let pti = state();
+    let full = table.utable.table().phys().data();
+    let shadow = table.shadow.base().data();
+    match id {
+        Some((id, flush)) => {
+            let id = usize::from(id);
+            let noflush = CR3_NOFLUSH as usize;
+            pti.kernel_cr3 = full | id | noflush;
+            pti.user_cr3_next = shadow | (id + USER_PCID) | noflush;
+            pti.user_cr3 = if flush {
+                pti.user_cr3_next & !noflush
+            } else {
+                pti.user_cr3_next
+            };
+        }
+        None => {
+            pti.kernel_cr3 = full;
+            pti.user_cr3 = shadow;
+            pti.user_cr3_next = shadow;
+        }
+    }
+    sync_user(pti);
+}
+
+/// Called when invalidating TLB entries of the current address space, with `all` set if page
+/// tables may be about to be freed.
+pub unsafe fn invalidate_user(all: bool) {
+    let pti = state();
+    pti.user_cr3 &= !(CR3_NOFLUSH as usize);
+    if all {
+        sync_user(pti);
+    }
+}
+
+/// Called on page faults from userspace at `address`, copying the top-level entry covering it from
+/// the full table if it is missing or outdated in the shadow table. Returns whether it was, in
+/// which case the access only has to be retried.
+pub fn sync_fault(address: usize) -> bool {
+    if address >= crate::USER_END_OFFSET {
+        return false;
+    }
+    unsafe {
+        let pti = state();
+        let index = index(address, RmmA::PAGE_LEVELS - 1);
+        let entry = entries(pti.kernel_cr3)[index].load(Ordering::Relaxed);
+        let shadow = &entries(pti.user_cr3)[index];
+        if !outdated(shadow.load(Ordering::Relaxed), entry) {
+            return false;
+        }
+        shadow.store(entry, Ordering::Relaxed);
+        // The outdated entry may still be cached.
+        pti.user_cr3 &= !(CR3_NOFLUSH as usize);
+    }
+    true
+}
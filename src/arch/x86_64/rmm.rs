@@ -1,4 +1,4 @@
-use rmm::{Arch, PageFlags, VirtualAddress};
+use rmm::{Arch, PageFlags, PhysicalAddress, VirtualAddress};
 
 pub unsafe fn page_flags<A: Arch>(virt: VirtualAddress) -> PageFlags<A> {
     use crate::kernel_executable_offsets::*;
@@ -16,3 +16,112 @@ pub unsafe fn page_flags<A: Arch>(virt: VirtualAddress) -> PageFlags<A> {
     })
     .global(cfg!(not(feature = "pti")))
 }
+
+/// Number of address space IDs, or PCIDs, the TLB can tag entries with. ID 0 is used for the
+/// kernel and for page tables loaded without one. With page table isolation, the upper half tags
+/// the user page tables instead, see `pti`.
+#[cfg(not(feature = "pti"))]
+pub const ASID_COUNT: usize = 4096;
+#[cfg(feature = "pti")]
+pub const ASID_COUNT: usize = 2048;
+
+pub const CR3_NOFLUSH: u64 = 1 << 63;
+/// 5-level paging, which can only be enabled before entering long mode.
+const CR4_LA57: usize = 1 << 12;
+
+/// Check that the bootloader enabled 5-level paging exactly if the kernel was built for it, as the
+/// memory map in `consts` and the paging levels of `CurrentRmmArch` depend on it.
+pub unsafe fn check_paging_levels() {
+    let enabled = x86::controlregs::cr4().bits() & CR4_LA57 != 0;
+    let supported = crate::cpuid::has_ext_feat(|feat| feat.has_la57());
+    if enabled != cfg!(feature = "la57") {
+        panic!(
+            "5-level paging is {} by the bootloader, but the kernel was built {} the la57 feature",
+            if enabled { "enabled" } else { "not enabled" },
+            if enabled { "without" } else { "with" },
+        );
+    }
+    if supported && !enabled {
+        log::info!("5-level paging is supported, but not enabled");
+    }
+}
+
+/// The x86_64 architecture with 5-level paging, which only differs from [`rmm::X8664Arch`] in the
+/// number of levels, the linear mapping of physical memory, and which addresses are canonical.
+#[cfg(feature = "la57")]
+#[derive(Clone, Copy, Debug)]
+pub struct X8664La57Arch;
+
+#[cfg(feature = "la57")]
+impl Arch for X8664La57Arch {
+    const PAGE_SHIFT: usize = rmm::X8664Arch::PAGE_SHIFT;
+    const PAGE_ENTRY_SHIFT: usize = rmm::X8664Arch::PAGE_ENTRY_SHIFT;
+    const PAGE_LEVELS: usize = 5;
+
+    const ENTRY_ADDRESS_WIDTH: usize = rmm::X8664Arch::ENTRY_ADDRESS_WIDTH;
+    const ENTRY_FLAG_DEFAULT_PAGE: usize = rmm::X8664Arch::ENTRY_FLAG_DEFAULT_PAGE;
+    const ENTRY_FLAG_DEFAULT_TABLE: usize = rmm::X8664Arch::ENTRY_FLAG_DEFAULT_TABLE;
+    const ENTRY_FLAG_PRESENT: usize = rmm::X8664Arch::ENTRY_FLAG_PRESENT;
+    const ENTRY_FLAG_READONLY: usize = rmm::X8664Arch::ENTRY_FLAG_READONLY;
+    const ENTRY_FLAG_READWRITE: usize = rmm::X8664Arch::ENTRY_FLAG_READWRITE;
+    const ENTRY_FLAG_PAGE_USER: usize = rmm::X8664Arch::ENTRY_FLAG_PAGE_USER;
+    const ENTRY_FLAG_NO_EXEC: usize = rmm::X8664Arch::ENTRY_FLAG_NO_EXEC;
+    const ENTRY_FLAG_EXEC: usize = rmm::X8664Arch::ENTRY_FLAG_EXEC;
+    const ENTRY_FLAG_GLOBAL: usize = rmm::X8664Arch::ENTRY_FLAG_GLOBAL;
+    const ENTRY_FLAG_NO_GLOBAL: usize = rmm::X8664Arch::ENTRY_FLAG_NO_GLOBAL;
+    const ENTRY_FLAG_WRITE_COMBINING: usize = rmm::X8664Arch::ENTRY_FLAG_WRITE_COMBINING;
+
+    const PHYS_OFFSET: usize = crate::arch::consts::PHYS_OFFSET;
+
+    unsafe fn init() -> &'static [rmm::MemoryArea] {
+        rmm::X8664Arch::init()
+    }
+
+    #[inline(always)]
+    unsafe fn invalidate(address: VirtualAddress) {
+        rmm::X8664Arch::invalidate(address);
+    }
+
+    #[inline(always)]
+    unsafe fn invalidate_all() {
+        rmm::X8664Arch::invalidate_all();
+    }
+
+    #[inline(always)]
+    unsafe fn table(table_kind: rmm::TableKind) -> PhysicalAddress {
+        rmm::X8664Arch::table(table_kind)
+    }
+
+    #[inline(always)]
+    unsafe fn set_table(table_kind: rmm::TableKind, address: PhysicalAddress) {
+        rmm::X8664Arch::set_table(table_kind, address);
+    }
+
+    fn virt_is_valid(address: VirtualAddress) -> bool {
+        const SHIFT: u32 = usize::BITS - crate::arch::consts::VIRT_ADDR_BITS;
+        let address = address.data();
+        (((address << SHIFT) as isize) >> SHIFT) as usize == address
+    }
+}
+
+/// Whether this CPU tags TLB entries with PCIDs, enabled by `misc::init`.
+pub fn asids_enabled() -> bool {
+    unsafe { x86::controlregs::cr4() }.contains(x86::controlregs::Cr4::CR4_ENABLE_PCID)
+}
+
+/// Load the user page table `table` with address space ID `asid`, flushing the TLB entries
+/// tagged with it if `flush` is set.
+pub unsafe fn set_user_table(table: PhysicalAddress, asid: u16, flush: bool) {
+    let noflush = if flush { 0 } else { CR3_NOFLUSH };
+    x86::controlregs::cr3_write(table.data() as u64 | u64::from(asid) | noflush);
+}
+
+/// Flush the TLB entries of all address space IDs, including global ones.
+pub unsafe fn invalidate_all_asids() {
+    use x86::controlregs::{cr4, cr4_write, Cr4};
+
+    // Any change of CR4.PGE flushes the entire TLB.
+    let cr4 = cr4();
+    cr4_write(cr4 ^ Cr4::CR4_ENABLE_GLOBAL_PAGES);
+    cr4_write(cr4);
+}
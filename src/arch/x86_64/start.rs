@@ -36,34 +36,55 @@ static BSP_READY: AtomicBool = AtomicBool::new(false);
 
 #[repr(C, packed(8))]
 pub struct KernelArgs {
-    kernel_base: u64,
-    kernel_size: u64,
-    stack_base: u64,
-    stack_size: u64,
-    env_base: u64,
-    env_size: u64,
+    pub(crate) kernel_base: u64,
+    pub(crate) kernel_size: u64,
+    pub(crate) stack_base: u64,
+    pub(crate) stack_size: u64,
+    pub(crate) env_base: u64,
+    pub(crate) env_size: u64,
 
     /// The base pointer to the saved RSDP.
     ///
     /// This field can be NULL, and if so, the system has not booted with UEFI or in some other way
     /// retrieved the RSDPs. The kernel or a userspace driver will thus try searching the BIOS
     /// memory instead. On UEFI systems, searching is not guaranteed to actually work though.
-    acpi_rsdp_base: u64,
+    pub(crate) acpi_rsdp_base: u64,
     /// The size of the RSDP region.
-    acpi_rsdp_size: u64,
+    pub(crate) acpi_rsdp_size: u64,
 
-    areas_base: u64,
-    areas_size: u64,
+    pub(crate) areas_base: u64,
+    pub(crate) areas_size: u64,
 
     /// The physical base 64-bit pointer to the contiguous bootstrap/initfs.
-    bootstrap_base: u64,
+    pub(crate) bootstrap_base: u64,
     /// Size of contiguous bootstrap/initfs physical region, not necessarily page aligned.
-    bootstrap_size: u64,
+    pub(crate) bootstrap_size: u64,
+
+    /// Random seed for placing the kernel, or zero if the bootloader has no entropy source.
+    #[cfg_attr(not(feature = "kaslr"), allow(dead_code))]
+    pub(crate) kaslr_seed: u64,
 }
 
 /// The entry to Rust, all things must be initialized
 #[no_mangle]
 pub unsafe extern "C" fn kstart(args_ptr: *const KernelArgs) -> ! {
+    // Move the kernel first, and start over at its new address. The kernel page table does not
+    // map the link-time one.
+    #[cfg(feature = "kaslr")]
+    if crate::kaslr::slide() == 0 {
+        let args = args_ptr.read();
+        if super::kaslr::relocate(
+            args.kernel_base as usize,
+            args.kernel_size as usize,
+            args.kaslr_seed,
+        ) != 0
+        {
+            let kstart: unsafe extern "C" fn(*const KernelArgs) -> ! =
+                core::mem::transmute(crate::kaslr::relocated(kstart as usize));
+            kstart(args_ptr);
+        }
+    }
+
     let bootstrap = {
         let args = args_ptr.read();
 
@@ -106,6 +127,7 @@ pub unsafe extern "C" fn kstart(args_ptr: *const KernelArgs) -> ! {
         });
 
         info!("Redox OS starting...");
+        crate::arch::rmm::check_paging_levels();
         info!(
             "Kernel: {:X}:{:X}",
             { args.kernel_base },
@@ -170,6 +192,11 @@ pub unsafe extern "C" fn kstart(args_ptr: *const KernelArgs) -> ! {
             args.bootstrap_size as usize,
             BootloaderMemoryKind::IdentityMap,
         );
+        crate::kexec::init(
+            env,
+            (args.acpi_rsdp_base as usize, args.acpi_rsdp_size as usize),
+            (args.bootstrap_base as usize, args.bootstrap_size as usize),
+        );
         crate::startup::memory::init(Some(0x100000), None);
 
         // Initialize PAT
@@ -206,15 +233,31 @@ pub unsafe extern "C" fn kstart(args_ptr: *const KernelArgs) -> ! {
 
         idt::init_paging_post_heap(LogicalCpuId::BSP);
 
+        // Map the entry code and the per-CPU data it uses in user page tables
+        #[cfg(feature = "pti")]
+        {
+            crate::arch::pti::init();
+            crate::arch::pti::init_cpu();
+        }
+
         // Activate memory logging
         crate::log::init();
 
         // Initialize miscellaneous processor features
         misc::init(LogicalCpuId::BSP);
 
+        // Relocate EFI runtime services to the kernel half
+        crate::efi::init(env);
+
+        crate::context::init_lazy_fpu(env);
+
         // Initialize devices
         device::init();
 
+        // Find the virtio console and entropy source
+        #[cfg(feature = "virtio")]
+        crate::devices::virtio::pci::init();
+
         // Read ACPI tables, starts APs
         #[cfg(feature = "acpi")]
         {
@@ -284,6 +327,9 @@ pub unsafe extern "C" fn kstart_ap(args_ptr: *const KernelArgsAp) -> ! {
         // Set up IDT for AP
         idt::init_paging_post_heap(cpu_id);
 
+        #[cfg(feature = "pti")]
+        crate::arch::pti::init_cpu();
+
         crate::alternative::early_init(false);
 
         // Set up syscall instruction
@@ -304,5 +350,7 @@ pub unsafe extern "C" fn kstart_ap(args_ptr: *const KernelArgsAp) -> ! {
         interrupt::pause();
     }
 
+    device::init_ap_timer();
+
     crate::kmain_ap(cpu_id);
 }
@@ -0,0 +1,91 @@
+//! Idle states: HLT, and the C-states MWAIT enumerates in CPUID leaf 5. States deeper than C1 may
+//! stop the local APIC timer, which wakes idle CPUs up, so they are only used if it always runs
+//! (ARAT).
+
+use arrayvec::ArrayVec;
+use core::arch::asm;
+use spin::Once;
+
+use crate::{
+    cpuid::cpuid,
+    idle::{IdleState, MAX_STATES},
+    percpu::PercpuBlock,
+};
+
+/// The hint of HLT, which is not entered using MWAIT.
+const HLT: u32 = !0;
+
+const MWAIT_NAMES: [&str; 7] = ["C1", "C2", "C3", "C4", "C5", "C6", "C7"];
+
+/// Target residencies of the C-states C1 to C7, in nanoseconds. CPUID does not report them, so
+/// these are conservative guesses.
+const MWAIT_TARGET_RESIDENCY: [u128; 7] = [
+    2_000, 20_000, 100_000, 400_000, 800_000, 1_000_000, 2_000_000,
+];
+
+static STATES: Once<ArrayVec<IdleState, MAX_STATES>> = Once::new();
+
+pub fn states() -> &'static [IdleState] {
+    STATES.call_once(|| {
+        let mut states = ArrayVec::new();
+        states.push(IdleState {
+            name: "hlt",
+            target_residency: 0,
+            hint: HLT,
+        });
+
+        let cpuid = cpuid();
+        if !cpuid
+            .get_feature_info()
+            .is_some_and(|info| info.has_monitor_mwait())
+        {
+            return states;
+        }
+        let Some(mwait) = cpuid.get_monitor_mwait_info() else {
+            return states;
+        };
+        let arat = cpuid
+            .get_thermal_power_info()
+            .is_some_and(|info| info.has_arat());
+        let substates = [
+            mwait.supported_c1_states(),
+            mwait.supported_c2_states(),
+            mwait.supported_c3_states(),
+            mwait.supported_c4_states(),
+            mwait.supported_c5_states(),
+            mwait.supported_c6_states(),
+            mwait.supported_c7_states(),
+        ];
+        for (i, count) in substates.into_iter().enumerate() {
+            if i > 0 && !arat {
+                break;
+            }
+            if count == 0 {
+                continue;
+            }
+            let _ = states.try_push(IdleState {
+                name: MWAIT_NAMES[i],
+                target_residency: MWAIT_TARGET_RESIDENCY[i],
+                // The C-state minus one in bits 4 to 7, and the first sub-state.
+                hint: (i as u32) << 4,
+            });
+        }
+        states
+    })
+}
+
+/// Enter `state` until the next interrupt, with interrupts disabled, which are enabled on return.
+pub unsafe fn enter(state: &IdleState) {
+    if state.hint == HLT {
+        crate::interrupt::enable_and_halt();
+        return;
+    }
+    // Nothing writes to the monitored line, so only interrupts wake the CPU up.
+    let monitored = core::ptr::addr_of!(PercpuBlock::current().idle);
+    #[cfg(target_arch = "x86")]
+    asm!("monitor", in("eax") monitored, in("ecx") 0, in("edx") 0, options(nostack));
+    #[cfg(target_arch = "x86_64")]
+    asm!("monitor", in("rax") monitored, in("ecx") 0, in("edx") 0, options(nostack));
+    // Like with HLT, the interrupt shadow of STI covers MWAIT.
+    asm!("sti; mwait", in("eax") state.hint, in("ecx") 0, options(nomem, nostack));
+}
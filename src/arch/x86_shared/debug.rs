@@ -5,11 +5,14 @@ use spin::MutexGuard;
 
 #[cfg(any(feature = "lpss_debug", feature = "serial_debug"))]
 use crate::devices::uart_16550::SerialPort;
-use crate::log::{Log, LOG};
 #[cfg(feature = "lpss_debug")]
 use crate::syscall::io::Mmio;
 #[cfg(any(feature = "qemu_debug", feature = "serial_debug"))]
 use crate::syscall::io::Pio;
+use crate::{
+    devices::console::{self, Output},
+    log::{Log, LOG},
+};
 #[cfg(feature = "qemu_debug")]
 use syscall::io::Io;
 
@@ -21,6 +24,8 @@ use super::device::serial::LPSS;
 use super::device::system76_ec::{System76Ec, SYSTEM76_EC};
 #[cfg(feature = "graphical_debug")]
 use crate::devices::graphical_debug::{DebugDisplay, DEBUG_DISPLAY};
+#[cfg(all(feature = "virtio", target_arch = "x86_64"))]
+use crate::devices::virtio::console::{Console, VIRTIO_CONSOLE};
 
 #[cfg(feature = "qemu_debug")]
 pub static QEMU: Mutex<Pio<u8>> = Mutex::new(Pio::<u8>::new(0x402));
@@ -37,6 +42,8 @@ pub struct Writer<'a> {
     serial: MutexGuard<'a, SerialPort<Pio<u8>>>,
     #[cfg(feature = "system76_ec_debug")]
     system76_ec: MutexGuard<'a, Option<System76Ec>>,
+    #[cfg(all(feature = "virtio", target_arch = "x86_64"))]
+    virtio_console: MutexGuard<'a, Option<Console>>,
 }
 
 impl<'a> Writer<'a> {
@@ -53,6 +60,8 @@ impl<'a> Writer<'a> {
             serial: COM1.lock(),
             #[cfg(feature = "system76_ec_debug")]
             system76_ec: SYSTEM76_EC.lock(),
+            #[cfg(all(feature = "virtio", target_arch = "x86_64"))]
+            virtio_console: VIRTIO_CONSOLE.lock(),
         }
     }
 
@@ -65,11 +74,18 @@ impl<'a> Writer<'a> {
 
         #[cfg(feature = "graphical_debug")]
         {
-            if let Some(ref mut display) = *self.display {
+            if let Some(ref mut display) = *self.display
+                && console::enabled(Output::Display)
+            {
                 display.write(buf);
             }
         }
 
+        // The serial ports, and any other debug port.
+        if !console::enabled(Output::Serial) {
+            return;
+        }
+
         #[cfg(feature = "lpss_debug")]
         {
             if let Some(ref mut lpss) = *self.lpss {
@@ -95,6 +111,13 @@ impl<'a> Writer<'a> {
                 system76_ec.print_slice(buf);
             }
         }
+
+        #[cfg(all(feature = "virtio", target_arch = "x86_64"))]
+        {
+            if let Some(ref mut console) = *self.virtio_console {
+                console.write(buf);
+            }
+        }
     }
 }
 
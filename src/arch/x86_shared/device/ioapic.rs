@@ -1,7 +1,6 @@
 use core::{fmt, ptr};
 
 use alloc::vec::Vec;
-use spin::Mutex;
 
 #[cfg(feature = "acpi")]
 use crate::acpi::madt::{self, Madt, MadtEntry, MadtIntSrcOverride, MadtIoApic};
@@ -10,6 +9,7 @@ use crate::{
     arch::interrupt::irq,
     memory::{Frame, KernelMapper},
     paging::{entry::EntryFlags, Page, PageFlags, PhysicalAddress},
+    sync::Mutex,
 };
 
 use super::pic;
@@ -222,6 +222,10 @@ static mut IOAPICS: Option<Vec<IoApic>> = None;
 // static mut for the same reason as above
 static mut SRC_OVERRIDES: Option<Vec<Override>> = None;
 
+/// The redirection entries of every I/O APIC, saved while the system is suspended.
+#[cfg(all(feature = "acpi", target_arch = "x86_64"))]
+static SAVED_REDIRECTIONS: Mutex<Vec<u64>> = Mutex::new(Vec::new());
+
 pub fn ioapics() -> &'static [IoApic] {
     unsafe { IOAPICS.as_ref().map_or(&[], |vector| &vector[..]) }
 }
@@ -422,6 +426,31 @@ fn find_ioapic(gsi: u32) -> Option<&'static IoApic> {
         .find(|apic| gsi >= apic.gsi_start && gsi < apic.gsi_start + u32::from(apic.count))
 }
 
+/// Save the redirection tables, which the I/O APICs lose while the system is suspended.
+#[cfg(all(feature = "acpi", target_arch = "x86_64"))]
+pub fn suspend() {
+    let mut saved = SAVED_REDIRECTIONS.lock();
+    saved.clear();
+    for apic in ioapics() {
+        let mut regs = apic.regs.lock();
+        // The version register holds the index of the last entry, rather than their number.
+        saved.extend((0..=apic.count).map(|idx| regs.read_ioredtbl(idx)));
+    }
+}
+
+/// Restore the redirection tables saved by [`suspend`].
+#[cfg(all(feature = "acpi", target_arch = "x86_64"))]
+pub fn resume() {
+    let saved = SAVED_REDIRECTIONS.lock();
+    let mut entries = saved.iter();
+    for apic in ioapics() {
+        let mut regs = apic.regs.lock();
+        for (idx, &entry) in (0..=apic.count).zip(&mut entries) {
+            regs.write_ioredtbl(idx, entry);
+        }
+    }
+}
+
 pub unsafe fn mask(irq: u8) {
     let gsi = resolve(irq);
     let apic = match find_ioapic(gsi) {
@@ -1,12 +1,16 @@
 use core::{
     cell::SyncUnsafeCell,
+    fmt,
     ptr::{read_volatile, write_volatile},
+    sync::atomic::{AtomicU32, AtomicUsize, Ordering},
 };
 use x86::msr::*;
 
 use crate::{
+    cpu_set::{LogicalCpuId, MAX_CPU_COUNT},
     ipi::IpiKind,
     paging::{PageFlags, PhysicalAddress},
+    percpu::PercpuBlock,
 };
 
 use crate::{arch::cpuid::cpuid, memory::KernelMapper};
@@ -19,6 +23,19 @@ pub unsafe fn the_local_apic() -> &'static mut LocalApic {
     &mut *LOCAL_APIC.get()
 }
 
+/// APIC ID of each logical CPU, used as the destination of IPIs.
+static APIC_IDS: [AtomicU32; MAX_CPU_COUNT as usize] = {
+    const ZERO: AtomicU32 = AtomicU32::new(0);
+    [ZERO; MAX_CPU_COUNT as usize]
+};
+
+pub fn set_apic_id(cpu_id: LogicalCpuId, apic_id: u32) {
+    APIC_IDS[cpu_id.get() as usize].store(apic_id, Ordering::Relaxed);
+}
+pub fn apic_id(cpu_id: LogicalCpuId) -> u32 {
+    APIC_IDS[cpu_id.get() as usize].load(Ordering::Relaxed)
+}
+
 pub unsafe fn init(active_table: &mut KernelMapper) {
     the_local_apic().init(active_table);
 }
@@ -27,6 +44,99 @@ pub unsafe fn init_ap() {
     the_local_apic().init_ap();
 }
 
+/// Vector of the local APIC error interrupt.
+pub const ERROR_VECTOR: u8 = 49;
+/// Vector of spurious interrupts, which the local APIC delivers without setting the ISR bit, and
+/// which therefore must not be acknowledged.
+pub const SPURIOUS_VECTOR: u8 = 0xFF;
+
+/// Per-CPU local APIC error and spurious interrupt counters.
+#[derive(Default)]
+pub struct LapicStats {
+    pub errors: AtomicUsize,
+    pub spurious: AtomicUsize,
+    /// All ESR bits seen so far.
+    pub esr: AtomicU32,
+}
+
+/// Names of the Error Status Register bits.
+const ESR_BITS: [&str; 8] = [
+    "send checksum",
+    "receive checksum",
+    "send accept",
+    "receive accept",
+    "redirectable IPI",
+    "send illegal vector",
+    "receive illegal vector",
+    "illegal register address",
+];
+
+/// Decoded Error Status Register value.
+pub struct Esr(pub u32);
+
+impl fmt::Display for Esr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut first = true;
+        for (bit, name) in ESR_BITS.iter().enumerate() {
+            if self.0 & (1 << bit) != 0 {
+                if !first {
+                    f.write_str(", ")?;
+                }
+                f.write_str(name)?;
+                first = false;
+            }
+        }
+        if first {
+            f.write_str("none")?;
+        }
+        Ok(())
+    }
+}
+
+/// Account for an error interrupt on the current CPU.
+///
+/// Each kind of error is logged the first time it occurs, and after that only when the number of
+/// errors reaches a power of two, so that persistent error conditions remain visible without
+/// flooding the log.
+pub unsafe fn handle_error() {
+    let esr = the_local_apic().esr();
+    let percpu = PercpuBlock::current();
+    let stats = &percpu.misc_arch_info.lapic_stats;
+
+    let count = stats.errors.fetch_add(1, Ordering::Relaxed) + 1;
+    let new_bits = esr & !stats.esr.fetch_or(esr, Ordering::Relaxed);
+
+    if new_bits != 0 || count.is_power_of_two() {
+        log::error!(
+            "Local APIC error on CPU {}: ESR={:#x} ({}), {} errors so far",
+            percpu.cpu_id,
+            esr,
+            Esr(esr),
+            count
+        );
+    }
+}
+
+/// Account for a spurious interrupt on the current CPU. These are expected occasionally, so only
+/// an unusually high number is logged.
+pub fn handle_spurious() {
+    let percpu = PercpuBlock::current();
+    let count = percpu
+        .misc_arch_info
+        .lapic_stats
+        .spurious
+        .fetch_add(1, Ordering::Relaxed)
+        + 1;
+
+    if count >= 1024 && count.is_power_of_two() {
+        log::warn!(
+            "{} spurious local APIC interrupts on CPU {}",
+            count,
+            percpu.cpu_id
+        );
+    }
+}
+
 /// Local APIC
 pub struct LocalApic {
     pub address: usize,
@@ -72,10 +182,12 @@ impl LocalApic {
 
     unsafe fn init_ap(&mut self) {
         if self.x2 {
-            wrmsr(IA32_APIC_BASE, rdmsr(IA32_APIC_BASE) | 1 << 10);
-            wrmsr(IA32_X2APIC_SIVR, 0x100);
+            // Both the global enable and x2APIC mode bits, as x2APIC mode cannot be entered from
+            // the disabled state.
+            wrmsr(IA32_APIC_BASE, rdmsr(IA32_APIC_BASE) | 1 << 11 | 1 << 10);
+            wrmsr(IA32_X2APIC_SIVR, 0x100 | u64::from(SPURIOUS_VECTOR));
         } else {
-            self.write(0xF0, 0x100);
+            self.write(0xF0, 0x100 | u32::from(SPURIOUS_VECTOR));
         }
         self.setup_error_int();
         //self.setup_timer();
@@ -133,18 +245,22 @@ impl LocalApic {
         }
     }
 
-    pub fn ipi(&mut self, apic_id: u32, kind: IpiKind) {
-        let mut icr = 0x40 | kind as u64;
+    /// The destination field of the ICR for the given APIC ID. x2APIC IDs are 32 bits wide, while
+    /// xAPIC IDs are limited to 8 bits.
+    pub fn icr_destination(&self, apic_id: u32) -> u64 {
         if self.x2 {
-            icr |= u64::from(apic_id) << 32;
+            u64::from(apic_id) << 32
         } else {
-            icr |= u64::from(apic_id) << 56;
+            debug_assert!(apic_id <= 0xFF, "xAPIC ID {} out of range", apic_id);
+            u64::from(apic_id & 0xFF) << 56
         }
-        self.set_icr(icr);
+    }
+
+    pub fn ipi(&mut self, apic_id: u32, kind: IpiKind) {
+        self.set_icr(self.icr_destination(apic_id) | 0x40 | kind as u64);
     }
     pub fn ipi_nmi(&mut self, apic_id: u32) {
-        let shift = if self.x2 { 32 } else { 56 };
-        self.set_icr((u64::from(apic_id) << shift) | (1 << 14) | (0b100 << 8));
+        self.set_icr(self.icr_destination(apic_id) | (1 << 14) | (0b100 << 8));
     }
 
     pub unsafe fn eoi(&mut self) {
@@ -229,9 +345,16 @@ impl LocalApic {
             self.write(0x370, lvt_error);
         }
     }
+    #[cfg(all(feature = "profiling", target_arch = "x86_64"))]
+    pub unsafe fn set_lvt_perf(&mut self, value: u32) {
+        if self.x2 {
+            wrmsr(IA32_X2APIC_LVT_PMI, u64::from(value));
+        } else {
+            self.write(0x340, value);
+        }
+    }
     unsafe fn setup_error_int(&mut self) {
-        let vector = 49u32;
-        self.set_lvt_error(vector);
+        self.set_lvt_error(u32::from(ERROR_VECTOR));
     }
 }
 
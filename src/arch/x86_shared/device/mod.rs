@@ -11,6 +11,7 @@ pub mod rtc;
 pub mod serial;
 #[cfg(feature = "system76_ec_debug")]
 pub mod system76_ec;
+pub mod tsc_deadline;
 
 #[cfg(feature = "x86_kvm_pv")]
 pub mod tsc;
@@ -52,7 +53,11 @@ pub unsafe fn init_noncore() {
         log::info!("TSC used as system clock source");
     }
 
-    if init_hpet() {
+    // The profiler uses the local APIC timer of its CPU.
+    if !cfg!(feature = "profiling") && tsc_deadline::init() {
+        crate::timer::enable_oneshot();
+        log::info!("TSC-deadline local APIC timer used as one-shot system timer");
+    } else if init_hpet() {
         log::info!("HPET used as system timer");
     } else {
         pit::init();
@@ -66,6 +71,35 @@ pub unsafe fn init_noncore() {
     log::info!("Finished initializing devices");
 }
 
+/// Restart the system timer on the BSP after resuming from suspend, which resets it.
+pub unsafe fn resume_timer() {
+    #[cfg(feature = "x86_kvm_pv")]
+    tsc::resume();
+
+    if crate::timer::oneshot() {
+        tsc_deadline::resume();
+    } else if !init_hpet() {
+        pit::init();
+    }
+}
+
+/// Save the state of the interrupt controllers, which they lose while the system is suspended.
+#[cfg(all(feature = "acpi", target_arch = "x86_64"))]
+pub unsafe fn suspend() {
+    ioapic::suspend();
+    pic::suspend();
+}
+
+/// Restore the interrupt controllers and serial ports on the BSP after resuming from suspend.
+/// The system timer is restarted by [`resume_timer`].
+#[cfg(all(feature = "acpi", target_arch = "x86_64"))]
+pub unsafe fn resume() {
+    local_apic::init_ap();
+    pic::resume();
+    ioapic::resume();
+    serial::resume();
+}
+
 pub unsafe fn init_ap() {
     local_apic::init_ap();
 
@@ -73,8 +107,21 @@ pub unsafe fn init_ap() {
     tsc::init();
 }
 
+/// Start the timer of a secondary CPU, once the BSP chose the system timer.
+pub unsafe fn init_ap_timer() {
+    if crate::timer::oneshot() {
+        tsc_deadline::init_ap();
+    }
+}
+
 #[derive(Default)]
 pub struct ArchPercpuMisc {
+    pub lapic_stats: local_apic::LapicStats,
+
     #[cfg(feature = "x86_kvm_pv")]
     pub tsc_info: tsc::TscPercpu,
+
+    /// Watchpoint table generation last loaded into this CPU's debug registers
+    #[cfg(feature = "debugger")]
+    pub watchpoint_gen: core::cell::Cell<usize>,
 }
@@ -6,7 +6,27 @@ use crate::{
 pub static mut MASTER: Pic = Pic::new(0x20);
 pub static mut SLAVE: Pic = Pic::new(0xA0);
 
+/// The masks of both PICs, saved while the system is suspended.
+#[cfg(all(feature = "acpi", target_arch = "x86_64"))]
+static mut SAVED_MASKS: (u8, u8) = (0, 0);
+
 pub unsafe fn init() {
+    remap();
+
+    // Unmask interrupts
+    MASTER.data.write(0);
+    SLAVE.data.write(0);
+
+    // Ack remaining interrupts
+    MASTER.ack();
+    SLAVE.ack();
+
+    // probably already set to PIC, but double-check
+    irq::set_irq_method(irq::IrqMethod::Pic);
+}
+
+/// Initialize both PICs, delivering their interrupts from vector 0x20.
+unsafe fn remap() {
     // Start initialization
     MASTER.cmd.write(0x11);
     SLAVE.cmd.write(0x11);
@@ -22,17 +42,21 @@ pub unsafe fn init() {
     // Set up interrupt mode (1 is 8086/88 mode, 2 is auto EOI)
     MASTER.data.write(1);
     SLAVE.data.write(1);
+}
 
-    // Unmask interrupts
-    MASTER.data.write(0);
-    SLAVE.data.write(0);
-
-    // Ack remaining interrupts
-    MASTER.ack();
-    SLAVE.ack();
+/// Save the masks, which the PICs lose while the system is suspended.
+#[cfg(all(feature = "acpi", target_arch = "x86_64"))]
+pub unsafe fn suspend() {
+    SAVED_MASKS = (MASTER.data.read(), SLAVE.data.read());
+}
 
-    // probably already set to PIC, but double-check
-    irq::set_irq_method(irq::IrqMethod::Pic);
+/// Initialize the PICs again after resuming, with the masks saved by [`suspend`].
+#[cfg(all(feature = "acpi", target_arch = "x86_64"))]
+pub unsafe fn resume() {
+    remap();
+    let (master, slave) = SAVED_MASKS;
+    MASTER.data.write(master);
+    SLAVE.data.write(slave);
 }
 
 pub unsafe fn disable() {
@@ -8,6 +8,7 @@ pub static mut COMMAND: Pio<u8> = Pio::new(0x43);
 const SELECT_CHAN0: u8 = 0b00 << 6;
 const ACCESS_LATCH: u8 = 0b00 << 4;
 const ACCESS_LOHI: u8 = 0b11 << 4;
+const MODE_0: u8 = 0b000 << 1;
 const MODE_2: u8 = 0b010 << 1;
 
 // 1 / (1.193182 MHz) = 838,095,110 femtoseconds ~= 838.095 ns
@@ -25,6 +26,11 @@ pub unsafe fn init() {
     CHAN0.write((CHAN0_DIVISOR >> 8) as u8);
 }
 
+/// Stop the periodic interrupt. In mode 0, the counter waits for a count that is never written.
+pub unsafe fn stop() {
+    COMMAND.write(SELECT_CHAN0 | ACCESS_LOHI | MODE_0);
+}
+
 pub unsafe fn read() -> u16 {
     COMMAND.write(SELECT_CHAN0 | ACCESS_LATCH);
     let low = CHAN0.read();
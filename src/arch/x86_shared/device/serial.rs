@@ -11,6 +11,13 @@ pub static COM2: Mutex<SerialPort<Pio<u8>>> = Mutex::new(SerialPort::<Pio<u8>>::
 #[cfg(feature = "lpss_debug")]
 pub static LPSS: Mutex<Option<&'static mut SerialPort<Mmio<u32>>>> = Mutex::new(None);
 
+/// Initialize the legacy serial ports again after resuming from suspend.
+#[cfg(all(feature = "acpi", target_arch = "x86_64"))]
+pub unsafe fn resume() {
+    COM1.lock().init();
+    COM2.lock().init();
+}
+
 pub unsafe fn init() {
     COM1.lock().init();
     COM2.lock().init();
@@ -97,6 +97,31 @@ pub fn monotonic_absolute() -> Option<u128> {
     }
 }
 
+/// Whether this CPU uses the pvclock as its monotonic clock.
+pub fn in_use() -> bool {
+    !PercpuBlock::current()
+        .misc_arch_info
+        .tsc_info
+        .vcpu_page
+        .get()
+        .is_null()
+}
+
+/// Re-register the pvclock page of this CPU after resuming from suspend, which resets the MSR.
+pub unsafe fn resume() {
+    let inf = &PercpuBlock::current().misc_arch_info.tsc_info;
+    let ptr = inf.vcpu_page.get();
+    if ptr.is_null() {
+        return;
+    }
+    x86::msr::wrmsr(
+        MSR_KVM_SYSTEM_TIME_NEW,
+        (ptr as usize - crate::PHYS_OFFSET) as u64 | 1,
+    );
+    // The clock may restart from a lower value, which the generic code compensates for.
+    inf.prev.set(0);
+}
+
 pub unsafe fn init() -> bool {
     let cpuid = crate::cpuid::cpuid();
     if !cpuid.get_feature_info().map_or(false, |f| f.has_tsc()) {
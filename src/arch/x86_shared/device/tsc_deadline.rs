@@ -0,0 +1,138 @@
+//! One-shot system timer using the TSC-deadline mode of the local APIC timer.
+//!
+//! With an invariant TSC, the TSC also serves as the monotonic clock, so that the periodic PIT
+//! interrupt can be stopped altogether. Its frequency is read from CPUID if reported there, and
+//! otherwise measured against the PIT, right before stopping it.
+
+use core::sync::atomic::{fence, AtomicU64, Ordering};
+use x86::{
+    msr::{wrmsr, IA32_TSC_DEADLINE},
+    time::rdtsc,
+};
+
+use super::{
+    local_apic::{the_local_apic, LvtTimerMode},
+    pit,
+};
+use crate::{
+    arch::cpuid::cpuid,
+    time::NANOS_PER_SEC,
+    vdso::{VdsoClock, VDSO_CLOCK_TSC},
+};
+
+/// IDT vector of the local APIC timer.
+const TIMER_VECTOR: u32 = 48;
+/// Number of PIT periods to measure the TSC frequency over, about 50 ms.
+const CALIBRATION_COUNTS: u64 = 59_659;
+
+/// TSC frequency in Hz, or zero if not used.
+static FREQUENCY: AtomicU64 = AtomicU64::new(0);
+/// TSC value, and monotonic time, at which the TSC became the clock source.
+static BASE_TSC: AtomicU64 = AtomicU64::new(0);
+static BASE_NANOS: AtomicU64 = AtomicU64::new(0);
+
+fn supported() -> bool {
+    let cpuid = cpuid();
+    cpuid
+        .get_feature_info()
+        .map_or(false, |info| info.has_tsc_deadline())
+        && cpuid
+            .get_advanced_power_mgmt_info()
+            .map_or(false, |info| info.has_invariant_tsc())
+}
+
+/// Measure the TSC frequency against the PIT, which must be running.
+unsafe fn calibrate() -> u64 {
+    let mut counts = 0;
+    let mut last = pit::read();
+    let start = rdtsc();
+    while counts < CALIBRATION_COUNTS {
+        // The PIT counter wraps around every period.
+        let now = pit::read();
+        counts += u64::from((now + pit::CHAN0_DIVISOR - last) % pit::CHAN0_DIVISOR);
+        last = now;
+    }
+    let elapsed = rdtsc() - start;
+    (u128::from(elapsed) * 1_000_000_000_000_000 / (u128::from(counts) * pit::PERIOD_FS)) as u64
+}
+
+/// Switch to the TSC as clock source and one-shot timer, on the BSP, stopping the PIT.
+pub unsafe fn init() -> bool {
+    if !supported() {
+        return false;
+    }
+    let frequency = match cpuid().get_tsc_info().and_then(|info| info.tsc_frequency()) {
+        Some(frequency) => frequency,
+        None => {
+            pit::init();
+            calibrate()
+        }
+    };
+    if frequency == 0 {
+        return false;
+    }
+    log::info!("TSC frequency: {} kHz", frequency / 1000);
+
+    // Continue from the clock used until now.
+    BASE_NANOS.store(
+        crate::arch::time::monotonic_absolute() as u64,
+        Ordering::Relaxed,
+    );
+    BASE_TSC.store(rdtsc(), Ordering::Relaxed);
+    FREQUENCY.store(frequency, Ordering::Release);
+
+    pit::stop();
+    init_ap();
+    true
+}
+
+/// Put the local APIC timer of this CPU in TSC-deadline mode, leaving it disarmed.
+pub unsafe fn init_ap() {
+    the_local_apic().set_lvt_timer(TIMER_VECTOR | (LvtTimerMode::TscDeadline as u32) << 17);
+    // Orders the LVT write before any write to the deadline MSR, as required in xAPIC mode.
+    fence(Ordering::SeqCst);
+}
+
+/// Rebase the clock on the BSP after resuming from suspend, which resets the TSC.
+pub unsafe fn resume() {
+    BASE_TSC.store(rdtsc(), Ordering::Relaxed);
+    BASE_NANOS.store(0, Ordering::Relaxed);
+    pit::stop();
+    init_ap();
+}
+
+pub fn monotonic() -> Option<u128> {
+    let frequency = FREQUENCY.load(Ordering::Acquire);
+    if frequency == 0 {
+        return None;
+    }
+    let ticks = unsafe { rdtsc() }.saturating_sub(BASE_TSC.load(Ordering::Relaxed));
+    Some(
+        u128::from(BASE_NANOS.load(Ordering::Relaxed))
+            + u128::from(ticks) * NANOS_PER_SEC / u128::from(frequency),
+    )
+}
+
+/// The clock parameters for the vDSO, if the TSC is the clock source.
+pub fn vdso_clock() -> Option<VdsoClock> {
+    let frequency = FREQUENCY.load(Ordering::Acquire);
+    (frequency != 0).then(|| VdsoClock {
+        mode: VDSO_CLOCK_TSC,
+        base_counter: BASE_TSC.load(Ordering::Relaxed),
+        base_nanos: BASE_NANOS.load(Ordering::Relaxed),
+        frequency,
+    })
+}
+
+/// Arm the timer of this CPU to fire at `deadline`, in nanoseconds of the monotonic clock.
+/// Stop the timer of this CPU until it is armed again.
+pub unsafe fn disarm() {
+    wrmsr(IA32_TSC_DEADLINE, 0);
+}
+pub unsafe fn arm(deadline: u128) {
+    let frequency = u128::from(FREQUENCY.load(Ordering::Relaxed));
+    let delta = deadline.saturating_sub(crate::time::monotonic());
+    let ticks = u64::try_from(delta * frequency / NANOS_PER_SEC).unwrap_or(u64::MAX);
+    // Writing zero disarms the timer, while a deadline in the past fires right away.
+    wrmsr(IA32_TSC_DEADLINE, rdtsc().saturating_add(ticks).max(1));
+}
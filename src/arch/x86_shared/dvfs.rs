@@ -0,0 +1,144 @@
+//! Frequency scaling of Intel processors, using hardware-controlled performance states (HWP)
+//! where available, and otherwise Enhanced SpeedStep through IA32_PERF_CTL. The performance
+//! states ACPI describes with `_PSS` need an AML interpreter, so SpeedStep uses the range of
+//! ratios in MSR_PLATFORM_INFO instead. Levels are ratios to the 100 MHz bus clock, which HWP
+//! levels only approximate on some processors. The energy bias is set in IA32_ENERGY_PERF_BIAS,
+//! and in the energy performance preference of HWP, where supported.
+
+use spin::Once;
+use x86::msr::{rdmsr, wrmsr};
+
+use crate::{
+    cpufreq::{CpufreqDriver, EnergyBias},
+    cpuid::cpuid,
+};
+
+const MSR_PLATFORM_INFO: u32 = 0xce;
+const IA32_PERF_CTL: u32 = 0x199;
+const IA32_MISC_ENABLE: u32 = 0x1a0;
+const IA32_ENERGY_PERF_BIAS: u32 = 0x1b0;
+const IA32_PM_ENABLE: u32 = 0x770;
+const IA32_HWP_CAPABILITIES: u32 = 0x771;
+const IA32_HWP_REQUEST: u32 = 0x774;
+
+/// Enhanced SpeedStep is enabled by the firmware.
+const MISC_ENABLE_EIST: u64 = 1 << 16;
+
+/// The kHz in a ratio.
+const BUS_KHZ: u32 = 100_000;
+
+enum Kind {
+    Hwp { epp: bool },
+    Speedstep { min: u32, max: u32 },
+}
+
+struct Driver {
+    kind: Kind,
+    epb: bool,
+}
+
+static DRIVER: Once<Option<Driver>> = Once::new();
+
+pub fn driver() -> Option<&'static dyn CpufreqDriver> {
+    DRIVER
+        .call_once(probe)
+        .as_ref()
+        .map(|driver| driver as &dyn CpufreqDriver)
+}
+
+fn probe() -> Option<Driver> {
+    let cpuid = cpuid();
+    if cpuid
+        .get_vendor_info()
+        .map_or(true, |vendor| vendor.as_str() != "GenuineIntel")
+    {
+        return None;
+    }
+    let power = cpuid.get_thermal_power_info()?;
+    let kind = if power.has_hwp() {
+        Kind::Hwp {
+            epp: power.has_hwp_energy_performance_preference(),
+        }
+    } else if cpuid.get_feature_info().is_some_and(|info| info.has_eist())
+        && unsafe { rdmsr(IA32_MISC_ENABLE) } & MISC_ENABLE_EIST != 0
+    {
+        let info = unsafe { rdmsr(MSR_PLATFORM_INFO) };
+        let (min, max) = ((info >> 40) as u8 as u32, (info >> 8) as u8 as u32);
+        if min == 0 || max < min {
+            return None;
+        }
+        Kind::Speedstep { min, max }
+    } else {
+        return None;
+    };
+    Some(Driver {
+        kind,
+        epb: power.has_energy_bias_pref(),
+    })
+}
+
+impl CpufreqDriver for Driver {
+    fn name(&self) -> &'static str {
+        match self.kind {
+            Kind::Hwp { .. } => "hwp",
+            Kind::Speedstep { .. } => "speedstep",
+        }
+    }
+    fn limits(&self) -> (u32, u32) {
+        match self.kind {
+            Kind::Hwp { .. } => {
+                let caps = unsafe { rdmsr(IA32_HWP_CAPABILITIES) };
+                // The lowest and highest performance.
+                ((caps >> 24) as u8 as u32, caps as u8 as u32)
+            }
+            Kind::Speedstep { min, max } => (min, max),
+        }
+    }
+    fn frequency(&self, level: u32) -> Option<u32> {
+        Some(level * BUS_KHZ)
+    }
+    fn set_bias(&self, bias: EnergyBias) {
+        unsafe {
+            if let Kind::Hwp { epp } = self.kind {
+                // Stays enabled until reset.
+                wrmsr(IA32_PM_ENABLE, 1);
+                if epp {
+                    let preference: u64 = match bias {
+                        EnergyBias::Performance => 0,
+                        EnergyBias::Balanced => 0x80,
+                        EnergyBias::Powersave => 0xff,
+                    };
+                    let request = rdmsr(IA32_HWP_REQUEST) & !(0xff << 24);
+                    wrmsr(IA32_HWP_REQUEST, request | preference << 24);
+                }
+            }
+            if self.epb {
+                let hint: u64 = match bias {
+                    EnergyBias::Performance => 0,
+                    EnergyBias::Balanced => 6,
+                    EnergyBias::Powersave => 15,
+                };
+                let value = rdmsr(IA32_ENERGY_PERF_BIAS) & !0xf;
+                wrmsr(IA32_ENERGY_PERF_BIAS, value | hint);
+            }
+        }
+    }
+    fn set_level(&self, level: u32) {
+        let level = u64::from(level);
+        unsafe {
+            match self.kind {
+                Kind::Hwp { .. } => {
+                    // Allow the whole range, and make the level the desired performance.
+                    let (min, max) = self.limits();
+                    let request = rdmsr(IA32_HWP_REQUEST) & !0xff_ffff;
+                    let fields = u64::from(min) | u64::from(max) << 8 | level << 16;
+                    wrmsr(IA32_HWP_REQUEST, request | fields);
+                }
+                Kind::Speedstep { .. } => {
+                    let ctl = rdmsr(IA32_PERF_CTL) & !0xff00;
+                    wrmsr(IA32_PERF_CTL, ctl | level << 8);
+                }
+            }
+        }
+    }
+}
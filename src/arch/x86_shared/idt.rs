@@ -14,9 +14,14 @@ use x86::{
 
 #[cfg(target_arch = "x86_64")]
 use crate::interrupt::irq::{__generic_interrupts_end, __generic_interrupts_start};
-use crate::{cpu_set::LogicalCpuId, interrupt::*, ipi::IpiKind};
+use crate::{
+    cpu_set::LogicalCpuId, device::local_apic::SPURIOUS_VECTOR, interrupt::*, ipi::IpiKind,
+    irq_stats, sync::RwLock,
+};
 
-use spin::RwLock;
+/// Size of the backup interrupt stack of each CPU, see [`init_generic`].
+#[cfg(target_arch = "x86_64")]
+pub const BACKUP_STACK_SIZE: usize = crate::paging::PAGE_SIZE << 4;
 
 pub static INIT_IDT: SyncUnsafeCell<[IdtEntry; 32]> = SyncUnsafeCell::new([IdtEntry::new(); 32]);
 
@@ -24,6 +29,8 @@ pub type IdtEntries = [IdtEntry; 256];
 pub type IdtReservations = [AtomicU32; 8];
 
 #[repr(C)]
+// Mapped in user page tables with page table isolation, so it must not share pages with anything.
+#[cfg_attr(feature = "pti", repr(align(4096)))]
 pub struct Idt {
     pub(crate) entries: IdtEntries,
     reservations: IdtReservations,
@@ -135,7 +142,9 @@ fn set_exceptions(idt: &mut [IdtEntry]) {
     idt[18].set_func(exception::machine_check);
     idt[19].set_func(exception::simd);
     idt[20].set_func(exception::virtualization);
-    // 21 through 29 reserved
+    #[cfg(target_arch = "x86_64")]
+    idt[21].set_func(exception::control_protection);
+    // 22 through 29 reserved
     idt[30].set_func(exception::security);
     // 31 reserved
 }
@@ -196,9 +205,7 @@ pub unsafe fn init_generic(cpu_id: LogicalCpuId, idt: &mut Idt) {
         // Put them in the 1st entry of the IST.
         #[cfg(target_arch = "x86_64")] // TODO: x86
         {
-            use crate::paging::PAGE_SIZE;
             // Allocate 64 KiB of stack space for the backup stack.
-            const BACKUP_STACK_SIZE: usize = PAGE_SIZE << 4;
             let frames = crate::memory::allocate_p2frame(4)
                 .expect("failed to allocate pages for backup interrupt stack");
 
@@ -258,6 +265,19 @@ pub unsafe fn init_generic(cpu_id: LogicalCpuId, idt: &mut Idt) {
 
         // reserve bits 49:32, which are for the standard IRQs, and for the local apic timer and error.
         *current_reservations[1].get_mut() |= 0x0003_FFFF;
+
+        // Handled by the kernel, while the other legacy IRQs are passed on to userspace.
+        for (vector, name) in [
+            (32, "pit"),
+            (33, "serio keyboard"),
+            (34, "pic cascade"),
+            (35, "serial com2"),
+            (36, "serial com1"),
+            (44, "serio mouse"),
+            (48, "lapic timer"),
+        ] {
+            irq_stats::set_handler(vector - 32, name);
+        }
     } else {
         // TODO: use_default_irqs! but also the legacy IRQs that are only needed on one CPU
         current_idt[49].set_func(irq::lapic_error);
@@ -274,10 +294,28 @@ pub unsafe fn init_generic(cpu_id: LogicalCpuId, idt: &mut Idt) {
     current_idt[IpiKind::Switch as usize].set_func(ipi::switch);
     current_idt[IpiKind::Tlb as usize].set_func(ipi::tlb);
     current_idt[IpiKind::Pit as usize].set_func(ipi::pit);
+    current_idt[IpiKind::Halt as usize].set_func(ipi::halt);
     idt.set_reserved_mut(IpiKind::Wakeup as u8, true);
     idt.set_reserved_mut(IpiKind::Switch as u8, true);
     idt.set_reserved_mut(IpiKind::Tlb as u8, true);
     idt.set_reserved_mut(IpiKind::Pit as u8, true);
+    idt.set_reserved_mut(IpiKind::Halt as u8, true);
+    for (kind, name) in [
+        (IpiKind::Wakeup, "ipi wakeup"),
+        (IpiKind::Switch, "ipi switch"),
+        (IpiKind::Tlb, "ipi tlb shootdown"),
+        (IpiKind::Pit, "ipi tick"),
+        (IpiKind::Halt, "ipi halt"),
+    ] {
+        irq_stats::set_handler(kind as u8 - 32, name);
+    }
+    irq_stats::set_handler(49 - 32, "lapic error");
+    irq_stats::set_handler(SPURIOUS_VECTOR - 32, "lapic spurious");
+
+    // Set the local APIC spurious interrupt handler
+    let current_idt = &mut idt.entries;
+    current_idt[SPURIOUS_VECTOR as usize].set_func(irq::lapic_spurious);
+    idt.set_reserved_mut(SPURIOUS_VECTOR, true);
 
     #[cfg(target_arch = "x86")]
     {
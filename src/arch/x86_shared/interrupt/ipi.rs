@@ -1,22 +1,35 @@
-use crate::{context, device::local_apic::the_local_apic, percpu::PercpuBlock};
+use crate::{
+    context, device::local_apic::the_local_apic, ipi::IpiKind, irq_stats, percpu::PercpuBlock,
+};
 
 interrupt!(wakeup, || {
+    irq_stats::count_vector(IpiKind::Wakeup as u8);
     the_local_apic().eoi();
 });
 
 interrupt!(tlb, || {
+    irq_stats::count_vector(IpiKind::Tlb as u8);
     PercpuBlock::current().maybe_handle_tlb_shootdown();
 
     the_local_apic().eoi();
 });
 
 interrupt!(switch, || {
+    irq_stats::count_vector(IpiKind::Switch as u8);
     the_local_apic().eoi();
 
     let _ = context::switch();
 });
 
+interrupt!(halt, || {
+    irq_stats::count_vector(IpiKind::Halt as u8);
+    the_local_apic().eoi();
+
+    crate::stop::halt_this_cpu();
+});
+
 interrupt!(pit, || {
+    irq_stats::count_vector(IpiKind::Pit as u8);
     the_local_apic().eoi();
 
     // Switch after a sufficient amount of time since the last switch.
@@ -11,6 +11,17 @@ pub unsafe fn disable() {
     core::arch::asm!("cli", options(nomem, nostack));
 }
 
+/// Whether interrupts are enabled
+#[cfg(debug_assertions)]
+#[inline(always)]
+pub fn enabled() -> bool {
+    let flags: usize;
+    unsafe {
+        core::arch::asm!("pushf; pop {}", out(reg) flags, options(nomem, preserves_flags));
+    }
+    flags & (1 << 9) != 0
+}
+
 /// Set interrupts and halt
 /// This will atomically wait for the next interrupt
 /// Performing enable followed by halt is not guaranteed to be atomic, use this instead!
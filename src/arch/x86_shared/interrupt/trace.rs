@@ -1,31 +1,62 @@
-use core::mem;
+//! Register conventions used by the unwinder in [`crate::unwind`].
 
-pub struct StackTrace {
-    pub fp: usize,
-    pub pc_ptr: *const usize,
-}
+use core::arch::asm;
 
-impl StackTrace {
-    #[inline(always)]
-    pub unsafe fn start() -> Option<Self> {
-        let mut fp: usize;
-        #[cfg(target_arch = "x86")]
-        core::arch::asm!("mov {}, ebp", out(reg) fp);
-        #[cfg(target_arch = "x86_64")]
-        core::arch::asm!("mov {}, rbp", out(reg) fp);
-        let pc_ptr = fp.checked_add(mem::size_of::<usize>())?;
-        Some(Self {
-            fp,
-            pc_ptr: pc_ptr as *const usize,
-        })
-    }
+use crate::unwind::FrameRecord;
+
+/// Number of DWARF registers tracked by the unwinder, including the return address column.
+#[cfg(target_arch = "x86")]
+pub const DWARF_REG_COUNT: usize = 9;
+#[cfg(target_arch = "x86")]
+pub const DWARF_SP: u16 = 4;
+#[cfg(target_arch = "x86")]
+pub const DWARF_FP: u16 = 5;
+#[cfg(target_arch = "x86")]
+pub const DWARF_RA: u16 = 8;
 
-    pub unsafe fn next(self) -> Option<Self> {
-        let fp = *(self.fp as *const usize);
-        let pc_ptr = fp.checked_add(mem::size_of::<usize>())?;
-        Some(Self {
-            fp: fp,
-            pc_ptr: pc_ptr as *const usize,
-        })
+#[cfg(target_arch = "x86_64")]
+pub const DWARF_REG_COUNT: usize = 17;
+#[cfg(target_arch = "x86_64")]
+pub const DWARF_SP: u16 = 7;
+#[cfg(target_arch = "x86_64")]
+pub const DWARF_FP: u16 = 6;
+#[cfg(target_arch = "x86_64")]
+pub const DWARF_RA: u16 = 16;
+
+/// The frame pointer points at the saved frame pointer, followed by the return address.
+pub const FRAME_RECORD: FrameRecord = FrameRecord {
+    saved_fp: 0,
+    ra: core::mem::size_of::<usize>() as isize,
+    cfa: 2 * core::mem::size_of::<usize>() as isize,
+};
+
+/// Get the instruction, stack and frame pointers at the point this is inlined into.
+#[inline(always)]
+pub fn current_regs() -> (usize, usize, usize) {
+    let (pc, sp, fp): (usize, usize, usize);
+    #[cfg(target_arch = "x86")]
+    unsafe {
+        asm!(
+            "call 2f",
+            "2: pop {pc}",
+            "mov {sp}, esp",
+            "mov {fp}, ebp",
+            pc = out(reg) pc,
+            sp = out(reg) sp,
+            fp = out(reg) fp,
+        );
+    }
+    #[cfg(target_arch = "x86_64")]
+    unsafe {
+        asm!(
+            "lea {pc}, [rip]",
+            "mov {sp}, rsp",
+            "mov {fp}, rbp",
+            pc = out(reg) pc,
+            sp = out(reg) sp,
+            fp = out(reg) fp,
+            options(nomem, nostack),
+        );
     }
+    (pc, sp, fp)
 }
@@ -8,6 +8,9 @@ pub enum IpiKind {
 
     #[cfg(feature = "profiling")]
     Profile = 0x44,
+
+    /// Stop the CPU for good, before a reset or power-off.
+    Halt = 0x45,
 }
 
 #[derive(Clone, Copy, Debug)]
@@ -42,14 +45,26 @@ use crate::cpu_set::LogicalCpuId;
 #[cfg(feature = "multi_core")]
 #[inline(always)]
 pub fn ipi_single(kind: IpiKind, target: LogicalCpuId) {
-    use crate::device::local_apic::the_local_apic;
+    use crate::device::local_apic::{apic_id, the_local_apic};
+
+    unsafe {
+        the_local_apic().ipi(apic_id(target), kind);
+    }
+}
+
+/// Send an NMI to a single CPU, used for debugging CPUs that are stuck with interrupts disabled.
+#[cfg(all(debug_assertions, feature = "multi_core"))]
+pub fn ipi_nmi(target: LogicalCpuId) {
+    use crate::device::local_apic::{apic_id, the_local_apic};
 
     unsafe {
-        // TODO: Distinguish between logical and physical CPU IDs
-        the_local_apic().ipi(target.get(), kind);
+        the_local_apic().ipi_nmi(apic_id(target));
     }
 }
 
+#[cfg(all(debug_assertions, not(feature = "multi_core")))]
+pub fn ipi_nmi(_target: LogicalCpuId) {}
+
 #[cfg(not(feature = "multi_core"))]
 #[inline(always)]
 pub fn ipi_single(_kind: IpiKind, _target: LogicalCpuId) {}
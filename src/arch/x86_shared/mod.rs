@@ -1,12 +1,18 @@
 /// CPUID wrapper
 pub mod cpuid;
 
+/// Idle states
+pub mod cpuidle;
+
 /// Debugging support
 pub mod debug;
 
 /// Devices
 pub mod device;
 
+/// CPU frequency scaling
+pub mod dvfs;
+
 /// Interrupt descriptor table
 pub mod idt;
 
@@ -17,10 +23,29 @@ pub mod interrupt;
 /// Inter-processor interrupts
 pub mod ipi;
 
-/// Page table isolation
-pub mod pti;
+/// Performance monitoring counters
+pub mod pmu;
+
+/// Hardware random numbers
+pub mod rng;
+
+/// Suspend to RAM
+#[cfg(all(feature = "acpi", target_arch = "x86_64"))]
+pub mod sleep;
+
+/// Suspend to RAM, which is not supported without ACPI or on i686
+#[cfg(not(all(feature = "acpi", target_arch = "x86_64")))]
+#[path = "sleep_unsupported.rs"]
+pub mod sleep;
+
+/// Speculative execution controls
+pub mod speculation;
 
 /// Stop function
 pub mod stop;
 
 pub mod time;
+
+/// Kernel data watchpoints
+#[cfg(feature = "debugger")]
+pub mod watchpoint;
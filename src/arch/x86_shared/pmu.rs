@@ -0,0 +1,188 @@
+//! Architectural performance monitoring, as described by CPUID leaf 0xA, whose general purpose
+//! counters count the events of [`crate::perf`]. Processors without it, such as those of AMD, have
+//! no counters for now.
+
+#[cfg(target_arch = "x86")]
+use core::arch::x86::__cpuid;
+#[cfg(target_arch = "x86_64")]
+use core::arch::x86_64::__cpuid;
+use spin::Once;
+use x86::msr::{rdmsr, wrmsr};
+
+use crate::perf::{PerfCounts, PerfEvent, EVENT_COUNT};
+
+const IA32_PMC0: u32 = 0xc1;
+const IA32_PERFEVTSEL0: u32 = 0x186;
+const IA32_PERF_GLOBAL_CTRL: u32 = 0x38f;
+#[cfg(all(feature = "profiling", target_arch = "x86_64"))]
+const IA32_PERF_GLOBAL_STATUS: u32 = 0x38e;
+#[cfg(all(feature = "profiling", target_arch = "x86_64"))]
+const IA32_PERF_GLOBAL_OVF_CTRL: u32 = 0x390;
+
+/// Count in user mode.
+const EVTSEL_USR: u64 = 1 << 16;
+/// Count in kernel mode.
+const EVTSEL_OS: u64 = 1 << 17;
+/// Raise an interrupt through the LVT performance counter entry on overflow.
+#[cfg(all(feature = "profiling", target_arch = "x86_64"))]
+const EVTSEL_INT: u64 = 1 << 20;
+const EVTSEL_EN: u64 = 1 << 22;
+
+/// Counter raising the NMIs of the profiler, after those of the counted events.
+#[cfg(all(feature = "profiling", target_arch = "x86_64"))]
+const SAMPLE_COUNTER: u32 = EVENT_COUNT as u32;
+
+/// LVT entry delivering the overflow interrupts as NMIs, unmasked.
+#[cfg(all(feature = "profiling", target_arch = "x86_64"))]
+const LVT_NMI: u32 = 0b100 << 8;
+
+struct Caps {
+    version: u8,
+    counters: u8,
+    /// Number of valid bits in `unavailable`.
+    events_len: u8,
+    /// Architectural events that are not available, by bit.
+    unavailable: u32,
+}
+
+static CAPS: Once<Caps> = Once::new();
+
+fn caps() -> &'static Caps {
+    CAPS.call_once(|| {
+        let max_leaf = unsafe { __cpuid(0) }.eax;
+        if max_leaf < 0xa {
+            return Caps {
+                version: 0,
+                counters: 0,
+                events_len: 0,
+                unavailable: 0,
+            };
+        }
+        let res = unsafe { __cpuid(0xa) };
+        Caps {
+            version: res.eax as u8,
+            counters: (res.eax >> 8) as u8,
+            events_len: (res.eax >> 24) as u8,
+            unavailable: res.ebx,
+        }
+    })
+}
+
+/// The event select and unit mask of the architectural event, and its bit in CPUID.0AH:EBX.
+fn architectural_event(event: PerfEvent) -> (u64, u8) {
+    match event {
+        // UnHalted Core Cycles
+        PerfEvent::Cycles => (0x003c, 0),
+        // Instructions Retired
+        PerfEvent::Instructions => (0x00c0, 1),
+        // LLC Misses
+        PerfEvent::CacheMisses => (0x412e, 4),
+    }
+}
+
+fn event_available(caps: &Caps, event: PerfEvent) -> bool {
+    let (_, bit) = architectural_event(event);
+    bit < caps.events_len && caps.unavailable & (1 << bit) == 0
+}
+
+/// Whether `event` can be counted. Every event has its own counter, numbered like the event.
+pub fn supported(event: PerfEvent) -> bool {
+    let caps = caps();
+    // The global control register only exists since version 2.
+    caps.version >= 2 && (event as u8) < caps.counters && event_available(caps, event)
+}
+
+fn supported_mask() -> u64 {
+    PerfEvent::ALL
+        .into_iter()
+        .filter(|&event| supported(event))
+        .fold(0, |mask, event| mask | 1 << event as u32)
+}
+
+/// Start counting the supported events from zero.
+pub unsafe fn start() {
+    for event in PerfEvent::ALL.into_iter().filter(|&event| supported(event)) {
+        let (select, _) = architectural_event(event);
+        wrmsr(IA32_PMC0 + event as u32, 0);
+        wrmsr(
+            IA32_PERFEVTSEL0 + event as u32,
+            select | EVTSEL_USR | EVTSEL_OS | EVTSEL_EN,
+        );
+    }
+    wrmsr(
+        IA32_PERF_GLOBAL_CTRL,
+        rdmsr(IA32_PERF_GLOBAL_CTRL) | supported_mask(),
+    );
+}
+
+pub unsafe fn stop() {
+    wrmsr(
+        IA32_PERF_GLOBAL_CTRL,
+        rdmsr(IA32_PERF_GLOBAL_CTRL) & !supported_mask(),
+    );
+    for event in PerfEvent::ALL.into_iter().filter(|&event| supported(event)) {
+        wrmsr(IA32_PERFEVTSEL0 + event as u32, 0);
+    }
+}
+
+/// Add what the counters counted since the last call, or [`start`], to `counts`, and reset them.
+pub unsafe fn read_and_reset(counts: &mut PerfCounts) {
+    for event in PerfEvent::ALL.into_iter().filter(|&event| supported(event)) {
+        counts[event as usize] += rdmsr(IA32_PMC0 + event as u32);
+        wrmsr(IA32_PMC0 + event as u32, 0);
+    }
+}
+
+/// Whether a counter can raise an NMI every given number of cycles.
+#[cfg(all(feature = "profiling", target_arch = "x86_64"))]
+pub fn can_sample() -> bool {
+    let caps = caps();
+    caps.version >= 2
+        && caps.counters as u32 > SAMPLE_COUNTER
+        && event_available(caps, PerfEvent::Cycles)
+}
+
+/// Load the sampling counter so that it overflows after `period` cycles. The counter is
+/// sign-extended from bit 31 when written, so periods must be less than `1 << 31`.
+#[cfg(all(feature = "profiling", target_arch = "x86_64"))]
+unsafe fn load_sample_counter(period: u32) {
+    wrmsr(IA32_PMC0 + SAMPLE_COUNTER, u64::from(period.wrapping_neg()));
+}
+
+/// Raise an NMI every `period` cycles.
+#[cfg(all(feature = "profiling", target_arch = "x86_64"))]
+pub unsafe fn start_sampling(period: u32) {
+    let (select, _) = architectural_event(PerfEvent::Cycles);
+    load_sample_counter(period);
+    wrmsr(
+        IA32_PERFEVTSEL0 + SAMPLE_COUNTER,
+        select | EVTSEL_USR | EVTSEL_OS | EVTSEL_INT | EVTSEL_EN,
+    );
+    crate::device::local_apic::the_local_apic().set_lvt_perf(LVT_NMI);
+    wrmsr(
+        IA32_PERF_GLOBAL_CTRL,
+        rdmsr(IA32_PERF_GLOBAL_CTRL) | 1 << SAMPLE_COUNTER,
+    );
+}
+
+#[cfg(all(feature = "profiling", target_arch = "x86_64"))]
+pub unsafe fn stop_sampling() {
+    wrmsr(
+        IA32_PERF_GLOBAL_CTRL,
+        rdmsr(IA32_PERF_GLOBAL_CTRL) & !(1 << SAMPLE_COUNTER),
+    );
+    wrmsr(IA32_PERFEVTSEL0 + SAMPLE_COUNTER, 0);
+    wrmsr(IA32_PERF_GLOBAL_OVF_CTRL, 1 << SAMPLE_COUNTER);
+}
+
+/// If the sampling counter overflowed, reload it with `period` and unmask the LVT entry, which
+/// the processor masks when delivering the NMI.
+#[cfg(all(feature = "profiling", target_arch = "x86_64"))]
+pub unsafe fn handle_sample_overflow(period: u32) {
+    if rdmsr(IA32_PERF_GLOBAL_STATUS) & (1 << SAMPLE_COUNTER) == 0 {
+        return;
+    }
+    load_sample_counter(period);
+    wrmsr(IA32_PERF_GLOBAL_OVF_CTRL, 1 << SAMPLE_COUNTER);
+    crate::device::local_apic::the_local_apic().set_lvt_perf(LVT_NMI);
+}
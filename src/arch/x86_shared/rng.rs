@@ -0,0 +1,52 @@
+//! RDRAND and the TSC, for the entropy pool.
+
+#[cfg(target_arch = "x86")]
+use core::arch::x86::{_rdrand32_step, _rdtsc};
+#[cfg(target_arch = "x86_64")]
+use core::arch::x86_64::{_rdrand64_step, _rdtsc};
+
+use spin::Once;
+
+use crate::arch::cpuid::cpuid;
+
+static HAS_RDRAND: Once<bool> = Once::new();
+
+/// A random number from RDRAND, if the CPU has it.
+pub fn hardware_random() -> Option<u64> {
+    let has_rdrand = *HAS_RDRAND.call_once(|| {
+        cpuid()
+            .get_feature_info()
+            .map_or(false, |info| info.has_rdrand())
+    });
+    if !has_rdrand {
+        return None;
+    }
+    unsafe { rdrand() }
+}
+
+#[target_feature(enable = "rdrand")]
+unsafe fn rdrand() -> Option<u64> {
+    // RDRAND may fail transiently when the entropy source is drained.
+    for _ in 0..10 {
+        #[cfg(target_arch = "x86_64")]
+        {
+            let mut value = 0;
+            if _rdrand64_step(&mut value) == 1 {
+                return Some(value);
+            }
+        }
+        #[cfg(target_arch = "x86")]
+        {
+            let (mut low, mut high) = (0, 0);
+            if _rdrand32_step(&mut low) == 1 && _rdrand32_step(&mut high) == 1 {
+                return Some(u64::from(high) << 32 | u64::from(low));
+            }
+        }
+    }
+    None
+}
+
+/// The TSC, whose low bits vary with caches, pipelines and interrupts.
+pub fn cycles() -> u64 {
+    unsafe { _rdtsc() }
+}
@@ -0,0 +1,330 @@
+//! Suspend to RAM through the ACPI S3 state.
+//!
+//! Each CPU saves the registers it loses while suspended on its stack, and calls
+//! [`save_and_sleep`]. The BSP points the FACS waking vector at the AP trampoline before entering
+//! S3, and the firmware resumes it there in real mode. The trampoline enters long mode with the
+//! kernel page table and jumps to [`resume_entry`], which restores the saved state and returns from
+//! [`save_and_sleep`] a second time. The APs halt rather than enter S3 themselves, and once the
+//! BSP resumed, are started through the trampoline the same way.
+//!
+//! Without an AML interpreter, the `_PTS` and `_WAK` methods are not run, which some firmware
+//! needs to resume its devices.
+
+use core::{
+    arch::asm,
+    ptr::addr_of_mut,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+};
+
+use x86::{
+    controlregs::{self, Cr0, Cr4, Xcr0},
+    dtables::{self, DescriptorTablePointer},
+    msr,
+};
+
+use crate::{
+    acpi::{
+        fadt::{Facs, FADT},
+        madt::arch::{
+            install_trampoline, remove_trampoline, set_trampoline_args, start_trampoline,
+            TRAMPOLINE,
+        },
+    },
+    context,
+    cpu_set::{LogicalCpuId, MAX_CPU_COUNT},
+    cpuid::cpuid,
+    device::{self, local_apic},
+    gdt::{self, ProcessorControlRegion},
+    interrupt,
+    percpu::PercpuBlock,
+    stop::acpi_enter_sleep_state,
+    syscall::{
+        error::{Error, Result, EIO, EOPNOTSUPP},
+        io::{Io, Pio},
+    },
+};
+
+/// Set in the PM1 status registers on waking up, cleared by writing it.
+const WAK_STS: u16 = 1 << 15;
+
+/// Registers lost while the system is suspended.
+#[repr(C)]
+struct CpuState {
+    /// Stack pointer after pushing the callee-saved registers, saved by [`save_and_sleep`].
+    rsp: usize,
+    cr0: usize,
+    cr3: u64,
+    cr4: usize,
+    xcr0: u64,
+    idt_limit: u16,
+    idt_base: u64,
+    efer: u64,
+    pat: u64,
+    star: u64,
+    lstar: u64,
+    fmask: u64,
+    fs_base: u64,
+    /// Points to the PCR, whose GDT is loaded again from there.
+    gs_base: u64,
+    kernel_gs_base: u64,
+    tsc_aux: Option<u64>,
+    /// U_CET and PL3_SSP, where CET is supported.
+    #[cfg(target_arch = "x86_64")]
+    cet: Option<[u64; 2]>,
+}
+
+impl CpuState {
+    unsafe fn current() -> Self {
+        let cr4 = controlregs::cr4();
+        let mut idtr = DescriptorTablePointer {
+            limit: 0,
+            base: core::ptr::null::<u64>(),
+        };
+        dtables::sidt(&mut idtr);
+        let has_rdtscp = cpuid()
+            .get_extended_processor_and_feature_identifiers()
+            .map_or(false, |feats| feats.has_rdtscp());
+
+        Self {
+            rsp: 0,
+            cr0: controlregs::cr0().bits(),
+            cr3: controlregs::cr3(),
+            cr4: cr4.bits(),
+            xcr0: if cr4.contains(Cr4::CR4_ENABLE_OS_XSAVE) {
+                controlregs::xcr0().bits()
+            } else {
+                0
+            },
+            idt_limit: idtr.limit,
+            idt_base: idtr.base as u64,
+            efer: msr::rdmsr(msr::IA32_EFER),
+            pat: msr::rdmsr(msr::IA32_PAT),
+            star: msr::rdmsr(msr::IA32_STAR),
+            lstar: msr::rdmsr(msr::IA32_LSTAR),
+            fmask: msr::rdmsr(msr::IA32_FMASK),
+            fs_base: msr::rdmsr(msr::IA32_FS_BASE),
+            gs_base: msr::rdmsr(msr::IA32_GS_BASE),
+            kernel_gs_base: msr::rdmsr(msr::IA32_KERNEL_GSBASE),
+            tsc_aux: has_rdtscp.then(|| msr::rdmsr(msr::IA32_TSC_AUX)),
+            #[cfg(target_arch = "x86_64")]
+            cet: crate::cet::save_msrs(),
+        }
+    }
+}
+
+/// Stack [`resume_entry`] runs on until it switches back to the saved one.
+#[repr(C, align(4096))]
+struct ResumeStack([u8; 4096]);
+
+static mut RESUME_STACK: ResumeStack = ResumeStack([0; 4096]);
+
+/// The state saved by each halted AP, to start it again with.
+static STATES: [AtomicUsize; MAX_CPU_COUNT as usize] = {
+    const NONE: AtomicUsize = AtomicUsize::new(0);
+    [NONE; MAX_CPU_COUNT as usize]
+};
+
+/// Set by an AP once it restored its state.
+static RESTARTED: AtomicBool = AtomicBool::new(false);
+
+/// Whether the firmware describes how to enter S3 and where to resume from it.
+pub fn supported() -> bool {
+    FADT.get().map_or(false, |fadt| {
+        fadt.s3_sleep_types.is_some() && fadt.facs.is_some()
+    })
+}
+
+/// Push the callee-saved registers, save the stack pointer in `state`, and call `sleep` with
+/// `state` and `arg`. Returns false if `sleep` returned, and true when [`resume_entry`] restored
+/// `state` after the CPU was suspended.
+#[naked]
+unsafe extern "C" fn save_and_sleep(
+    _state: *mut CpuState,
+    _sleep: unsafe extern "C" fn(*mut CpuState, usize),
+    _arg: usize,
+) -> bool {
+    core::arch::asm!(
+        "
+        push rbp
+        push rbx
+        push r12
+        push r13
+        push r14
+        push r15
+        mov [rdi], rsp
+
+        mov rax, rsi
+        mov rsi, rdx
+        sub rsp, 8
+        call rax
+        add rsp, 8
+
+        xor eax, eax
+        pop r15
+        pop r14
+        pop r13
+        pop r12
+        pop rbx
+        pop rbp
+        ret
+        ",
+        options(noreturn)
+    );
+}
+
+/// Jumped to by the trampoline in long mode, with the kernel page table and a temporary stack, and
+/// the address of its copy of the state pointer in RDI.
+#[naked]
+unsafe extern "C" fn resume_entry() {
+    core::arch::asm!(
+        "
+        mov rdi, [rdi]
+        call {restore}
+        mov rsp, [rax]
+
+        mov eax, 1
+        pop r15
+        pop r14
+        pop r13
+        pop r12
+        pop rbx
+        pop rbp
+        ret
+        ",
+        restore = sym restore,
+        options(noreturn)
+    );
+}
+
+unsafe extern "C" fn restore(state: &CpuState) -> &CpuState {
+    // The trampoline loaded a CR3 without PCID, so that CR4.PCIDE can be set before it.
+    msr::wrmsr(msr::IA32_EFER, state.efer);
+    let cr4 = Cr4::from_bits_truncate(state.cr4);
+    controlregs::cr4_write(cr4);
+    if cr4.contains(Cr4::CR4_ENABLE_OS_XSAVE) {
+        controlregs::xcr0_write(Xcr0::from_bits_truncate(state.xcr0));
+    }
+    controlregs::cr0_write(Cr0::from_bits_truncate(state.cr0));
+    controlregs::cr3_write(state.cr3);
+
+    gdt::reload(state.gs_base as *mut ProcessorControlRegion);
+
+    msr::wrmsr(msr::IA32_PAT, state.pat);
+    msr::wrmsr(msr::IA32_STAR, state.star);
+    msr::wrmsr(msr::IA32_LSTAR, state.lstar);
+    msr::wrmsr(msr::IA32_FMASK, state.fmask);
+    msr::wrmsr(msr::IA32_FS_BASE, state.fs_base);
+    msr::wrmsr(msr::IA32_GS_BASE, state.gs_base);
+    msr::wrmsr(msr::IA32_KERNEL_GSBASE, state.kernel_gs_base);
+    if let Some(tsc_aux) = state.tsc_aux {
+        msr::wrmsr(msr::IA32_TSC_AUX, tsc_aux);
+    }
+    // Sets CR4.CET again, which the saved CR4 lacks as it only keeps the bits known to the x86
+    // crate.
+    #[cfg(target_arch = "x86_64")]
+    crate::cet::restore_msrs(state.cet);
+
+    dtables::lidt(&DescriptorTablePointer {
+        limit: state.idt_limit,
+        base: state.idt_base as *const u64,
+    });
+
+    state
+}
+
+/// Make the trampoline resume `state` on [`RESUME_STACK`].
+unsafe fn set_resume_args(state: *mut CpuState, page_table_physaddr: usize) {
+    let stack_start = addr_of_mut!(RESUME_STACK) as usize;
+    set_trampoline_args(
+        state as u64,
+        page_table_physaddr,
+        stack_start,
+        stack_start + core::mem::size_of::<ResumeStack>(),
+        resume_entry as usize,
+    );
+}
+
+unsafe extern "C" fn sleep_bsp(state: *mut CpuState, page_table_physaddr: usize) {
+    let Some(fadt) = FADT.get() else {
+        return;
+    };
+    let Some(sleep_types) = fadt.s3_sleep_types else {
+        return;
+    };
+    set_resume_args(state, page_table_physaddr);
+
+    // Caches are not preserved in S3.
+    asm!("wbinvd");
+    acpi_enter_sleep_state(fadt, sleep_types);
+}
+
+/// Enter S3 on the BSP, once the APs halted in [`suspend_ap`], returning after waking up.
+pub unsafe fn enter() -> Result<()> {
+    let fadt = FADT.get().ok_or(Error::new(EOPNOTSUPP))?;
+    let facs = fadt.facs.ok_or(Error::new(EOPNOTSUPP))? as *mut Facs;
+
+    // The FPU state of the suspending context is lost with the other registers.
+    let context_lock = context::current();
+    context::save_fpu(&mut context_lock.write());
+    device::suspend();
+
+    let page_table_physaddr = install_trampoline();
+    addr_of_mut!((*facs).firmware_waking_vector).write_unaligned(TRAMPOLINE as u32);
+    addr_of_mut!((*facs).x_firmware_waking_vector).write_unaligned(0);
+    for port in [fadt.pm1a_event, fadt.pm1b_event] {
+        if port != 0 {
+            Pio::<u16>::new(port).write(WAK_STS);
+        }
+    }
+
+    let mut state = CpuState::current();
+    let resumed = save_and_sleep(&mut state, sleep_bsp, page_table_physaddr);
+
+    remove_trampoline();
+    device::resume();
+    context::load_fpu(&context_lock.read());
+
+    if resumed {
+        Ok(())
+    } else {
+        log::warn!("Failed to enter ACPI S3");
+        Err(Error::new(EIO))
+    }
+}
+
+unsafe extern "C" fn halt_ap(state: *mut CpuState, _arg: usize) {
+    let cpu = PercpuBlock::current().cpu_id;
+    STATES[cpu.get() as usize].store(state as usize, Ordering::Release);
+    asm!("wbinvd");
+    crate::suspend::ap_down();
+    loop {
+        asm!("cli", "hlt");
+    }
+}
+
+/// Save the state of this AP and halt it until [`restart_ap`] starts it again, after waking up.
+pub unsafe fn suspend_ap() {
+    let mut state = CpuState::current();
+    if save_and_sleep(&mut state, halt_ap, 0) {
+        local_apic::init_ap();
+        RESTARTED.store(true, Ordering::Release);
+    }
+}
+
+/// Start `cpu`, halted in [`suspend_ap`], through the trampoline, and wait until it restored its
+/// state.
+pub unsafe fn restart_ap(cpu: LogicalCpuId) {
+    let state = STATES[cpu.get() as usize].swap(0, Ordering::Acquire);
+    if state == 0 {
+        return;
+    }
+    RESTARTED.store(false, Ordering::Relaxed);
+
+    let page_table_physaddr = install_trampoline();
+    set_resume_args(state as *mut CpuState, page_table_physaddr);
+    start_trampoline(local_apic::the_local_apic(), local_apic::apic_id(cpu));
+    while !RESTARTED.load(Ordering::Acquire) {
+        interrupt::pause();
+    }
+    remove_trampoline();
+}
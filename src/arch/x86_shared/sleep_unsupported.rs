@@ -0,0 +1,18 @@
+//! Suspend to RAM needs ACPI S3, which is only supported on x86_64.
+
+use crate::{
+    cpu_set::LogicalCpuId,
+    syscall::error::{Error, Result, EOPNOTSUPP},
+};
+
+pub fn supported() -> bool {
+    false
+}
+
+pub unsafe fn enter() -> Result<()> {
+    Err(Error::new(EOPNOTSUPP))
+}
+
+pub unsafe fn suspend_ap() {}
+
+pub unsafe fn restart_ap(_cpu: LogicalCpuId) {}
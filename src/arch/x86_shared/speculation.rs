@@ -0,0 +1,153 @@
+//! Speculative execution vulnerabilities and controls of x86 processors, as enumerated by CPUID
+//! leaf 7, leaf 0x80000008 on AMD, and IA32_ARCH_CAPABILITIES where present. IBRS and SSBD are bits
+//! of IA32_SPEC_CTRL, which are left set in the kernel and userspace alike, and IBPB is a command
+//! written to IA32_PRED_CMD. Legacy IBRS slows userspace down as well, so it is only enabled by
+//! default where the processor has enhanced IBRS, which is meant to be left set.
+
+#[cfg(target_arch = "x86")]
+use core::arch::x86::{__cpuid, __cpuid_count};
+#[cfg(target_arch = "x86_64")]
+use core::arch::x86_64::{__cpuid, __cpuid_count};
+use spin::Once;
+use x86::msr::{rdmsr, wrmsr};
+
+use crate::{
+    cpuid::cpuid,
+    mitigations::{self, Mitigation, Status, Vulnerability},
+};
+
+const IA32_SPEC_CTRL: u32 = 0x48;
+const IA32_PRED_CMD: u32 = 0x49;
+const IA32_ARCH_CAPABILITIES: u32 = 0x10a;
+
+const SPEC_CTRL_IBRS: u64 = 1;
+const SPEC_CTRL_SSBD: u64 = 1 << 2;
+const PRED_CMD_IBPB: u64 = 1;
+
+/// Not affected by Meltdown.
+const ARCH_CAP_RDCL_NO: u64 = 1;
+/// Enhanced IBRS.
+const ARCH_CAP_IBRS_ALL: u64 = 1 << 1;
+/// Not affected by speculative store bypass.
+const ARCH_CAP_SSB_NO: u64 = 1 << 4;
+
+/// CPUID.(EAX=07H,ECX=0):EDX, IBRS and IBPB.
+const CPUID_SPEC_CTRL: u32 = 1 << 26;
+const CPUID_ARCH_CAPABILITIES: u32 = 1 << 29;
+const CPUID_SSBD: u32 = 1 << 31;
+
+/// CPUID.80000008H:EBX on AMD.
+const AMD_IBPB: u32 = 1 << 12;
+const AMD_IBRS: u32 = 1 << 14;
+const AMD_SSBD: u32 = 1 << 24;
+const AMD_SSB_NO: u32 = 1 << 26;
+
+struct Caps {
+    ibrs: bool,
+    enhanced_ibrs: bool,
+    ibpb: bool,
+    ssbd: bool,
+    meltdown: bool,
+    spec_store_bypass: bool,
+}
+
+static CAPS: Once<Caps> = Once::new();
+
+fn caps() -> &'static Caps {
+    CAPS.call_once(|| {
+        let amd = cpuid()
+            .get_vendor_info()
+            .is_some_and(|vendor| matches!(vendor.as_str(), "AuthenticAMD" | "HygonGenuine"));
+        let leaf7 = if unsafe { __cpuid(0) }.eax >= 7 {
+            unsafe { __cpuid_count(7, 0) }.edx
+        } else {
+            0
+        };
+        let amd_ebx = if amd && unsafe { __cpuid(0x8000_0000) }.eax >= 0x8000_0008 {
+            unsafe { __cpuid(0x8000_0008) }.ebx
+        } else {
+            0
+        };
+        let arch_caps = if leaf7 & CPUID_ARCH_CAPABILITIES != 0 {
+            unsafe { rdmsr(IA32_ARCH_CAPABILITIES) }
+        } else {
+            0
+        };
+
+        Caps {
+            ibrs: leaf7 & CPUID_SPEC_CTRL != 0 || amd_ebx & AMD_IBRS != 0,
+            enhanced_ibrs: arch_caps & ARCH_CAP_IBRS_ALL != 0,
+            ibpb: leaf7 & CPUID_SPEC_CTRL != 0 || amd_ebx & AMD_IBPB != 0,
+            ssbd: leaf7 & CPUID_SSBD != 0 || amd_ebx & AMD_SSBD != 0,
+            meltdown: !amd && arch_caps & ARCH_CAP_RDCL_NO == 0,
+            spec_store_bypass: arch_caps & ARCH_CAP_SSB_NO == 0 && amd_ebx & AMD_SSB_NO == 0,
+        }
+    })
+}
+
+pub fn supported(mitigation: Mitigation) -> bool {
+    let caps = caps();
+    match mitigation {
+        Mitigation::Ibrs => caps.ibrs,
+        Mitigation::Ibpb => caps.ibpb,
+        Mitigation::Ssbd => caps.ssbd,
+    }
+}
+
+/// Whether `mitigation` is enabled by default, where supported.
+pub fn recommended(mitigation: Mitigation) -> bool {
+    let caps = caps();
+    match mitigation {
+        Mitigation::Ibrs => caps.enhanced_ibrs,
+        Mitigation::Ibpb => true,
+        // Slows down every load following a store, and only matters for processes running
+        // untrusted code in the same address space, such as JITs.
+        Mitigation::Ssbd => false,
+    }
+}
+
+pub fn status(vulnerability: Vulnerability) -> Status {
+    let caps = caps();
+    match vulnerability {
+        Vulnerability::Meltdown if !caps.meltdown => Status::NotAffected,
+        Vulnerability::Meltdown if cfg!(all(feature = "pti", target_arch = "x86_64")) => {
+            Status::Mitigated("pti")
+        }
+        Vulnerability::SpectreV2 => match (
+            mitigations::enabled(Mitigation::Ibrs),
+            mitigations::enabled(Mitigation::Ibpb),
+        ) {
+            (true, true) => Status::Mitigated("ibrs, ibpb"),
+            (true, false) => Status::Mitigated("ibrs"),
+            (false, true) => Status::Mitigated("ibpb, between processes only"),
+            (false, false) => Status::Vulnerable,
+        },
+        Vulnerability::SpecStoreBypass if !caps.spec_store_bypass => Status::NotAffected,
+        Vulnerability::SpecStoreBypass if mitigations::enabled(Mitigation::Ssbd) => {
+            Status::Mitigated("ssbd")
+        }
+        _ => Status::Vulnerable,
+    }
+}
+
+/// Set IA32_SPEC_CTRL on this CPU according to the enabled mitigations.
+pub unsafe fn apply() {
+    let caps = caps();
+    if !caps.ibrs && !caps.ssbd {
+        return;
+    }
+    let mut spec_ctrl = rdmsr(IA32_SPEC_CTRL) & !(SPEC_CTRL_IBRS | SPEC_CTRL_SSBD);
+    if caps.ibrs && mitigations::enabled(Mitigation::Ibrs) {
+        spec_ctrl |= SPEC_CTRL_IBRS;
+    }
+    if caps.ssbd && mitigations::enabled(Mitigation::Ssbd) {
+        spec_ctrl |= SPEC_CTRL_SSBD;
+    }
+    wrmsr(IA32_SPEC_CTRL, spec_ctrl);
+}
+
+pub unsafe fn flush_branch_predictors() {
+    if caps().ibpb {
+        wrmsr(IA32_PRED_CMD, PRED_CMD_IBPB);
+    }
+}
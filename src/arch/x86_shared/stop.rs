@@ -1,19 +1,97 @@
+use core::sync::atomic::{AtomicU32, Ordering};
+
 #[cfg(feature = "acpi")]
-use crate::{context, scheme::acpi, time};
+use crate::{
+    acpi::fadt::{Fadt, FADT},
+    context,
+    scheme::acpi,
+    time,
+};
+
+use crate::{
+    cpu_set::LogicalCpuId,
+    syscall::io::{Io, Pio},
+};
+
+/// Number of CPUs stopped by [`halt_other_cpus`].
+static HALTED_CPUS: AtomicU32 = AtomicU32::new(0);
+
+/// Give a reset or power-off method some time to take effect, before trying the next one. Timers
+/// cannot be relied on, as interrupts are disabled.
+fn settle() {
+    for _ in 0..10_000_000 {
+        crate::interrupt::pause();
+    }
+}
+
+/// Stop all other CPUs, so that they cannot run anything while the hardware is being reset.
+pub unsafe fn halt_other_cpus() {
+    #[cfg(feature = "multi_core")]
+    {
+        use crate::ipi::{ipi, IpiKind, IpiTarget};
+
+        ipi(IpiKind::Halt, IpiTarget::Other);
+
+        let others = crate::cpu_count().saturating_sub(1);
+        for _ in 0..10_000_000 {
+            if HALTED_CPUS.load(Ordering::Acquire) >= others {
+                return;
+            }
+            crate::interrupt::pause();
+        }
+        log::warn!(
+            "{} of {} other CPUs halted",
+            HALTED_CPUS.load(Ordering::Acquire),
+            others
+        );
+    }
+}
 
-use crate::syscall::io::{Io, Pio};
+/// Called from the halt IPI.
+pub fn halt_this_cpu() -> ! {
+    HALTED_CPUS.fetch_add(1, Ordering::Release);
+    loop {
+        unsafe {
+            crate::interrupt::disable();
+            crate::interrupt::halt();
+        }
+    }
+}
+
+/// Wait for [`unpark`] on an offline CPU, or any other interrupt.
+pub unsafe fn park_wait() {
+    crate::interrupt::enable_and_halt();
+    crate::interrupt::disable();
+}
+
+/// Wake up `cpu` from [`park_wait`].
+pub fn unpark(cpu: LogicalCpuId) {
+    crate::ipi::ipi_single(crate::ipi::IpiKind::Wakeup, cpu);
+}
 
 pub unsafe fn kreset() -> ! {
     log::info!("kreset");
 
+    halt_other_cpus();
+    crate::interrupt::disable();
+
+    #[cfg(feature = "acpi")]
+    if let Some((port, value)) = FADT.get().and_then(|fadt| fadt.reset) {
+        println!("Reset with ACPI reset register {:#X}", port);
+        Pio::<u8>::new(port).write(value);
+        settle();
+    }
+
     // 8042 reset
     {
         println!("Reset with 8042");
         let mut port = Pio::<u8>::new(0x64);
         while port.readf(2) {}
         port.write(0xFE);
+        settle();
     }
 
+    println!("Reset with triple fault");
     emergency_reset();
 }
 
@@ -60,12 +138,66 @@ fn userspace_acpi_shutdown() {
     }
 }
 
+/// Enter the sleep state with the given SLP_TYPa and SLP_TYPb values, switching to ACPI mode
+/// first if the firmware did not.
+#[cfg(feature = "acpi")]
+pub unsafe fn acpi_enter_sleep_state(fadt: &Fadt, (slp_typ_a, slp_typ_b): (u8, u8)) {
+    const SCI_EN: u16 = 1 << 0;
+    const SLP_TYP_MASK: u16 = 0b111 << 10;
+    const SLP_EN: u16 = 1 << 13;
+
+    let mut pm1a = Pio::<u16>::new(fadt.pm1a_control);
+    if pm1a.read() & SCI_EN == 0 && fadt.smi_command_port != 0 {
+        Pio::<u8>::new(fadt.smi_command_port).write(fadt.acpi_enable);
+        for _ in 0..10_000_000 {
+            if pm1a.read() & SCI_EN != 0 {
+                break;
+            }
+            crate::interrupt::pause();
+        }
+    }
+
+    if fadt.pm1b_control != 0 {
+        let mut pm1b = Pio::<u16>::new(fadt.pm1b_control);
+        let value = pm1b.read() & !SLP_TYP_MASK;
+        pm1b.write(value | (u16::from(slp_typ_b) << 10) | SLP_EN);
+    }
+    let value = pm1a.read() & !SLP_TYP_MASK;
+    pm1a.write(value | (u16::from(slp_typ_a) << 10) | SLP_EN);
+    settle();
+}
+
+/// Enter the S5 state using the FADT and the sleep types from the DSDT, for when no userspace ACPI
+/// driver did.
+#[cfg(feature = "acpi")]
+unsafe fn kernel_acpi_shutdown() {
+    let Some(fadt) = FADT.get() else {
+        return;
+    };
+    let Some((slp_typ_a, slp_typ_b)) = fadt.s5_sleep_types else {
+        log::warn!("No \\_S5_ object found, cannot power off using ACPI");
+        return;
+    };
+
+    println!(
+        "Shutdown with ACPI SLP_TYPa {:#X}, SLP_TYPb {:#X}",
+        slp_typ_a, slp_typ_b
+    );
+    acpi_enter_sleep_state(fadt, (slp_typ_a, slp_typ_b));
+}
+
 pub unsafe fn kstop() -> ! {
     log::info!("Running kstop()");
 
     #[cfg(feature = "acpi")]
     userspace_acpi_shutdown();
 
+    halt_other_cpus();
+    crate::interrupt::disable();
+
+    #[cfg(feature = "acpi")]
+    kernel_acpi_shutdown();
+
     // Magic shutdown code for bochs and qemu (older versions).
     for c in "Shutdown".bytes() {
         let port = 0x8900;
@@ -10,8 +10,45 @@ pub fn monotonic_absolute() -> u128 {
         return ns;
     }
 
+    if let Some(ns) = super::device::tsc_deadline::monotonic() {
+        return ns;
+    }
+
     *crate::time::OFFSET.lock() + hpet_or_pit()
 }
+/// The clock parameters for the vDSO, if userspace can compute the monotonic clock by itself.
+pub fn vdso_clock() -> Option<crate::vdso::VdsoClock> {
+    // The pvclock pages are per CPU, and not mapped into userspace.
+    #[cfg(feature = "x86_kvm_pv")]
+    if super::device::tsc::in_use() {
+        return None;
+    }
+
+    super::device::tsc_deadline::vdso_clock()
+}
+/// Arm the one-shot timer of this CPU, once the TSC-deadline timer is in use.
+pub unsafe fn arm_oneshot(deadline: u128) {
+    super::device::tsc_deadline::arm(deadline);
+}
+/// Stop the one-shot timer of this CPU, as it goes offline.
+pub unsafe fn disarm_oneshot() {
+    super::device::tsc_deadline::disarm();
+}
+/// Wall clock time from the RTC, which keeps running while suspended, in nanoseconds since the
+/// Unix epoch.
+pub fn persistent_clock() -> Option<u128> {
+    Some(u128::from(super::device::rtc::Rtc::new().time()) * crate::time::NANOS_PER_SEC)
+}
+pub unsafe fn resume() {
+    super::device::resume_timer();
+}
+pub unsafe fn resume_ap() {
+    #[cfg(feature = "x86_kvm_pv")]
+    super::device::tsc::resume();
+
+    super::device::init_ap_timer();
+}
+
 fn hpet_or_pit() -> u128 {
     #[cfg(feature = "acpi")]
     if let Some(ref hpet) = *crate::acpi::ACPI_TABLE.hpet.read() {
@@ -0,0 +1,187 @@
+//! Kernel data watchpoints, backed by the DR0-DR3 debug registers.
+//!
+//! Watchpoints are global: arming one updates a shared table, and every CPU reloads its debug
+//! registers from that table the next time it context switches. When a watchpoint is hit, the
+//! debug trap handler dumps the registers of the writer along with a stack trace.
+
+use core::{
+    arch::asm,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use crate::{
+    arch::consts::USER_END_OFFSET,
+    interrupt::InterruptStack,
+    panic::stack_trace,
+    percpu::PercpuBlock,
+    sync::Mutex,
+    syscall::error::{Error, Result, EBUSY, EINVAL},
+};
+
+/// Number of hardware watchpoint slots.
+pub const SLOT_COUNT: usize = 4;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum WatchKind {
+    /// Trap on data writes.
+    Write,
+    /// Trap on data reads or writes.
+    ReadWrite,
+}
+
+const ZERO: AtomicUsize = AtomicUsize::new(0);
+
+/// Addresses loaded into DR0-DR3.
+static ADDRS: [AtomicUsize; SLOT_COUNT] = [ZERO; SLOT_COUNT];
+/// Value loaded into DR7.
+static DR7: AtomicUsize = AtomicUsize::new(0);
+/// Incremented every time the table changes, so that CPUs know when to reload.
+static GENERATION: AtomicUsize = AtomicUsize::new(0);
+/// Serializes writers. Never taken by `sync_percpu`, which runs with interrupts disabled.
+static WRITER_LOCK: Mutex<()> = Mutex::new(());
+
+// Enables exact data breakpoint matching, recommended by both Intel and AMD.
+const DR7_GE: usize = 1 << 9;
+
+fn dr7_enable_bit(slot: usize) -> usize {
+    1 << (slot * 2 + 1)
+}
+fn dr7_control_shift(slot: usize) -> usize {
+    16 + slot * 4
+}
+
+/// Whether any kernel watchpoint is armed, in which case the debug registers are not available
+/// to user watchpoints.
+pub fn armed() -> bool {
+    DR7.load(Ordering::Relaxed) != 0
+}
+
+/// Arm a watchpoint on the kernel address `addr`, covering `len` bytes. Returns the slot used.
+pub fn arm(addr: usize, len: usize, kind: WatchKind) -> Result<usize> {
+    let len_bits = match len {
+        1 => 0b00,
+        2 => 0b01,
+        4 => 0b11,
+        #[cfg(target_arch = "x86_64")]
+        8 => 0b10,
+        _ => return Err(Error::new(EINVAL)),
+    };
+    if addr < USER_END_OFFSET || addr % len != 0 {
+        return Err(Error::new(EINVAL));
+    }
+    let rw_bits = match kind {
+        WatchKind::Write => 0b01,
+        WatchKind::ReadWrite => 0b11,
+    };
+
+    let _guard = WRITER_LOCK.lock();
+
+    let dr7 = DR7.load(Ordering::Relaxed);
+    let slot = (0..SLOT_COUNT)
+        .find(|&slot| dr7 & dr7_enable_bit(slot) == 0)
+        .ok_or(Error::new(EBUSY))?;
+
+    let shift = dr7_control_shift(slot);
+    let new_dr7 = (dr7 & !(0b1111 << shift))
+        | ((len_bits << 2 | rw_bits) << shift)
+        | dr7_enable_bit(slot)
+        | DR7_GE;
+
+    ADDRS[slot].store(addr, Ordering::Relaxed);
+    DR7.store(new_dr7, Ordering::Relaxed);
+    GENERATION.fetch_add(1, Ordering::Release);
+
+    log::info!("Armed kernel watchpoint {slot} at {addr:#x} ({len} bytes, {kind:?})");
+
+    unsafe {
+        sync_percpu();
+    }
+    Ok(slot)
+}
+
+/// Disarm the watchpoint in `slot`.
+pub fn disarm(slot: usize) -> Result<()> {
+    if slot >= SLOT_COUNT {
+        return Err(Error::new(EINVAL));
+    }
+    let _guard = WRITER_LOCK.lock();
+
+    let dr7 = DR7.load(Ordering::Relaxed);
+    if dr7 & dr7_enable_bit(slot) == 0 {
+        return Err(Error::new(EINVAL));
+    }
+    let mut new_dr7 = dr7 & !dr7_enable_bit(slot) & !(0b1111 << dr7_control_shift(slot));
+    if (0..SLOT_COUNT).all(|slot| new_dr7 & dr7_enable_bit(slot) == 0) {
+        new_dr7 = 0;
+    }
+
+    DR7.store(new_dr7, Ordering::Relaxed);
+    ADDRS[slot].store(0, Ordering::Relaxed);
+    GENERATION.fetch_add(1, Ordering::Release);
+
+    unsafe {
+        sync_percpu();
+    }
+    Ok(())
+}
+
+/// Reload this CPU's debug registers, if the watchpoint table has changed since the last time.
+///
+/// # Safety
+///
+/// Must be called with interrupts disabled.
+pub unsafe fn sync_percpu() {
+    let local_gen = &PercpuBlock::current().misc_arch_info.watchpoint_gen;
+    let generation = GENERATION.load(Ordering::Acquire);
+    if local_gen.get() == generation {
+        return;
+    }
+    local_gen.set(generation);
+
+    unsafe {
+        // Disable all watchpoints first, so that no stale address is ever enabled.
+        asm!("mov dr7, {}", in(reg) 0_usize);
+        asm!("mov dr0, {}", in(reg) ADDRS[0].load(Ordering::Relaxed));
+        asm!("mov dr1, {}", in(reg) ADDRS[1].load(Ordering::Relaxed));
+        asm!("mov dr2, {}", in(reg) ADDRS[2].load(Ordering::Relaxed));
+        asm!("mov dr3, {}", in(reg) ADDRS[3].load(Ordering::Relaxed));
+        asm!("mov dr7, {}", in(reg) DR7.load(Ordering::Relaxed));
+    }
+}
+
+/// Called from the debug trap handler. Returns true if the trap was caused by a kernel
+/// watchpoint, in which case it has already been reported.
+pub fn handle_debug_trap(stack: &InterruptStack) -> bool {
+    let dr6: usize;
+    unsafe {
+        asm!("mov {}, dr6", out(reg) dr6);
+    }
+    let dr7 = DR7.load(Ordering::Relaxed);
+    let hits = (0..SLOT_COUNT)
+        .filter(|&slot| dr6 & (1 << slot) != 0 && dr7 & dr7_enable_bit(slot) != 0)
+        .fold(0_usize, |acc, slot| acc | 1 << slot);
+
+    if hits == 0 {
+        return false;
+    }
+
+    // The processor never clears the B0-B3 status bits by itself.
+    unsafe {
+        asm!("mov dr6, {}", in(reg) dr6 & !0b1111);
+    }
+
+    for slot in (0..SLOT_COUNT).filter(|slot| hits & (1 << slot) != 0) {
+        println!(
+            "Kernel watchpoint {} hit on CPU {}: address {:#x}",
+            slot,
+            crate::cpu_id(),
+            ADDRS[slot].load(Ordering::Relaxed),
+        );
+    }
+    stack.dump();
+    unsafe {
+        stack_trace();
+    }
+
+    true
+}
@@ -26,7 +26,7 @@ startup_ap:
     ; initialize stack to invalid value
     mov sp, 0
 
-    ; cr3 holds pointer to PML4
+    ; cr3 holds pointer to PML4, or PML5 with 5-level paging
     mov edi, [trampoline.page_table]
     mov cr3, edi
 
@@ -42,6 +42,10 @@ startup_ap:
     ; 4: Page Size Extension
     mov eax, cr4
     or eax, 1 << 9 | 1 << 7 | 1 << 5 | 1 << 4
+%ifdef LA57
+    ; 12: 5-Level Paging, which can only be set before enabling long mode
+    or eax, 1 << 12
+%endif
     mov cr4, eax
 
     ; initialize floating point registers
@@ -0,0 +1,252 @@
+//! Syscall auditing.
+//!
+//! A privileged daemon installs [`Rule`]s through `audit:rules`, each matching syscalls by number,
+//! process and result, and reads a [`Record`] of every syscall matching any of them from
+//! `audit:log`, once it returned. Records are kept in a ring buffer allocated with the first rule.
+//! While it is full, new records are dropped rather than overwriting those not read yet, and the
+//! daemon sees the gap in the sequence numbers. Without rules, a syscall only costs a relaxed load.
+//!
+//! The syscalls of processes reading the log are not audited, as every read would otherwise add
+//! another record to read.
+
+use alloc::{collections::VecDeque, vec::Vec};
+use core::{
+    fmt,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use crate::{
+    context::{self, process::ProcessId},
+    percpu::PercpuBlock,
+    sync::{Mutex, RwLock, WaitCondition},
+    syscall::error::{Error, Result, EINTR, EINVAL, ENOENT, ENOMEM},
+    time,
+};
+
+/// Number of records the ring buffer holds.
+const CAPACITY: usize = 4096;
+/// Most rules installed at once, as every audited syscall is checked against each of them.
+const MAX_RULES: usize = 64;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum ResultFilter {
+    Any,
+    Success,
+    Failure,
+    Errno(usize),
+}
+
+/// Syscalls to audit, written as `syscall=<number> pid=<pid> result=<ok|err|errno>`, where
+/// omitted fields match anything.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Rule {
+    syscall: Option<usize>,
+    pid: Option<ProcessId>,
+    result: ResultFilter,
+}
+
+impl Rule {
+    pub fn parse(text: &str) -> Result<Self> {
+        let mut rule = Rule {
+            syscall: None,
+            pid: None,
+            result: ResultFilter::Any,
+        };
+        for field in text.split_whitespace() {
+            let (name, value) = field.split_once('=').ok_or(Error::new(EINVAL))?;
+            let number = || value.parse::<usize>().map_err(|_| Error::new(EINVAL));
+            match name {
+                "syscall" => rule.syscall = Some(number()?),
+                "pid" => rule.pid = Some(ProcessId::new(number()?)),
+                "result" => {
+                    rule.result = match value {
+                        "ok" => ResultFilter::Success,
+                        "err" => ResultFilter::Failure,
+                        _ => ResultFilter::Errno(number()?),
+                    }
+                }
+                _ => return Err(Error::new(EINVAL)),
+            }
+        }
+        Ok(rule)
+    }
+
+    fn matches(&self, syscall: usize, pid: ProcessId, result: Result<usize>) -> bool {
+        self.syscall.is_none_or(|number| number == syscall)
+            && self.pid.is_none_or(|rule_pid| rule_pid == pid)
+            && match (self.result, result) {
+                (ResultFilter::Any, _) => true,
+                (ResultFilter::Success, result) => result.is_ok(),
+                (ResultFilter::Failure, result) => result.is_err(),
+                (ResultFilter::Errno(errno), Err(err)) => err.errno as usize == errno,
+                (ResultFilter::Errno(_), Ok(_)) => false,
+            }
+    }
+}
+
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut sep = "";
+        if let Some(syscall) = self.syscall {
+            write!(f, "syscall={}", syscall)?;
+            sep = " ";
+        }
+        if let Some(pid) = self.pid {
+            write!(f, "{}pid={}", sep, pid.get())?;
+            sep = " ";
+        }
+        match self.result {
+            ResultFilter::Any => Ok(()),
+            ResultFilter::Success => write!(f, "{}result=ok", sep),
+            ResultFilter::Failure => write!(f, "{}result=err", sep),
+            ResultFilter::Errno(errno) => write!(f, "{}result={}", sep, errno),
+        }
+    }
+}
+
+/// An audited syscall, as read from `audit:log`.
+#[derive(Clone, Copy, Debug, Default)]
+#[repr(C)]
+pub struct Record {
+    /// Increased by one for every audited syscall, including those dropped.
+    pub seq: u64,
+    /// Monotonic time in nanoseconds, when the syscall returned.
+    pub time: u64,
+    pub pid: u64,
+    /// Address of the context, which tells threads of a process apart, as in trace records.
+    pub context: u64,
+    /// The syscall number followed by its arguments.
+    pub args: [u64; 6],
+    /// The return value, a negated errno on failure.
+    pub ret: u64,
+}
+
+struct Ring {
+    records: VecDeque<Record>,
+    next_seq: u64,
+}
+
+static RULES: RwLock<Vec<Rule>> = RwLock::new(Vec::new());
+/// Set while there are rules.
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+static RING: Mutex<Option<Ring>> = Mutex::new(None);
+static READERS: WaitCondition = WaitCondition::new();
+
+pub fn rules() -> Vec<Rule> {
+    RULES.read().clone()
+}
+
+/// Add `rule`, allocating the ring buffer if needed.
+pub fn add_rule(rule: Rule) -> Result<()> {
+    {
+        let mut ring = RING.lock();
+        if ring.is_none() {
+            let mut records = VecDeque::new();
+            records
+                .try_reserve_exact(CAPACITY)
+                .map_err(|_| Error::new(ENOMEM))?;
+            *ring = Some(Ring {
+                records,
+                next_seq: 0,
+            });
+        }
+    }
+
+    let mut rules = RULES.write();
+    if rules.contains(&rule) {
+        return Ok(());
+    }
+    if rules.len() >= MAX_RULES {
+        return Err(Error::new(ENOMEM));
+    }
+    rules.push(rule);
+    ACTIVE.store(true, Ordering::Relaxed);
+    Ok(())
+}
+
+pub fn remove_rule(rule: Rule) -> Result<()> {
+    let mut rules = RULES.write();
+    let index = rules
+        .iter()
+        .position(|r| *r == rule)
+        .ok_or(Error::new(ENOENT))?;
+    rules.remove(index);
+    ACTIVE.store(!rules.is_empty(), Ordering::Relaxed);
+    Ok(())
+}
+
+pub fn clear_rules() {
+    RULES.write().clear();
+    ACTIVE.store(false, Ordering::Relaxed);
+}
+
+/// Audit a syscall that returned `result`, called before returning to userspace.
+#[inline]
+pub fn syscall(args: [usize; 6], result: Result<usize>) {
+    if ACTIVE.load(Ordering::Relaxed) {
+        syscall_slow(args, result);
+    }
+}
+
+#[cold]
+fn syscall_slow(args: [usize; 6], result: Result<usize>) {
+    let pid = context::current().read().pid;
+    if !RULES
+        .read()
+        .iter()
+        .any(|rule| rule.matches(args[0], pid, result))
+        || crate::scheme::audit::is_reader(pid)
+    {
+        return;
+    }
+
+    {
+        let mut guard = RING.lock();
+        let Some(ref mut ring) = *guard else {
+            return;
+        };
+        let seq = ring.next_seq;
+        ring.next_seq += 1;
+        if ring.records.len() >= CAPACITY {
+            return;
+        }
+        ring.records.push_back(Record {
+            seq,
+            time: time::monotonic() as u64,
+            pid: pid.get() as u64,
+            context: PercpuBlock::current().switch_internals.context_addr() as u64,
+            args: args.map(|arg| arg as u64),
+            ret: Error::mux(result) as u64,
+        });
+    }
+    READERS.notify();
+    crate::scheme::audit::trigger_events();
+}
+
+pub fn has_records() -> bool {
+    RING.lock()
+        .as_ref()
+        .is_some_and(|ring| !ring.records.is_empty())
+}
+
+/// Remove up to `max` records, blocking while there are none if `block` is set.
+pub fn read(max: usize, block: bool) -> Result<Vec<Record>> {
+    let mut records = Vec::new();
+    records
+        .try_reserve_exact(max.min(CAPACITY))
+        .map_err(|_| Error::new(ENOMEM))?;
+
+    loop {
+        let mut guard = RING.lock();
+        if let Some(ref mut ring) = *guard {
+            let count = max.min(ring.records.len());
+            records.extend(ring.records.drain(..count));
+        }
+        if !records.is_empty() || !block {
+            return Ok(records);
+        }
+        if !READERS.wait(guard, "audit::read") {
+            return Err(Error::new(EINTR));
+        }
+    }
+}
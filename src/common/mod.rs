@@ -1,6 +1,7 @@
 pub mod aligned_box;
 #[macro_use]
 pub mod int_like;
+pub mod try_alloc;
 pub mod unique;
 
 /// Debug macro, lifted from the std
@@ -0,0 +1,67 @@
+//! Fallible allocation, for kernel allocations whose size or number userspace controls.
+//!
+//! The OOM handler of the global allocator panics, so allocations that a process can trigger at
+//! will go through these functions instead, and fail with ENOMEM.
+
+use alloc::{string::String, sync::Arc, vec::Vec};
+
+use crate::syscall::error::{Error, Result, ENOMEM};
+
+/// Fail if a heap allocation failure has been injected by the in-kernel tests.
+#[inline]
+fn injected_failure() -> Result<()> {
+    #[cfg(feature = "ktest")]
+    if crate::ktest::fault::should_fail(crate::ktest::fault::Site::HeapAlloc) {
+        return Err(Error::new(ENOMEM));
+    }
+    Ok(())
+}
+
+pub fn try_arc<T>(value: T) -> Result<Arc<T>> {
+    injected_failure()?;
+    Arc::try_new(value).map_err(|_| Error::new(ENOMEM))
+}
+
+/// Allocate a vector of `len` copies of `value`.
+pub fn try_vec_filled<T: Clone>(value: T, len: usize) -> Result<Vec<T>> {
+    injected_failure()?;
+    let mut vec = Vec::new();
+    vec.try_reserve_exact(len).map_err(|_| Error::new(ENOMEM))?;
+    vec.resize(len, value);
+    Ok(vec)
+}
+pub fn try_vec_from_slice<T: Clone>(slice: &[T]) -> Result<Vec<T>> {
+    injected_failure()?;
+    let mut vec = Vec::new();
+    vec.try_reserve_exact(slice.len())
+        .map_err(|_| Error::new(ENOMEM))?;
+    vec.extend_from_slice(slice);
+    Ok(vec)
+}
+pub fn try_string(str: &str) -> Result<String> {
+    injected_failure()?;
+    let mut string = String::new();
+    string
+        .try_reserve_exact(str.len())
+        .map_err(|_| Error::new(ENOMEM))?;
+    string.push_str(str);
+    Ok(string)
+}
+
+pub fn try_push<T>(vec: &mut Vec<T>, value: T) -> Result<()> {
+    injected_failure()?;
+    vec.try_reserve(1).map_err(|_| Error::new(ENOMEM))?;
+    vec.push(value);
+    Ok(())
+}
+pub fn try_collect<T>(iter: impl IntoIterator<Item = T>) -> Result<Vec<T>> {
+    injected_failure()?;
+    let iter = iter.into_iter();
+    let mut vec = Vec::new();
+    vec.try_reserve(iter.size_hint().0)
+        .map_err(|_| Error::new(ENOMEM))?;
+    for item in iter {
+        try_push(&mut vec, item)?;
+    }
+    Ok(vec)
+}
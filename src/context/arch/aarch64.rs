@@ -1,9 +1,18 @@
 use crate::{
-    arch::{device::cpu::registers::control_regs, interrupt::InterruptStack, paging::PageMapper},
+    arch::{
+        debug_regs::{self, DebugRegisters},
+        device::cpu::registers::control_regs,
+        interrupt::InterruptStack,
+        mte::{self, MteState},
+        paging::PageMapper,
+        pauth::{self, PacKeys},
+        sve, KFX_SIZE,
+    },
     context::{context::Kstack, memory::Table},
     percpu::PercpuBlock,
     syscall::FloatRegisters,
 };
+use alloc::boxed::Box;
 use core::{arch::asm, mem, mem::offset_of, ptr, sync::atomic::AtomicBool};
 use rmm::TableKind;
 use spin::Once;
@@ -40,6 +49,12 @@ pub struct Context {
     x21: usize, /* Callee saved Register                                */
     x20: usize, /* Callee saved Register                                */
     x19: usize, /* Callee saved Register                                */
+    /// Hardware breakpoints and watchpoints, if any are in use.
+    debug: Option<Box<DebugRegisters>>,
+    /// Pointer authentication keys of userspace.
+    pub(crate) pac_keys: PacKeys,
+    /// Tag checking of userspace.
+    pub(crate) mte: MteState,
 }
 
 impl Context {
@@ -65,9 +80,18 @@ impl Context {
             x21: 0,
             x20: 0,
             x19: 0,
+            debug: None,
+            pac_keys: PacKeys::default(),
+            mte: MteState::default(),
         }
     }
 
+    /// Copy the state a new thread or child inherits from its parent.
+    pub(crate) fn inherit(&mut self, parent: &Self) {
+        self.pac_keys = parent.pac_keys;
+        self.mte = parent.mte;
+    }
+
     fn set_stack(&mut self, address: usize) {
         self.sp = address;
     }
@@ -102,6 +126,7 @@ impl Context {
                 stack_top.write_bytes(0_u8, INT_REGS_SIZE);
                 (&mut *stack_top.cast::<InterruptStack>()).init();
             }
+            self.pac_keys = PacKeys::generate();
         }
 
         self.set_lr(crate::interrupt::syscall::enter_usermode as usize);
@@ -152,6 +177,15 @@ impl super::Context {
         unsafe {
             ptr::write(self.kfx.as_mut_ptr() as *mut FloatRegisters, new);
         }
+
+        // The V registers are the low 128 bits of the Z registers, which are loaded after them.
+        if let Some(length) = sve::vector_length() {
+            let fp_simd_regs = new.fp_simd_regs;
+            let z_regs = self.kfx[KFX_SIZE..].chunks_exact_mut(length);
+            for (z, v) in z_regs.zip(fp_simd_regs) {
+                z[..16].copy_from_slice(&v.to_le_bytes());
+            }
+        }
     }
     pub fn current_syscall(&self) -> Option<[usize; 6]> {
         if !self.inside_syscall {
@@ -193,6 +227,48 @@ impl super::Context {
             tpidrro_el0: self.arch.tpidrro_el0,
         })
     }
+
+    pub(crate) fn read_debug_regs(&self) -> DebugRegisters {
+        self.arch.debug.as_deref().copied().unwrap_or_default()
+    }
+
+    pub(crate) fn write_debug_regs(&mut self, mut regs: DebugRegisters) -> Result<()> {
+        regs.sanitize()?;
+
+        let in_use = regs.mdscr != 0 || regs.bcr.iter().chain(&regs.wcr).any(|ctrl| ctrl & 1 != 0);
+        self.arch.debug = in_use.then(|| Box::new(regs));
+        Ok(())
+    }
+
+    pub(crate) fn write_current_debug_regs(&mut self, regs: DebugRegisters) -> Result<()> {
+        self.write_debug_regs(regs)?;
+        unsafe {
+            debug_regs::load(self.arch.debug.as_deref());
+        }
+        Ok(())
+    }
+
+    /// Replace the pointer authentication keys of the running context, which is executing a new
+    /// program, and disable its tag checking.
+    pub(crate) fn reset_current_for_exec(&mut self) {
+        self.arch.pac_keys = PacKeys::generate();
+        self.arch.mte = MteState::default();
+        unsafe {
+            pauth::switch_to(&self.arch.pac_keys);
+            mte::switch_to(&self.arch.mte);
+        }
+    }
+
+    pub(crate) fn write_mte(&mut self, state: MteState) {
+        self.arch.mte = state;
+    }
+
+    pub(crate) fn write_current_mte(&mut self, state: MteState) {
+        self.write_mte(state);
+        unsafe {
+            mte::switch_to(&self.arch.mte);
+        }
+    }
 }
 
 pub static EMPTY_CR3: Once<rmm::PhysicalAddress> = Once::new();
@@ -203,6 +279,22 @@ pub unsafe fn empty_cr3() -> rmm::PhysicalAddress {
     *EMPTY_CR3.get_unchecked()
 }
 
+/// Save the FP/SIMD registers of the running context to its `kfx`, as when switching from it.
+pub unsafe fn save_fpu(context: &mut super::Context) {
+    fp_save(&mut *(context.kfx.as_mut_ptr() as *mut FloatRegisters));
+    if sve::vector_length().is_some() {
+        sve::save(context.kfx.as_mut_ptr().add(KFX_SIZE));
+    }
+}
+
+/// Load the FP/SIMD registers of the running context from its `kfx`, as when switching to it.
+pub unsafe fn load_fpu(context: &mut super::Context) {
+    fp_load(&mut *(context.kfx.as_mut_ptr() as *mut FloatRegisters));
+    if sve::vector_length().is_some() {
+        sve::load(context.kfx.as_ptr().add(KFX_SIZE));
+    }
+}
+
 #[target_feature(enable = "neon")]
 #[naked]
 unsafe extern "C" fn fp_save(float_regs: &mut FloatRegisters) {
@@ -270,12 +362,21 @@ unsafe extern "C" fn fp_load(float_regs: &mut FloatRegisters) {
 }
 
 pub unsafe fn switch_to(prev: &mut super::Context, next: &mut super::Context) {
-    fp_save(&mut *(prev.kfx.as_mut_ptr() as *mut FloatRegisters));
+    save_fpu(prev);
 
     prev.arch.fx_loadable = true;
 
     if next.arch.fx_loadable {
-        fp_load(&mut *(next.kfx.as_mut_ptr() as *mut FloatRegisters));
+        load_fpu(next);
+    }
+
+    if prev.arch.debug.is_some() || next.arch.debug.is_some() {
+        debug_regs::load(next.arch.debug.as_deref());
+    }
+
+    if next.userspace {
+        pauth::switch_to(&next.arch.pac_keys);
+        mte::switch_to(&next.arch.mte);
     }
 
     PercpuBlock::current()
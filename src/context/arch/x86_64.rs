@@ -1,20 +1,36 @@
+use alloc::{boxed::Box, sync::Arc};
 use core::{
     ptr::{addr_of, addr_of_mut},
-    sync::atomic::AtomicBool,
+    str,
+    sync::atomic::{AtomicBool, Ordering},
 };
 
 use crate::syscall::FloatRegisters;
 
 use crate::{
-    arch::{interrupt::InterruptStack, paging::PageMapper},
-    context::{context::Kstack, memory::Table},
+    arch::{
+        alternative::{self, KcpuFeatures, FXSAVE_SIZE},
+        cet::{self, CetState},
+        debug_regs::{self, DebugRegisters},
+        interrupt::InterruptStack,
+        paging::PageMapper,
+    },
+    context::{
+        context::Kstack,
+        memory::{PageSpan, Table},
+    },
+    gdt::{IoBitmap, IOBITMAP_ALL},
     memory::RmmA,
 };
 use core::mem::offset_of;
 use rmm::{Arch, TableKind, VirtualAddress};
 use spin::Once;
 use syscall::{error::*, EnvRegisters};
-use x86::msr;
+use x86::{
+    bits64::segmentation,
+    controlregs::{self, Cr0},
+    msr,
+};
 
 /// This must be used by the kernel to ensure that context switches are done atomically
 /// Compare and exchange this to true when beginning a context switch on any CPU
@@ -24,6 +40,53 @@ pub static CONTEXT_SWITCH_LOCK: AtomicBool = AtomicBool::new(false);
 
 const ST_RESERVED: u128 = 0xFFFF_FFFF_FFFF_0000_0000_0000_0000_0000;
 
+/// XSTATE_BV, in the XSAVE header after the legacy region, has a bit set for each state component
+/// that was saved rather than left in its initial configuration, the first two being the x87 and
+/// SSE registers of the legacy region.
+const XSTATE_BV_OFFSET: usize = FXSAVE_SIZE;
+const XSTATE_X87: u64 = 1 << 0;
+const XSTATE_SSE: u64 = 1 << 1;
+
+/// FCW in the initial x87 configuration.
+const FCW_INIT: u16 = 0x037F;
+
+/// Whether the FPU state of a context is only restored when it first uses it after being switched
+/// to, as set by `LAZY_FPU=1` in the environment of the kernel. Contexts never using it are then
+/// neither restored nor saved.
+static LAZY_FPU: AtomicBool = AtomicBool::new(false);
+
+/// Times in a row a context must have used the FPU after being switched to for its state to be
+/// restored eagerly again, sparing it the trap. The counter wraps, making it lazy every so often.
+const FPU_EAGER_THRESHOLD: u8 = 5;
+
+/// Codes written to the `arch-prctl` handle of `proc:`, as for `arch_prctl` on Linux. The getters
+/// store the base at the address written after them.
+pub const ARCH_SET_GS: usize = 0x1001;
+pub const ARCH_SET_FS: usize = 0x1002;
+pub const ARCH_GET_FS: usize = 0x1003;
+pub const ARCH_GET_GS: usize = 0x1004;
+
+/// Codes controlling CET, see [`crate::cet`], each followed by features. `ARCH_SHSTK_STATUS` is
+/// followed by the address to store the enabled features at instead.
+pub const ARCH_SHSTK_ENABLE: usize = 0x5001;
+pub const ARCH_SHSTK_DISABLE: usize = 0x5002;
+pub const ARCH_SHSTK_LOCK: usize = 0x5003;
+pub const ARCH_SHSTK_STATUS: usize = 0x5005;
+
+/// Features of the `ARCH_SHSTK_*` codes. Indirect branch tracking is not supported by Linux.
+pub const ARCH_SHSTK_SHSTK: usize = 1 << 0;
+pub const ARCH_SHSTK_WRSS: usize = 1 << 1;
+pub const ARCH_SHSTK_IBT: usize = 1 << 2;
+
+/// Set in the flags read from the `arch-prctl` handle when userspace may use RDFSBASE, WRFSBASE,
+/// RDGSBASE and WRGSBASE, like `HWCAP2_FSGSBASE` on Linux.
+pub const HWCAP2_FSGSBASE: usize = 1 << 1;
+
+/// Whether CR4.FSGSBASE is set, letting both the kernel and userspace access the bases directly.
+pub fn fsgsbase_enabled() -> bool {
+    alternative::features().contains(KcpuFeatures::FSGSBASE)
+}
+
 #[cfg(cpu_feature_never = "xsave")]
 pub const KFX_ALIGN: usize = 16;
 
@@ -61,10 +124,25 @@ pub struct Context {
     /// running. With fsgsbase, this is neither saved nor restored upon every syscall (there is no
     /// need to!), and thus it must be re-read from the register before copying this struct.
     pub(crate) gsbase: usize,
+    /// Whether all ports may be accessed, as granted by `iopl`.
     userspace_io_allowed: bool,
+    /// The ports that may be accessed, as granted by `ioperm`. Shared with the threads and children
+    /// inheriting it, until one of them changes it.
+    io_bitmap: Option<Arc<IoBitmap>>,
+    /// Watchpoints set by a tracer.
+    debug: Option<Box<DebugRegisters>>,
+    /// Times in a row the context used the FPU after being switched to, with lazy FPU switching.
+    fpu_counter: u8,
+    pub(crate) cet: CetState,
 }
 
 impl Context {
+    /// Stack and frame pointer saved when the context was last switched out, at which point the
+    /// stack pointer points to the return address into `switch_to`.
+    pub fn saved_stack(&self) -> (usize, usize) {
+        (self.rsp, self.rbp)
+    }
+
     pub fn new() -> Context {
         Context {
             rflags: 0,
@@ -78,6 +156,25 @@ impl Context {
             fsbase: 0,
             gsbase: 0,
             userspace_io_allowed: false,
+            io_bitmap: None,
+            debug: None,
+            fpu_counter: 0,
+            cet: CetState::default(),
+        }
+    }
+
+    /// Copy the state a new thread or child inherits from its parent.
+    pub(crate) fn inherit(&mut self, parent: &Self) {
+        self.userspace_io_allowed = parent.userspace_io_allowed;
+        self.io_bitmap = parent.io_bitmap.clone();
+        self.cet = parent.cet.inherit();
+    }
+
+    fn io_bitmap(&self) -> Option<&IoBitmap> {
+        if self.userspace_io_allowed {
+            Some(&IOBITMAP_ALL)
+        } else {
+            self.io_bitmap.as_deref()
         }
     }
 
@@ -119,6 +216,22 @@ impl super::Context {
     pub fn get_fx_regs(&self) -> FloatRegisters {
         let mut regs = unsafe { self.kfx.as_ptr().cast::<FloatRegisters>().read() };
         regs._reserved = 0;
+        // XSAVEOPT and XSAVEC leave the legacy region as it was if the registers are in their
+        // initial configuration.
+        if let Some(xstate_bv) = self.xstate_bv() {
+            if xstate_bv & XSTATE_X87 == 0 {
+                regs.fcw = FCW_INIT;
+                regs.fsw = 0;
+                regs.ftw = 0;
+                regs.fop = 0;
+                regs.fip = 0;
+                regs.fdp = 0;
+                regs.st_space = [0; 8];
+            }
+            if xstate_bv & XSTATE_SSE == 0 {
+                regs.xmm_space = [0; 16];
+            }
+        }
         let mut new_st = regs.st_space;
         for st in &mut new_st {
             // Only allow access to the 80 lowest bits
@@ -146,14 +259,65 @@ impl super::Context {
         unsafe {
             self.kfx.as_mut_ptr().cast::<FloatRegisters>().write(new);
         }
+        // Otherwise, XRSTOR would put the registers in their initial configuration instead.
+        if let Some(xstate_bv) = self.xstate_bv() {
+            let xstate_bv = xstate_bv | XSTATE_X87 | XSTATE_SSE;
+            self.kfx[XSTATE_BV_OFFSET..XSTATE_BV_OFFSET + 8]
+                .copy_from_slice(&xstate_bv.to_le_bytes());
+        }
+    }
+
+    /// XSTATE_BV of `kfx`, if it is an XSAVE area.
+    fn xstate_bv(&self) -> Option<u64> {
+        if !alternative::features().contains(KcpuFeatures::XSAVE) {
+            return None;
+        }
+        let bytes = self.kfx.get(XSTATE_BV_OFFSET..XSTATE_BV_OFFSET + 8)?;
+        Some(u64::from_le_bytes(bytes.try_into().unwrap()))
     }
 
     pub fn set_userspace_io_allowed(&mut self, allowed: bool) {
         self.arch.userspace_io_allowed = allowed;
+        self.reload_userspace_io();
+    }
+
+    /// Allow or deny access to `count` ports starting at `from`, in addition to those allowed by
+    /// `set_userspace_io_allowed`.
+    pub fn set_io_permission(&mut self, from: usize, count: usize, allowed: bool) -> Result<()> {
+        if from.checked_add(count).map_or(true, |end| end > 1 << 16) {
+            return Err(Error::new(EINVAL));
+        }
+
+        let bitmap = match self.arch.io_bitmap {
+            Some(ref mut bitmap) => bitmap,
+            None if !allowed => return Ok(()),
+            None => self.arch.io_bitmap.insert(IoBitmap::new_denied()?),
+        };
+        if Arc::get_mut(bitmap).is_none() {
+            *bitmap = bitmap.try_clone()?;
+        }
+        Arc::get_mut(bitmap)
+            .expect("bitmap is no longer shared")
+            .set(from, count, allowed);
+        if bitmap.is_all_denied() {
+            self.arch.io_bitmap = None;
+        }
+        self.reload_userspace_io();
+        Ok(())
+    }
+
+    /// The TSS I/O bitmap of the ports this context may access, where a set bit denies access.
+    pub fn io_permission_bitmap(&self) -> &[u8] {
+        match self.arch.io_bitmap() {
+            Some(bitmap) => bitmap.bits(),
+            None => &[0xFF; 65536 / 8],
+        }
+    }
 
+    fn reload_userspace_io(&self) {
         if self.is_current_context() {
             unsafe {
-                crate::gdt::set_userspace_io_allowed(crate::gdt::pcr(), allowed);
+                crate::gdt::set_userspace_io(crate::gdt::pcr(), self.arch.io_bitmap());
             }
         }
     }
@@ -175,10 +339,15 @@ impl super::Context {
     }
 
     pub(crate) fn read_current_env_regs(&self) -> Result<EnvRegisters> {
-        // TODO: Avoid rdmsr if fsgsbase is not enabled, if this is worth optimizing for.
+        // The user GSBASE is only reachable with RDGSBASE after a SWAPGS, so the MSR is used for
+        // it either way.
         unsafe {
             Ok(EnvRegisters {
-                fsbase: msr::rdmsr(msr::IA32_FS_BASE),
+                fsbase: if fsgsbase_enabled() {
+                    segmentation::rdfsbase()
+                } else {
+                    msr::rdmsr(msr::IA32_FS_BASE)
+                },
                 gsbase: msr::rdmsr(msr::IA32_KERNEL_GSBASE),
             })
         }
@@ -196,7 +365,11 @@ impl super::Context {
             && RmmA::virt_is_valid(VirtualAddress::new(regs.gsbase as usize))
         {
             unsafe {
-                x86::msr::wrmsr(x86::msr::IA32_FS_BASE, regs.fsbase as u64);
+                if fsgsbase_enabled() {
+                    segmentation::wrfsbase(regs.fsbase);
+                } else {
+                    x86::msr::wrmsr(x86::msr::IA32_FS_BASE, regs.fsbase as u64);
+                }
                 // We have to write to KERNEL_GSBASE, because when the kernel returns to
                 // userspace, it will have executed SWAPGS first.
                 x86::msr::wrmsr(x86::msr::IA32_KERNEL_GSBASE, regs.gsbase as u64);
@@ -221,6 +394,71 @@ impl super::Context {
             Err(Error::new(EINVAL))
         }
     }
+
+    pub(crate) fn read_debug_regs(&self) -> DebugRegisters {
+        self.arch.debug.as_deref().copied().unwrap_or_default()
+    }
+
+    pub(crate) fn write_debug_regs(&mut self, mut regs: DebugRegisters) -> Result<()> {
+        regs.sanitize()?;
+        if regs.in_use() {
+            debug_regs::check_available()?;
+        }
+        self.arch.debug = regs.in_use().then(|| Box::new(regs));
+        Ok(())
+    }
+
+    pub(crate) fn write_current_debug_regs(&mut self, regs: DebugRegisters) -> Result<()> {
+        self.write_debug_regs(regs)?;
+        unsafe {
+            debug_regs::load(self.arch.debug.as_deref());
+        }
+        Ok(())
+    }
+
+    /// Disable CET for the running context, which is executing a new program.
+    pub(crate) fn reset_current_for_exec(&mut self) {
+        self.arch.cet = CetState::default();
+        unsafe {
+            cet::load(&self.arch.cet);
+        }
+    }
+
+    /// Apply an `ARCH_SHSTK_*` code, with `shadow_stack` mapped for the context if it enables one,
+    /// returning the shadow stack to unmap.
+    pub(crate) fn write_cet(
+        &mut self,
+        code: usize,
+        features: usize,
+        shadow_stack: Option<PageSpan>,
+    ) -> Result<Option<PageSpan>> {
+        let (cet, unused) = self.arch.cet.with(code, features, shadow_stack)?;
+        self.arch.cet = cet;
+        Ok(unused)
+    }
+
+    pub(crate) fn write_current_cet(
+        &mut self,
+        code: usize,
+        features: usize,
+        shadow_stack: Option<PageSpan>,
+    ) -> Result<Option<PageSpan>> {
+        unsafe {
+            cet::save(&mut self.arch.cet);
+        }
+        let unused = self.write_cet(code, features, shadow_stack)?;
+        unsafe {
+            cet::load(&self.arch.cet);
+        }
+        Ok(unused)
+    }
+
+    /// Record the watchpoints that were hit, for the tracer to read from `regs/debug`.
+    pub(crate) fn record_watchpoint_hits(&mut self, hits: u64) {
+        if let Some(ref mut debug) = self.arch.debug {
+            debug.dr6 = hits;
+        }
+    }
 }
 
 pub static EMPTY_CR3: Once<rmm::PhysicalAddress> = Once::new();
@@ -231,46 +469,167 @@ pub unsafe fn empty_cr3() -> rmm::PhysicalAddress {
     *EMPTY_CR3.get_unchecked()
 }
 
-/// Switch to the next context by restoring its stack and registers
-pub unsafe fn switch_to(prev: &mut super::Context, next: &mut super::Context) {
-    // Update contexts' timestamps
-    let switch_time = crate::time::monotonic();
-    prev.cpu_time += switch_time.saturating_sub(prev.switch_time);
-    next.switch_time = switch_time;
-
-    let pcr = crate::gdt::pcr();
-
-    if let Some(ref stack) = next.kstack {
-        crate::gdt::set_tss_stack(pcr, stack.initial_top() as usize);
+/// Save the FPU registers of the running context to its `kfx`, as when switching from it.
+pub unsafe fn save_fpu(context: &mut super::Context) {
+    if controlregs::cr0().contains(Cr0::CR0_TASK_SWITCHED) {
+        // Switched to lazily and not used since, so `kfx` is already up to date.
+        return;
     }
-    crate::gdt::set_userspace_io_allowed(pcr, next.arch.userspace_io_allowed);
-
     core::arch::asm!(
-        alternative2!(
-            feature1: "xsaveopt",
+        alternative3!(
+            feature1: "xsavec",
             then1: ["
                 mov eax, 0xffffffff
                 mov edx, eax
-                xsaveopt64 [{prev_fx}]
-                xrstor64 [{next_fx}]
+                xsavec64 [{fx}]
             "],
-            feature2: "xsave",
+            feature2: "xsaveopt",
             then2: ["
                 mov eax, 0xffffffff
                 mov edx, eax
-                xsave64 [{prev_fx}]
-                xrstor64 [{next_fx}]
+                xsaveopt64 [{fx}]
+            "],
+            feature3: "xsave",
+            then3: ["
+                mov eax, 0xffffffff
+                mov edx, eax
+                xsave64 [{fx}]
+            "],
+            default: ["
+                fxsave64 [{fx}]
+            "]
+        ),
+        fx = in(reg) context.kfx.as_mut_ptr(),
+        out("eax") _,
+        out("edx") _,
+    );
+}
+
+/// Load the FPU registers of the running context from its `kfx`, as when switching to it.
+pub unsafe fn load_fpu(context: &super::Context) {
+    clts();
+    core::arch::asm!(
+        alternative!(
+            feature: "xsave",
+            then: ["
+                mov eax, 0xffffffff
+                mov edx, eax
+                xrstor64 [{fx}]
             "],
             default: ["
-                fxsave64 [{prev_fx}]
-                fxrstor64 [{next_fx}]
+                fxrstor64 [{fx}]
             "]
         ),
-        prev_fx = in(reg) prev.kfx.as_mut_ptr(),
-        next_fx = in(reg) next.kfx.as_ptr(),
+        fx = in(reg) context.kfx.as_ptr(),
         out("eax") _,
         out("edx") _,
     );
+}
+
+/// Clear CR0.TS, allowing the FPU to be used without trapping.
+unsafe fn clts() {
+    core::arch::asm!("clts", options(nostack, preserves_flags));
+}
+
+/// Read `LAZY_FPU` from the environment of the kernel.
+pub fn init_lazy_fpu(env: &[u8]) {
+    for line in str::from_utf8(env).unwrap_or("").lines() {
+        let mut parts = line.splitn(2, '=');
+        let name = parts.next().unwrap_or("");
+        let value = parts.next().unwrap_or("");
+
+        if name == "LAZY_FPU" && value == "1" {
+            LAZY_FPU.store(true, Ordering::Relaxed);
+            log::info!("Lazy FPU switching enabled");
+        }
+    }
+}
+
+/// Save the FPU registers of `prev` if it used them, and either restore those of `next` or set
+/// CR0.TS, so that it traps with #NM on its first use of the FPU, see [`fpu_trap`].
+unsafe fn switch_fpu_lazy(prev: &mut super::Context, next: &mut super::Context) {
+    if controlregs::cr0().contains(Cr0::CR0_TASK_SWITCHED) {
+        prev.arch.fpu_counter = 0;
+    } else {
+        save_fpu(prev);
+    }
+
+    if next.arch.fpu_counter > FPU_EAGER_THRESHOLD {
+        next.arch.fpu_counter = next.arch.fpu_counter.wrapping_add(1);
+        load_fpu(next);
+    } else {
+        controlregs::cr0_write(controlregs::cr0() | Cr0::CR0_TASK_SWITCHED);
+    }
+}
+
+/// Handle #NM, restoring the FPU registers of the current context if it was switched to lazily.
+/// Returns false if the trap has another cause.
+pub fn fpu_trap() -> bool {
+    if !LAZY_FPU.load(Ordering::Relaxed)
+        || !unsafe { controlregs::cr0() }.contains(Cr0::CR0_TASK_SWITCHED)
+    {
+        return false;
+    }
+    let context_lock = super::current();
+    let mut context = context_lock.write();
+    context.arch.fpu_counter += 1;
+    unsafe {
+        load_fpu(&context);
+    }
+    true
+}
+
+/// Switch to the next context by restoring its stack and registers
+pub unsafe fn switch_to(prev: &mut super::Context, next: &mut super::Context) {
+    let pcr = crate::gdt::pcr();
+
+    if let Some(ref stack) = next.kstack {
+        crate::gdt::set_tss_stack(pcr, stack.initial_top() as usize);
+    }
+    crate::gdt::set_userspace_io(pcr, next.arch.io_bitmap());
+
+    if prev.arch.debug.is_some() || next.arch.debug.is_some() {
+        debug_regs::load(next.arch.debug.as_deref());
+    }
+    cet::switch_to(&mut prev.arch.cet, &next.arch.cet);
+
+    if LAZY_FPU.load(Ordering::Relaxed) {
+        switch_fpu_lazy(prev, next);
+    } else {
+        core::arch::asm!(
+            alternative3!(
+                feature1: "xsavec",
+                then1: ["
+                    mov eax, 0xffffffff
+                    mov edx, eax
+                    xsavec64 [{prev_fx}]
+                    xrstor64 [{next_fx}]
+                "],
+                feature2: "xsaveopt",
+                then2: ["
+                    mov eax, 0xffffffff
+                    mov edx, eax
+                    xsaveopt64 [{prev_fx}]
+                    xrstor64 [{next_fx}]
+                "],
+                feature3: "xsave",
+                then3: ["
+                    mov eax, 0xffffffff
+                    mov edx, eax
+                    xsave64 [{prev_fx}]
+                    xrstor64 [{next_fx}]
+                "],
+                default: ["
+                    fxsave64 [{prev_fx}]
+                    fxrstor64 [{next_fx}]
+                "]
+            ),
+            prev_fx = in(reg) prev.kfx.as_mut_ptr(),
+            next_fx = in(reg) next.kfx.as_ptr(),
+            out("eax") _,
+            out("edx") _,
+        );
+    }
 
     {
         core::arch::asm!(
@@ -416,15 +775,26 @@ pub fn setup_new_utable() -> Result<Table> {
         // paranoid ISRs which can occur anywhere; we don't want interrupts to triple fault!) and
         // map lazily via page faults in the kernel.
 
+        // The entries of the top level table, which is a PML5 with 5-level paging, in which case
+        // the kernel image and heap share one.
+        let top_entry = |address: usize| {
+            let shift = RmmA::PAGE_SHIFT + RmmA::PAGE_ENTRY_SHIFT * (RmmA::PAGE_LEVELS - 1);
+            (address >> shift) % RmmA::PAGE_ENTRIES
+        };
+
         // Copy kernel image mapping
-        copy_mapping(crate::KERNEL_PML4);
+        copy_mapping(top_entry(crate::KERNEL_OFFSET));
 
         // Copy kernel heap mapping
-        copy_mapping(crate::KERNEL_HEAP_PML4);
+        copy_mapping(top_entry(crate::KERNEL_HEAP_OFFSET));
 
         // Copy physmap mapping
-        copy_mapping(crate::PHYS_PML4);
+        copy_mapping(top_entry(crate::PHYS_OFFSET));
     }
 
-    Ok(Table { utable })
+    Ok(Table {
+        utable,
+        #[cfg(feature = "pti")]
+        shadow: crate::arch::pti::new_shadow()?,
+    })
 }
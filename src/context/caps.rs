@@ -0,0 +1,77 @@
+//! Capabilities, which privileged operations require rather than an effective uid of root.
+//!
+//! Every context has a permitted set of capabilities, which it can only ever drop, by writing the
+//! ones to keep to its `caps` handle in `proc:`, and an effective set, which reading it returns and
+//! privileged operations check. The first context has all of them, and new threads and forks
+//! inherit both sets of the context creating them. A process whose effective uid stops being root
+//! loses its effective capabilities, and gets the permitted ones back once it is root again, as
+//! with `seteuid` around an unprivileged section. Executing a new program without being root drops
+//! both sets, so that privileged daemons can drop root entirely, or keep it and drop the
+//! capabilities they do not need.
+
+use crate::{
+    context::{self, process::Process, Context},
+    syscall::error::{Error, Result, EPERM},
+};
+
+bitflags! {
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub struct Capabilities: u32 {
+        /// Access I/O ports, with `iopl` or the `ioperm` handle in `proc:`.
+        const IO_PORT = 1 << 0;
+        /// Map physical memory, with other memory types than writeback through `memory:`, and
+        /// translate virtual addresses with `virttophys`.
+        const PHYS_MEM = 1 << 1;
+        /// Allocate and handle IRQs through `irq:`.
+        const IRQ = 1 << 2;
+        /// Trace processes owned by other users, and any process with `PTRACE_SCOPE=admin`.
+        const PTRACE = 1 << 3;
+        /// Reboot and power off, by signalling init, or through `kexec:`.
+        const REBOOT = 1 << 4;
+        /// Give a child a new scheme namespace, with `newns` in `proc:new-clone`.
+        const NAMESPACE = 1 << 5;
+        /// Raise the priority of contexts, by lowering their nice level, giving them a real-time
+        /// policy, or setting their CPU bandwidth with `cpu-max`.
+        const SYS_NICE = 1 << 6;
+        /// Raise hard resource limits, and lower the OOM score adjustment of contexts.
+        const SYS_RESOURCE = 1 << 7;
+        /// Exempt contexts from the W^X policy, with `allow-wx` in `proc:`.
+        const ALLOW_WX = 1 << 8;
+    }
+}
+
+/// Whether the current context has `caps`.
+pub fn has(caps: Capabilities) -> bool {
+    context::current().read().caps.contains(caps)
+}
+
+/// Fail with EPERM unless the current context has `caps`.
+pub fn check(caps: Capabilities) -> Result<()> {
+    if has(caps) {
+        Ok(())
+    } else {
+        Err(Error::new(EPERM))
+    }
+}
+
+/// Keep only the capabilities in `keep`, of both sets of `context`.
+pub fn keep(context: &mut Context, keep: Capabilities) {
+    context.caps &= keep;
+    context.caps_permitted &= keep;
+}
+
+/// Set the effective uid of `process` to `euid`, which empties the effective capabilities of its
+/// threads if it stops being root, and restores the permitted ones if it becomes root again.
+pub fn set_euid(process: &mut Process, euid: u32) {
+    if (process.euid == 0) != (euid == 0) {
+        for thread in process.threads.iter().filter_map(|thread| thread.upgrade()) {
+            let mut thread = thread.write();
+            thread.caps = if euid == 0 {
+                thread.caps_permitted
+            } else {
+                Capabilities::empty()
+            };
+        }
+    }
+    process.euid = euid;
+}
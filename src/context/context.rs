@@ -1,30 +1,41 @@
-use alloc::{borrow::Cow, collections::VecDeque, sync::Arc, vec::Vec};
+use alloc::{borrow::Cow, boxed::Box, collections::VecDeque, sync::Arc, vec::Vec};
 use core::{
     cmp::Ordering,
     mem::{self, size_of},
     num::NonZeroUsize,
 };
-use spin::RwLock;
 use syscall::{RtSigInfo, SigProcControl, Sigcontrol};
 
 use crate::{
     arch::{interrupt::InterruptStack, paging::PAGE_SIZE},
     common::aligned_box::AlignedBox,
-    context::{self, arch, file::FileDescriptor},
-    cpu_set::{LogicalCpuId, LogicalCpuSet},
+    context::{
+        self, arch,
+        file::FileDescriptor,
+        rt::SchedPolicy,
+        switch::{self, Nice},
+    },
+    cpu_set::{LogicalCpuId, LogicalCpuSet, RawMask},
     ipi::{ipi, IpiKind, IpiTarget},
-    memory::{allocate_p2frame, deallocate_p2frame, Enomem, Frame, RaiiFrame},
+    memory::{allocate_p2frame, deallocate_p2frame, memcg::MemGroup, Enomem, Frame, RaiiFrame},
+    numa::MemPolicy,
     paging::{RmmA, RmmArch},
     percpu::PercpuBlock,
     scheme::FileHandle,
+    sync::{RwLock, WaitCondition},
 };
 
-use crate::syscall::error::{Error, Result, EAGAIN, ESRCH};
+use crate::syscall::error::{Error, Result, EAGAIN, ENOMEM, ESRCH};
 
 use super::{
+    caps::Capabilities,
     empty_cr3,
+    group::ContextGroup,
     memory::{AddrSpaceWrapper, GrantFileRef},
     process::{Process, ProcessId},
+    rlimit::{Rlimits, RLIMIT_NOFILE},
+    signal::KernelSignals,
+    stats::ContextStats,
 };
 
 /// The status of a context - used for scheduling
@@ -131,6 +142,8 @@ pub struct Context {
     pub process: Arc<RwLock<Process>>,
     /// Signal handler
     pub sig: Option<SignalState>,
+    /// Signals with handlers registered with the kernel, used while `sig` is unset.
+    pub ksig: Option<Box<KernelSignals>>,
     /// Status of context
     pub status: Status,
     pub status_reason: &'static str,
@@ -142,12 +155,28 @@ pub struct Context {
     pub switch_time: u128,
     /// Amount of CPU time used
     pub cpu_time: u128,
+    /// CPU time used, scaled by the weight of the nice level, used to pick the next context
+    pub vruntime: u128,
+    /// Scheduling priority, lower values are scheduled more often
+    pub nice: Nice,
+    /// Real-time policy, taking precedence over the nice level
+    pub sched_policy: SchedPolicy,
     /// Scheduler CPU affinity. If set, [`cpu_id`] can except [`None`] never be anything else than
     /// this value.
     pub sched_affinity: LogicalCpuSet,
+    /// Group whose resource limits apply to this context, if any
+    pub group: Option<Arc<ContextGroup>>,
+    /// Memory group the user memory this context allocates is charged to, if any
+    pub memcg: Option<Arc<MemGroup>>,
     /// Keeps track of whether this context is currently handling a syscall. Only up-to-date when
     /// not running.
     pub inside_syscall: bool,
+    /// Hardware events counted while this context ran, up to its last context switch.
+    pub perf_counts: crate::perf::PerfCounts,
+    /// Number of nested IRQ handlers this context switched away from, for the lock validator.
+    /// Only up-to-date when not running.
+    #[cfg(debug_assertions)]
+    pub irq_depth: usize,
 
     #[cfg(feature = "syscall_debug")]
     pub syscall_debug_info: crate::syscall::debug::SyscallDebugInfo,
@@ -181,6 +210,33 @@ pub struct Context {
     pub userspace: bool,
     pub being_sigkilled: bool,
     pub fmap_ret: Option<Frame>,
+    /// Set for vfork children while they borrow the parent's address space. The parent waits on
+    /// this until the child replaces its address space or exits.
+    pub vfork_done: Option<Arc<WaitCondition>>,
+    /// Time in nanoseconds after which blocking calls to userspace schemes fail with ETIMEDOUT.
+    pub scheme_timeout: Option<u128>,
+    /// Whether address spaces this context creates have a randomized base for grants, which can be
+    /// disabled for debugging.
+    pub aslr: bool,
+    /// Whether the context may map pages both writable and executable despite `WX_POLICY=deny`,
+    /// as JITs without a separate writable mapping of their code do, see
+    /// [`check_wx`](super::memory::check_wx).
+    pub allow_wx: bool,
+    /// Privileged operations the context may currently perform, see [`caps`](super::caps).
+    pub caps: Capabilities,
+    /// Capabilities the context may get back, which it can only drop.
+    pub caps_permitted: Capabilities,
+    /// Resource limits
+    pub rlimits: Rlimits,
+    /// Memory placement policy for pages of user grants this context touches first, unless their
+    /// address space has a policy of its own.
+    pub mempolicy: MemPolicy,
+    /// Added to the badness of the context when the OOM killer picks a process to kill, from
+    /// [`OOM_SCORE_ADJ_MIN`](crate::memory::oom::OOM_SCORE_ADJ_MIN), which exempts it, to
+    /// [`OOM_SCORE_ADJ_MAX`](crate::memory::oom::OOM_SCORE_ADJ_MAX).
+    pub oom_score_adj: i16,
+    /// Resource usage, see [`super::stats::snapshot`] to read it.
+    pub stats: ContextStats,
 }
 
 #[derive(Debug)]
@@ -206,6 +262,7 @@ impl Context {
             pid,
             process,
             sig: None,
+            ksig: None,
             status: Status::HardBlocked {
                 reason: HardBlockedReason::NotYetStarted,
             },
@@ -214,8 +271,16 @@ impl Context {
             cpu_id: None,
             switch_time: 0,
             cpu_time: 0,
+            vruntime: 0,
+            nice: 0,
+            sched_policy: SchedPolicy::Normal,
             sched_affinity: LogicalCpuSet::all(),
+            group: None,
+            memcg: None,
             inside_syscall: false,
+            perf_counts: [0; crate::perf::EVENT_COUNT],
+            #[cfg(debug_assertions)]
+            irq_depth: 0,
             syscall_head: Some(RaiiFrame::allocate()?),
             syscall_tail: Some(RaiiFrame::allocate()?),
             wake: None,
@@ -224,10 +289,20 @@ impl Context {
             kstack: None,
             addr_space: None,
             name: Cow::Borrowed(""),
-            files: Arc::new(RwLock::new(Vec::new())),
+            files: Arc::try_new(RwLock::new(Vec::new())).map_err(|_| Error::new(ENOMEM))?,
             userspace: false,
             fmap_ret: None,
             being_sigkilled: false,
+            vfork_done: None,
+            scheme_timeout: None,
+            aslr: true,
+            allow_wx: false,
+            caps: Capabilities::all(),
+            caps_permitted: Capabilities::all(),
+            rlimits: Rlimits::new(),
+            mempolicy: MemPolicy::default(),
+            oom_score_adj: 0,
+            stats: ContextStats::default(),
 
             #[cfg(feature = "syscall_debug")]
             syscall_debug_info: crate::syscall::debug::SyscallDebugInfo::default(),
@@ -261,10 +336,14 @@ impl Context {
         if self.unblock_no_ipi() {
             // TODO: Only send IPI if currently running?
             if let Some(cpu_id) = self.cpu_id {
-                if cpu_id != crate::cpu_id() {
-                    // Send IPI if not on current CPU
+                // Preempt the context running there if this one has a higher priority, or
+                // otherwise send IPI if not on current CPU
+                if !switch::preempt_for(cpu_id, self) && cpu_id != crate::cpu_id() {
                     ipi(IpiKind::Wakeup, IpiTarget::Other);
                 }
+            } else if crate::timer::oneshot() {
+                // Idle CPUs no longer look for runnable contexts on every tick.
+                ipi(IpiKind::Wakeup, IpiTarget::Other);
             }
 
             true
@@ -273,6 +352,25 @@ impl Context {
         }
     }
 
+    /// Replace the scheduler affinity with `mask`.
+    ///
+    /// A context that last ran on a CPU which is no longer allowed is released from it, so that
+    /// an allowed CPU can pick it up. If it is still running there, that CPU is returned, and the
+    /// caller must make it reschedule; the context is then released when it is switched out.
+    pub fn set_sched_affinity(&mut self, mask: &RawMask) -> Option<LogicalCpuId> {
+        self.sched_affinity.override_from(mask);
+
+        let cpu_id = self.cpu_id?;
+        if self.sched_affinity.contains(cpu_id) {
+            None
+        } else if self.running {
+            Some(cpu_id)
+        } else {
+            self.cpu_id = None;
+            None
+        }
+    }
+
     /// Unblock context without IPI, and return true if it was blocked before being marked runnable
     pub fn unblock_no_ipi(&mut self) -> bool {
         if self.status.is_soft_blocked() {
@@ -291,18 +389,26 @@ impl Context {
         self.add_file_min(file, 0)
     }
 
+    /// Highest file descriptor number plus one that may be allocated
+    fn max_files(&self) -> usize {
+        self.rlimits
+            .soft(RLIMIT_NOFILE)
+            .min(super::CONTEXT_MAX_FILES)
+    }
+
     /// Add a file to the lowest available slot greater than or equal to min.
     /// Return the file descriptor number or None if no slot was found
     pub fn add_file_min(&self, file: FileDescriptor, min: usize) -> Option<FileHandle> {
+        let max_files = self.max_files();
         let mut files = self.files.write();
-        for (i, file_option) in files.iter_mut().enumerate() {
+        for (i, file_option) in files.iter_mut().enumerate().take(max_files) {
             if file_option.is_none() && i >= min {
                 *file_option = Some(file);
                 return Some(FileHandle::from(i));
             }
         }
         let len = files.len();
-        if len < super::CONTEXT_MAX_FILES {
+        if len < max_files {
             if len >= min {
                 files.push(Some(file));
                 Some(FileHandle::from(len))
@@ -328,10 +434,10 @@ impl Context {
     /// Insert a file with a specific handle number. This is used by dup2
     /// Return the file descriptor number or None if the slot was not empty, or i was invalid
     pub fn insert_file(&self, i: FileHandle, file: FileDescriptor) -> Option<FileHandle> {
-        let mut files = self.files.write();
-        if i.get() >= super::CONTEXT_MAX_FILES {
+        if i.get() >= self.max_files() {
             return None;
         }
+        let mut files = self.files.write();
         if i.get() >= files.len() {
             files.resize_with(i.get() + 1, || None);
         }
@@ -396,7 +502,7 @@ impl Context {
                 new_addrsp.used_by.atomic_set(this_percpu.cpu_id);
 
                 unsafe {
-                    new_addrsp.table.utable.make_current();
+                    crate::memory::asid::switch_to(new, &new_addrsp);
                 }
             } else {
                 unsafe {
@@ -407,6 +513,10 @@ impl Context {
             assert!(!self.running);
         }
 
+        if let Some(vfork_done) = self.vfork_done.take() {
+            vfork_done.notify();
+        }
+
         core::mem::replace(&mut self.addr_space, addr_space)
     }
 
@@ -545,6 +655,9 @@ impl Drop for BorrowedHtBuf {
     }
 }
 
+/// Size of kernel stacks, which are naturally aligned.
+pub const KSTACK_SIZE: usize = PAGE_SIZE << 4;
+
 pub struct Kstack {
     /// naturally aligned, order 4
     base: Frame,
@@ -556,10 +669,10 @@ impl Kstack {
         })
     }
     pub fn initial_top(&self) -> *mut u8 {
-        unsafe { (RmmA::phys_to_virt(self.base.base()).data() as *mut u8).add(PAGE_SIZE << 4) }
+        unsafe { (RmmA::phys_to_virt(self.base.base()).data() as *mut u8).add(KSTACK_SIZE) }
     }
     pub fn len(&self) -> usize {
-        PAGE_SIZE << 4
+        KSTACK_SIZE
     }
 }
 
@@ -0,0 +1,233 @@
+//! # User page faults
+//!
+//! A fault on user memory is first classified by what the address space holds at the faulting
+//! page, and then resolved by the handler of its [`FaultKind`]:
+//!
+//! - a write to a present page that is not writable breaks copy-on-write,
+//! - a page of an anonymous grant that was never touched is allocated, or mapped to the zeroed
+//!   frame if it is only read,
+//! - a page in the gap right below a growsdown grant extends it, see [`AddrSpace::grow_down`],
+//! - a page of a grant backed by a file or borrowed from another address space is filled from it,
+//! - a page that was swapped out is read back,
+//! - a page registered with a userfault handle is delegated to its handler,
+//! - and anything else, an address with no grant or an access the grant does not allow, is a
+//!   genuine violation.
+//!
+//! Violations raise SIGSEGV, with `SEGV_MAPERR` or `SEGV_ACCERR` and the faulting address passed
+//! to the handler, while memory that exists but cannot be brought in raises SIGBUS. When no frame
+//! is left, or the memory group of the context is at its limit, the faulting context reclaims what
+//! it can and retries, and only if nothing could be reclaimed is a process killed, see
+//! [`crate::memory::oom`].
+//!
+//! Faults are counted by kind, which `sys:fault` reads.
+
+use alloc::sync::Arc;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::{
+    memory::{memcg, reclaim},
+    paging::Page,
+    sync::{lockdep::Tracked, RwLockWriteGuard},
+    syscall::flag::{SIGBUS, SIGSEGV},
+};
+
+use super::{
+    huge_page,
+    memory::{correct_inner, AccessMode, AddrSpace, AddrSpaceWrapper, PfError, Provider},
+    signal::{FaultInfo, BUS_ADRERR, BUS_OBJERR, SEGV_ACCERR, SEGV_MAPERR},
+    userfault::{UserfaultEvent, USERFAULT_FLAG_WRITE},
+};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FaultKind {
+    CowBreak,
+    LazyAlloc,
+    StackGrowth,
+    FileFill,
+    SwapIn,
+    Userfault,
+    /// No grant covers the page.
+    MapError,
+    /// The grant covering the page does not allow the access.
+    AccessError,
+}
+
+impl FaultKind {
+    pub const ALL: [Self; 8] = [
+        Self::CowBreak,
+        Self::LazyAlloc,
+        Self::StackGrowth,
+        Self::FileFill,
+        Self::SwapIn,
+        Self::Userfault,
+        Self::MapError,
+        Self::AccessError,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::CowBreak => "cow_break",
+            Self::LazyAlloc => "lazy_alloc",
+            Self::StackGrowth => "stack_growth",
+            Self::FileFill => "file_fill",
+            Self::SwapIn => "swap_in",
+            Self::Userfault => "userfault",
+            Self::MapError => "map_error",
+            Self::AccessError => "access_error",
+        }
+    }
+
+    /// The number of faults of this kind so far.
+    pub fn count(self) -> usize {
+        COUNTS[self as usize].load(Ordering::Relaxed)
+    }
+}
+
+static COUNTS: [AtomicUsize; FaultKind::ALL.len()] =
+    [const { AtomicUsize::new(0) }; FaultKind::ALL.len()];
+
+/// Resolves a fault of some kind, with the address space of the faulting context locked.
+type Handler = for<'l> fn(
+    &'l Arc<AddrSpaceWrapper>,
+    Tracked<RwLockWriteGuard<'l, AddrSpace>>,
+    Page,
+    AccessMode,
+) -> Result<(), PfError>;
+
+/// The handler of each kind, in the order of [`FaultKind::ALL`].
+static HANDLERS: [Handler; FaultKind::ALL.len()] = [
+    populate, populate, grow_stack, populate, populate, delegate, violation, violation,
+];
+
+/// A fault that could not be resolved, and what it was.
+#[derive(Debug)]
+pub struct UnresolvedFault {
+    pub kind: FaultKind,
+    pub error: PfError,
+}
+
+impl UnresolvedFault {
+    /// The signal to raise in the faulting context, and why, for a fault at `addr`.
+    pub fn info(&self, addr: usize) -> FaultInfo {
+        let (sig, code) = match (&self.error, self.kind) {
+            (PfError::Segv, FaultKind::AccessError) => (SIGSEGV, SEGV_ACCERR),
+            (PfError::Segv, FaultKind::FileFill | FaultKind::SwapIn) => (SIGBUS, BUS_ADRERR),
+            (PfError::Segv, _) => (SIGSEGV, SEGV_MAPERR),
+            _ => (SIGBUS, BUS_OBJERR),
+        };
+        FaultInfo { sig, code, addr }
+    }
+}
+
+/// Classify a fault at `page`, which `addr_space` must be the locked address space of.
+pub fn classify(addr_space: &AddrSpace, page: Page, access: AccessMode) -> FaultKind {
+    let Some((_, info)) = addr_space.grants.contains(page) else {
+        return if addr_space.grows_down_to(page).is_some() {
+            FaultKind::StackGrowth
+        } else {
+            FaultKind::MapError
+        };
+    };
+    let flags = info.flags();
+    if (access == AccessMode::Write && !flags.has_write())
+        || (access == AccessMode::InstrFetch && !flags.has_execute())
+    {
+        return FaultKind::AccessError;
+    }
+    if addr_space.userfault_handler(page).is_some() {
+        return FaultKind::Userfault;
+    }
+    if addr_space.grants.swapped.contains(page) {
+        return FaultKind::SwapIn;
+    }
+    let present = huge_page::translate(&addr_space.table.utable, page.start_address()).is_some();
+    if present && access == AccessMode::Write {
+        return FaultKind::CowBreak;
+    }
+    match info.provider {
+        Provider::Allocated {
+            cow_file_ref: None, ..
+        }
+        | Provider::AllocatedShared { .. } => FaultKind::LazyAlloc,
+        _ => FaultKind::FileFill,
+    }
+}
+
+/// Resolve a fault of the current context at `page`, returning what it was.
+pub fn handle(page: Page, access: AccessMode) -> Result<FaultKind, UnresolvedFault> {
+    let Ok(addr_space_lock) = AddrSpace::current() else {
+        log::debug!("User page fault without address space being set.");
+        return Err(UnresolvedFault {
+            kind: FaultKind::MapError,
+            error: PfError::Segv,
+        });
+    };
+
+    let guard = addr_space_lock.acquire_write();
+    let kind = classify(&guard, page, access);
+    COUNTS[kind as usize].fetch_add(1, Ordering::Relaxed);
+
+    // Memory is reclaimed with the address space no longer locked, so that its pages can be
+    // evicted too, and the access is then simply retried.
+    match HANDLERS[kind as usize](&addr_space_lock, guard, page, access) {
+        Ok(()) => Ok(kind),
+        Err(PfError::MemLimit) if memcg::reclaim_for_current() => Ok(kind),
+        Err(PfError::Oom) if reclaim::reclaim_direct() => Ok(kind),
+        Err(error) => Err(UnresolvedFault { kind, error }),
+    }
+}
+
+fn populate<'l>(
+    addr_space_lock: &'l Arc<AddrSpaceWrapper>,
+    guard: Tracked<RwLockWriteGuard<'l, AddrSpace>>,
+    page: Page,
+    access: AccessMode,
+) -> Result<(), PfError> {
+    let (_, flush, _) = correct_inner(addr_space_lock, guard, page, access, 0)?;
+    flush.flush();
+    Ok(())
+}
+
+fn grow_stack<'l>(
+    addr_space_lock: &'l Arc<AddrSpaceWrapper>,
+    mut guard: Tracked<RwLockWriteGuard<'l, AddrSpace>>,
+    page: Page,
+    access: AccessMode,
+) -> Result<(), PfError> {
+    guard.grow_down(page);
+    populate(addr_space_lock, guard, page, access)
+}
+
+fn delegate<'l>(
+    _addr_space_lock: &'l Arc<AddrSpaceWrapper>,
+    guard: Tracked<RwLockWriteGuard<'l, AddrSpace>>,
+    page: Page,
+    access: AccessMode,
+) -> Result<(), PfError> {
+    let Some(userfault) = guard.userfault_handler(page) else {
+        return Err(PfError::NonfatalInternalError);
+    };
+    userfault.events.send(UserfaultEvent {
+        address: page.start_address().data(),
+        flags: if access == AccessMode::Write {
+            USERFAULT_FLAG_WRITE
+        } else {
+            0
+        },
+    });
+    // The handler needs the address space lock to resolve the fault, so it cannot have been
+    // resolved before we start waiting. Once woken up, the access is simply retried.
+    if !userfault.resolved.wait(guard, "userfault") {
+        return Err(PfError::Interrupted);
+    }
+    Ok(())
+}
+
+fn violation<'l>(
+    _addr_space_lock: &'l Arc<AddrSpaceWrapper>,
+    _guard: Tracked<RwLockWriteGuard<'l, AddrSpace>>,
+    _page: Page,
+    _access: AccessMode,
+) -> Result<(), PfError> {
+    Err(PfError::Segv)
+}
@@ -3,10 +3,10 @@
 use crate::{
     event,
     scheme::{self, SchemeId},
+    sync::RwLock,
     syscall::error::{Error, Result, EBADF},
 };
 use alloc::sync::Arc;
-use spin::RwLock;
 use syscall::{schemev2::NewFdFlags, RwFlags, O_APPEND, O_NONBLOCK};
 
 /// A file description
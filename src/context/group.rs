@@ -0,0 +1,116 @@
+//! Context groups, which allow resource limits to be shared by a set of contexts.
+//!
+//! A context starts out without a group. Setting a limit on a context places it in a fresh group,
+//! which is then inherited by every thread or child process it creates.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use alloc::sync::Arc;
+
+use crate::syscall::error::{Error, Result, EINVAL, ENOMEM};
+
+/// A set of contexts sharing resource limits.
+#[derive(Debug, Default)]
+pub struct ContextGroup {
+    pub cpu: CpuBandwidth,
+}
+
+impl ContextGroup {
+    pub fn new() -> Result<Arc<Self>> {
+        Arc::try_new(Self::default()).map_err(|_| Error::new(ENOMEM))
+    }
+}
+
+/// CPU bandwidth limit, in the style of cgroup `cpu.max`. The contexts in a group may in total run
+/// for at most `quota` nanoseconds every `period` nanoseconds, after which the scheduler skips
+/// them until the next period begins.
+///
+/// Everything is atomic rather than locked, since the scheduler checks and charges the group with
+/// interrupts disabled, and must never wait for a CPU that is changing the limit.
+#[derive(Debug)]
+pub struct CpuBandwidth {
+    quota: AtomicU64,
+    period: AtomicU64,
+    period_start: AtomicU64,
+    used: AtomicU64,
+}
+
+/// Quota meaning the group is not limited.
+pub const QUOTA_MAX: u64 = u64::MAX;
+/// Default period, 100 ms, same as Linux.
+pub const DEFAULT_PERIOD: u64 = 100_000_000;
+// Shorter periods than this would be below the granularity of the scheduler tick.
+const MIN_PERIOD: u64 = 1_000_000;
+
+impl Default for CpuBandwidth {
+    fn default() -> Self {
+        Self {
+            quota: AtomicU64::new(QUOTA_MAX),
+            period: AtomicU64::new(DEFAULT_PERIOD),
+            period_start: AtomicU64::new(0),
+            used: AtomicU64::new(0),
+        }
+    }
+}
+
+impl CpuBandwidth {
+    /// Returns `(quota, period)` in nanoseconds.
+    pub fn limit(&self) -> (u64, u64) {
+        (
+            self.quota.load(Ordering::Relaxed),
+            self.period.load(Ordering::Relaxed),
+        )
+    }
+    pub fn set_limit(&self, quota: u64, period: u64) -> Result<()> {
+        if period < MIN_PERIOD || quota == 0 {
+            return Err(Error::new(EINVAL));
+        }
+        self.period.store(period, Ordering::Relaxed);
+        self.quota.store(quota, Ordering::Relaxed);
+        self.used.store(0, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Start a new period if the current one has elapsed.
+    fn refresh(&self, now: u64) {
+        let period = self.period.load(Ordering::Relaxed);
+        let start = self.period_start.load(Ordering::Acquire);
+        if now.saturating_sub(start) < period {
+            return;
+        }
+        let new_start = now - (now - start) % period;
+
+        // Only one CPU gets to reset the usage.
+        if self
+            .period_start
+            .compare_exchange(start, new_start, Ordering::AcqRel, Ordering::Relaxed)
+            .is_ok()
+        {
+            self.used.store(0, Ordering::Release);
+        }
+    }
+
+    /// Account `ran` nanoseconds of CPU time to the group.
+    pub fn charge(&self, ran: u64, now: u64) {
+        if self.quota.load(Ordering::Relaxed) == QUOTA_MAX {
+            return;
+        }
+        self.refresh(now);
+        self.used.fetch_add(ran, Ordering::AcqRel);
+    }
+
+    /// When the current period ends, and the group may run again if throttled.
+    pub fn period_end(&self) -> u64 {
+        self.period_start.load(Ordering::Acquire) + self.period.load(Ordering::Relaxed)
+    }
+
+    /// Whether the group has used up its quota for the current period.
+    pub fn is_throttled(&self, now: u64) -> bool {
+        let quota = self.quota.load(Ordering::Relaxed);
+        if quota == QUOTA_MAX {
+            return false;
+        }
+        self.refresh(now);
+        self.used.load(Ordering::Acquire) >= quota
+    }
+}
@@ -0,0 +1,410 @@
+//! Transparent huge pages in user address spaces.
+//!
+//! Private anonymous grants are mapped with a 2 MiB page when a write fault hits a 2 MiB aligned
+//! range that lies entirely within the grant and has nothing mapped yet, while read faults map the
+//! zeroed frame. Physically contiguous grants are mapped with the largest pages allowed by the
+//! alignment of both addresses when they are created, including 1 GiB pages if the CPU supports
+//! them. Every frame of a huge page keeps its own `PageInfo`, exactly as if it was mapped with
+//! 4 KiB pages. Forking shares anonymous huge pages copy-on-write, with a reference to each of
+//! their frames, and otherwise all of them are `RefCount::One`.
+//!
+//! Nothing but this module and [`table_share`] understand huge leaf entries, and in particular the
+//! `rmm` page mapper assumes every user mapping is a 4 KiB page. The huge pages overlapping a
+//! range are therefore split into tables of smaller pages by [`table_share::unshare`], which must
+//! precede every modification of user page tables anyway, including the first write to a huge
+//! page shared by forking. Unmapping entire grants removes their huge pages as a whole.
+//!
+//! [`table_share`]: super::table_share
+//! [`table_share::unshare`]: super::table_share::unshare
+
+use core::{
+    num::NonZeroUsize,
+    ops::Range,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use spin::Once;
+
+use crate::{
+    memory::{
+        allocate_frame, allocate_p2frame_complex, get_page_info, memcg, Enomem, Frame, RefCount,
+    },
+    numa::NodeHint,
+    paging::{
+        Page, PageFlags, PageMapper, PhysicalAddress, RmmA, RmmArch, VirtualAddress, ENTRY_COUNT,
+        PAGE_SIZE,
+    },
+};
+
+use super::{
+    memory::{GenericFlusher, PageSpan, TlbShootdownActions, ENTRY_FLAG_HUGE},
+    table_share::{entries, entry_frame, entry_pages, entry_span, is_present},
+};
+
+/// Whether user mappings can use huge pages. Other architectures encode large page entries
+/// differently, and are not supported yet.
+pub const SUPPORTED: bool = cfg!(target_arch = "x86_64");
+
+// Set in the entries of user tables, along with the present and writable bits, so that only the
+// leaf entries restrict access.
+const ENTRY_FLAG_USER: usize = 1 << 2;
+const TABLE_FLAGS: usize = RmmA::ENTRY_FLAG_PRESENT | RmmA::ENTRY_FLAG_READWRITE | ENTRY_FLAG_USER;
+
+const TOP_LEVEL: usize = RmmA::PAGE_LEVELS - 1;
+
+/// Level of the tables containing 2 MiB entries, the smallest huge pages.
+const HUGE_LEVEL: usize = 1;
+
+static MAX_LEVEL: Once<usize> = Once::new();
+
+/// The highest level of tables in which huge pages can be mapped, or 0 if none.
+fn max_level() -> usize {
+    *MAX_LEVEL.call_once(|| {
+        #[cfg(target_arch = "x86_64")]
+        {
+            let has_1gib_pages = crate::cpuid::cpuid()
+                .get_extended_processor_and_feature_identifiers()
+                .map_or(false, |info| info.has_1gib_pages());
+            if has_1gib_pages {
+                HUGE_LEVEL + 1
+            } else {
+                HUGE_LEVEL
+            }
+        }
+        #[cfg(not(target_arch = "x86_64"))]
+        {
+            0
+        }
+    })
+}
+
+pub(super) fn is_huge(entry: usize) -> bool {
+    SUPPORTED && is_present(entry) && entry & ENTRY_FLAG_HUGE != 0
+}
+
+fn index(address: usize, level: usize) -> usize {
+    (address / entry_span(level)) % ENTRY_COUNT
+}
+
+/// The alignment for new grants of `size` bytes, so that they can be mapped with the largest
+/// huge pages not exceeding their size.
+pub fn alignment(size: usize) -> usize {
+    (HUGE_LEVEL..=max_level())
+        .rev()
+        .map(entry_span)
+        .find(|&span| span <= size)
+        .unwrap_or(PAGE_SIZE)
+}
+
+/// Like [`PageMapper::translate`], but also handling huge pages. Returns the address of the frame
+/// containing `virt`.
+pub fn translate(mapper: &PageMapper, virt: VirtualAddress) -> Option<PhysicalAddress> {
+    if let Some((entry, level)) = huge_entry(mapper, virt.data()) {
+        let offset = virt.data() % entry_span(level) / PAGE_SIZE * PAGE_SIZE;
+        return Some(entry_frame(entry).base().add(offset));
+    }
+    mapper.translate(virt).map(|(phys, _)| phys)
+}
+
+/// Whether `virt` is mapped by a huge page.
+pub fn is_mapped_huge(mapper: &PageMapper, virt: VirtualAddress) -> bool {
+    huge_entry(mapper, virt.data()).is_some()
+}
+
+/// The huge page entry mapping `address`, along with the level of its table.
+fn huge_entry(mapper: &PageMapper, address: usize) -> Option<(usize, usize)> {
+    if !SUPPORTED {
+        return None;
+    }
+    let mut table = Frame::containing(mapper.table().phys());
+    for level in (HUGE_LEVEL..=TOP_LEVEL).rev() {
+        let entry = entries(table)[index(address, level)].load(Ordering::Relaxed);
+        if !is_present(entry) {
+            return None;
+        }
+        if is_huge(entry) {
+            return Some((entry, level));
+        }
+        table = entry_frame(entry);
+    }
+    None
+}
+
+/// The slot for the entry mapping `address` in the table at `level`, creating the tables leading
+/// to it if necessary. Returns `None` if a huge page already covers `address` at a higher level,
+/// or a table cannot be allocated.
+unsafe fn slot_or_create(
+    mapper: &mut PageMapper,
+    address: usize,
+    level: usize,
+) -> Option<&'static AtomicUsize> {
+    let mut table = Frame::containing(mapper.table().phys());
+    for table_level in (level + 1..=TOP_LEVEL).rev() {
+        let slot = &entries(table)[index(address, table_level)];
+        let mut entry = slot.load(Ordering::Relaxed);
+        if !is_present(entry) {
+            entry = allocate_frame()?.base().data() | TABLE_FLAGS;
+            slot.store(entry, Ordering::Relaxed);
+        } else if is_huge(entry) {
+            return None;
+        }
+        table = entry_frame(entry);
+    }
+    Some(&entries(table)[index(address, level)])
+}
+
+/// Map a zeroed 2 MiB page containing `page`, if it lies entirely within `grant_span` and nothing
+/// in its range is mapped yet. Returns the frame now mapped at `page`, or `None` if the caller has
+/// to map a single page instead.
+pub fn try_map_zeroed(
+    mapper: &mut PageMapper,
+    grant_span: PageSpan,
+    page: Page,
+    flags: PageFlags<RmmA>,
+    hint: Option<NodeHint>,
+) -> Option<Frame> {
+    if max_level() < HUGE_LEVEL {
+        return None;
+    }
+    let span = entry_span(HUGE_LEVEL);
+    let base = page.start_address().data() / span * span;
+    let huge_span = PageSpan::new(
+        Page::containing_address(VirtualAddress::new(base)),
+        span / PAGE_SIZE,
+    );
+    if huge_span.base < grant_span.base || huge_span.end() > grant_span.end() {
+        return None;
+    }
+
+    let slot = unsafe { slot_or_create(mapper, base, HUGE_LEVEL)? };
+    if is_present(slot.load(Ordering::Relaxed)) {
+        return None;
+    }
+
+    // If the group is at its limit, the fault falls back to a single page, which may reclaim.
+    let memcg = memcg::charge_current(huge_span.count).ok()?;
+    // The allocator zeroes the frames.
+    let order = RmmA::PAGE_ENTRY_SHIFT as u32;
+    let Some((frame, _)) = allocate_p2frame_complex(order, (), hint, order) else {
+        memcg::uncharge(memcg, huge_span.count);
+        return None;
+    };
+    // Tagged even if not charged, as the huge page may be split and freed page by page.
+    memcg::tag(frame, order, memcg);
+    for i in 0..huge_span.count {
+        get_page_info(frame.next_by(i))
+            .expect("PageInfo must exist for allocated frame")
+            .refcount
+            .store(RefCount::One.to_raw(), Ordering::Relaxed);
+    }
+    slot.store(
+        frame.base().data() | flags.data() | ENTRY_FLAG_HUGE,
+        Ordering::Relaxed,
+    );
+
+    Some(frame.next_by(page.offset_from(huge_span.base)))
+}
+
+/// Map the largest huge page starting at `page`, backed by the physically contiguous frames
+/// starting at `frame`, that fits in `max_count` pages. Returns the number of pages mapped, or
+/// `None` if the caller has to map a single page instead.
+///
+/// # Safety
+///
+/// The frames must stay valid until unmapped, and the range of the huge page must not be mapped.
+pub unsafe fn try_map_contiguous(
+    mapper: &mut PageMapper,
+    page: Page,
+    frame: Frame,
+    max_count: usize,
+    flags: PageFlags<RmmA>,
+) -> Option<NonZeroUsize> {
+    // On x86, the flag selects the PAT entry in 4 KiB pages, which is a different bit in huge
+    // pages.
+    if flags.data() & ENTRY_FLAG_HUGE != 0 {
+        return None;
+    }
+    let virt = page.start_address().data();
+    let phys = frame.base().data();
+
+    let level = (HUGE_LEVEL..=max_level()).rev().find(|&level| {
+        let span = entry_span(level);
+        virt % span == 0 && phys % span == 0 && max_count * PAGE_SIZE >= span
+    })?;
+
+    let slot = slot_or_create(mapper, virt, level)?;
+    if is_present(slot.load(Ordering::Relaxed)) {
+        return None;
+    }
+    slot.store(phys | flags.data() | ENTRY_FLAG_HUGE, Ordering::Relaxed);
+
+    NonZeroUsize::new(entry_span(level) / PAGE_SIZE)
+}
+
+/// Replace the huge pages overlapping `span` by tables of smaller pages mapping the same frames,
+/// so that only 4 KiB pages are mapped within `span`. The tables covering `span` must already be
+/// exclusive to the address space of `mapper`.
+pub fn split(
+    mapper: &mut PageMapper,
+    span: PageSpan,
+    flusher: &mut impl GenericFlusher,
+) -> Result<(), Enomem> {
+    if !SUPPORTED || span.is_empty() {
+        return Ok(());
+    }
+    let top = Frame::containing(mapper.table().phys());
+    let start = span.base.start_address().data();
+    let end = span.end().start_address().data();
+
+    unsafe { split_range(top, TOP_LEVEL, 0, start..end, flusher) }
+}
+
+unsafe fn split_range(
+    table: Frame,
+    level: usize,
+    table_base: usize,
+    range: Range<usize>,
+    flusher: &mut impl GenericFlusher,
+) -> Result<(), Enomem> {
+    let span = entry_span(level);
+    let first = (range.start - table_base) / span;
+    let last = (range.end - 1 - table_base) / span;
+
+    for (i, slot) in entries(table).iter().enumerate().take(last + 1).skip(first) {
+        let mut entry = slot.load(Ordering::Relaxed);
+        if !is_present(entry) {
+            continue;
+        }
+        let base = table_base + i * span;
+
+        if is_huge(entry) {
+            entry = split_entry(slot, level).ok_or(Enomem)?;
+            flusher.queue(
+                entry_pages(base, level),
+                entry_frame(entry),
+                None,
+                TlbShootdownActions::SPLIT,
+            );
+        }
+        if level > 1 {
+            let sub_range = range.start.max(base)..range.end.min(base + span);
+            split_range(entry_frame(entry), level - 1, base, sub_range, flusher)?;
+        }
+    }
+    Ok(())
+}
+
+/// Replace the huge page entry in `slot`, of a table at `level`, by a table of entries one level
+/// below mapping the same range. Returns the new entry.
+unsafe fn split_entry(slot: &AtomicUsize, level: usize) -> Option<usize> {
+    let table = allocate_frame()?;
+
+    let entry = slot.load(Ordering::Relaxed);
+    let phys = entry & RmmA::ENTRY_ADDRESS_MASK;
+    let mut flags = entry & !RmmA::ENTRY_ADDRESS_MASK;
+    if level - 1 < HUGE_LEVEL {
+        flags &= !ENTRY_FLAG_HUGE;
+    }
+    let sub_span = entry_span(level - 1);
+    for (i, sub_slot) in entries(table).iter().enumerate() {
+        sub_slot.store((phys + i * sub_span) | flags, Ordering::Relaxed);
+    }
+
+    let new_entry = table.base().data() | TABLE_FLAGS;
+    slot.store(new_entry, Ordering::Relaxed);
+    Some(new_entry)
+}
+
+/// Remove the huge pages within `span`, which must not extend beyond it. If `free` is set, the
+/// references held by their frames are dropped once flushed, otherwise the caller takes them over.
+/// Tables left empty are released through `flusher`. Returns the frame that was mapped at the
+/// start of `span`, if it was part of a huge page.
+pub fn unmap(
+    mapper: &mut PageMapper,
+    span: PageSpan,
+    free: bool,
+    flusher: &mut impl GenericFlusher,
+) -> Option<Frame> {
+    if !SUPPORTED || span.is_empty() {
+        return None;
+    }
+    let top = Frame::containing(mapper.table().phys());
+    let start = span.base.start_address().data();
+    let end = span.end().start_address().data();
+
+    let mut first = None;
+    unsafe { unmap_range(top, TOP_LEVEL, 0, start..end, free, flusher, &mut first) };
+    first
+}
+
+/// Returns whether mappings were removed from `table`, which no longer contains any.
+unsafe fn unmap_range(
+    table: Frame,
+    level: usize,
+    table_base: usize,
+    range: Range<usize>,
+    free: bool,
+    flusher: &mut impl GenericFlusher,
+    first: &mut Option<Frame>,
+) -> bool {
+    let span = entry_span(level);
+    let start = range.start;
+    let index_first = (range.start - table_base) / span;
+    let index_last = (range.end - 1 - table_base) / span;
+    let mut removed = false;
+
+    for (i, slot) in entries(table)
+        .iter()
+        .enumerate()
+        .take(index_last + 1)
+        .skip(index_first)
+    {
+        let entry = slot.load(Ordering::Relaxed);
+        if !is_present(entry) {
+            continue;
+        }
+        let base = table_base + i * span;
+
+        if is_huge(entry) {
+            debug_assert!(
+                range.start <= base && base + span <= range.end,
+                "huge page extends beyond the unmapped range"
+            );
+            slot.store(0, Ordering::Relaxed);
+
+            let frame = entry_frame(entry);
+            if base == start {
+                *first = Some(frame);
+            }
+            // Physically borrowed memory is not reference counted.
+            if free && get_page_info(frame).is_some() {
+                flusher.queue(
+                    entry_pages(base, level),
+                    frame,
+                    NonZeroUsize::new(span / PAGE_SIZE),
+                    TlbShootdownActions::FREE,
+                );
+            } else {
+                flusher.queue(
+                    entry_pages(base, level),
+                    frame,
+                    None,
+                    TlbShootdownActions::MOVE,
+                );
+            }
+            removed = true;
+        } else if level > HUGE_LEVEL {
+            let sub_range = range.start.max(base)..range.end.min(base + span);
+            let next = entry_frame(entry);
+            if unmap_range(next, level - 1, base, sub_range, free, flusher, first) {
+                slot.store(0, Ordering::Relaxed);
+                flusher.queue_table_release(next, level - 1);
+                removed = true;
+            }
+        }
+    }
+    removed
+        && entries(table)
+            .iter()
+            .all(|slot| !is_present(slot.load(Ordering::Relaxed)))
+}
@@ -1,31 +1,237 @@
-use alloc::{collections::BTreeMap, sync::Arc, vec::Vec};
+use alloc::{
+    collections::{BTreeMap, BTreeSet},
+    sync::Arc,
+    vec::Vec,
+};
 use arrayvec::ArrayVec;
 use core::{
     cmp,
     fmt::Debug,
     num::NonZeroUsize,
-    sync::atomic::{AtomicU32, Ordering},
+    str,
+    sync::atomic::{AtomicU32, AtomicU64, AtomicU8, AtomicUsize, Ordering},
 };
 use rmm::{Arch as _, PageFlush};
-use spin::{RwLock, RwLockReadGuard, RwLockUpgradableGuard, RwLockWriteGuard};
 use syscall::{error::*, flag::MapFlags, GrantFlags, MunmapFlags};
 
 use crate::{
     arch::paging::PAGE_SIZE,
+    common::try_alloc::{try_collect, try_push},
     context::arch::setup_new_utable,
     cpu_set::LogicalCpuSet,
+    entropy,
     memory::{
-        deallocate_frame, deallocate_p2frame, get_page_info, init_frame, the_zeroed_frame,
+        asid::AsidState,
+        deallocate_frame, deallocate_p2frame, deallocate_p2frame_batched, get_page_info,
+        init_user_frame,
+        ksm::{self, KsmState},
+        memcg,
+        swap::{self, SwapMap},
+        the_zeroed_frame,
+        writeback::DirtyRange,
         AddRefError, Enomem, Frame, PageInfo, RaiiFrame, RefCount, RefKind,
     },
-    paging::{Page, PageFlags, PageMapper, RmmA, TableKind, VirtualAddress},
-    percpu::PercpuBlock,
+    numa::{self, HomeNode, MemPolicy, NodeHint},
+    paging::{Page, PageFlags, PageMapper, PhysicalAddress, RmmA, TableKind, VirtualAddress},
+    percpu::{percpu, PercpuBlock, TlbShootdown},
     scheme::{self, KernelSchemes},
+    sync::{
+        lockdep::{LockClass, LockMode, Tracked},
+        RwLock, RwLockReadGuard, RwLockUpgradableGuard, RwLockWriteGuard,
+    },
+    syscall::usercopy::UserSliceRo,
 };
 
-use super::{context::HardBlockedReason, file::FileDescription};
+use super::{
+    context::HardBlockedReason, file::FileDescription, huge_page, rlimit, table_share,
+    userfault::Userfault,
+};
 
 pub const MMAP_MIN_DEFAULT: usize = PAGE_SIZE;
+/// Bits of randomness in the base of grants placed by the kernel, as a number of pages. At most a
+/// quarter of the user address space is used, which leaves fewer bits where it is small.
+#[cfg(target_pointer_width = "64")]
+const MMAP_RANDOM_BITS: u32 = 28;
+#[cfg(target_pointer_width = "32")]
+const MMAP_RANDOM_BITS: u32 = 8;
+
+pub struct CowStats {
+    /// Pages shared copy-on-write when copying mappings, e.g. by fork.
+    pub shared: AtomicUsize,
+    /// Copy-on-write pages that had to be copied when written to,
+    pub copied: AtomicUsize,
+    /// and those that were no longer shared, and could be written to directly.
+    pub reused: AtomicUsize,
+    /// Private pages mapped to the zeroed frame when first read, rather than allocated.
+    pub zero_mapped: AtomicUsize,
+}
+impl CowStats {
+    /// The counts of all CPUs added up, in the order of the fields.
+    pub fn total() -> [usize; 4] {
+        COW_STATS
+            .iter()
+            .fold([0; 4], |[shared, copied, reused, zero_mapped], stats| {
+                [
+                    shared + stats.shared.load(Ordering::Relaxed),
+                    copied + stats.copied.load(Ordering::Relaxed),
+                    reused + stats.reused.load(Ordering::Relaxed),
+                    zero_mapped + stats.zero_mapped.load(Ordering::Relaxed),
+                ]
+            })
+    }
+}
+percpu! {
+    /// Counted per CPU, as page faults on all of them update it.
+    pub static COW_STATS: CowStats = CowStats {
+        shared: AtomicUsize::new(0),
+        copied: AtomicUsize::new(0),
+        reused: AtomicUsize::new(0),
+        zero_mapped: AtomicUsize::new(0),
+    };
+}
+
+// Set by the MMU in leaf entries when the page is written to. Zero where dirty bits are either
+// missing or managed in software.
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+const ENTRY_FLAG_DIRTY: usize = crate::paging::entry::EntryFlags::DIRTY.bits();
+#[cfg(not(any(target_arch = "x86", target_arch = "x86_64")))]
+const ENTRY_FLAG_DIRTY: usize = 0;
+
+/// Whether the MMU tracks which pages were written to.
+pub fn has_dirty_bits() -> bool {
+    ENTRY_FLAG_DIRTY != 0
+}
+
+// Set by the MMU in leaf entries when the page is accessed, used to find pages worth keeping when
+// swapping out. Zero where accessed bits are either missing or managed in software.
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+const ENTRY_FLAG_ACCESSED: usize = crate::paging::entry::EntryFlags::ACCESSED.bits();
+#[cfg(not(any(target_arch = "x86", target_arch = "x86_64")))]
+const ENTRY_FLAG_ACCESSED: usize = 0;
+
+// Ignored by the MMU. Set in leaf entries of pages marked with MADV_FREE, which reclaim may drop
+// for as long as they stay clean. Zero without dirty bits, where such pages are dropped right away.
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+const ENTRY_FLAG_LAZYFREE: usize = 1 << 10;
+#[cfg(not(any(target_arch = "x86", target_arch = "x86_64")))]
+const ENTRY_FLAG_LAZYFREE: usize = 0;
+
+// Set in intermediate entries that map a large page rather than a table.
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub(super) const ENTRY_FLAG_HUGE: usize = crate::paging::entry::EntryFlags::HUGE_PAGE.bits();
+#[cfg(not(any(target_arch = "x86", target_arch = "x86_64")))]
+pub(super) const ENTRY_FLAG_HUGE: usize = 0;
+/// Maximum number of pages a growsdown grant can be extended by, in a single fault.
+pub const GROWSDOWN_MAX_GAP: usize = 256;
+/// Number of unmapped pages that must remain between a growsdown grant and the grant below it.
+pub const GROWSDOWN_GUARD_PAGES: usize = 1;
+/// Default size of the region reserved below stack grants for them to grow into, in pages.
+pub const STACK_WINDOW_DEFAULT: usize = (8 * 1024 * 1024) / PAGE_SIZE;
+/// Maximum length in bytes of a grant label.
+pub const GRANT_LABEL_MAX: usize = 32;
+
+/// Operation of `proc:` address space handles, followed by the address, the length and one of the
+/// `MADV_*` values.
+pub const ADDRSPACE_OP_MADVISE: usize = 5;
+
+/// Read in the pages that would be read in on their first fault, from swap or from their file.
+pub const MADV_WILLNEED: usize = 3;
+/// Drop the pages of private anonymous grants, which read as zeroes again once touched.
+pub const MADV_DONTNEED: usize = 4;
+/// Let reclaim drop the pages of private anonymous grants that are not written to in the meantime.
+pub const MADV_FREE: usize = 8;
+/// Allow faults to map private anonymous grants with huge pages, which is the default.
+pub const MADV_HUGEPAGE: usize = 14;
+/// Forbid faults to map private anonymous grants with huge pages.
+pub const MADV_NOHUGEPAGE: usize = 15;
+
+/// Operations of `proc:` address space handles, followed by the address and the length.
+pub const ADDRSPACE_OP_MLOCK: usize = 6;
+pub const ADDRSPACE_OP_MUNLOCK: usize = 7;
+/// Map private anonymous grants as tagged memory, the equivalent of `PROT_MTE`, see
+/// [`crate::mte`].
+#[cfg(target_arch = "aarch64")]
+pub const ADDRSPACE_OP_MTE: usize = 8;
+/// Make writable pages executable instead, see [`AddrSpaceWrapper::make_executable`].
+pub const ADDRSPACE_OP_MKEXEC: usize = 9;
+
+/// How pages mapped both writable and executable are handled, set at boot with `WX_POLICY=allow`,
+/// `audit` or `deny` in the environment.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[repr(u8)]
+enum WxPolicy {
+    Allow,
+    /// Allowed, but logged.
+    Audit,
+    /// Refused and logged, unless the context mapping them has
+    /// [`allow_wx`](crate::context::Context::allow_wx) set.
+    Deny,
+}
+
+static WX_POLICY: AtomicU8 = AtomicU8::new(WxPolicy::Allow as u8);
+
+pub fn init_wx_policy(env: &[u8]) {
+    for line in str::from_utf8(env).unwrap_or("").lines() {
+        let mut parts = line.splitn(2, '=');
+        let name = parts.next().unwrap_or("");
+        let value = parts.next().unwrap_or("");
+
+        if name == "WX_POLICY" {
+            let policy = match value {
+                "allow" => WxPolicy::Allow,
+                "audit" => WxPolicy::Audit,
+                "deny" => WxPolicy::Deny,
+                _ => {
+                    log::warn!("Invalid W^X policy {:?}", value);
+                    continue;
+                }
+            };
+            WX_POLICY.store(policy as u8, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Check pages the current context maps or changes to `flags` at `base` against the W^X policy.
+/// `op` names the operation in the log.
+pub fn check_wx(op: &str, base: Page, flags: MapFlags) -> Result<()> {
+    if !flags.contains(MapFlags::PROT_WRITE | MapFlags::PROT_EXEC) {
+        return Ok(());
+    }
+    let policy = match WX_POLICY.load(Ordering::Relaxed) {
+        policy if policy == WxPolicy::Audit as u8 => WxPolicy::Audit,
+        policy if policy == WxPolicy::Deny as u8 => WxPolicy::Deny,
+        _ => return Ok(()),
+    };
+
+    let context = crate::context::current();
+    let context = context.read();
+    if policy == WxPolicy::Deny && context.allow_wx {
+        return Ok(());
+    }
+    let denied = policy == WxPolicy::Deny;
+    log::warn!(
+        "{}: {} writable and executable pages at {:#x} for {} (pid {})",
+        op,
+        if denied { "denied" } else { "mapped" },
+        base.start_address().data(),
+        context.name,
+        context.pid.get(),
+    );
+    if denied {
+        Err(Error::new(EACCES))
+    } else {
+        Ok(())
+    }
+}
+
+/// A random base for grants without a fixed address, page aligned and above [`MMAP_MIN_DEFAULT`].
+fn random_mmap_base() -> usize {
+    let pages = cmp::min(
+        1 << MMAP_RANDOM_BITS,
+        crate::USER_END_OFFSET / PAGE_SIZE / 4,
+    );
+    MMAP_MIN_DEFAULT + (entropy::random_u64() as usize % pages) * PAGE_SIZE
+}
 
 pub fn page_flags(flags: MapFlags) -> PageFlags<RmmA> {
     PageFlags::new()
@@ -34,6 +240,19 @@ pub fn page_flags(flags: MapFlags) -> PageFlags<RmmA> {
         .write(flags.contains(MapFlags::PROT_WRITE))
     //TODO: PROT_READ
 }
+/// Mark `flags` as guarded pages if they are executable and `bti` is set.
+fn guard_flags(flags: PageFlags<RmmA>, bti: bool) -> PageFlags<RmmA> {
+    #[cfg(target_arch = "aarch64")]
+    {
+        use crate::paging::entry::EntryFlags;
+        flags.custom_flag(EntryFlags::GUARDED.bits(), bti && flags.has_execute())
+    }
+    #[cfg(not(target_arch = "aarch64"))]
+    {
+        let _ = bti;
+        flags
+    }
+}
 pub fn map_flags(page_flags: PageFlags<RmmA>) -> MapFlags {
     let mut flags = MapFlags::PROT_READ;
     if page_flags.has_write() {
@@ -83,46 +302,55 @@ impl UnmapResult {
 pub struct AddrSpaceWrapper {
     inner: RwLock<AddrSpace>,
     pub tlb_ack: AtomicU32,
+    /// Incremented by every TLB shootdown, so that CPUs can tell which ones they have missed.
+    pub tlb_generation: AtomicU64,
+    /// The NUMA node most of the memory was allocated from, readable without the lock.
+    pub home_node: HomeNode,
+    /// The ID tagging the TLB entries of the address space.
+    pub asid: AsidState,
 }
 impl AddrSpaceWrapper {
     pub fn new() -> Result<Arc<Self>> {
         Arc::try_new(Self {
             inner: RwLock::new(AddrSpace::new()?),
             tlb_ack: AtomicU32::new(0),
+            tlb_generation: AtomicU64::new(0),
+            home_node: HomeNode::new(),
+            asid: AsidState::new(),
         })
         .map_err(|_| Error::new(ENOMEM))
     }
-    pub fn acquire_read(&self) -> RwLockReadGuard<'_, AddrSpace> {
-        let my_percpu = PercpuBlock::current();
-
-        loop {
-            match self.inner.try_read() {
-                Some(g) => return g,
-                None => {
-                    my_percpu.maybe_handle_tlb_shootdown();
-                    core::hint::spin_loop();
-                }
-            }
-        }
+    pub fn acquire_read(&self) -> Tracked<RwLockReadGuard<'_, AddrSpace>> {
+        Tracked::acquire(
+            LockClass::AddrSpace,
+            &self.inner,
+            LockMode::Shared,
+            |inner| Self::spin(|| inner.try_read()),
+        )
     }
-    pub fn acquire_upgradeable_read(&self) -> RwLockUpgradableGuard<'_, AddrSpace> {
-        let my_percpu = PercpuBlock::current();
-
-        loop {
-            match self.inner.try_upgradeable_read() {
-                Some(g) => return g,
-                None => {
-                    my_percpu.maybe_handle_tlb_shootdown();
-                    core::hint::spin_loop();
-                }
-            }
-        }
+    pub fn acquire_upgradeable_read(&self) -> Tracked<RwLockUpgradableGuard<'_, AddrSpace>> {
+        Tracked::acquire(
+            LockClass::AddrSpace,
+            &self.inner,
+            LockMode::Exclusive,
+            |inner| Self::spin(|| inner.try_upgradable_read()),
+        )
     }
-    pub fn acquire_write(&self) -> RwLockWriteGuard<'_, AddrSpace> {
+    pub fn acquire_write(&self) -> Tracked<RwLockWriteGuard<'_, AddrSpace>> {
+        Tracked::acquire(
+            LockClass::AddrSpace,
+            &self.inner,
+            LockMode::Exclusive,
+            |inner| Self::spin(|| inner.try_write()),
+        )
+    }
+    /// Spin until `try_lock` succeeds, handling TLB shootdowns meanwhile, which the holder of the
+    /// lock may be waiting for.
+    fn spin<G>(try_lock: impl Fn() -> Option<G>) -> G {
         let my_percpu = PercpuBlock::current();
 
         loop {
-            match self.inner.try_write() {
+            match try_lock() {
                 Some(g) => return g,
                 None => {
                     my_percpu.maybe_handle_tlb_shootdown();
@@ -143,6 +371,23 @@ pub struct AddrSpace {
     /// the exception that we have a memory safe kernel which doesn't have to protect itself
     /// against null pointers, so fixed mmaps to address zero are still allowed.
     pub mmap_min: usize,
+    /// Address from which grants without a fixed address are placed, randomized when the address
+    /// space is created unless its creator has disabled it, see [`AddrSpace::find_free`]. Forks
+    /// keep it, so that they have the same layout.
+    pub mmap_base: usize,
+    /// Number of pages reserved below new stack grants, which they can grow into on faults.
+    pub stack_window: usize,
+    /// Page ranges whose faults are delegated to a userspace handler.
+    pub userfault: Vec<(PageSpan, Arc<Userfault>)>,
+    /// NUMA placement policy for new pages of allocated grants.
+    pub mempolicy: MemPolicy,
+    /// Present if pages may be merged with identical ones by [`ksm`].
+    pub ksm: Option<KsmState>,
+    /// Set when pages were marked with MADV_FREE, until reclaim finds none left.
+    pub lazyfree: bool,
+    /// Set if executable grants mapped from now on are guarded pages, for branch target
+    /// identification on AArch64.
+    pub bti: bool,
 }
 impl AddrSpaceWrapper {
     /// Attempt to clone an existing address space so that all mappings are copied (CoW).
@@ -156,10 +401,42 @@ impl AddrSpaceWrapper {
             Arc::get_mut(&mut new_arc).expect("expected new address space Arc not to be aliased");
 
         let this_mapper = &mut guard.table.utable;
-        let mut this_flusher = Flusher::with_cpu_set(&mut guard.used_by, &self.tlb_ack);
+        let mut this_flusher = Flusher::with_cpu_set(&mut guard.used_by, self);
+
+        // Swapped out pages can only belong to private anonymous grants, which are always kept.
+        new.inner.get_mut().grants.swapped = guard.grants.swapped.try_clone()?;
+
+        if table_share::SUPPORTED {
+            // Copy the page tables lazily, leaving out the grants that are not kept across forks.
+            let new_addrsp = new.inner.get_mut();
+            let mut excluded = Vec::new();
+
+            for (grant_base, grant_info) in guard.grants.iter() {
+                match grant_info.clone_for_fork() {
+                    Some(info) => new_addrsp.grants.insert(Grant {
+                        base: grant_base,
+                        info,
+                    }),
+                    None => try_push(
+                        &mut excluded,
+                        PageSpan::new(grant_base, grant_info.page_count),
+                    )?,
+                }
+            }
+            unsafe {
+                table_share::share_all(
+                    this_mapper,
+                    &guard.grants,
+                    &mut new_addrsp.table.utable,
+                    &excluded,
+                    &mut this_flusher,
+                )?;
+            }
+            return Ok(new_arc);
+        }
 
         for (grant_base, grant_info) in guard.grants.iter() {
-            let new_grant = match grant_info.provider {
+            let mut new_grant = match grant_info.provider {
                 // No, your temporary UserScheme mappings will not be kept across forks.
                 Provider::External {
                     is_pinned_userscheme_borrow: true,
@@ -231,81 +508,879 @@ impl AddrSpaceWrapper {
                 )?,
                 Provider::FmapBorrowed { .. } => continue,
             };
+            new_grant.info.growsdown = grant_info.growsdown;
+            new_grant.info.stack_reserve = grant_info.stack_reserve;
+            new_grant.info.label = grant_info.label.clone();
+            new_grant.info.no_huge = grant_info.no_huge;
 
             new.inner.get_mut().grants.insert(new_grant);
         }
+        new.inner.get_mut().mempolicy = guard.mempolicy;
+        new.inner.get_mut().stack_window = guard.stack_window;
+        new.inner.get_mut().mmap_base = guard.mmap_base;
+        new.inner.get_mut().bti = guard.bti;
+        new.inner.get_mut().ksm = guard.ksm.as_ref().map(|_| KsmState::default());
+        new.home_node.set(self.home_node.get());
+
         Ok(new_arc)
     }
+    /// Collect and clear the dirty bits of the pages in `span`, setting bit `i % 8` of
+    /// `bitmap[i / 8]` if page `i` of the span was written to since the last time. Pages that are
+    /// not mapped are reported as clean.
+    pub fn harvest_dirty(&self, span: PageSpan, bitmap: &mut [u8]) -> Result<()> {
+        if ENTRY_FLAG_DIRTY == 0 {
+            return Err(Error::new(EOPNOTSUPP));
+        }
+        assert!(bitmap.len() * 8 >= span.count);
+        bitmap.fill(0);
+
+        let mut guard = self.acquire_write();
+        let guard = &mut *guard;
+
+        let mapper = &mut guard.table.utable;
+        let mut flusher = Flusher::with_cpu_set(&mut guard.used_by, self);
+
+        // The bits would otherwise be cleared for all address spaces sharing the tables.
+        table_share::unshare(mapper, &guard.grants, span, &mut flusher)?;
+
+        // Shadow stacks are mapped dirty, and cannot be written to as clean pages.
+        for (base, info) in guard
+            .grants
+            .conflicts(span)
+            .filter(|(_, info)| !info.is_shadow_stack())
+        {
+            for page in PageSpan::new(base, info.page_count)
+                .intersection(span)
+                .pages()
+            {
+                let Some(slot) = leaf_entry(mapper, page) else {
+                    continue;
+                };
+                let entry = slot.load(Ordering::Relaxed);
+                if entry & RmmA::ENTRY_FLAG_PRESENT == 0 || entry & ENTRY_FLAG_DIRTY == 0 {
+                    continue;
+                }
+                // The MMU sets the bit atomically, so it must also be cleared atomically.
+                let entry = slot.fetch_and(!ENTRY_FLAG_DIRTY, Ordering::Relaxed);
+                let i = page.offset_from(span.base);
+                bitmap[i / 8] |= 1 << (i % 8);
+
+                flusher.queue(
+                    PageSpan::new(page, 1),
+                    Frame::containing(PhysicalAddress::new(entry & RmmA::ENTRY_ADDRESS_MASK)),
+                    None,
+                    TlbShootdownActions::CLEAN,
+                );
+            }
+        }
+        Ok(())
+    }
+    /// Collect and clear the dirty bits of the pages of shared file mappings in `span`, returning
+    /// the ranges of the files written to since the last time, to be written back by the schemes
+    /// providing them. Without dirty bits, every resident page of writable mappings is reported.
+    pub fn harvest_dirty_fmap(&self, span: PageSpan) -> Result<Vec<DirtyRange>> {
+        let mut ranges = Vec::new();
+
+        let mut guard = self.acquire_write();
+        let guard = &mut *guard;
+
+        let mapper = &mut guard.table.utable;
+        let mut flusher = Flusher::with_cpu_set(&mut guard.used_by, self);
+
+        table_share::unshare(mapper, &guard.grants, span, &mut flusher)?;
+
+        for (base, info) in guard.grants.conflicts(span) {
+            let Provider::FmapBorrowed { ref file_ref, .. } = info.provider else {
+                continue;
+            };
+            if !info.mapped || (ENTRY_FLAG_DIRTY == 0 && !info.flags.has_write()) {
+                continue;
+            }
+            let range = |run: PageSpan| DirtyRange {
+                file_ref: GrantFileRef {
+                    description: Arc::clone(&file_ref.description),
+                    base_offset: file_ref.base_offset + run.base.offset_from(base) * PAGE_SIZE,
+                },
+                size: run.count * PAGE_SIZE,
+            };
+
+            let mut run: Option<PageSpan> = None;
+            let mut full = false;
+            for page in PageSpan::new(base, info.page_count)
+                .intersection(span)
+                .pages()
+            {
+                let Some(slot) = leaf_entry(mapper, page) else {
+                    continue;
+                };
+                let entry = slot.load(Ordering::Relaxed);
+                if entry & RmmA::ENTRY_FLAG_PRESENT == 0
+                    || (ENTRY_FLAG_DIRTY != 0 && entry & ENTRY_FLAG_DIRTY == 0)
+                {
+                    continue;
+                }
+                match run {
+                    Some(ref mut run) if run.end() == page => run.count += 1,
+                    _ => {
+                        // Reserved before cleaning the page, so that its range is never lost.
+                        // The remaining pages stay dirty until the next time.
+                        if ranges.try_reserve(2).is_err() {
+                            full = true;
+                            break;
+                        }
+                        if let Some(prev) = run.replace(PageSpan::new(page, 1)) {
+                            ranges.push(range(prev));
+                        }
+                    }
+                }
+                if ENTRY_FLAG_DIRTY != 0 {
+                    // Written back only once no CPU can write through a stale TLB entry without
+                    // setting the bit again.
+                    let entry = slot.fetch_and(!ENTRY_FLAG_DIRTY, Ordering::Relaxed);
+                    flusher.queue(
+                        PageSpan::new(page, 1),
+                        Frame::containing(PhysicalAddress::new(entry & RmmA::ENTRY_ADDRESS_MASK)),
+                        None,
+                        TlbShootdownActions::CLEAN,
+                    );
+                }
+            }
+            if let Some(run) = run {
+                ranges.push(range(run));
+            }
+            if full {
+                break;
+            }
+        }
+        Ok(ranges)
+    }
+    /// Evict up to `max` resident pages of private anonymous grants to swap, returning how many
+    /// were evicted. Pages accessed since the previous scan are given a second chance, and pages
+    /// also mapped elsewhere, or by huge pages, are skipped.
+    pub fn swap_out(&self, max: usize) -> usize {
+        let mut guard = self.acquire_write();
+        let guard = &mut *guard;
+
+        let mut evicted = Vec::new();
+        {
+            let mapper = &mut guard.table.utable;
+            let mut flusher = Flusher::with_cpu_set(&mut guard.used_by, self);
+
+            let Ok(spans) = try_collect(
+                guard
+                    .grants
+                    .iter()
+                    .filter(|(_, info)| {
+                        info.mapped
+                            && !info.is_pinned()
+                            && !info.locked
+                            // Tags are not swapped out.
+                            && !info.is_tagged()
+                            && matches!(
+                                info.provider,
+                                Provider::Allocated {
+                                    cow_file_ref: None,
+                                    phys_contiguous: false,
+                                }
+                            )
+                    })
+                    .map(|(base, info)| PageSpan::new(base, info.page_count)),
+            ) else {
+                return 0;
+            };
+
+            'grants: for span in spans {
+                // Frames reachable from tables shared with other address spaces are not private.
+                if table_share::unshare(mapper, &guard.grants, span, &mut flusher).is_err() {
+                    break;
+                }
+                for page in span.pages() {
+                    if evicted.len() >= max {
+                        break 'grants;
+                    }
+                    let Some(slot) = leaf_entry(mapper, page) else {
+                        continue;
+                    };
+                    let entry = slot.load(Ordering::Relaxed);
+                    if entry & RmmA::ENTRY_FLAG_PRESENT == 0 {
+                        continue;
+                    }
+                    if entry & ENTRY_FLAG_ACCESSED != 0 {
+                        // A stale TLB entry only delays setting the bit again, which is harmless.
+                        slot.fetch_and(!ENTRY_FLAG_ACCESSED, Ordering::Relaxed);
+                        continue;
+                    }
+                    let frame = Frame::containing(PhysicalAddress::new(
+                        entry & RmmA::ENTRY_ADDRESS_MASK,
+                    ));
+                    if get_page_info(frame).and_then(|info| info.refcount()) != Some(RefCount::One)
+                    {
+                        continue;
+                    }
+                    if evicted.try_reserve(1).is_err() {
+                        break 'grants;
+                    }
+                    let Some(swap_entry) = swap::reserve() else {
+                        break 'grants;
+                    };
+
+                    let Some((_, _, flush)) =
+                        (unsafe { mapper.unmap_phys(page.start_address(), false) })
+                    else {
+                        continue;
+                    };
+                    unsafe {
+                        flush.ignore();
+                    }
+                    flusher.queue(
+                        PageSpan::new(page, 1),
+                        frame,
+                        None,
+                        TlbShootdownActions::MOVE,
+                    );
+                    evicted.push((swap_entry.slot(), frame));
+                    guard.grants.swapped.insert(page, swap_entry);
+                }
+            }
+        }
+
+        // Only written out once no CPU can still write to the frames through stale TLB entries.
+        let count = evicted.len();
+        for (slot, frame) in evicted {
+            swap::write_out(slot, frame);
+        }
+        count
+    }
+    /// Scan up to `max` resident pages of private anonymous grants starting at `start`, merging
+    /// them with identical pages through [`ksm`]. Returns where the next batch starts, or None
+    /// once the end of the address space was reached, or if it opted out in the meantime.
+    ///
+    /// `unstable` holds the checksums of the candidate pages seen during the current pass.
+    pub fn ksm_scan(
+        &self,
+        start: Page,
+        max: usize,
+        unstable: &mut BTreeSet<u64>,
+    ) -> Option<Page> {
+        let mut guard = self.acquire_write();
+        let guard = &mut *guard;
+        let state = guard.ksm.as_mut()?;
+
+        let mut spans = Vec::new();
+        let mut next = None;
+        let mut budget = max;
+        for (base, info) in guard.grants.iter() {
+            let span = PageSpan::new(base, info.page_count);
+            if !info.mapped
+                || info.is_pinned()
+                || info.is_tagged()
+                || span.end() <= start
+                || !matches!(
+                    info.provider,
+                    Provider::Allocated {
+                        cow_file_ref: None,
+                        phys_contiguous: false,
+                    }
+                )
+            {
+                continue;
+            }
+            let from = cmp::max(base, start);
+            let count = cmp::min(span.end().offset_from(from), budget);
+            try_push(&mut spans, PageSpan::new(from, count)).ok()?;
+            budget -= count;
+            if budget == 0 {
+                next = Some(from.next_by(count));
+                break;
+            }
+        }
+
+        let mapper = &mut guard.table.utable;
+        let mut flusher = Flusher::with_cpu_set(&mut guard.used_by, self);
+        let mut stable = ksm::stable();
+
+        let mut candidates = Vec::new();
+        'spans: for span in spans {
+            // Frames reachable from tables shared with other address spaces are not private.
+            table_share::unshare(mapper, &guard.grants, span, &mut flusher).ok()?;
+
+            for page in span.pages() {
+                let Some(slot) = leaf_entry(mapper, page) else {
+                    continue;
+                };
+                let entry = slot.load(Ordering::Relaxed);
+                if entry & RmmA::ENTRY_FLAG_PRESENT == 0 {
+                    continue;
+                }
+                let frame =
+                    Frame::containing(PhysicalAddress::new(entry & RmmA::ENTRY_ADDRESS_MASK));
+                // Frames that are already shared, including those in the stable tree.
+                if get_page_info(frame).and_then(|info| info.refcount()) != Some(RefCount::One) {
+                    continue;
+                }
+                ksm::STATS.pages_scanned.fetch_add(1, Ordering::Relaxed);
+
+                let checksum = ksm::checksum(frame);
+                if !state.update(page, checksum) {
+                    continue;
+                }
+                if !stable.contains_checksum(checksum) && unstable.insert(checksum) {
+                    continue;
+                }
+                if candidates.try_reserve(1).is_err() {
+                    break 'spans;
+                }
+                let Some((_, _, flush)) = (unsafe {
+                    mapper.remap_with(page.start_address(), |flags| flags.write(false))
+                }) else {
+                    continue;
+                };
+                unsafe {
+                    flush.ignore();
+                }
+                flusher.queue(
+                    PageSpan::new(page, 1),
+                    frame,
+                    None,
+                    TlbShootdownActions::REVOKE_WRITE,
+                );
+                candidates.push((page, frame, checksum));
+            }
+        }
+
+        // The contents can only be compared once no CPU can write to the candidates anymore. They
+        // are left read-only even if not merged, and made writable again by the next write fault.
+        flusher.flush();
+
+        for (page, frame, checksum) in candidates {
+            let Some(shared) = stable.find(checksum, frame) else {
+                if ksm::checksum(frame) == checksum {
+                    stable.insert(checksum, frame);
+                }
+                continue;
+            };
+            let shared_info = get_page_info(shared).expect("allocated frame needs page info");
+            if shared_info.add_ref(RefKind::Cow).is_err() {
+                continue;
+            }
+            let Some((_, _, flush)) = (unsafe {
+                mapper.remap_with_full(page.start_address(), |_, flags| (shared.base(), flags))
+            }) else {
+                let _ = shared_info.remove_ref();
+                continue;
+            };
+            unsafe {
+                flush.ignore();
+            }
+            flusher.queue(
+                PageSpan::new(page, 1),
+                frame,
+                None,
+                TlbShootdownActions::FREE,
+            );
+            state.merged += 1;
+            ksm::STATS.pages_merged.fetch_add(1, Ordering::Relaxed);
+        }
+
+        next
+    }
+    /// Change the protection of the pages within `requested_span`, splitting the grants at its
+    /// boundaries. Every grant is checked before any is changed, so that a failure leaves the
+    /// whole range as it was.
     pub fn mprotect(&self, requested_span: PageSpan, flags: MapFlags) -> Result<()> {
+        let prot = MapFlags::PROT_READ | MapFlags::PROT_WRITE | MapFlags::PROT_EXEC;
+        if !(flags - prot).is_empty() {
+            return Err(Error::new(EINVAL));
+        }
+        check_wx("mprotect", requested_span.base, flags)?;
+
         let mut guard = self.acquire_write();
         let guard = &mut *guard;
 
+        let bti = guard.bti;
         let mapper = &mut guard.table.utable;
-        let mut flusher = Flusher::with_cpu_set(&mut guard.used_by, &self.tlb_ack);
+        let mut flusher = Flusher::with_cpu_set(&mut guard.used_by, self);
 
         // TODO: Remove allocation (might require BTreeMap::set_key or interior mutability).
+        let regions = try_collect(guard.grants.conflicts(requested_span).map(|(base, info)| {
+            if info.is_pinned() {
+                Err(Error::new(EBUSY))
+            } else if !info.can_have_flags(flags) {
+                Err(Error::new(EACCES))
+            } else {
+                Ok(PageSpan::new(base, info.page_count))
+            }
+        }))?;
+        if let Some(Err(err)) = regions.iter().find(|region| region.is_err()) {
+            return Err(*err);
+        }
+
+        table_share::unshare(mapper, &guard.grants, requested_span, &mut flusher)?;
+
+        for grant_span in regions.into_iter().flatten() {
+            let grant = guard
+                .grants
+                .remove(grant_span.base)
+                .expect("grant cannot magically disappear while we hold the lock!");
+            //log::info!("Mprotecting {:#?} to {:#?} in {:#?}", grant, flags, grant_span);
+            let intersection = grant_span.intersection(requested_span);
+
+            let (before, mut grant, after) = grant
+                .extract(intersection)
+                .expect("failed to extract grant");
+            //log::info!("Sliced into\n\n{:#?}\n\n{:#?}\n\n{:#?}", before, grant, after);
+
+            if let Some(before) = before {
+                guard.grants.insert(before);
+            }
+            if let Some(after) = after {
+                guard.grants.insert(after);
+            }
+
+            let new_flags = grant
+                .info
+                .flags()
+                // TODO: Require a capability in order to map executable memory?
+                .execute(flags.contains(MapFlags::PROT_EXEC))
+                .write(flags.contains(MapFlags::PROT_WRITE));
+            let new_flags = guard_flags(new_flags, bti);
+
+            // TODO: Allow enabling/disabling read access on architectures which allow it. On
+            // x86_64 with protection keys (although only enforced by userspace), and AArch64 (I
+            // think), execute-only memory is also supported.
+
+            grant.remap(mapper, &mut flusher, new_flags);
+            //log::info!("Mprotect grant became {:#?}", grant);
+            guard.grants.insert(grant);
+        }
+        Ok(())
+    }
+    /// Make the pages within `span` readable and executable, but no longer writable, once a JIT
+    /// wrote code to them. The W^X policy always allows this, and unlike mprotect, the instruction
+    /// caches are made coherent with the code written, where the architecture does not do it.
+    pub fn make_executable(&self, span: PageSpan) -> Result<()> {
+        self.mprotect(span, MapFlags::PROT_READ | MapFlags::PROT_EXEC)?;
+
+        #[cfg(target_arch = "aarch64")]
+        {
+            let guard = self.acquire_read();
+            for page in span.pages() {
+                let Some((phys, _)) = guard.table.utable.translate(page.start_address()) else {
+                    continue;
+                };
+                unsafe {
+                    crate::arch::misc::sync_icache(RmmA::phys_to_virt(phys).data(), PAGE_SIZE);
+                }
+            }
+        }
+        Ok(())
+    }
+    /// Set or clear the label of all grants within `requested_span`, splitting them at its
+    /// boundaries.
+    pub fn set_label(&self, requested_span: PageSpan, label: Option<Arc<str>>) -> Result<()> {
+        if label.as_ref().is_some_and(|l| l.len() > GRANT_LABEL_MAX) {
+            return Err(Error::new(ENAMETOOLONG));
+        }
+
+        let mut guard = self.acquire_write();
+        let guard = &mut *guard;
+
+        let mapper = &mut guard.table.utable;
+        let mut flusher = Flusher::with_cpu_set(&mut guard.used_by, self);
+
+        table_share::unshare(mapper, &guard.grants, requested_span, &mut flusher)?;
+
         let regions = guard
             .grants
             .conflicts(requested_span)
             .map(|(base, info)| {
-                if info.is_pinned() {
-                    Err(Error::new(EBUSY))
-                } else {
+                if info.can_extract(false) {
                     Ok(PageSpan::new(base, info.page_count))
+                } else {
+                    Err(Error::new(EBUSY))
                 }
             })
-            .collect::<Vec<_>>();
+            .collect::<Result<Vec<_>>>()?;
 
-        for grant_span_res in regions {
-            let grant_span = grant_span_res?;
+        for grant_span in regions {
+            let grant = guard
+                .grants
+                .remove(grant_span.base)
+                .expect("grant cannot magically disappear while we hold the lock!");
+
+            let (before, mut grant, after) = grant
+                .extract(grant_span.intersection(requested_span))
+                .expect("failed to extract grant");
+
+            if let Some(before) = before {
+                guard.grants.insert(before);
+            }
+            if let Some(after) = after {
+                guard.grants.insert(after);
+            }
+
+            grant.info.label = label.clone();
+            guard.grants.insert(grant);
+        }
+        Ok(())
+    }
+    /// Apply `advice`, one of the `MADV_*` values, to the pages within `span`, which must be
+    /// entirely covered by grants.
+    pub fn madvise(self: &Arc<Self>, span: PageSpan, advice: usize) -> Result<()> {
+        match advice {
+            MADV_WILLNEED => self.prefault(span),
+            MADV_DONTNEED => self.discard(span, false),
+            MADV_FREE => self.discard(span, true),
+            MADV_HUGEPAGE => self.set_no_huge(span, false),
+            MADV_NOHUGEPAGE => self.set_no_huge(span, true),
+            _ => Err(Error::new(EINVAL)),
+        }
+    }
+    /// Map the pages within `span` that are swapped out, or not populated yet in grants other
+    /// than private anonymous ones, as if they were read.
+    fn prefault(self: &Arc<Self>, span: PageSpan) -> Result<()> {
+        let mut guard = self.acquire_write();
+        check_advisable(&guard.grants, span, false)?;
+
+        for page in span.pages() {
+            let wanted = guard.grants.swapped.contains(page)
+                || guard.grants.contains(page).is_some_and(|(_, info)| {
+                    !is_private_anon(info)
+                        && guard.table.utable.translate(page.start_address()).is_none()
+                });
+            if !wanted || guard.userfault_handler(page).is_some() {
+                continue;
+            }
+            match correct_inner(self, guard, page, AccessMode::Read, 0) {
+                Ok((_, flush, new_guard)) => {
+                    flush.flush();
+                    guard = new_guard;
+                }
+                // Only a hint, the pages left are read in on their first fault as usual.
+                Err(_) => return Ok(()),
+            }
+        }
+        Ok(())
+    }
+    /// Drop the pages of the private anonymous grants within `span`, along with their swapped out
+    /// contents, so that they read as zeroes again once touched. With `lazy`, present pages are
+    /// only marked, and dropped by reclaim unless they are written to first, see
+    /// [`Self::reclaim_lazyfree`].
+    fn discard(&self, span: PageSpan, lazy: bool) -> Result<()> {
+        let lazy = lazy && ENTRY_FLAG_LAZYFREE != 0;
 
+        let mut guard = self.acquire_write();
+        let guard = &mut *guard;
+        check_advisable(&guard.grants, span, true)?;
+        if guard.grants.conflicts(span).any(|(_, info)| info.locked) {
+            return Err(Error::new(EINVAL));
+        }
+
+        let mapper = &mut guard.table.utable;
+        let mut flusher = Flusher::with_cpu_set(&mut guard.used_by, self);
+
+        // Frames reachable from tables shared with other address spaces are not private.
+        table_share::unshare(mapper, &guard.grants, span, &mut flusher)?;
+        drop(guard.grants.swapped.remove_span(span));
+
+        for page in span.pages() {
+            if lazy {
+                let Some(slot) = leaf_entry(mapper, page) else {
+                    continue;
+                };
+                if slot.load(Ordering::Relaxed) & RmmA::ENTRY_FLAG_PRESENT == 0 {
+                    continue;
+                }
+                // The MMU sets the dirty bit atomically, so it must also be cleared atomically.
+                let entry = slot.fetch_and(!ENTRY_FLAG_DIRTY, Ordering::Relaxed);
+                slot.fetch_or(ENTRY_FLAG_LAZYFREE, Ordering::Relaxed);
+                flusher.queue(
+                    PageSpan::new(page, 1),
+                    Frame::containing(PhysicalAddress::new(entry & RmmA::ENTRY_ADDRESS_MASK)),
+                    None,
+                    TlbShootdownActions::CLEAN,
+                );
+                guard.lazyfree = true;
+                continue;
+            }
+            let Some((phys, _, flush)) =
+                (unsafe { mapper.unmap_phys(page.start_address(), false) })
+            else {
+                continue;
+            };
+            unsafe {
+                flush.ignore();
+            }
+            flusher.queue(
+                PageSpan::new(page, 1),
+                Frame::containing(phys),
+                None,
+                TlbShootdownActions::FREE,
+            );
+        }
+        Ok(())
+    }
+    /// Drop up to `max` pages marked with MADV_FREE that are still clean, returning how many were
+    /// dropped. Pages written to since they were marked, or shared in the meantime, are kept.
+    pub fn reclaim_lazyfree(&self, max: usize) -> usize {
+        let mut guard = self.acquire_write();
+        let guard = &mut *guard;
+        if !guard.lazyfree {
+            return 0;
+        }
+
+        let Ok(spans) = try_collect(
+            guard
+                .grants
+                .iter()
+                .filter(|(_, info)| info.mapped && !info.locked && is_private_anon(info))
+                .map(|(base, info)| PageSpan::new(base, info.page_count)),
+        ) else {
+            return 0;
+        };
+
+        let mapper = &mut guard.table.utable;
+        let mut flusher = Flusher::with_cpu_set(&mut guard.used_by, self);
+
+        let mut dropped = 0;
+        let mut done = true;
+        'grants: for span in spans {
+            if table_share::unshare(mapper, &guard.grants, span, &mut flusher).is_err() {
+                done = false;
+                break;
+            }
+            for page in span.pages() {
+                let Some(slot) = leaf_entry(mapper, page) else {
+                    continue;
+                };
+                let entry = slot.load(Ordering::Relaxed);
+                if entry & RmmA::ENTRY_FLAG_PRESENT == 0 || entry & ENTRY_FLAG_LAZYFREE == 0 {
+                    continue;
+                }
+                if dropped >= max {
+                    done = false;
+                    break 'grants;
+                }
+                let frame =
+                    Frame::containing(PhysicalAddress::new(entry & RmmA::ENTRY_ADDRESS_MASK));
+                let private =
+                    get_page_info(frame).and_then(|info| info.refcount()) == Some(RefCount::One);
+                // A write through a stale TLB entry that was clean has to set the dirty bit in the
+                // entry, and faults once it is gone, so the contents cannot change after this.
+                if entry & ENTRY_FLAG_DIRTY != 0
+                    || !private
+                    || slot
+                        .compare_exchange(entry, 0, Ordering::Relaxed, Ordering::Relaxed)
+                        .is_err()
+                {
+                    slot.fetch_and(!ENTRY_FLAG_LAZYFREE, Ordering::Relaxed);
+                    continue;
+                }
+                flusher.queue(
+                    PageSpan::new(page, 1),
+                    frame,
+                    None,
+                    TlbShootdownActions::FREE,
+                );
+                dropped += 1;
+            }
+        }
+        guard.lazyfree = !done;
+        dropped
+    }
+    /// Allow or forbid faults to map the private anonymous grants within `span` with huge pages,
+    /// splitting them at its boundaries. Huge pages that are already mapped are kept.
+    fn set_no_huge(&self, span: PageSpan, no_huge: bool) -> Result<()> {
+        let mut guard = self.acquire_write();
+        let guard = &mut *guard;
+        check_advisable(&guard.grants, span, true)?;
+
+        let mapper = &mut guard.table.utable;
+        let mut flusher = Flusher::with_cpu_set(&mut guard.used_by, self);
+
+        table_share::unshare(mapper, &guard.grants, span, &mut flusher)?;
+
+        guard
+            .grants
+            .update_span(span, |info| info.no_huge = no_huge)
+    }
+    /// Map the private anonymous grants within `span` as tagged memory, splitting them at its
+    /// boundaries. The tags of their pages already populated are zeroed, apart from those still
+    /// shared copy-on-write, whose tags are already zero or were set by a tagged grant.
+    #[cfg(target_arch = "aarch64")]
+    pub fn set_tagged(&self, span: PageSpan) -> Result<()> {
+        if !crate::mte::enabled() {
+            return Err(Error::new(EOPNOTSUPP));
+        }
+
+        let mut guard = self.acquire_write();
+        let guard = &mut *guard;
+        check_advisable(&guard.grants, span, true)?;
+
+        let mapper = &mut guard.table.utable;
+        let mut flusher = Flusher::with_cpu_set(&mut guard.used_by, self);
+
+        table_share::unshare(mapper, &guard.grants, span, &mut flusher)?;
+
+        let regions = try_collect(
+            guard
+                .grants
+                .conflicts(span)
+                .filter(|(_, info)| !info.is_tagged())
+                .map(|(base, info)| PageSpan::new(base, info.page_count)),
+        )?;
+
+        for grant_span in regions {
             let grant = guard
                 .grants
                 .remove(grant_span.base)
                 .expect("grant cannot magically disappear while we hold the lock!");
-            //log::info!("Mprotecting {:#?} to {:#?} in {:#?}", grant, flags, grant_span);
-            let intersection = grant_span.intersection(requested_span);
 
             let (before, mut grant, after) = grant
-                .extract(intersection)
+                .extract(grant_span.intersection(span))
                 .expect("failed to extract grant");
-            //log::info!("Sliced into\n\n{:#?}\n\n{:#?}\n\n{:#?}", before, grant, after);
 
-            if let Some(before) = before {
-                guard.grants.insert(before);
-            }
-            if let Some(after) = after {
-                guard.grants.insert(after);
+            if let Some(before) = before {
+                guard.grants.insert(before);
+            }
+            if let Some(after) = after {
+                guard.grants.insert(after);
+            }
+
+            for page in grant.span().pages() {
+                let Some((phys, _)) = mapper.translate(page.start_address()) else {
+                    continue;
+                };
+                let frame = Frame::containing(phys);
+                if get_page_info(frame).is_some_and(|info| info.refcount() == Some(RefCount::One)) {
+                    crate::mte::zero_tags(frame);
+                }
+            }
+            let flags = crate::mte::tagged_flags(grant.info.flags(), true);
+            grant.remap(mapper, &mut flusher, flags);
+            guard.grants.insert(grant);
+        }
+        Ok(())
+    }
+    /// Map a shadow stack of `page_count` pages, see [`crate::cet`]. It is populated eagerly and
+    /// physically contiguous, which keeps it out of forks, swap and KSM.
+    #[cfg(target_arch = "x86_64")]
+    pub fn mmap_shadow_stack(&self, page_count: NonZeroUsize) -> Result<PageSpan> {
+        let base = self.acquire_write().mmap_anywhere(
+            self,
+            page_count,
+            MapFlags::PROT_READ,
+            |page, flags, mapper, flusher| {
+                Ok(Grant::zeroed_phys_contiguous(
+                    PageSpan::new(page, page_count.get()),
+                    crate::cet::shadow_stack_flags(flags),
+                    mapper,
+                    flusher,
+                )?)
+            },
+        )?;
+        Ok(PageSpan::new(base, page_count.get()))
+    }
+    /// Unmap the shadow stack at `span`, unless userspace already unmapped it.
+    #[cfg(target_arch = "x86_64")]
+    pub fn munmap_shadow_stack(&self, span: PageSpan) {
+        let mut guard = self.acquire_write();
+        let guard = &mut *guard;
+        if !guard
+            .grants
+            .contains(span.base)
+            .is_some_and(|(base, info)| base == span.base && info.is_shadow_stack())
+        {
+            return;
+        }
+        let mut flusher = Flusher::with_cpu_set(&mut guard.used_by, self);
+        // Shadow stacks are anonymous, leaving no file to notify.
+        let _ = AddrSpace::munmap_inner(
+            &mut guard.grants,
+            &mut guard.table.utable,
+            &mut flusher,
+            span,
+            false,
+        );
+    }
+    /// Lock the pages within `span` in memory, which must be entirely covered by grants, so that
+    /// they are exempt from swap and reclaim, and populate them. Private writable pages are
+    /// populated as if they were written, so that they are not shared copy-on-write anymore.
+    ///
+    /// The locked grants of the address space may not exceed the RLIMIT_MEMLOCK of the current
+    /// context.
+    pub fn mlock(self: &Arc<Self>, span: PageSpan) -> Result<()> {
+        let mut guard = self.acquire_write();
+        {
+            let guard = &mut *guard;
+            check_advisable(&guard.grants, span, false)?;
+
+            let newly_locked: usize = guard
+                .grants
+                .conflicts(span)
+                .filter(|(_, info)| !info.locked)
+                .map(|(base, info)| {
+                    PageSpan::new(base, info.page_count)
+                        .intersection(span)
+                        .count
+                })
+                .sum();
+            let limit = rlimit::current_mem_limits().locked / PAGE_SIZE;
+            if guard.grants.locked_count() + newly_locked > limit {
+                return Err(Error::new(ENOMEM));
             }
 
-            if !grant.info.can_have_flags(flags) {
-                guard.grants.insert(grant);
-                return Err(Error::new(EACCES));
-            }
+            let mapper = &mut guard.table.utable;
+            let mut flusher = Flusher::with_cpu_set(&mut guard.used_by, self);
 
-            let new_flags = grant
-                .info
-                .flags()
-                // TODO: Require a capability in order to map executable memory?
-                .execute(flags.contains(MapFlags::PROT_EXEC))
-                .write(flags.contains(MapFlags::PROT_WRITE));
+            table_share::unshare(mapper, &guard.grants, span, &mut flusher)?;
 
-            // TODO: Allow enabling/disabling read access on architectures which allow it. On
-            // x86_64 with protection keys (although only enforced by userspace), and AArch64 (I
-            // think), execute-only memory is also supported.
+            guard.grants.update_span(span, |info| info.locked = true)?;
+        }
 
-            grant.remap(mapper, &mut flusher, new_flags);
-            //log::info!("Mprotect grant became {:#?}", grant);
-            guard.grants.insert(grant);
+        for page in span.pages() {
+            let Some((_, info)) = guard.grants.contains(page) else {
+                continue;
+            };
+            let private = matches!(info.provider, Provider::Allocated { .. });
+            let access = if private && info.flags.has_write() {
+                AccessMode::Write
+            } else {
+                AccessMode::Read
+            };
+            let populated = match guard.table.utable.translate(page.start_address()) {
+                Some((_, flags)) => access == AccessMode::Read || flags.has_write(),
+                None => false,
+            };
+            if populated || guard.userfault_handler(page).is_some() {
+                continue;
+            }
+            let (_, flush, new_guard) =
+                correct_inner(self, guard, page, access, 0).map_err(|_| Error::new(EAGAIN))?;
+            flush.flush();
+            guard = new_guard;
         }
         Ok(())
     }
+    /// Unlock the pages within `span`, which stay resident until reclaimed as usual.
+    pub fn munlock(&self, span: PageSpan) -> Result<()> {
+        let mut guard = self.acquire_write();
+        let guard = &mut *guard;
+        check_advisable(&guard.grants, span, false)?;
+
+        let mapper = &mut guard.table.utable;
+        let mut flusher = Flusher::with_cpu_set(&mut guard.used_by, self);
+
+        table_share::unshare(mapper, &guard.grants, span, &mut flusher)?;
+
+        guard.grants.update_span(span, |info| info.locked = false)
+    }
     #[must_use = "needs to notify files"]
     pub fn munmap(&self, requested_span: PageSpan, unpin: bool) -> Result<Vec<UnmapResult>> {
         let mut guard = self.acquire_write();
         let guard = &mut *guard;
 
-        let mut flusher = Flusher::with_cpu_set(&mut guard.used_by, &self.tlb_ack);
+        let mut flusher = Flusher::with_cpu_set(&mut guard.used_by, self);
         AddrSpace::munmap_inner(
             &mut guard.grants,
             &mut guard.table.utable,
@@ -314,6 +1389,165 @@ impl AddrSpaceWrapper {
             unpin,
         )
     }
+    /// Resize the grants at `span` to `new_page_count` pages without moving them, keeping their
+    /// contents and CoW state. Shrinking unmaps the pages past the new end, and growing extends
+    /// the last private anonymous grant with lazily zeroed pages. Returns false if the grants
+    /// cannot be resized in place, or have other flags than `new_flags`, and fails with EFAULT if
+    /// nothing is mapped at `span`, EEXIST if the pages to add are mapped or reserved for a stack,
+    /// or ENOMEM if they would exceed RLIMIT_AS.
+    pub fn resize_in_place(
+        &self,
+        span: PageSpan,
+        new_page_count: usize,
+        new_flags: MapFlags,
+        notify_files: &mut Vec<UnmapResult>,
+    ) -> Result<bool> {
+        let mut guard = self.acquire_write();
+        let guard = &mut *guard;
+
+        if guard.grants.conflicts(span).next().is_none() {
+            return Err(Error::new(EFAULT));
+        }
+
+        let new_page_flags = guard.page_flags(new_flags);
+        if guard
+            .grants
+            .conflicts(span)
+            .any(|(_, info)| info.flags().data() != new_page_flags.data())
+        {
+            return Ok(false);
+        }
+
+        if new_page_count < span.count {
+            let mut flusher = Flusher::with_cpu_set(&mut guard.used_by, self);
+            let unpin = false;
+            notify_files.append(&mut AddrSpace::munmap_inner(
+                &mut guard.grants,
+                &mut guard.table.utable,
+                &mut flusher,
+                PageSpan::new(
+                    span.base.next_by(new_page_count),
+                    span.count - new_page_count,
+                ),
+                unpin,
+            )?);
+            return Ok(true);
+        }
+        if new_page_count == span.count {
+            return Ok(true);
+        }
+
+        let last_page = span.base.next_by(span.count - 1);
+        let Some((base, info)) = guard.grants.contains(last_page) else {
+            return Ok(false);
+        };
+        let growable = matches!(
+            info.provider,
+            Provider::Allocated {
+                cow_file_ref: None,
+                phys_contiguous: false,
+            }
+        );
+        if !growable || base.next_by(info.page_count) != span.end() {
+            return Ok(false);
+        }
+        if PageSpan::validate(span.base.start_address(), new_page_count * PAGE_SIZE).is_none() {
+            return Ok(false);
+        }
+        let extra = PageSpan::new(span.end(), new_page_count - span.count);
+        if !guard.grants.is_free(extra) {
+            return Err(Error::new(EEXIST));
+        }
+        if !guard.within_as_limit(extra.count) {
+            return Err(Error::new(ENOMEM));
+        }
+
+        // Nothing is mapped in the new pages, which are populated on the first fault.
+        let mut new_info = GrantInfo::new(
+            extra.count,
+            info.flags,
+            true,
+            Provider::Allocated {
+                cow_file_ref: None,
+                phys_contiguous: false,
+            },
+        );
+        new_info.growsdown = info.growsdown;
+        new_info.label = info.label.clone();
+        new_info.no_huge = info.no_huge;
+        guard.grants.insert(Grant {
+            base: extra.base,
+            info: new_info,
+        });
+        Ok(true)
+    }
+    /// Resolve a delegated fault, by mapping a new frame at the not-yet-present `page`, filled
+    /// from `src` or zeroed. Contexts waiting on `userfault` are woken up.
+    pub fn userfault_fill(
+        &self,
+        userfault: &Userfault,
+        page: Page,
+        src: Option<UserSliceRo>,
+    ) -> Result<()> {
+        let frame = init_user_frame(RefCount::One, None).map_err(|_| Error::new(ENOMEM))?;
+
+        // Copy before taking the lock, since the source may well be in this address space.
+        if let Some(src) = src {
+            let dst = unsafe {
+                core::slice::from_raw_parts_mut(
+                    RmmA::phys_to_virt(frame.base()).data() as *mut u8,
+                    PAGE_SIZE,
+                )
+            };
+            if let Err(err) = src.copy_to_slice(dst) {
+                handle_free_action(frame, None);
+                return Err(err);
+            }
+        }
+
+        let mut guard = self.acquire_write();
+        let guard = &mut *guard;
+
+        let flags = match guard.grants.contains(page) {
+            Some((_, info)) if matches!(info.provider, Provider::Allocated { .. }) => info.flags(),
+            _ => {
+                handle_free_action(frame, None);
+                return Err(Error::new(EINVAL));
+            }
+        };
+        if guard.table.utable.translate(page.start_address()).is_some() {
+            handle_free_action(frame, None);
+            return Err(Error::new(EEXIST));
+        }
+        let mut flusher = Flusher::with_cpu_set(&mut guard.used_by, self);
+        if let Err(err) = table_share::unshare(
+            &mut guard.table.utable,
+            &guard.grants,
+            PageSpan::new(page, 1),
+            &mut flusher,
+        ) {
+            handle_free_action(frame, None);
+            return Err(err.into());
+        }
+        drop(flusher);
+
+        let flush_opt = unsafe {
+            guard
+                .table
+                .utable
+                .map_phys(page.start_address(), frame.base(), flags)
+        };
+        let Some(flush) = flush_opt else {
+            handle_free_action(frame, None);
+            return Err(Error::new(ENOMEM));
+        };
+        // Not-present pages are never cached in the TLB.
+        flush.ignore();
+        guard.grants.swapped.remove(page);
+
+        userfault.resolved.notify();
+        Ok(())
+    }
     pub fn r#move(
         &self,
         mut src_opt: Option<(&AddrSpaceWrapper, &mut AddrSpace)>,
@@ -326,18 +1560,24 @@ impl AddrSpaceWrapper {
         let dst_lock = self;
         let mut dst = dst_lock.acquire_write();
         let dst = &mut *dst;
+        let new_page_flags = dst.page_flags(new_flags);
+
+        if new_page_count > src_span.count && !dst.within_as_limit(new_page_count - src_span.count)
+        {
+            return Err(Error::new(ENOMEM));
+        }
 
         let mut src_owned_opt = src_opt.as_mut().map(|(aw, a)| {
             (
                 &mut a.grants,
                 &mut a.table.utable,
-                Flusher::with_cpu_set(&mut a.used_by, &aw.tlb_ack),
+                Flusher::with_cpu_set(&mut a.used_by, aw),
             )
         });
         let mut src_opt = src_owned_opt
             .as_mut()
             .map(|(g, m, f)| (&mut *g, &mut *m, &mut *f));
-        let mut dst_flusher = Flusher::with_cpu_set(&mut dst.used_by, &dst_lock.tlb_ack);
+        let mut dst_flusher = Flusher::with_cpu_set(&mut dst.used_by, dst_lock);
 
         let dst_base = match requested_dst_base {
             Some(base) if new_flags.contains(MapFlags::MAP_FIXED_NOREPLACE) => {
@@ -365,17 +1605,23 @@ impl AddrSpaceWrapper {
                 base
             }
             _ => {
-                dst.grants
-                    .find_free(dst.mmap_min, cmp::max(new_page_count, src_span.count))
+                dst.find_free(cmp::max(new_page_count, src_span.count))
                     .ok_or(Error::new(ENOMEM))?
                     .base
             }
         };
+        table_share::unshare(
+            &mut dst.table.utable,
+            &dst.grants,
+            PageSpan::new(dst_base, new_page_count),
+            &mut dst_flusher,
+        )?;
 
         let (src_grants, src_mapper, src_flusher) = src_opt.as_mut().map_or(
             (&mut dst.grants, &mut dst.table.utable, &mut dst_flusher),
             |(g, m, f)| (&mut *g, &mut *m, &mut *f),
         );
+        table_share::unshare(src_mapper, src_grants, src_span, src_flusher)?;
 
         if src_grants
             .conflicts(src_span)
@@ -409,10 +1655,7 @@ impl AddrSpaceWrapper {
 
         let mut remaining_src_span = PageSpan::new(src_span.base, new_page_count);
 
-        let to_remap = src_grants
-            .conflicts(remaining_src_span)
-            .map(|(b, _)| b)
-            .collect::<Vec<_>>();
+        let to_remap = try_collect(src_grants.conflicts(remaining_src_span).map(|(b, _)| b))?;
 
         let mut prev_grant_end = src_span.base;
 
@@ -426,7 +1669,7 @@ impl AddrSpaceWrapper {
                 );
                 dst.grants.insert(Grant::zeroed(
                     hole_span,
-                    page_flags(new_flags),
+                    new_page_flags,
                     &mut dst.table.utable,
                     &mut dst_flusher,
                     false,
@@ -454,6 +1697,7 @@ impl AddrSpaceWrapper {
 
             let dst_grant_base = dst_base.next_by(middle.base.offset_from(src_span.base));
             let middle_span = middle.span();
+            let swapped = src_grants.swapped.remove_span(middle_span);
 
             let mut src_opt = src_opt
                 .as_mut()
@@ -462,7 +1706,7 @@ impl AddrSpaceWrapper {
             dst.grants.insert(match src_opt.as_mut() {
                 Some((_, other_mapper, other_flusher)) => middle.transfer(
                     dst_grant_base,
-                    page_flags(new_flags),
+                    new_page_flags,
                     other_mapper,
                     Some(&mut dst.table.utable),
                     other_flusher,
@@ -470,13 +1714,16 @@ impl AddrSpaceWrapper {
                 )?,
                 None => middle.transfer(
                     dst_grant_base,
-                    page_flags(new_flags),
+                    new_page_flags,
                     &mut dst.table.utable,
                     None,
                     &mut dst_flusher,
                     &mut NopFlusher,
                 )?,
             });
+            dst.grants
+                .swapped
+                .insert_moved(swapped, middle_span.base, dst_grant_base);
 
             prev_grant_end = middle_span.base.next_by(middle_span.count);
             let pages_advanced = prev_grant_end.offset_from(remaining_src_span.base);
@@ -491,7 +1738,7 @@ impl AddrSpaceWrapper {
             );
             dst.grants.insert(Grant::zeroed(
                 last_hole_span,
-                page_flags(new_flags),
+                new_page_flags,
                 &mut dst.table.utable,
                 &mut dst_flusher,
                 false,
@@ -513,6 +1760,16 @@ impl AddrSpaceWrapper {
         if !matches!(info.provider, Provider::Allocated { .. }) {
             return Err(Error::new(EPERM));
         }
+        {
+            let addr_space = &mut *guard;
+            let mut flusher = Flusher::with_cpu_set(&mut addr_space.used_by, self);
+            table_share::unshare(
+                &mut addr_space.table.utable,
+                &addr_space.grants,
+                PageSpan::new(page, 1),
+                &mut flusher,
+            )?;
+        }
 
         let frame = if let Some((f, fl)) = guard.table.utable.translate(page.start_address())
             && fl.has_write()
@@ -557,9 +1814,128 @@ impl AddrSpace {
             grants: UserGrants::new(),
             table: setup_new_utable()?,
             mmap_min: MMAP_MIN_DEFAULT,
+            mmap_base: random_mmap_base(),
+            stack_window: STACK_WINDOW_DEFAULT,
             used_by: LogicalCpuSet::empty(),
+            userfault: Vec::new(),
+            mempolicy: MemPolicy::default(),
+            ksm: None,
+            lazyfree: false,
+            bti: false,
         })
     }
+    /// Return a free region for a grant without a fixed address, at or above the randomized base
+    /// if there is room there, or else anywhere above `mmap_min`.
+    pub fn find_free(&self, page_count: usize) -> Option<PageSpan> {
+        self.grants
+            .find_free(cmp::max(self.mmap_base, self.mmap_min), page_count)
+            .or_else(|| self.grants.find_free(self.mmap_min, page_count))
+    }
+    /// The page flags of new grants with the protection `flags` in this address space.
+    pub fn page_flags(&self, flags: MapFlags) -> PageFlags<RmmA> {
+        guard_flags(page_flags(flags), self.bti)
+    }
+    /// The number of pages of allocated grants backed by a frame of their own, rather than swapped
+    /// out, not yet populated, or mapped to the zeroed frame.
+    pub fn resident_pages(&self) -> usize {
+        let zeroed = the_zeroed_frame().0;
+        self.grants
+            .iter()
+            .filter(|(_, info)| {
+                matches!(
+                    info.provider,
+                    Provider::Allocated { .. } | Provider::AllocatedShared { .. }
+                )
+            })
+            .flat_map(|(base, info)| PageSpan::new(base, info.page_count).pages())
+            .filter(|page| {
+                self.table
+                    .utable
+                    .translate(page.start_address())
+                    .is_some_and(|(phys, _)| Frame::containing(phys) != zeroed)
+            })
+            .count()
+    }
+    /// Whether `page_count` more pages can be mapped, under the RLIMIT_AS of the current context.
+    fn within_as_limit(&self, page_count: usize) -> bool {
+        let limit = rlimit::current_mem_limits().address_space / PAGE_SIZE;
+        self.grants.page_count().saturating_add(page_count) <= limit
+    }
+    /// If `page` lies in the gap right below a growsdown grant, extend that grant down to `page`.
+    ///
+    /// The new pages are only reserved, and populated lazily like any other zeroed grant. At
+    /// least [`GROWSDOWN_GUARD_PAGES`] pages are always left unmapped above the grant below, and
+    /// a single fault may not extend the grant by more than [`GROWSDOWN_MAX_GAP`] pages, unless
+    /// it is a stack grant, which can grow anywhere in its reserved region but the guard pages at
+    /// the bottom of it.
+    pub(super) fn grow_down(&mut self, page: Page) {
+        let Some((gap, info)) = self.grows_down_to(page) else {
+            return;
+        };
+        let flags = info.flags;
+        let label = info.label.clone();
+        let no_huge = info.no_huge;
+        let stack_reserve = info.stack_reserve;
+
+        let mut info = GrantInfo::new(
+            gap,
+            flags,
+            true,
+            Provider::Allocated {
+                cow_file_ref: None,
+                phys_contiguous: false,
+            },
+        );
+        info.growsdown = true;
+        // Merged into the grant above, taking over what is left of the reservation.
+        info.stack_reserve = stack_reserve.saturating_sub(gap);
+        info.label = label;
+        info.no_huge = no_huge;
+
+        self.grants.insert(Grant { base: page, info });
+    }
+    /// The number of pages a growsdown grant would have to be extended by down to `page`, and
+    /// that grant, if [`Self::grow_down`] would extend it.
+    pub(super) fn grows_down_to(&self, page: Page) -> Option<(usize, &GrantInfo)> {
+        if self.grants.contains(page).is_some() || page.start_address().data() < self.mmap_min {
+            return None;
+        }
+        let (&base, info) = self.grants.inner.range(page..).next()?;
+        let gap = base.offset_from(page);
+        let window = match info.stack_reserve {
+            0 => GROWSDOWN_MAX_GAP,
+            reserve => reserve.saturating_sub(GROWSDOWN_GUARD_PAGES),
+        };
+        if !info.growsdown || gap > window || !self.within_as_limit(gap) {
+            return None;
+        }
+        let stack_limit = rlimit::current_mem_limits().stack / PAGE_SIZE;
+        if info.stack_reserve != 0 && info.page_count + gap > stack_limit {
+            return None;
+        }
+        if let Some((prev_base, prev_info)) = self.grants.inner.range(..page).next_back() {
+            let prev_end = prev_base.next_by(prev_info.page_count);
+            if page.offset_from(prev_end) < GROWSDOWN_GUARD_PAGES {
+                return None;
+            }
+        }
+        Some((gap, info))
+    }
+    /// Returns the handler a fault at `page` should be delegated to, if the page is registered
+    /// and not yet present.
+    pub(super) fn userfault_handler(&self, page: Page) -> Option<Arc<Userfault>> {
+        let (_, info) = self.grants.contains(page)?;
+        if !matches!(info.provider, Provider::Allocated { .. })
+            || self.table.utable.translate(page.start_address()).is_some()
+            || self.grants.swapped.contains(page)
+        {
+            return None;
+        }
+        self.userfault
+            .iter()
+            .find(|(span, _)| span.intersects(PageSpan::new(page, 1)))
+            .map(|(_, userfault)| Arc::clone(userfault))
+    }
     fn munmap_inner(
         this_grants: &mut UserGrants,
         this_mapper: &mut PageMapper,
@@ -569,6 +1945,8 @@ impl AddrSpace {
     ) -> Result<Vec<UnmapResult>> {
         let mut notify_files = Vec::new();
 
+        table_share::unshare(this_mapper, this_grants, requested_span, this_flusher)?;
+
         let next = |grants: &mut UserGrants, span: PageSpan| {
             grants
                 .conflicts(span)
@@ -639,6 +2017,7 @@ impl AddrSpace {
 
             // Remove irrelevant region
             let unmap_result = grant.unmap(this_mapper, this_flusher);
+            drop(this_grants.swapped.remove_span(intersection));
 
             // Notify scheme that holds grant
             if unmap_result.file_desc.is_some() {
@@ -666,7 +2045,7 @@ impl AddrSpace {
         notify_files_out: &mut Vec<UnmapResult>,
         map: impl FnOnce(Page, PageFlags<RmmA>, &mut PageMapper, &mut Flusher) -> Result<Grant>,
     ) -> Result<Page> {
-        debug_assert_eq!(dst_lock.inner.as_mut_ptr(), self as *mut Self);
+        debug_assert_eq!(dst_lock.inner.data_ptr(), self as *mut Self);
 
         let selected_span = match requested_base_opt {
             // TODO: Rename MAP_FIXED+MAP_FIXED_NOREPLACE to MAP_FIXED and
@@ -684,7 +2063,7 @@ impl AddrSpace {
                     let mut notify_files = Self::munmap_inner(
                         &mut self.grants,
                         &mut self.table.utable,
-                        &mut Flusher::with_cpu_set(&mut self.used_by, &dst_lock.tlb_ack),
+                        &mut Flusher::with_cpu_set(&mut self.used_by, dst_lock),
                         requested_span,
                         unpin,
                     )?;
@@ -693,26 +2072,39 @@ impl AddrSpace {
                     requested_span
                 } else {
                     self.grants
-                        .find_free_near(self.mmap_min, page_count.get(), Some(requested_base))
+                        .find_free_near(
+                            cmp::max(self.mmap_base, self.mmap_min),
+                            page_count.get(),
+                            Some(requested_base),
+                        )
+                        .or_else(|| self.find_free(page_count.get()))
                         .ok_or(Error::new(ENOMEM))?
                 }
             }
-            None => self
-                .grants
-                .find_free(self.mmap_min, page_count.get())
-                .ok_or(Error::new(ENOMEM))?,
+            None => self.find_free(page_count.get()).ok_or(Error::new(ENOMEM))?,
         };
+        if !self.within_as_limit(page_count.get()) {
+            return Err(Error::new(ENOMEM));
+        }
 
         // TODO: Threads share address spaces, so not only the inactive flusher should be sending
         // out IPIs. IPIs will only be sent when downgrading mappings (i.e. when a stale TLB entry
         // will not be corrected by a page fault), and will furthermore require proper
         // synchronization.
 
+        let mut flusher = Flusher::with_cpu_set(&mut self.used_by, dst_lock);
+        table_share::unshare(
+            &mut self.table.utable,
+            &self.grants,
+            selected_span,
+            &mut flusher,
+        )?;
+
         let grant = map(
             selected_span.base,
-            page_flags(flags),
+            self.page_flags(flags),
             &mut self.table.utable,
-            &mut Flusher::with_cpu_set(&mut self.used_by, &dst_lock.tlb_ack),
+            &mut flusher,
         )?;
         self.grants.insert(grant);
 
@@ -728,6 +2120,12 @@ pub struct UserGrants {
     holes: BTreeMap<VirtualAddress, usize>,
     // TODO: Would an additional map ordered by (size,start) to allow for O(log n) allocations be
     // beneficial?
+    /// Swapped out pages of private anonymous grants, whose PTEs are left empty.
+    pub swapped: SwapMap,
+    /// Total number of pages of the grants, limited by RLIMIT_AS.
+    page_count: usize,
+    /// Number of pages of the locked grants, limited by RLIMIT_MEMLOCK.
+    locked_count: usize,
 }
 
 #[derive(Clone, Copy)]
@@ -833,8 +2231,19 @@ impl UserGrants {
             inner: BTreeMap::new(),
             holes: core::iter::once((VirtualAddress::new(0), crate::USER_END_OFFSET))
                 .collect::<BTreeMap<_, _>>(),
+            swapped: SwapMap::new(),
+            page_count: 0,
+            locked_count: 0,
         }
     }
+    /// Total number of pages of the grants.
+    pub fn page_count(&self) -> usize {
+        self.page_count
+    }
+    /// Number of pages of the locked grants.
+    pub fn locked_count(&self) -> usize {
+        self.locked_count
+    }
     /// Returns the grant, if any, which occupies the specified page
     pub fn contains(&self, page: Page) -> Option<(Page, &GrantInfo)> {
         self.inner
@@ -859,6 +2268,16 @@ impl UserGrants {
             .take_while(move |(base, info)| PageSpan::new(**base, info.page_count).intersects(span))
             .map(|(base, info)| (*base, info))
     }
+    /// Whether no grant occupies any part of `span`, and no stack grant right above it reserves
+    /// any of it to grow into.
+    pub fn is_free(&self, span: PageSpan) -> bool {
+        self.conflicts(span).next().is_none()
+            && self
+                .inner
+                .range(span.end()..)
+                .next()
+                .is_none_or(|(base, info)| base.offset_from(span.end()) >= info.stack_reserve)
+    }
     // TODO: DEDUPLICATE CODE!
     pub fn conflicts_mut(
         &mut self,
@@ -877,8 +2296,8 @@ impl UserGrants {
             .take_while(move |(base, info)| PageSpan::new(**base, info.page_count).intersects(span))
             .map(|(base, info)| (*base, info))
     }
-    /// Return a free region with the specified size
-    // TODO: Alignment (x86_64: 4 KiB, 2 MiB, or 1 GiB).
+    /// Return a free region with the specified size. Large regions are aligned so that they can
+    /// be mapped with huge pages, if there is a hole large enough.
     // TODO: Support finding grant close to a requested address?
     pub fn find_free_near(
         &self,
@@ -893,25 +2312,34 @@ impl UserGrants {
         // TODO: Allow explicitly allocating guard pages? Perhaps using mprotect or mmap with
         // PROT_NONE?
 
-        let (hole_start, _hole_size) = self
-            .holes
-            .iter()
-            .skip_while(|(hole_offset, hole_size)| hole_offset.data() + **hole_size <= min)
-            .find(|(hole_offset, hole_size)| {
-                let avail_size =
-                    if hole_offset.data() <= min && min <= hole_offset.data() + **hole_size {
-                        **hole_size - (min - hole_offset.data())
-                    } else {
-                        **hole_size
-                    };
-                page_count * PAGE_SIZE <= avail_size
-            })?;
+        let size = page_count * PAGE_SIZE;
+        let find = |align: usize| {
+            self.holes
+                .iter()
+                .skip_while(|(hole_offset, hole_size)| hole_offset.data() + **hole_size <= min)
+                .find_map(|(hole_offset, hole_size)| {
+                    // Leave the reserved region of a stack right above the hole free.
+                    let hole_end = (hole_offset.data() + *hole_size)
+                        .saturating_sub(self.reserved_below(hole_offset.data() + *hole_size));
+                    let start = cmp::max(hole_offset.data(), min).next_multiple_of(align);
+                    (start < hole_end && size <= hole_end - start).then_some(start)
+                })
+        };
+        let start = find(huge_page::alignment(size)).or_else(|| find(PAGE_SIZE))?;
+
         // Create new region
         Some(PageSpan::new(
-            Page::containing_address(VirtualAddress::new(cmp::max(hole_start.data(), min))),
+            Page::containing_address(VirtualAddress::new(start)),
             page_count,
         ))
     }
+    /// Size in bytes of the region reserved right below `address`, by a stack grant starting
+    /// there.
+    fn reserved_below(&self, address: usize) -> usize {
+        self.inner
+            .get(&Page::containing_address(VirtualAddress::new(address)))
+            .map_or(0, |info| info.stack_reserve * PAGE_SIZE)
+    }
     pub fn find_free(&self, min: usize, page_count: usize) -> Option<PageSpan> {
         self.find_free_near(min, page_count, None)
     }
@@ -981,6 +2409,10 @@ impl UserGrants {
             .next()
             .is_none());
         self.reserve(grant.base, grant.info.page_count);
+        self.page_count += grant.info.page_count;
+        if grant.info.locked {
+            self.locked_count += grant.info.page_count;
+        }
 
         let before_region = self
             .inner
@@ -990,7 +2422,7 @@ impl UserGrants {
                 base.next_by(info.page_count) == grant.base
                     && info.can_be_merged_if_adjacent(&grant.info)
             })
-            .map(|(base, info)| (*base, info.page_count));
+            .map(|(base, info)| (*base, info.page_count, info.stack_reserve));
 
         let after_region = self
             .inner
@@ -1002,9 +2434,10 @@ impl UserGrants {
             })
             .map(|(base, info)| (*base, info.page_count));
 
-        if let Some((before_base, before_page_count)) = before_region {
+        if let Some((before_base, before_page_count, before_stack_reserve)) = before_region {
             grant.base = before_base;
             grant.info.page_count += before_page_count;
+            grant.info.stack_reserve = before_stack_reserve;
 
             core::mem::forget(self.inner.remove(&before_base));
         }
@@ -1019,8 +2452,46 @@ impl UserGrants {
     pub fn remove(&mut self, base: Page) -> Option<Grant> {
         let info = self.inner.remove(&base)?;
         Self::unreserve(&mut self.holes, base, info.page_count);
+        self.page_count -= info.page_count;
+        if info.locked {
+            self.locked_count -= info.page_count;
+        }
         Some(Grant { base, info })
     }
+    /// Apply `f` to the grants within `span`, splitting them at its boundaries.
+    fn update_span(&mut self, span: PageSpan, mut f: impl FnMut(&mut GrantInfo)) -> Result<()> {
+        let regions = try_collect(self.conflicts(span).map(|(base, info)| {
+            if info.can_extract(false) {
+                Ok(PageSpan::new(base, info.page_count))
+            } else {
+                Err(Error::new(EBUSY))
+            }
+        }))?;
+        if let Some(Err(err)) = regions.iter().find(|region| region.is_err()) {
+            return Err(*err);
+        }
+
+        for grant_span in regions.into_iter().flatten() {
+            let grant = self
+                .remove(grant_span.base)
+                .expect("grant cannot magically disappear while we hold the lock!");
+
+            let (before, mut grant, after) = grant
+                .extract(grant_span.intersection(span))
+                .expect("failed to extract grant");
+
+            if let Some(before) = before {
+                self.insert(before);
+            }
+            if let Some(after) = after {
+                self.insert(after);
+            }
+
+            f(&mut grant.info);
+            self.insert(grant);
+        }
+        Ok(())
+    }
     pub fn iter(&self) -> impl Iterator<Item = (Page, &GrantInfo)> + '_ {
         self.inner.iter().map(|(base, info)| (*base, info))
     }
@@ -1040,6 +2511,18 @@ pub struct GrantInfo {
     flags: PageFlags<RmmA>,
     // TODO: Rename to unmapped?
     mapped: bool,
+    /// Whether faults in the gap right below the grant extend it downwards, like MAP_GROWSDOWN.
+    growsdown: bool,
+    /// Number of pages right below a stack grant, kept free for it to grow into. Never merged
+    /// into, as it always belongs to the lowest part of the stack.
+    stack_reserve: usize,
+    /// Short user-supplied description, such as "[stack]" or a library name, shown in memory maps.
+    label: Option<Arc<str>>,
+    /// Whether faults must not map huge pages, as set by MADV_NOHUGEPAGE.
+    no_huge: bool,
+    /// Whether the pages are kept in memory, exempt from swap and reclaim, as set by mlock. Not
+    /// kept across forks.
+    locked: bool,
     pub(crate) provider: Provider,
 }
 
@@ -1132,19 +2615,24 @@ impl Grant {
                 .ok_or(Error::new(ENOMEM))?
                 .ignore();
 
-            flusher.queue(frame, None, TlbShootdownActions::NEW_MAPPING);
+            flusher.queue(
+                PageSpan::new(page, 1),
+                frame,
+                None,
+                TlbShootdownActions::NEW_MAPPING,
+            );
         }
 
         Ok(Grant {
             base: page,
-            info: GrantInfo {
-                page_count: 1,
+            info: GrantInfo::new(
+                1,
                 flags,
-                mapped: true,
-                provider: Provider::AllocatedShared {
+                true,
+                Provider::AllocatedShared {
                     is_pinned_userscheme_borrow: is_pinned,
                 },
-            },
+            ),
         })
     }
 
@@ -1164,9 +2652,28 @@ impl Grant {
             }
         }
 
-        for (i, page) in span.pages().enumerate().take(MAX_EAGER_PAGES) {
+        let mut i = 0;
+        let mut eager_entries = 0;
+        while i < span.count && eager_entries < MAX_EAGER_PAGES {
+            let page = span.base.next_by(i);
             let frame = phys.next_by(i);
+            eager_entries += 1;
             unsafe {
+                // Huge pages are mapped with the final flags, since the first write fault would
+                // split them otherwise.
+                if let Some(count) =
+                    huge_page::try_map_contiguous(mapper, page, frame, span.count - i, flags)
+                {
+                    flusher.queue(
+                        PageSpan::new(page, count.get()),
+                        frame,
+                        None,
+                        TlbShootdownActions::NEW_MAPPING,
+                    );
+                    i += count.get();
+                    continue;
+                }
+
                 let Some(result) =
                     mapper.map_phys(page.start_address(), frame.base(), flags.write(false))
                 else {
@@ -1174,18 +2681,24 @@ impl Grant {
                 };
                 result.ignore();
 
-                flusher.queue(frame, None, TlbShootdownActions::NEW_MAPPING);
+                flusher.queue(
+                    PageSpan::new(page, 1),
+                    frame,
+                    None,
+                    TlbShootdownActions::NEW_MAPPING,
+                );
             }
+            i += 1;
         }
 
         Ok(Grant {
             base: span.base,
-            info: GrantInfo {
-                page_count: span.count,
+            info: GrantInfo::new(
+                span.count,
                 flags,
-                mapped: true,
-                provider: Provider::PhysBorrowed { base: phys },
-            },
+                true,
+                Provider::PhysBorrowed { base: phys },
+            ),
         })
     }
     pub fn zeroed_phys_contiguous(
@@ -1199,37 +2712,65 @@ impl Grant {
         }
 
         let alloc_order = span.count.next_power_of_two().trailing_zeros();
-        let base = crate::memory::allocate_p2frame(alloc_order).ok_or(Enomem)?;
-
-        for (i, page) in span.pages().enumerate() {
-            let frame = base.next_by(i);
+        let memcg = memcg::charge_current(1 << alloc_order).map_err(|_| Enomem)?;
+        let Some(base) = crate::memory::allocate_p2frame(alloc_order) else {
+            memcg::uncharge(memcg, 1 << alloc_order);
+            return Err(Enomem);
+        };
+        memcg::tag(base, alloc_order, memcg);
 
-            get_page_info(frame)
+        for i in 0..span.count {
+            get_page_info(base.next_by(i))
                 .expect("PageInfo must exist for allocated frame")
                 .refcount
                 .store(RefCount::One.to_raw(), Ordering::Relaxed);
+        }
+
+        let mut i = 0;
+        while i < span.count {
+            let page = span.base.next_by(i);
+            let frame = base.next_by(i);
 
             unsafe {
+                if let Some(count) =
+                    huge_page::try_map_contiguous(mapper, page, frame, span.count - i, flags)
+                {
+                    flusher.queue(
+                        PageSpan::new(page, count.get()),
+                        frame,
+                        None,
+                        TlbShootdownActions::NEW_MAPPING,
+                    );
+                    i += count.get();
+                    continue;
+                }
+
                 let result = mapper
                     .map_phys(page.start_address(), frame.base(), flags)
                     .expect("TODO: page table OOM");
                 result.ignore();
 
-                flusher.queue(frame, None, TlbShootdownActions::NEW_MAPPING);
+                flusher.queue(
+                    PageSpan::new(page, 1),
+                    frame,
+                    None,
+                    TlbShootdownActions::NEW_MAPPING,
+                );
             }
+            i += 1;
         }
 
         Ok(Grant {
             base: span.base,
-            info: GrantInfo {
-                page_count: span.count,
+            info: GrantInfo::new(
+                span.count,
                 flags,
-                mapped: true,
-                provider: Provider::Allocated {
+                true,
+                Provider::Allocated {
                     cow_file_ref: None,
                     phys_contiguous: true,
                 },
-            },
+            ),
         })
     }
     pub fn zeroed(
@@ -1259,17 +2800,22 @@ impl Grant {
                     break;
                 };
                 result.ignore();
-                flusher.queue(the_frame, None, TlbShootdownActions::NEW_MAPPING);
+                flusher.queue(
+                    PageSpan::new(page, 1),
+                    the_frame,
+                    None,
+                    TlbShootdownActions::NEW_MAPPING,
+                );
             }
         }
 
         Ok(Grant {
             base: span.base,
-            info: GrantInfo {
-                page_count: span.count,
+            info: GrantInfo::new(
+                span.count,
                 flags,
-                mapped: true,
-                provider: if shared {
+                true,
+                if shared {
                     Provider::AllocatedShared {
                         is_pinned_userscheme_borrow: false,
                     }
@@ -1279,7 +2825,7 @@ impl Grant {
                         phys_contiguous: false,
                     }
                 },
-            },
+            ),
         })
     }
 
@@ -1296,16 +2842,16 @@ impl Grant {
     ) -> Result<Grant, Enomem> {
         Ok(Grant {
             base: dst_base,
-            info: GrantInfo {
-                page_count: src_info.page_count,
-                flags: src_info.flags,
-                mapped: true,
-                provider: Provider::External {
+            info: GrantInfo::new(
+                src_info.page_count,
+                src_info.flags,
+                true,
+                Provider::External {
                     src_base,
                     address_space: src_address_space_lock,
                     is_pinned_userscheme_borrow: false,
                 },
-            },
+            ),
         })
     }
 
@@ -1321,8 +2867,15 @@ impl Grant {
         if let Some(src) = src {
             let mut guard = src.addr_space_guard;
             let mut src_addrspace = &mut *guard;
-            let mut src_flusher_state =
-                Flusher::with_cpu_set(&mut src_addrspace.used_by, &lock.tlb_ack).detach();
+            let mut src_flusher = Flusher::with_cpu_set(&mut src_addrspace.used_by, lock);
+            table_share::unshare(
+                &mut src_addrspace.table.utable,
+                &src_addrspace.grants,
+                PageSpan::new(src.src_base, span.count),
+                &mut src_flusher,
+            )?;
+            src_flusher.flush();
+            let mut src_flusher_state = src_flusher.detach();
             for dst_page in span.pages() {
                 let src_page = src.src_base.next_by(dst_page.offset_from(span.base));
 
@@ -1407,13 +2960,19 @@ impl Grant {
                                 state: src_flusher_state,
                             };
                             src_flusher.queue(
+                                PageSpan::new(src_page, 1),
                                 frame,
                                 None,
                                 TlbShootdownActions::change_of_flags(old_flags, new_flags),
                             );
 
                             if let Some(old_frame) = old_frame {
-                                src_flusher.queue(old_frame, None, TlbShootdownActions::FREE);
+                                src_flusher.queue(
+                                    PageSpan::new(src_page, 1),
+                                    old_frame,
+                                    None,
+                                    TlbShootdownActions::FREE,
+                                );
                             }
                             src_flusher_state = src_flusher.detach();
 
@@ -1439,22 +2998,27 @@ impl Grant {
                         .unwrap();
                     flush.ignore();
 
-                    flusher.queue(frame, None, TlbShootdownActions::NEW_MAPPING);
+                    flusher.queue(
+                        PageSpan::new(dst_page, 1),
+                        frame,
+                        None,
+                        TlbShootdownActions::NEW_MAPPING,
+                    );
                 }
             }
         }
 
         Ok(Self {
             base: span.base,
-            info: GrantInfo {
-                page_count: span.count,
-                mapped: true,
-                flags: new_flags,
-                provider: Provider::FmapBorrowed {
+            info: GrantInfo::new(
+                span.count,
+                new_flags,
+                true,
+                Provider::FmapBorrowed {
                     file_ref,
                     pin_refcount: 0,
                 },
-            },
+            ),
         })
     }
 
@@ -1531,6 +3095,18 @@ impl Grant {
             return Err(Error::new(EINVAL));
         }
         if eager {
+            let mut src_flusher = Flusher::with_cpu_set(
+                &mut src_address_space.used_by,
+                src_address_space_lock,
+            );
+            table_share::unshare(
+                &mut src_address_space.table.utable,
+                &src_address_space.grants,
+                src_span,
+                &mut src_flusher,
+            )?;
+            drop(src_flusher);
+
             for (i, page) in PageSpan::new(src_base, page_count)
                 .pages()
                 .enumerate()
@@ -1566,6 +3142,7 @@ impl Grant {
                     flush.ignore();
 
                     dst_flusher.queue(
+                        PageSpan::new(dst_base.next_by(i), 1),
                         Frame::containing(phys),
                         None,
                         TlbShootdownActions::NEW_MAPPING,
@@ -1576,16 +3153,16 @@ impl Grant {
 
         Ok(Grant {
             base: dst_base,
-            info: GrantInfo {
+            info: GrantInfo::new(
                 page_count,
                 flags,
-                mapped: true,
-                provider: Provider::External {
+                true,
+                Provider::External {
                     address_space: src_address_space_lock,
                     src_base,
                     is_pinned_userscheme_borrow,
                 },
-            },
+            ),
         })
     }
     pub fn copy_mappings(
@@ -1625,7 +3202,12 @@ impl Grant {
                         flush.ignore();
                     }
                     let frame = Frame::containing(phys);
-                    src_flusher.queue(frame, None, TlbShootdownActions::REVOKE_WRITE);
+                    src_flusher.queue(
+                        PageSpan::new(src_page, 1),
+                        frame,
+                        None,
+                        TlbShootdownActions::REVOKE_WRITE,
+                    );
                     frame
                 }
                 RefKind::Shared => {
@@ -1633,7 +3215,8 @@ impl Grant {
                         Frame::containing(phys)
                     } else {
                         // TODO: Omit the unnecessary subsequent add_ref call.
-                        let new_frame = init_frame(RefCount::One).expect("TODO: handle OOM");
+                        let new_frame =
+                            init_user_frame(RefCount::One, None).map_err(|_| Enomem)?;
                         let src_flush = unsafe {
                             src_mapper
                                 .map_phys(src_page.start_address(), new_frame.base(), flags)
@@ -1642,7 +3225,12 @@ impl Grant {
                         unsafe {
                             src_flush.ignore();
                         }
-                        src_flusher.queue(new_frame, None, TlbShootdownActions::NEW_MAPPING);
+                        src_flusher.queue(
+                            PageSpan::new(src_page, 1),
+                            new_frame,
+                            None,
+                            TlbShootdownActions::NEW_MAPPING,
+                        );
 
                         new_frame
                     }
@@ -1654,14 +3242,24 @@ impl Grant {
                     .expect("allocated page was not present in the global page array");
 
                 match src_page_info.add_ref(rk) {
-                    Ok(()) => src_frame,
+                    Ok(()) => {
+                        if rk == RefKind::Cow {
+                            COW_STATS.get().shared.fetch_add(1, Ordering::Relaxed);
+                        }
+                        src_frame
+                    }
                     Err(AddRefError::CowToShared) => {
                         let CowResult {
                             new_frame,
                             old_frame,
                         } = cow(src_frame, src_page_info, rk).map_err(|_| Enomem)?;
                         if let Some(old_frame) = old_frame {
-                            src_flusher.queue(old_frame, None, TlbShootdownActions::FREE);
+                            src_flusher.queue(
+                                PageSpan::new(src_page, 1),
+                                old_frame,
+                                None,
+                                TlbShootdownActions::FREE,
+                            );
                         }
 
                         // TODO: Flusher
@@ -1679,6 +3277,7 @@ impl Grant {
 
                                 // FIXME: Is MOVE correct?
                                 src_flusher.queue(
+                                    PageSpan::new(src_page, 1),
                                     Frame::containing(phys),
                                     None,
                                     TlbShootdownActions::MOVE,
@@ -1702,7 +3301,12 @@ impl Grant {
                             old_frame,
                         } = cow(src_frame, src_page_info, rk).map_err(|_| Enomem)?;
                         if let Some(old_frame) = old_frame {
-                            src_flusher.queue(old_frame, None, TlbShootdownActions::FREE);
+                            src_flusher.queue(
+                                PageSpan::new(src_page, 1),
+                                old_frame,
+                                None,
+                                TlbShootdownActions::FREE,
+                            );
                         }
                         new_frame
                     }
@@ -1723,16 +3327,21 @@ impl Grant {
                 map_result.ignore();
             }
 
-            dst_flusher.queue(src_frame, None, TlbShootdownActions::NEW_MAPPING);
+            dst_flusher.queue(
+                PageSpan::new(dst_base.next_by(page_idx), 1),
+                src_frame,
+                None,
+                TlbShootdownActions::NEW_MAPPING,
+            );
         }
 
         Ok(Grant {
             base: dst_base,
-            info: GrantInfo {
+            info: GrantInfo::new(
                 page_count,
                 flags,
-                mapped: true,
-                provider: match mode {
+                true,
+                match mode {
                     CopyMappingsMode::Owned { cow_file_ref } => Provider::Allocated {
                         cow_file_ref,
                         phys_contiguous: false,
@@ -1741,7 +3350,7 @@ impl Grant {
                         is_pinned_userscheme_borrow: false,
                     },
                 },
-            },
+            ),
         })
     }
     /// Move a grant between two address spaces.
@@ -1770,20 +3379,34 @@ impl Grant {
             unsafe {
                 flush.ignore();
             }
-            src_flusher.queue(Frame::containing(phys), None, TlbShootdownActions::MOVE);
+            src_flusher.queue(
+                PageSpan::new(src_page, 1),
+                Frame::containing(phys),
+                None,
+                TlbShootdownActions::MOVE,
+            );
 
             let dst_mapper = dst_mapper.as_deref_mut().unwrap_or(&mut *src_mapper);
 
+            // Frames still shared copy-on-write must remain read-only.
+            let writable =
+                get_page_info(Frame::containing(phys)).map_or(true, |info| info.allows_writable());
+
             // TODO: Preallocate to handle OOM?
             let flush = unsafe {
                 dst_mapper
-                    .map_phys(dst_page.start_address(), phys, flags)
+                    .map_phys(
+                        dst_page.start_address(),
+                        phys,
+                        flags.write(flags.has_write() && writable),
+                    )
                     .expect("TODO: OOM")
             };
             unsafe {
                 flush.ignore();
             }
             dst_flusher.queue(
+                PageSpan::new(dst_page, 1),
                 Frame::containing(phys),
                 None,
                 TlbShootdownActions::NEW_MAPPING,
@@ -1804,19 +3427,28 @@ impl Grant {
         assert!(self.info.mapped);
 
         for page in self.span().pages() {
+            // Pages still shared copy-on-write, like the zeroed frame, are only made writable by
+            // the fault that copies them.
+            let cow = flags.has_write()
+                && mapper
+                    .translate(page.start_address())
+                    .and_then(|(phys, _)| get_page_info(Frame::containing(phys)))
+                    .is_some_and(|info| matches!(info.refcount(), Some(RefCount::Cow(_))));
+            let page_flags = if cow { flags.write(false) } else { flags };
             unsafe {
                 // Lazy mappings don't require remapping, as info.flags will be updated.
                 let Some((old_flags, phys, flush)) =
-                    mapper.remap_with(page.start_address(), |_| flags)
+                    mapper.remap_with(page.start_address(), |_| page_flags)
                 else {
                     continue;
                 };
                 flush.ignore();
                 //log::info!("Remapped page {:?} (frame {:?})", page, Frame::containing(mapper.translate(page.start_address()).unwrap().0));
                 flusher.queue(
+                    PageSpan::new(page, 1),
                     Frame::containing(phys),
                     None,
-                    TlbShootdownActions::change_of_flags(old_flags, flags),
+                    TlbShootdownActions::change_of_flags(old_flags, page_flags),
                 );
             }
         }
@@ -1874,16 +3506,27 @@ impl Grant {
             Provider::PhysBorrowed { .. } => None,
             Provider::FmapBorrowed { .. } => Some(true),
         };
+        // Whether pages of a shared file mapping were written to since they were last written
+        // back, which without dirty bits is assumed of all writable mappings.
+        let mut written = ENTRY_FLAG_DIRTY == 0 && self.info.flags.has_write();
+
+        // Huge pages never extend beyond their grant, and are removed as a whole.
+        let huge_base = huge_page::unmap(mapper, self.span(), !is_phys_contiguous, flusher);
 
         if is_phys_contiguous {
-            let (phys_base, _) = mapper.translate(self.base.start_address()).unwrap();
-            let base_frame = Frame::containing(phys_base);
+            let base_frame = huge_base.unwrap_or_else(|| {
+                let (phys_base, _) = mapper.translate(self.base.start_address()).unwrap();
+                Frame::containing(phys_base)
+            });
 
             for i in 0..self.info.page_count {
                 unsafe {
-                    let (phys, _, flush) = mapper
-                        .unmap_phys(self.base.next_by(i).start_address(), true)
-                        .expect("all physborrowed grants must be fully Present in the page tables");
+                    // Pages that were part of huge pages are already gone.
+                    let Some((phys, _, flush)) =
+                        mapper.unmap_phys(self.base.next_by(i).start_address(), true)
+                    else {
+                        continue;
+                    };
                     flush.ignore();
 
                     assert_eq!(phys, base_frame.next_by(i).base());
@@ -1891,6 +3534,7 @@ impl Grant {
             }
 
             flusher.queue(
+                self.span(),
                 base_frame,
                 Some(NonZeroUsize::new(self.info.page_count).unwrap()),
                 TlbShootdownActions::FREE,
@@ -1898,7 +3542,7 @@ impl Grant {
         } else {
             for page in self.span().pages() {
                 // Lazy mappings do not need to be unmapped.
-                let Some((phys, _, flush)) =
+                let Some((phys, flags, flush)) =
                     (unsafe { mapper.unmap_phys(page.start_address(), true) })
                 else {
                     continue;
@@ -1906,8 +3550,14 @@ impl Grant {
                 unsafe {
                     flush.ignore();
                 }
+                written |= flags.data() & ENTRY_FLAG_DIRTY != 0;
 
-                flusher.queue(Frame::containing(phys), None, TlbShootdownActions::FREE);
+                flusher.queue(
+                    PageSpan::new(page, 1),
+                    Frame::containing(phys),
+                    None,
+                    TlbShootdownActions::FREE,
+                );
             }
         }
 
@@ -1924,7 +3574,7 @@ impl Grant {
         let mut munmap_flags = MunmapFlags::empty();
         munmap_flags.set(
             MunmapFlags::NEEDS_SYNC,
-            is_fmap_shared.unwrap_or(false) && self.info.flags.has_write(),
+            is_fmap_shared.unwrap_or(false) && written,
         );
 
         UnmapResult {
@@ -1964,6 +3614,11 @@ impl Grant {
             info: GrantInfo {
                 flags: self.info.flags,
                 mapped: self.info.mapped,
+                growsdown: self.info.growsdown,
+                stack_reserve: core::mem::take(&mut self.info.stack_reserve),
+                label: self.info.label.clone(),
+                no_huge: self.info.no_huge,
+                locked: self.info.locked,
                 page_count: span.count,
                 provider: match self.info.provider {
                     Provider::External {
@@ -2018,6 +3673,11 @@ impl Grant {
             info: GrantInfo {
                 flags: self.info.flags,
                 mapped: self.info.mapped,
+                growsdown: self.info.growsdown,
+                stack_reserve: 0,
+                label: self.info.label.clone(),
+                no_huge: self.info.no_huge,
+                locked: self.info.locked,
                 page_count: span.count,
                 provider: match self.info.provider {
                     Provider::Allocated {
@@ -2070,6 +3730,25 @@ impl Grant {
     }
 }
 impl GrantInfo {
+    /// A grant of `page_count` pages, neither growing down nor locked, without a label.
+    pub fn new(
+        page_count: usize,
+        flags: PageFlags<RmmA>,
+        mapped: bool,
+        provider: Provider,
+    ) -> Self {
+        Self {
+            page_count,
+            flags,
+            mapped,
+            growsdown: false,
+            stack_reserve: 0,
+            label: None,
+            no_huge: false,
+            locked: false,
+            provider,
+        }
+    }
     pub fn is_pinned(&self) -> bool {
         matches!(
             self.provider,
@@ -2112,10 +3791,124 @@ impl GrantInfo {
     pub fn flags(&self) -> PageFlags<RmmA> {
         self.flags
     }
+    /// Whether the grant is mapped as tagged memory, see [`crate::mte`].
+    pub fn is_tagged(&self) -> bool {
+        #[cfg(target_arch = "aarch64")]
+        {
+            crate::mte::is_tagged(self.flags)
+        }
+        #[cfg(not(target_arch = "aarch64"))]
+        {
+            false
+        }
+    }
+    /// Whether the grant is a shadow stack, see [`crate::cet`].
+    pub fn is_shadow_stack(&self) -> bool {
+        #[cfg(target_arch = "x86_64")]
+        {
+            crate::cet::is_shadow_stack(self.flags)
+        }
+        #[cfg(not(target_arch = "x86_64"))]
+        {
+            false
+        }
+    }
     pub fn page_count(&self) -> usize {
         self.page_count
     }
+    pub fn is_growsdown(&self) -> bool {
+        self.growsdown
+    }
+    pub fn label(&self) -> Option<&str> {
+        self.label.as_deref()
+    }
+    /// The grant as retained by a clone of the address space, without any of its pages, or None
+    /// if the grant is not kept across forks.
+    fn clone_for_fork(&self) -> Option<GrantInfo> {
+        let provider = match self.provider {
+            // No, your temporary UserScheme mappings will not be kept across forks.
+            Provider::External {
+                is_pinned_userscheme_borrow: true,
+                ..
+            }
+            | Provider::AllocatedShared {
+                is_pinned_userscheme_borrow: true,
+            } => return None,
+
+            // No, physically contiguous driver memory won't either.
+            Provider::Allocated {
+                phys_contiguous: true,
+                ..
+            } => return None,
+            Provider::FmapBorrowed { .. } => return None,
+
+            Provider::Allocated {
+                ref cow_file_ref,
+                phys_contiguous: false,
+            } => Provider::Allocated {
+                cow_file_ref: cow_file_ref.clone(),
+                phys_contiguous: false,
+            },
+            Provider::AllocatedShared {
+                is_pinned_userscheme_borrow: false,
+            } => Provider::AllocatedShared {
+                is_pinned_userscheme_borrow: false,
+            },
+            Provider::PhysBorrowed { base } => Provider::PhysBorrowed { base },
+            Provider::External {
+                ref address_space,
+                src_base,
+                ..
+            } => Provider::External {
+                address_space: Arc::clone(address_space),
+                src_base,
+                is_pinned_userscheme_borrow: false,
+            },
+        };
+        let mut info = GrantInfo::new(self.page_count, self.flags, true, provider);
+        info.growsdown = self.growsdown;
+        info.stack_reserve = self.stack_reserve;
+        info.label = self.label.clone();
+        info.no_huge = self.no_huge;
+        Some(info)
+    }
+    /// Whether a read-only borrow with `flags` can map the leaf page tables of this grant as is,
+    /// rather than mapping each page separately.
+    pub fn allows_table_sharing(&self, flags: PageFlags<RmmA>) -> bool {
+        matches!(
+            self.provider,
+            Provider::AllocatedShared {
+                is_pinned_userscheme_borrow: false
+            }
+        ) && !self.flags.has_write()
+            && !flags.has_write()
+            && self.flags.has_execute() == flags.has_execute()
+    }
+    /// Mark the grant as growing downwards. Only private anonymous grants can grow.
+    pub fn set_growsdown(&mut self) -> Result<()> {
+        if !matches!(
+            self.provider,
+            Provider::Allocated {
+                cow_file_ref: None,
+                phys_contiguous: false,
+            }
+        ) {
+            return Err(Error::new(EOPNOTSUPP));
+        }
+        self.growsdown = true;
+        Ok(())
+    }
+    /// Make the grant a stack, growing down into the `reserve` pages right below it.
+    pub fn set_stack(&mut self, reserve: usize) -> Result<()> {
+        self.set_growsdown()?;
+        self.stack_reserve = reserve;
+        Ok(())
+    }
     pub fn can_have_flags(&self, flags: MapFlags) -> bool {
+        // Made writable or even read-only again, they would no longer be shadow stacks.
+        if self.is_shadow_stack() {
+            return false;
+        }
         // TODO: read (some architectures support execute-only pages)
         let is_downgrade = (self.flags.has_write() || !flags.contains(MapFlags::PROT_WRITE))
             && (self.flags.has_execute() || !flags.contains(MapFlags::PROT_EXEC));
@@ -2127,7 +3920,13 @@ impl GrantInfo {
     }
 
     pub fn can_be_merged_if_adjacent(&self, with: &Self) -> bool {
-        if self.mapped != with.mapped || self.flags.data() != with.flags.data() {
+        if self.mapped != with.mapped
+            || self.growsdown != with.growsdown
+            || self.label != with.label
+            || self.no_huge != with.no_huge
+            || self.locked != with.locked
+            || self.flags.data() != with.flags.data()
+        {
             return false;
         }
 
@@ -2220,10 +4019,18 @@ pub const DANGLING: usize = 1 << (usize::BITS - 2);
 #[derive(Debug)]
 pub struct Table {
     pub utable: PageMapper,
+    /// The table userspace runs with, see `arch::pti`.
+    #[cfg(all(feature = "pti", target_arch = "x86_64"))]
+    pub shadow: Frame,
 }
 
 impl Drop for AddrSpace {
     fn drop(&mut self) {
+        // Page tables still shared with other address spaces are left to them.
+        unsafe {
+            table_share::detach(&mut self.table.utable);
+        }
+
         for mut grant in core::mem::take(&mut self.grants).into_iter() {
             // Unpinning the grant is allowed, because pinning only occurs in UserScheme calls to
             // prevent unmapping the mapped range twice (which would corrupt only the scheme
@@ -2255,6 +4062,8 @@ impl Drop for Table {
         }
         unsafe {
             deallocate_frame(Frame::containing(self.utable.table().phys()));
+            #[cfg(all(feature = "pti", target_arch = "x86_64"))]
+            deallocate_frame(self.shadow);
         }
     }
 }
@@ -2274,6 +4083,10 @@ pub enum PfError {
     // TODO: Handle recursion limit by mapping a zeroed page? Or forbid borrowing borrowed memory,
     // and ensure pages are mapped at grant time?
     RecursionLimitExceeded,
+    /// Waiting for a userfault handler was interrupted by a signal.
+    Interrupted,
+    /// The memory group of the context is at its limit, and no pages could be reclaimed.
+    MemLimit,
 }
 
 pub struct CowResult {
@@ -2303,6 +4116,8 @@ fn cow(
     };
 
     if old_refcount == Some(RefCount::One) {
+        COW_STATS.get().reused.fetch_add(1, Ordering::Relaxed);
+
         // We were lucky; the frame was already exclusively owned, so the refcount cannot be
         // modified unless we modify it. This is the special case where the old_frame returned is
         // None.
@@ -2318,7 +4133,8 @@ fn cow(
         });
     }
 
-    let new_frame = init_frame(initial_rc)?;
+    let new_frame = init_user_frame(initial_rc, None)?;
+    COW_STATS.get().copied.fetch_add(1, Ordering::Relaxed);
 
     if old_frame != the_zeroed_frame().0 {
         unsafe {
@@ -2332,13 +4148,128 @@ fn cow(
     })
 }
 
+fn is_private_anon(info: &GrantInfo) -> bool {
+    matches!(
+        info.provider,
+        Provider::Allocated {
+            cow_file_ref: None,
+            phys_contiguous: false,
+        }
+    )
+}
+/// Check that `span` is entirely covered by grants, which with `private_only` must all be private
+/// anonymous ones.
+fn check_advisable(grants: &UserGrants, span: PageSpan, private_only: bool) -> Result<()> {
+    let mut covered = 0;
+    for (base, info) in grants.conflicts(span) {
+        if private_only && !is_private_anon(info) {
+            return Err(Error::new(EINVAL));
+        }
+        covered += PageSpan::new(base, info.page_count)
+            .intersection(span)
+            .count;
+    }
+    if covered != span.count {
+        return Err(Error::new(ENOMEM));
+    }
+    Ok(())
+}
+/// The leaf entry that maps, or would map, `page`, if the tables leading to it are present.
+fn leaf_entry(mapper: &PageMapper, page: Page) -> Option<&'static AtomicUsize> {
+    let address = page.start_address().data();
+    let mut table = mapper.table().phys();
+
+    for level in (0..RmmA::PAGE_LEVELS).rev() {
+        let shift = RmmA::PAGE_SHIFT + level * RmmA::PAGE_ENTRY_SHIFT;
+        let index = (address >> shift) & (RmmA::PAGE_ENTRIES - 1);
+        let slot = unsafe { &*(RmmA::phys_to_virt(table).data() as *const AtomicUsize).add(index) };
+        if level == 0 {
+            return Some(slot);
+        }
+        let entry = slot.load(Ordering::Relaxed);
+        if entry & RmmA::ENTRY_FLAG_PRESENT == 0 || entry & ENTRY_FLAG_HUGE != 0 {
+            return None;
+        }
+        table = PhysicalAddress::new(entry & RmmA::ENTRY_ADDRESS_MASK);
+    }
+    None
+}
+
+/// Map `page` back in if it was swapped out, waiting for the swap provider to read it if needed.
+fn swap_in<'l>(
+    addr_space_lock: &'l Arc<AddrSpaceWrapper>,
+    mut addr_space_guard: Tracked<RwLockWriteGuard<'l, AddrSpace>>,
+    page: Page,
+) -> Result<Tracked<RwLockWriteGuard<'l, AddrSpace>>, PfError> {
+    loop {
+        let addr_space = &mut *addr_space_guard;
+        if !addr_space.grants.swapped.contains(page) {
+            return Ok(addr_space_guard);
+        }
+        let Some((_, grant_info)) = addr_space.grants.contains(page) else {
+            return Ok(addr_space_guard);
+        };
+        let flags = grant_info.flags();
+
+        let mut flusher = Flusher::with_cpu_set(&mut addr_space.used_by, addr_space_lock);
+        table_share::unshare(
+            &mut addr_space.table.utable,
+            &addr_space.grants,
+            PageSpan::new(page, 1),
+            &mut flusher,
+        )
+        .map_err(|_| PfError::Oom)?;
+        drop(flusher);
+
+        let Some(entry) = addr_space.grants.swapped.remove(page) else {
+            return Ok(addr_space_guard);
+        };
+        let frame = match swap::swap_in(&entry) {
+            Ok(Some(frame)) => frame,
+            Ok(None) => {
+                let slot = entry.slot();
+                addr_space.grants.swapped.insert(page, entry);
+                drop(addr_space_guard);
+
+                if !swap::wait_for_read(slot) {
+                    return Err(PfError::Interrupted);
+                }
+                addr_space_guard = addr_space_lock.acquire_write();
+                continue;
+            }
+            Err(err) => {
+                addr_space.grants.swapped.insert(page, entry);
+                return Err(err);
+            }
+        };
+
+        let Some(flush) = (unsafe {
+            addr_space
+                .table
+                .utable
+                .map_phys(page.start_address(), frame.base(), flags)
+        }) else {
+            swap::undo_swap_in(&entry, frame);
+            addr_space.grants.swapped.insert(page, entry);
+            return Err(PfError::Oom);
+        };
+        // Not-present pages are never cached in the TLB.
+        unsafe {
+            flush.ignore();
+        }
+        addr_space_lock.home_node.account(frame);
+        return Ok(addr_space_guard);
+    }
+}
+
 fn map_zeroed(
     mapper: &mut PageMapper,
     page: Page,
     page_flags: PageFlags<RmmA>,
     _writable: bool,
+    hint: Option<NodeHint>,
 ) -> Result<Frame, PfError> {
-    let new_frame = init_frame(RefCount::One)?;
+    let new_frame = init_user_frame(RefCount::One, hint)?;
 
     unsafe {
         mapper
@@ -2356,6 +4287,10 @@ pub unsafe fn copy_frame_to_frame_directly(dst: Frame, src: Frame) {
     // TODO: For new frames, when the kernel's linear phys=>virt mappings are 4k, this is almost
     // guaranteed to cause either one (or two) TLB misses.
 
+    // Pages of tagged grants keep their tags when copied on write.
+    #[cfg(target_arch = "aarch64")]
+    crate::mte::copy_tags(dst, src);
+
     let dst = unsafe { RmmA::phys_to_virt(dst.base()).data() as *mut u8 };
     let src = unsafe { RmmA::phys_to_virt(src.base()).data() as *const u8 };
 
@@ -2364,28 +4299,24 @@ pub unsafe fn copy_frame_to_frame_directly(dst: Frame, src: Frame) {
     }
 }
 
-pub fn try_correcting_page_tables(faulting_page: Page, access: AccessMode) -> Result<(), PfError> {
-    let Ok(addr_space_lock) = AddrSpace::current() else {
-        log::debug!("User page fault without address space being set.");
-        return Err(PfError::Segv);
-    };
-
-    let lock = &addr_space_lock;
-    let (_, flush, _) = correct_inner(lock, lock.acquire_write(), faulting_page, access, 0)?;
-
-    flush.flush();
-
-    Ok(())
-}
-fn correct_inner<'l>(
+pub(super) fn correct_inner<'l>(
     addr_space_lock: &'l Arc<AddrSpaceWrapper>,
-    mut addr_space_guard: RwLockWriteGuard<'l, AddrSpace>,
+    mut addr_space_guard: Tracked<RwLockWriteGuard<'l, AddrSpace>>,
     faulting_page: Page,
     access: AccessMode,
     recursion_level: u32,
-) -> Result<(Frame, PageFlush<RmmA>, RwLockWriteGuard<'l, AddrSpace>), PfError> {
+) -> Result<
+    (
+        Frame,
+        PageFlush<RmmA>,
+        Tracked<RwLockWriteGuard<'l, AddrSpace>>,
+    ),
+    PfError,
+> {
+    addr_space_guard = swap_in(addr_space_lock, addr_space_guard, faulting_page)?;
+
     let mut addr_space = &mut *addr_space_guard;
-    let mut flusher = Flusher::with_cpu_set(&mut addr_space.used_by, &addr_space_lock.tlb_ack);
+    let mut flusher = Flusher::with_cpu_set(&mut addr_space.used_by, addr_space_lock);
 
     let Some((grant_base, grant_info)) = addr_space.grants.contains(faulting_page) else {
         log::debug!("Lacks grant");
@@ -2414,6 +4345,14 @@ fn correct_inner<'l>(
     // By now, the memory at the faulting page is actually valid, but simply not yet mapped, either
     // at all, or with the required flags.
 
+    table_share::unshare(
+        &mut addr_space.table.utable,
+        &addr_space.grants,
+        PageSpan::new(faulting_page, 1),
+        &mut flusher,
+    )
+    .map_err(|_| PfError::Oom)?;
+
     let faulting_frame_opt = addr_space
         .table
         .utable
@@ -2421,12 +4360,55 @@ fn correct_inner<'l>(
         .map(|(phys, _page_flags)| Frame::containing(phys));
     let faulting_pageinfo_opt = faulting_frame_opt.map(|frame| (frame, get_page_info(frame)));
 
+    // The policy of the address space takes precedence over that of the context touching it.
+    let node_hint = addr_space
+        .mempolicy
+        .or(numa::current_policy())
+        .hint(faulting_page);
+
     // TODO: Aligned readahead? AMD Zen3+ CPUs can smash 4 4k pages that are 16k-aligned, into a
     // single TLB entry, thus emulating 16k pages albeit with higher page table overhead. With the
     // correct madvise information, allocating 4 contiguous pages and mapping them together, might
     // be a useful future optimization.
-    //
-    // TODO: Readahead backwards, i.e. MAP_GROWSDOWN.
+
+    // Private anonymous memory is mapped with huge pages where possible when written to, unless
+    // the fault comes from an address space borrowing from this one, which will share the frame.
+    // Reads map the zeroed frame below instead, so that sparse reads allocate nothing.
+    let huge_eligible = huge_page::SUPPORTED
+        && recursion_level == 0
+        && access == AccessMode::Write
+        && faulting_frame_opt.is_none()
+        && grant_flags.has_write()
+        && !grant_info.no_huge
+        && !grant_info.is_tagged()
+        && matches!(
+            grant_info.provider,
+            Provider::Allocated {
+                cow_file_ref: None,
+                phys_contiguous: false,
+            }
+        )
+        && !addr_space
+            .grants
+            .swapped
+            .intersects(PageSpan::new(grant_base, grant_info.page_count));
+    if huge_eligible
+        && let Some(frame) = huge_page::try_map_zeroed(
+            &mut addr_space.table.utable,
+            PageSpan::new(grant_base, grant_info.page_count),
+            faulting_page,
+            grant_flags,
+            node_hint,
+        )
+    {
+        addr_space_lock.home_node.account(frame);
+        drop(flusher);
+        return Ok((
+            frame,
+            PageFlush::new(faulting_page.start_address()),
+            addr_space_guard,
+        ));
+    }
 
     let mut allow_writable = true;
 
@@ -2442,17 +4424,27 @@ fn correct_inner<'l>(
                     } else {
                         let result = cow(frame, info, RefKind::Cow)?;
                         if let Some(old_frame) = result.old_frame {
-                            flusher.queue(old_frame, None, TlbShootdownActions::FREE);
+                            flusher.queue(
+                                PageSpan::new(faulting_page, 1),
+                                old_frame,
+                                None,
+                                TlbShootdownActions::FREE,
+                            );
                         }
                         result.new_frame
                     }
                 }
-                _ => map_zeroed(
-                    &mut addr_space.table.utable,
-                    faulting_page,
-                    grant_flags,
-                    true,
-                )?,
+                _ => {
+                    let frame = map_zeroed(
+                        &mut addr_space.table.utable,
+                        faulting_page,
+                        grant_flags,
+                        true,
+                        node_hint,
+                    )?;
+                    addr_space_lock.home_node.account(frame);
+                    frame
+                }
             }
         }
 
@@ -2471,14 +4463,35 @@ fn correct_inner<'l>(
                     frame
                 }
 
+                // Private pages that were never written to read as zeroes, so they map the zeroed
+                // frame, and only get a frame of their own when written to, copied on write. Not
+                // for borrowers, as the frame would then be shared rather than copied.
+                None if recursion_level == 0
+                    && matches!(
+                        grant_info.provider,
+                        Provider::Allocated {
+                            phys_contiguous: false,
+                            ..
+                        }
+                    ) =>
+                {
+                    let (frame, info) = the_zeroed_frame();
+                    info.add_ref(RefKind::Cow)
+                        .expect("the static zeroed frame cannot be shared!");
+                    allow_writable = false;
+                    COW_STATS.get().zero_mapped.fetch_add(1, Ordering::Relaxed);
+                    frame
+                }
                 None => {
-                    // TODO: the zeroed page first, readonly?
-                    map_zeroed(
+                    let frame = map_zeroed(
                         &mut addr_space.table.utable,
                         faulting_page,
                         grant_flags,
                         false,
-                    )?
+                        node_hint,
+                    )?;
+                    addr_space_lock.home_node.account(frame);
+                    frame
                 }
             }
         }
@@ -2497,7 +4510,73 @@ fn correct_inner<'l>(
             let mut guard = foreign_address_space.acquire_upgradeable_read();
             let src_page = src_base.next_by(pages_from_grant_start);
 
-            if let Some(_) = guard.grants.contains(src_page) {
+            if let Some((src_grant_base, src_info)) = guard.grants.contains(src_page) {
+                let is_private = matches!(src_info.provider, Provider::Allocated { .. });
+
+                // Large read-only shared mappings can reuse the leaf tables of the foreign
+                // address space, if aligned the same way.
+                let dst_window = table_share::leaf_table_span(faulting_page);
+                let src_window = table_share::leaf_table_span(src_page);
+                let share_table = table_share::SUPPORTED
+                    && access != AccessMode::Write
+                    && src_info.allows_table_sharing(grant_flags)
+                    && faulting_page.offset_from(dst_window.base)
+                        == src_page.offset_from(src_window.base)
+                    && PageSpan::new(grant_base, grant_info.page_count)
+                        .intersection(dst_window)
+                        .count
+                        == dst_window.count
+                    && PageSpan::new(src_grant_base, src_info.page_count)
+                        .intersection(src_window)
+                        .count
+                        == src_window.count;
+
+                if is_private || share_table {
+                    let mut foreign_guard = Tracked::map(guard, RwLockUpgradableGuard::upgrade);
+                    let foreign = &mut *foreign_guard;
+                    let mut foreign_flusher =
+                        Flusher::with_cpu_set(&mut foreign.used_by, foreign_address_space);
+
+                    if is_private {
+                        // The frame is about to be shared, so it must not be reachable from page
+                        // tables shared with other clones of the foreign address space.
+                        table_share::unshare(
+                            &mut foreign.table.utable,
+                            &foreign.grants,
+                            PageSpan::new(src_page, 1),
+                            &mut foreign_flusher,
+                        )
+                        .map_err(|_| PfError::Oom)?;
+                    } else if unsafe {
+                        table_share::share_leaf_table(
+                            &mut foreign.table.utable,
+                            src_window,
+                            &mut addr_space.table.utable,
+                            dst_window,
+                            &mut foreign_flusher,
+                        )
+                    }
+                    .map_err(|_| PfError::Oom)?
+                    {
+                        let (phys, _) = addr_space
+                            .table
+                            .utable
+                            .translate(faulting_page.start_address())
+                            .ok_or(PfError::NonfatalInternalError)?;
+
+                        drop(foreign_flusher);
+                        drop(foreign_guard);
+                        drop(flusher);
+                        return Ok((
+                            Frame::containing(phys),
+                            PageFlush::new(faulting_page.start_address()),
+                            addr_space_guard,
+                        ));
+                    }
+                    drop(foreign_flusher);
+                    guard = Tracked::map(foreign_guard, RwLockWriteGuard::downgrade_to_upgradable);
+                }
+
                 let src_frame = if let Some((phys, _)) =
                     guard.table.utable.translate(src_page.start_address())
                 {
@@ -2532,7 +4611,7 @@ fn correct_inner<'l>(
                     addr_space_guard = addr_space_lock.acquire_write();
                     addr_space = &mut *addr_space_guard;
                     flusher =
-                        Flusher::with_cpu_set(&mut addr_space.used_by, &addr_space_lock.tlb_ack);
+                        Flusher::with_cpu_set(&mut addr_space.used_by, addr_space_lock);
                     guard = foreign_address_space.acquire_upgradeable_read();
 
                     frame
@@ -2549,11 +4628,16 @@ fn correct_inner<'l>(
                         } = cow(src_frame, info, RefKind::Shared)?;
 
                         if let Some(old_frame) = old_frame {
-                            flusher.queue(old_frame, None, TlbShootdownActions::FREE);
+                            flusher.queue(
+                                PageSpan::new(src_page, 1),
+                                old_frame,
+                                None,
+                                TlbShootdownActions::FREE,
+                            );
                             flusher.flush();
                         }
 
-                        let mut guard = RwLockUpgradableGuard::upgrade(guard);
+                        let mut guard = Tracked::map(guard, RwLockUpgradableGuard::upgrade);
 
                         // TODO: flusher
                         unsafe {
@@ -2575,7 +4659,19 @@ fn correct_inner<'l>(
                 // simply let the current context fail. TODO: But all borrowed memory shouldn't
                 // really be lazy though? TODO: Should a grant be created?
 
-                let mut guard = RwLockUpgradableGuard::upgrade(guard);
+                let mut guard = Tracked::map(guard, RwLockUpgradableGuard::upgrade);
+                {
+                    let foreign = &mut *guard;
+                    let mut foreign_flusher =
+                        Flusher::with_cpu_set(&mut foreign.used_by, foreign_address_space);
+                    table_share::unshare(
+                        &mut foreign.table.utable,
+                        &foreign.grants,
+                        PageSpan::new(src_page, 1),
+                        &mut foreign_flusher,
+                    )
+                    .map_err(|_| PfError::Oom)?;
+                }
 
                 // TODO: Should this be called?
                 log::warn!("Mapped zero page since grant didn't exist");
@@ -2584,6 +4680,7 @@ fn correct_inner<'l>(
                     src_page,
                     grant_flags,
                     access == AccessMode::Write,
+                    None,
                 )?
             }
         }
@@ -2628,7 +4725,7 @@ fn correct_inner<'l>(
 
             addr_space_guard = addr_space_lock.acquire_write();
             addr_space = &mut *addr_space_guard;
-            flusher = Flusher::with_cpu_set(&mut addr_space.used_by, &addr_space_lock.tlb_ack);
+            flusher = Flusher::with_cpu_set(&mut addr_space.used_by, addr_space_lock);
 
             log::info!("Got frame {:?} from external fmap", frame);
 
@@ -2636,6 +4733,15 @@ fn correct_inner<'l>(
         }
     };
 
+    // The lock may have been dropped in the meantime, and the address space cloned again.
+    table_share::unshare(
+        &mut addr_space.table.utable,
+        &addr_space.grants,
+        PageSpan::new(faulting_page, 1),
+        &mut flusher,
+    )
+    .map_err(|_| PfError::Oom)?;
+
     let new_flags = grant_flags.write(grant_flags.has_write() && allow_writable);
     let Some(flush) = (unsafe {
         addr_space
@@ -2662,7 +4768,7 @@ pub struct BorrowedFmapSource<'a> {
     pub mode: MmapMode,
     // TODO: There should be a method that obtains the lock from the guard.
     pub addr_space_lock: &'a Arc<AddrSpaceWrapper>,
-    pub addr_space_guard: RwLockWriteGuard<'a, AddrSpace>,
+    pub addr_space_guard: Tracked<RwLockWriteGuard<'a, AddrSpace>>,
 }
 
 pub fn handle_notify_files(notify_files: Vec<UnmapResult>) {
@@ -2679,39 +4785,76 @@ pub enum CopyMappingsMode {
 // TODO: Check if polymorphism is worth it in terms of code size performance penalty vs optimized
 // away checks.
 pub trait GenericFlusher {
-    // TODO: Don't require a frame unless FREE, require Page otherwise
+    /// Invalidate the TLB entries of `span`, which used to map `frame`.
+    // TODO: Don't require a frame unless FREE
     fn queue(
         &mut self,
+        span: PageSpan,
         frame: Frame,
         phys_contiguous_count: Option<NonZeroUsize>,
         actions: TlbShootdownActions,
     );
+    /// Drop a reference to a page table at `level`, once no TLB can reference it anymore.
+    fn queue_table_release(&mut self, table: Frame, level: usize);
 }
 pub struct NopFlusher;
 impl GenericFlusher for NopFlusher {
     fn queue(
         &mut self,
+        _span: PageSpan,
         frame: Frame,
         phys_contiguous_count: Option<NonZeroUsize>,
         actions: TlbShootdownActions,
     ) {
         if actions.contains(TlbShootdownActions::FREE) {
-            handle_free_action(frame, phys_contiguous_count);
+            handle_free_action_batched(frame, phys_contiguous_count);
         }
     }
+    fn queue_table_release(&mut self, table: Frame, level: usize) {
+        unsafe { table_share::release_table(table, level) }
+    }
+}
+impl Drop for NopFlusher {
+    fn drop(&mut self) {
+        crate::memory::flush_free_batch();
+    }
+}
+pub(super) fn handle_free_action(base: Frame, phys_contiguous_count: Option<NonZeroUsize>) {
+    free_action(base, phys_contiguous_count, deallocate_p2frame)
 }
-fn handle_free_action(base: Frame, phys_contiguous_count: Option<NonZeroUsize>) {
+/// Like [`handle_free_action`], but leaving the frames in the free batch of the current CPU,
+/// which the caller must flush eventually.
+fn handle_free_action_batched(base: Frame, phys_contiguous_count: Option<NonZeroUsize>) {
+    free_action(base, phys_contiguous_count, deallocate_p2frame_batched)
+}
+fn free_action(
+    base: Frame,
+    phys_contiguous_count: Option<NonZeroUsize>,
+    deallocate: unsafe fn(Frame, u32),
+) {
     if let Some(count) = phys_contiguous_count {
+        let info =
+            |i| get_page_info(base.next_by(i)).expect("phys_contiguous frames all need PageInfos");
+        // Huge pages shared by forking give up their frames one by one, as the other address
+        // spaces may still hold some of them.
+        if (0..count.get()).any(|i| info(i).refcount() != Some(RefCount::One)) {
+            for i in 0..count.get() {
+                if info(i).remove_ref() == None {
+                    unsafe {
+                        deallocate(base.next_by(i), 0);
+                    }
+                }
+            }
+            return;
+        }
         for i in 0..count.get() {
-            let new_rc = get_page_info(base.next_by(i))
-                .expect("phys_contiguous frames all need PageInfos")
-                .remove_ref();
+            let new_rc = info(i).remove_ref();
 
             assert_eq!(new_rc, None);
         }
         unsafe {
             let order = count.get().next_power_of_two().trailing_zeros();
-            deallocate_p2frame(base, order);
+            deallocate(base, order);
         }
     } else {
         let Some(info) = get_page_info(base) else {
@@ -2719,53 +4862,124 @@ fn handle_free_action(base: Frame, phys_contiguous_count: Option<NonZeroUsize>)
         };
         if info.remove_ref() == None {
             unsafe {
-                deallocate_frame(base);
+                deallocate(base, 0);
             }
         }
     }
 }
+/// Most ranges sent with a TLB shootdown, beyond which the entire TLB is flushed instead.
+const TLB_RANGES: usize = 16;
+/// Most pages invalidated one by one, beyond which flushing the entire TLB is cheaper.
+const TLB_MAX_PAGES: usize = 64;
+
+/// The virtual ranges whose TLB entries must be invalidated, with adjacent ranges coalesced.
+#[derive(Clone, Default)]
+pub struct TlbRanges {
+    ranges: ArrayVec<PageSpan, TLB_RANGES>,
+    page_count: usize,
+    all: bool,
+}
+impl TlbRanges {
+    pub fn is_empty(&self) -> bool {
+        !self.all && self.ranges.is_empty()
+    }
+    pub fn add(&mut self, span: PageSpan) {
+        if self.all || span.is_empty() {
+            return;
+        }
+        self.page_count += span.count;
+        if self.page_count > TLB_MAX_PAGES {
+            return self.add_all();
+        }
+        if let Some(last) = self.ranges.last_mut()
+            && last.end() == span.base
+        {
+            last.count += span.count;
+            return;
+        }
+        if self.ranges.try_push(span).is_err() {
+            self.add_all();
+        }
+    }
+    /// Invalidate the entire TLB, e.g. when page tables are removed.
+    pub fn add_all(&mut self) {
+        self.all = true;
+        self.ranges.clear();
+        self.page_count = 0;
+    }
+    /// Invalidate the ranges in the TLB of the current CPU.
+    pub unsafe fn invalidate(&self) {
+        if self.all {
+            return Self::invalidate_all();
+        }
+        for page in self.ranges.iter().flat_map(|range| range.pages()) {
+            RmmA::invalidate(page.start_address());
+        }
+        #[cfg(all(feature = "pti", target_arch = "x86_64"))]
+        crate::arch::pti::invalidate_user(false);
+    }
+    /// Invalidate the entire TLB of the current CPU, for the current address space.
+    pub unsafe fn invalidate_all() {
+        RmmA::invalidate_all();
+        #[cfg(all(feature = "pti", target_arch = "x86_64"))]
+        crate::arch::pti::invalidate_user(true);
+    }
+}
+
 struct FlusherState<'addrsp> {
     // TODO: what capacity?
     pagequeue: ArrayVec<PageQueueEntry, 32>,
-    dirty: bool,
+    ranges: TlbRanges,
 
     ackword: &'addrsp AtomicU32,
+    generation: &'addrsp AtomicU64,
+    asid: &'addrsp AsidState,
 }
 
+/// Actions to take once no TLB can reference the old mappings anymore.
 enum PageQueueEntry {
     Free {
         base: Frame,
         phys_contiguous_count: Option<NonZeroUsize>,
     },
-    Other {
-        actions: TlbShootdownActions,
-        //page: Page,
+    ReleaseTable {
+        table: Frame,
+        level: usize,
     },
 }
 
+/// Batches the TLB invalidations of an address space, and sends them to the other CPUs using it
+/// as a single shootdown with a list of ranges. CPUs that are not running the address space are
+/// skipped, and flush its TLB entries when switching back to it instead.
 pub struct Flusher<'guard, 'addrsp> {
     active_cpus: &'guard mut LogicalCpuSet,
     state: FlusherState<'addrsp>,
 }
 impl<'guard, 'addrsp> Flusher<'guard, 'addrsp> {
-    fn with_cpu_set(set: &'guard mut LogicalCpuSet, ackword: &'addrsp AtomicU32) -> Self {
+    fn with_cpu_set(set: &'guard mut LogicalCpuSet, addrsp: &'addrsp AddrSpaceWrapper) -> Self {
         Self {
             active_cpus: set,
             state: FlusherState {
                 pagequeue: ArrayVec::new(),
-                dirty: false,
-                ackword,
+                ranges: TlbRanges::default(),
+                ackword: &addrsp.tlb_ack,
+                generation: &addrsp.tlb_generation,
+                asid: &addrsp.asid,
             },
         }
     }
     fn detach(mut self) -> FlusherState<'addrsp> {
         static DUMMY: AtomicU32 = AtomicU32::new(0);
+        static DUMMY_GENERATION: AtomicU64 = AtomicU64::new(0);
+        static DUMMY_ASID: AsidState = AsidState::new();
         let state = core::mem::replace(
             &mut self.state,
             FlusherState {
                 pagequeue: ArrayVec::new(),
+                ranges: TlbRanges::default(),
                 ackword: &DUMMY,
-                dirty: false,
+                generation: &DUMMY_GENERATION,
+                asid: &DUMMY_ASID,
             },
         );
         core::mem::forget(self);
@@ -2774,28 +4988,45 @@ impl<'guard, 'addrsp> Flusher<'guard, 'addrsp> {
     // NOTE: Lock must be held, which must be guaranteed by the caller.
     pub fn flush(&mut self) {
         let pages = core::mem::take(&mut self.state.pagequeue);
+        let ranges = core::mem::take(&mut self.state.ranges);
 
-        if pages.is_empty() && core::mem::replace(&mut self.state.dirty, false) == false {
+        if pages.is_empty() && ranges.is_empty() {
             return;
         }
 
         self.state.ackword.store(0, Ordering::SeqCst);
+        let generation = self.state.generation.fetch_add(1, Ordering::Relaxed) + 1;
 
         let mut affected_cpu_count = 0;
 
         let current_cpu_id = crate::cpu_id();
+        let current_is_active = self.active_cpus.contains(current_cpu_id);
+
+        self.state
+            .asid
+            .mark_stale(current_is_active.then_some(current_cpu_id));
 
         for cpu_id in self.active_cpus.iter_mut() {
             if cpu_id == current_cpu_id {
                 continue;
             }
 
-            crate::percpu::shootdown_tlb_ipi(Some(cpu_id));
+            crate::percpu::shootdown_tlb_ipi(
+                cpu_id,
+                TlbShootdown {
+                    ranges: ranges.clone(),
+                    generation,
+                    ack: self.state.ackword,
+                },
+            );
             affected_cpu_count += 1;
         }
 
-        if self.active_cpus.contains(current_cpu_id) {
-            rmm::PageFlushAll::<RmmA>::new().flush();
+        if current_is_active {
+            unsafe {
+                ranges.invalidate();
+            }
+            PercpuBlock::current().tlb_generation.set(generation);
         }
 
         while self.state.ackword.load(Ordering::SeqCst) < affected_cpu_count {
@@ -2804,45 +5035,51 @@ impl<'guard, 'addrsp> Flusher<'guard, 'addrsp> {
         }
 
         for entry in pages {
-            let PageQueueEntry::Free {
-                base,
-                phys_contiguous_count,
-            } = entry
-            else {
-                continue;
-            };
-            handle_free_action(base, phys_contiguous_count);
+            match entry {
+                PageQueueEntry::Free {
+                    base,
+                    phys_contiguous_count,
+                } => handle_free_action_batched(base, phys_contiguous_count),
+                PageQueueEntry::ReleaseTable { table, level } => unsafe {
+                    table_share::release_table(table, level)
+                },
+            }
+        }
+    }
+    fn push(&mut self, entry: PageQueueEntry) {
+        if self.state.pagequeue.is_full() {
+            self.flush();
         }
+        self.state.pagequeue.push(entry);
     }
 }
 impl GenericFlusher for Flusher<'_, '_> {
     fn queue(
         &mut self,
+        span: PageSpan,
         frame: Frame,
         phys_contiguous_count: Option<NonZeroUsize>,
         actions: TlbShootdownActions,
     ) {
-        let actions = actions & !TlbShootdownActions::NEW_MAPPING;
+        self.state.ranges.add(span);
 
-        let entry = if actions.contains(TlbShootdownActions::FREE) {
-            PageQueueEntry::Free {
+        if actions.contains(TlbShootdownActions::FREE) {
+            self.push(PageQueueEntry::Free {
                 base: frame,
                 phys_contiguous_count,
-            }
-        } else {
-            PageQueueEntry::Other { actions }
-        };
-        self.state.dirty = true;
-
-        if self.state.pagequeue.is_full() {
-            self.flush();
+            });
         }
-        self.state.pagequeue.push(entry);
+    }
+    fn queue_table_release(&mut self, table: Frame, level: usize) {
+        // Paging-structure caches are not invalidated by address on every architecture.
+        self.state.ranges.add_all();
+        self.push(PageQueueEntry::ReleaseTable { table, level });
     }
 }
 impl Drop for Flusher<'_, '_> {
     fn drop(&mut self) {
         self.flush();
+        crate::memory::flush_free_batch();
     }
 }
 bitflags::bitflags! {
@@ -2858,6 +5095,12 @@ bitflags::bitflags! {
         // Unmap a page from one address space without deallocating it.
         const MOVE = 1 << 4;
 
+        // Clear the dirty bit of a page, which the MMU only sets again if it is not cached.
+        const CLEAN = 1 << 5;
+
+        // Replace a huge page by a table mapping the same frames with smaller pages.
+        const SPLIT = 1 << 6;
+
         // Add a new mapping to an address space.
         // Not really a TLB shootdown action on most architectures, so almost always a no-op.
         const NEW_MAPPING = 1 << 31;
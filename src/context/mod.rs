@@ -2,18 +2,23 @@
 //!
 //! For resources on contexts, please consult [wikipedia](https://en.wikipedia.org/wiki/Context_switch) and  [osdev](https://wiki.osdev.org/Context_Switching)
 
-use alloc::{borrow::Cow, collections::BTreeSet, sync::Arc, vec::Vec};
+use alloc::{borrow::Cow, boxed::Box, sync::Arc, vec::Vec};
+use core::slice;
 
-use spin::{Once, RwLock, RwLockReadGuard, RwLockWriteGuard};
-use spinning_top::RwSpinlock;
+use spin::Once;
 use syscall::ENOMEM;
 
 use crate::{
+    common::try_alloc::{try_push, try_vec_from_slice},
     context::memory::AddrSpaceWrapper,
     cpu_set::LogicalCpuSet,
     paging::{RmmA, RmmArch, TableKind},
     percpu::PercpuBlock,
-    sync::WaitMap,
+    sync::{
+        lockdep::{LockClass, LockMode, Tracked},
+        rcu::{Rcu, RcuRef},
+        Mutex, RwLock, RwSpinlock, WaitMap,
+    },
     syscall::error::{Error, Result},
 };
 
@@ -42,39 +47,77 @@ mod arch;
 #[path = "arch/riscv64.rs"]
 mod arch;
 
+/// Capabilities for privileged operations
+pub mod caps;
+
 /// Context struct
 pub mod context;
 
 /// Context switch function
 pub mod switch;
 
+/// Classification and resolution of user page faults
+pub mod fault;
+
 /// File struct - defines a scheme and a file number
 pub mod file;
 
+/// Context groups - resource limits shared between contexts
+pub mod group;
+
+/// Transparent huge pages in user address spaces
+pub mod huge_page;
+
 /// Memory struct - contains a set of pages for a context
 pub mod memory;
 
 /// Process handling - TODO move to userspace
 pub mod process;
 
+/// Resource limits
+pub mod rlimit;
+
+/// Real-time scheduling policies
+pub mod rt;
+
 /// Signal handling
 pub mod signal;
 
+/// Resource usage accounting
+pub mod stats;
+
+/// Lazy sharing of user page tables between address spaces
+pub mod table_share;
+
 /// Timeout handling
 pub mod timeout;
 
+/// Userspace page fault delegation
+pub mod userfault;
+
 pub use self::switch::switch_finish_hook;
 
 /// Maximum context files
 pub const CONTEXT_MAX_FILES: usize = 65_536;
 
 pub use self::arch::empty_cr3;
+#[cfg(target_arch = "x86_64")]
+pub use self::arch::{
+    fpu_trap, fsgsbase_enabled, init_lazy_fpu, ARCH_GET_FS, ARCH_GET_GS, ARCH_SET_FS, ARCH_SET_GS,
+    ARCH_SHSTK_DISABLE, ARCH_SHSTK_ENABLE, ARCH_SHSTK_IBT, ARCH_SHSTK_LOCK, ARCH_SHSTK_SHSTK,
+    ARCH_SHSTK_STATUS, ARCH_SHSTK_WRSS, HWCAP2_FSGSBASE,
+};
+#[cfg(any(target_arch = "aarch64", target_arch = "x86_64"))]
+pub use self::arch::{load_fpu, save_fpu};
 
 static KMAIN_PROCESS: Once<Arc<RwLock<Process>>> = Once::new();
 
-// Set of weak references to all contexts available for scheduling. The only strong references are
-// the context file descriptors.
-static CONTEXTS: RwLock<BTreeSet<ContextRef>> = RwLock::new(BTreeSet::new());
+// Set of all contexts available for scheduling. Readers, starting with the scheduler, go through
+// RCU so that they never wait for each other or for an update, which copies the set.
+static NO_CONTEXTS: ContextList = ContextList(Vec::new());
+static CONTEXTS: Rcu<ContextList> = Rcu::new(&NO_CONTEXTS);
+/// Serializes updates of `CONTEXTS`.
+static CONTEXTS_UPDATE: Mutex<()> = Mutex::new(());
 
 pub fn init() {
     let pid = ProcessId::new(0);
@@ -101,9 +144,8 @@ pub fn init() {
 
     let context_lock = Arc::new(RwSpinlock::new(context));
 
-    CONTEXTS
-        .write()
-        .insert(ContextRef(Arc::clone(&context_lock)));
+    update_contexts(|contexts| contexts.insert(ContextRef(Arc::clone(&context_lock))))
+        .expect("failed to add kmain context");
 
     unsafe {
         let percpu = PercpuBlock::current();
@@ -114,14 +156,59 @@ pub fn init() {
     }
 }
 
-/// Get the global schemes list, const
-pub fn contexts() -> RwLockReadGuard<'static, BTreeSet<ContextRef>> {
+/// Get the global contexts list, as of now. A context removed from it is only freed once the
+/// returned reference is dropped.
+pub fn contexts() -> RcuRef<ContextList> {
     CONTEXTS.read()
 }
 
-/// Get the global schemes list, mutable
-pub fn contexts_mut() -> RwLockWriteGuard<'static, BTreeSet<ContextRef>> {
-    CONTEXTS.write()
+/// Update the global contexts list with `f`, which is given a copy of it. The list is left as it
+/// was if copying it or `f` fails.
+pub fn update_contexts<R>(f: impl FnOnce(&mut ContextList) -> Result<R>) -> Result<R> {
+    let _update = Tracked::acquire(
+        LockClass::Contexts,
+        &CONTEXTS_UPDATE,
+        LockMode::Exclusive,
+        Mutex::lock,
+    );
+    let mut contexts = ContextList(try_vec_from_slice(&CONTEXTS.read().0)?);
+    let ret = f(&mut contexts)?;
+    CONTEXTS.replace(Box::try_new(contexts).map_err(|_| Error::new(ENOMEM))?);
+    Ok(ret)
+}
+
+/// The contexts available for scheduling, sorted by address. A vector rather than a set, so that
+/// copying it and adding to it can fail.
+pub struct ContextList(Vec<ContextRef>);
+impl ContextList {
+    /// Add `context`, returning whether it was not in the list already.
+    pub fn insert(&mut self, context: ContextRef) -> Result<bool> {
+        let Err(index) = self.0.binary_search(&context) else {
+            return Ok(false);
+        };
+        self.0.try_reserve(1).map_err(|_| Error::new(ENOMEM))?;
+        self.0.insert(index, context);
+        Ok(true)
+    }
+    /// Remove `context`, returning whether it was in the list.
+    pub fn remove(&mut self, context: &ContextRef) -> bool {
+        match self.0.binary_search(context) {
+            Ok(index) => {
+                self.0.remove(index);
+                true
+            }
+            Err(_) => false,
+        }
+    }
+    pub fn iter(&self) -> slice::Iter<'_, ContextRef> {
+        self.0.iter()
+    }
+    /// The contexts after `context` in address order, then those before it, leaving it out.
+    pub fn around(&self, context: &ContextRef) -> impl Iterator<Item = &ContextRef> {
+        let before = self.0.partition_point(|other| other < context);
+        let after = self.0.partition_point(|other| other <= context);
+        self.0[after..].iter().chain(&self.0[..before])
+    }
 }
 
 pub fn current() -> Arc<RwSpinlock<Context>> {
@@ -139,6 +226,7 @@ pub fn current_pid() -> Result<ProcessId> {
     Ok(current().read().pid)
 }
 
+#[derive(Clone)]
 pub struct ContextRef(pub Arc<RwSpinlock<Context>>);
 impl ContextRef {
     pub fn upgrade(&self) -> Option<Arc<RwSpinlock<Context>>> {
@@ -177,11 +265,8 @@ pub fn spawn(
     )?))
     .map_err(|_| Error::new(ENOMEM))?;
 
-    CONTEXTS
-        .write()
-        .insert(ContextRef(Arc::clone(&context_lock)));
-
-    process.write().threads.push(Arc::downgrade(&context_lock));
+    try_push(&mut process.write().threads, Arc::downgrade(&context_lock))?;
+    update_contexts(|contexts| contexts.insert(ContextRef(Arc::clone(&context_lock))))?;
     {
         let mut context = context_lock.write();
         let _ = context.set_addr_space(Some(AddrSpaceWrapper::new()?));
@@ -10,14 +10,11 @@ use alloc::{
     vec::Vec,
 };
 
-use spin::RwLock;
-use spinning_top::RwSpinlock;
-
 use syscall::{Error, Result, ENOMEM, ESRCH};
 
 use crate::{
     scheme::{CallerCtx, SchemeNamespace},
-    sync::WaitMap,
+    sync::{RwLock, RwSpinlock, WaitMap},
 };
 
 use crate::context::{self, Context, WaitpidKey};
@@ -0,0 +1,223 @@
+//! Per-context resource limits, like POSIX `getrlimit`/`setrlimit`.
+//!
+//! Each limit has a soft value, which is what gets enforced, and a hard value, up to which the
+//! soft value may be raised. Both can always be lowered, but raising the hard value requires
+//! [`Capabilities::SYS_RESOURCE`].
+//! New contexts inherit the limits of the context creating them. The limits are read and set
+//! through the `rlimit` handle of the context in the `proc:` scheme.
+
+use alloc::sync::Arc;
+
+use syscall::{SenderInfo, SIGKILL, SIGXCPU};
+
+use crate::{
+    context::{
+        self,
+        caps::{self, Capabilities},
+        signal, Context,
+    },
+    percpu::PercpuBlock,
+    sync::RwSpinlock,
+    syscall::{
+        error::{Error, Result, EINVAL, EPERM},
+        process::{send_signal, KillMode, KillTarget},
+    },
+    time,
+};
+
+/// CPU time in seconds. Past the soft limit, the context is sent `SIGXCPU` every second, and
+/// killed once it reaches the hard limit.
+pub const RLIMIT_CPU: usize = 0;
+/// Size in bytes each stack grant can grow up to.
+pub const RLIMIT_STACK: usize = 3;
+/// Number of file descriptors, i.e. one more than the highest that can be allocated.
+pub const RLIMIT_NOFILE: usize = 7;
+/// Size in bytes of the grants of an address space locked in memory with mlock.
+pub const RLIMIT_MEMLOCK: usize = 8;
+/// Size in bytes of the grants of the address space.
+pub const RLIMIT_AS: usize = 9;
+
+const RLIMIT_COUNT: usize = 10;
+const SUPPORTED: [usize; 5] = [
+    RLIMIT_CPU,
+    RLIMIT_STACK,
+    RLIMIT_NOFILE,
+    RLIMIT_MEMLOCK,
+    RLIMIT_AS,
+];
+
+pub const RLIM_INFINITY: usize = usize::MAX;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Rlimit {
+    pub cur: usize,
+    pub max: usize,
+}
+
+impl Rlimit {
+    pub const INFINITY: Self = Self {
+        cur: RLIM_INFINITY,
+        max: RLIM_INFINITY,
+    };
+}
+
+#[derive(Debug)]
+pub struct Rlimits {
+    limits: [Rlimit; RLIMIT_COUNT],
+    /// CPU time in seconds when `SIGXCPU` was last sent.
+    xcpu_sent: Option<u128>,
+}
+
+impl Rlimits {
+    pub fn new() -> Self {
+        let mut limits = [Rlimit::INFINITY; RLIMIT_COUNT];
+        limits[RLIMIT_NOFILE] = Rlimit {
+            cur: super::CONTEXT_MAX_FILES,
+            max: super::CONTEXT_MAX_FILES,
+        };
+        limits[RLIMIT_STACK].cur = 8 * 1024 * 1024;
+        limits[RLIMIT_MEMLOCK] = Rlimit {
+            cur: 8 * 1024 * 1024,
+            max: 8 * 1024 * 1024,
+        };
+        Self {
+            limits,
+            xcpu_sent: None,
+        }
+    }
+
+    /// A copy of the limits, for a new context.
+    pub fn inherit(&self) -> Self {
+        Self {
+            limits: self.limits,
+            xcpu_sent: None,
+        }
+    }
+
+    pub fn get(&self, resource: usize) -> Result<Rlimit> {
+        if !SUPPORTED.contains(&resource) {
+            return Err(Error::new(EINVAL));
+        }
+        Ok(self.limits[resource])
+    }
+
+    pub fn set(&mut self, resource: usize, new: Rlimit, privileged: bool) -> Result<()> {
+        let old = self.get(resource)?;
+        if new.cur > new.max {
+            return Err(Error::new(EINVAL));
+        }
+        if new.max > old.max && !privileged {
+            return Err(Error::new(EPERM));
+        }
+        if resource == RLIMIT_NOFILE && new.max > super::CONTEXT_MAX_FILES {
+            return Err(Error::new(EPERM));
+        }
+        self.limits[resource] = new;
+        Ok(())
+    }
+
+    /// The soft and hard limit of every resource, indexed by resource.
+    pub fn to_raw(&self) -> [usize; 2 * RLIMIT_COUNT] {
+        let mut raw = [0; 2 * RLIMIT_COUNT];
+        for (pair, limit) in raw.chunks_exact_mut(2).zip(&self.limits) {
+            pair.copy_from_slice(&[limit.cur, limit.max]);
+        }
+        raw
+    }
+
+    pub fn soft(&self, resource: usize) -> usize {
+        self.limits[resource].cur
+    }
+
+    pub fn mem_limits(&self) -> MemLimits {
+        MemLimits {
+            address_space: self.soft(RLIMIT_AS),
+            stack: self.soft(RLIMIT_STACK),
+            locked: self.soft(RLIMIT_MEMLOCK),
+        }
+    }
+}
+
+/// The soft memory limits of the context running on a CPU, in bytes, mirrored in its
+/// [`ContextSwitchPercpu`](super::switch::ContextSwitchPercpu) so that they can be checked while
+/// the address space is locked, without locking the context.
+#[derive(Clone, Copy, Debug)]
+pub struct MemLimits {
+    pub address_space: usize,
+    pub stack: usize,
+    pub locked: usize,
+}
+
+impl Default for MemLimits {
+    fn default() -> Self {
+        Self {
+            address_space: RLIM_INFINITY,
+            stack: RLIM_INFINITY,
+            locked: RLIM_INFINITY,
+        }
+    }
+}
+
+/// The memory limits of the current context.
+pub fn current_mem_limits() -> MemLimits {
+    PercpuBlock::current().switch_internals.mem_limits.get()
+}
+
+/// Set a limit of `context`, on behalf of the current context.
+pub fn set(context: &Arc<RwSpinlock<Context>>, resource: usize, new: Rlimit) -> Result<()> {
+    let privileged = caps::has(Capabilities::SYS_RESOURCE);
+    let mem_limits = {
+        let mut context = context.write();
+        context.rlimits.set(resource, new, privileged)?;
+        context.rlimits.mem_limits()
+    };
+    if context::is_current(context) {
+        PercpuBlock::current()
+            .switch_internals
+            .mem_limits
+            .set(mem_limits);
+    }
+    Ok(())
+}
+
+/// Check the CPU time of the current context against its limit, from the timer interrupt.
+pub fn check_cpu() {
+    let current = context::current();
+    let sig = {
+        let mut context = current.write();
+        if !context.userspace {
+            return;
+        }
+        let limit = context.rlimits.limits[RLIMIT_CPU];
+        if limit.cur == RLIM_INFINITY {
+            return;
+        }
+        let used = context.cpu_time + time::monotonic().saturating_sub(context.switch_time);
+        let seconds = used / time::NANOS_PER_SEC;
+
+        if seconds >= limit.max as u128 {
+            SIGKILL
+        } else if seconds >= limit.cur as u128
+            && context
+                .rlimits
+                .xcpu_sent
+                .map_or(true, |sent| seconds > sent)
+        {
+            context.rlimits.xcpu_sent = Some(seconds);
+            SIGXCPU
+        } else {
+            return;
+        }
+    };
+
+    let mut killed_self = false;
+    let _ = send_signal(
+        KillTarget::Thread(current),
+        sig,
+        KillMode::Idempotent,
+        false,
+        &mut killed_self,
+        SenderInfo { pid: 0, ruid: 0 },
+    );
+    signal::signal_handler();
+}
@@ -0,0 +1,183 @@
+//! Real-time scheduling policies.
+//!
+//! Besides the weighted fair scheduling of normal contexts by nice level, a context may use one of
+//! two real-time policies, set through the `sched-policy` handle of `proc:`. Real-time contexts
+//! have a priority from [`RT_PRIORITY_MIN`] to [`RT_PRIORITY_MAX`], and always run before normal
+//! contexts and real-time contexts of a lower priority, preempting them as they wake up. A
+//! [`SchedPolicy::Fifo`] context runs until it blocks, yields, or a higher priority one preempts
+//! it, while [`SchedPolicy::RoundRobin`] contexts of the same priority take turns every RR
+//! timeslice.
+//!
+//! So that a runaway real-time context cannot starve kernel threads and everything else, the
+//! real-time contexts of a CPU may only run for a runtime out of every period, by default 950 ms
+//! out of every second, same as Linux. Once they used it up, they only run when no normal context
+//! is runnable, until the period ends. Writing `period <us>`, `runtime <us>` or
+//! `rr_timeslice <us>` to `sys:sched_rt` changes these, and a runtime equal to the period disables
+//! the throttle.
+
+use core::{
+    cell::Cell,
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+};
+
+use crate::{
+    percpu::percpu,
+    syscall::error::{Error, Result, EINVAL},
+};
+
+pub type RtPriority = u8;
+
+pub const RT_PRIORITY_MIN: RtPriority = 1;
+pub const RT_PRIORITY_MAX: RtPriority = 99;
+
+/// Scheduling policy of a context.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SchedPolicy {
+    /// Weighted fair scheduling according to the nice level.
+    #[default]
+    Normal,
+    /// Real-time, running until it blocks or a higher priority context preempts it.
+    Fifo(RtPriority),
+    /// Real-time, taking turns with the contexts of the same priority.
+    RoundRobin(RtPriority),
+}
+
+impl SchedPolicy {
+    /// Policy numbers of the `sched-policy` handle, as `SCHED_OTHER`, `SCHED_FIFO` and
+    /// `SCHED_RR`.
+    pub const NORMAL: usize = 0;
+    pub const FIFO: usize = 1;
+    pub const ROUND_ROBIN: usize = 2;
+
+    /// The policy numbered `policy`, with `priority`, which must be 0 for normal contexts.
+    pub fn from_raw(policy: usize, priority: usize) -> Result<Self> {
+        let rt_priority = || {
+            RtPriority::try_from(priority)
+                .ok()
+                .filter(|p| (RT_PRIORITY_MIN..=RT_PRIORITY_MAX).contains(p))
+                .ok_or(Error::new(EINVAL))
+        };
+        match policy {
+            Self::NORMAL if priority == 0 => Ok(Self::Normal),
+            Self::FIFO => Ok(Self::Fifo(rt_priority()?)),
+            Self::ROUND_ROBIN => Ok(Self::RoundRobin(rt_priority()?)),
+            _ => Err(Error::new(EINVAL)),
+        }
+    }
+
+    /// The policy number and priority.
+    pub fn into_raw(self) -> (usize, usize) {
+        match self {
+            Self::Normal => (Self::NORMAL, 0),
+            Self::Fifo(priority) => (Self::FIFO, priority.into()),
+            Self::RoundRobin(priority) => (Self::ROUND_ROBIN, priority.into()),
+        }
+    }
+
+    /// The priority of real-time policies.
+    pub fn rt_priority(self) -> Option<RtPriority> {
+        match self {
+            Self::Normal => None,
+            Self::Fifo(priority) | Self::RoundRobin(priority) => Some(priority),
+        }
+    }
+}
+
+const NANOS_PER_MICRO: u64 = 1_000;
+
+static PERIOD: AtomicU64 = AtomicU64::new(1_000_000_000);
+static RUNTIME: AtomicU64 = AtomicU64::new(950_000_000);
+static RR_TIMESLICE: AtomicU64 = AtomicU64::new(100_000_000);
+// Slices are whole ticks, so a period shorter than a few of them could not be enforced.
+const MIN_PERIOD: u64 = 1_000_000;
+
+percpu! {
+    /// Number of times the real-time contexts of each CPU used up their runtime.
+    static THROTTLED: AtomicUsize = AtomicUsize::new(0);
+}
+
+/// How long round robin contexts run before the next one of the same priority, in nanoseconds.
+pub fn rr_timeslice() -> u128 {
+    RR_TIMESLICE.load(Ordering::Relaxed).into()
+}
+
+/// Runtime of the real-time contexts of a CPU in the current period. Only accessed by its CPU.
+#[derive(Default)]
+pub struct RtBandwidth {
+    period_start: Cell<u128>,
+    used: Cell<u128>,
+}
+
+impl RtBandwidth {
+    /// Start a new period if the current one has elapsed.
+    fn refresh(&self, now: u128) {
+        let period = u128::from(PERIOD.load(Ordering::Relaxed));
+        let start = self.period_start.get();
+        if now.saturating_sub(start) >= period {
+            self.period_start.set(now - (now - start) % period);
+            self.used.set(0);
+        }
+    }
+
+    /// Account `ran` nanoseconds run by a real-time context.
+    pub fn charge(&self, ran: u128, now: u128) {
+        self.refresh(now);
+        let runtime = u128::from(RUNTIME.load(Ordering::Relaxed));
+        let used = self.used.get();
+        if used < runtime && used + ran >= runtime {
+            THROTTLED.get().fetch_add(1, Ordering::Relaxed);
+        }
+        self.used.set(used + ran);
+    }
+
+    /// The runtime left to real-time contexts in the current period, or None if it is unlimited.
+    pub fn remaining(&self, now: u128) -> Option<u128> {
+        let runtime = RUNTIME.load(Ordering::Relaxed);
+        if runtime >= PERIOD.load(Ordering::Relaxed) {
+            return None;
+        }
+        self.refresh(now);
+        Some(u128::from(runtime).saturating_sub(self.used.get()))
+    }
+
+    /// When the current period ends, and throttled real-time contexts may run again.
+    pub fn period_end(&self) -> u128 {
+        self.period_start.get() + u128::from(PERIOD.load(Ordering::Relaxed))
+    }
+}
+
+/// The settings in microseconds, and how often the throttle kicked in, for `sys:sched_rt`.
+pub fn settings() -> (u64, u64, u64, usize) {
+    (
+        PERIOD.load(Ordering::Relaxed) / NANOS_PER_MICRO,
+        RUNTIME.load(Ordering::Relaxed) / NANOS_PER_MICRO,
+        RR_TIMESLICE.load(Ordering::Relaxed) / NANOS_PER_MICRO,
+        THROTTLED
+            .iter()
+            .map(|throttled| throttled.load(Ordering::Relaxed))
+            .sum(),
+    )
+}
+
+/// Handle a command written to `sys:sched_rt`.
+pub fn command(command: &str) -> Result<()> {
+    let (setting, value) = command.trim().split_once(' ').ok_or(Error::new(EINVAL))?;
+    let value = value
+        .trim()
+        .parse::<u64>()
+        .ok()
+        .and_then(|value| value.checked_mul(NANOS_PER_MICRO))
+        .ok_or(Error::new(EINVAL))?;
+
+    match setting {
+        "period" if value >= MIN_PERIOD && value >= RUNTIME.load(Ordering::Relaxed) => {
+            PERIOD.store(value, Ordering::Relaxed)
+        }
+        "runtime" if value <= PERIOD.load(Ordering::Relaxed) => {
+            RUNTIME.store(value, Ordering::Relaxed)
+        }
+        "rr_timeslice" if value != 0 => RR_TIMESLICE.store(value, Ordering::Relaxed),
+        _ => return Err(Error::new(EINVAL)),
+    }
+    Ok(())
+}
@@ -1,10 +1,222 @@
+//! Signal delivery. Contexts that set up a `sighandler` through `proc:` manage their signals in
+//! userspace, with the kernel only jumping to their handler, see [`signal_handler`]. The others can
+//! register handlers with the kernel through the `sigaction`, `sigprocmask` and `sigreturn` handles,
+//! see [`KernelSignals`], for which the kernel keeps the masks and builds the signal frames itself.
+//! Those handlers are only called on x86_64 and aarch64.
+#![cfg_attr(
+    not(any(target_arch = "aarch64", target_arch = "x86_64")),
+    allow(dead_code)
+)]
+
+use alloc::sync::Arc;
 use core::sync::atomic::Ordering;
 
 use crate::{
     context,
-    syscall::flag::{SigcontrolFlags, SIGKILL},
+    sync::Mutex,
+    syscall::flag::{
+        SigcontrolFlags, SIGCHLD, SIGCONT, SIGKILL, SIGSTOP, SIGTSTP, SIGTTIN, SIGTTOU, SIGURG,
+        SIGWINCH,
+    },
+};
+#[cfg(any(target_arch = "aarch64", target_arch = "x86_64"))]
+use core::mem;
+
+#[cfg(any(target_arch = "aarch64", target_arch = "x86_64"))]
+use crate::{
+    percpu::PercpuBlock,
+    syscall::{
+        flag::SIGSEGV,
+        usercopy::{UserSliceRo, UserSliceWo},
+        IntRegisters,
+    },
 };
 
+/// Handler for the default action of a signal.
+pub const SIG_DFL: usize = 0;
+/// Handler for ignoring a signal. Any other handler is the address of a function, called with the
+/// signal number and the address of its [`SignalFrame`].
+pub const SIG_IGN: usize = 1;
+
+/// Do not block the signal while its handler runs.
+pub const SA_NODEFER: usize = 1 << 0;
+/// Reset the handler to [`SIG_DFL`] when it is called.
+pub const SA_RESETHAND: usize = 1 << 1;
+
+/// Codes of the faults raising SIGSEGV: the address is not mapped, or not with the access made.
+pub const SEGV_MAPERR: usize = 1;
+pub const SEGV_ACCERR: usize = 2;
+/// Codes of the faults raising SIGBUS: the memory backing the address could not be read, or the
+/// kernel could not map it.
+pub const BUS_ADRERR: usize = 2;
+pub const BUS_OBJERR: usize = 3;
+
+/// Signals that can be neither caught nor blocked.
+const UNBLOCKABLE: u64 = sig_bit(SIGKILL) | sig_bit(SIGSTOP);
+
+const fn sig_bit(sig: usize) -> u64 {
+    1 << (sig - 1)
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SigAction {
+    pub handler: usize,
+    /// Signals blocked while the handler runs, in addition to the signal itself.
+    pub mask: u64,
+    pub flags: usize,
+    /// Address the handler returns to, which must pass the frame to `sigreturn`.
+    pub restorer: usize,
+}
+
+/// Why a fault raised a signal, passed to its handler in the [`SignalFrame`].
+#[derive(Clone, Copy, Debug)]
+pub struct FaultInfo {
+    pub sig: usize,
+    pub code: usize,
+    pub addr: usize,
+}
+
+/// Signals of a context whose handlers are registered with the kernel.
+#[derive(Debug)]
+pub struct KernelSignals {
+    /// Shared with the contexts cloned with `SIGHAND`, see [`Self::share`].
+    pub actions: Arc<Mutex<[SigAction; 64]>>,
+    pub blocked: u64,
+    pub pending: u64,
+    /// The fault that raised the signal [`excp_handler`] is about to deliver, if it was one.
+    pub fault: Option<FaultInfo>,
+    /// Frame passed to `sigreturn`, restored when the syscall returns.
+    #[cfg(any(target_arch = "aarch64", target_arch = "x86_64"))]
+    pub sigreturn: Option<SignalFrame>,
+}
+impl Default for KernelSignals {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl KernelSignals {
+    pub fn new() -> Self {
+        Self {
+            actions: Arc::new(Mutex::new([SigAction::default(); 64])),
+            blocked: 0,
+            pending: 0,
+            fault: None,
+            #[cfg(any(target_arch = "aarch64", target_arch = "x86_64"))]
+            sigreturn: None,
+        }
+    }
+    fn ignores(&self, sig: usize) -> bool {
+        match self.actions.lock()[sig - 1].handler {
+            SIG_IGN => true,
+            SIG_DFL => matches!(sig, SIGCHLD | SIGCONT | SIGURG | SIGWINCH),
+            _ => false,
+        }
+    }
+    /// Whether `sig`, one of the job control signals, stops the process.
+    pub fn will_stop(&self, sig: usize) -> bool {
+        matches!(sig, SIGTSTP | SIGTTIN | SIGTTOU)
+            && self.actions.lock()[sig - 1].handler == SIG_DFL
+    }
+    /// Make `sig` pending unless it is ignored, returning whether it can be delivered right away.
+    pub fn queue(&mut self, sig: usize) -> bool {
+        if self.ignores(sig) {
+            return false;
+        }
+        self.pending |= sig_bit(sig);
+        self.blocked & sig_bit(sig) == 0
+    }
+    /// Change the blocked signals, with `how` being 0 to block, 1 to unblock and 2 to set them.
+    pub fn set_blocked(&mut self, how: usize, mask: u64) -> bool {
+        self.blocked = match how {
+            0 => self.blocked | mask,
+            1 => self.blocked & !mask,
+            2 => mask,
+            _ => return false,
+        } & !UNBLOCKABLE;
+        true
+    }
+    /// Register `action` for `sig`, which must be neither SIGKILL nor SIGSTOP. Pending signals it
+    /// now ignores are discarded, while those of the contexts sharing the actions are left to
+    /// [`deliver`], which skips them.
+    pub fn set_action(&mut self, sig: usize, action: SigAction) -> bool {
+        if !(1..=64).contains(&sig) || sig_bit(sig) & UNBLOCKABLE != 0 {
+            return false;
+        }
+        if !matches!(action.handler, SIG_DFL | SIG_IGN) && action.restorer == 0 {
+            return false;
+        }
+        self.actions.lock()[sig - 1] = action;
+        if self.ignores(sig) {
+            self.pending &= !sig_bit(sig);
+        }
+        true
+    }
+    /// A copy of the handlers and the blocked signals, for a new thread or child.
+    pub fn inherit(&self) -> Self {
+        Self {
+            actions: Arc::new(Mutex::new(*self.actions.lock())),
+            blocked: self.blocked,
+            ..Self::new()
+        }
+    }
+    /// The blocked signals and the handlers themselves, for a context cloned with `SIGHAND`, so
+    /// that registering one in either context changes it in both.
+    pub fn share(&self) -> Self {
+        Self {
+            actions: Arc::clone(&self.actions),
+            blocked: self.blocked,
+            ..Self::new()
+        }
+    }
+    /// Reset the handlers on exec, since they point into the replaced address space. Ignored
+    /// signals stay ignored. The reset handlers are no longer shared, as the other contexts keep
+    /// the old address space.
+    pub fn reset_handlers(&mut self) {
+        let mut actions = *self.actions.lock();
+        for action in actions.iter_mut() {
+            if action.handler != SIG_IGN {
+                *action = SigAction::default();
+            }
+        }
+        self.actions = Arc::new(Mutex::new(actions));
+    }
+    /// Take the lowest pending signal that is not blocked.
+    #[cfg(any(target_arch = "aarch64", target_arch = "x86_64"))]
+    fn take_next(&mut self) -> Option<usize> {
+        let deliverable = self.pending & !self.blocked;
+        if deliverable == 0 {
+            return None;
+        }
+        let sig = deliverable.trailing_zeros() as usize + 1;
+        self.pending &= !sig_bit(sig);
+        Some(sig)
+    }
+}
+
+/// Saved on the user stack when calling a handler registered with the kernel, and restored by
+/// `sigreturn`.
+#[cfg(any(target_arch = "aarch64", target_arch = "x86_64"))]
+#[derive(Clone, Copy, Debug)]
+#[repr(C)]
+pub struct SignalFrame {
+    pub regs: IntRegisters,
+    /// Not part of [`IntRegisters`] on every architecture.
+    pub ip: usize,
+    pub sp: usize,
+    /// Signals blocked before the handler was called.
+    pub blocked: u64,
+    pub sig: usize,
+    /// The code and address of the fault that raised `sig`, or zero if it was not raised by one.
+    pub code: usize,
+    pub addr: usize,
+}
+
+/// Bytes below the stack pointer that the interrupted code may use without moving it.
+#[cfg(target_arch = "x86_64")]
+const RED_ZONE: usize = 128;
+#[cfg(target_arch = "aarch64")]
+const RED_ZONE: usize = 0;
+
 pub fn signal_handler() {
     let context_lock = context::current();
     let mut context_guard = context_lock.write();
@@ -18,6 +230,17 @@ pub fn signal_handler() {
         crate::syscall::process::exit(SIGKILL << 8);
     }
 
+    // Signals with handlers registered with the kernel are delivered when returning to userspace,
+    // which for an interrupted syscall is when it returns.
+    #[cfg(any(target_arch = "aarch64", target_arch = "x86_64"))]
+    if context.sig.is_none() && context.ksig.is_some() {
+        drop(context_guard);
+        if !PercpuBlock::current().inside_syscall.get() {
+            deliver(&context_lock);
+        }
+        return;
+    }
+
     /*let thumbs_down = ptrace::breakpoint_callback(
         PTRACE_STOP_SIGNAL,
         Some(ptrace_event!(PTRACE_STOP_SIGNAL)),
@@ -74,8 +297,34 @@ pub fn signal_handler() {
         Ordering::Release,
     );
 }
-pub fn excp_handler(_signal: usize) {
+/// Record why a fault of the current context raised a signal, before calling [`excp_handler`].
+pub fn record_fault(fault: FaultInfo) {
+    if let Some(ksig) = context::current().write().ksig.as_mut() {
+        ksig.fault = Some(fault);
+    }
+}
+
+pub fn excp_handler(signal: usize) {
     let current = context::current();
+
+    // Faults are delivered right away to a handler registered with the kernel, unless the signal
+    // is blocked or has its default action, which kills the context as for any other.
+    #[cfg(any(target_arch = "aarch64", target_arch = "x86_64"))]
+    {
+        let mut context = current.write();
+        if let Some(ksig) = context.ksig.as_mut()
+            && !matches!(ksig.actions.lock()[signal - 1].handler, SIG_DFL | SIG_IGN)
+            && ksig.blocked & sig_bit(signal) == 0
+        {
+            ksig.pending |= sig_bit(signal);
+            drop(context);
+            deliver(&current);
+            return;
+        }
+    }
+    #[cfg(not(any(target_arch = "aarch64", target_arch = "x86_64")))]
+    let _ = signal;
+
     let context = current.write();
 
     let Some(_eh) = context.sig.as_ref().and_then(|s| s.excp_handler) else {
@@ -85,3 +334,130 @@ pub fn excp_handler(_signal: usize) {
 
     // TODO
 }
+
+/// Restore the frame passed to `sigreturn` and deliver pending signals, with the return value of
+/// the syscall already stored in the registers.
+#[cfg(any(target_arch = "aarch64", target_arch = "x86_64"))]
+pub fn syscall_exit() {
+    let context_lock = context::current();
+    let mut context = context_lock.write();
+    let Some(ksig) = context.ksig.as_mut() else {
+        return;
+    };
+    if let Some(frame) = ksig.sigreturn.take() {
+        ksig.blocked = frame.blocked & !UNBLOCKABLE;
+        if let Some(regs) = context.regs_mut() {
+            regs.load(&frame.regs);
+            regs.set_instr_pointer(frame.ip);
+            regs.set_stack_pointer(frame.sp);
+        }
+    }
+    drop(context);
+    deliver(&context_lock);
+}
+
+/// Read the frame at `address` for `sigreturn`.
+#[cfg(any(target_arch = "aarch64", target_arch = "x86_64"))]
+pub fn read_frame(address: usize) -> crate::syscall::error::Result<SignalFrame> {
+    unsafe { UserSliceRo::new(address, mem::size_of::<SignalFrame>())?.read_exact() }
+}
+
+/// Call the handler of each pending signal that is not blocked, on the user stack of the current
+/// context, or carry out the default action of the signal.
+#[cfg(any(target_arch = "aarch64", target_arch = "x86_64"))]
+fn deliver(context_lock: &alloc::sync::Arc<crate::sync::RwSpinlock<context::Context>>) {
+    loop {
+        let mut context = context_lock.write();
+        let Some(ksig) = context.ksig.as_mut() else {
+            return;
+        };
+        let Some(sig) = ksig.take_next() else {
+            return;
+        };
+        let action = ksig.actions.lock()[sig - 1];
+        match action.handler {
+            SIG_IGN => continue,
+            SIG_DFL if ksig.ignores(sig) => continue,
+            SIG_DFL => {
+                drop(context);
+                crate::syscall::process::exit(sig);
+            }
+            _ => (),
+        }
+        let blocked = ksig.blocked;
+        let fault = ksig.fault.take().filter(|fault| fault.sig == sig);
+        if action.flags & SA_NODEFER == 0 {
+            ksig.blocked |= sig_bit(sig);
+        }
+        ksig.blocked |= action.mask & !UNBLOCKABLE;
+        if action.flags & SA_RESETHAND != 0 {
+            ksig.actions.lock()[sig - 1] = SigAction::default();
+        }
+
+        let Some(regs) = context.regs_mut() else {
+            return;
+        };
+        let mut frame = SignalFrame {
+            regs: IntRegisters::default(),
+            ip: regs.instr_pointer(),
+            sp: regs.stack_pointer(),
+            blocked,
+            sig,
+            code: fault.map_or(0, |fault| fault.code),
+            addr: fault.map_or(0, |fault| fault.addr),
+        };
+        regs.save(&mut frame.regs);
+        // The user stack may fault, which needs the context lock.
+        drop(context);
+
+        let frame_address = frame
+            .sp
+            .wrapping_sub(RED_ZONE + mem::size_of::<SignalFrame>())
+            & !15;
+        if write_frame(frame_address, &frame, action.restorer).is_err() {
+            crate::syscall::process::exit(SIGSEGV);
+        }
+
+        let mut context = context_lock.write();
+        let Some(regs) = context.regs_mut() else {
+            return;
+        };
+        regs.set_instr_pointer(action.handler);
+        #[cfg(target_arch = "x86_64")]
+        {
+            // Calls push the return address, and the ABI requires the direction flag to be clear.
+            regs.scratch.rdi = sig;
+            regs.scratch.rsi = frame_address;
+            regs.iret.rsp = frame_address - mem::size_of::<usize>();
+            regs.iret.rflags &= !(1 << 10);
+        }
+        #[cfg(target_arch = "aarch64")]
+        {
+            regs.scratch.x0 = sig;
+            regs.scratch.x1 = frame_address;
+            regs.preserved.x30 = action.restorer;
+            regs.iret.sp_el0 = frame_address;
+        }
+    }
+}
+
+#[cfg(any(target_arch = "aarch64", target_arch = "x86_64"))]
+fn write_frame(
+    address: usize,
+    frame: &SignalFrame,
+    restorer: usize,
+) -> crate::syscall::error::Result<()> {
+    let bytes = unsafe {
+        core::slice::from_raw_parts(
+            (frame as *const SignalFrame).cast::<u8>(),
+            mem::size_of::<SignalFrame>(),
+        )
+    };
+    UserSliceWo::new(address, bytes.len())?.copy_from_slice(bytes)?;
+    #[cfg(target_arch = "x86_64")]
+    UserSliceWo::new(address - mem::size_of::<usize>(), mem::size_of::<usize>())?
+        .write_usize(restorer)?;
+    #[cfg(not(target_arch = "x86_64"))]
+    let _ = restorer;
+    Ok(())
+}
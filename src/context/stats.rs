@@ -0,0 +1,110 @@
+//! Per-context resource usage, for `top`-like tools to show without tracing the contexts.
+//!
+//! Syscalls and page faults are counted per CPU, and added to the running context when it is
+//! switched away from, so that those paths do not need to lock it. The bytes read and written are
+//! counted per scheme, once the call returns. Contexts start with zeroed counters, and the usage is
+//! read through the `stats` handle of the context in the `proc:` scheme, or for all contexts from
+//! `sys:iostat`.
+
+use alloc::vec::Vec;
+use core::cell::Cell;
+
+use crate::{
+    context::{self, Context},
+    percpu::PercpuBlock,
+    scheme::SchemeId,
+};
+
+#[derive(Clone, Debug, Default)]
+pub struct ContextStats {
+    pub syscalls: u64,
+    /// Page faults on user memory, including those from the kernel copying to or from it.
+    pub page_faults: u64,
+    /// Switches away from the context as it blocked.
+    pub voluntary_switches: u64,
+    /// Switches away from the context while it was still runnable, i.e. preemptions.
+    pub involuntary_switches: u64,
+    /// Bytes read and written through each scheme, in order of first use.
+    pub io: Vec<SchemeIo>,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct SchemeIo {
+    pub scheme: SchemeId,
+    pub read: u64,
+    pub written: u64,
+}
+
+impl ContextStats {
+    pub fn account_io(&mut self, scheme: SchemeId, read: usize, written: usize) {
+        let io = match self.io.iter_mut().find(|io| io.scheme == scheme) {
+            Some(io) => io,
+            None => {
+                self.io.push(SchemeIo {
+                    scheme,
+                    read: 0,
+                    written: 0,
+                });
+                self.io.last_mut().unwrap()
+            }
+        };
+        io.read += read as u64;
+        io.written += written as u64;
+    }
+
+    /// Bytes read and written through all schemes.
+    pub fn total_io(&self) -> (u64, u64) {
+        self.io.iter().fold((0, 0), |(read, written), io| {
+            (read + io.read, written + io.written)
+        })
+    }
+}
+
+/// Counts of the running context not yet added to its stats.
+#[derive(Default)]
+pub struct PendingStats {
+    syscalls: Cell<u64>,
+    page_faults: Cell<u64>,
+}
+
+impl PendingStats {
+    /// Move the pending counts to `stats`, of the context switched away from.
+    pub(crate) fn flush(&self, stats: &mut ContextStats) {
+        stats.syscalls += self.syscalls.take();
+        stats.page_faults += self.page_faults.take();
+    }
+}
+
+fn pending() -> &'static PendingStats {
+    &PercpuBlock::current().switch_internals.pending_stats
+}
+
+pub fn count_syscall() {
+    let pending = pending();
+    pending.syscalls.set(pending.syscalls.get() + 1);
+}
+
+pub fn count_page_fault() {
+    let pending = pending();
+    pending.page_faults.set(pending.page_faults.get() + 1);
+}
+
+/// Account bytes read or written by the current context through `scheme`.
+pub fn account_io(scheme: SchemeId, read: usize, written: usize) {
+    context::current()
+        .write()
+        .stats
+        .account_io(scheme, read, written);
+}
+
+/// The usage of `context`, including what is still pending if it is the one running on this CPU.
+/// Contexts running on other CPUs lag by at most the rest of their time slice.
+pub fn snapshot(context: &Context) -> ContextStats {
+    let mut stats = context.stats.clone();
+    if context.running && context.cpu_id == Some(crate::cpu_id()) {
+        let pending = pending();
+        stats.syscalls += pending.syscalls.get();
+        stats.page_faults += pending.page_faults.get();
+    }
+    stats
+}
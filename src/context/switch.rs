@@ -1,26 +1,149 @@
-///! This module provides a context-switching mechanism that utilizes a simple round-robin scheduler.
-///! The scheduler iterates over available contexts, selecting the next context to run, while
-///! handling process states and synchronization.
+///! This module provides a context-switching mechanism that utilizes a weighted fair scheduler.
+///! Each context accumulates virtual runtime, its CPU time scaled by the weight of its nice level,
+///! and the runnable context with the least virtual runtime is selected next, while handling
+///! process states and synchronization. Real-time contexts, see [`rt`](super::rt), are selected
+///! before all others.
 use core::{
     cell::{Cell, RefCell},
     mem,
-    ops::Bound,
-    sync::atomic::Ordering,
+    sync::atomic::{AtomicBool, AtomicI8, Ordering},
 };
 
 use alloc::sync::Arc;
-use spinning_top::{guard::ArcRwSpinlockWriteGuard, RwSpinlock};
 use syscall::PtraceFlags;
 
 use crate::{
     context::{arch, contexts, Context},
     cpu_set::LogicalCpuId,
-    interrupt,
+    hotplug, interrupt,
+    memory::memcg::MemcgId,
+    numa,
     percpu::PercpuBlock,
-    ptrace, time,
+    ptrace, suspend,
+    sync::{ArcRwSpinlockWriteGuard, RwSpinlock},
+    time,
+    trace::{self, Event},
 };
 
-use super::ContextRef;
+use super::{
+    rlimit::{self, MemLimits},
+    rt::{self, SchedPolicy, RT_PRIORITY_MAX},
+    stats, ContextRef,
+};
+
+/// Nice level of a context, from [`NICE_MIN`] (highest priority) to [`NICE_MAX`].
+pub type Nice = i8;
+
+pub const NICE_MIN: Nice = -20;
+pub const NICE_MAX: Nice = 19;
+
+/// Priority with which a context preempts others, the lower the sooner: real-time priorities come
+/// below every nice level.
+type Priority = i8;
+
+/// Weight of a context at nice 0. Each nice level is roughly 1.25 times as heavy as the next one,
+/// so that a context gets about 10% more CPU time than a context one level nicer.
+const NICE_0_WEIGHT: u128 = 1024;
+const NICE_WEIGHTS: [u128; 40] = [
+    88761, 71755, 56483, 46273, 36291, 29154, 23254, 18705, 14949, 11916, 9548, 7620, 6100, 4904,
+    3906, 3121, 2501, 1991, 1586, 1277, 1024, 820, 655, 526, 423, 335, 272, 215, 172, 137, 110, 87,
+    70, 56, 45, 36, 29, 23, 18, 15,
+];
+
+/// Length of a tick of the periodic timer, which slices are counted in.
+const TICK_NANOS: u128 = 2_250_000;
+/// Number of ticks a context at nice 0 runs before being preempted (approx. 6.75 ms).
+const BASE_SLICE_TICKS: u128 = 3;
+/// Upper bound for the slice of high priority contexts.
+const MAX_SLICE_TICKS: u128 = 12;
+
+/// How far behind the least virtual runtime of a CPU a waking context may be placed, in
+/// nanoseconds. This favors contexts that slept, without letting them monopolize the CPU.
+const SLEEPER_CREDIT: u128 = 3_000_000;
+
+fn weight(nice: Nice) -> u128 {
+    NICE_WEIGHTS[(nice.clamp(NICE_MIN, NICE_MAX) - NICE_MIN) as usize]
+}
+
+/// Number of ticks a context with the given nice level may run before being preempted.
+fn slice_ticks(nice: Nice) -> usize {
+    (BASE_SLICE_TICKS * weight(nice) / NICE_0_WEIGHT).clamp(1, MAX_SLICE_TICKS) as usize
+}
+
+/// Priority with which `context` preempts others.
+fn priority(context: &Context) -> Priority {
+    match context.sched_policy.rt_priority() {
+        Some(rt_priority) => NICE_MIN - rt_priority as Priority,
+        None => context.nice,
+    }
+}
+
+/// Order in which runnable contexts are selected, the least first: real-time contexts by priority,
+/// then normal contexts by virtual runtime, then real-time contexts that used up their runtime.
+fn pick_order(context: &Context, vruntime: u128, rt_throttled: bool) -> (u8, u128) {
+    match context.sched_policy.rt_priority() {
+        Some(rt_priority) if !rt_throttled => (0, u128::from(RT_PRIORITY_MAX - rt_priority)),
+        None => (1, vruntime),
+        Some(_) => (2, vruntime),
+    }
+}
+
+/// How long `context` may run before being preempted, or None if until it blocks, where
+/// `rt_remaining` is the runtime left to real-time contexts.
+fn slice_nanos(context: &Context, rt_remaining: Option<u128>) -> Option<u128> {
+    let slice = match context.sched_policy {
+        SchedPolicy::Normal => return Some(slice_ticks(context.nice) as u128 * TICK_NANOS),
+        SchedPolicy::Fifo(_) => None,
+        SchedPolicy::RoundRobin(_) => Some(rt::rr_timeslice()),
+    };
+    match (slice, rt_remaining) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        (a, b) => a.or(b),
+    }
+}
+
+/// The virtual runtime `context` would be scheduled with on a CPU whose least virtual runtime is
+/// `min_vruntime`. Contexts that have not yet been placed on a CPU start at that CPU's minimum.
+fn placed_vruntime(context: &Context, min_vruntime: u128) -> u128 {
+    if context.cpu_id.is_none() {
+        min_vruntime
+    } else {
+        context
+            .vruntime
+            .max(min_vruntime.saturating_sub(SLEEPER_CREDIT))
+    }
+}
+
+/// Make `cpu_id` reschedule if the context running there has a lower priority than `context`,
+/// interrupting it if it is another CPU. Returns whether a reschedule was requested.
+pub fn preempt_for(cpu_id: LogicalCpuId, context: &Context) -> bool {
+    let Some(percpu) = crate::percpu::get(cpu_id) else {
+        return false;
+    };
+    let running = percpu
+        .switch_internals
+        .running_priority
+        .load(Ordering::Relaxed);
+    if priority(context) >= running {
+        return false;
+    }
+    resched(cpu_id);
+    true
+}
+
+/// Make `cpu_id` reschedule, interrupting it if it is another CPU.
+pub fn resched(cpu_id: LogicalCpuId) {
+    let Some(percpu) = crate::percpu::get(cpu_id) else {
+        return;
+    };
+    percpu
+        .switch_internals
+        .need_resched
+        .store(true, Ordering::Relaxed);
+    if cpu_id != crate::cpu_id() {
+        crate::ipi::ipi_single(crate::ipi::IpiKind::Switch, cpu_id);
+    }
+}
 
 enum UpdateResult {
     CanSwitch,
@@ -46,8 +169,13 @@ unsafe fn update_runnable(context: &mut Context, cpu_id: LogicalCpuId) -> Update
         return UpdateResult::Skip;
     }
 
-    // Ignore contexts assigned to other CPUs.
-    if !context.sched_affinity.contains(cpu_id) {
+    // Userspace stays stopped while the system is suspended.
+    if suspend::is_frozen(context) {
+        return UpdateResult::Skip;
+    }
+
+    // Ignore contexts assigned to other CPUs, unless those are all offline.
+    if !context.sched_affinity.contains(cpu_id) && hotplug::any_online_in(&context.sched_affinity) {
         return UpdateResult::Skip;
     }
 
@@ -56,6 +184,19 @@ unsafe fn update_runnable(context: &mut Context, cpu_id: LogicalCpuId) -> Update
         return UpdateResult::Skip;
     }
 
+    // Contexts stay on the first CPU they run on, so steer that choice towards the NUMA node
+    // holding most of their memory, unless their affinity excludes all CPUs of that node.
+    if context.cpu_id.is_none()
+        && let Some(home) = context
+            .addr_space
+            .as_ref()
+            .and_then(|addr_space| addr_space.home_node.get())
+        && numa::cpu_node(cpu_id) != home
+        && numa::node_has_cpu_in(home, &context.sched_affinity)
+    {
+        return UpdateResult::Skip;
+    }
+
     // If context is soft-blocked and has a wake-up time, check if it should wake up.
     if context.status.is_soft_blocked() {
         if let Some(wake) = context.wake {
@@ -67,6 +208,17 @@ unsafe fn update_runnable(context: &mut Context, cpu_id: LogicalCpuId) -> Update
         }
     }
 
+    // Skip contexts whose group has used up its CPU quota for the current period.
+    if let Some(ref group) = context.group {
+        if group.cpu.is_throttled(time::monotonic() as u64) {
+            // Without a periodic tick, this CPU must look again once the period ends.
+            let unthrottle_at = &PercpuBlock::current().switch_internals.unthrottle_at;
+            let end = u128::from(group.cpu.period_end());
+            unthrottle_at.set(Some(unthrottle_at.get().map_or(end, |at| at.min(end))));
+            return UpdateResult::Skip;
+        }
+    }
+
     // If the context is runnable, indicate it can be switched to.
     if context.status.is_runnable() {
         UpdateResult::CanSwitch
@@ -83,22 +235,58 @@ struct SwitchResultInner {
 /// Tick function to update PIT ticks and trigger a context switch if necessary.
 ///
 /// Called periodically, this function increments a per-CPU tick counter and performs a context
-/// switch if the counter reaches a set threshold (e.g., every 3 ticks).
+/// switch if the counter reaches the slice of the current context, which depends on its nice
+/// level or real-time policy, or if a higher priority context became runnable.
 ///
 /// The function also calls the signal handler after switching contexts.
 pub fn tick() {
-    let ticks_cell = &PercpuBlock::current().switch_internals.pit_ticks;
+    crate::timer::run_expired();
+    rlimit::check_cpu();
+    crate::klog::wake_readers();
+    #[cfg(feature = "debugger")]
+    crate::debugger::poll();
+
+    let internals = &PercpuBlock::current().switch_internals;
+    let ticks_cell = &internals.pit_ticks;
 
     let new_ticks = ticks_cell.get() + 1;
     ticks_cell.set(new_ticks);
 
-    // Trigger a context switch after every 3 ticks (approx. 6.75 ms).
-    if new_ticks >= 3 {
+    if new_ticks >= internals.slice_ticks.get() || internals.need_resched.load(Ordering::Relaxed) {
+        switch();
+        crate::context::signal::signal_handler();
+    }
+}
+
+/// Called from the one-shot timer interrupt, in place of [`tick`], to switch if the slice of the
+/// current context ended, or if a higher priority context became runnable.
+pub fn slice_timer() {
+    rlimit::check_cpu();
+    crate::klog::wake_readers();
+    #[cfg(feature = "debugger")]
+    crate::debugger::poll();
+    let internals = &PercpuBlock::current().switch_internals;
+    let slice_ended = internals
+        .slice_end
+        .get()
+        .is_some_and(|end| time::monotonic() >= end);
+
+    if slice_ended || internals.need_resched.load(Ordering::Relaxed) {
         switch();
         crate::context::signal::signal_handler();
     }
 }
 
+/// The earliest time this CPU must reschedule at: the end of the slice of the current context,
+/// or that of the period of a group throttled during the last switch.
+pub fn next_deadline() -> Option<u128> {
+    let internals = &PercpuBlock::current().switch_internals;
+    match (internals.slice_end.get(), internals.unthrottle_at.get()) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        (a, b) => a.or(b),
+    }
+}
+
 /// Finishes the context switch by clearing any temporary data and resetting the lock.
 ///
 /// This function is called after a context switch is completed to perform cleanup, including
@@ -123,10 +311,11 @@ pub enum SwitchResult {
     AllContextsIdle,
 }
 
-/// Selects and switches to the next context using a round-robin scheduler.
+/// Selects and switches to the next context using a weighted fair scheduler.
 ///
-/// This function performs the context switch, checking each context in a loop for eligibility
-/// until it finds a context ready to run. If no other context is runnable, it returns to the
+/// This function performs the context switch, checking each context for eligibility and picking
+/// the real-time one with the highest priority, or otherwise the one with the least virtual
+/// runtime, in round-robin order among equals. If no other context is runnable, it returns to the
 /// idle context.
 ///
 /// # Warning
@@ -139,8 +328,13 @@ pub enum SwitchResult {
 pub fn switch() -> SwitchResult {
     let percpu = PercpuBlock::current();
 
-    //set PIT Interrupt counter to 0, giving each process same amount of PIT ticks
+    //set PIT Interrupt counter to 0, giving the next context its full slice
     percpu.switch_internals.pit_ticks.set(0);
+    percpu
+        .switch_internals
+        .need_resched
+        .store(false, Ordering::Relaxed);
+    percpu.switch_internals.unthrottle_at.set(None);
 
     // Acquire the global lock to ensure exclusive access during context switch and avoid
     // issues that would be caused by the unsafe operations below
@@ -153,7 +347,14 @@ pub fn switch() -> SwitchResult {
         percpu.maybe_handle_tlb_shootdown();
     }
 
+    #[cfg(all(feature = "debugger", any(target_arch = "x86", target_arch = "x86_64")))]
+    unsafe {
+        crate::arch::watchpoint::sync_percpu();
+    }
+
     let cpu_id = crate::cpu_id();
+    // Only the idle context runs on a CPU going offline.
+    let offline = hotplug::is_offline(cpu_id);
 
     let mut switch_context_opt = None;
     {
@@ -164,34 +365,35 @@ pub fn switch() -> SwitchResult {
         let prev_context_guard = prev_context_lock.write_arc();
 
         let idle_context = percpu.switch_internals.idle_context();
+        let min_vruntime = percpu.switch_internals.min_vruntime.get();
+
+        // Whether real-time contexts used up their runtime, counting that of the previous context
+        // if it is one, which is only charged below.
+        let now = time::monotonic();
+        let prev_rt_time = if prev_context_guard.sched_policy == SchedPolicy::Normal {
+            0
+        } else {
+            now.saturating_sub(prev_context_guard.switch_time)
+        };
+        let rt_throttled = percpu
+            .switch_internals
+            .rt_bandwidth
+            .remaining(now)
+            .is_some_and(|remaining| remaining <= prev_rt_time);
+        let mut rt_waiting = false;
 
-        // Stateful flag used to skip the idle process the first time it shows up.
-        // After that, this flag is set to `false` so the idle process can be
-        // picked up.
-        let mut skip_idle = true;
+        // The eligible context first in pick order so far, and its virtual runtime.
+        let mut best: Option<((u8, u128), u128, ArcRwSpinlockWriteGuard<Context>)> = None;
 
         // Attempt to locate the next context to switch to.
+        // Include all contexts with IDs greater than the current, and then all contexts with IDs
+        // less than the current, but not the current context, which is already locked.
         for next_context_lock in contexts
-            // Include all contexts with IDs greater than the current...
-            .range((
-                Bound::Excluded(ContextRef(Arc::clone(&prev_context_lock))),
-                Bound::Unbounded,
-            ))
-            // ... and all contexts with IDs less than the current...
-            .chain(contexts.range((
-                Bound::Unbounded,
-                Bound::Excluded(ContextRef(Arc::clone(&prev_context_lock))),
-            )))
+            .around(&ContextRef(Arc::clone(&prev_context_lock)))
             .filter_map(ContextRef::upgrade)
-            // ... and the idle context...
-            .chain(Some(Arc::clone(&idle_context)))
-        // ... but not the current context (note the `Bound::Excluded`),
-        // which is already locked.
         {
-            if Arc::ptr_eq(&next_context_lock, &idle_context) && skip_idle {
-                // Skip idle process the first time it shows up, but allow it
-                // to be picked up again the next time.
-                skip_idle = false;
+            // The idle context is only picked when nothing else is runnable.
+            if offline || Arc::ptr_eq(&next_context_lock, &idle_context) {
                 continue;
             }
 
@@ -202,16 +404,52 @@ pub fn switch() -> SwitchResult {
             if let UpdateResult::CanSwitch =
                 unsafe { update_runnable(&mut *next_context_guard, cpu_id) }
             {
-                // Store locks for previous and next context and break out from loop
-                // for the switch
+                let vruntime = placed_vruntime(&next_context_guard, min_vruntime);
+                let order = pick_order(&next_context_guard, vruntime, rt_throttled);
+                rt_waiting |= rt_throttled && order.0 == 2;
+                if best.as_ref().map_or(true, |(best, _, _)| order < *best) {
+                    // Replacing the previous best releases its lock.
+                    best = Some((order, vruntime, next_context_guard));
+                }
+            }
+        }
+
+        // Without a periodic tick, this CPU must look again once throttled real-time contexts may
+        // run.
+        if rt_waiting {
+            let unthrottle_at = &percpu.switch_internals.unthrottle_at;
+            let end = percpu.switch_internals.rt_bandwidth.period_end();
+            unthrottle_at.set(Some(unthrottle_at.get().map_or(end, |at| at.min(end))));
+        }
+
+        if let Some((_, vruntime, mut next_context_guard)) = best {
+            next_context_guard.vruntime = vruntime;
+            percpu
+                .switch_internals
+                .min_vruntime
+                .set(min_vruntime.max(vruntime));
+            switch_context_opt = Some((prev_context_guard, next_context_guard));
+        } else if !Arc::ptr_eq(&prev_context_lock, &idle_context) {
+            let mut next_context_guard = idle_context.write_arc();
+            if let UpdateResult::CanSwitch =
+                unsafe { update_runnable(&mut *next_context_guard, cpu_id) }
+            {
                 switch_context_opt = Some((prev_context_guard, next_context_guard));
-                break;
             }
         }
     };
 
     // Switch process states, TSS stack pointer, and store new context ID
     if let Some((mut prev_context_guard, mut next_context_guard)) = switch_context_opt {
+        trace::record(
+            Event::Switch,
+            [
+                Arc::as_ptr(ArcRwSpinlockWriteGuard::rwlock(&next_context_guard)) as u64,
+                next_context_guard.pid.get() as u64,
+                0,
+            ],
+        );
+
         // Update context states and prepare for the switch.
         let prev_context = &mut *prev_context_guard;
         let next_context = &mut *next_context_guard;
@@ -219,12 +457,83 @@ pub fn switch() -> SwitchResult {
         // Set the previous context as "not running"
         prev_context.running = false;
 
+        // Release the previous context from this CPU if its affinity changed while it was
+        // running, or the CPU is going offline, so that it migrates to an allowed CPU.
+        if offline || !prev_context.sched_affinity.contains(cpu_id) {
+            prev_context.cpu_id = None;
+        }
+
+        // Update contexts' timestamps, and charge the time to the previous context's group.
+        let switch_time = time::monotonic();
+        let ran = switch_time.saturating_sub(prev_context.switch_time);
+        prev_context.cpu_time += ran;
+        prev_context.vruntime += ran * NICE_0_WEIGHT / weight(prev_context.nice);
+        next_context.switch_time = switch_time;
+        if let Some(ref group) = prev_context.group {
+            group.cpu.charge(ran as u64, switch_time as u64);
+        }
+
+        let percpu = PercpuBlock::current();
+        if prev_context.sched_policy != SchedPolicy::Normal {
+            percpu
+                .switch_internals
+                .rt_bandwidth
+                .charge(ran, switch_time);
+        }
+        percpu
+            .switch_internals
+            .pending_stats
+            .flush(&mut prev_context.stats);
+        if prev_context.status.is_runnable() {
+            prev_context.stats.involuntary_switches += 1;
+        } else {
+            prev_context.stats.voluntary_switches += 1;
+        }
+
         // Set the next context as "running"
         next_context.running = true;
         // Set the CPU ID for the next context
         next_context.cpu_id = Some(cpu_id);
 
-        let percpu = PercpuBlock::current();
+        // Sleeping contexts are woken up by a timer of this CPU.
+        if prev_context.status.is_soft_blocked()
+            && let Some(wake) = prev_context.wake
+        {
+            crate::timer::queue(
+                wake,
+                crate::timer::Timer::Wake(Arc::downgrade(ArcRwSpinlockWriteGuard::rwlock(
+                    &prev_context_guard,
+                ))),
+            );
+        }
+
+        let (slice, running_priority, slice_end) = if Arc::ptr_eq(
+            ArcRwSpinlockWriteGuard::rwlock(&next_context_guard),
+            &percpu.switch_internals.idle_context(),
+        ) {
+            // Any context waking up should preempt the idle context.
+            (BASE_SLICE_TICKS as usize, Priority::MAX, None)
+        } else {
+            let rt_remaining = percpu.switch_internals.rt_bandwidth.remaining(switch_time);
+            match slice_nanos(next_context, rt_remaining) {
+                Some(nanos) => {
+                    // Slices are at least one tick, even once the real-time runtime is used up.
+                    let slice = (nanos / TICK_NANOS).clamp(1, usize::MAX as u128);
+                    (
+                        slice as usize,
+                        priority(next_context),
+                        Some(switch_time + slice * TICK_NANOS),
+                    )
+                }
+                None => (usize::MAX, priority(next_context), None),
+            }
+        };
+        percpu.switch_internals.slice_ticks.set(slice);
+        percpu.switch_internals.slice_end.set(slice_end);
+        percpu
+            .switch_internals
+            .running_priority
+            .store(running_priority, Ordering::Relaxed);
         unsafe {
             percpu.switch_internals.set_current_context(Arc::clone(
                 ArcRwSpinlockWriteGuard::rwlock(&next_context_guard),
@@ -257,6 +566,16 @@ pub fn switch() -> SwitchResult {
         *percpu.ptrace_session.borrow_mut() = ptrace_session;
         percpu.ptrace_flags.set(ptrace_flags);
         prev_context.inside_syscall = percpu.inside_syscall.replace(next_context.inside_syscall);
+        crate::perf::switch(percpu, prev_context);
+        crate::cpufreq::switch(percpu);
+        crate::mitigations::switch(percpu);
+
+        #[cfg(debug_assertions)]
+        {
+            crate::sync::lockdep::check_switch();
+            crate::sync::rcu::check_switch();
+            prev_context.irq_depth = percpu.lockdep.irq_depth.replace(next_context.irq_depth);
+        }
 
         #[cfg(feature = "syscall_debug")]
         {
@@ -271,6 +590,20 @@ pub fn switch() -> SwitchResult {
             .switch_internals
             .being_sigkilled
             .set(next_context.being_sigkilled);
+        percpu
+            .switch_internals
+            .mem_limits
+            .set(next_context.rlimits.mem_limits());
+        percpu
+            .switch_internals
+            .mempolicy
+            .set(next_context.mempolicy);
+        percpu
+            .switch_internals
+            .memcg
+            .set(next_context.memcg.as_ref().map(|memcg| memcg.id()));
+
+        crate::timer::program();
 
         unsafe {
             arch::switch_to(prev_context, next_context);
@@ -287,6 +620,8 @@ pub fn switch() -> SwitchResult {
         // No target was found, unset global lock and return
         arch::CONTEXT_SWITCH_LOCK.store(false, Ordering::SeqCst);
 
+        crate::timer::program();
+
         SwitchResult::AllContextsIdle
     }
 }
@@ -299,6 +634,23 @@ pub fn switch() -> SwitchResult {
 pub struct ContextSwitchPercpu {
     switch_result: Cell<Option<SwitchResultInner>>,
     pit_ticks: Cell<usize>,
+    /// Number of ticks the current context may run before being preempted.
+    slice_ticks: Cell<usize>,
+    /// When the slice of the current context ends, or None if idle or running until it blocks,
+    /// for the one-shot timer.
+    slice_end: Cell<Option<u128>>,
+    /// End of the earliest period of the groups throttled during the last switch, or of the
+    /// real-time period if real-time contexts were throttled.
+    unthrottle_at: Cell<Option<u128>>,
+    /// Least virtual runtime of the contexts scheduled on this CPU, only ever increasing.
+    min_vruntime: Cell<u128>,
+    /// Runtime of the real-time contexts on this CPU.
+    rt_bandwidth: rt::RtBandwidth,
+
+    /// Priority of the running context, read by other CPUs waking up contexts.
+    running_priority: AtomicI8,
+    /// Set when a context with a higher priority than the running one became runnable.
+    need_resched: AtomicBool,
 
     current_ctxt: RefCell<Option<Arc<RwSpinlock<Context>>>>,
 
@@ -306,6 +658,14 @@ pub struct ContextSwitchPercpu {
     idle_ctxt: RefCell<Option<Arc<RwSpinlock<Context>>>>,
 
     pub(crate) being_sigkilled: Cell<bool>,
+    /// Memory limits of the running context.
+    pub(crate) mem_limits: Cell<MemLimits>,
+    /// Memory policy of the running context.
+    pub(crate) mempolicy: Cell<numa::MemPolicy>,
+    /// Memory group of the running context.
+    pub(crate) memcg: Cell<Option<MemcgId>>,
+    /// Counts of the running context not yet added to its stats.
+    pub(crate) pending_stats: stats::PendingStats,
 }
 
 impl ContextSwitchPercpu {
@@ -324,6 +684,15 @@ impl ContextSwitchPercpu {
             .expect("not inside of context"))
     }
 
+    /// Address of the current context, or zero if there is none, for debug output only.
+    pub fn context_addr(&self) -> usize {
+        self.current_ctxt
+            .try_borrow()
+            .ok()
+            .and_then(|context| context.as_ref().map(|c| Arc::as_ptr(c) as usize))
+            .unwrap_or(0)
+    }
+
     /// Sets the current context to a new value.
     ///
     /// # Safety
@@ -0,0 +1,586 @@
+//! Lazy copying of user page tables when cloning address spaces.
+//!
+//! Rather than walking every page table entry when forking, the parent and child start out
+//! pointing to the same next-level tables, with the top-level entries write-protected in both.
+//! Since x86 enforces write permissions at every paging level, the first write through a shared
+//! subtree faults, and only the tables on the path to the faulting page are copied, one level at a
+//! time. Everything else modifying the page tables of an address space must [`unshare`] the
+//! affected range first.
+//!
+//! Page tables are reference counted using their `PageInfo`, like the frames they map, and every
+//! present entry of a table holds one reference to what it points to. An entry pointing to another
+//! table is only writable if that table is exclusive to the address space.
+//!
+//! Frames that are `RefCount::Shared`, e.g. pages borrowed by the kernel or a scheme, only remain
+//! shared for the address space that owned them before the fork. The other address spaces reach
+//! the shared tables through entries marked as borrowed, and when copying a table through such an
+//! entry, shared frames in private mappings are replaced by private copies. Until then, writes by
+//! the other holders of those frames remain visible.
+//!
+//! Huge pages are leaf entries in tables above the lowest level. Copying a table shares its huge
+//! pages copy-on-write, holding one reference to every frame of each, and the first write to one
+//! splits it, in the address space writing to it only.
+//!
+//! The same mechanism lets address spaces borrowing large read-only shared mappings from another
+//! address space map its leaf tables directly, rather than filling their own (see
+//! [`share_leaf_table`]). Those tables are copied as soon as either side modifies them.
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::{
+    memory::{
+        allocate_frame, deallocate_frame, get_page_info, init_frame, AddRefError, Enomem, Frame,
+        PageInfo, RefCount, RefKind,
+    },
+    paging::{
+        Page, PageMapper, PhysicalAddress, RmmA, RmmArch, VirtualAddress, ENTRY_COUNT, PAGE_SIZE,
+    },
+};
+
+use super::{
+    huge_page,
+    memory::{
+        copy_frame_to_frame_directly, handle_free_action, GenericFlusher, PageSpan, Provider,
+        TlbShootdownActions, UserGrants,
+    },
+};
+
+/// Whether the MMU honors write permissions of intermediate entries, for the entire subtree. Only
+/// true for x86, other architectures still copy address spaces eagerly.
+pub const SUPPORTED: bool = cfg!(target_arch = "x86_64");
+
+// Ignored by the MMU. Set on entries pointing to tables shared with, but not owned by, this
+// address space.
+const ENTRY_FLAG_BORROWED: usize = 1 << 9;
+
+const TOP_LEVEL: usize = RmmA::PAGE_LEVELS - 1;
+
+pub(super) fn entries(table: Frame) -> &'static [AtomicUsize] {
+    unsafe {
+        core::slice::from_raw_parts(
+            RmmA::phys_to_virt(table.base()).data() as *const AtomicUsize,
+            ENTRY_COUNT,
+        )
+    }
+}
+pub(super) fn entry_frame(entry: usize) -> Frame {
+    Frame::containing(PhysicalAddress::new(entry & RmmA::ENTRY_ADDRESS_MASK))
+}
+pub(super) fn is_present(entry: usize) -> bool {
+    entry & RmmA::ENTRY_FLAG_PRESENT != 0
+}
+fn is_writable(entry: usize) -> bool {
+    entry & RmmA::ENTRY_FLAG_READWRITE != 0
+}
+fn table_info(table: Frame) -> &'static PageInfo {
+    get_page_info(table).expect("page tables need a PageInfo")
+}
+/// Size of the range covered by each entry of a table at `level`, where the tables at level 0
+/// contain the leaf entries.
+pub(super) fn entry_span(level: usize) -> usize {
+    PAGE_SIZE << (level * RmmA::PAGE_ENTRY_SHIFT)
+}
+/// The pages covered by the entry at `base` of a table at `level`.
+pub(super) fn entry_pages(base: usize, level: usize) -> PageSpan {
+    PageSpan::new(
+        Page::containing_address(VirtualAddress::new(base)),
+        entry_span(level) / PAGE_SIZE,
+    )
+}
+
+/// Make the user page tables of `dst`, which must not have any user mappings yet, point to the
+/// same tables as `src`, which keeps owning them. Tables covering the `excluded` spans are not
+/// shared, and the pages in those spans are left out of `dst`. The TLB flush of `src` is queued
+/// on `src_flusher`.
+///
+/// # Safety
+///
+/// The caller must hold the write lock of the source address space.
+pub unsafe fn share_all(
+    src: &mut PageMapper,
+    src_grants: &UserGrants,
+    dst: &mut PageMapper,
+    excluded: &[PageSpan],
+    src_flusher: &mut impl GenericFlusher,
+) -> Result<(), Enomem> {
+    for span in excluded {
+        unshare(src, src_grants, *span, src_flusher)?;
+    }
+    share_range(
+        Frame::containing(src.table().phys()),
+        Frame::containing(dst.table().phys()),
+        TOP_LEVEL,
+        0,
+        crate::USER_END_OFFSET.div_ceil(entry_span(TOP_LEVEL)),
+        excluded,
+        src_grants,
+        src_flusher,
+    )
+}
+
+unsafe fn share_range(
+    src: Frame,
+    dst: Frame,
+    level: usize,
+    table_base: usize,
+    count: usize,
+    excluded: &[PageSpan],
+    src_grants: &UserGrants,
+    src_flusher: &mut impl GenericFlusher,
+) -> Result<(), Enomem> {
+    let span = entry_span(level);
+
+    for (i, (src_slot, dst_slot)) in entries(src)
+        .iter()
+        .zip(entries(dst))
+        .take(count)
+        .enumerate()
+    {
+        let entry = src_slot.load(Ordering::Relaxed);
+        if !is_present(entry) {
+            continue;
+        }
+        let base = table_base + i * span;
+        let is_excluded = excluded.iter().any(|excluded| {
+            excluded.base.start_address().data() < base + span
+                && excluded.end().start_address().data() > base
+        });
+
+        if level == 0 || huge_page::is_huge(entry) {
+            // Huge pages never extend beyond their grant, so they are either entirely excluded,
+            // or not at all.
+            if is_excluded {
+                continue;
+            }
+            dst_slot.store(
+                copy_entry(src_slot, level, base, true, src_grants)?,
+                Ordering::Relaxed,
+            );
+
+            if is_writable(entry) && !is_writable(src_slot.load(Ordering::Relaxed)) {
+                src_flusher.queue(
+                    entry_pages(base, level),
+                    entry_frame(entry),
+                    None,
+                    TlbShootdownActions::REVOKE_WRITE,
+                );
+            }
+        } else if is_excluded {
+            // Excluded spans have already been unshared, so this table belongs to `src` alone.
+            let next = allocate_frame().ok_or(Enomem)?;
+            dst_slot.store(
+                next.base().data() | entry & !RmmA::ENTRY_ADDRESS_MASK,
+                Ordering::Relaxed,
+            );
+            share_range(
+                entry_frame(entry),
+                next,
+                level - 1,
+                base,
+                ENTRY_COUNT,
+                excluded,
+                src_grants,
+                src_flusher,
+            )?;
+        } else {
+            let table = entry_frame(entry);
+            table_info(table)
+                .add_ref(RefKind::Cow)
+                .map_err(|_| Enomem)?;
+
+            if is_writable(entry) {
+                src_slot.fetch_and(!RmmA::ENTRY_FLAG_READWRITE, Ordering::Relaxed);
+                src_flusher.queue(
+                    entry_pages(base, level),
+                    table,
+                    None,
+                    TlbShootdownActions::REVOKE_WRITE,
+                );
+            }
+            dst_slot.store(
+                entry & !RmmA::ENTRY_FLAG_READWRITE | ENTRY_FLAG_BORROWED,
+                Ordering::Relaxed,
+            );
+        }
+    }
+    Ok(())
+}
+
+/// The span of pages covered by the leaf table containing `page`.
+pub fn leaf_table_span(page: Page) -> PageSpan {
+    let span = entry_span(1);
+    let base = page.start_address().data() / span * span;
+
+    PageSpan::new(
+        Page::containing_address(VirtualAddress::new(base)),
+        span / PAGE_SIZE,
+    )
+}
+
+/// Make `dst` map the leaf table of `src` covering `src_span`, at `dst_span`, without copying it.
+/// Both spans must be the [`leaf_table_span`] of a page, and be entirely covered by read-only
+/// grants sharing the same frames. Returns false, leaving the mappings of `dst` unmodified, unless
+/// every page in `src_span` is present and `dst` has no leaf table for `dst_span` yet.
+///
+/// # Safety
+///
+/// The caller must hold the write locks of both address spaces, and the tables covering
+/// `dst_span` must already be exclusive to `dst`.
+pub unsafe fn share_leaf_table(
+    src: &mut PageMapper,
+    src_span: PageSpan,
+    dst: &mut PageMapper,
+    dst_span: PageSpan,
+    src_flusher: &mut impl GenericFlusher,
+) -> Result<bool, Enomem> {
+    if !SUPPORTED {
+        return Ok(false);
+    }
+    let index = |address: usize, level: usize| (address / entry_span(level)) % ENTRY_COUNT;
+    let src_address = src_span.base.start_address().data();
+    let dst_address = dst_span.base.start_address().data();
+
+    let mut src_table = Frame::containing(src.table().phys());
+    for level in (2..=TOP_LEVEL).rev() {
+        let entry = entries(src_table)[index(src_address, level)].load(Ordering::Relaxed);
+        if !is_present(entry) || huge_page::is_huge(entry) {
+            return Ok(false);
+        }
+        src_table = entry_frame(entry);
+    }
+    let src_slot = &entries(src_table)[index(src_address, 1)];
+    let src_entry = src_slot.load(Ordering::Relaxed);
+    if !is_present(src_entry) || huge_page::is_huge(src_entry) {
+        return Ok(false);
+    }
+    let leaf_table = entry_frame(src_entry);
+    if !entries(leaf_table)
+        .iter()
+        .all(|entry| is_present(entry.load(Ordering::Relaxed)))
+    {
+        return Ok(false);
+    }
+
+    let mut dst_table = Frame::containing(dst.table().phys());
+    for level in (2..=TOP_LEVEL).rev() {
+        let slot = &entries(dst_table)[index(dst_address, level)];
+        let entry = slot.load(Ordering::Relaxed);
+
+        dst_table = if is_present(entry) {
+            debug_assert!(is_writable(entry), "dst tables must have been unshared");
+            entry_frame(entry)
+        } else {
+            // Use the same flags as the leaf table entry in `src`, which is a user table too.
+            let new_table = allocate_frame().ok_or(Enomem)?;
+            let flags = src_entry & !RmmA::ENTRY_ADDRESS_MASK & !ENTRY_FLAG_BORROWED;
+            slot.store(
+                new_table.base().data() | flags | RmmA::ENTRY_FLAG_READWRITE,
+                Ordering::Relaxed,
+            );
+            new_table
+        };
+    }
+    let dst_slot = &entries(dst_table)[index(dst_address, 1)];
+    if is_present(dst_slot.load(Ordering::Relaxed)) {
+        return Ok(false);
+    }
+
+    table_info(leaf_table)
+        .add_ref(RefKind::Cow)
+        .map_err(|_| Enomem)?;
+    if is_writable(src_entry) {
+        src_slot.fetch_and(!RmmA::ENTRY_FLAG_READWRITE, Ordering::Relaxed);
+        src_flusher.queue(
+            entry_pages(src_address & !(entry_span(1) - 1), 1),
+            leaf_table,
+            None,
+            TlbShootdownActions::REVOKE_WRITE,
+        );
+    }
+    dst_slot.store(
+        src_entry & !RmmA::ENTRY_FLAG_READWRITE | ENTRY_FLAG_BORROWED,
+        Ordering::Relaxed,
+    );
+    Ok(true)
+}
+
+/// Make the page tables covering `span` exclusive to the address space of `mapper` and `grants`,
+/// by copying those still shared with other address spaces, and split the huge pages overlapping
+/// it. Must be called before modifying any page table entry in `span`.
+pub fn unshare(
+    mapper: &mut PageMapper,
+    grants: &UserGrants,
+    span: PageSpan,
+    flusher: &mut impl GenericFlusher,
+) -> Result<(), Enomem> {
+    if !SUPPORTED || span.is_empty() {
+        return Ok(());
+    }
+    let top = Frame::containing(mapper.table().phys());
+    let start = span.base.start_address().data();
+    let end = span.end().start_address().data();
+
+    unsafe { unshare_range(top, TOP_LEVEL, 0, start..end, grants, flusher)? };
+    huge_page::split(mapper, span, flusher)
+}
+
+unsafe fn unshare_range(
+    table: Frame,
+    level: usize,
+    table_base: usize,
+    range: core::ops::Range<usize>,
+    grants: &UserGrants,
+    flusher: &mut impl GenericFlusher,
+) -> Result<(), Enomem> {
+    let span = entry_span(level);
+    let first = (range.start - table_base) / span;
+    let last = (range.end - 1 - table_base) / span;
+
+    for (i, slot) in entries(table).iter().enumerate().take(last + 1).skip(first) {
+        let entry = slot.load(Ordering::Relaxed);
+        // Huge pages are split by the caller once their tables are exclusive.
+        if !is_present(entry) || huge_page::is_huge(entry) {
+            continue;
+        }
+        let base = table_base + i * span;
+        let old = entry_frame(entry);
+        let borrowed = entry & ENTRY_FLAG_BORROWED != 0;
+
+        let next = if is_writable(entry) {
+            old
+        } else {
+            let next = if !borrowed && table_info(old).refcount() == Some(RefCount::One) {
+                // Every other address space has already copied or dropped it.
+                old
+            } else {
+                let copy = copy_table(old, level - 1, base, borrowed, grants)?;
+                flusher.queue_table_release(old, level - 1);
+                copy
+            };
+            let flags = entry & !RmmA::ENTRY_ADDRESS_MASK & !ENTRY_FLAG_BORROWED;
+            slot.store(
+                next.base().data() | flags | RmmA::ENTRY_FLAG_READWRITE,
+                Ordering::Relaxed,
+            );
+            next
+        };
+
+        if level > 1 {
+            let sub_range = range.start.max(base)..range.end.min(base + span);
+            unshare_range(next, level - 1, base, sub_range, grants, flusher)?;
+        }
+    }
+    Ok(())
+}
+
+/// Copy `table` at `level`, covering the addresses starting at `base`, adding a reference to
+/// everything it points to. Private frames become CoW in both copies.
+unsafe fn copy_table(
+    table: Frame,
+    level: usize,
+    base: usize,
+    borrowed: bool,
+    grants: &UserGrants,
+) -> Result<Frame, Enomem> {
+    let copy = allocate_frame().ok_or(Enomem)?;
+    let dst = entries(copy);
+
+    for (i, src_entry) in entries(table).iter().enumerate() {
+        match copy_entry(
+            src_entry,
+            level,
+            base + i * entry_span(level),
+            borrowed,
+            grants,
+        ) {
+            Ok(entry) => dst[i].store(entry, Ordering::Relaxed),
+            Err(Enomem) => {
+                for entry in &dst[..i] {
+                    let entry = entry.load(Ordering::Relaxed);
+                    if is_present(entry) {
+                        release_entry(entry, level);
+                    }
+                }
+                deallocate_frame(copy);
+                return Err(Enomem);
+            }
+        }
+    }
+    Ok(copy)
+}
+
+unsafe fn copy_entry(
+    src_entry: &AtomicUsize,
+    level: usize,
+    address: usize,
+    borrowed: bool,
+    grants: &UserGrants,
+) -> Result<usize, Enomem> {
+    let entry = src_entry.load(Ordering::Relaxed);
+    if !is_present(entry) {
+        return Ok(entry);
+    }
+    let frame = entry_frame(entry);
+
+    // Bit 7 of leaf entries selects the PAT entry instead.
+    if level > 0 && huge_page::is_huge(entry) {
+        return copy_huge_entry(src_entry, level, address, borrowed, grants);
+    }
+    if level > 0 {
+        table_info(frame)
+            .add_ref(RefKind::Cow)
+            .map_err(|_| Enomem)?;
+        src_entry.fetch_and(!RmmA::ENTRY_FLAG_READWRITE, Ordering::Relaxed);
+
+        let borrowed_flag = if borrowed { ENTRY_FLAG_BORROWED } else { 0 };
+        return Ok(entry & !RmmA::ENTRY_FLAG_READWRITE | borrowed_flag);
+    }
+
+    let grant = grants.contains(Page::containing_address(VirtualAddress::new(address)));
+    let entry = match grant {
+        Some((_, grant)) if borrowed && !grant.flags().has_write() => {
+            entry & !RmmA::ENTRY_FLAG_READWRITE
+        }
+        Some(_) => entry,
+        // Only the owner keeps pages outside its grants.
+        None if borrowed => return Ok(0),
+        None => entry,
+    };
+    let Some(info) = get_page_info(frame) else {
+        // Physically borrowed memory is not reference counted.
+        return Ok(entry);
+    };
+    let is_private = grant.map_or(true, |(_, grant)| {
+        matches!(grant.provider, Provider::Allocated { .. })
+    });
+
+    match info.refcount() {
+        Some(RefCount::Shared(_)) if borrowed && is_private => {
+            let private = init_frame(RefCount::One).map_err(|_| Enomem)?;
+            copy_frame_to_frame_directly(private, frame);
+
+            return Ok(entry & !RmmA::ENTRY_ADDRESS_MASK | private.base().data());
+        }
+        Some(RefCount::Shared(_)) => {
+            info.add_ref(RefKind::Shared).map_err(|_| Enomem)?;
+            return Ok(entry);
+        }
+        _ if !is_private => match info.add_ref(RefKind::Shared) {
+            Ok(()) => return Ok(entry),
+            Err(AddRefError::CowToShared) => (),
+            Err(_) => return Err(Enomem),
+        },
+        _ => (),
+    }
+    info.add_ref(RefKind::Cow).map_err(|_| Enomem)?;
+    src_entry.fetch_and(!RmmA::ENTRY_FLAG_READWRITE, Ordering::Relaxed);
+
+    Ok(entry & !RmmA::ENTRY_FLAG_READWRITE)
+}
+
+/// Like [`copy_entry`], for the huge page entry `src_entry` of a table at `level`, which is made
+/// CoW in both copies with a reference to each of its frames.
+unsafe fn copy_huge_entry(
+    src_entry: &AtomicUsize,
+    level: usize,
+    address: usize,
+    borrowed: bool,
+    grants: &UserGrants,
+) -> Result<usize, Enomem> {
+    let entry = src_entry.load(Ordering::Relaxed);
+    let frame = entry_frame(entry);
+    let page = Page::containing_address(VirtualAddress::new(address));
+    if borrowed && grants.contains(page).is_none() {
+        // Only the owner keeps pages outside its grants.
+        return Ok(0);
+    }
+    if get_page_info(frame).is_none() {
+        // Physically borrowed memory is not reference counted.
+        return Ok(entry);
+    }
+
+    let count = entry_span(level) / PAGE_SIZE;
+    for i in 0..count {
+        let info = get_page_info(frame.next_by(i)).expect("huge pages need a PageInfo per frame");
+        if info.add_ref(RefKind::Cow).is_err() {
+            for j in 0..i {
+                handle_free_action(frame.next_by(j), None);
+            }
+            return Err(Enomem);
+        }
+    }
+    src_entry.fetch_and(!RmmA::ENTRY_FLAG_READWRITE, Ordering::Relaxed);
+
+    Ok(entry & !RmmA::ENTRY_FLAG_READWRITE)
+}
+
+/// Drop the references held by the present `entry` of a table at `level`.
+unsafe fn release_entry(entry: usize, level: usize) {
+    if level > 0 && huge_page::is_huge(entry) {
+        for i in 0..entry_span(level) / PAGE_SIZE {
+            handle_free_action(entry_frame(entry).next_by(i), None);
+        }
+    } else if level > 0 {
+        release_table(entry_frame(entry), level - 1);
+    } else {
+        handle_free_action(entry_frame(entry), None);
+    }
+}
+
+/// Drop a reference to `table` at `level`. Dropping the last reference frees the table, along
+/// with the references held by its entries.
+///
+/// # Safety
+///
+/// No TLB may still contain translations through the reference being dropped.
+pub unsafe fn release_table(table: Frame, level: usize) {
+    if table_info(table).remove_ref().is_some() {
+        return;
+    }
+    for slot in entries(table) {
+        let entry = slot.load(Ordering::Relaxed);
+        if is_present(entry) {
+            release_entry(entry, level);
+        }
+    }
+    deallocate_frame(table);
+}
+
+/// Drop the references to all tables shared with other address spaces, and clear the entries
+/// pointing to them, so that tearing down the mappings of this address space leaves them alone.
+///
+/// # Safety
+///
+/// The address space must no longer be in use by any CPU.
+pub unsafe fn detach(mapper: &mut PageMapper) {
+    if !SUPPORTED {
+        return;
+    }
+    let user_entries = crate::USER_END_OFFSET.div_ceil(entry_span(TOP_LEVEL));
+    detach_inner(
+        Frame::containing(mapper.table().phys()),
+        TOP_LEVEL,
+        user_entries,
+    );
+}
+
+unsafe fn detach_inner(table: Frame, level: usize, count: usize) {
+    for slot in &entries(table)[..count] {
+        let entry = slot.load(Ordering::Relaxed);
+        // Huge pages are leaf entries, unmapped along with their grants.
+        if !is_present(entry) || huge_page::is_huge(entry) {
+            continue;
+        }
+        let next = entry_frame(entry);
+
+        // Removing the last reference does not change the refcount, in which case the table is
+        // exclusive again and is torn down along with the grants.
+        if !is_writable(entry) && table_info(next).remove_ref().is_some() {
+            slot.store(0, Ordering::Relaxed);
+            continue;
+        }
+        if level > 1 {
+            detach_inner(next, level - 1, ENTRY_COUNT);
+        }
+    }
+}
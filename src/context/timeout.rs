@@ -1,14 +1,16 @@
 use alloc::collections::VecDeque;
-use spin::{Mutex, MutexGuard, Once};
+use spin::Once;
 
 use crate::{
     event,
     scheme::SchemeId,
+    sync::{Mutex, MutexGuard},
     syscall::{
         data::TimeSpec,
         flag::{CLOCK_MONOTONIC, CLOCK_REALTIME, EVENT_READ},
     },
     time,
+    timer::{self, Timer},
 };
 
 #[derive(Debug)]
@@ -34,13 +36,28 @@ fn registry() -> MutexGuard<'static, Registry> {
 }
 
 pub fn register(scheme_id: SchemeId, event_id: usize, clock: usize, time: TimeSpec) {
-    let mut registry = registry();
-    registry.push_back(Timeout {
+    let nanos = (time.tv_sec as u128 * time::NANOS_PER_SEC) + (time.tv_nsec as u128);
+    registry().push_back(Timeout {
         scheme_id,
         event_id,
         clock,
-        time: (time.tv_sec as u128 * time::NANOS_PER_SEC) + (time.tv_nsec as u128),
+        time: nanos,
     });
+
+    // Timers are on the monotonic clock, so convert the deadline.
+    let mono = time::monotonic();
+    let now = match clock {
+        CLOCK_REALTIME => time::realtime(),
+        time::CLOCK_BOOTTIME => time::boottime(),
+        _ => mono,
+    };
+    timer::add(mono + nanos.saturating_sub(now), Timer::Timeouts);
+}
+
+/// The number of registered timeouts.
+#[cfg(feature = "ktest")]
+pub fn pending() -> usize {
+    registry().len()
 }
 
 pub fn trigger() {
@@ -48,6 +65,7 @@ pub fn trigger() {
 
     let mono = time::monotonic();
     let real = time::realtime();
+    let boot = time::boottime();
 
     let mut i = 0;
     while i < registry.len() {
@@ -60,6 +78,10 @@ pub fn trigger() {
                 let time = registry[i].time;
                 real >= time
             }
+            time::CLOCK_BOOTTIME => {
+                let time = registry[i].time;
+                boot >= time
+            }
             clock => {
                 println!("timeout::trigger: unknown clock {}", clock);
                 true
@@ -0,0 +1,56 @@
+//! Userspace page fault delegation, similar to Linux's userfaultfd.
+//!
+//! A handler registers page ranges of an address space through a `userfault` handle obtained from
+//! the `proc:` address space handle. When a registered page that is not yet present is accessed,
+//! the kernel queues a [`UserfaultEvent`] on the handle and blocks the faulting context, instead of
+//! mapping a zeroed page. The handler resolves the fault by copying data into the page (or
+//! zero-filling it), which wakes up the faulting context.
+
+use alloc::sync::Arc;
+
+use crate::{
+    sync::{WaitCondition, WaitQueue},
+    syscall::error::{Error, Result, ENOMEM},
+};
+
+/// Register `[base, base+size)`. Arguments: `base`, `size`.
+pub const USERFAULT_REGISTER: usize = 0;
+/// Unregister all ranges overlapping `[base, base+size)`. Arguments: `base`, `size`.
+pub const USERFAULT_UNREGISTER: usize = 1;
+/// Resolve faults by copying user memory into not-yet-present pages. Arguments: `dst`, `src`,
+/// `size`.
+pub const USERFAULT_COPY: usize = 2;
+/// Resolve faults by mapping zeroed pages. Arguments: `dst`, `size`.
+pub const USERFAULT_ZEROPAGE: usize = 3;
+/// Wake up all contexts waiting for a fault to be resolved, which will then retry the access.
+pub const USERFAULT_WAKE: usize = 4;
+
+/// The fault was caused by a write.
+pub const USERFAULT_FLAG_WRITE: usize = 1;
+
+#[derive(Clone, Copy, Debug)]
+#[repr(C)]
+pub struct UserfaultEvent {
+    /// Page-aligned faulting address.
+    pub address: usize,
+    pub flags: usize,
+}
+
+#[derive(Debug)]
+pub struct Userfault {
+    /// Faults not yet read by the handler.
+    pub events: WaitQueue<UserfaultEvent>,
+    /// Contexts waiting for the handler to resolve their fault. Always waited on while holding
+    /// the address space lock, which must also be held when notifying.
+    pub resolved: WaitCondition,
+}
+
+impl Userfault {
+    pub fn new() -> Result<Arc<Self>> {
+        Arc::try_new(Self {
+            events: WaitQueue::new(),
+            resolved: WaitCondition::new(),
+        })
+        .map_err(|_| Error::new(ENOMEM))
+    }
+}
@@ -73,6 +73,21 @@ impl LogicalCpuSet {
         let (word, bit) = parts(id);
         let _ = self.0[word].fetch_and(!(1 << bit), Ordering::Release);
     }
+    pub fn atomic_contains(&self, id: LogicalCpuId) -> bool {
+        let (word, bit) = parts(id);
+        self.0[word].load(Ordering::Acquire) & (1 << bit) != 0
+    }
+    /// Add all CPUs of `raw` to the set.
+    pub fn atomic_union(&self, raw: &RawMask) {
+        for (word, bits) in self.0.iter().zip(raw) {
+            let _ = word.fetch_or(*bits, Ordering::Release);
+        }
+    }
+    pub fn atomic_clear_all(&self) {
+        for word in self.0.iter() {
+            word.store(0, Ordering::Release);
+        }
+    }
 
     pub fn override_from(&mut self, raw: &RawMask) {
         self.0 = raw.map(AtomicUsize::new);
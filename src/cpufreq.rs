@@ -0,0 +1,182 @@
+//! CPU frequency scaling.
+//!
+//! The driver of the architecture, in its `dvfs` module, sets the performance level of each CPU
+//! between limits it reports per CPU, and a governor chosen through `sys:cpufreq` picks the level:
+//! `performance`
+//! and `powersave` keep the highest and the lowest one, while `ondemand`, the default, follows the
+//! load of the CPU, which is the share of time it did not spend idle since the governor last ran.
+//! The governor runs on context switches, at most every [`SAMPLE_INTERVAL`].
+
+use core::{
+    cell::Cell,
+    sync::atomic::{AtomicU32, AtomicU8, Ordering},
+};
+
+use crate::{
+    dvfs, idle,
+    percpu::PercpuBlock,
+    syscall::error::{Error, Result, EINVAL, ENODEV},
+    time,
+};
+
+/// Shortest time between two runs of the governor on a CPU, in nanoseconds.
+pub const SAMPLE_INTERVAL: u128 = 20 * time::NANOS_PER_SEC / 1000;
+
+/// Load in percent above which `ondemand` uses the highest level.
+const UP_THRESHOLD: u128 = 80;
+
+/// How much the hardware should favor performance over energy, for drivers with such a hint.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum EnergyBias {
+    Performance,
+    Balanced,
+    Powersave,
+}
+
+pub trait CpufreqDriver: Sync {
+    fn name(&self) -> &'static str;
+    /// The lowest and highest performance level of the current CPU, in driver specific units.
+    fn limits(&self) -> (u32, u32);
+    /// The frequency of `level` in kHz, if known.
+    fn frequency(&self, level: u32) -> Option<u32>;
+    /// Set the energy bias of the current CPU, called before it first sets a level, and whenever
+    /// the governor changes.
+    fn set_bias(&self, bias: EnergyBias);
+    /// Request `level` on the current CPU.
+    fn set_level(&self, level: u32);
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[repr(u8)]
+pub enum Governor {
+    Performance,
+    Powersave,
+    Ondemand,
+}
+
+impl Governor {
+    pub const ALL: [Self; 3] = [Self::Performance, Self::Powersave, Self::Ondemand];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Performance => "performance",
+            Self::Powersave => "powersave",
+            Self::Ondemand => "ondemand",
+        }
+    }
+    fn bias(self) -> EnergyBias {
+        match self {
+            Self::Performance => EnergyBias::Performance,
+            Self::Powersave => EnergyBias::Powersave,
+            Self::Ondemand => EnergyBias::Balanced,
+        }
+    }
+}
+
+static GOVERNOR: AtomicU8 = AtomicU8::new(Governor::Ondemand as u8);
+/// Incremented when the governor changes, for the CPUs to update their energy bias.
+static GENERATION: AtomicU32 = AtomicU32::new(1);
+
+pub fn governor() -> Governor {
+    Governor::ALL[usize::from(GOVERNOR.load(Ordering::Relaxed))]
+}
+
+/// The frequency scaling state of a CPU.
+#[derive(Default)]
+pub struct PercpuCpufreq {
+    /// The generation of the governor the energy bias was set for, or zero.
+    generation: Cell<u32>,
+    /// Time the governor last ran at.
+    last_run: Cell<u128>,
+    /// Nanoseconds spent idle when the governor last ran.
+    last_idle: Cell<u64>,
+    /// The limits of this CPU, once known.
+    min: AtomicU32,
+    max: AtomicU32,
+    /// The level last requested.
+    level: AtomicU32,
+}
+
+impl PercpuCpufreq {
+    /// The lowest, highest, and last requested level of this CPU, if the governor ran on it.
+    pub fn levels(&self) -> Option<(u32, u32, u32)> {
+        let max = self.max.load(Ordering::Relaxed);
+        (max != 0).then(|| {
+            (
+                self.min.load(Ordering::Relaxed),
+                max,
+                self.level.load(Ordering::Relaxed),
+            )
+        })
+    }
+}
+
+pub fn driver() -> Option<&'static dyn CpufreqDriver> {
+    dvfs::driver()
+}
+
+/// Called on every context switch, to run the governor if it is time to.
+pub fn switch(percpu: &PercpuBlock) {
+    let Some(driver) = driver() else {
+        return;
+    };
+    let state = &percpu.cpufreq;
+    let now = time::monotonic();
+    let elapsed = now.saturating_sub(state.last_run.get());
+    if elapsed < SAMPLE_INTERVAL {
+        return;
+    }
+
+    let idle = (0..idle::states().len())
+        .map(|i| percpu.idle.stats(i).1)
+        .sum::<u64>();
+    let idle_elapsed = u128::from(idle.saturating_sub(state.last_idle.get()));
+    let load = elapsed.saturating_sub(idle_elapsed) * 100 / elapsed;
+    state.last_run.set(now);
+    state.last_idle.set(idle);
+
+    let governor = governor();
+    let generation = GENERATION.load(Ordering::Relaxed);
+    let changed = state.generation.get() != generation;
+    if changed {
+        let (min, max) = driver.limits();
+        state.min.store(min, Ordering::Relaxed);
+        state.max.store(max, Ordering::Relaxed);
+        driver.set_bias(governor.bias());
+        state.generation.set(generation);
+    }
+
+    let (min, max) = (
+        state.min.load(Ordering::Relaxed),
+        state.max.load(Ordering::Relaxed),
+    );
+    let level = match governor {
+        Governor::Performance => max,
+        Governor::Powersave => min,
+        Governor::Ondemand if load > UP_THRESHOLD => max,
+        Governor::Ondemand => min + (u128::from(max - min) * load / 100) as u32,
+    };
+    if changed || level != state.level.load(Ordering::Relaxed) {
+        driver.set_level(level);
+        state.level.store(level, Ordering::Relaxed);
+    }
+}
+
+/// Handle a command written to `sys:cpufreq`, `governor <name>`.
+pub fn command(command: &str) -> Result<()> {
+    match command.trim().split_once(' ') {
+        Some(("governor", name)) => {
+            if driver().is_none() {
+                return Err(Error::new(ENODEV));
+            }
+            let governor = Governor::ALL
+                .into_iter()
+                .find(|governor| governor.name() == name.trim())
+                .ok_or(Error::new(EINVAL))?;
+            GOVERNOR.store(governor as u8, Ordering::Relaxed);
+            GENERATION.fetch_add(1, Ordering::Relaxed);
+        }
+        _ => return Err(Error::new(EINVAL)),
+    }
+    Ok(())
+}
@@ -1,227 +1,104 @@
-use crate::{
-    context::Context,
-    paging::{RmmA, RmmArch, TableKind, PAGE_SIZE},
+//! Kernel debugger, dumping the state of contexts and checking the consistency of their address
+//! spaces. The dump is the same on every architecture, which only provide the few details in
+//! [`DebuggerArch`].
+//!
+//! Like the magic SysRq key, typing `Ctrl-\` and then `d` on the serial console enters an
+//! interactive monitor on the next timer tick, which reads commands from the serial port with
+//! interrupts disabled until told to resume, unless the kernel is locked down. Typing `Ctrl-\`
+//! twice sends it through, as does following it with anything else but `d`. Other CPUs keep
+//! running, and the monitor takes the same locks as the code it inspects, so like the rest of the
+//! debugger it may deadlock or show inconsistent state.
+
+use alloc::sync::Arc;
+use core::{
+    str,
+    sync::atomic::{AtomicBool, Ordering},
 };
-use spinning_top::RwSpinlock;
-
-//TODO: combine arches into one function (aarch64 one is newest)
-
-// Super unsafe due to page table switching and raw pointers!
-#[cfg(target_arch = "aarch64")]
-pub unsafe fn debugger(target_id: Option<crate::context::ContextId>) {
-    use hashbrown::HashSet;
-
-    use crate::memory::{get_page_info, RefCount};
-
-    println!("DEBUGGER START");
-    println!();
-
-    let mut tree = HashMap::new();
-
-    let old_table = RmmA::table(TableKind::User);
-
-    let mut spaces = HashSet::new();
-
-    for (id, context_lock) in crate::context::contexts().iter() {
-        if target_id.map_or(false, |target_id| *id != target_id) {
-            continue;
-        }
-        let context = context_lock.read();
-        println!("{}: {}", (*id).get(), context.name);
-
-        println!("status: {:?}", context.status);
-        if !context.status_reason.is_empty() {
-            println!("reason: {}", context.status_reason);
-        }
-
-        // Switch to context page table to ensure syscall debug and stack dump will work
-        if let Some(ref space) = context.addr_space {
-            let new_as = spaces.insert(space.acquire_read().table.utable.table().phys().data());
+use hashbrown::{HashMap, HashSet};
 
-            RmmA::set_table(
-                TableKind::User,
-                space.acquire_read().table.utable.table().phys(),
-            );
-            check_consistency(&mut *space.acquire_write(), new_as, &mut tree);
-
-            if let Some([a, b, c, d, e, f]) = context.current_syscall() {
-                println!(
-                    "syscall: {}",
-                    crate::syscall::debug::format_call(a, b, c, d, e, f)
-                );
-            }
-
-            {
-                let space = space.acquire_read();
-                if !space.grants.is_empty() {
-                    println!("grants:");
-                    for (base, grant) in space.grants.iter() {
-                        println!(
-                            "    virt 0x{:016x}:0x{:016x} size 0x{:08x} {:?}",
-                            base.start_address().data(),
-                            base.next_by(grant.page_count() - 1).start_address().data() + 0xFFF,
-                            grant.page_count() * PAGE_SIZE,
-                            grant.provider,
-                        );
-                    }
-                }
-            }
-
-            if let Some(regs) = context.regs() {
-                println!("regs:");
-                regs.dump();
-
-                let mut sp = regs.iret.sp_el0;
-                println!("stack: {:>016x}", sp);
-                //Maximum 64 usizes
-                for _ in 0..64 {
-                    if context.addr_space.as_ref().map_or(false, |space| {
-                        space
-                            .acquire_read()
-                            .table
-                            .utable
-                            .translate(crate::paging::VirtualAddress::new(sp))
-                            .is_some()
-                    }) {
-                        let value = *(sp as *const usize);
-                        println!("    {:>016x}: {:>016x}", sp, value);
-                        if let Some(next_sp) = sp.checked_add(core::mem::size_of::<usize>()) {
-                            sp = next_sp;
-                        } else {
-                            println!("    {:>016x}: OVERFLOW", sp);
-                            break;
-                        }
-                    } else {
-                        println!("    {:>016x}: GUARD PAGE", sp);
-                        break;
-                    }
-                }
-            }
-
-            // Switch to original page table
-            RmmA::set_table(TableKind::User, old_table);
-        }
+use crate::{
+    arch::interrupt::InterruptStack,
+    context::{huge_page, memory::AddrSpace, Context},
+    lockdown,
+    memory::{get_page_info, the_zeroed_frame, Frame, KernelMapper, RefCount},
+    paging::{PhysicalAddress, RmmA, RmmArch, TableKind, VirtualAddress, PAGE_SIZE},
+    sync::RwSpinlock,
+    syscall::usercopy::UserSlice,
+};
 
-        println!();
-    }
-    for (frame, (count, p)) in tree {
-        let rc = get_page_info(frame).unwrap().refcount();
-        let c = match rc {
-            RefCount::Zero => 0,
-            RefCount::One => 1,
-            RefCount::Cow(c) => c.get(),
-            RefCount::Shared(s) => s.get(),
-        };
-        if c < count {
-            println!("undercounted frame {:?} ({} < {})", frame, c, count);
-        }
+/// The parts of the debugger that differ between architectures, implemented by their
+/// [`InterruptStack`].
+pub trait DebuggerArch {
+    /// Number of hex digits of an address.
+    const ADDR_WIDTH: usize = 2 * core::mem::size_of::<usize>();
+    /// Whether user page tables have the four levels of 512 entries [`check_consistency`] walks.
+    const CHECK_CONSISTENCY: bool = true;
+
+    /// The user stack pointer of an interrupted context.
+    fn user_stack_pointer(&self) -> usize;
+
+    /// Switch to the user page table `table`, so that the memory of another context can be read,
+    /// returning the previous one.
+    unsafe fn switch_table(table: PhysicalAddress) -> PhysicalAddress {
+        let old_table = RmmA::table(TableKind::User);
+        RmmA::set_table(TableKind::User, table);
+        old_table
     }
 
-    println!("DEBUGGER END");
-}
+    /// Print the kernel stack of a context that is switched out, if it can be unwound.
+    fn dump_saved_kernel_stack(_context: &Context) {}
 
-// Super unsafe due to page table switching and raw pointers!
-#[cfg(target_arch = "x86")]
-pub unsafe fn debugger(target_id: Option<crate::context::ContextId>) {
-    println!("DEBUGGER START");
-    println!();
-
-    let old_table = RmmA::table(TableKind::User);
-
-    for (id, context_lock) in crate::context::contexts().iter() {
-        if target_id.map_or(false, |target_id| *id != target_id) {
-            continue;
-        }
-        let context = context_lock.read();
-        println!("{}: {}", (*id).get(), context.name);
+    /// Read a byte from the serial console without waiting, for the monitor.
+    fn poll_input() -> Option<u8>;
+}
 
-        // Switch to context page table to ensure syscall debug and stack dump will work
-        if let Some(ref space) = context.addr_space {
-            RmmA::set_table(
-                TableKind::User,
-                space.acquire_read().table.utable.table().phys(),
-            );
-            //TODO check_consistency(&mut space.write());
-        }
+/// Read a word of the user stack of the current address space. This goes through the usercopy
+/// functions like any other access to user memory, as SMAP and PAN forbid it otherwise.
+fn read_user_word(addr: usize) -> Option<usize> {
+    UserSlice::ro(addr, core::mem::size_of::<usize>())
+        .and_then(|slice| slice.read_usize())
+        .ok()
+}
 
-        println!("status: {:?}", context.status);
-        if !context.status_reason.is_empty() {
-            println!("reason: {}", context.status_reason);
-        }
-        if let Some([a, b, c, d, e, f]) = context.current_syscall() {
-            println!(
-                "syscall: {}",
-                crate::syscall::debug::format_call(a, b, c, d, e, f)
-            );
+/// Print up to 64 words of the user stack of `context`, whose address space must be active.
+fn dump_user_stack(context: &Context, regs: &InterruptStack) {
+    const W: usize = InterruptStack::ADDR_WIDTH;
+
+    let mut sp = regs.user_stack_pointer();
+    println!("stack: {:>0w$x}", sp, w = W);
+    for _ in 0..64 {
+        let mapped = context.addr_space.as_ref().map_or(false, |space| {
+            space
+                .acquire_read()
+                .table
+                .utable
+                .translate(VirtualAddress::new(sp))
+                .is_some()
+        });
+        if !mapped {
+            println!("    {:>0w$x}: GUARD PAGE", sp, w = W);
+            break;
         }
-        if let Some(ref addr_space) = context.addr_space {
-            let addr_space = addr_space.acquire_read();
-            if !addr_space.grants.is_empty() {
-                println!("grants:");
-                for (base, grant) in addr_space.grants.iter() {
-                    println!(
-                        "    virt 0x{:08x}:0x{:08x} size 0x{:08x} {:?}",
-                        base.start_address().data(),
-                        base.next_by(grant.page_count()).start_address().data() + 0xFFF,
-                        grant.page_count() * crate::memory::PAGE_SIZE,
-                        grant.provider,
-                    );
-                }
+        match read_user_word(sp) {
+            Some(value) => println!("    {:>0w$x}: {:>0w$x}", sp, value, w = W),
+            None => {
+                println!("    {:>0w$x}: UNREADABLE", sp, w = W);
+                break;
             }
         }
-        if let Some(regs) = context.regs() {
-            println!("regs:");
-            regs.dump();
-
-            let mut sp = regs.iret.esp;
-            println!("stack: {:>08x}", sp);
-            //Maximum 64 dwords
-            for _ in 0..64 {
-                if context.addr_space.as_ref().map_or(false, |space| {
-                    space
-                        .acquire_read()
-                        .table
-                        .utable
-                        .translate(crate::paging::VirtualAddress::new(sp))
-                        .is_some()
-                }) {
-                    let value = *(sp as *const usize);
-                    println!("    {:>08x}: {:>08x}", sp, value);
-                    if let Some(next_sp) = sp.checked_add(core::mem::size_of::<usize>()) {
-                        sp = next_sp;
-                    } else {
-                        println!("    {:>08x}: OVERFLOW", sp);
-                        break;
-                    }
-                } else {
-                    println!("    {:>08x}: GUARD PAGE", sp);
-                    break;
-                }
-            }
+        if let Some(next_sp) = sp.checked_add(core::mem::size_of::<usize>()) {
+            sp = next_sp;
+        } else {
+            println!("    {:>0w$x}: OVERFLOW", sp, w = W);
+            break;
         }
-
-        // Switch to original page table
-        RmmA::set_table(TableKind::User, old_table);
-
-        println!();
     }
-
-    println!("DEBUGGER END");
 }
 
 // Super unsafe due to page table switching and raw pointers!
-#[cfg(target_arch = "x86_64")]
 pub unsafe fn debugger(target_id: Option<*const RwSpinlock<Context>>) {
-    use core::sync::atomic::Ordering;
-
-    use alloc::sync::Arc;
-    use hashbrown::HashSet;
-
-    use crate::memory::{get_page_info, the_zeroed_frame, RefCount};
-
-    unsafe {
-        x86::bits64::rflags::stac();
-    }
+    const W: usize = InterruptStack::ADDR_WIDTH;
+    let check = InterruptStack::CHECK_CONSISTENCY;
 
     println!("DEBUGGER START");
     println!();
@@ -233,8 +110,6 @@ pub unsafe fn debugger(target_id: Option<*const RwSpinlock<Context>>) {
 
     tree.insert(the_zeroed_frame().0, (1, false));
 
-    let old_table = RmmA::table(TableKind::User);
-
     for context_lock in crate::context::contexts().iter() {
         if target_id.map_or(false, |target_id| Arc::as_ptr(&context_lock.0) != target_id) {
             continue;
@@ -254,14 +129,15 @@ pub unsafe fn debugger(target_id: Option<*const RwSpinlock<Context>>) {
         }
 
         // Switch to context page table to ensure syscall debug and stack dump will work
-        if let Some(ref space) = context.addr_space {
-            let was_new = spaces.insert(space.acquire_read().table.utable.table().phys().data());
-            RmmA::set_table(
-                TableKind::User,
-                space.acquire_read().table.utable.table().phys(),
-            );
-            check_consistency(&mut space.acquire_write(), was_new, &mut tree);
-        }
+        let old_table = context.addr_space.as_ref().map(|space| {
+            let table = space.acquire_read().table.utable.table().phys();
+            let was_new = spaces.insert(table.data());
+            let old_table = InterruptStack::switch_table(table);
+            if check {
+                check_consistency(&mut space.acquire_write(), was_new, &mut tree);
+            }
+            old_table
+        });
 
         println!("status: {:?}", context.status);
         if !context.status_reason.is_empty() {
@@ -280,11 +156,13 @@ pub unsafe fn debugger(target_id: Option<*const RwSpinlock<Context>>) {
                 for (base, info) in addr_space.grants.iter() {
                     let size = info.page_count() * PAGE_SIZE;
                     println!(
-                        "    virt 0x{:016x}:0x{:016x} size 0x{:08x} {:?}",
+                        "    virt 0x{:0w$x}:0x{:0w$x} size 0x{:08x} {:?} {}",
                         base.start_address().data(),
                         base.start_address().data() + size - 1,
                         size,
                         info.provider,
+                        info.label().unwrap_or(""),
+                        w = W,
                     );
                 }
             }
@@ -293,75 +171,58 @@ pub unsafe fn debugger(target_id: Option<*const RwSpinlock<Context>>) {
             println!("regs:");
             regs.dump();
 
-            let mut rsp = regs.iret.rsp;
-            println!("stack: {:>016x}", rsp);
-            //Maximum 64 qwords
-            for _ in 0..64 {
-                if context.addr_space.as_ref().map_or(false, |space| {
-                    space
-                        .acquire_read()
-                        .table
-                        .utable
-                        .translate(crate::paging::VirtualAddress::new(rsp))
-                        .is_some()
-                }) {
-                    let value = *(rsp as *const usize);
-                    println!("    {:>016x}: {:>016x}", rsp, value);
-                    if let Some(next_rsp) = rsp.checked_add(core::mem::size_of::<usize>()) {
-                        rsp = next_rsp;
-                    } else {
-                        println!("    {:>016x}: OVERFLOW", rsp);
-                        break;
-                    }
-                } else {
-                    println!("    {:>016x}: GUARD PAGE", rsp);
-                    break;
-                }
+            if old_table.is_some() {
+                dump_user_stack(&context, regs);
             }
         }
+        // The kernel stack of a running context is changing under us, unless it is this one.
+        if !context.running {
+            InterruptStack::dump_saved_kernel_stack(&context);
+        } else if Arc::ptr_eq(&context_lock.0, &crate::context::current()) {
+            println!("kernel stack:");
+            crate::panic::stack_trace();
+        }
 
         // Switch to original page table
-        RmmA::set_table(TableKind::User, old_table);
+        if let Some(old_table) = old_table {
+            InterruptStack::switch_table(old_table);
+        }
 
         println!();
     }
-    crate::scheme::proc::foreach_addrsp(|addrsp| {
-        let was_new = spaces.insert(addrsp.acquire_read().table.utable.table().phys().data());
-        check_consistency(&mut *addrsp.acquire_write(), was_new, &mut tree);
-    });
-    for (frame, (count, p)) in tree {
-        let Some(info) = get_page_info(frame) else {
-            assert!(p);
-            continue;
-        };
-        let rc = info.refcount();
-        let (c, s) = match rc {
-            None => (0, false),
-            Some(RefCount::One) => (1, false),
-            Some(RefCount::Cow(c)) => (c.get(), false),
-            Some(RefCount::Shared(s)) => (s.get(), true),
-        };
-        if c != count {
-            println!(
-                "frame refcount mismatch for {:?} ({} != {} s {})",
-                frame, c, count, s
-            );
+    if check {
+        crate::scheme::proc::foreach_addrsp(|addrsp| {
+            let was_new = spaces.insert(addrsp.acquire_read().table.utable.table().phys().data());
+            check_consistency(&mut *addrsp.acquire_write(), was_new, &mut tree);
+        });
+        for (frame, (count, p)) in tree {
+            let Some(info) = get_page_info(frame) else {
+                assert!(p);
+                continue;
+            };
+            let rc = info.refcount();
+            let (c, s) = match rc {
+                None => (0, false),
+                Some(RefCount::One) => (1, false),
+                Some(RefCount::Cow(c)) => (c.get(), false),
+                Some(RefCount::Shared(s)) => (s.get(), true),
+            };
+            if c != count {
+                println!(
+                    "frame refcount mismatch for {:?} ({} != {} s {})",
+                    frame, c, count, s
+                );
+            }
         }
+        println!(
+            "({} kernel-owned references were not counted)",
+            temporarily_taken_htbufs
+        );
     }
-    println!(
-        "({} kernel-owned references were not counted)",
-        temporarily_taken_htbufs
-    );
 
     println!("DEBUGGER END");
-    unsafe {
-        x86::bits64::rflags::clac();
-    }
 }
-#[cfg(any(target_arch = "aarch64", target_arch = "x86_64"))]
-use {crate::memory::Frame, hashbrown::HashMap};
 
-#[cfg(any(target_arch = "aarch64", target_arch = "x86_64"))]
 pub unsafe fn check_consistency(
     addr_space: &mut crate::context::memory::AddrSpace,
     new_as: bool,
@@ -369,8 +230,7 @@ pub unsafe fn check_consistency(
 ) {
     use crate::{
         context::memory::{PageSpan, Provider},
-        memory::{get_page_info, RefCount},
-        paging::*,
+        paging::Page,
     };
 
     let p4 = addr_space.table.utable.table();
@@ -480,3 +340,219 @@ pub unsafe fn check_consistency(
     }*/
     println!("Consistency appears correct");
 }
+
+/// `Ctrl-\`, which followed by `d` enters the monitor.
+const SYSRQ: u8 = 0x1c;
+/// Most bytes of memory dumped by one command.
+const MAX_DUMP_LEN: usize = 4096;
+
+/// Set after [`SYSRQ`] was received, until the next byte.
+static SYSRQ_PENDING: AtomicBool = AtomicBool::new(false);
+/// Set once the monitor was requested, until a CPU enters it.
+static REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// Called for every byte received on the serial console, passing the bytes that are not part of
+/// a request for the monitor on to `forward`. A pending [`SYSRQ`] is forwarded along with the
+/// next byte, unless that is `d`, or another [`SYSRQ`], which is only forwarded once.
+pub fn serial_input(c: u8, mut forward: impl FnMut(u8)) {
+    if SYSRQ_PENDING.swap(false, Ordering::Relaxed) {
+        match c {
+            b'd' => REQUESTED.store(true, Ordering::Release),
+            SYSRQ => forward(SYSRQ),
+            _ => {
+                forward(SYSRQ);
+                forward(c);
+            }
+        }
+    } else if c == SYSRQ {
+        SYSRQ_PENDING.store(true, Ordering::Relaxed);
+    } else {
+        forward(c);
+    }
+}
+
+/// Enter the monitor if it was requested and lockdown allows it, called from the timer tick.
+pub fn poll() {
+    if REQUESTED.swap(false, Ordering::Acquire)
+        && lockdown::check(lockdown::Reason::KernelDebugger).is_ok()
+    {
+        unsafe { monitor() }
+    }
+}
+
+/// Read a line from the serial console into `buf`, echoing it, and return its length.
+fn read_line(buf: &mut [u8]) -> usize {
+    let mut len = 0;
+    loop {
+        let Some(c) = InterruptStack::poll_input() else {
+            core::hint::spin_loop();
+            continue;
+        };
+        match c {
+            b'\r' | b'\n' => {
+                println!();
+                return len;
+            }
+            // Backspace and delete
+            0x08 | 0x7f => {
+                if len > 0 {
+                    len -= 1;
+                    print!("\x08 \x08");
+                }
+            }
+            b' '..=b'~' if len < buf.len() => {
+                buf[len] = c;
+                len += 1;
+                print!("{}", c as char);
+            }
+            _ => (),
+        }
+    }
+}
+
+fn parse_number(arg: Option<&str>) -> Option<usize> {
+    let arg = arg?;
+    match arg.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16).ok(),
+        None => arg.parse().ok(),
+    }
+}
+
+/// The context whose address is `addr`, as printed by `ps`.
+fn find_context(addr: usize) -> Option<Arc<RwSpinlock<Context>>> {
+    crate::context::contexts()
+        .iter()
+        .find(|context_lock| Arc::as_ptr(&context_lock.0) as usize == addr)
+        .map(|context_lock| Arc::clone(&context_lock.0))
+}
+
+/// Translate `addr` through the page tables of `space`, or the kernel page tables, to its address
+/// in the linear mapping of physical memory.
+fn translate(space: Option<&AddrSpace>, addr: usize) -> Option<usize> {
+    let page = addr & !(PAGE_SIZE - 1);
+    let phys = match space {
+        Some(space) => huge_page::translate(&space.table.utable, VirtualAddress::new(page)),
+        None => KernelMapper::lock()
+            .translate(VirtualAddress::new(page))
+            .map(|(phys, _)| phys),
+    }?;
+    Some(unsafe { RmmA::phys_to_virt(phys) }.data() + (addr - page))
+}
+
+/// Print `len` bytes at `addr`, in the address space `space` or the kernel.
+fn dump_memory(space: Option<&AddrSpace>, addr: usize, len: usize) {
+    const W: usize = InterruptStack::ADDR_WIDTH;
+
+    for line in (0..len.min(MAX_DUMP_LEN)).step_by(16) {
+        let Some(line_addr) = addr.checked_add(line) else {
+            break;
+        };
+        print!("{:>0w$x}:", line_addr, w = W);
+        for i in 0..16.min(len - line) {
+            match line_addr
+                .checked_add(i)
+                .and_then(|byte_addr| translate(space, byte_addr))
+            {
+                Some(virt) => print!(" {:02x}", unsafe { (virt as *const u8).read_volatile() }),
+                None => print!(" ??"),
+            }
+        }
+        println!();
+    }
+}
+
+fn print_frame_stats() {
+    println!(
+        "frames: {} total, {} used, {} free",
+        crate::memory::total_frames(),
+        crate::memory::used_frames(),
+        crate::memory::free_frames()
+    );
+    let stats = crate::memory::free_stats();
+    for (order, (free, failures)) in stats
+        .free_blocks
+        .iter()
+        .zip(stats.alloc_failures.iter())
+        .enumerate()
+    {
+        println!(
+            "    order {:>2}: {:>8} free, {:>8} failed allocations",
+            order, free, failures
+        );
+    }
+}
+
+const HELP: &str = "\
+help                    show this help
+ps                      list contexts
+dump [context]          dump all contexts, or one by the address shown by ps
+kmem <addr> [len]       read kernel memory
+umem <context> <addr> [len]
+                        read user memory of a context
+frames                  show frame allocator statistics
+c, continue, resume     leave the monitor";
+
+/// The interactive monitor, reading commands until told to resume.
+unsafe fn monitor() {
+    println!();
+    println!(
+        "DEBUGGER MONITOR on CPU {}, type help for commands",
+        crate::cpu_id()
+    );
+
+    let mut buf = [0_u8; 128];
+    loop {
+        print!("debug> ");
+        let len = read_line(&mut buf);
+        let Ok(line) = str::from_utf8(&buf[..len]) else {
+            continue;
+        };
+        let mut args = line.split_whitespace();
+        match args.next() {
+            None => (),
+            Some("help") => println!("{}", HELP),
+            Some("ps") => {
+                for context_lock in crate::context::contexts().iter() {
+                    let context = context_lock.0.read();
+                    println!(
+                        "{:p}: {:?} cpu {:?} {}",
+                        Arc::as_ptr(&context_lock.0),
+                        context.status,
+                        context.cpu_id,
+                        context.name
+                    );
+                }
+            }
+            Some("dump") => match args.next() {
+                None => debugger(None),
+                arg => match parse_number(arg).and_then(find_context) {
+                    Some(context_lock) => debugger(Some(Arc::as_ptr(&context_lock))),
+                    None => println!("no such context"),
+                },
+            },
+            Some("kmem") => match parse_number(args.next()) {
+                Some(addr) => dump_memory(None, addr, parse_number(args.next()).unwrap_or(64)),
+                None => println!("usage: kmem <addr> [len]"),
+            },
+            Some("umem") => {
+                let context = parse_number(args.next()).and_then(find_context);
+                let addr = parse_number(args.next());
+                let len = parse_number(args.next()).unwrap_or(64);
+                match (context, addr) {
+                    (Some(context_lock), Some(addr)) => {
+                        let space = context_lock.read().addr_space.clone();
+                        match space {
+                            Some(space) => dump_memory(Some(&*space.acquire_read()), addr, len),
+                            None => println!("context has no address space"),
+                        }
+                    }
+                    _ => println!("usage: umem <context> <addr> [len]"),
+                }
+            }
+            Some("frames") => print_frame_stats(),
+            Some("c" | "continue" | "resume") => break,
+            Some(command) => println!("unknown command {}, type help for commands", command),
+        }
+    }
+    println!("DEBUGGER RESUME");
+}
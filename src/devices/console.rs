@@ -0,0 +1,90 @@
+//! # Kernel console
+//!
+//! Kernel output, and what userspace writes to the `debug:` scheme, goes to every enabled output
+//! of the console at once: the framebuffer of the `graphical_debug` feature, and the serial-like
+//! debug ports of the `*_debug` features and the virtio console, next to the kernel log. Outputs can be disabled and
+//! enabled again at runtime by writing `disable <output>` or `enable <output>` to `sys:console`,
+//! for example to stop drawing over the framebuffer once a display driver took it over.
+//!
+//! The framebuffer keeps a scrollback of what was written to it, which can be paged through with
+//! [`scroll`], by writing `scroll <rows>` or `scroll end` to `sys:console`, or by the debugger
+//! after a panic, as nothing else draws to it anymore.
+
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use crate::syscall::error::{Error, Result, EINVAL, ENODEV};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Output {
+    Display,
+    /// The serial ports, and the other debug ports where available.
+    Serial,
+}
+
+/// The outputs and their names in `sys:console`.
+pub const OUTPUTS: &[(Output, &str)] = &[(Output::Display, "display"), (Output::Serial, "serial")];
+
+/// Bits of the disabled outputs, so that all are enabled from the start.
+static DISABLED: AtomicU32 = AtomicU32::new(0);
+
+impl Output {
+    pub fn from_name(name: &str) -> Option<Self> {
+        OUTPUTS
+            .iter()
+            .find(|(_, output_name)| *output_name == name)
+            .map(|&(output, _)| output)
+    }
+    fn bit(self) -> u32 {
+        1 << self as u32
+    }
+}
+
+pub fn enabled(output: Output) -> bool {
+    DISABLED.load(Ordering::Relaxed) & output.bit() == 0
+}
+
+pub fn set_enabled(output: Output, enabled: bool) {
+    if enabled {
+        DISABLED.fetch_and(!output.bit(), Ordering::Relaxed);
+    } else {
+        DISABLED.fetch_or(output.bit(), Ordering::Relaxed);
+    }
+}
+
+/// Enable every output, so that a panic is seen wherever possible.
+pub fn enable_all() {
+    DISABLED.store(0, Ordering::Relaxed);
+}
+
+/// Scroll the framebuffer back by `rows` rows from the most recent output, or forward if
+/// negative, returning how far back it is now. It stays there while new output is recorded, until
+/// it is scrolled back down to 0.
+pub fn scroll(rows: isize) -> Result<usize> {
+    #[cfg(feature = "graphical_debug")]
+    if let Some(ref mut display) = *super::graphical_debug::DEBUG_DISPLAY.lock() {
+        return display.scroll_view(rows).ok_or(Error::new(ENODEV));
+    }
+    let _ = rows;
+    Err(Error::new(ENODEV))
+}
+
+/// Handle a command written to `sys:console`.
+pub fn command(command: &str) -> Result<()> {
+    let (action, arg) = command.trim().split_once(' ').ok_or(Error::new(EINVAL))?;
+    let arg = arg.trim();
+    match action {
+        "enable" | "disable" => {
+            let output = Output::from_name(arg).ok_or(Error::new(EINVAL))?;
+            set_enabled(output, action == "enable");
+        }
+        "scroll" => {
+            let rows = match arg {
+                "end" => isize::MIN,
+                rows => rows.parse().map_err(|_| Error::new(EINVAL))?,
+            };
+            scroll(rows)?;
+        }
+        _ => return Err(Error::new(EINVAL)),
+    }
+    Ok(())
+}
@@ -1,15 +1,23 @@
+use alloc::collections::VecDeque;
 use core::{cmp, ptr};
 
 use super::Display;
 
 static FONT: &[u8] = include_bytes!("../../../res/unifont.font");
 
+/// Bytes of output kept to scroll back through.
+const SCROLLBACK_SIZE: usize = 256 * 1024;
+
 pub struct DebugDisplay {
-    pub(super) display: Display,
+    display: Display,
     x: usize,
     y: usize,
     w: usize,
     h: usize,
+    /// The most recent output, once the heap is initialized.
+    scrollback: Option<VecDeque<u8>>,
+    /// Rows scrolled back from the most recent output, during which nothing new is drawn.
+    view: usize,
 }
 
 impl DebugDisplay {
@@ -22,9 +30,16 @@ impl DebugDisplay {
             y: 0,
             w,
             h,
+            scrollback: None,
+            view: 0,
         }
     }
 
+    pub(super) fn heap_init(&mut self) {
+        self.display.heap_init();
+        self.scrollback = Some(VecDeque::with_capacity(SCROLLBACK_SIZE));
+    }
+
     fn write_char(&mut self, c: char) {
         if self.x >= self.w || c == '\n' {
             self.x = 0;
@@ -57,11 +72,91 @@ impl DebugDisplay {
     }
 
     pub fn write(&mut self, buf: &[u8]) {
+        if let Some(ref mut scrollback) = self.scrollback {
+            for &b in buf {
+                if scrollback.len() >= SCROLLBACK_SIZE {
+                    scrollback.pop_front();
+                }
+                scrollback.push_back(b);
+            }
+        }
+        if self.view != 0 {
+            return;
+        }
         for &b in buf {
             self.write_char(b as char);
         }
     }
 
+    /// Move the view `rows` rows further back, or forward if negative, and redraw it from the
+    /// scrollback. Returns how far back the view is, or `None` without scrollback.
+    pub fn scroll_view(&mut self, rows: isize) -> Option<usize> {
+        let scrollback = self.scrollback.take()?;
+
+        // Rows are wrapped like in write_char, with the last one always being the current row.
+        let mut total = 1;
+        let mut col = 0;
+        for &b in scrollback.iter() {
+            if col >= self.w || b == b'\n' {
+                total += 1;
+                col = 0;
+            }
+            if b != b'\n' {
+                col += 1;
+            }
+        }
+
+        let max_view = total.saturating_sub(self.h);
+        let view = self.view.saturating_add_signed(rows).min(max_view);
+        if view != self.view {
+            self.view = view;
+            self.redraw(&scrollback, total - view);
+        }
+
+        self.scrollback = Some(scrollback);
+        Some(self.view)
+    }
+
+    /// Draw the screen of rows ending before row `end` of `scrollback`.
+    fn redraw(&mut self, scrollback: &VecDeque<u8>, end: usize) {
+        let first = end.saturating_sub(self.h);
+        unsafe {
+            ptr::write_bytes(
+                self.display.data_mut(),
+                0,
+                self.display.stride * self.display.height,
+            );
+        }
+
+        let mut row = 0;
+        let mut col = 0;
+        for &b in scrollback.iter() {
+            if col >= self.w || b == b'\n' {
+                row += 1;
+                col = 0;
+            }
+            if row >= end {
+                break;
+            }
+            if b != b'\n' {
+                if row >= first {
+                    self.char(col * 8, (row - first) * 16, b as char, 0xFFFFFF);
+                }
+                col += 1;
+            }
+        }
+        if self.view == 0 {
+            // Back at the most recent output, which continues from here.
+            self.x = col;
+            self.y = row - first;
+        }
+
+        unsafe {
+            self.display
+                .sync(0, 0, self.display.width, self.display.height);
+        }
+    }
+
     /// Draw a character
     fn char(&mut self, x: usize, y: usize, character: char, color: u32) {
         if x + 8 <= self.display.width && y + 16 <= self.display.height {
@@ -70,7 +70,7 @@ pub fn init(env: &[u8]) {
 #[allow(unused)]
 pub fn init_heap() {
     if let Some(debug_display) = &mut *DEBUG_DISPLAY.lock() {
-        debug_display.display.heap_init();
+        debug_display.heap_init();
     }
 }
 
@@ -1,3 +1,9 @@
+pub mod console;
 #[cfg(feature = "graphical_debug")]
 pub mod graphical_debug;
 pub mod uart_16550;
+#[cfg(all(
+    feature = "virtio",
+    any(target_arch = "aarch64", target_arch = "x86_64")
+))]
+pub mod virtio;
@@ -0,0 +1,72 @@
+//! virtio-console, written to like the serial ports, see [`crate::devices::console`]. Only the
+//! first port is used, and only to write to.
+
+use alloc::boxed::Box;
+use spin::Mutex;
+
+use crate::{
+    memory::allocate_frame,
+    paging::{PhysicalAddress, RmmA, RmmArch, PAGE_SIZE},
+};
+
+use super::{
+    driver_ok, fail, negotiate,
+    queue::{Buffer, Virtqueue},
+    Transport,
+};
+
+/// The transmit queue of the first port, after its receive queue.
+const TRANSMITQ: u16 = 1;
+
+pub struct Console {
+    transport: Box<dyn Transport>,
+    transmit: Virtqueue,
+    /// Physical address of the page written buffers are copied to.
+    buffer: usize,
+}
+
+impl Console {
+    pub fn write(&mut self, buf: &[u8]) {
+        let page = unsafe { RmmA::phys_to_virt(PhysicalAddress::new(self.buffer)).data() };
+        for chunk in buf.chunks(PAGE_SIZE) {
+            unsafe {
+                (page as *mut u8).copy_from_nonoverlapping(chunk.as_ptr(), chunk.len());
+            }
+            let buffer = Buffer {
+                phys: self.buffer,
+                len: chunk.len(),
+                writable: false,
+            };
+            if self
+                .transmit
+                .transfer(&mut *self.transport, &[buffer])
+                .is_none()
+            {
+                return;
+            }
+        }
+    }
+}
+
+pub static VIRTIO_CONSOLE: Mutex<Option<Console>> = Mutex::new(None);
+
+pub fn init(mut transport: Box<dyn Transport>) {
+    if VIRTIO_CONSOLE.lock().is_some() || !negotiate(&mut *transport, 0) {
+        return;
+    }
+    let (Some(transmit), Some(buffer)) =
+        (Virtqueue::new(&mut *transport, TRANSMITQ), allocate_frame())
+    else {
+        log::warn!("virtio-console: failed to set up the transmit queue");
+        fail(&mut *transport);
+        return;
+    };
+    driver_ok(&mut *transport);
+
+    *VIRTIO_CONSOLE.lock() = Some(Console {
+        transport,
+        transmit,
+        buffer: buffer.base().data(),
+    });
+    log::info!("virtio-console used as a debug output");
+}
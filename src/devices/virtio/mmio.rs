@@ -0,0 +1,137 @@
+//! The virtio-mmio transport, both the legacy version 1, which QEMU uses by default, and version 2.
+
+use alloc::boxed::Box;
+use core::ptr::{read_volatile, write_volatile};
+
+use fdt::Fdt;
+
+use crate::paging::PAGE_SIZE;
+
+use super::{attach, Rings, Transport};
+
+const MAGIC: u32 = 0x7472_6976;
+
+const REG_MAGIC: usize = 0x000;
+const REG_VERSION: usize = 0x004;
+const REG_DEVICE_ID: usize = 0x008;
+const REG_DEVICE_FEATURES: usize = 0x010;
+const REG_DEVICE_FEATURES_SEL: usize = 0x014;
+const REG_DRIVER_FEATURES: usize = 0x020;
+const REG_DRIVER_FEATURES_SEL: usize = 0x024;
+const REG_GUEST_PAGE_SIZE: usize = 0x028;
+const REG_QUEUE_SEL: usize = 0x030;
+const REG_QUEUE_NUM_MAX: usize = 0x034;
+const REG_QUEUE_NUM: usize = 0x038;
+const REG_QUEUE_ALIGN: usize = 0x03c;
+const REG_QUEUE_PFN: usize = 0x040;
+const REG_QUEUE_READY: usize = 0x044;
+const REG_QUEUE_NOTIFY: usize = 0x050;
+const REG_STATUS: usize = 0x070;
+const REG_QUEUE_DESC: usize = 0x080;
+const REG_QUEUE_DRIVER: usize = 0x090;
+const REG_QUEUE_DEVICE: usize = 0x0a0;
+
+struct Mmio {
+    /// Kernel address of the registers.
+    base: usize,
+    version: u32,
+}
+
+impl Mmio {
+    fn read(&self, reg: usize) -> u32 {
+        unsafe { read_volatile((self.base + reg) as *const u32) }
+    }
+
+    fn write(&mut self, reg: usize, value: u32) {
+        unsafe { write_volatile((self.base + reg) as *mut u32, value) }
+    }
+
+    /// Write a 64-bit value to a pair of registers, the low half first.
+    fn write64(&mut self, reg: usize, value: u64) {
+        self.write(reg, value as u32);
+        self.write(reg + 4, (value >> 32) as u32);
+    }
+}
+
+impl Transport for Mmio {
+    fn status(&mut self) -> u8 {
+        self.read(REG_STATUS) as u8
+    }
+    fn set_status(&mut self, status: u8) {
+        self.write(REG_STATUS, status.into());
+    }
+    fn device_features(&mut self) -> u64 {
+        self.write(REG_DEVICE_FEATURES_SEL, 0);
+        let low = self.read(REG_DEVICE_FEATURES);
+        self.write(REG_DEVICE_FEATURES_SEL, 1);
+        let high = self.read(REG_DEVICE_FEATURES);
+        u64::from(low) | u64::from(high) << 32
+    }
+    fn set_driver_features(&mut self, features: u64) {
+        self.write(REG_DRIVER_FEATURES_SEL, 0);
+        self.write(REG_DRIVER_FEATURES, features as u32);
+        self.write(REG_DRIVER_FEATURES_SEL, 1);
+        self.write(REG_DRIVER_FEATURES, (features >> 32) as u32);
+    }
+    fn is_legacy(&self) -> bool {
+        self.version == 1
+    }
+    fn queue_size(&mut self, queue: u16, wanted: u16) -> u16 {
+        self.write(REG_QUEUE_SEL, queue.into());
+        let max = self.read(REG_QUEUE_NUM_MAX);
+        (max as u16).min(wanted)
+    }
+    fn enable_queue(&mut self, queue: u16, size: u16, rings: Rings) {
+        self.write(REG_QUEUE_SEL, queue.into());
+        self.write(REG_QUEUE_NUM, size.into());
+        if self.is_legacy() {
+            self.write(REG_GUEST_PAGE_SIZE, PAGE_SIZE as u32);
+            self.write(REG_QUEUE_ALIGN, PAGE_SIZE as u32);
+            self.write(REG_QUEUE_PFN, (rings.descriptors / PAGE_SIZE) as u32);
+        } else {
+            self.write64(REG_QUEUE_DESC, rings.descriptors as u64);
+            self.write64(REG_QUEUE_DRIVER, rings.available as u64);
+            self.write64(REG_QUEUE_DEVICE, rings.used as u64);
+            self.write(REG_QUEUE_READY, 1);
+        }
+    }
+    fn notify(&mut self, queue: u16) {
+        self.write(REG_QUEUE_NOTIFY, queue.into());
+    }
+}
+
+/// Attach drivers to the virtio-mmio devices in the devicetree. QEMU describes many more slots
+/// than devices, of which the empty ones have a device ID of zero.
+pub unsafe fn init(fdt: &Fdt) {
+    for node in fdt.all_nodes() {
+        if !node.compatible().map_or(false, |compatible| {
+            compatible.all().any(|c| c == "virtio,mmio")
+        }) {
+            continue;
+        }
+        let Some(reg) = node.reg().and_then(|mut reg| reg.next()) else {
+            continue;
+        };
+        let phys = reg.starting_address as usize;
+        let mut mmio = Mmio {
+            base: crate::PHYS_OFFSET + phys,
+            version: 0,
+        };
+        if mmio.read(REG_MAGIC) != MAGIC {
+            log::warn!("virtio-mmio at {:#x}: bad magic value", phys);
+            continue;
+        }
+        mmio.version = mmio.read(REG_VERSION);
+        let device_type = mmio.read(REG_DEVICE_ID);
+        if device_type == 0 || !matches!(mmio.version, 1 | 2) {
+            continue;
+        }
+        log::info!(
+            "virtio-mmio at {:#x}: version {}, device type {}",
+            phys,
+            mmio.version,
+            device_type
+        );
+        attach(device_type, Box::new(mmio));
+    }
+}
@@ -0,0 +1,104 @@
+//! # virtio
+//!
+//! Minimal drivers for the virtio devices a virtual machine needs before userspace drivers run:
+//! virtio-console, written to as one more debug output, and virtio-rng, which seeds the entropy
+//! pool. Devices are found on the virtio-mmio transport described by the devicetree on aarch64,
+//! and on PCI on x86_64, where both the legacy interface in I/O space and the modern one are
+//! supported.
+//!
+//! Each driver uses a single split virtqueue, and waits for every buffer it makes available to be
+//! used, so no interrupts are needed. The devices are left to userspace drivers otherwise.
+
+use alloc::boxed::Box;
+
+pub mod console;
+#[cfg(target_arch = "aarch64")]
+pub mod mmio;
+#[cfg(target_arch = "x86_64")]
+pub mod pci;
+mod queue;
+pub mod rng;
+
+const STATUS_ACKNOWLEDGE: u8 = 1;
+const STATUS_DRIVER: u8 = 2;
+const STATUS_DRIVER_OK: u8 = 4;
+const STATUS_FEATURES_OK: u8 = 8;
+const STATUS_FAILED: u8 = 128;
+
+/// Set by devices implementing the virtio 1.0 interface, which must be acknowledged.
+const F_VERSION_1: u64 = 1 << 32;
+
+const DEVICE_CONSOLE: u32 = 3;
+const DEVICE_ENTROPY: u32 = 4;
+
+/// Physical addresses of the parts of a virtqueue.
+#[derive(Clone, Copy, Debug)]
+pub struct Rings {
+    pub descriptors: usize,
+    pub available: usize,
+    pub used: usize,
+}
+
+/// Access to the registers of a device, through one of the transports.
+pub trait Transport: Send {
+    fn status(&mut self) -> u8;
+    fn set_status(&mut self, status: u8);
+    /// The features offered by the device. Legacy devices only have the lower 32.
+    fn device_features(&mut self) -> u64;
+    fn set_driver_features(&mut self, features: u64);
+    /// Whether the device only has the legacy interface, without `F_VERSION_1` and
+    /// `STATUS_FEATURES_OK`, and with the layout of virtqueues fixed.
+    fn is_legacy(&self) -> bool;
+    /// Select `queue`, and return the size it is used with, at most `wanted` unless the device
+    /// requires its own, or zero if there is no such queue.
+    fn queue_size(&mut self, queue: u16, wanted: u16) -> u16;
+    /// Tell the device where the selected `queue` of `size` entries is, and enable it.
+    fn enable_queue(&mut self, queue: u16, size: u16, rings: Rings);
+    /// Tell the device new buffers are available in `queue`.
+    fn notify(&mut self, queue: u16);
+}
+
+/// Reset the device and negotiate `features`, of which the device may support only some. The
+/// driver then sets up its queues, and calls [`driver_ok`].
+fn negotiate(transport: &mut dyn Transport, features: u64) -> bool {
+    transport.set_status(0);
+    transport.set_status(STATUS_ACKNOWLEDGE);
+    transport.set_status(STATUS_ACKNOWLEDGE | STATUS_DRIVER);
+
+    let offered = transport.device_features();
+    if transport.is_legacy() {
+        transport.set_driver_features(offered & features);
+        return true;
+    }
+    if offered & F_VERSION_1 == 0 {
+        transport.set_status(STATUS_FAILED);
+        return false;
+    }
+    transport.set_driver_features(offered & (features | F_VERSION_1));
+    transport.set_status(STATUS_ACKNOWLEDGE | STATUS_DRIVER | STATUS_FEATURES_OK);
+    if transport.status() & STATUS_FEATURES_OK == 0 {
+        transport.set_status(STATUS_FAILED);
+        return false;
+    }
+    true
+}
+
+fn driver_ok(transport: &mut dyn Transport) {
+    let status = transport.status();
+    transport.set_status(status | STATUS_DRIVER_OK);
+}
+
+/// Tell the device initializing it failed.
+fn fail(transport: &mut dyn Transport) {
+    let status = transport.status();
+    transport.set_status(status | STATUS_FAILED);
+}
+
+/// Start the driver for a device of `device_type` found by a transport, if there is one.
+fn attach(device_type: u32, transport: Box<dyn Transport>) {
+    match device_type {
+        DEVICE_CONSOLE => console::init(transport),
+        DEVICE_ENTROPY => rng::init(transport),
+        _ => (),
+    }
+}
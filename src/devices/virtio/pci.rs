@@ -0,0 +1,352 @@
+//! The virtio PCI transport, through the modern interface in memory space where the device has
+//! one, and otherwise the legacy interface in I/O space of transitional devices.
+//!
+//! Devices are found by scanning the configuration space through the legacy I/O ports, which every
+//! PC and QEMU machine has. Only the devices there is a driver for are enabled.
+
+use alloc::boxed::Box;
+use core::ptr::{read_volatile, write_volatile};
+
+use crate::{
+    memory::map_device_memory,
+    paging::{PhysicalAddress, PAGE_SIZE},
+    syscall::io::{Io, Pio},
+};
+
+use super::{attach, Rings, Transport, DEVICE_CONSOLE, DEVICE_ENTROPY};
+
+const CONFIG_ADDRESS: u16 = 0xCF8;
+const CONFIG_DATA: u16 = 0xCFC;
+
+const VENDOR_VIRTIO: u16 = 0x1AF4;
+/// Device IDs of transitional devices, which have the legacy interface.
+const TRANSITIONAL_IDS: core::ops::RangeInclusive<u16> = 0x1000..=0x103F;
+/// Device IDs of modern devices, which are 0x1040 plus the device type.
+const MODERN_IDS: core::ops::RangeInclusive<u16> = 0x1040..=0x107F;
+
+const COMMAND_IO: u32 = 1 << 0;
+const COMMAND_MEMORY: u32 = 1 << 1;
+const COMMAND_BUS_MASTER: u32 = 1 << 2;
+const STATUS_CAPABILITIES: u32 = 1 << 20;
+
+const CAP_VENDOR: u32 = 0x09;
+const CAP_COMMON_CFG: u32 = 1;
+const CAP_NOTIFY_CFG: u32 = 2;
+
+// Registers of the modern common configuration structure.
+const COMMON_DEVICE_FEATURE_SELECT: usize = 0x00;
+const COMMON_DEVICE_FEATURE: usize = 0x04;
+const COMMON_DRIVER_FEATURE_SELECT: usize = 0x08;
+const COMMON_DRIVER_FEATURE: usize = 0x0C;
+const COMMON_DEVICE_STATUS: usize = 0x14;
+const COMMON_QUEUE_SELECT: usize = 0x16;
+const COMMON_QUEUE_SIZE: usize = 0x18;
+const COMMON_QUEUE_ENABLE: usize = 0x1C;
+const COMMON_QUEUE_NOTIFY_OFF: usize = 0x1E;
+const COMMON_QUEUE_DESC: usize = 0x20;
+const COMMON_QUEUE_DRIVER: usize = 0x28;
+const COMMON_QUEUE_DEVICE: usize = 0x30;
+
+// Registers of the legacy interface, in the I/O space of BAR 0.
+const LEGACY_DEVICE_FEATURES: u16 = 0x00;
+const LEGACY_DRIVER_FEATURES: u16 = 0x04;
+const LEGACY_QUEUE_PFN: u16 = 0x08;
+const LEGACY_QUEUE_SIZE: u16 = 0x0C;
+const LEGACY_QUEUE_SELECT: u16 = 0x0E;
+const LEGACY_QUEUE_NOTIFY: u16 = 0x10;
+const LEGACY_DEVICE_STATUS: u16 = 0x12;
+
+#[derive(Clone, Copy)]
+struct PciAddress {
+    bus: u8,
+    device: u8,
+    function: u8,
+}
+
+impl PciAddress {
+    fn select(&self, offset: u8) {
+        Pio::<u32>::new(CONFIG_ADDRESS).write(
+            1 << 31
+                | u32::from(self.bus) << 16
+                | u32::from(self.device) << 11
+                | u32::from(self.function) << 8
+                | u32::from(offset & 0xFC),
+        );
+    }
+
+    fn read(&self, offset: u8) -> u32 {
+        self.select(offset);
+        Pio::<u32>::new(CONFIG_DATA).read()
+    }
+
+    fn write(&self, offset: u8, value: u32) {
+        self.select(offset);
+        Pio::<u32>::new(CONFIG_DATA).write(value);
+    }
+
+    /// The physical address and size of memory BAR `bar`.
+    fn memory_bar(&self, bar: u8) -> Option<(usize, usize)> {
+        if bar > 5 {
+            return None;
+        }
+        let offset = 0x10 + 4 * bar;
+        let low = self.read(offset);
+        if low & 1 != 0 {
+            return None;
+        }
+        let is_64 = low & 0b110 == 0b100;
+        let high = if is_64 { self.read(offset + 4) } else { 0 };
+
+        // Size the BAR with decoding off, by writing all ones and seeing which bits stick.
+        let command = self.read(0x04) & 0xFFFF;
+        self.write(0x04, command & !(COMMAND_IO | COMMAND_MEMORY));
+        self.write(offset, u32::MAX);
+        let mut mask = u64::from(self.read(offset) & !0xF);
+        self.write(offset, low);
+        if is_64 {
+            self.write(offset + 4, u32::MAX);
+            mask |= u64::from(self.read(offset + 4)) << 32;
+            self.write(offset + 4, high);
+        } else {
+            mask |= 0xFFFF_FFFF_0000_0000;
+        }
+        self.write(0x04, command);
+
+        let base = (u64::from(high) << 32 | u64::from(low & !0xF)) as usize;
+        let size = (!mask).wrapping_add(1) as usize;
+        (base != 0 && size != 0).then_some((base, size))
+    }
+}
+
+/// A structure of the modern interface, by the BAR it is in and its offset in it.
+#[derive(Clone, Copy)]
+struct Region {
+    bar: u8,
+    offset: usize,
+}
+
+struct Modern {
+    /// Kernel address of the common configuration structure.
+    common: usize,
+    /// Kernel address of the notification structure.
+    notify: usize,
+    notify_multiplier: usize,
+}
+
+impl Modern {
+    fn read<T>(&self, reg: usize) -> T {
+        unsafe { read_volatile((self.common + reg) as *const T) }
+    }
+
+    fn write<T>(&mut self, reg: usize, value: T) {
+        unsafe { write_volatile((self.common + reg) as *mut T, value) }
+    }
+
+    /// Write a 64-bit register as two 32-bit halves, the low one first.
+    fn write64(&mut self, reg: usize, value: u64) {
+        self.write(reg, value as u32);
+        self.write(reg + 4, (value >> 32) as u32);
+    }
+}
+
+impl Transport for Modern {
+    fn status(&mut self) -> u8 {
+        self.read(COMMON_DEVICE_STATUS)
+    }
+    fn set_status(&mut self, status: u8) {
+        self.write(COMMON_DEVICE_STATUS, status);
+    }
+    fn device_features(&mut self) -> u64 {
+        self.write(COMMON_DEVICE_FEATURE_SELECT, 0_u32);
+        let low: u32 = self.read(COMMON_DEVICE_FEATURE);
+        self.write(COMMON_DEVICE_FEATURE_SELECT, 1_u32);
+        let high: u32 = self.read(COMMON_DEVICE_FEATURE);
+        u64::from(low) | u64::from(high) << 32
+    }
+    fn set_driver_features(&mut self, features: u64) {
+        self.write(COMMON_DRIVER_FEATURE_SELECT, 0_u32);
+        self.write(COMMON_DRIVER_FEATURE, features as u32);
+        self.write(COMMON_DRIVER_FEATURE_SELECT, 1_u32);
+        self.write(COMMON_DRIVER_FEATURE, (features >> 32) as u32);
+    }
+    fn is_legacy(&self) -> bool {
+        false
+    }
+    fn queue_size(&mut self, queue: u16, wanted: u16) -> u16 {
+        self.write(COMMON_QUEUE_SELECT, queue);
+        self.read::<u16>(COMMON_QUEUE_SIZE).min(wanted)
+    }
+    fn enable_queue(&mut self, queue: u16, size: u16, rings: Rings) {
+        self.write(COMMON_QUEUE_SELECT, queue);
+        self.write(COMMON_QUEUE_SIZE, size);
+        self.write64(COMMON_QUEUE_DESC, rings.descriptors as u64);
+        self.write64(COMMON_QUEUE_DRIVER, rings.available as u64);
+        self.write64(COMMON_QUEUE_DEVICE, rings.used as u64);
+        self.write(COMMON_QUEUE_ENABLE, 1_u16);
+    }
+    fn notify(&mut self, queue: u16) {
+        self.write(COMMON_QUEUE_SELECT, queue);
+        let offset = usize::from(self.read::<u16>(COMMON_QUEUE_NOTIFY_OFF));
+        unsafe {
+            write_volatile(
+                (self.notify + offset * self.notify_multiplier) as *mut u16,
+                queue,
+            );
+        }
+    }
+}
+
+struct Legacy {
+    port: u16,
+}
+
+impl Transport for Legacy {
+    fn status(&mut self) -> u8 {
+        Pio::<u8>::new(self.port + LEGACY_DEVICE_STATUS).read()
+    }
+    fn set_status(&mut self, status: u8) {
+        Pio::<u8>::new(self.port + LEGACY_DEVICE_STATUS).write(status);
+    }
+    fn device_features(&mut self) -> u64 {
+        Pio::<u32>::new(self.port + LEGACY_DEVICE_FEATURES)
+            .read()
+            .into()
+    }
+    fn set_driver_features(&mut self, features: u64) {
+        Pio::<u32>::new(self.port + LEGACY_DRIVER_FEATURES).write(features as u32);
+    }
+    fn is_legacy(&self) -> bool {
+        true
+    }
+    fn queue_size(&mut self, queue: u16, _wanted: u16) -> u16 {
+        Pio::<u16>::new(self.port + LEGACY_QUEUE_SELECT).write(queue);
+        Pio::<u16>::new(self.port + LEGACY_QUEUE_SIZE).read()
+    }
+    fn enable_queue(&mut self, queue: u16, _size: u16, rings: Rings) {
+        Pio::<u16>::new(self.port + LEGACY_QUEUE_SELECT).write(queue);
+        Pio::<u32>::new(self.port + LEGACY_QUEUE_PFN).write((rings.descriptors / PAGE_SIZE) as u32);
+    }
+    fn notify(&mut self, queue: u16) {
+        Pio::<u16>::new(self.port + LEGACY_QUEUE_NOTIFY).write(queue);
+    }
+}
+
+/// Find the modern interface of the device at `address`, and map it.
+unsafe fn modern(address: PciAddress) -> Option<Modern> {
+    if address.read(0x04) & STATUS_CAPABILITIES == 0 {
+        return None;
+    }
+    let (mut common, mut notify) = (None, None);
+    let mut cap = (address.read(0x34) & 0xFC) as u8;
+    while cap != 0 {
+        let header = address.read(cap);
+        if header & 0xFF == CAP_VENDOR {
+            let region = Region {
+                bar: address.read(cap + 4) as u8,
+                offset: address.read(cap + 8) as usize,
+            };
+            match header >> 24 {
+                CAP_COMMON_CFG if common.is_none() => common = Some(region),
+                CAP_NOTIFY_CFG if notify.is_none() => {
+                    notify = Some((region, address.read(cap + 16) as usize));
+                }
+                _ => (),
+            }
+        }
+        cap = ((header >> 8) & 0xFC) as u8;
+    }
+    let (common, (notify, notify_multiplier)) = (common?, notify?);
+
+    // Both structures are usually in the same BAR, which must only be mapped once.
+    let mut mapped = [None; 6];
+    let mut map = |region: Region| -> Option<usize> {
+        let bar = usize::from(region.bar);
+        if mapped.get(bar)?.is_none() {
+            let (base, size) = address.memory_bar(region.bar)?;
+            mapped[bar] = Some(map_device_memory(PhysicalAddress::new(base), size).data());
+        }
+        Some(mapped[bar]? + region.offset)
+    };
+    Some(Modern {
+        common: map(common)?,
+        notify: map(notify)?,
+        notify_multiplier,
+    })
+}
+
+unsafe fn probe(address: PciAddress, device_id: u16) {
+    let device_type = if TRANSITIONAL_IDS.contains(&device_id) {
+        address.read(0x2C) >> 16
+    } else if MODERN_IDS.contains(&device_id) {
+        u32::from(device_id - MODERN_IDS.start())
+    } else {
+        return;
+    };
+    if !matches!(device_type, DEVICE_CONSOLE | DEVICE_ENTROPY) {
+        return;
+    }
+
+    let transport: Box<dyn Transport> = if let Some(modern) = modern(address) {
+        Box::new(modern)
+    } else if TRANSITIONAL_IDS.contains(&device_id) && address.read(0x10) & 1 == 1 {
+        Box::new(Legacy {
+            port: (address.read(0x10) & 0xFFFC) as u16,
+        })
+    } else {
+        log::warn!(
+            "virtio-pci at {:02x}:{:02x}.{}: no usable interface",
+            address.bus,
+            address.device,
+            address.function
+        );
+        return;
+    };
+    let command = address.read(0x04) & 0xFFFF;
+    address.write(
+        0x04,
+        command | COMMAND_IO | COMMAND_MEMORY | COMMAND_BUS_MASTER,
+    );
+
+    log::info!(
+        "virtio-pci at {:02x}:{:02x}.{}: {} interface, device type {}",
+        address.bus,
+        address.device,
+        address.function,
+        if transport.is_legacy() {
+            "legacy"
+        } else {
+            "modern"
+        },
+        device_type
+    );
+    attach(device_type, transport);
+}
+
+/// Attach drivers to the virtio devices on PCI.
+pub unsafe fn init() {
+    for bus in 0..=255 {
+        for device in 0..32 {
+            for function in 0..8 {
+                let address = PciAddress {
+                    bus,
+                    device,
+                    function,
+                };
+                let id = address.read(0x00);
+                if id & 0xFFFF == 0xFFFF {
+                    if function == 0 {
+                        break;
+                    }
+                    continue;
+                }
+                if id & 0xFFFF == u32::from(VENDOR_VIRTIO) {
+                    probe(address, (id >> 16) as u16);
+                }
+                // Only multi-function devices have functions past the first.
+                if function == 0 && address.read(0x0C) & (0x80 << 16) == 0 {
+                    break;
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,146 @@
+//! Split virtqueues, laid out the way legacy devices require, which modern devices accept as well.
+
+use core::{
+    hint, ptr,
+    sync::atomic::{fence, Ordering},
+};
+
+use crate::{
+    memory::allocate_p2frame,
+    paging::{PhysicalAddress, RmmA, RmmArch, PAGE_SIZE},
+};
+
+use super::{Rings, Transport};
+
+/// Queue size used unless the device requires its own, enough for the few buffers in flight.
+const QUEUE_SIZE: u16 = 16;
+
+const DESC_SIZE: usize = 16;
+const DESC_F_NEXT: u16 = 1;
+const DESC_F_WRITE: u16 = 2;
+
+/// Keeps the device from interrupting when it used a buffer, as the buffers are polled.
+const AVAIL_F_NO_INTERRUPT: u16 = 1;
+
+/// How many times to check whether the device used a buffer before giving up on it.
+const SPIN_LIMIT: usize = 100_000_000;
+
+/// A buffer made available to the device, by its physical address and length, and whether the
+/// device writes to it rather than reads from it.
+pub struct Buffer {
+    pub phys: usize,
+    pub len: usize,
+    pub writable: bool,
+}
+
+pub struct Virtqueue {
+    index: u16,
+    size: u16,
+    rings: Rings,
+    /// The index in the used ring the next used buffer will be at.
+    last_used: u16,
+    /// Set once the device did not use a buffer in time, as it may still write to it.
+    broken: bool,
+}
+
+impl Virtqueue {
+    /// Allocate queue `index` of the device, and enable it.
+    pub fn new(transport: &mut dyn Transport, index: u16) -> Option<Self> {
+        let size = transport.queue_size(index, QUEUE_SIZE);
+        if size == 0 {
+            return None;
+        }
+        let available_len = 2 * (3 + usize::from(size));
+        let used_offset =
+            (DESC_SIZE * usize::from(size) + available_len).next_multiple_of(PAGE_SIZE);
+        let used_len = 6 + 8 * usize::from(size);
+        let pages = (used_offset + used_len).div_ceil(PAGE_SIZE);
+        let frame = allocate_p2frame(pages.next_power_of_two().trailing_zeros())?;
+
+        let base = frame.base().data();
+        let rings = Rings {
+            descriptors: base,
+            available: base + DESC_SIZE * usize::from(size),
+            used: base + used_offset,
+        };
+        let queue = Self {
+            index,
+            size,
+            rings,
+            last_used: 0,
+            broken: false,
+        };
+        unsafe {
+            queue.available(0).write_volatile(AVAIL_F_NO_INTERRUPT);
+        }
+        transport.enable_queue(index, size, rings);
+        Some(queue)
+    }
+
+    fn virt(phys: usize) -> usize {
+        unsafe { RmmA::phys_to_virt(PhysicalAddress::new(phys)).data() }
+    }
+
+    /// The `i`th 16-bit field of the available ring: the flags, the index, then the ring.
+    fn available(&self, i: usize) -> *mut u16 {
+        (Self::virt(self.rings.available) as *mut u16).wrapping_add(i)
+    }
+
+    /// The `i`th 16-bit field of the used ring, of which only the flags and the index are.
+    fn used(&self, i: usize) -> *mut u16 {
+        (Self::virt(self.rings.used) as *mut u16).wrapping_add(i)
+    }
+
+    /// Make `buffers` available to the device as one chain, and wait until the device used them,
+    /// returning how many bytes it wrote, or None if it did not use them in time.
+    pub fn transfer(&mut self, transport: &mut dyn Transport, buffers: &[Buffer]) -> Option<u32> {
+        if self.broken || buffers.is_empty() || buffers.len() > usize::from(self.size) {
+            return None;
+        }
+
+        // Only one chain is in flight at a time, which starts at the first descriptor.
+        let descriptors = Self::virt(self.rings.descriptors) as *mut u8;
+        for (i, buffer) in buffers.iter().enumerate() {
+            let mut flags = 0;
+            if buffer.writable {
+                flags |= DESC_F_WRITE;
+            }
+            if i + 1 < buffers.len() {
+                flags |= DESC_F_NEXT;
+            }
+            unsafe {
+                let descriptor = descriptors.add(i * DESC_SIZE);
+                (descriptor as *mut u64).write_volatile(buffer.phys as u64);
+                (descriptor.add(8) as *mut u32).write_volatile(buffer.len as u32);
+                (descriptor.add(12) as *mut u16).write_volatile(flags);
+                (descriptor.add(14) as *mut u16).write_volatile(i as u16 + 1);
+            }
+        }
+
+        unsafe {
+            let index = self.available(1).read_volatile();
+            self.available(2 + usize::from(index % self.size))
+                .write_volatile(0);
+            // The device must see the descriptors before the new index.
+            fence(Ordering::SeqCst);
+            self.available(1).write_volatile(index.wrapping_add(1));
+            fence(Ordering::SeqCst);
+        }
+        transport.notify(self.index);
+
+        for _ in 0..SPIN_LIMIT {
+            if unsafe { self.used(1).read_volatile() } != self.last_used {
+                fence(Ordering::SeqCst);
+                let slot = usize::from(self.last_used % self.size);
+                // Each element of the used ring is the 32-bit head index, then the length.
+                let len =
+                    unsafe { ptr::read_volatile((self.used(2) as *const u32).add(2 * slot + 1)) };
+                self.last_used = self.last_used.wrapping_add(1);
+                return Some(len);
+            }
+            hint::spin_loop();
+        }
+        self.broken = true;
+        None
+    }
+}
@@ -0,0 +1,79 @@
+//! virtio-rng, seeding the entropy pool when it is found.
+
+use alloc::boxed::Box;
+
+use crate::{
+    entropy,
+    memory::allocate_frame,
+    paging::{PhysicalAddress, RmmA, RmmArch, PAGE_SIZE},
+    sync::Mutex,
+};
+
+use super::{
+    driver_ok, fail, negotiate,
+    queue::{Buffer, Virtqueue},
+    Transport,
+};
+
+const REQUESTQ: u16 = 0;
+
+/// Bytes to seed the entropy pool with.
+const SEED_SIZE: usize = 64;
+
+struct Rng {
+    transport: Box<dyn Transport>,
+    request: Virtqueue,
+    /// Physical address of the page the device writes to.
+    buffer: usize,
+}
+
+static VIRTIO_RNG: Mutex<Option<Rng>> = Mutex::new(None);
+
+/// Fill the start of `buf` with random bytes from the device, returning how many it provided.
+pub fn read(buf: &mut [u8]) -> usize {
+    let mut rng = VIRTIO_RNG.lock();
+    let Some(rng) = rng.as_mut() else {
+        return 0;
+    };
+    let len = buf.len().min(PAGE_SIZE);
+    let buffer = Buffer {
+        phys: rng.buffer,
+        len,
+        writable: true,
+    };
+    let Some(written) = rng.request.transfer(&mut *rng.transport, &[buffer]) else {
+        return 0;
+    };
+    let written = (written as usize).min(len);
+    unsafe {
+        let page = RmmA::phys_to_virt(PhysicalAddress::new(rng.buffer)).data();
+        buf.as_mut_ptr()
+            .copy_from_nonoverlapping(page as *const u8, written);
+    }
+    written
+}
+
+pub fn init(mut transport: Box<dyn Transport>) {
+    if VIRTIO_RNG.lock().is_some() || !negotiate(&mut *transport, 0) {
+        return;
+    }
+    let (Some(request), Some(buffer)) =
+        (Virtqueue::new(&mut *transport, REQUESTQ), allocate_frame())
+    else {
+        log::warn!("virtio-rng: failed to set up the request queue");
+        fail(&mut *transport);
+        return;
+    };
+    driver_ok(&mut *transport);
+
+    *VIRTIO_RNG.lock() = Some(Rng {
+        transport,
+        request,
+        buffer: buffer.base().data(),
+    });
+
+    let mut seed = [0; SEED_SIZE];
+    let len = read(&mut seed);
+    entropy::add(&seed[..len]);
+    log::info!("virtio-rng: seeded the entropy pool with {} bytes", len);
+}
@@ -25,6 +25,13 @@ pub trait InterruptController: InterruptHandler {
     fn irq_disable(&mut self, irq_num: u32);
     fn irq_xlate(&self, irq_data: &[u32; 3]) -> Result<usize>;
     fn irq_to_virq(&self, hwirq: u32) -> Option<usize>;
+    /// Initialize the per-CPU part of the controller on a secondary CPU.
+    fn irq_init_ap(&mut self) {}
+    /// Save the state the controller loses while the system is suspended, on the BSP.
+    fn suspend(&mut self) {}
+    /// Initialize the controller again with the state saved by `suspend`, on the BSP after waking
+    /// up.
+    fn resume(&mut self) {}
 }
 
 pub struct IrqConnection {
@@ -263,6 +270,9 @@ impl IrqChipCore {
 
     pub fn trigger_virq(&mut self, virq: u32) {
         if virq < 1024 {
+            if let Ok(irq) = u8::try_from(virq) {
+                crate::irq_stats::count(irq);
+            }
             let desc = &mut self.irq_desc[virq as usize];
             if let Some(handler) = &mut desc.handler {
                 handler.irq_handler(virq);
@@ -274,6 +284,29 @@ impl IrqChipCore {
         }
     }
 
+    #[cfg(target_arch = "aarch64")]
+    pub fn init_ap(&mut self) {
+        for chip in self.irq_chip_list.chips.iter_mut() {
+            chip.ic.irq_init_ap();
+        }
+    }
+
+    /// Save the state of the controllers before the system is suspended, children first.
+    #[cfg(target_arch = "aarch64")]
+    pub fn suspend(&mut self) {
+        for chip in self.irq_chip_list.chips.iter_mut().rev() {
+            chip.ic.suspend();
+        }
+    }
+
+    /// Initialize the controllers again after waking up, parents first.
+    #[cfg(target_arch = "aarch64")]
+    pub fn resume(&mut self) {
+        for chip in self.irq_chip_list.chips.iter_mut() {
+            chip.ic.resume();
+        }
+    }
+
     pub fn init(&mut self, fdt_opt: Option<&Fdt>) {
         for (i, desc) in self.irq_desc.iter_mut().enumerate() {
             desc.basic.idx = i;
@@ -331,7 +364,8 @@ pub fn init(fdt: &Fdt) {
     }
 }
 
-pub fn register_irq(virq: u32, handler: Box<dyn InterruptHandler>) {
+/// Handle `virq` in the kernel, as `name` in the interrupt statistics.
+pub fn register_irq(virq: u32, name: &'static str, handler: Box<dyn InterruptHandler>) {
     if virq >= 1024 {
         error!("irq {} exceed 1024!!!", virq);
         return;
@@ -345,6 +379,9 @@ pub fn register_irq(virq: u32, handler: Box<dyn InterruptHandler>) {
 
         IRQ_CHIP.irq_desc[virq as usize].handler = Some(handler);
     }
+    if let Ok(irq) = u8::try_from(virq) {
+        crate::irq_stats::set_handler(irq, name);
+    }
 }
 
 #[inline]
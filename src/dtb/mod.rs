@@ -1,6 +1,10 @@
 pub mod irqchip;
 
-use crate::startup::memory::{register_memory_region, BootloaderMemoryKind};
+use crate::{
+    numa::{self, CpuAffinity, MemoryAffinity, NodeId, MAX_NODE_COUNT},
+    paging::PhysicalAddress,
+    startup::memory::{register_memory_region, BootloaderMemoryKind},
+};
 use alloc::vec::Vec;
 use byteorder::{ByteOrder, BE};
 use core::slice;
@@ -76,6 +80,61 @@ pub fn register_memory_ranges(dt: &Fdt) {
     }
 }
 
+/// Register the NUMA topology from the `numa-node-id` properties of the memory and CPU nodes, if
+/// they describe more than one node. CPUs are identified by the low 32 bits of their `reg`.
+pub fn init_numa(dt: &Fdt) {
+    let node_of = |node: &fdt::node::FdtNode| -> Option<NodeId> {
+        let id = node.property("numa-node-id")?.as_usize()?;
+        if id >= MAX_NODE_COUNT {
+            log::warn!("devicetree: ignoring NUMA node {}", id);
+            return None;
+        }
+        Some(NodeId::new(id as u8))
+    };
+
+    let mut memory = Vec::new();
+    let mut cpus = Vec::new();
+    for node in dt.all_nodes() {
+        let device_type = node.property("device_type").and_then(NodeProperty::as_str);
+        let Some(numa_node) = node_of(&node) else {
+            continue;
+        };
+        match device_type {
+            Some("memory") => {
+                for region in node.reg().into_iter().flatten() {
+                    let Some(size) = region.size.filter(|&size| size != 0) else {
+                        continue;
+                    };
+                    memory.push(MemoryAffinity {
+                        base: PhysicalAddress::new(region.starting_address as usize),
+                        size,
+                        node: numa_node,
+                    });
+                }
+            }
+            Some("cpu") => {
+                if let Some(reg) = node.property("reg").and_then(NodeProperty::as_usize) {
+                    cpus.push(CpuAffinity {
+                        hw_id: reg as u32,
+                        node: numa_node,
+                    });
+                }
+            }
+            _ => (),
+        }
+    }
+
+    let node_count = memory
+        .iter()
+        .map(|area| area.node)
+        .chain(cpus.iter().map(|cpu| cpu.node))
+        .max()
+        .map_or(0, |node| usize::from(node.get()) + 1);
+    if node_count > 1 {
+        numa::init(node_count, memory, cpus);
+    }
+}
+
 pub fn register_dev_memory_ranges(dt: &Fdt) {
     if cfg!(target_arch = "aarch64") {
         // work around for qemu-arm64
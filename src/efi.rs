@@ -0,0 +1,446 @@
+//! EFI runtime services.
+//!
+//! The bootloader passes the EFI system table and the final memory map, as handed over by
+//! ExitBootServices, through the environment. The map is copied to frames owned by the kernel, the
+//! runtime regions are mapped at their linear mapping in the kernel half, and the firmware is told
+//! about those addresses using SetVirtualAddressMap, after which the variable and reset services
+//! can be called from any address space.
+
+use alloc::vec::Vec;
+use core::{arch::asm, mem, slice, str};
+
+use spin::Once;
+use x86::controlregs::{self, Cr0};
+
+use crate::{
+    arch::paging::entry::EntryFlags,
+    memory::{allocate_p2frame, KernelMapper, PAGE_SIZE},
+    paging::{PageFlags, PhysicalAddress, RmmA, RmmArch, VirtualAddress},
+    sync::Mutex,
+    syscall::error::*,
+};
+
+const SYSTEM_TABLE_SIGNATURE: u64 = 0x5453_5953_2049_4249;
+const RUNTIME_SERVICES_SIGNATURE: u64 = 0x5652_4553_544e_5552;
+
+const RUNTIME_SERVICES_CODE: u32 = 5;
+const MEMORY_MAPPED_IO: u32 = 11;
+const MEMORY_MAPPED_IO_PORT_SPACE: u32 = 12;
+
+/// The region must be mapped for use by runtime services.
+const MEMORY_RUNTIME: u64 = 1 << 63;
+
+const EFI_PAGE_SIZE: usize = 4096;
+
+pub const RESET_COLD: u32 = 0;
+pub const RESET_WARM: u32 = 1;
+pub const RESET_SHUTDOWN: u32 = 2;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[repr(C)]
+pub struct Guid(pub u32, pub u16, pub u16, pub [u8; 8]);
+
+impl Guid {
+    /// Parse the textual form, e.g. `8be4df61-93ca-11d2-aa0d-00e098032b8c`.
+    pub fn parse(s: &str) -> Option<Self> {
+        let mut parts = s.split('-');
+        let a = u32::from_str_radix(parts.next()?, 16).ok()?;
+        let b = u16::from_str_radix(parts.next()?, 16).ok()?;
+        let c = u16::from_str_radix(parts.next()?, 16).ok()?;
+        let d = parts.next()?;
+        let e = parts.next()?;
+        if parts.next().is_some() || d.len() != 4 || e.len() != 12 {
+            return None;
+        }
+        let mut bytes = [0; 8];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            let hex = if i < 2 {
+                d.get(i * 2..i * 2 + 2)?
+            } else {
+                e.get((i - 2) * 2..(i - 2) * 2 + 2)?
+            };
+            *byte = u8::from_str_radix(hex, 16).ok()?;
+        }
+        Some(Guid(a, b, c, bytes))
+    }
+}
+
+impl core::fmt::Display for Guid {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        let d = &self.3;
+        write!(
+            f,
+            "{:08x}-{:04x}-{:04x}-{:02x}{:02x}-{:02x}{:02x}{:02x}{:02x}{:02x}{:02x}",
+            self.0, self.1, self.2, d[0], d[1], d[2], d[3], d[4], d[5], d[6], d[7]
+        )
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+#[repr(C)]
+struct TableHeader {
+    signature: u64,
+    revision: u32,
+    header_size: u32,
+    crc32: u32,
+    _reserved: u32,
+}
+
+#[repr(C)]
+struct SystemTable {
+    header: TableHeader,
+    firmware_vendor: u64,
+    firmware_revision: u32,
+    console_in_handle: u64,
+    con_in: u64,
+    console_out_handle: u64,
+    con_out: u64,
+    standard_error_handle: u64,
+    std_err: u64,
+    runtime_services: u64,
+    boot_services: u64,
+    number_of_table_entries: u64,
+    configuration_table: u64,
+}
+
+type Status = usize;
+
+#[repr(C)]
+struct RuntimeServices {
+    header: TableHeader,
+    get_time: usize,
+    set_time: usize,
+    get_wakeup_time: usize,
+    set_wakeup_time: usize,
+    set_virtual_address_map: extern "efiapi" fn(usize, usize, u32, *const u8) -> Status,
+    convert_pointer: usize,
+    get_variable:
+        extern "efiapi" fn(*const u16, *const Guid, *mut u32, *mut usize, *mut u8) -> Status,
+    get_next_variable_name: extern "efiapi" fn(*mut usize, *mut u16, *mut Guid) -> Status,
+    set_variable: extern "efiapi" fn(*const u16, *const Guid, u32, usize, *const u8) -> Status,
+    get_next_high_monotonic_count: usize,
+    reset_system: extern "efiapi" fn(u32, Status, usize, *const u8) -> !,
+}
+
+#[derive(Clone, Copy, Debug)]
+#[repr(C)]
+struct MemoryDescriptor {
+    ty: u32,
+    physical_start: u64,
+    virtual_start: u64,
+    number_of_pages: u64,
+    attribute: u64,
+}
+
+const SUCCESS: Status = 0;
+const ERROR_BIT: Status = 1 << (usize::BITS - 1);
+
+fn status_to_error(status: Status) -> Error {
+    Error::new(match status & !ERROR_BIT {
+        // EFI_INVALID_PARAMETER
+        2 => EINVAL,
+        // EFI_UNSUPPORTED
+        3 => EOPNOTSUPP,
+        // EFI_BUFFER_TOO_SMALL
+        5 => EOVERFLOW,
+        // EFI_WRITE_PROTECTED
+        8 => EROFS,
+        // EFI_OUT_OF_RESOURCES
+        9 => ENOSPC,
+        // EFI_NOT_FOUND
+        14 => ENOENT,
+        // EFI_SECURITY_VIOLATION
+        26 => EACCES,
+        _ => EIO,
+    })
+}
+
+fn check(status: Status) -> Result<()> {
+    if status == SUCCESS {
+        Ok(())
+    } else {
+        Err(status_to_error(status))
+    }
+}
+
+/// The memory map handed over by the bootloader, with the virtual addresses of the runtime
+/// regions filled in.
+pub struct MemoryMap {
+    pub data: &'static [u8],
+    pub descriptor_size: usize,
+    pub descriptor_version: u32,
+}
+
+pub static MEMORY_MAP: Once<MemoryMap> = Once::new();
+static RUNTIME: Once<&'static RuntimeServices> = Once::new();
+
+/// Runtime services are not reentrant.
+static LOCK: Mutex<()> = Mutex::new(());
+
+#[derive(Default)]
+struct Env {
+    system_table: usize,
+    memory_map: usize,
+    memory_map_size: usize,
+    descriptor_size: usize,
+    descriptor_version: usize,
+}
+
+pub unsafe fn init(env: &[u8]) {
+    let mut args = Env::default();
+    for line in str::from_utf8(env).unwrap_or("").lines() {
+        let mut parts = line.splitn(2, '=');
+        let name = parts.next().unwrap_or("");
+        let value = parts.next().unwrap_or("");
+        let value = || usize::from_str_radix(value, 16).unwrap_or(0);
+
+        match name {
+            "EFI_SYSTEM_TABLE" => args.system_table = value(),
+            "EFI_MEMORY_MAP" => args.memory_map = value(),
+            "EFI_MEMORY_MAP_SIZE" => args.memory_map_size = value(),
+            "EFI_MEMORY_DESCRIPTOR_SIZE" => args.descriptor_size = value(),
+            "EFI_MEMORY_DESCRIPTOR_VERSION" => args.descriptor_version = value(),
+            _ => (),
+        }
+    }
+
+    if args.system_table == 0
+        || args.memory_map == 0
+        || args.memory_map_size == 0
+        || args.descriptor_size < mem::size_of::<MemoryDescriptor>()
+    {
+        log::info!("EFI runtime services not available");
+        return;
+    }
+
+    // Preserve the memory map in contiguous frames, so that it can be passed to the firmware by
+    // physical address.
+    let order = args
+        .memory_map_size
+        .div_ceil(PAGE_SIZE)
+        .next_power_of_two()
+        .trailing_zeros();
+    let Some(map_frame) = allocate_p2frame(order) else {
+        log::warn!("Failed to allocate the EFI memory map");
+        return;
+    };
+    let map = slice::from_raw_parts_mut(
+        RmmA::phys_to_virt(map_frame.base()).data() as *mut u8,
+        args.memory_map_size,
+    );
+    map.copy_from_slice(slice::from_raw_parts(
+        (args.memory_map + crate::PHYS_OFFSET) as *const u8,
+        args.memory_map_size,
+    ));
+
+    let mut mapper = KernelMapper::lock();
+    let mapper = mapper
+        .get_mut()
+        .expect("expected KernelMapper not to be locked re-entrant while initializing EFI");
+
+    // Map the runtime regions at their linear mapping, and temporarily identity map them together
+    // with the memory map, as SetVirtualAddressMap is called with the physical mapping.
+    let mut identity = Vec::new();
+    for descriptor in map.chunks_exact_mut(args.descriptor_size) {
+        let mut desc = descriptor
+            .as_ptr()
+            .cast::<MemoryDescriptor>()
+            .read_unaligned();
+        if desc.attribute & MEMORY_RUNTIME == 0 {
+            continue;
+        }
+
+        let mut flags = PageFlags::new()
+            .write(true)
+            .execute(desc.ty == RUNTIME_SERVICES_CODE);
+        if matches!(desc.ty, MEMORY_MAPPED_IO | MEMORY_MAPPED_IO_PORT_SPACE) {
+            flags = flags.custom_flag(EntryFlags::NO_CACHE.bits(), true);
+        }
+
+        let size = desc.number_of_pages as usize * EFI_PAGE_SIZE;
+        for offset in (0..size).step_by(PAGE_SIZE) {
+            let phys = PhysicalAddress::new(desc.physical_start as usize + offset);
+            let Some(flush) = mapper.map_phys(RmmA::phys_to_virt(phys), phys, flags) else {
+                log::warn!("Failed to map EFI runtime region at {:#x}", phys.data());
+                return;
+            };
+            flush.flush();
+            identity.push((phys, flags));
+        }
+
+        desc.virtual_start =
+            RmmA::phys_to_virt(PhysicalAddress::new(desc.physical_start as usize)).data() as u64;
+        descriptor
+            .as_mut_ptr()
+            .cast::<MemoryDescriptor>()
+            .write_unaligned(desc);
+    }
+    for offset in (0..args.memory_map_size).step_by(PAGE_SIZE) {
+        identity.push((map_frame.base().add(offset), PageFlags::new()));
+    }
+
+    // The system table lives in runtime services data, which is only mapped now.
+    let system_table = &*((args.system_table + crate::PHYS_OFFSET) as *const SystemTable);
+    if system_table.header.signature != SYSTEM_TABLE_SIGNATURE {
+        log::warn!("Invalid EFI system table at {:#x}", args.system_table);
+        return;
+    }
+    let runtime_phys = system_table.runtime_services as usize;
+    let runtime = &*((runtime_phys + crate::PHYS_OFFSET) as *const RuntimeServices);
+    if runtime.header.signature != RUNTIME_SERVICES_SIGNATURE {
+        log::warn!("Invalid EFI runtime services table at {:#x}", runtime_phys);
+        return;
+    }
+
+    for &(phys, flags) in &identity {
+        if let Some(flush) = mapper.map_phys(VirtualAddress::new(phys.data()), phys, flags) {
+            flush.flush();
+        }
+    }
+
+    let status = with_fpu_saved(|| {
+        (runtime.set_virtual_address_map)(
+            args.memory_map_size,
+            args.descriptor_size,
+            args.descriptor_version as u32,
+            map_frame.base().data() as *const u8,
+        )
+    });
+
+    for &(phys, _) in &identity {
+        if let Some((_, _, flush)) = mapper.unmap_phys(VirtualAddress::new(phys.data()), true) {
+            flush.flush();
+        }
+    }
+
+    if let Err(err) = check(status) {
+        log::warn!("EFI SetVirtualAddressMap failed: {}", err);
+        return;
+    }
+
+    log::info!(
+        "EFI runtime services revision {:#x}",
+        system_table.header.revision
+    );
+    MEMORY_MAP.call_once(|| MemoryMap {
+        data: map,
+        descriptor_size: args.descriptor_size,
+        descriptor_version: args.descriptor_version as u32,
+    });
+    RUNTIME.call_once(|| runtime);
+}
+
+pub fn available() -> bool {
+    RUNTIME.get().is_some()
+}
+
+/// The firmware may use SSE registers, which still hold the state of the calling userspace
+/// context during a syscall, unless it was switched to lazily and has not used them yet.
+fn with_fpu_saved<T>(f: impl FnOnce() -> T) -> T {
+    #[repr(C, align(16))]
+    struct FxArea([u8; 512]);
+
+    let lazy = unsafe { controlregs::cr0() }.contains(Cr0::CR0_TASK_SWITCHED);
+    let mut area = FxArea([0; 512]);
+    unsafe {
+        if lazy {
+            asm!("clts", options(nostack, preserves_flags));
+        } else {
+            asm!("fxsave64 [{}]", in(reg) area.0.as_mut_ptr(), options(nostack));
+        }
+    }
+    let ret = f();
+    unsafe {
+        if lazy {
+            controlregs::cr0_write(controlregs::cr0() | Cr0::CR0_TASK_SWITCHED);
+        } else {
+            asm!("fxrstor64 [{}]", in(reg) area.0.as_ptr(), options(nostack));
+        }
+    }
+    ret
+}
+
+fn call<T>(f: impl FnOnce(&RuntimeServices) -> T) -> Result<T> {
+    let runtime = RUNTIME.get().ok_or(Error::new(ENODEV))?;
+    let _guard = LOCK.lock();
+    Ok(with_fpu_saved(|| f(runtime)))
+}
+
+/// Read the variable `name` (without the terminating NUL) into `buf`, returning its attributes
+/// and size. If `buf` is too small, fails with EOVERFLOW.
+pub fn get_variable(name: &[u16], guid: &Guid, buf: &mut [u8]) -> Result<(u32, usize)> {
+    let name = nul_terminated(name);
+    let mut attributes = 0;
+    let mut size = buf.len();
+    let status = call(|runtime| {
+        (runtime.get_variable)(
+            name.as_ptr(),
+            guid,
+            &mut attributes,
+            &mut size,
+            buf.as_mut_ptr(),
+        )
+    })?;
+    check(status).map(|()| (attributes, size))
+}
+
+/// Read a variable of any size.
+pub fn read_variable(name: &[u16], guid: &Guid) -> Result<(u32, Vec<u8>)> {
+    let mut buf = Vec::new();
+    loop {
+        match get_variable(name, guid, &mut buf) {
+            Ok((attributes, size)) => {
+                buf.truncate(size);
+                return Ok((attributes, buf));
+            }
+            Err(err) if err.errno == EOVERFLOW && buf.len() < 1024 * 1024 => {
+                buf.resize(buf.len().max(256) * 2, 0);
+            }
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+/// Write a variable. Writing no data with the append attribute clear deletes it.
+pub fn set_variable(name: &[u16], guid: &Guid, attributes: u32, data: &[u8]) -> Result<()> {
+    let name = nul_terminated(name);
+    let status = call(|runtime| {
+        (runtime.set_variable)(name.as_ptr(), guid, attributes, data.len(), data.as_ptr())
+    })?;
+    check(status)
+}
+
+/// List the names and vendor GUIDs of all variables.
+pub fn variable_names() -> Result<Vec<(Vec<u16>, Guid)>> {
+    let mut names = Vec::new();
+    let mut name = alloc::vec![0_u16; 256];
+    let mut guid = Guid::default();
+    loop {
+        let mut size = name.len() * 2;
+        let status = call(|runtime| {
+            (runtime.get_next_variable_name)(&mut size, name.as_mut_ptr(), &mut guid)
+        })?;
+        match check(status) {
+            Ok(()) => {
+                let len = name.iter().position(|&c| c == 0).unwrap_or(name.len());
+                names.push((name[..len].to_vec(), guid));
+            }
+            Err(err) if err.errno == EOVERFLOW => name.resize(size.div_ceil(2), 0),
+            Err(err) if err.errno == ENOENT => return Ok(names),
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+/// Reset or power off using the firmware. Only returns if runtime services are unavailable.
+pub fn reset_system(kind: u32) -> Result<()> {
+    let runtime = RUNTIME.get().ok_or(Error::new(ENODEV))?;
+    let _guard = LOCK.lock();
+    (runtime.reset_system)(kind, SUCCESS, 0, core::ptr::null())
+}
+
+fn nul_terminated(name: &[u16]) -> Vec<u16> {
+    let mut name = name.to_vec();
+    name.push(0);
+    name
+}
@@ -3,6 +3,7 @@
 //! ELF executables
 
 use alloc::string::String;
+use core::str;
 
 use goblin::elf::section_header::SHT_SYMTAB;
 
@@ -51,7 +52,25 @@ impl<'a> Elf<'a> {
         }
     }
 
-    pub fn sections(&'a self) -> ElfSections<'a> {
+    /// The virtual address of the entry point
+    pub fn entry(&self) -> usize {
+        self.header.e_entry as usize
+    }
+
+    /// The machine the executable is for, one of the `EM_*` constants
+    pub fn machine(&self) -> u16 {
+        self.header.e_machine
+    }
+
+    pub fn segments(&self) -> ElfSegments<'a> {
+        ElfSegments {
+            data: self.data,
+            header: self.header,
+            i: 0,
+        }
+    }
+
+    pub fn sections(&self) -> ElfSections<'a> {
         ElfSections {
             data: self.data,
             header: self.header,
@@ -59,7 +78,27 @@ impl<'a> Elf<'a> {
         }
     }
 
-    pub fn symbols(&'a self) -> Option<ElfSymbols<'a>> {
+    /// The contents of a section
+    pub fn section_data(&self, section: &section_header::SectionHeader) -> Option<&'a [u8]> {
+        let start = section.sh_offset as usize;
+        self.data
+            .get(start..start.checked_add(section.sh_size as usize)?)
+    }
+
+    /// The name of a section, from the section header string table
+    pub fn section_name(&self, section: &section_header::SectionHeader) -> Option<&'a str> {
+        let shstrtab = self.sections().nth(self.header.e_shstrndx as usize)?;
+        self.string(shstrtab, section.sh_name as usize)
+    }
+
+    /// The NUL-terminated string at `offset` in the string table `strtab`
+    pub fn string(&self, strtab: &section_header::SectionHeader, offset: usize) -> Option<&'a str> {
+        let data = self.section_data(strtab)?.get(offset..)?;
+        let len = data.iter().position(|&b| b == 0)?;
+        str::from_utf8(&data[..len]).ok()
+    }
+
+    pub fn symbols(&self) -> Option<ElfSymbols<'a>> {
         let mut symtab_opt = None;
         for section in self.sections() {
             if section.sh_type == SHT_SYMTAB {
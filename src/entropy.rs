@@ -0,0 +1,216 @@
+//! # Entropy pool
+//!
+//! Random numbers for the kernel, such as the KASLR seed of kernels started with kexec and the
+//! randomized base of user grants, and for userspace through the `getrandom:` scheme, come from a
+//! CSPRNG built on the ChaCha20 block function.
+//!
+//! Its key is seeded at boot from the hardware random number generator of the CPU where there is
+//! one (RDRAND or RNDR), the jitter of timing a short loop with the cycle counter, and virtio-rng
+//! when it is found, and whatever is added later is mixed into it the same way: XORed into the key,
+//! which is then replaced by a block generated with it. The CPU generator is mixed in again before
+//! every output, and the timing of interrupts whenever a CPU took enough of them, from its work
+//! queue.
+//!
+//! Outputs use fast key erasure: each one replaces the key with a block generated with it, and
+//! derives the key of its own keystream from another, so that earlier outputs cannot be recovered
+//! from the state, and the keystream is generated without holding the lock.
+
+use core::{
+    cell::{Cell, RefCell},
+    hint,
+};
+
+use crate::{
+    percpu::PercpuBlock,
+    rng,
+    sync::Mutex,
+    workqueue::{schedule_work, Work},
+};
+
+/// Nonces of the blocks used to mix inputs in, to replace the key when generating an output, and
+/// for the keystream of an output, so that none of them share a block.
+const NONCE_MIX: u64 = 0;
+const NONCE_REKEY: u64 = 1;
+const NONCE_OUTPUT: u64 = 2;
+
+/// Bytes of the keystream of an output generated at once.
+const BLOCK_SIZE: usize = 64;
+
+/// Timing samples taken when seeding.
+const JITTER_SAMPLES: usize = 256;
+
+/// Interrupts timed on a CPU before the samples are mixed in.
+const INTERRUPT_SAMPLES: usize = 64;
+
+static KEY: Mutex<[u32; 8]> = Mutex::new([0; 8]);
+
+fn quarter_round(state: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+    state[a] = state[a].wrapping_add(state[b]);
+    state[d] = (state[d] ^ state[a]).rotate_left(16);
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_left(12);
+    state[a] = state[a].wrapping_add(state[b]);
+    state[d] = (state[d] ^ state[a]).rotate_left(8);
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_left(7);
+}
+
+/// The ChaCha20 block of `key` at `counter`, with a 64-bit counter and nonce.
+fn chacha20_block(key: &[u32; 8], counter: u64, nonce: u64) -> [u32; 16] {
+    let mut initial = [0; 16];
+    initial[..4].copy_from_slice(&[0x6170_7865, 0x3320_646E, 0x7962_2D32, 0x6B20_6574]);
+    initial[4..12].copy_from_slice(key);
+    initial[12..].copy_from_slice(&[
+        counter as u32,
+        (counter >> 32) as u32,
+        nonce as u32,
+        (nonce >> 32) as u32,
+    ]);
+
+    let mut state = initial;
+    for _ in 0..10 {
+        quarter_round(&mut state, 0, 4, 8, 12);
+        quarter_round(&mut state, 1, 5, 9, 13);
+        quarter_round(&mut state, 2, 6, 10, 14);
+        quarter_round(&mut state, 3, 7, 11, 15);
+        quarter_round(&mut state, 0, 5, 10, 15);
+        quarter_round(&mut state, 1, 6, 11, 12);
+        quarter_round(&mut state, 2, 7, 8, 13);
+        quarter_round(&mut state, 3, 4, 9, 14);
+    }
+    for (word, initial) in state.iter_mut().zip(initial) {
+        *word = word.wrapping_add(initial);
+    }
+    state
+}
+
+/// Replace `key` by the first half of its block with `nonce`.
+fn rekey(key: &mut [u32; 8], nonce: u64) {
+    let block = chacha20_block(key, 0, nonce);
+    key.copy_from_slice(&block[..8]);
+}
+
+/// XOR `data` into `key` 32 bytes at a time, replacing it after each.
+fn mix(key: &mut [u32; 8], data: &[u8]) {
+    for chunk in data.chunks(32) {
+        for (word, bytes) in key.iter_mut().zip(chunk.chunks(4)) {
+            let mut padded = [0; 4];
+            padded[..bytes.len()].copy_from_slice(bytes);
+            *word ^= u32::from_le_bytes(padded);
+        }
+        rekey(key, NONCE_MIX);
+    }
+}
+
+/// Mix `data` from a source of randomness into the pool. It is never harmful, whatever its
+/// quality.
+pub fn add(data: &[u8]) {
+    mix(&mut KEY.lock(), data);
+}
+
+/// The low byte of the cycle counter at the last interrupts taken by a CPU, not mixed in yet.
+pub struct InterruptEntropy {
+    samples: RefCell<[u8; INTERRUPT_SAMPLES]>,
+    count: Cell<usize>,
+    work: Work,
+}
+
+impl InterruptEntropy {
+    pub const fn new() -> Self {
+        Self {
+            samples: RefCell::new([0; INTERRUPT_SAMPLES]),
+            count: Cell::new(0),
+            work: Work::new(mix_interrupts),
+        }
+    }
+}
+
+/// Time an interrupt of this CPU, from its dispatch path.
+pub fn add_interrupt() {
+    let entropy = &PercpuBlock::current().interrupt_entropy;
+    let count = entropy.count.get();
+    if count < INTERRUPT_SAMPLES {
+        entropy.samples.borrow_mut()[count] = rng::cycles() as u8;
+        entropy.count.set(count + 1);
+    }
+    // Scheduled again until the samples were mixed in, in case the worker ran on another CPU.
+    if count + 1 >= INTERRUPT_SAMPLES {
+        schedule_work(&entropy.work);
+    }
+}
+
+fn mix_interrupts() {
+    let entropy = &PercpuBlock::current().interrupt_entropy;
+    if entropy.count.get() < INTERRUPT_SAMPLES {
+        return;
+    }
+    let samples = *entropy.samples.borrow();
+    entropy.count.set(0);
+    add(&samples);
+}
+
+/// Time a short loop, which varies with caches, pipelines, frequency changes and interrupts.
+fn jitter() -> [u8; JITTER_SAMPLES] {
+    let mut samples = [0; JITTER_SAMPLES];
+    for sample in samples.iter_mut() {
+        let start = rng::cycles();
+        let mut x = start;
+        for i in 0..32 {
+            x = hint::black_box(x.rotate_left(7) ^ i);
+        }
+        *sample = rng::cycles().wrapping_sub(start) as u8;
+    }
+    samples
+}
+
+/// Seed the pool at boot, before anything needs random numbers.
+pub fn init() {
+    let mut hardware = [0; 64];
+    let mut hardware_len = 0;
+    while hardware_len < hardware.len() {
+        let Some(random) = rng::hardware_random() else {
+            break;
+        };
+        hardware[hardware_len..hardware_len + 8].copy_from_slice(&random.to_le_bytes());
+        hardware_len += 8;
+    }
+    add(&hardware[..hardware_len]);
+    add(&jitter());
+    add(&crate::time::monotonic().to_le_bytes());
+
+    log::info!(
+        "Entropy pool seeded with {} bytes from the CPU and {} timing samples",
+        hardware_len,
+        JITTER_SAMPLES
+    );
+}
+
+/// Fill `buf` with random bytes.
+pub fn fill(buf: &mut [u8]) {
+    let output_key = {
+        let mut key = KEY.lock();
+        if let Some(random) = rng::hardware_random() {
+            key[0] ^= random as u32;
+            key[1] ^= (random >> 32) as u32;
+        }
+        let block = chacha20_block(&key, 0, NONCE_REKEY);
+        key.copy_from_slice(&block[..8]);
+        let mut output_key = [0; 8];
+        output_key.copy_from_slice(&block[8..]);
+        output_key
+    };
+
+    for (counter, chunk) in buf.chunks_mut(BLOCK_SIZE).enumerate() {
+        let block = chacha20_block(&output_key, counter as u64, NONCE_OUTPUT);
+        for (bytes, word) in chunk.chunks_mut(4).zip(block) {
+            bytes.copy_from_slice(&word.to_le_bytes()[..bytes.len()]);
+        }
+    }
+}
+
+/// A random 64-bit number.
+pub fn random_u64() -> u64 {
+    let mut bytes = [0; 8];
+    fill(&mut bytes);
+    u64::from_le_bytes(bytes)
+}
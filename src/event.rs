@@ -1,12 +1,12 @@
 use alloc::sync::Arc;
 use core::sync::atomic::{AtomicUsize, Ordering};
 use hashbrown::HashMap;
-use spin::{Once, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use spin::Once;
 
 use crate::{
     context,
     scheme::{self, SchemeId},
-    sync::WaitQueue,
+    sync::{RwLock, RwLockReadGuard, RwLockWriteGuard, WaitQueue},
     syscall::{
         data::Event,
         error::{Error, Result, EBADF},
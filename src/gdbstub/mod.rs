@@ -0,0 +1,549 @@
+//! A GDB remote serial protocol stub, for debugging the kernel from a host with GDB.
+//!
+//! The stub is enabled with `GDBSTUB=com1` or `GDBSTUB=com2` in the environment, and then owns
+//! that UART: it is entered on kernel breakpoints, single-steps, and when GDB interrupts the
+//! kernel with Ctrl-C. With `GDBSTUB_WAIT=1`, the kernel stops early in `kmain` until GDB
+//! attaches, e.g. with `target remote /dev/ttyS1` or, in QEMU, `-serial stdio -serial tcp::1234,server`
+//! and `target remote :1234`.
+//!
+//! Registers, memory, software breakpoints and single-stepping are supported. Memory is accessed
+//! through the linear mapping of the physical address, so breakpoints can be placed in read-only
+//! kernel text. Only the CPU that trapped is stopped; the others keep running, and will wait in
+//! the stub if they trap while it is in use.
+
+use core::{
+    arch::asm,
+    sync::atomic::{AtomicBool, AtomicU16, Ordering},
+};
+
+use crate::{
+    arch::consts::USER_END_OFFSET,
+    context::huge_page,
+    interrupt::InterruptStack,
+    lockdown,
+    memory::{KernelMapper, TheFrameAllocator, PAGE_SIZE},
+    paging::{PageMapper, RmmA, RmmArch, TableKind, VirtualAddress},
+    sync::Mutex,
+    syscall::error::{EFAULT, EINVAL, ENOSPC},
+};
+
+use self::packet::{decode_hex, parse_hex, Connection, Reply, PACKET_SIZE};
+
+mod packet;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Port {
+    Com1,
+    Com2,
+}
+
+impl Port {
+    fn base(self) -> u16 {
+        match self {
+            Port::Com1 => 0x3F8,
+            Port::Com2 => 0x2F8,
+        }
+    }
+}
+
+/// Base of the UART owned by the stub, or zero if disabled.
+static PORT_BASE: AtomicU16 = AtomicU16::new(0);
+/// Set when the next entry is caused by GDB interrupting the kernel.
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+const SIGINT: u8 = 2;
+const SIGTRAP: u8 = 5;
+
+const INT3: u8 = 0xCC;
+const MAX_BREAKPOINTS: usize = 64;
+
+#[derive(Clone, Copy)]
+struct Breakpoint {
+    addr: usize,
+    original: u8,
+}
+
+struct State {
+    breakpoints: [Option<Breakpoint>; MAX_BREAKPOINTS],
+    /// Whether GDB is waiting for a stop reply, after resuming the kernel.
+    resumed: bool,
+    packet: [u8; PACKET_SIZE],
+    reply: Reply,
+    /// Memory being read or written, kept here since the exception stacks are small.
+    memory: [u8; PACKET_SIZE / 2],
+}
+
+static STATE: Mutex<State> = Mutex::new(State {
+    breakpoints: [None; MAX_BREAKPOINTS],
+    resumed: false,
+    packet: [0; PACKET_SIZE],
+    reply: Reply::new(),
+    memory: [0; PACKET_SIZE / 2],
+});
+
+pub fn init(env: &[u8]) {
+    let mut port = None;
+    let mut wait = false;
+    for line in core::str::from_utf8(env).unwrap_or("").lines() {
+        let mut parts = line.splitn(2, '=');
+        let name = parts.next().unwrap_or("");
+        let value = parts.next().unwrap_or("");
+
+        match name {
+            "GDBSTUB" => match value {
+                "com1" => port = Some(Port::Com1),
+                "com2" => port = Some(Port::Com2),
+                _ => log::warn!("Unknown GDB stub port {:?}", value),
+            },
+            "GDBSTUB_WAIT" => wait = value == "1",
+            _ => (),
+        }
+    }
+
+    let Some(port) = port else {
+        return;
+    };
+    if lockdown::check(lockdown::Reason::KernelDebugger).is_err() {
+        return;
+    }
+
+    PORT_BASE.store(port.base(), Ordering::SeqCst);
+    log::info!("GDB stub on {:?}", port);
+
+    if wait {
+        log::info!("Waiting for GDB to attach");
+        breakpoint();
+    }
+}
+
+fn enabled() -> bool {
+    PORT_BASE.load(Ordering::Relaxed) != 0
+}
+
+/// Called by the serial interrupt handler for each received byte, returning whether the byte was
+/// consumed by the stub. Any packet from GDB while the kernel runs, usually Ctrl-C, stops it.
+pub fn serial_input(port: Port, c: u8) -> bool {
+    if PORT_BASE.load(Ordering::Relaxed) != port.base() {
+        return false;
+    }
+    match c {
+        // Interrupt request
+        0x03 => {
+            INTERRUPTED.store(true, Ordering::SeqCst);
+            breakpoint();
+        }
+        // GDB attaching, or sending a packet without interrupting first.
+        b'$' => {
+            packet::set_packet_started();
+            breakpoint();
+        }
+        // Stray acknowledgements
+        _ => (),
+    }
+    true
+}
+
+/// Trap into the stub, if enabled.
+pub fn breakpoint() {
+    if enabled() {
+        unsafe {
+            asm!("int3");
+        }
+    }
+}
+
+/// Called from the breakpoint exception handler, with RIP pointing to the `int3` instruction.
+/// Returns whether the trap was handled by the stub.
+pub fn handle_breakpoint(stack: &mut InterruptStack) -> bool {
+    handle_trap(stack, true)
+}
+
+/// Called from the debug exception handler, returning whether the trap was handled by the stub.
+pub fn handle_debug(stack: &mut InterruptStack) -> bool {
+    handle_trap(stack, false)
+}
+
+fn handle_trap(stack: &mut InterruptStack, breakpoint: bool) -> bool {
+    if !enabled() || stack.iret.cs & 3 != 0 {
+        return false;
+    }
+
+    let mut state = STATE.lock();
+
+    // A compiled-in int3, such as the one in `breakpoint`, is stepped over rather than
+    // re-executed.
+    let rip = stack.iret.rip;
+    if breakpoint && !state.breakpoints.iter().flatten().any(|bp| bp.addr == rip) {
+        let mut byte = [0];
+        if read_memory(rip, &mut byte).is_ok() && byte[0] == INT3 {
+            stack.iret.rip += 1;
+        }
+    }
+    stack.set_singlestep(false);
+
+    let signal = if INTERRUPTED.swap(false, Ordering::SeqCst) {
+        SIGINT
+    } else {
+        SIGTRAP
+    };
+    run(&mut state, stack, signal);
+
+    true
+}
+
+/// Serve GDB until it resumes the kernel.
+fn run(state: &mut State, stack: &mut InterruptStack, signal: u8) {
+    let mut connection = Connection::new(PORT_BASE.load(Ordering::Relaxed));
+
+    if core::mem::take(&mut state.resumed) {
+        state.reply.clear();
+        stop_reply(&mut state.reply, signal);
+        connection.write_packet(state.reply.as_bytes());
+    }
+
+    loop {
+        let len = connection.read_packet(&mut state.packet);
+        let State {
+            breakpoints,
+            packet,
+            reply,
+            memory,
+            ..
+        } = &mut *state;
+        let packet = &packet[..len];
+        reply.clear();
+
+        let resume = match packet.first().copied() {
+            Some(b'?') => {
+                stop_reply(reply, signal);
+                None
+            }
+            Some(b'g') => {
+                for reg in 0..REGISTER_COUNT {
+                    read_register(stack, reg, reply);
+                }
+                None
+            }
+            Some(b'G') => {
+                match write_registers(stack, &packet[1..]) {
+                    Some(()) => reply.push_str("OK"),
+                    None => reply.error(EINVAL as u8),
+                }
+                None
+            }
+            Some(b'p') => {
+                match parse_hex(&packet[1..]).filter(|&reg| reg < REGISTER_COUNT) {
+                    Some(reg) => read_register(stack, reg, reply),
+                    None => reply.error(EINVAL as u8),
+                }
+                None
+            }
+            Some(b'P') => {
+                let result = split(&packet[1..], b'=')
+                    .and_then(|(reg, value)| write_register(stack, parse_hex(reg)?, value));
+                match result {
+                    Some(()) => reply.push_str("OK"),
+                    None => reply.error(EINVAL as u8),
+                }
+                None
+            }
+            Some(b'm') => {
+                match split(&packet[1..], b',')
+                    .and_then(|(addr, len)| Some((parse_hex(addr)?, parse_hex(len)?)))
+                {
+                    // Each byte takes two characters in the reply.
+                    Some((addr, len)) if len <= memory.len() => {
+                        match read_memory(addr, &mut memory[..len]) {
+                            Ok(()) => reply.push_hex_bytes(&memory[..len]),
+                            Err(errno) => reply.error(errno),
+                        }
+                    }
+                    _ => reply.error(EINVAL as u8),
+                }
+                None
+            }
+            Some(b'M') => {
+                let result = split(&packet[1..], b',').and_then(|(addr, rest)| {
+                    let (len, data) = split(rest, b':')?;
+                    Some((parse_hex(addr)?, parse_hex(len)?, data))
+                });
+                match result {
+                    Some((addr, len, data)) if len <= memory.len() => {
+                        match decode_hex(data, &mut memory[..len]) {
+                            Some(()) => match write_memory(breakpoints, addr, &memory[..len]) {
+                                Ok(()) => reply.push_str("OK"),
+                                Err(errno) => reply.error(errno),
+                            },
+                            None => reply.error(EINVAL as u8),
+                        }
+                    }
+                    _ => reply.error(EINVAL as u8),
+                }
+                None
+            }
+            Some(command @ (b'c' | b's')) => {
+                if packet.len() > 1 {
+                    match parse_hex(&packet[1..]) {
+                        Some(addr) => stack.iret.rip = addr,
+                        None => reply.error(EINVAL as u8),
+                    }
+                }
+                if reply.as_bytes().is_empty() {
+                    Some(command == b's')
+                } else {
+                    None
+                }
+            }
+            Some(command @ (b'Z' | b'z')) => {
+                // Only software breakpoints are supported; other kinds get the empty reply.
+                if packet.get(1..3) == Some(&b"0,"[..]) {
+                    let addr = split(&packet[3..], b',').and_then(|(addr, _kind)| parse_hex(addr));
+                    let result = match addr {
+                        Some(addr) if command == b'Z' => insert_breakpoint(breakpoints, addr),
+                        Some(addr) => remove_breakpoint(breakpoints, addr),
+                        None => Err(EINVAL as u8),
+                    };
+                    match result {
+                        Ok(()) => reply.push_str("OK"),
+                        Err(errno) => reply.error(errno),
+                    }
+                }
+                None
+            }
+            // Detach or kill: remove all breakpoints and let the kernel run freely.
+            Some(command @ (b'D' | b'k')) => {
+                for bp in breakpoints.iter_mut() {
+                    if let Some(old) = bp.take() {
+                        let _ = patch_byte(old.addr, old.original);
+                    }
+                }
+                if command == b'D' {
+                    reply.push_str("OK");
+                    connection.write_packet(reply.as_bytes());
+                }
+                return;
+            }
+            // There is one thread, the CPU that trapped.
+            Some(b'H' | b'T') => {
+                reply.push_str("OK");
+                None
+            }
+            Some(b'q') => {
+                let query = &packet[1..];
+                if query.starts_with(b"Supported") {
+                    reply.push_str("PacketSize=");
+                    reply.push_hex((PACKET_SIZE >> 8) as u8);
+                    reply.push_hex(PACKET_SIZE as u8);
+                } else if query == b"Attached" {
+                    reply.push_str("1");
+                } else if query == b"C" {
+                    reply.push_str("QC1");
+                } else if query == b"fThreadInfo" {
+                    reply.push_str("m1");
+                } else if query == b"sThreadInfo" {
+                    reply.push_str("l");
+                }
+                None
+            }
+            _ => None,
+        };
+
+        if let Some(step) = resume {
+            stack.set_singlestep(step);
+            state.resumed = true;
+            return;
+        }
+        connection.write_packet(state.reply.as_bytes());
+    }
+}
+
+fn stop_reply(reply: &mut Reply, signal: u8) {
+    reply.push(b'S');
+    reply.push_hex(signal);
+}
+
+fn split(s: &[u8], separator: u8) -> Option<(&[u8], &[u8])> {
+    let i = s.iter().position(|&c| c == separator)?;
+    Some((&s[..i], &s[i + 1..]))
+}
+
+/// The general purpose registers, RIP, EFLAGS and the segment registers, in GDB's amd64 order.
+const REGISTER_COUNT: usize = 24;
+const RIP: usize = 16;
+const EFLAGS: usize = 17;
+
+fn register(stack: &mut InterruptStack, reg: usize) -> Option<&mut usize> {
+    Some(match reg {
+        0 => &mut stack.scratch.rax,
+        1 => &mut stack.preserved.rbx,
+        2 => &mut stack.scratch.rcx,
+        3 => &mut stack.scratch.rdx,
+        4 => &mut stack.scratch.rsi,
+        5 => &mut stack.scratch.rdi,
+        6 => &mut stack.preserved.rbp,
+        7 => &mut stack.iret.rsp,
+        8 => &mut stack.scratch.r8,
+        9 => &mut stack.scratch.r9,
+        10 => &mut stack.scratch.r10,
+        11 => &mut stack.scratch.r11,
+        12 => &mut stack.preserved.r12,
+        13 => &mut stack.preserved.r13,
+        14 => &mut stack.preserved.r14,
+        15 => &mut stack.preserved.r15,
+        RIP => &mut stack.iret.rip,
+        EFLAGS => &mut stack.iret.rflags,
+        18 => &mut stack.iret.cs,
+        19 => &mut stack.iret.ss,
+        _ => return None,
+    })
+}
+
+/// The size of a register in the `g` and `p` packets. EFLAGS and the segment registers are
+/// 32-bit.
+fn register_size(reg: usize) -> usize {
+    if reg <= RIP {
+        8
+    } else {
+        4
+    }
+}
+
+fn read_register(stack: &mut InterruptStack, reg: usize, reply: &mut Reply) {
+    // DS, ES, FS and GS are not saved, and are zero in the kernel.
+    let value = register(stack, reg).map_or(0, |value| *value);
+    reply.push_hex_bytes(&value.to_le_bytes()[..register_size(reg)]);
+}
+
+fn write_register(stack: &mut InterruptStack, reg: usize, hex: &[u8]) -> Option<()> {
+    let mut bytes = [0_u8; 8];
+    let size = register_size(reg);
+    decode_hex(hex, &mut bytes[..size])?;
+
+    // The segment registers cannot be changed.
+    if reg >= REGISTER_COUNT {
+        return None;
+    } else if reg > EFLAGS {
+        return Some(());
+    }
+    let value = register(stack, reg)?;
+    *value = usize::from_le_bytes(bytes);
+    Some(())
+}
+
+fn write_registers(stack: &mut InterruptStack, mut hex: &[u8]) -> Option<()> {
+    for reg in 0..REGISTER_COUNT {
+        // GDB may send fewer registers than are described.
+        if hex.is_empty() {
+            break;
+        }
+        let (value, rest) = hex.split_at_checked(register_size(reg) * 2)?;
+        write_register(stack, reg, value)?;
+        hex = rest;
+    }
+    Some(())
+}
+
+/// Translate `addr` through the kernel page tables, or the current user page tables for lower
+/// half addresses, returning the address in the linear mapping of physical memory and the number
+/// of bytes remaining in the page.
+fn translate(addr: usize) -> Result<(usize, usize), u8> {
+    let page = addr & !(PAGE_SIZE - 1);
+    let offset = addr - page;
+
+    let translated = if addr >= USER_END_OFFSET {
+        KernelMapper::lock()
+            .translate(VirtualAddress::new(page))
+            .map(|(phys, _)| phys)
+    } else {
+        let mapper = unsafe { PageMapper::current(TableKind::User, TheFrameAllocator) };
+        huge_page::translate(&mapper, VirtualAddress::new(page))
+    };
+    let phys = translated.ok_or(EFAULT as u8)?;
+
+    let virt = unsafe { RmmA::phys_to_virt(phys) }.data();
+    Ok((virt + offset, PAGE_SIZE - offset))
+}
+
+fn read_memory(mut addr: usize, mut buf: &mut [u8]) -> Result<(), u8> {
+    while !buf.is_empty() {
+        let (virt, available) = translate(addr)?;
+        let len = available.min(buf.len());
+        unsafe {
+            core::ptr::copy_nonoverlapping(virt as *const u8, buf.as_mut_ptr(), len);
+        }
+        addr = addr.checked_add(len).ok_or(EFAULT as u8)?;
+        buf = &mut buf[len..];
+    }
+    Ok(())
+}
+
+fn patch_byte(addr: usize, value: u8) -> Result<(), u8> {
+    let (virt, _) = translate(addr)?;
+    unsafe {
+        (virt as *mut u8).write_volatile(value);
+    }
+    Ok(())
+}
+
+/// Write memory on behalf of GDB. Bytes covered by inserted breakpoints update the saved original
+/// instead, keeping the breakpoint in place.
+fn write_memory(
+    breakpoints: &mut [Option<Breakpoint>; MAX_BREAKPOINTS],
+    addr: usize,
+    data: &[u8],
+) -> Result<(), u8> {
+    // Check the whole range first, to avoid partial writes.
+    let end = addr.checked_add(data.len()).ok_or(EFAULT as u8)?;
+    let mut page = addr & !(PAGE_SIZE - 1);
+    while page < end {
+        translate(page)?;
+        page += PAGE_SIZE;
+    }
+
+    for (i, &value) in data.iter().enumerate() {
+        let byte_addr = addr + i;
+        match breakpoints
+            .iter_mut()
+            .flatten()
+            .find(|bp| bp.addr == byte_addr)
+        {
+            Some(bp) => bp.original = value,
+            None => patch_byte(byte_addr, value)?,
+        }
+    }
+    Ok(())
+}
+
+fn insert_breakpoint(
+    breakpoints: &mut [Option<Breakpoint>; MAX_BREAKPOINTS],
+    addr: usize,
+) -> Result<(), u8> {
+    if breakpoints.iter().flatten().any(|bp| bp.addr == addr) {
+        return Ok(());
+    }
+    let slot = breakpoints
+        .iter_mut()
+        .find(|bp| bp.is_none())
+        .ok_or(ENOSPC as u8)?;
+
+    let mut original = [0];
+    read_memory(addr, &mut original)?;
+    patch_byte(addr, INT3)?;
+    *slot = Some(Breakpoint {
+        addr,
+        original: original[0],
+    });
+    Ok(())
+}
+
+fn remove_breakpoint(
+    breakpoints: &mut [Option<Breakpoint>; MAX_BREAKPOINTS],
+    addr: usize,
+) -> Result<(), u8> {
+    let slot = breakpoints
+        .iter_mut()
+        .find(|bp| bp.is_some_and(|bp| bp.addr == addr))
+        .ok_or(EINVAL as u8)?;
+    let bp = slot.take().unwrap();
+    patch_byte(bp.addr, bp.original)
+}
@@ -0,0 +1,192 @@
+//! The GDB remote serial protocol framing: `$<data>#<checksum>` packets, acknowledged with `+`
+//! or `-`.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::{devices::uart_16550::SerialPort, syscall::io::Pio};
+
+/// Size of the packet buffers, advertised to GDB as the maximum packet size.
+pub const PACKET_SIZE: usize = 0x1000;
+
+/// Set when the serial interrupt handler consumed the `$` starting a packet.
+static PACKET_STARTED: AtomicBool = AtomicBool::new(false);
+
+pub fn set_packet_started() {
+    PACKET_STARTED.store(true, Ordering::SeqCst);
+}
+
+/// The UART used by the stub, polled with interrupts disabled.
+///
+/// This does not use the locked `COM1` and `COM2` statics, since the stub can be entered while
+/// they are held.
+pub struct Connection {
+    port: SerialPort<Pio<u8>>,
+}
+
+impl Connection {
+    pub const fn new(base: u16) -> Self {
+        Self {
+            port: SerialPort::<Pio<u8>>::new(base),
+        }
+    }
+
+    fn getc(&mut self) -> u8 {
+        loop {
+            if let Some(c) = self.port.receive() {
+                return c;
+            }
+            core::hint::spin_loop();
+        }
+    }
+
+    /// Read the next packet into `buf`, returning its length. Packets that do not fit, or have an
+    /// invalid checksum, are rejected with `-` so that GDB retransmits them.
+    pub fn read_packet(&mut self, buf: &mut [u8; PACKET_SIZE]) -> usize {
+        loop {
+            if !PACKET_STARTED.swap(false, Ordering::SeqCst) {
+                while self.getc() != b'$' {}
+            }
+
+            let mut len = 0;
+            let mut checksum = 0_u8;
+            let mut overflow = false;
+            loop {
+                match self.getc() {
+                    b'#' => break,
+                    // GDB restarted the packet.
+                    b'$' => {
+                        len = 0;
+                        checksum = 0;
+                        overflow = false;
+                    }
+                    c => {
+                        checksum = checksum.wrapping_add(c);
+                        match buf.get_mut(len) {
+                            Some(slot) => *slot = c,
+                            None => overflow = true,
+                        }
+                        len += 1;
+                    }
+                }
+            }
+
+            let expected = match (from_hex_digit(self.getc()), from_hex_digit(self.getc())) {
+                (Some(high), Some(low)) => Some(high << 4 | low),
+                _ => None,
+            };
+            if !overflow && expected == Some(checksum) {
+                self.port.send(b'+');
+                return len;
+            }
+            self.port.send(b'-');
+        }
+    }
+
+    /// Send a packet, retransmitting it until GDB acknowledges it.
+    pub fn write_packet(&mut self, data: &[u8]) {
+        let checksum = data.iter().fold(0_u8, |sum, &c| sum.wrapping_add(c));
+        loop {
+            self.port.send(b'$');
+            for &c in data {
+                self.port.send(c);
+            }
+            self.port.send(b'#');
+            self.port.send(HEX_DIGITS[usize::from(checksum >> 4)]);
+            self.port.send(HEX_DIGITS[usize::from(checksum & 0xF)]);
+
+            loop {
+                match self.getc() {
+                    b'+' => return,
+                    b'-' => break,
+                    // GDB gave up waiting and sent a new packet, which is handled as if the
+                    // reply was received.
+                    b'$' => {
+                        set_packet_started();
+                        return;
+                    }
+                    _ => continue,
+                }
+            }
+        }
+    }
+}
+
+const HEX_DIGITS: &[u8; 16] = b"0123456789abcdef";
+
+pub fn from_hex_digit(c: u8) -> Option<u8> {
+    match c {
+        b'0'..=b'9' => Some(c - b'0'),
+        b'a'..=b'f' => Some(c - b'a' + 10),
+        b'A'..=b'F' => Some(c - b'A' + 10),
+        _ => None,
+    }
+}
+
+/// Parse a big-endian hexadecimal number, as used for addresses and lengths.
+pub fn parse_hex(s: &[u8]) -> Option<usize> {
+    if s.is_empty() || s.len() > 2 * core::mem::size_of::<usize>() {
+        return None;
+    }
+    s.iter().try_fold(0_usize, |value, &c| {
+        Some(value << 4 | usize::from(from_hex_digit(c)?))
+    })
+}
+
+/// Decode hexadecimal byte pairs from `s` into `out`, which must be exactly half as long.
+pub fn decode_hex(s: &[u8], out: &mut [u8]) -> Option<()> {
+    if s.len() != out.len() * 2 {
+        return None;
+    }
+    for (pair, byte) in s.chunks_exact(2).zip(out) {
+        *byte = from_hex_digit(pair[0])? << 4 | from_hex_digit(pair[1])?;
+    }
+    Some(())
+}
+
+/// A reply being assembled. Output beyond the packet size is dropped, which the callers avoid by
+/// limiting the lengths they accept.
+pub struct Reply {
+    buf: [u8; PACKET_SIZE],
+    len: usize,
+}
+
+impl Reply {
+    pub const fn new() -> Self {
+        Self {
+            buf: [0; PACKET_SIZE],
+            len: 0,
+        }
+    }
+    pub fn clear(&mut self) {
+        self.len = 0;
+    }
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+    pub fn push(&mut self, c: u8) {
+        if let Some(slot) = self.buf.get_mut(self.len) {
+            *slot = c;
+            self.len += 1;
+        }
+    }
+    pub fn push_str(&mut self, s: &str) {
+        for &c in s.as_bytes() {
+            self.push(c);
+        }
+    }
+    pub fn push_hex(&mut self, byte: u8) {
+        self.push(HEX_DIGITS[usize::from(byte >> 4)]);
+        self.push(HEX_DIGITS[usize::from(byte & 0xF)]);
+    }
+    pub fn push_hex_bytes(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.push_hex(byte);
+        }
+    }
+    /// An error reply with the given errno.
+    pub fn error(&mut self, errno: u8) {
+        self.clear();
+        self.push(b'E');
+        self.push_hex(errno);
+    }
+}
@@ -0,0 +1,124 @@
+//! # CPU hotplug
+//!
+//! Secondary CPUs can be taken out of the scheduler, and brought back later, by writing
+//! `offline <id>` or `online <id>` to `sys:cpu`. A CPU going offline stops picking contexts, and
+//! once it switched to its idle context, releases the contexts that stayed on it so that the other
+//! CPUs can pick them up, moves its timers to the BSP, disarms its timer and parks in a low-power
+//! wait loop, until it is woken up by [`online`]. Contexts whose affinity only allows offline CPUs
+//! run on any other CPU instead.
+//!
+//! The CPU keeps its per-CPU block and idle context while parked, and still takes interrupts on
+//! x86, so that TLB shootdowns and the halt IPI reach it.
+
+use crate::{
+    context::{self, switch},
+    cpu_set::{LogicalCpuId, LogicalCpuSet, RawMask},
+    percpu::PercpuBlock,
+    syscall::error::{Error, Result, EBUSY, ENODEV},
+};
+
+/// CPUs requested to go offline.
+static OFFLINE: LogicalCpuSet = LogicalCpuSet::empty();
+/// CPUs parked in [`park`].
+static PARKED: LogicalCpuSet = LogicalCpuSet::empty();
+
+pub fn is_offline(cpu: LogicalCpuId) -> bool {
+    OFFLINE.atomic_contains(cpu)
+}
+
+/// The CPUs requested to go offline.
+pub fn offline_mask() -> RawMask {
+    OFFLINE.to_raw()
+}
+
+/// Whether `set` contains a CPU that exists and is online.
+pub fn any_online_in(set: &LogicalCpuSet) -> bool {
+    let cpu_count = crate::cpu_count();
+    set.to_raw()
+        .iter()
+        .zip(OFFLINE.to_raw())
+        .enumerate()
+        .any(|(i, (&cpus, offline))| {
+            let present = match cpu_count.saturating_sub(i as u32 * usize::BITS) {
+                0 => 0,
+                n if n >= usize::BITS => !0,
+                n => (1 << n) - 1,
+            };
+            cpus & !offline & present != 0
+        })
+}
+
+fn check_cpu(cpu: LogicalCpuId) -> Result<()> {
+    if cpu == LogicalCpuId::BSP {
+        // Runs the periodic timer, and takes the timers of the offline CPUs.
+        return Err(Error::new(EBUSY));
+    }
+    if cpu.get() >= crate::cpu_count() || crate::percpu::get(cpu).is_none() {
+        return Err(Error::new(ENODEV));
+    }
+    Ok(())
+}
+
+/// Take `cpu` out of the scheduler, returning once it is parked.
+pub fn offline(cpu: LogicalCpuId) -> Result<()> {
+    check_cpu(cpu)?;
+    OFFLINE.atomic_set(cpu);
+    switch::resched(cpu);
+
+    // The caller moves to another CPU when switching away, if it ran on this one.
+    while OFFLINE.atomic_contains(cpu) && !PARKED.atomic_contains(cpu) {
+        context::switch();
+    }
+    Ok(())
+}
+
+/// Bring `cpu` back into the scheduler.
+pub fn online(cpu: LogicalCpuId) -> Result<()> {
+    check_cpu(cpu)?;
+    OFFLINE.atomic_clear(cpu);
+    crate::arch::stop::unpark(cpu);
+    Ok(())
+}
+
+/// Called by the idle loop of this CPU with interrupts disabled, to park it if it is offline.
+pub unsafe fn maybe_park() {
+    let percpu = PercpuBlock::current();
+    let cpu = percpu.cpu_id;
+    if !OFFLINE.atomic_contains(cpu) {
+        return;
+    }
+
+    // Only the idle context runs here now, so that none of them is running.
+    for context_ref in context::contexts().iter() {
+        let Some(context_lock) = context_ref.upgrade() else {
+            continue;
+        };
+        let mut context = context_lock.write();
+        if context.cpu_id == Some(cpu) && !context.running {
+            context.cpu_id = None;
+        }
+    }
+    crate::timer::migrate(LogicalCpuId::BSP);
+    if crate::timer::oneshot() {
+        crate::arch::time::disarm_oneshot();
+    }
+
+    PARKED.atomic_set(cpu);
+    log::info!("CPU {} offline", cpu);
+
+    while OFFLINE.atomic_contains(cpu) {
+        crate::arch::stop::park_wait();
+        crate::suspend::maybe_suspend_ap(cpu);
+    }
+
+    PARKED.atomic_clear(cpu);
+    log::info!("CPU {} online", cpu);
+    crate::timer::program();
+}
+
+/// The CPUs requested to go offline, for `sys:cpu`.
+pub fn offline_cpus() -> impl Iterator<Item = LogicalCpuId> {
+    (0..crate::cpu_count())
+        .map(LogicalCpuId::new)
+        .filter(|&cpu| is_offline(cpu))
+}
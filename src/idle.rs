@@ -0,0 +1,66 @@
+//! Idle state selection. When a CPU has nothing to run, the governor predicts how long it stays
+//! idle from the next deadline of its timers, and enters the deepest idle state of the
+//! architecture whose target residency fits, so that waking up early does not cost more power
+//! than the state saves. How often each state was entered and for how long is recorded per CPU.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::{cpuidle, percpu::PercpuBlock, time, timer};
+
+/// Maximum number of idle states of an architecture.
+pub const MAX_STATES: usize = 8;
+
+pub struct IdleState {
+    pub name: &'static str,
+    /// Shortest predicted idle time, in nanoseconds, for which entering the state is worth it.
+    pub target_residency: u128,
+    /// Architecture specific parameter, such as the MWAIT hint on x86.
+    pub hint: u32,
+}
+
+/// The residency statistics of a CPU.
+#[derive(Default)]
+pub struct PercpuIdle {
+    /// Number of times each state was entered.
+    usage: [AtomicU64; MAX_STATES],
+    /// Nanoseconds spent in each state, including handling the interrupt that ended it.
+    residency: [AtomicU64; MAX_STATES],
+}
+
+impl PercpuIdle {
+    /// Number of times `state` was entered, and the nanoseconds spent in it.
+    pub fn stats(&self, state: usize) -> (u64, u64) {
+        (
+            self.usage[state].load(Ordering::Relaxed),
+            self.residency[state].load(Ordering::Relaxed),
+        )
+    }
+}
+
+/// The idle states of this system, from the shallowest to the deepest.
+pub fn states() -> &'static [IdleState] {
+    cpuidle::states()
+}
+
+/// The deepest state worth entering for `predicted` nanoseconds, or the shallowest one.
+fn select(states: &[IdleState], predicted: u128) -> usize {
+    states
+        .iter()
+        .rposition(|state| state.target_residency <= predicted)
+        .unwrap_or(0)
+}
+
+/// Wait for the next interrupt in the idle state selected by the governor. Must be called with
+/// interrupts disabled, and returns with them enabled, like `interrupt::enable_and_halt`.
+pub unsafe fn idle() {
+    let states = states();
+    let start = time::monotonic();
+    let index = select(states, timer::next_deadline().saturating_sub(start));
+
+    cpuidle::enter(&states[index]);
+
+    let stats = &PercpuBlock::current().idle;
+    let elapsed = time::monotonic().saturating_sub(start);
+    stats.usage[index].fetch_add(1, Ordering::Relaxed);
+    stats.residency[index].fetch_add(elapsed as u64, Ordering::Relaxed);
+}
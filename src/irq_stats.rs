@@ -0,0 +1,84 @@
+//! # Interrupt statistics
+//!
+//! Every interrupt is counted as it is dispatched, per CPU, along with the time of the last one,
+//! to find interrupt storms and interrupts all landing on the same CPU. Interrupts are identified
+//! by their IRQ number as used by the `irq:` scheme, which is the vector minus 32 on x86, and the
+//! virtual IRQ on devicetree platforms.
+//!
+//! The counters are shown in `sys:irq`, together with what handles each IRQ, which is either the
+//! name of a kernel handler, or the names of the processes that opened it through `irq:`.
+
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::{cpu_set::LogicalCpuId, percpu::PercpuBlock, sync::Mutex, time};
+
+/// IRQ numbers are bytes, like in the `irq:` scheme.
+pub const IRQ_COUNT: usize = 256;
+
+/// The counters of one CPU, only written by that CPU.
+pub struct IrqStats {
+    counts: [AtomicU64; IRQ_COUNT],
+    /// Monotonic time of the last interrupt, in nanoseconds.
+    last: [AtomicU64; IRQ_COUNT],
+}
+
+impl IrqStats {
+    pub const fn new() -> Self {
+        Self {
+            counts: [const { AtomicU64::new(0) }; IRQ_COUNT],
+            last: [const { AtomicU64::new(0) }; IRQ_COUNT],
+        }
+    }
+    pub fn count(&self, irq: u8) -> u64 {
+        self.counts[usize::from(irq)].load(Ordering::Relaxed)
+    }
+    pub fn last(&self, irq: u8) -> u64 {
+        self.last[usize::from(irq)].load(Ordering::Relaxed)
+    }
+}
+
+/// Names of the kernel handlers of IRQs.
+static HANDLERS: Mutex<[Option<&'static str>; IRQ_COUNT]> = Mutex::new([None; IRQ_COUNT]);
+
+/// Count an interrupt of `irq` on this CPU, from its dispatch path.
+pub fn count(irq: u8) {
+    let stats = &PercpuBlock::current().irq_stats;
+    let irq = usize::from(irq);
+    stats.counts[irq].store(
+        stats.counts[irq].load(Ordering::Relaxed) + 1,
+        Ordering::Relaxed,
+    );
+    stats.last[irq].store(time::monotonic() as u64, Ordering::Relaxed);
+    crate::entropy::add_interrupt();
+}
+
+/// Count an interrupt received at `vector`, which is not an exception.
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub fn count_vector(vector: u8) {
+    count(vector - 32);
+}
+
+/// Record that `irq` is handled by the kernel, as `name`.
+pub fn set_handler(irq: u8, name: &'static str) {
+    HANDLERS.lock()[usize::from(irq)] = Some(name);
+}
+
+pub fn handler(irq: u8) -> Option<&'static str> {
+    HANDLERS.lock()[usize::from(irq)]
+}
+
+/// The number of interrupts of `irq` on each CPU, and the time of the last one on any CPU.
+pub fn snapshot(irq: u8) -> (Vec<u64>, u64) {
+    let mut last = 0;
+    let counts = (0..crate::cpu_count())
+        .map(|id| {
+            let Some(percpu) = crate::percpu::get(LogicalCpuId::new(id)) else {
+                return 0;
+            };
+            last = last.max(percpu.irq_stats.last(irq));
+            percpu.irq_stats.count(irq)
+        })
+        .collect();
+    (counts, last)
+}
@@ -0,0 +1,63 @@
+//! # Kernel address space layout randomization
+//!
+//! With the `kaslr` feature, the kernel moves its image to a random address right after entering
+//! `kstart`, before anything stores pointers into it, so that exploits cannot rely on where its
+//! code and data are. The image is linked with `--emit-relocs` (see the Makefile), and moving it
+//! is a matter of adjusting its absolute references by the slide, the distance from the address
+//! it was linked at. How the new range is mapped, and where the entropy comes from, is up to the
+//! architecture, currently only x86_64.
+//!
+//! The physmap is not randomized, as its offset is a constant of RMM.
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::KERNEL_OFFSET;
+
+/// Distance of the kernel image from its link-time address.
+static SLIDE: AtomicUsize = AtomicUsize::new(0);
+
+pub fn slide() -> usize {
+    SLIDE.load(Ordering::Relaxed)
+}
+
+/// The address the kernel image starts at.
+pub fn kernel_offset() -> usize {
+    KERNEL_OFFSET + slide()
+}
+
+/// The current address of `addr` in the kernel image, which may have been computed at the
+/// link-time address, before relocating.
+#[cfg(all(feature = "kaslr", target_arch = "x86_64"))]
+pub fn relocated(addr: usize) -> usize {
+    // The image never moves onto its link-time range, so any address below its start is from
+    // before.
+    if (KERNEL_OFFSET..kernel_offset()).contains(&addr) {
+        addr + slide()
+    } else {
+        addr
+    }
+}
+
+/// Pick a slide for an image of `size` bytes, a multiple of `align` such that the image stays in
+/// the first `window` bytes from `KERNEL_OFFSET`, without overlapping the link-time range, or
+/// zero if it does not fit.
+#[cfg(all(feature = "kaslr", target_arch = "x86_64"))]
+pub fn choose_slide(seed: u64, size: usize, align: usize, window: usize) -> usize {
+    let image_slots = size.div_ceil(align);
+    let window_slots = window / align;
+    let Some(choices) = window_slots.checked_sub(2 * image_slots) else {
+        return 0;
+    };
+    // Mix the seed, as the low bits of a timestamp are all it may contain.
+    let mut mixed = seed;
+    mixed = (mixed ^ (mixed >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    mixed = (mixed ^ (mixed >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    mixed ^= mixed >> 31;
+
+    (image_slots + (mixed % (choices as u64 + 1)) as usize) * align
+}
+
+#[cfg(all(feature = "kaslr", target_arch = "x86_64"))]
+pub(crate) fn set_slide(slide: usize) {
+    SLIDE.store(slide, Ordering::Relaxed);
+}
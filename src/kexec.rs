@@ -0,0 +1,571 @@
+//! # kexec
+//!
+//! Booting another kernel from the running one, without going through the firmware and the
+//! bootloader, either as a fast reboot or to start a crash kernel when this one panics.
+//!
+//! The kernel is loaded flat into contiguous physical memory, like the bootloader does, so memory
+//! for the images must be reserved at boot with `KEXEC_MEMORY=<MiB>` in the environment. An image
+//! consists of the kernel, and optionally an initfs and an environment, which default to those this
+//! kernel was booted with. The EFI variables are left out of the environment, as the firmware
+//! cannot be given another virtual address map.
+//!
+//! Everything the new kernel needs is prepared when an image is loaded: the `KernelArgs` the
+//! bootloader would pass, a copy of the memory map it was given, a stack, and page tables mapping
+//! the lower half identity, the physmap and the image at `KERNEL_OFFSET`. Starting it then only
+//! takes halting the other CPUs and jumping to a stub, executing in the physmap, which both page
+//! tables map, and which switches to the new page table.
+//!
+//! The crash kernel is only given the reserved memory as free memory, so that the memory of the
+//! kernel that panicked is left intact for it to inspect.
+
+use alloc::vec::Vec;
+use core::{
+    mem::size_of,
+    ptr, slice, str,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use spin::Once;
+
+#[cfg(feature = "la57")]
+use crate::arch::consts::PML5_SIZE;
+use crate::{
+    arch::consts::PHYS_PML4,
+    elf::{header::EM_X86_64, program_header::PT_LOAD, Elf},
+    lockdown,
+    memory::KernelMapper,
+    paging::{PageFlags, PhysicalAddress, RmmA, RmmArch, VirtualAddress, PAGE_SIZE},
+    start::KernelArgs,
+    startup::memory::{
+        bootloader_areas, reserve_free, BootloaderMemoryEntry, BootloaderMemoryKind,
+    },
+    sync::Mutex,
+    syscall::error::{Error, Result, EBUSY, EINVAL, ENOEXEC, ENOMEM, ENOSPC},
+    KERNEL_OFFSET, PHYS_OFFSET,
+};
+
+/// Stack of the new kernel, which it keeps using as the stack of its first context.
+const STACK_SIZE: usize = 128 * 1024;
+
+const LARGE_PAGE_SIZE: usize = 2 << 20;
+const GIGABYTE: usize = 1 << 30;
+const ENTRY_PRESENT: u64 = 1 << 0;
+const ENTRY_WRITABLE: u64 = 1 << 1;
+const ENTRY_HUGE: u64 = 1 << 7;
+
+/// The memory map of the bootloader has at most this many entries.
+const MAX_AREAS: usize = 512;
+
+core::arch::global_asm!(
+    "
+    .globl __kexec_stub_start
+    .globl __kexec_stub_end
+__kexec_stub_start:
+    mov cr3, rsi
+    // Flush the global pages of this kernel, and turn off PCIDs, which the bootloader does not
+    // use either.
+    mov rax, cr4
+    btr rax, 7
+    btr rax, 17
+    mov cr4, rax
+
+    mov rsp, rdx
+    xor ebp, ebp
+    call rcx
+2:
+    cli
+    hlt
+    jmp 2b
+__kexec_stub_end:
+"
+);
+
+extern "C" {
+    fn __kexec_stub_start();
+    fn __kexec_stub_end();
+}
+
+/// Called with the arguments, page table, stack and entry point of the new kernel.
+type Stub = unsafe extern "C" fn(usize, usize, usize, usize) -> !;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Slot {
+    /// Started when rebooting through `kexec:exec`.
+    Reboot = 0,
+    /// Started when this kernel panics.
+    Crash = 1,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Part {
+    Kernel,
+    Initfs,
+    Env,
+}
+
+/// What this kernel was booted with.
+struct Boot {
+    env: &'static [u8],
+    acpi_rsdp: (usize, usize),
+    bootstrap: (usize, usize),
+}
+
+/// Physical memory in the reserved memory.
+#[derive(Clone, Copy)]
+struct Range {
+    base: usize,
+    len: usize,
+}
+
+impl Range {
+    fn end(&self) -> usize {
+        self.base + self.len.next_multiple_of(PAGE_SIZE)
+    }
+
+    unsafe fn bytes(&self) -> &'static mut [u8] {
+        slice::from_raw_parts_mut(
+            RmmA::phys_to_virt(PhysicalAddress::new(self.base)).data() as *mut u8,
+            self.len,
+        )
+    }
+}
+
+/// The arguments the stub is called with.
+#[derive(Clone, Copy)]
+struct Start {
+    args: usize,
+    page_table: usize,
+    stack_end: usize,
+    entry: usize,
+}
+
+struct Image {
+    /// The kernel, and the address of its entry point.
+    kernel: Option<(Range, usize)>,
+    initfs: Option<Range>,
+    env: Option<Vec<u8>>,
+    /// The page tables, arguments, stack and environment, prepared once the kernel is loaded.
+    control: Option<(Range, Start)>,
+}
+
+impl Image {
+    const EMPTY: Self = Self {
+        kernel: None,
+        initfs: None,
+        env: None,
+        control: None,
+    };
+
+    fn ranges(&self) -> impl Iterator<Item = Range> {
+        [
+            self.kernel.map(|(range, _)| range),
+            self.initfs,
+            self.control.map(|(range, _)| range),
+        ]
+        .into_iter()
+        .flatten()
+    }
+}
+
+static BOOT: Once<Boot> = Once::new();
+/// The reserved memory, whose first page holds the stub.
+static RESERVED: Once<Range> = Once::new();
+/// The physmap address of the stub, once copied.
+static STUB: Once<usize> = Once::new();
+static IMAGES: Mutex<[Image; 2]> = Mutex::new([Image::EMPTY, Image::EMPTY]);
+/// Set while an image is loaded, which is done without holding the lock on [`IMAGES`], as it
+/// copies from userspace.
+static LOADING: AtomicBool = AtomicBool::new(false);
+
+/// Remember what this kernel was booted with, and reserve the memory requested with
+/// `KEXEC_MEMORY`. Called before the frame allocator is initialized.
+pub fn init(env: &'static [u8], acpi_rsdp: (usize, usize), bootstrap: (usize, usize)) {
+    BOOT.call_once(|| Boot {
+        env,
+        acpi_rsdp,
+        bootstrap,
+    });
+
+    let Some(size) = env_lines(env)
+        .find_map(|line| line.strip_prefix("KEXEC_MEMORY="))
+        .and_then(|mib| mib.parse::<usize>().ok())
+        .filter(|&mib| mib != 0)
+        .map(|mib| mib << 20)
+    else {
+        return;
+    };
+    match reserve_free(size) {
+        Some(base) => {
+            log::info!("kexec: reserved {:#x}:{:#x}", base, base + size);
+            RESERVED.call_once(|| Range { base, len: size });
+        }
+        None => log::warn!("kexec: failed to reserve {} bytes", size),
+    }
+}
+
+fn env_lines(env: &[u8]) -> impl Iterator<Item = &str> {
+    str::from_utf8(env).unwrap_or("").lines()
+}
+
+/// Whether memory was reserved for the images.
+pub fn available() -> bool {
+    RESERVED.get().is_some()
+}
+
+/// The length of what was loaded as `part` of the image in `slot`.
+pub fn loaded_len(slot: Slot, part: Part) -> usize {
+    let images = IMAGES.lock();
+    let image = &images[slot as usize];
+    match part {
+        Part::Kernel => image.kernel.map_or(0, |(range, _)| range.len),
+        Part::Initfs => image.initfs.map_or(0, |range| range.len),
+        Part::Env => image.env.as_ref().map_or(0, Vec::len),
+    }
+}
+
+/// Whether the image in `slot` can be started.
+pub fn is_loaded(slot: Slot) -> bool {
+    IMAGES.lock()[slot as usize].control.is_some()
+}
+
+/// Replace `part` of the image in `slot` with `len` bytes, copied by `fill`, or unload it if `len`
+/// is zero. The part is unloaded if loading it fails.
+pub fn load(
+    slot: Slot,
+    part: Part,
+    len: usize,
+    fill: impl FnOnce(&mut [u8]) -> Result<()>,
+) -> Result<()> {
+    lockdown::check(lockdown::Reason::Kexec)?;
+    if LOADING.swap(true, Ordering::Acquire) {
+        return Err(Error::new(EBUSY));
+    }
+    let result = load_inner(slot, part, len, fill);
+    LOADING.store(false, Ordering::Release);
+    result
+}
+
+fn load_inner(
+    slot: Slot,
+    part: Part,
+    len: usize,
+    fill: impl FnOnce(&mut [u8]) -> Result<()>,
+) -> Result<()> {
+    let range = {
+        let mut images = IMAGES.lock();
+        let image = &mut images[slot as usize];
+        image.control = None;
+        match part {
+            Part::Kernel => image.kernel = None,
+            Part::Initfs => image.initfs = None,
+            Part::Env => image.env = None,
+        }
+        if len == 0 || part == Part::Env {
+            None
+        } else {
+            Some(Range {
+                base: allocate(&images, len).ok_or(Error::new(ENOSPC))?,
+                len,
+            })
+        }
+    };
+
+    match (part, range) {
+        (Part::Env, _) if len != 0 => {
+            let mut env = vec![0; len];
+            fill(&mut env)?;
+            IMAGES.lock()[slot as usize].env = Some(env);
+        }
+        (Part::Kernel, Some(range)) => {
+            let bytes = unsafe { range.bytes() };
+            fill(bytes)?;
+            let entry = validate(bytes)?;
+            IMAGES.lock()[slot as usize].kernel = Some((range, entry));
+        }
+        (Part::Initfs, Some(range)) => {
+            fill(unsafe { range.bytes() })?;
+            IMAGES.lock()[slot as usize].initfs = Some(range);
+        }
+        _ => (),
+    }
+
+    let mut images = IMAGES.lock();
+    unsafe { prepare(&mut images, slot) }
+}
+
+/// Find room for `len` bytes in the reserved memory, besides what the images use.
+fn allocate(images: &[Image; 2], len: usize) -> Option<usize> {
+    let reserved = RESERVED.get()?;
+    let size = len.next_multiple_of(PAGE_SIZE);
+    let used = || images.iter().flat_map(Image::ranges);
+
+    core::iter::once(reserved.base + PAGE_SIZE)
+        .chain(used().map(|range| range.end()))
+        .filter(|&start| {
+            start + size <= reserved.end()
+                && used().all(|range| start + size <= range.base || range.end() <= start)
+        })
+        .min()
+}
+
+/// Check the kernel is an x86_64 executable, laid out the way the bootloader loads it, and return
+/// its entry point.
+fn validate(kernel: &[u8]) -> Result<usize> {
+    let elf = Elf::from(kernel).map_err(|_| Error::new(ENOEXEC))?;
+    if elf.machine() != EM_X86_64 {
+        return Err(Error::new(ENOEXEC));
+    }
+    // The new page table maps at most a gigabyte at KERNEL_OFFSET.
+    if kernel.len() > GIGABYTE {
+        return Err(Error::new(EINVAL));
+    }
+    let image = KERNEL_OFFSET..KERNEL_OFFSET + kernel.len();
+
+    // Each segment must be at the same offset from KERNEL_OFFSET as in the file.
+    for segment in elf.segments().filter(|segment| segment.p_type == PT_LOAD) {
+        let flat = (segment.p_vaddr as usize).checked_sub(KERNEL_OFFSET)
+            == Some(segment.p_offset as usize);
+        let end = (segment.p_offset as usize).checked_add(segment.p_memsz as usize);
+        if !flat || end.map_or(true, |end| end > kernel.len()) {
+            return Err(Error::new(ENOEXEC));
+        }
+    }
+    if !image.contains(&elf.entry()) {
+        return Err(Error::new(ENOEXEC));
+    }
+    Ok(elf.entry())
+}
+
+/// The environment of this boot, to start `slot` with when none was loaded.
+fn default_env(slot: Slot) -> Vec<u8> {
+    let env = BOOT.get().map_or(&[][..], |boot| boot.env);
+    let mut default = Vec::new();
+    for line in env_lines(env) {
+        // The crash kernel only has the reserved memory, which it should not reserve from again.
+        if line.starts_with("EFI_") || (slot == Slot::Crash && line.starts_with("KEXEC_MEMORY=")) {
+            continue;
+        }
+        default.extend_from_slice(line.as_bytes());
+        default.push(b'\n');
+    }
+    default
+}
+
+/// The memory map to give the kernel started from `slot`.
+fn areas(slot: Slot) -> Vec<BootloaderMemoryEntry> {
+    let mut areas = bootloader_areas().collect::<Vec<_>>();
+    if slot == Slot::Crash {
+        areas.retain(|area| area.kind != BootloaderMemoryKind::Free);
+        if let Some(reserved) = RESERVED.get() {
+            areas.push(BootloaderMemoryEntry {
+                base: reserved.base as u64,
+                size: reserved.len as u64,
+                kind: BootloaderMemoryKind::Free,
+            });
+        }
+    }
+    areas.truncate(MAX_AREAS);
+    areas
+}
+
+/// Copy the stub to the first page of the reserved memory, and make it executable in the physmap.
+unsafe fn install_stub() -> Result<usize> {
+    if let Some(&stub) = STUB.get() {
+        return Ok(stub);
+    }
+    let reserved = RESERVED.get().ok_or(Error::new(ENOMEM))?;
+    let stub = RmmA::phys_to_virt(PhysicalAddress::new(reserved.base)).data();
+    let code = slice::from_raw_parts(
+        __kexec_stub_start as usize as *const u8,
+        __kexec_stub_end as usize - __kexec_stub_start as usize,
+    );
+    ptr::copy_nonoverlapping(code.as_ptr(), stub as *mut u8, code.len());
+
+    // Nothing else accesses the reserved memory, so no other CPU can have this page in its TLB.
+    let mut mapper = KernelMapper::lock();
+    mapper
+        .get_mut()
+        .ok_or(Error::new(EBUSY))?
+        .remap(
+            VirtualAddress::new(stub),
+            PageFlags::new()
+                .execute(true)
+                .global(cfg!(not(feature = "pti"))),
+        )
+        .ok_or(Error::new(ENOMEM))?
+        .flush();
+
+    Ok(*STUB.call_once(|| stub))
+}
+
+/// Prepare the page tables, arguments, stack and environment of the image in `slot`, if its kernel
+/// is loaded.
+unsafe fn prepare(images: &mut [Image; 2], slot: Slot) -> Result<()> {
+    let Some((kernel, entry)) = images[slot as usize].kernel else {
+        return Ok(());
+    };
+    let boot = BOOT.get().ok_or(Error::new(ENOMEM))?;
+    install_stub()?;
+
+    let env = match &images[slot as usize].env {
+        Some(env) => env.clone(),
+        None => default_env(slot),
+    };
+    let bootstrap = images[slot as usize]
+        .initfs
+        .map_or(boot.bootstrap, |initfs| (initfs.base, initfs.len));
+    let areas = areas(slot);
+
+    // The lower half and the physmap map all RAM with 2 MiB pages, and share their PDPT.
+    let ram_end = areas
+        .iter()
+        .filter(|area| {
+            matches!(
+                area.kind,
+                BootloaderMemoryKind::Free | BootloaderMemoryKind::Reclaim
+            )
+        })
+        .map(|area| (area.base + area.size) as usize)
+        .max()
+        .unwrap_or(0);
+    let gigabytes = ram_end.div_ceil(GIGABYTE);
+    if gigabytes > 512 {
+        return Err(Error::new(ENOMEM));
+    }
+    let kernel_tables = kernel.len.div_ceil(LARGE_PAGE_SIZE);
+    // PML4, low PDPT, kernel PDPT, kernel PD, low PDs and kernel PTs, and a PML5 with 5-level
+    // paging, which cannot be turned off again in long mode.
+    let table_count = 4 + gigabytes + kernel_tables + usize::from(cfg!(feature = "la57"));
+
+    let tables_size = table_count * PAGE_SIZE;
+    let args_size = (size_of::<KernelArgs>() + MAX_AREAS * size_of::<BootloaderMemoryEntry>())
+        .next_multiple_of(PAGE_SIZE);
+    let stack_offset = tables_size + args_size;
+    let env_offset = stack_offset + STACK_SIZE;
+    let len = env_offset + env.len();
+
+    let base = allocate(images, len).ok_or(Error::new(ENOSPC))?;
+    let control = Range { base, len };
+    control.bytes().fill(0);
+
+    // Page tables
+    let table = |i: usize| {
+        RmmA::phys_to_virt(PhysicalAddress::new(base + i * PAGE_SIZE)).data() as *mut [u64; 512]
+    };
+    let entry_to = |i: usize| (base + i * PAGE_SIZE) as u64 | ENTRY_PRESENT | ENTRY_WRITABLE;
+    let (pml4, low_pdpt, kernel_pdpt, kernel_pd, low_pds) = (0, 1, 2, 3, 4);
+    let kernel_pts = low_pds + gigabytes;
+
+    #[cfg(not(feature = "la57"))]
+    let top = pml4;
+    // The PML4 is shared by the three PML5 entries: the identity map and the physmap both start at
+    // its first entry, and the kernel is in its last one.
+    #[cfg(feature = "la57")]
+    let top = {
+        let pml5 = kernel_pts + kernel_tables;
+        (*table(pml5))[0] = entry_to(pml4);
+        (*table(pml5))[(PHYS_OFFSET / PML5_SIZE) % 512] = entry_to(pml4);
+        (*table(pml5))[511] = entry_to(pml4);
+        pml5
+    };
+    (*table(pml4))[0] = entry_to(low_pdpt);
+    (*table(pml4))[PHYS_PML4] = entry_to(low_pdpt);
+    (*table(pml4))[511] = entry_to(kernel_pdpt);
+    for gigabyte in 0..gigabytes {
+        (*table(low_pdpt))[gigabyte] = entry_to(low_pds + gigabyte);
+        for (i, entry) in (*table(low_pds + gigabyte)).iter_mut().enumerate() {
+            *entry = (gigabyte * GIGABYTE + i * LARGE_PAGE_SIZE) as u64
+                | ENTRY_PRESENT
+                | ENTRY_WRITABLE
+                | ENTRY_HUGE;
+        }
+    }
+    (*table(kernel_pdpt))[(KERNEL_OFFSET / GIGABYTE) % 512] = entry_to(kernel_pd);
+    for page in 0..kernel.len.div_ceil(PAGE_SIZE) {
+        let pt = kernel_pts + page / 512;
+        if page % 512 == 0 {
+            (*table(kernel_pd))[page / 512] = entry_to(pt);
+        }
+        (*table(pt))[page % 512] =
+            (kernel.base + page * PAGE_SIZE) as u64 | ENTRY_PRESENT | ENTRY_WRITABLE;
+    }
+
+    // Memory map, and the environment
+    let args_phys = base + tables_size;
+    let areas_phys = args_phys + size_of::<KernelArgs>();
+    ptr::copy_nonoverlapping(
+        areas.as_ptr(),
+        RmmA::phys_to_virt(PhysicalAddress::new(areas_phys)).data() as *mut BootloaderMemoryEntry,
+        areas.len(),
+    );
+    control.bytes()[env_offset..].copy_from_slice(&env);
+
+    // The stack, page tables and arguments are reserved by the new kernel as its stack.
+    let args = KernelArgs {
+        kernel_base: kernel.base as u64,
+        kernel_size: kernel.len as u64,
+        stack_base: base as u64,
+        stack_size: env_offset as u64,
+        env_base: (base + env_offset) as u64,
+        env_size: env.len() as u64,
+        acpi_rsdp_base: boot.acpi_rsdp.0 as u64,
+        acpi_rsdp_size: boot.acpi_rsdp.1 as u64,
+        areas_base: (PHYS_OFFSET + areas_phys) as u64,
+        areas_size: (areas.len() * size_of::<BootloaderMemoryEntry>()) as u64,
+        bootstrap_base: bootstrap.0 as u64,
+        bootstrap_size: bootstrap.1 as u64,
+        kaslr_seed: crate::entropy::random_u64(),
+    };
+    (RmmA::phys_to_virt(PhysicalAddress::new(args_phys)).data() as *mut KernelArgs).write(args);
+
+    images[slot as usize].control = Some((
+        control,
+        Start {
+            args: PHYS_OFFSET + args_phys,
+            page_table: base + top * PAGE_SIZE,
+            stack_end: PHYS_OFFSET + base + env_offset,
+            entry,
+        },
+    ));
+    Ok(())
+}
+
+/// Halt the other CPUs, and start a kernel prepared by [`prepare`].
+unsafe fn start(start: Start) -> ! {
+    crate::stop::halt_other_cpus();
+    crate::interrupt::disable();
+
+    let stub: Stub = core::mem::transmute(*STUB.get().expect("kexec stub not installed"));
+    stub(start.args, start.page_table, start.stack_end, start.entry)
+}
+
+/// Start the kernel loaded to reboot into, or reset if there is none. Called at the end of
+/// [`crate::shutdown::kexec`].
+pub unsafe fn exec() -> ! {
+    let prepared = IMAGES.lock()[Slot::Reboot as usize]
+        .control
+        .map(|(_, start)| start);
+    match prepared {
+        Some(prepared) => {
+            log::info!("kexec: starting the loaded kernel");
+            ::log::logger().flush();
+            start(prepared)
+        }
+        None => crate::stop::kreset(),
+    }
+}
+
+/// Start the crash kernel, if one is loaded. Called when panicking.
+pub unsafe fn crash() {
+    // The lock may be held by the context that panicked.
+    let Some(images) = IMAGES.try_lock() else {
+        return;
+    };
+    let Some((_, prepared)) = images[Slot::Crash as usize].control else {
+        return;
+    };
+    drop(images);
+
+    println!("Starting crash kernel");
+    start(prepared)
+}
@@ -0,0 +1,210 @@
+//! # Kernel log
+//!
+//! Messages logged through the `log` crate are stored as binary records in a fixed-size ring
+//! buffer, with their level, CPU and time, besides being printed to the console. A dmesg or
+//! journal daemon reads them through the `klog:` scheme, each handle from where it stopped, and
+//! blocks until new records arrive. When the ring is full, the oldest records are overwritten, and
+//! readers that were behind skip to the oldest remaining record, seeing the gap in the sequence
+//! numbers.
+//!
+//! Readers are woken up from the timer tick rather than by the logger, which may run with any lock
+//! held.
+
+use alloc::{boxed::Box, vec, vec::Vec};
+use core::{
+    fmt::{self, Write},
+    mem,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use crate::{
+    percpu::PercpuBlock,
+    sync::{Mutex, WaitCondition},
+    syscall::error::{Error, Result, EINTR, EINVAL, ENOMEM},
+    time,
+};
+
+/// Size of the ring buffer, a power of two.
+const RING_SIZE: usize = 256 * 1024;
+/// Longest message stored in a record, longer messages are truncated.
+const MAX_MESSAGE_LEN: usize = 512;
+
+/// The header of a record, as read from the `klog:` scheme. It is followed by the message, padded
+/// to a multiple of 8 bytes.
+#[derive(Clone, Copy, Debug, Default)]
+#[repr(C)]
+pub struct RecordHeader {
+    /// Increased by one for every record.
+    pub seq: u64,
+    /// Monotonic time in nanoseconds.
+    pub time: u64,
+    /// The `log::Level`, from 1 for errors to 5 for trace messages.
+    pub level: u8,
+    pub _rsvd: u8,
+    pub cpu: u16,
+    /// Length of the message.
+    pub len: u32,
+}
+
+const HEADER_SIZE: usize = mem::size_of::<RecordHeader>();
+
+struct Ring {
+    data: Box<[u8]>,
+    /// Position after the newest record. Positions only increase, and are taken modulo the size.
+    head: u64,
+    /// Position of the oldest record.
+    tail: u64,
+    next_seq: u64,
+}
+
+impl Ring {
+    fn copy_in(&mut self, pos: u64, bytes: &[u8]) {
+        for (i, &b) in bytes.iter().enumerate() {
+            self.data[(pos as usize + i) % RING_SIZE] = b;
+        }
+    }
+    fn copy_out(&self, pos: u64, bytes: &mut [u8]) {
+        for (i, b) in bytes.iter_mut().enumerate() {
+            *b = self.data[(pos as usize + i) % RING_SIZE];
+        }
+    }
+    fn header(&self, pos: u64) -> RecordHeader {
+        let mut bytes = [0_u8; HEADER_SIZE];
+        self.copy_out(pos, &mut bytes);
+        unsafe { mem::transmute::<[u8; HEADER_SIZE], RecordHeader>(bytes) }
+    }
+    fn record_size(header: &RecordHeader) -> u64 {
+        (HEADER_SIZE + (header.len as usize).next_multiple_of(8)) as u64
+    }
+    fn push(&mut self, mut header: RecordHeader, message: &[u8]) {
+        header.seq = self.next_seq;
+        self.next_seq += 1;
+
+        let size = Self::record_size(&header);
+        while self.head + size - self.tail > RING_SIZE as u64 {
+            self.tail += Self::record_size(&self.header(self.tail));
+        }
+
+        let bytes = unsafe { mem::transmute::<RecordHeader, [u8; HEADER_SIZE]>(header) };
+        self.copy_in(self.head, &bytes);
+        self.copy_in(self.head + HEADER_SIZE as u64, message);
+        self.head += size;
+    }
+}
+
+static RING: Mutex<Option<Ring>> = Mutex::new(None);
+/// Set when records were added since readers were last woken up.
+static PENDING: AtomicBool = AtomicBool::new(false);
+static READERS: WaitCondition = WaitCondition::new();
+
+/// Allocate the ring buffer. Records logged before are only printed.
+pub fn init() {
+    *RING.lock() = Some(Ring {
+        data: vec![0; RING_SIZE].into_boxed_slice(),
+        head: 0,
+        tail: 0,
+        next_seq: 0,
+    });
+}
+
+/// A message truncated to [`MAX_MESSAGE_LEN`].
+struct Message {
+    buf: [u8; MAX_MESSAGE_LEN],
+    len: usize,
+}
+
+impl Write for Message {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let count = s.len().min(MAX_MESSAGE_LEN - self.len);
+        self.buf[self.len..self.len + count].copy_from_slice(&s.as_bytes()[..count]);
+        self.len += count;
+        Ok(())
+    }
+}
+
+/// Store a record of `record`, called by the logger.
+pub fn record(record: &log::Record) {
+    let mut message = Message {
+        buf: [0; MAX_MESSAGE_LEN],
+        len: 0,
+    };
+    let _ = write!(message, "{}: {}", record.target(), record.args());
+
+    if let Some(ref mut ring) = *RING.lock() {
+        let header = RecordHeader {
+            seq: 0,
+            time: time::monotonic() as u64,
+            level: record.level() as u8,
+            _rsvd: 0,
+            cpu: PercpuBlock::current().cpu_id.get() as u16,
+            len: message.len as u32,
+        };
+        ring.push(header, &message.buf[..message.len]);
+        PENDING.store(true, Ordering::Release);
+    }
+}
+
+/// Whether there are records at or after `pos`.
+pub fn has_records(pos: u64) -> bool {
+    RING.lock().as_ref().is_some_and(|ring| ring.head > pos)
+}
+
+/// Copy whole records at or after `pos` of at most `max_level`, up to `max_len` bytes, advancing
+/// `pos` past them. Blocks while there are none if `block` is set.
+pub fn read(pos: &mut u64, max_level: u8, max_len: usize, block: bool) -> Result<Vec<u8>> {
+    // Reserved up front, rather than with the ring locked, which blocks every CPU logging.
+    let max_len = max_len.min(RING_SIZE);
+    let mut records = Vec::new();
+    records
+        .try_reserve_exact(max_len)
+        .map_err(|_| Error::new(ENOMEM))?;
+
+    loop {
+        let guard = RING.lock();
+        let Some(ref ring) = *guard else {
+            return Ok(records);
+        };
+        // Records that were overwritten are skipped.
+        *pos = (*pos).max(ring.tail);
+
+        while *pos < ring.head {
+            let header = ring.header(*pos);
+            let size = Ring::record_size(&header) as usize;
+            if header.level <= max_level {
+                if records.len() + size > max_len {
+                    break;
+                }
+                let start = records.len();
+                records.resize(start + size, 0);
+                ring.copy_out(*pos, &mut records[start..]);
+            }
+            *pos += size as u64;
+        }
+
+        if records.is_empty() && *pos < ring.head {
+            // The next record does not fit.
+            return Err(Error::new(EINVAL));
+        }
+        if !records.is_empty() || !block {
+            return Ok(records);
+        }
+        if !READERS.wait(guard, "klog::read") {
+            return Err(Error::new(EINTR));
+        }
+    }
+}
+
+/// Drop all records, so that readers only see those logged afterwards.
+pub fn clear() {
+    if let Some(ref mut ring) = *RING.lock() {
+        ring.tail = ring.head;
+    }
+}
+
+/// Wake up readers waiting for records, from the timer tick.
+pub fn wake_readers() {
+    if PENDING.swap(false, Ordering::Acquire) {
+        READERS.notify();
+        crate::scheme::klog::trigger_events();
+    }
+}
@@ -0,0 +1,118 @@
+//! # Kernel symbol table
+//!
+//! Once the kernel is linked, the build extracts its functions with `nm`, and embeds them in the
+//! `.ksyms` section of the kernel image (see the Makefile). Each line of the table holds the
+//! address and size of a function in hexadecimal, followed by its mangled name, and the lines
+//! are sorted by address, so that a lookup can bisect the table in place, without allocating.
+//!
+//! The section is not loaded, but the whole kernel image is mapped at `KERNEL_OFFSET`, plus the
+//! KASLR slide, which is subtracted from the addresses looked up, as the table holds link-time
+//! addresses. Images built without it fall back to scanning `.symtab`.
+
+use core::{slice, sync::atomic::Ordering};
+use goblin::elf::{section_header::SHT_SYMTAB, sym};
+use rustc_demangle::{demangle, Demangle};
+
+use crate::{elf::Elf, kaslr, start::KERNEL_SIZE};
+
+/// A kernel function.
+#[derive(Clone, Copy, Debug)]
+pub struct Symbol {
+    /// Mangled name.
+    pub name: &'static str,
+    pub start: usize,
+}
+
+impl Symbol {
+    pub fn demangled(&self) -> Demangle<'static> {
+        demangle(self.name)
+    }
+}
+
+fn image() -> Option<Elf<'static>> {
+    let data = unsafe {
+        slice::from_raw_parts(
+            kaslr::kernel_offset() as *const u8,
+            KERNEL_SIZE.load(Ordering::SeqCst),
+        )
+    };
+    Elf::from(data).ok()
+}
+
+/// Find the function containing `addr`.
+pub fn lookup(addr: usize) -> Option<Symbol> {
+    let elf = image()?;
+    let link_addr = addr.wrapping_sub(kaslr::slide());
+    let symbol = match elf
+        .sections()
+        .find(|section| elf.section_name(section) == Some(".ksyms"))
+        .and_then(|section| elf.section_data(section))
+        .filter(|table| !table.is_empty())
+    {
+        Some(table) => lookup_table(table, link_addr),
+        None => lookup_symtab(&elf, link_addr),
+    }?;
+    Some(Symbol {
+        start: symbol.start + kaslr::slide(),
+        ..symbol
+    })
+}
+
+/// Parse a line of the table into the address, size and name of a function.
+fn parse_line(line: &'static [u8]) -> Option<(usize, usize, &'static str)> {
+    let line = core::str::from_utf8(line).ok()?;
+    let mut fields = line.splitn(3, ' ');
+    let start = usize::from_str_radix(fields.next()?, 16).ok()?;
+    let size = usize::from_str_radix(fields.next()?, 16).ok()?;
+    Some((start, size, fields.next()?))
+}
+
+fn lookup_table(table: &'static [u8], addr: usize) -> Option<Symbol> {
+    // Bisect over the byte offsets, looking at the line each falls in, for the last function
+    // starting at or before `addr`.
+    let mut found = None;
+    let (mut low, mut high) = (0, table.len());
+    while low < high {
+        let mid = low + (high - low) / 2;
+        let line_start = table[..mid]
+            .iter()
+            .rposition(|&b| b == b'\n')
+            .map_or(0, |i| i + 1);
+        let line_end = table[mid..]
+            .iter()
+            .position(|&b| b == b'\n')
+            .map_or(table.len(), |i| mid + i);
+
+        let (start, size, name) = parse_line(&table[line_start..line_end])?;
+        if start <= addr {
+            found = Some((start, size, name));
+            low = line_end + 1;
+        } else {
+            high = line_start;
+        }
+    }
+
+    let (start, size, name) = found?;
+    // Functions without a size, such as those written in assembly, extend to the next one.
+    if size != 0 && addr - start >= size {
+        return None;
+    }
+    Some(Symbol { name, start })
+}
+
+fn lookup_symtab(elf: &Elf<'static>, addr: usize) -> Option<Symbol> {
+    let symtab = elf
+        .sections()
+        .find(|section| section.sh_type == SHT_SYMTAB)?;
+    let strtab = elf.sections().nth(symtab.sh_link as usize)?;
+
+    let symbol = elf.symbols()?.find(|symbol| {
+        sym::st_type(symbol.st_info) == sym::STT_FUNC
+            && addr >= symbol.st_value as usize
+            && addr - (symbol.st_value as usize) < symbol.st_size as usize
+    })?;
+    Some(Symbol {
+        name: elf.string(strtab, symbol.st_name as usize)?,
+        start: symbol.st_value as usize,
+    })
+}
@@ -0,0 +1,1332 @@
+use alloc::{sync::Arc, vec::Vec};
+use core::num::NonZeroUsize;
+
+use crate::{
+    common::try_alloc,
+    context::{
+        self,
+        caps::{self, Capabilities},
+        fault::FaultKind,
+        memory::{
+            AccessMode, AddrSpaceWrapper, Grant, PageSpan, PfError, MADV_DONTNEED, MADV_HUGEPAGE,
+            MADV_NOHUGEPAGE,
+        },
+        process,
+        rt::{self, RtBandwidth, SchedPolicy},
+        signal::{KernelSignals, SigAction, SIG_IGN},
+        stats, timeout,
+    },
+    irq_stats,
+    klog::{self, RecordHeader},
+    memory::{
+        self, deallocate_p2frame, get_page_info, memcg, oom,
+        slab::{Magazine, SlabCache},
+        Frame, RefCount, PAGE_SIZE,
+    },
+    numa::{self, NodeHint, NodeMask},
+    paging::Page,
+    percpu::{percpu, PercpuBlock},
+    scheme::{
+        self,
+        itimer::{self, ITimerScheme},
+        proc::{CloneFlags, CloneParts},
+        CallerCtx, KernelScheme, OpenResult, SchemeId,
+    },
+    sync::rcu,
+    syscall::{
+        data::TimeSpec,
+        error::{EAGAIN, EBADF, EFAULT, EINVAL, ENOMEM},
+        flag::{
+            MapFlags, CLOCK_MONOTONIC, FUTEX_REQUEUE, FUTEX_WAIT, FUTEX_WAKE, SIGCHLD, SIGKILL,
+            SIGTSTP, SIGUSR1,
+        },
+        futex::futex,
+        usercopy::UserSlice,
+    },
+    time, timer, vdso,
+    workqueue::{Work, WorkQueue},
+};
+
+use super::{
+    fault::{self, Site},
+    ktest_assert, Test, TestResult,
+};
+
+pub static TESTS: &[Test] = &[
+    Test {
+        name: "frame_allocator",
+        run: frame_allocator,
+    },
+    Test {
+        name: "frame_allocator_injected_failure",
+        run: frame_allocator_injected_failure,
+    },
+    Test {
+        name: "frame_allocator_numa",
+        run: frame_allocator_numa,
+    },
+    Test {
+        name: "heap_injected_failure",
+        run: heap_injected_failure,
+    },
+    Test {
+        name: "grant_map_unmap",
+        run: grant_map_unmap,
+    },
+    Test {
+        name: "grant_map_injected_failure",
+        run: grant_map_injected_failure,
+    },
+    Test {
+        name: "grant_placement_randomized",
+        run: grant_placement_randomized,
+    },
+    Test {
+        name: "grant_mprotect",
+        run: grant_mprotect,
+    },
+    Test {
+        name: "grant_make_executable",
+        run: grant_make_executable,
+    },
+    Test {
+        name: "grant_madvise",
+        run: grant_madvise,
+    },
+    Test {
+        name: "grant_mlock",
+        run: grant_mlock,
+    },
+    Test {
+        name: "grant_zero_page",
+        run: grant_zero_page,
+    },
+    Test {
+        name: "fault_classify",
+        run: fault_classify,
+    },
+    #[cfg(target_arch = "x86_64")]
+    Test {
+        name: "huge_page_fork",
+        run: huge_page_fork,
+    },
+    Test {
+        name: "futex",
+        run: futex_basic,
+    },
+    Test {
+        name: "futex_requeue",
+        run: futex_requeue,
+    },
+    Test {
+        name: "usercopy_injected_fault",
+        run: usercopy_injected_fault,
+    },
+    Test {
+        name: "timeout",
+        run: timeout_trigger,
+    },
+    Test {
+        name: "context_stats_io",
+        run: context_stats_io,
+    },
+    Test {
+        name: "vdso_clock",
+        run: vdso_clock,
+    },
+    Test {
+        name: "irq_stats",
+        run: irq_stats_count,
+    },
+    Test {
+        name: "itimer_periodic",
+        run: itimer_periodic,
+    },
+    Test {
+        name: "klog_levels",
+        run: klog_levels,
+    },
+    Test {
+        name: "clone_flags",
+        run: clone_flags,
+    },
+    Test {
+        name: "clone_parts",
+        run: clone_parts,
+    },
+    Test {
+        name: "caps_euid",
+        run: caps_euid,
+    },
+    Test {
+        name: "sched_rt",
+        run: sched_rt,
+    },
+    Test {
+        name: "memcg_limit",
+        run: memcg_limit,
+    },
+    Test {
+        name: "oom_no_candidate",
+        run: oom_no_candidate,
+    },
+    Test {
+        name: "kernel_signals",
+        run: kernel_signals,
+    },
+    Test {
+        name: "workqueue",
+        run: workqueue_schedule,
+    },
+    Test {
+        name: "rcu_defer_drop",
+        run: rcu_defer_drop,
+    },
+    Test {
+        name: "rw_spinlock_upgrade",
+        run: rw_spinlock_upgrade,
+    },
+    Test {
+        name: "percpu_vars",
+        run: percpu_vars,
+    },
+    Test {
+        name: "slab_cache",
+        run: slab_cache,
+    },
+    #[cfg(target_arch = "x86_64")]
+    Test {
+        name: "fx_regs_initial_state",
+        run: fx_regs_initial_state,
+    },
+];
+
+fn frame_allocator() -> TestResult {
+    let mut allocated = Vec::<(Frame, u32)>::new();
+    for order in 0..5 {
+        for _ in 0..4 {
+            let frame = memory::allocate_p2frame(order)
+                .ok_or_else(|| alloc::format!("failed to allocate order {}", order))?;
+            ktest_assert!(frame.is_aligned_to_order(order), "{:?} not aligned", frame);
+            ktest_assert!(
+                get_page_info(frame).is_some(),
+                "{:?} has no page info",
+                frame
+            );
+
+            let size = PAGE_SIZE << order;
+            let overlapping = allocated.iter().any(|&(other, other_order)| {
+                let other_size = PAGE_SIZE << other_order;
+                frame.base().data() < other.base().data() + other_size
+                    && other.base().data() < frame.base().data() + size
+            });
+            ktest_assert!(!overlapping, "{:?} was allocated twice", frame);
+
+            allocated.push((frame, order));
+        }
+    }
+    for (frame, order) in allocated {
+        unsafe {
+            deallocate_p2frame(frame, order);
+        }
+    }
+    Ok(())
+}
+
+fn frame_allocator_injected_failure() -> TestResult {
+    fault::inject(Site::FrameAlloc, 1);
+    let first = memory::allocate_frame();
+    let second = memory::allocate_frame();
+    let third = memory::allocate_frame();
+
+    let result = (|| {
+        ktest_assert!(first.is_some());
+        ktest_assert!(second.is_none(), "injected failure was ignored");
+        ktest_assert!(third.is_some(), "failure was injected more than once");
+        ktest_assert!(fault::injected(Site::FrameAlloc) == 1);
+        Ok(())
+    })();
+
+    for frame in [first, second, third].into_iter().flatten() {
+        unsafe {
+            deallocate_p2frame(frame, 0);
+        }
+    }
+    result
+}
+
+fn frame_allocator_numa() -> TestResult {
+    for node in NodeMask::all().iter() {
+        let hint = NodeHint {
+            nodes: NodeMask::single(node),
+            strict: true,
+        };
+        let free_before = memory::free_frames_per_node()[usize::from(node.get())];
+        // A node may consist of CPUs only.
+        let Some(frame) = memory::allocate_frame_on(hint) else {
+            ktest_assert!(free_before == 0, "node {} has free frames", node.get());
+            continue;
+        };
+        let free_after = memory::free_frames_per_node()[usize::from(node.get())];
+        unsafe {
+            deallocate_p2frame(frame, 0);
+        }
+
+        ktest_assert!(
+            numa::frame_node(frame) == node,
+            "{:?} is not on node {}",
+            frame,
+            node.get()
+        );
+        ktest_assert!(free_after < free_before, "node {} free count", node.get());
+    }
+    Ok(())
+}
+
+fn heap_injected_failure() -> TestResult {
+    fault::inject(Site::HeapAlloc, 2);
+    let result = try_alloc::try_collect(0..8_usize);
+    ktest_assert!(
+        matches!(result, Err(ref err) if err.errno == ENOMEM),
+        "expected ENOMEM"
+    );
+    ktest_assert!(try_alloc::try_collect(0..8_usize).is_ok());
+    Ok(())
+}
+
+/// Map `count` zeroed pages anywhere in `addr_space`.
+fn map_zeroed(addr_space: &Arc<AddrSpaceWrapper>, count: usize) -> TestResult<Page> {
+    let count = NonZeroUsize::new(count).unwrap();
+    addr_space
+        .acquire_write()
+        .mmap_anywhere(
+            addr_space,
+            count,
+            MapFlags::PROT_READ | MapFlags::PROT_WRITE | MapFlags::MAP_PRIVATE,
+            |page, flags, mapper, flusher| {
+                Ok(Grant::zeroed(
+                    PageSpan::new(page, count.get()),
+                    flags,
+                    mapper,
+                    flusher,
+                    false,
+                )?)
+            },
+        )
+        .map_err(|err| alloc::format!("mmap failed: {}", err))
+}
+
+fn unmap(addr_space: &Arc<AddrSpaceWrapper>, page: Page, count: usize) -> TestResult {
+    let results = addr_space
+        .munmap(PageSpan::new(page, count), false)
+        .map_err(|err| alloc::format!("munmap failed: {}", err))?;
+    for result in results {
+        result
+            .unmap()
+            .map_err(|err| alloc::format!("unmap failed: {}", err))?;
+    }
+    Ok(())
+}
+
+fn grant_map_unmap() -> TestResult {
+    let addr_space = AddrSpaceWrapper::new().map_err(|err| alloc::format!("{}", err))?;
+
+    let page = map_zeroed(&addr_space, 4)?;
+    {
+        let guard = addr_space.acquire_read();
+        for i in 0..4 {
+            let page = page.next_by(i);
+            ktest_assert!(
+                guard.grants.contains(page).is_some(),
+                "{:?} has no grant",
+                page
+            );
+        }
+    }
+
+    // Unmapping the middle must split the grant.
+    unmap(&addr_space, page.next_by(1), 2)?;
+    {
+        let guard = addr_space.acquire_read();
+        ktest_assert!(guard.grants.contains(page).is_some());
+        ktest_assert!(guard.grants.contains(page.next_by(1)).is_none());
+        ktest_assert!(guard.grants.contains(page.next_by(2)).is_none());
+        ktest_assert!(guard.grants.contains(page.next_by(3)).is_some());
+        ktest_assert!(guard
+            .table
+            .utable
+            .translate(page.next_by(1).start_address())
+            .is_none());
+    }
+
+    unmap(&addr_space, page, 4)?;
+    ktest_assert!(addr_space.acquire_read().grants.iter().next().is_none());
+    Ok(())
+}
+
+fn grant_map_injected_failure() -> TestResult {
+    let addr_space = AddrSpaceWrapper::new().map_err(|err| alloc::format!("{}", err))?;
+
+    // Page table allocation failing must leave the grant consistent, with the remaining pages
+    // faulted in lazily.
+    fault::inject(Site::FrameAlloc, 0);
+    if let Ok(page) = map_zeroed(&addr_space, 4) {
+        unmap(&addr_space, page, 4)?;
+    }
+    ktest_assert!(addr_space.acquire_read().grants.iter().next().is_none());
+    Ok(())
+}
+
+fn grant_placement_randomized() -> TestResult {
+    let addr_space = AddrSpaceWrapper::new().map_err(|err| alloc::format!("{}", err))?;
+    let (base, min) = {
+        let guard = addr_space.acquire_read();
+        (guard.mmap_base, guard.mmap_min)
+    };
+    ktest_assert!(base >= min && base % PAGE_SIZE == 0, "bad base {:#x}", base);
+
+    // Grants without a fixed address are placed from the base.
+    let page = map_zeroed(&addr_space, 4)?;
+    ktest_assert!(
+        page.start_address().data() >= base,
+        "{:?} below base {:#x}",
+        page,
+        base
+    );
+    unmap(&addr_space, page, 4)
+}
+
+fn grant_mprotect() -> TestResult {
+    let addr_space = AddrSpaceWrapper::new().map_err(|err| alloc::format!("{}", err))?;
+    let page = map_zeroed(&addr_space, 4)?;
+    let middle = PageSpan::new(page.next_by(1), 2);
+
+    let result = addr_space.mprotect(middle, MapFlags::MAP_SHARED);
+    ktest_assert!(
+        matches!(result, Err(ref err) if err.errno == EINVAL),
+        "accepted mapping flags"
+    );
+
+    // Protecting the middle must split the grant.
+    addr_space
+        .mprotect(middle, MapFlags::PROT_READ)
+        .map_err(|err| alloc::format!("mprotect failed: {}", err))?;
+    {
+        let guard = addr_space.acquire_read();
+        let (base, info) = guard.grants.contains(page.next_by(1)).ok_or("no grant")?;
+        ktest_assert!(base == page.next_by(1) && info.page_count() == 2);
+        ktest_assert!(!info.flags().has_write());
+        let (_, info) = guard.grants.contains(page.next_by(3)).ok_or("no grant")?;
+        ktest_assert!(info.flags().has_write());
+    }
+
+    // Restoring it merges the grants again, without making the zeroed frame writable.
+    addr_space
+        .mprotect(middle, MapFlags::PROT_READ | MapFlags::PROT_WRITE)
+        .map_err(|err| alloc::format!("mprotect failed: {}", err))?;
+    {
+        let guard = addr_space.acquire_read();
+        let (base, info) = guard.grants.contains(page.next_by(1)).ok_or("no grant")?;
+        ktest_assert!(base == page && info.page_count() == 4);
+        let mapping = guard
+            .table
+            .utable
+            .translate(page.next_by(1).start_address());
+        ktest_assert!(mapping.is_some_and(|(_, flags)| !flags.has_write()));
+    }
+
+    unmap(&addr_space, page, 4)
+}
+
+fn grant_make_executable() -> TestResult {
+    let addr_space = AddrSpaceWrapper::new().map_err(|err| alloc::format!("{}", err))?;
+    let page = map_zeroed(&addr_space, 2)?;
+
+    addr_space
+        .make_executable(PageSpan::new(page, 2))
+        .map_err(|err| alloc::format!("make_executable failed: {}", err))?;
+    {
+        let guard = addr_space.acquire_read();
+        let (_, info) = guard.grants.contains(page).ok_or("no grant")?;
+        ktest_assert!(info.flags().has_execute() && !info.flags().has_write());
+    }
+
+    unmap(&addr_space, page, 2)
+}
+
+fn grant_madvise() -> TestResult {
+    let addr_space = AddrSpaceWrapper::new().map_err(|err| alloc::format!("{}", err))?;
+    let page = map_zeroed(&addr_space, 4)?;
+    let middle = PageSpan::new(page.next_by(1), 2);
+
+    let result = addr_space.madvise(middle, usize::MAX);
+    ktest_assert!(
+        matches!(result, Err(ref err) if err.errno == EINVAL),
+        "accepted unknown advice"
+    );
+    let result = addr_space.madvise(PageSpan::new(page.next_by(2), 4), MADV_DONTNEED);
+    ktest_assert!(
+        matches!(result, Err(ref err) if err.errno == ENOMEM),
+        "accepted a range beyond the grant"
+    );
+
+    // Dropping the middle pages unmaps them, within the same grant.
+    addr_space
+        .madvise(middle, MADV_DONTNEED)
+        .map_err(|err| alloc::format!("madvise failed: {}", err))?;
+    {
+        let guard = addr_space.acquire_read();
+        let (base, info) = guard.grants.contains(page.next_by(1)).ok_or("no grant")?;
+        ktest_assert!(base == page && info.page_count() == 4);
+        for i in 0..4 {
+            let mapped = guard
+                .table
+                .utable
+                .translate(page.next_by(i).start_address())
+                .is_some();
+            ktest_assert!(mapped == (i == 0 || i == 3), "page {}", i);
+        }
+    }
+
+    // Huge page eligibility is per range, which splits the grant until it is allowed again.
+    addr_space
+        .madvise(middle, MADV_NOHUGEPAGE)
+        .map_err(|err| alloc::format!("madvise failed: {}", err))?;
+    {
+        let guard = addr_space.acquire_read();
+        let (base, info) = guard.grants.contains(page.next_by(1)).ok_or("no grant")?;
+        ktest_assert!(base == page.next_by(1) && info.page_count() == 2);
+    }
+    addr_space
+        .madvise(middle, MADV_HUGEPAGE)
+        .map_err(|err| alloc::format!("madvise failed: {}", err))?;
+    {
+        let guard = addr_space.acquire_read();
+        let (base, info) = guard.grants.contains(page.next_by(1)).ok_or("no grant")?;
+        ktest_assert!(base == page && info.page_count() == 4);
+    }
+
+    unmap(&addr_space, page, 4)
+}
+
+fn grant_mlock() -> TestResult {
+    let addr_space = AddrSpaceWrapper::new().map_err(|err| alloc::format!("{}", err))?;
+    let page = map_zeroed(&addr_space, 4)?;
+    let middle = PageSpan::new(page.next_by(1), 2);
+
+    // Locking populates the pages with private frames, rather than the shared zeroed frame.
+    addr_space
+        .mlock(middle)
+        .map_err(|err| alloc::format!("mlock failed: {}", err))?;
+    {
+        let guard = addr_space.acquire_read();
+        ktest_assert!(guard.grants.locked_count() == 2);
+        let (base, info) = guard.grants.contains(page.next_by(1)).ok_or("no grant")?;
+        ktest_assert!(base == page.next_by(1) && info.page_count() == 2);
+        for page in middle.pages() {
+            let (phys, flags) = guard
+                .table
+                .utable
+                .translate(page.start_address())
+                .ok_or("locked page not mapped")?;
+            let refcount = get_page_info(Frame::containing(phys)).and_then(|info| info.refcount());
+            ktest_assert!(flags.has_write() && refcount == Some(RefCount::One));
+        }
+    }
+
+    let result = addr_space.madvise(middle, MADV_DONTNEED);
+    ktest_assert!(
+        matches!(result, Err(ref err) if err.errno == EINVAL),
+        "dropped locked pages"
+    );
+
+    addr_space
+        .munlock(middle)
+        .map_err(|err| alloc::format!("munlock failed: {}", err))?;
+    {
+        let guard = addr_space.acquire_read();
+        ktest_assert!(guard.grants.locked_count() == 0);
+        let (base, info) = guard.grants.contains(page.next_by(1)).ok_or("no grant")?;
+        ktest_assert!(base == page && info.page_count() == 4);
+    }
+
+    unmap(&addr_space, page, 4)
+}
+
+fn fault_classify() -> TestResult {
+    let addr_space = AddrSpaceWrapper::new().map_err(|err| alloc::format!("{}", err))?;
+    let grant = map_zeroed(&addr_space, 32)?;
+    {
+        let guard = addr_space.acquire_read();
+        let classify = |page, access| context::fault::classify(&guard, page, access);
+        ktest_assert!(classify(grant.next_by(24), AccessMode::Read) == FaultKind::LazyAlloc);
+        ktest_assert!(classify(grant.next_by(32), AccessMode::Read) == FaultKind::MapError);
+        // The grant is not executable.
+        ktest_assert!(
+            classify(grant.next_by(24), AccessMode::InstrFetch) == FaultKind::AccessError
+        );
+    }
+    unmap(&addr_space, grant, 32)
+}
+
+fn grant_zero_page() -> TestResult {
+    let addr_space = AddrSpaceWrapper::new().map_err(|err| alloc::format!("{}", err))?;
+    // Past the pages mapped eagerly, so that the faults populate it.
+    let grant = map_zeroed(&addr_space, 32)?;
+    let page = grant.next_by(24);
+
+    let previous = context::current()
+        .write()
+        .set_addr_space(Some(Arc::clone(&addr_space)));
+    let result = (|| -> TestResult {
+        let translate = || {
+            addr_space
+                .acquire_read()
+                .table
+                .utable
+                .translate(page.start_address())
+        };
+        let zeroed = memory::the_zeroed_frame().0;
+        let resident = || addr_space.acquire_read().resident_pages();
+        let resident_before = resident();
+
+        // Reading maps the zeroed frame read-only, allocating nothing.
+        let kind = context::fault::handle(page, AccessMode::Read)
+            .map_err(|err| alloc::format!("read fault failed: {:?}", err))?;
+        ktest_assert!(kind == FaultKind::LazyAlloc);
+        let (phys, flags) = translate().ok_or("page not mapped after read")?;
+        ktest_assert!(Frame::containing(phys) == zeroed && !flags.has_write());
+        ktest_assert!(resident() == resident_before);
+
+        // Writing then gives it a private frame.
+        let kind = context::fault::handle(page, AccessMode::Write)
+            .map_err(|err| alloc::format!("write fault failed: {:?}", err))?;
+        ktest_assert!(kind == FaultKind::CowBreak);
+        let (phys, flags) = translate().ok_or("page not mapped after write")?;
+        let frame = Frame::containing(phys);
+        ktest_assert!(frame != zeroed && flags.has_write());
+        ktest_assert!(get_page_info(frame).and_then(|info| info.refcount()) == Some(RefCount::One));
+        ktest_assert!(resident() == resident_before + 1);
+        Ok(())
+    })();
+    context::current().write().set_addr_space(previous);
+
+    unmap(&addr_space, grant, 32)?;
+    result
+}
+
+#[cfg(target_arch = "x86_64")]
+fn huge_page_fork() -> TestResult {
+    use crate::{
+        context::huge_page,
+        paging::{VirtualAddress, ENTRY_COUNT},
+    };
+
+    let addr_space = AddrSpaceWrapper::new().map_err(|err| alloc::format!("{}", err))?;
+    // Large enough to contain an aligned 2 MiB range past the pages mapped eagerly.
+    let grant = map_zeroed(&addr_space, 3 * ENTRY_COUNT)?;
+    let huge_size = ENTRY_COUNT * PAGE_SIZE;
+    let huge_base = (grant.start_address().data() + 2 * huge_size) / huge_size * huge_size;
+    let page = Page::containing_address(VirtualAddress::new(huge_base)).next_by(1);
+
+    let previous = context::current()
+        .write()
+        .set_addr_space(Some(Arc::clone(&addr_space)));
+    let result = (|| -> TestResult {
+        let is_huge = |addr_space: &Arc<AddrSpaceWrapper>| {
+            huge_page::is_mapped_huge(
+                &addr_space.acquire_read().table.utable,
+                page.start_address(),
+            )
+        };
+        let translate = |addr_space: &Arc<AddrSpaceWrapper>| {
+            huge_page::translate(
+                &addr_space.acquire_read().table.utable,
+                page.start_address(),
+            )
+        };
+
+        context::fault::handle(page, AccessMode::Write)
+            .map_err(|err| alloc::format!("write fault failed: {:?}", err))?;
+        ktest_assert!(is_huge(&addr_space), "write fault did not map a huge page");
+        let frame = translate(&addr_space);
+
+        let child = addr_space
+            .try_clone()
+            .map_err(|err| alloc::format!("fork failed: {}", err))?;
+        ktest_assert!(
+            is_huge(&addr_space),
+            "fork split the huge page of the parent"
+        );
+        ktest_assert!(is_huge(&child) && translate(&child) == frame);
+
+        // Writing gives the parent its own copy of the page, leaving the shared one to the child.
+        let kind = context::fault::handle(page, AccessMode::Write)
+            .map_err(|err| alloc::format!("write fault failed: {:?}", err))?;
+        ktest_assert!(kind == FaultKind::CowBreak);
+        ktest_assert!(translate(&addr_space) != frame && translate(&child) == frame);
+        ktest_assert!(is_huge(&child), "write split the huge page of the child");
+        Ok(())
+    })();
+    context::current().write().set_addr_space(previous);
+
+    unmap(&addr_space, grant, 3 * ENTRY_COUNT)?;
+    result
+}
+
+/// Run `f` with a new address space containing one zeroed page, as the current address space.
+fn with_user_page(f: impl FnOnce(usize) -> TestResult) -> TestResult {
+    let addr_space = AddrSpaceWrapper::new().map_err(|err| alloc::format!("{}", err))?;
+    let page = map_zeroed(&addr_space, 1)?;
+
+    let previous = context::current()
+        .write()
+        .set_addr_space(Some(Arc::clone(&addr_space)));
+    let result = f(page.start_address().data());
+    context::current().write().set_addr_space(previous);
+
+    unmap(&addr_space, page, 1)?;
+    result
+}
+
+fn futex_basic() -> TestResult {
+    with_user_page(|addr| {
+        // The page is zeroed, so waiting for any other value must not block.
+        let result = futex(addr, FUTEX_WAIT, 1, 0, 0);
+        ktest_assert!(
+            matches!(result, Err(ref err) if err.errno == EAGAIN),
+            "expected EAGAIN, got {:?}",
+            result
+        );
+
+        let result = futex(addr + 1, FUTEX_WAIT, 1, 0, 0);
+        ktest_assert!(
+            matches!(result, Err(ref err) if err.errno == EINVAL),
+            "expected EINVAL for a misaligned futex, got {:?}",
+            result
+        );
+
+        let result = futex(crate::USER_END_OFFSET, FUTEX_WAIT, 0, 0, 0);
+        ktest_assert!(
+            matches!(result, Err(ref err) if err.errno == EFAULT),
+            "expected EFAULT for a kernel address, got {:?}",
+            result
+        );
+
+        let result = futex(addr, FUTEX_WAKE, 1, 0, 0);
+        ktest_assert!(result == Ok(0), "expected no waiters, got {:?}", result);
+        Ok(())
+    })
+}
+
+fn futex_requeue() -> TestResult {
+    with_user_page(|addr| {
+        let result = futex(addr, FUTEX_REQUEUE, 1, usize::MAX, addr + 4);
+        ktest_assert!(result == Ok(0), "expected no waiters, got {:?}", result);
+
+        let result = futex(addr, FUTEX_REQUEUE, 1, usize::MAX, crate::USER_END_OFFSET);
+        ktest_assert!(
+            matches!(result, Err(ref err) if err.errno == EFAULT),
+            "expected EFAULT for a kernel requeue target, got {:?}",
+            result
+        );
+        Ok(())
+    })
+}
+
+fn usercopy_injected_fault() -> TestResult {
+    with_user_page(|addr| {
+        let mut buf = [0xFF_u8; 16];
+
+        fault::inject(Site::UserCopy, 0);
+        let result = UserSlice::ro(addr, buf.len()).and_then(|slice| slice.copy_to_slice(&mut buf));
+        ktest_assert!(
+            matches!(result, Err(ref err) if err.errno == EFAULT),
+            "expected EFAULT"
+        );
+
+        UserSlice::ro(addr, buf.len())
+            .and_then(|slice| slice.copy_to_slice(&mut buf))
+            .map_err(|err| alloc::format!("copy failed: {}", err))?;
+        ktest_assert!(buf.iter().all(|&byte| byte == 0), "page was not zeroed");
+        Ok(())
+    })
+}
+
+fn timeout_trigger() -> TestResult {
+    let before = timeout::pending();
+
+    // Already expired, so the next trigger must remove it.
+    timeout::register(
+        SchemeId::new(usize::MAX),
+        0,
+        CLOCK_MONOTONIC,
+        TimeSpec::default(),
+    );
+    ktest_assert!(timeout::pending() == before + 1);
+
+    timeout::trigger();
+    ktest_assert!(
+        timeout::pending() <= before,
+        "expired timeout was not triggered"
+    );
+    Ok(())
+}
+
+fn context_stats_io() -> TestResult {
+    let scheme = SchemeId::new(usize::MAX);
+    let scheme_io = || {
+        stats::snapshot(&context::current().read())
+            .io
+            .iter()
+            .find(|io| io.scheme == scheme)
+            .map_or((0, 0), |io| (io.read, io.written))
+    };
+    let (read, written) = scheme_io();
+
+    stats::account_io(scheme, 100, 0);
+    stats::account_io(scheme, 20, 3);
+    ktest_assert!(
+        scheme_io() == (read + 120, written + 3),
+        "got {:?}",
+        scheme_io()
+    );
+    Ok(())
+}
+
+/// Read the monotonic clock like userspace does from the vDSO page.
+fn vdso_monotonic() -> Option<u128> {
+    use core::sync::atomic::{fence, Ordering};
+
+    let data = vdso::data();
+    loop {
+        let seq = data.seq.load(Ordering::Acquire);
+        if seq % 2 == 1 {
+            core::hint::spin_loop();
+            continue;
+        }
+        let mode = data.clock_mode.load(Ordering::Relaxed);
+        let base_counter = data.base_counter.load(Ordering::Relaxed);
+        let base_nanos = data.base_nanos.load(Ordering::Relaxed);
+        let frequency = data.frequency.load(Ordering::Relaxed);
+        fence(Ordering::Acquire);
+        if data.seq.load(Ordering::Relaxed) != seq {
+            continue;
+        }
+
+        let counter = match mode {
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            vdso::VDSO_CLOCK_TSC => unsafe { x86::time::rdtsc() },
+            #[cfg(target_arch = "aarch64")]
+            vdso::VDSO_CLOCK_CNTVCT => unsafe {
+                crate::device::cpu::registers::control_regs::cntvct_el0()
+            },
+            _ => return None,
+        };
+        let elapsed = u128::from(counter.saturating_sub(base_counter));
+        return Some(
+            u128::from(base_nanos) + elapsed * time::NANOS_PER_SEC / u128::from(frequency),
+        );
+    }
+}
+
+fn vdso_clock() -> TestResult {
+    let before = time::monotonic();
+    let Some(now) = vdso_monotonic() else {
+        // No clock userspace can read, so the syscalls are used instead.
+        return Ok(());
+    };
+    let after = time::monotonic();
+    ktest_assert!(
+        before <= now && now <= after,
+        "vDSO time {} not between {} and {}",
+        now,
+        before,
+        after
+    );
+    Ok(())
+}
+
+fn irq_stats_count() -> TestResult {
+    // No interrupt is dispatched with this number on x86, as it would be vector 287.
+    let irq = u8::MAX;
+    let cpu = crate::cpu_id().get() as usize;
+    let (before, _) = irq_stats::snapshot(irq);
+    let start = time::monotonic() as u64;
+
+    irq_stats::count(irq);
+    let (after, last) = irq_stats::snapshot(irq);
+    ktest_assert!(
+        after[cpu] > before[cpu],
+        "count {} after {}",
+        after[cpu],
+        before[cpu]
+    );
+    ktest_assert!(last >= start, "last interrupt at {} before {}", last, start);
+    Ok(())
+}
+
+fn itimer_periodic() -> TestResult {
+    let ctx = CallerCtx {
+        pid: 0,
+        uid: 0,
+        gid: 0,
+    };
+    let id = match ITimerScheme.kopen("1", 0, ctx) {
+        Ok(OpenResult::SchemeLocal(id, _)) => id,
+        Ok(_) => return Err("itimer: not a local handle".into()),
+        Err(err) => return Err(alloc::format!("itimer: open failed: {}", err)),
+    };
+    const INTERVAL: u128 = 1000;
+
+    itimer::set(id, 1, INTERVAL).map_err(|err| alloc::format!("set failed: {}", err))?;
+    let end = time::monotonic() + 3 * INTERVAL;
+    while time::monotonic() < end {
+        core::hint::spin_loop();
+    }
+    timer::run_expired();
+
+    let expirations = itimer::take_expirations(id).map_err(|err| alloc::format!("{}", err))?;
+    ktest_assert!(expirations >= 3, "{} expirations", expirations);
+    let (left, interval) = itimer::get(id).map_err(|err| alloc::format!("{}", err))?;
+    ktest_assert!(interval == INTERVAL && left > 0 && left <= INTERVAL);
+
+    let _ = ITimerScheme.close(id);
+    ktest_assert!(itimer::set(id, 1, 0).map_err(|err| err.errno) == Err(EBADF));
+    Ok(())
+}
+
+fn klog_levels() -> TestResult {
+    const MESSAGE: &str = "klog_levels test record";
+
+    // Skip the records logged so far, as no record has level 0.
+    let mut pos = 0;
+    klog::read(&mut pos, 0, 0, false).map_err(|err| alloc::format!("skip failed: {}", err))?;
+    log::info!("{}", MESSAGE);
+    log::warn!("{}", MESSAGE);
+
+    // Other CPUs may log in between, so only the records of this test are looked at.
+    let records = klog::read(&mut pos, log::Level::Warn as u8, 4096, false)
+        .map_err(|err| alloc::format!("read failed: {}", err))?;
+    let mut found = 0;
+    let mut offset = 0;
+    while offset < records.len() {
+        let header =
+            unsafe { core::ptr::read_unaligned(records[offset..].as_ptr().cast::<RecordHeader>()) };
+        ktest_assert!(header.level <= log::Level::Warn as u8, "{:?}", header);
+
+        let start = offset + size_of::<RecordHeader>();
+        let message = &records[start..start + header.len as usize];
+        if message.ends_with(MESSAGE.as_bytes()) {
+            ktest_assert!(header.level == log::Level::Warn as u8, "{:?}", header);
+            found += 1;
+        }
+        offset = start + (header.len as usize).next_multiple_of(8);
+    }
+    ktest_assert!(found == 1, "found {} records", found);
+    Ok(())
+}
+
+fn clone_flags() -> TestResult {
+    let parse = |names| CloneFlags::parse(names).map_err(|err| err.errno);
+
+    ktest_assert!(parse("") == Ok(CloneFlags::empty()));
+    ktest_assert!(parse("files,newns") == Ok(CloneFlags::FILES | CloneFlags::NEWNS));
+    ktest_assert!(parse("vm,sighand,thread,files").is_ok());
+    ktest_assert!(parse("vm,vfork").is_ok());
+
+    // Signal actions and threads need the shared address space.
+    ktest_assert!(parse("sighand") == Err(EINVAL));
+    ktest_assert!(parse("vm,thread") == Err(EINVAL));
+    ktest_assert!(parse("vfork") == Err(EINVAL));
+    ktest_assert!(parse("vm,sighand,thread,vfork") == Err(EINVAL));
+    ktest_assert!(parse("vm,sighand,thread,newns") == Err(EINVAL));
+    ktest_assert!(parse("vm,unknown") == Err(EINVAL));
+    Ok(())
+}
+
+fn clone_parts() -> TestResult {
+    use crate::sync::RwLock;
+
+    let addr_space = AddrSpaceWrapper::new().map_err(|err| alloc::format!("{}", err))?;
+    let files = Arc::new(RwLock::new(Vec::new()));
+    let ksig = KernelSignals::new();
+    let ens = process::current()
+        .map_err(|err| alloc::format!("{}", err))?
+        .read()
+        .ens;
+    let clone = |flags| {
+        CloneParts::new(
+            flags,
+            Arc::clone(&addr_space),
+            Arc::clone(&files),
+            Some(ksig.share()),
+            ens,
+        )
+        .map_err(|err| alloc::format!("{}", err))
+    };
+    let ignore = SigAction {
+        handler: SIG_IGN,
+        ..SigAction::default()
+    };
+
+    let copied = clone(CloneFlags::empty())?;
+    ktest_assert!(!Arc::ptr_eq(&copied.addr_space, &addr_space));
+    ktest_assert!(!Arc::ptr_eq(&copied.files, &files));
+    ktest_assert!(copied.namespace.is_none());
+    let mut copied_ksig = copied.ksig.ok_or("signals were not copied")?;
+    ktest_assert!(!Arc::ptr_eq(&copied_ksig.actions, &ksig.actions));
+    ktest_assert!(copied_ksig.set_action(SIGUSR1, ignore));
+    ktest_assert!(ksig.actions.lock()[SIGUSR1 - 1].handler != SIG_IGN);
+
+    let shared = clone(CloneFlags::VM | CloneFlags::FILES | CloneFlags::SIGHAND)?;
+    ktest_assert!(Arc::ptr_eq(&shared.addr_space, &addr_space));
+    ktest_assert!(Arc::ptr_eq(&shared.files, &files));
+    ktest_assert!(shared.namespace.is_none());
+    let mut shared_ksig = shared.ksig.ok_or("signals were not shared")?;
+    ktest_assert!(shared_ksig.set_action(SIGUSR1, ignore));
+    ktest_assert!(ksig.actions.lock()[SIGUSR1 - 1].handler == SIG_IGN);
+
+    // Exec stops sharing the handlers, leaving those of the other contexts alone.
+    shared_ksig.reset_handlers();
+    ktest_assert!(!Arc::ptr_eq(&shared_ksig.actions, &ksig.actions));
+
+    // The new namespace starts with the schemes of the old one.
+    let namespace = clone(CloneFlags::NEWNS)?
+        .namespace
+        .ok_or("no namespace was created")?;
+    ktest_assert!(namespace != ens);
+    let schemes = scheme::schemes();
+    let id = |ns| schemes.get_name(ns, "pipe").map(|(id, _)| id);
+    ktest_assert!(id(namespace).is_some() && id(namespace) == id(ens));
+    Ok(())
+}
+
+fn caps_euid() -> TestResult {
+    let process = process::current().map_err(|e| alloc::format!("process: {}", e.errno))?;
+    let current = context::current();
+    let euid = process.read().euid;
+    let (effective, permitted) = {
+        let context = current.read();
+        (context.caps, context.caps_permitted)
+    };
+    caps::set_euid(&mut process.write(), 0);
+    current.write().caps_permitted = Capabilities::all() - Capabilities::REBOOT;
+
+    let result = (|| {
+        caps::set_euid(&mut process.write(), 1000);
+        ktest_assert!(current.read().caps.is_empty());
+        // Only the permitted capabilities come back with root.
+        caps::set_euid(&mut process.write(), 0);
+        ktest_assert!(caps::has(Capabilities::PTRACE | Capabilities::IRQ));
+        ktest_assert!(!caps::has(Capabilities::REBOOT));
+        // Switching between other users keeps them empty.
+        caps::set_euid(&mut process.write(), 1000);
+        caps::set_euid(&mut process.write(), 1001);
+        ktest_assert!(current.read().caps.is_empty());
+        Ok(())
+    })();
+
+    process.write().euid = euid;
+    let mut context = current.write();
+    context.caps = effective;
+    context.caps_permitted = permitted;
+    result
+}
+
+fn sched_rt() -> TestResult {
+    let from_raw = |policy, priority| SchedPolicy::from_raw(policy, priority).map_err(|e| e.errno);
+
+    ktest_assert!(from_raw(SchedPolicy::NORMAL, 0) == Ok(SchedPolicy::Normal));
+    ktest_assert!(from_raw(SchedPolicy::FIFO, 99) == Ok(SchedPolicy::Fifo(99)));
+    ktest_assert!(from_raw(SchedPolicy::ROUND_ROBIN, 1) == Ok(SchedPolicy::RoundRobin(1)));
+    ktest_assert!(SchedPolicy::RoundRobin(7).into_raw() == (SchedPolicy::ROUND_ROBIN, 7));
+    ktest_assert!(from_raw(SchedPolicy::NORMAL, 1) == Err(EINVAL));
+    ktest_assert!(from_raw(SchedPolicy::FIFO, 0) == Err(EINVAL));
+    ktest_assert!(from_raw(SchedPolicy::ROUND_ROBIN, 100) == Err(EINVAL));
+    ktest_assert!(from_raw(3, 1) == Err(EINVAL));
+
+    let (period_us, runtime_us, _, _) = rt::settings();
+    if runtime_us >= period_us {
+        // The throttle is disabled.
+        return Ok(());
+    }
+    let runtime = u128::from(runtime_us) * 1000;
+    let bandwidth = RtBandwidth::default();
+    let now = time::monotonic();
+
+    ktest_assert!(bandwidth.remaining(now) == Some(runtime));
+    bandwidth.charge(runtime / 2, now);
+    ktest_assert!(bandwidth.remaining(now) == Some(runtime - runtime / 2));
+    bandwidth.charge(runtime, now);
+    ktest_assert!(bandwidth.remaining(now) == Some(0));
+    // The runtime is given back once the period ends.
+    let end = bandwidth.period_end();
+    ktest_assert!(end > now);
+    ktest_assert!(bandwidth.remaining(end) == Some(runtime));
+    Ok(())
+}
+
+fn memcg_limit() -> TestResult {
+    let parent = memcg::create("ktest").map_err(|e| alloc::format!("create: {}", e.errno))?;
+    let child = memcg::create("ktest/child").map_err(|e| alloc::format!("create: {}", e.errno))?;
+    parent.set_limit(Some(2 * PAGE_SIZE));
+
+    let current = context::current();
+    current.write().memcg = Some(Arc::clone(&child));
+    // Switching to the context also sets the group of the CPU, in case it happens in between.
+    PercpuBlock::current()
+        .switch_internals
+        .memcg
+        .set(Some(child.id()));
+
+    // Charged to the child, but limited by the parent.
+    let frames = [0; 3].map(|_| memory::init_user_frame(RefCount::One, None));
+
+    current.write().memcg = None;
+    PercpuBlock::current().switch_internals.memcg.set(None);
+
+    let result = (|| {
+        ktest_assert!(frames[0].is_ok() && frames[1].is_ok());
+        ktest_assert!(matches!(frames[2], Err(PfError::MemLimit)));
+        ktest_assert!(child.stats()[0] == 2 * PAGE_SIZE);
+        ktest_assert!(parent.stats()[2] == 1, "failed charge was not counted");
+        Ok(())
+    })();
+
+    for frame in frames.into_iter().flatten() {
+        unsafe {
+            deallocate_p2frame(frame, 0);
+        }
+    }
+    ktest_assert!(child.stats()[0] == 0, "freed frames were not uncharged");
+    ktest_assert!(parent.stats()[0] == 0);
+    ktest_assert!(parent.stats()[1] == 2 * PAGE_SIZE);
+
+    ktest_assert!(
+        memcg::remove("ktest").is_err(),
+        "group with children was removed"
+    );
+    memcg::remove("ktest/child").map_err(|e| alloc::format!("remove: {}", e.errno))?;
+    memcg::remove("ktest").map_err(|e| alloc::format!("remove: {}", e.errno))?;
+    result
+}
+
+fn oom_no_candidate() -> TestResult {
+    use core::sync::atomic::Ordering;
+
+    // No userspace process has been started yet, and kernel contexts are never killed.
+    let kills = oom::KILLS.load(Ordering::Relaxed);
+    ktest_assert!(oom::out_of_memory().map_err(|e| e.errno) == Err(ENOMEM));
+    ktest_assert!(oom::KILLS.load(Ordering::Relaxed) == kills);
+    ktest_assert!(!context::current().read().being_sigkilled);
+    Ok(())
+}
+
+fn kernel_signals() -> TestResult {
+    let mut ksig = KernelSignals::new();
+    let handler = SigAction {
+        handler: 0x1000,
+        restorer: 0x2000,
+        ..SigAction::default()
+    };
+
+    // SIGKILL can be neither caught nor blocked, and handlers need a restorer.
+    ktest_assert!(!ksig.set_action(SIGKILL, handler));
+    ktest_assert!(!ksig.set_action(
+        SIGUSR1,
+        SigAction {
+            restorer: 0,
+            ..handler
+        }
+    ));
+    ktest_assert!(ksig.set_action(SIGUSR1, handler));
+    ktest_assert!(ksig.set_blocked(2, !0));
+    ktest_assert!(ksig.blocked & (1 << (SIGKILL - 1)) == 0);
+
+    // Blocked signals stay pending, and ignored ones are discarded.
+    ktest_assert!(!ksig.queue(SIGUSR1));
+    ktest_assert!(ksig.pending == 1 << (SIGUSR1 - 1));
+    ktest_assert!(!ksig.queue(SIGCHLD));
+    ktest_assert!(ksig.set_blocked(1, !0));
+    ktest_assert!(ksig.queue(SIGUSR1));
+    ktest_assert!(ksig.will_stop(SIGTSTP));
+
+    ktest_assert!(ksig.set_action(
+        SIGUSR1,
+        SigAction {
+            handler: SIG_IGN,
+            ..handler
+        }
+    ));
+    ktest_assert!(ksig.pending == 0);
+
+    // Exec resets handlers, but not ignored signals.
+    ktest_assert!(ksig.set_action(SIGTSTP, handler));
+    ksig.reset_handlers();
+    ktest_assert!(ksig.actions.lock()[SIGUSR1 - 1].handler == SIG_IGN);
+    ktest_assert!(ksig.will_stop(SIGTSTP));
+    Ok(())
+}
+
+fn workqueue_schedule() -> TestResult {
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    static QUEUE: WorkQueue = WorkQueue::new();
+    static WORK: Work = Work::new(count_run);
+    static RUNS: AtomicUsize = AtomicUsize::new(0);
+    fn count_run() {
+        RUNS.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // Pending until it runs, so that scheduling it again does nothing.
+    ktest_assert!(QUEUE.schedule(&WORK));
+    ktest_assert!(!QUEUE.schedule(&WORK));
+    QUEUE.run_pending();
+    ktest_assert!(RUNS.load(Ordering::Relaxed) == 1);
+
+    const DELAY: u128 = 1000;
+    ktest_assert!(QUEUE.schedule_delayed(&WORK, DELAY));
+    ktest_assert!(!QUEUE.schedule(&WORK));
+    QUEUE.run_pending();
+    ktest_assert!(RUNS.load(Ordering::Relaxed) == 1, "delayed work ran early");
+
+    let end = time::monotonic() + 2 * DELAY;
+    while time::monotonic() < end {
+        core::hint::spin_loop();
+    }
+    timer::run_expired();
+    QUEUE.run_pending();
+    ktest_assert!(
+        RUNS.load(Ordering::Relaxed) == 2,
+        "delayed work did not run"
+    );
+    Ok(())
+}
+
+fn rcu_defer_drop() -> TestResult {
+    use core::sync::atomic::{AtomicBool, Ordering};
+
+    static DROPPED: AtomicBool = AtomicBool::new(false);
+    struct Retired;
+    impl Drop for Retired {
+        fn drop(&mut self) {
+            DROPPED.store(true, Ordering::Relaxed);
+        }
+    }
+
+    let guard = rcu::read_lock();
+    rcu::defer_drop(Retired);
+    rcu::reclaim();
+    ktest_assert!(
+        !DROPPED.load(Ordering::Relaxed),
+        "dropped inside a read-side section"
+    );
+
+    drop(guard);
+    rcu::reclaim();
+    ktest_assert!(
+        DROPPED.load(Ordering::Relaxed),
+        "not dropped after the section"
+    );
+    Ok(())
+}
+
+fn rw_spinlock_upgrade() -> TestResult {
+    use crate::sync::{RwLock, RwLockUpgradableGuard, RwLockWriteGuard};
+
+    let lock = RwLock::new(0);
+    let reader = lock.read();
+    let upgradable = lock.upgradable_read();
+
+    // An upgradable reader keeps new readers out, and waits for those already in to upgrade.
+    ktest_assert!(lock.try_read().is_none());
+    let Err(upgradable) = RwLockUpgradableGuard::try_upgrade(upgradable) else {
+        return Err("upgraded alongside a reader".into());
+    };
+    drop(reader);
+
+    let mut writer = RwLockUpgradableGuard::upgrade(upgradable);
+    *writer = 1;
+    ktest_assert!(lock.try_read().is_none());
+
+    let reader = RwLockWriteGuard::downgrade(writer);
+    ktest_assert!(*reader == 1);
+    ktest_assert!(lock.try_read().is_some());
+    ktest_assert!(lock.try_write().is_none());
+    drop(reader);
+    ktest_assert!(!lock.is_locked());
+    Ok(())
+}
+
+fn percpu_vars() -> TestResult {
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    percpu! {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    }
+
+    COUNTER.get().fetch_add(1, Ordering::Relaxed);
+    let this_cpu = COUNTER.get_for(PercpuBlock::current().cpu_id);
+    ktest_assert!(this_cpu.is_some_and(|counter| core::ptr::eq(counter, COUNTER.get())));
+    ktest_assert!(
+        COUNTER
+            .iter()
+            .map(|counter| counter.load(Ordering::Relaxed))
+            .sum::<usize>()
+            == 1,
+        "incremented on another CPU"
+    );
+    Ok(())
+}
+
+fn slab_cache() -> TestResult {
+    use crate::sync::Mutex;
+    use core::alloc::Layout;
+
+    percpu! {
+        static MAGAZINES: Mutex<Magazine> = Mutex::new(Magazine::new());
+    }
+    static CACHE: SlabCache = SlabCache::new(Layout::new::<[u64; 40]>(), &MAGAZINES);
+
+    let objects = (0..100).map(|_| CACHE.alloc()).collect::<Vec<_>>();
+    for (i, &object) in objects.iter().enumerate() {
+        ktest_assert!(!object.is_null(), "allocation {} failed", i);
+        ktest_assert!(object as usize % 8 == 0, "{:p} is not aligned", object);
+        ktest_assert!(
+            !objects[..i]
+                .iter()
+                .any(|&other| (other as usize).abs_diff(object as usize) < 320),
+            "{:p} overlaps another object",
+            object
+        );
+    }
+
+    for object in objects {
+        unsafe { CACHE.free(object) };
+    }
+    ktest_assert!(CACHE.shrink() > 0, "no slab was freed");
+    ktest_assert!(CACHE.shrink() == 0, "slabs were freed twice");
+    Ok(())
+}
+
+/// XSAVE leaves the registers of the legacy region out of the area when they are in their initial
+/// configuration, which must not hide those written by a tracer.
+#[cfg(target_arch = "x86_64")]
+fn fx_regs_initial_state() -> TestResult {
+    let context_lock = context::current();
+    let mut context = context_lock.write();
+    let saved = context.kfx.to_vec();
+
+    context.kfx.fill(0);
+    let mut regs = context.get_fx_regs();
+    let mut xmm_space = regs.xmm_space;
+    xmm_space[3] = 0x1234;
+    regs.xmm_space = xmm_space;
+    context.set_fx_regs(regs);
+    let xmm_space = context.get_fx_regs().xmm_space;
+
+    context.kfx.copy_from_slice(&saved);
+    ktest_assert!(xmm_space[3] == 0x1234);
+    Ok(())
+}
@@ -0,0 +1,63 @@
+//! Fault injection, making chosen operations fail as if they ran out of memory or hit an invalid
+//! user address.
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+#[derive(Clone, Copy, Debug)]
+#[repr(usize)]
+pub enum Site {
+    /// `allocate_p2frame_complex` returns `None`.
+    FrameAlloc,
+    /// The fallible heap allocation helpers in `common::try_alloc` fail with ENOMEM.
+    HeapAlloc,
+    /// Copies to and from userspace fail with EFAULT.
+    UserCopy,
+}
+
+const SITE_COUNT: usize = 3;
+const DISABLED: usize = usize::MAX;
+
+/// For each site, the number of calls to let succeed before failing one.
+static COUNTDOWN: [AtomicUsize; SITE_COUNT] = {
+    const INIT: AtomicUsize = AtomicUsize::new(DISABLED);
+    [INIT; SITE_COUNT]
+};
+/// For each site, the number of failures injected.
+static INJECTED: [AtomicUsize; SITE_COUNT] = {
+    const INIT: AtomicUsize = AtomicUsize::new(0);
+    [INIT; SITE_COUNT]
+};
+
+/// Make the call at `site` after the next `after` calls fail, once.
+pub fn inject(site: Site, after: usize) {
+    COUNTDOWN[site as usize].store(after, Ordering::SeqCst);
+}
+
+/// The number of failures injected at `site` since the last `clear`.
+pub fn injected(site: Site) -> usize {
+    INJECTED[site as usize].load(Ordering::SeqCst)
+}
+
+pub fn clear() {
+    for (countdown, injected) in COUNTDOWN.iter().zip(&INJECTED) {
+        countdown.store(DISABLED, Ordering::SeqCst);
+        injected.store(0, Ordering::SeqCst);
+    }
+}
+
+/// Called at each site, returning whether to fail.
+#[inline]
+pub fn should_fail(site: Site) -> bool {
+    let countdown = &COUNTDOWN[site as usize];
+    let result = countdown.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| match n {
+        DISABLED => None,
+        0 => Some(DISABLED),
+        n => Some(n - 1),
+    });
+    if result == Ok(0) {
+        INJECTED[site as usize].fetch_add(1, Ordering::SeqCst);
+        true
+    } else {
+        false
+    }
+}
@@ -0,0 +1,82 @@
+//! In-kernel tests, run at boot when the `ktest` feature is enabled.
+//!
+//! The tests run in the kmain context, before userspace is started, and the results are printed
+//! to the console. Afterwards the machine is stopped, and on x86 QEMU exits through the
+//! `isa-debug-exit` device (`-device isa-debug-exit,iobase=0xf4,iosize=0x04`) with status 33 if
+//! all tests passed, or 35 otherwise.
+
+use alloc::string::String;
+
+mod cases;
+pub mod fault;
+
+pub type TestResult<T = ()> = Result<T, String>;
+
+pub struct Test {
+    pub name: &'static str,
+    pub run: fn() -> TestResult,
+}
+
+/// Fail the current test if the condition does not hold.
+macro_rules! ktest_assert {
+    ($cond:expr) => {
+        if !$cond {
+            return Err(alloc::format!(
+                "assertion failed: {} at {}:{}",
+                stringify!($cond),
+                file!(),
+                line!()
+            ));
+        }
+    };
+    ($cond:expr, $($arg:tt)+) => {
+        if !$cond {
+            return Err(alloc::format!(
+                "{} at {}:{}",
+                alloc::format!($($arg)+),
+                file!(),
+                line!()
+            ));
+        }
+    };
+}
+pub(crate) use ktest_assert;
+
+pub fn run() -> ! {
+    let tests = cases::TESTS;
+    println!("ktest: running {} tests", tests.len());
+
+    let mut failed = 0;
+    for test in tests {
+        fault::clear();
+        let result = (test.run)();
+        fault::clear();
+
+        match result {
+            Ok(()) => println!("ktest: {} ... ok", test.name),
+            Err(message) => {
+                println!("ktest: {} ... FAILED: {}", test.name, message);
+                failed += 1;
+            }
+        }
+    }
+
+    println!("ktest: {} passed, {} failed", tests.len() - failed, failed);
+    ::log::logger().flush();
+
+    exit(failed == 0)
+}
+
+fn exit(success: bool) -> ! {
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    unsafe {
+        use crate::syscall::io::{Io, Pio};
+
+        Pio::<u32>::new(0xF4).write(if success { 0x10 } else { 0x11 });
+    }
+
+    if !success {
+        println!("ktest: FAILED");
+    }
+    unsafe { crate::stop::kstop() }
+}
@@ -0,0 +1,113 @@
+//! Kernel lockdown.
+//!
+//! The lockdown level is selected at boot using `LOCKDOWN=integrity` or
+//! `LOCKDOWN=confidentiality` in the environment, and restricts all of userspace, including root.
+//! The integrity level prevents userspace from modifying the running kernel, by denying mappings
+//! of RAM through `memory:physical`, port I/O privileges, the kernel debugger and loading another
+//! kernel with kexec. The confidentiality level additionally prevents reading kernel memory, by
+//! denying kernel profiling and tracing, and disabling speculative execution mitigations.
+//!
+//! There is no interface for accessing MSRs from userspace, so nothing needs to be denied there.
+
+use core::{
+    str,
+    sync::atomic::{AtomicU8, Ordering},
+};
+
+use crate::syscall::error::{Error, Result, EPERM};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(u8)]
+pub enum Level {
+    None = 0,
+    Integrity = 1,
+    Confidentiality = 2,
+}
+
+impl Level {
+    fn from_name(name: &str) -> Option<Self> {
+        Some(match name {
+            "none" => Level::None,
+            "integrity" => Level::Integrity,
+            "confidentiality" => Level::Confidentiality,
+            _ => return None,
+        })
+    }
+    pub fn name(self) -> &'static str {
+        match self {
+            Level::None => "none",
+            Level::Integrity => "integrity",
+            Level::Confidentiality => "confidentiality",
+        }
+    }
+}
+
+/// Features that are denied when locked down.
+#[derive(Clone, Copy, Debug)]
+pub enum Reason {
+    PhysicalMemory,
+    PortIo,
+    #[cfg_attr(
+        not(any(feature = "debugger", all(feature = "gdbstub", target_arch = "x86_64"))),
+        allow(dead_code)
+    )]
+    KernelDebugger,
+    #[cfg_attr(not(feature = "profiling"), allow(dead_code))]
+    KernelProfiling,
+    KernelTracing,
+    Mitigations,
+    #[cfg_attr(not(target_arch = "x86_64"), allow(dead_code))]
+    Kexec,
+}
+
+impl Reason {
+    /// The lowest level denying this feature.
+    fn level(self) -> Level {
+        match self {
+            Reason::PhysicalMemory | Reason::PortIo | Reason::KernelDebugger | Reason::Kexec => {
+                Level::Integrity
+            }
+            Reason::KernelProfiling | Reason::KernelTracing | Reason::Mitigations => {
+                Level::Confidentiality
+            }
+        }
+    }
+}
+
+static LEVEL: AtomicU8 = AtomicU8::new(Level::None as u8);
+
+pub fn init(env: &[u8]) {
+    for line in str::from_utf8(env).unwrap_or("").lines() {
+        let mut parts = line.splitn(2, '=');
+        let name = parts.next().unwrap_or("");
+        let value = parts.next().unwrap_or("");
+
+        if name == "LOCKDOWN" {
+            match Level::from_name(value) {
+                Some(level) => {
+                    LEVEL.store(level as u8, Ordering::SeqCst);
+                    log::info!("Kernel lockdown: {}", level.name());
+                }
+                None => log::warn!("Unknown lockdown level {:?}", value),
+            }
+        }
+    }
+}
+
+pub fn level() -> Level {
+    match LEVEL.load(Ordering::Relaxed) {
+        0 => Level::None,
+        1 => Level::Integrity,
+        _ => Level::Confidentiality,
+    }
+}
+
+/// Fails with EPERM if `reason` is denied by the current lockdown level.
+pub fn check(reason: Reason) -> Result<()> {
+    let level = level();
+    if level >= reason.level() {
+        log::warn!("Lockdown ({}): denied {:?}", level.name(), reason);
+        return Err(Error::new(EPERM));
+    }
+    Ok(())
+}
@@ -5,6 +5,7 @@ pub static LOG: Mutex<Option<Log>> = Mutex::new(None);
 
 pub fn init() {
     *LOG.lock() = Some(Log::new(1024 * 1024));
+    crate::klog::init();
 }
 
 pub struct Log {
@@ -43,6 +44,7 @@ impl ::log::Log for RedoxLogger {
         false
     }
     fn log(&self, record: &log::Record<'_>) {
+        crate::klog::record(record);
         (self.log_func)(record)
     }
     fn flush(&self) {}
@@ -89,6 +89,9 @@ use crate::arch::*;
 /// Heap allocators
 mod allocator;
 
+/// Syscall auditing
+mod audit;
+
 /// ACPI table parsing
 #[cfg(feature = "acpi")]
 #[allow(dead_code)] // TODO
@@ -100,6 +103,9 @@ mod dtb;
 /// Logical CPU ID and bitset types
 mod cpu_set;
 
+/// CPU frequency scaling
+mod cpufreq;
+
 /// Context management
 mod context;
 
@@ -107,33 +113,82 @@ mod context;
 #[cfg(feature = "debugger")]
 mod debugger;
 
+/// EFI runtime services
+#[cfg(target_arch = "x86_64")]
+mod efi;
+
+/// GDB remote serial protocol stub
+#[cfg(all(feature = "gdbstub", target_arch = "x86_64"))]
+mod gdbstub;
+
 /// Architecture-independent devices
 mod devices;
 
 /// ELF file parsing
 mod elf;
 
+/// Entropy pool
+mod entropy;
+
 /// Event handling
 mod event;
 
+/// CPU hotplug
+mod hotplug;
+
+/// Idle state selection
+mod idle;
+
+/// Interrupt statistics
+mod irq_stats;
+
 /// External functions
 #[cfg(not(test))]
 mod externs;
 
+/// In-kernel tests
+#[cfg(feature = "ktest")]
+mod ktest;
+
+/// Kernel address space layout randomization
+mod kaslr;
+
+/// Booting another kernel from the running one
+#[cfg(target_arch = "x86_64")]
+mod kexec;
+
+/// Kernel log ring buffer
+mod klog;
+
+/// Kernel symbol table
+mod ksyms;
+
+/// Kernel lockdown
+mod lockdown;
+
 /// Logging
 mod log;
 use ::log::info;
 use alloc::sync::Arc;
-use spinning_top::RwSpinlock;
+use crate::sync::RwSpinlock;
 
 /// Memory management
 mod memory;
 
+/// Speculative execution mitigations
+mod mitigations;
+
+/// NUMA topology and memory placement policies
+mod numa;
+
 /// Panic
 mod panic;
 
 mod percpu;
 
+/// Hardware performance counters
+mod perf;
+
 /// Process tracing
 mod ptrace;
 
@@ -144,9 +199,15 @@ pub mod profiling;
 /// Schemes, filesystem handlers
 mod scheme;
 
+/// Reboot and power-off
+mod shutdown;
+
 /// Early init
 mod startup;
 
+/// Suspend to RAM
+mod suspend;
+
 /// Synchronization primitives
 mod sync;
 
@@ -156,6 +217,21 @@ mod syscall;
 /// Time
 mod time;
 
+/// Per-CPU timers
+mod timer;
+
+/// Kernel event tracing
+mod trace;
+
+/// Stack unwinding
+mod unwind;
+
+/// Clock and CPU data mapped into userspace
+mod vdso;
+
+/// Deferred work
+mod workqueue;
+
 #[cfg_attr(not(test), global_allocator)]
 static ALLOCATOR: allocator::Allocator = allocator::Allocator;
 
@@ -195,12 +271,37 @@ static INIT_THREAD: spin::Once<Arc<RwSpinlock<crate::context::Context>>> = spin:
 fn kmain(cpu_count: u32, bootstrap: Bootstrap) -> ! {
     CPU_COUNT.store(cpu_count, Ordering::SeqCst);
 
+    // Every CPU has its percpu block by now.
+    #[cfg(debug_assertions)]
+    {
+        sync::lockdep::init();
+        sync::spinlock::init();
+    }
+
+    memory::asid::init();
+    memory::slab::init();
+
+    entropy::init();
+
     //Initialize the first context, stored in kernel/src/context/mod.rs
     context::init();
 
+    lockdown::init(bootstrap.env);
+    mitigations::init(bootstrap.env);
+    ptrace::init_scope(bootstrap.env);
+    context::memory::init_wx_policy(bootstrap.env);
+
+    #[cfg(all(feature = "gdbstub", target_arch = "x86_64"))]
+    gdbstub::init(bootstrap.env);
+
     //Initialize global schemes, such as `acpi:`.
     scheme::init_globals();
 
+    vdso::update();
+
+    #[cfg(feature = "ktest")]
+    ktest::run();
+
     let pid = syscall::getpid();
     info!("BSP: {:?} {}", pid, cpu_count);
     info!("Env: {:?}", ::core::str::from_utf8(bootstrap.env));
@@ -242,6 +343,17 @@ fn kmain(cpu_count: u32, bootstrap: Bootstrap) -> ! {
         }
     }
 
+    workqueue::spawn_worker();
+    if let Err(err) = memory::ksm::spawn() {
+        log::warn!("failed to spawn ksm thread: {:?}", err);
+    }
+    if let Err(err) = memory::writeback::spawn() {
+        log::warn!("failed to spawn writeback thread: {:?}", err);
+    }
+    if let Err(err) = memory::reclaim::spawn() {
+        log::warn!("failed to spawn kswapd thread: {:?}", err);
+    }
+
     run_userspace()
 }
 
@@ -263,6 +375,7 @@ fn kmain_ap(cpu_id: crate::cpu_set::LogicalCpuId) -> ! {
         }
     }
     context::init();
+    workqueue::spawn_worker();
 
     let pid = syscall::getpid();
     info!("AP {}: {:?}", cpu_id, pid);
@@ -276,13 +389,14 @@ fn run_userspace() -> ! {
     loop {
         unsafe {
             interrupt::disable();
+            hotplug::maybe_park();
             match context::switch() {
                 SwitchResult::Switched => {
                     interrupt::enable_and_nop();
                 }
                 SwitchResult::AllContextsIdle => {
-                    // Enable interrupts, then halt CPU (to save power) until the next interrupt is actually fired.
-                    interrupt::enable_and_halt();
+                    // Enable interrupts, then idle (to save power) until the next interrupt is actually fired.
+                    idle::idle();
                 }
             }
         }
@@ -328,9 +442,17 @@ mod kernel_executable_offsets {
         __bss_start,
         __bss_end,
         __usercopy_start,
-        __usercopy_end
+        __usercopy_end,
+        __eh_frame_start,
+        __eh_frame_end,
+        __percpu_start,
+        __percpu_end
     );
 
     #[cfg(target_arch = "x86_64")]
     linker_offsets!(__altrelocs_start, __altrelocs_end);
+    #[cfg(target_arch = "x86_64")]
+    linker_offsets!(__interrupt_frames_start, __interrupt_frames_end);
+    #[cfg(feature = "pti")]
+    linker_offsets!(__entry_text_start, __entry_text_end);
 }
@@ -0,0 +1,151 @@
+//! Address space IDs (PCIDs on x86_64, ASIDs on aarch64), which tag TLB entries with the address
+//! space they belong to, so that switching page tables does not have to flush the TLB.
+//!
+//! IDs are allocated to address spaces when they are first switched to, from a global pool, and
+//! only become free again once the pool is exhausted. Then the generation is increased, all IDs
+//! become free, and every CPU flushes its entire TLB before loading a page table with an ID of the
+//! new generation. Address spaces still holding an ID of an older generation get a new one the
+//! next time they are switched to.
+//!
+//! TLB shootdowns only reach the CPUs currently running an address space, while others may still
+//! have its entries cached. Every CPU that has run the address space since it got its ID is thus
+//! marked as stale by each shootdown, and flushes the entries of that ID when switching back to
+//! it.
+
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use crate::{
+    arch::rmm::{asids_enabled, invalidate_all_asids, set_user_table, ASID_COUNT},
+    context::memory::{AddrSpace, AddrSpaceWrapper},
+    cpu_set::{LogicalCpuId, LogicalCpuSet},
+    percpu::PercpuBlock,
+    sync::Mutex,
+};
+
+/// Bits of [`AsidState::id`] holding the ID, above which the generation is stored.
+const ID_BITS: u32 = 16;
+const ID_MASK: u64 = (1 << ID_BITS) - 1;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+/// Starts at 1, so that CPUs flush their TLB before the first ID is used.
+static GENERATION: AtomicU64 = AtomicU64::new(1);
+
+struct AsidPool {
+    used: [u64; ASID_COUNT.div_ceil(64)],
+    /// Where to start looking for a free ID.
+    next: usize,
+}
+
+static POOL: Mutex<AsidPool> = Mutex::new(AsidPool {
+    used: [0; ASID_COUNT.div_ceil(64)],
+    next: 1,
+});
+
+impl AsidPool {
+    fn take_free(&mut self) -> Option<u16> {
+        let id = (self.next..ASID_COUNT).find(|&id| self.used[id / 64] & (1 << (id % 64)) == 0)?;
+        self.used[id / 64] |= 1 << (id % 64);
+        self.next = id + 1;
+        Some(id as u16)
+    }
+    /// Free all IDs for the next generation. ID 0 is never allocated.
+    fn rollover(&mut self) -> u64 {
+        self.used = [0; ASID_COUNT.div_ceil(64)];
+        self.next = 1;
+        GENERATION.fetch_add(1, Ordering::AcqRel) + 1
+    }
+}
+
+/// The ID of an address space, and the CPUs that may have its entries cached.
+#[derive(Debug)]
+pub struct AsidState {
+    /// Generation and ID, or 0 if none has been allocated yet.
+    id: AtomicU64,
+    /// CPUs that have loaded the page table with the current ID.
+    ran_on: LogicalCpuSet,
+    /// CPUs that must flush the entries of the ID before loading the page table again.
+    stale_on: LogicalCpuSet,
+}
+
+impl AsidState {
+    pub const fn new() -> Self {
+        Self {
+            id: AtomicU64::new(0),
+            ran_on: LogicalCpuSet::empty(),
+            stale_on: LogicalCpuSet::empty(),
+        }
+    }
+
+    /// Called when invalidating entries of the address space, with the lock held, so that CPUs not
+    /// running it flush them before switching back to it. `except` has just done so.
+    pub fn mark_stale(&self, except: Option<LogicalCpuId>) {
+        self.stale_on.atomic_union(&self.ran_on.to_raw());
+        if let Some(cpu) = except {
+            self.stale_on.atomic_clear(cpu);
+        }
+    }
+}
+
+/// Enable address space IDs if the CPU supports them, on the BSP.
+pub fn init() {
+    if ASID_COUNT > 1 && asids_enabled() {
+        ENABLED.store(true, Ordering::Relaxed);
+        log::info!("Tagging TLB entries with {} address space IDs", ASID_COUNT);
+    }
+}
+
+/// Load the page table of `addrsp` on this CPU, whose lock is held.
+pub unsafe fn switch_to(wrapper: &AddrSpaceWrapper, addrsp: &AddrSpace) {
+    if !ENABLED.load(Ordering::Relaxed) {
+        addrsp.table.utable.make_current();
+        #[cfg(all(feature = "pti", target_arch = "x86_64"))]
+        crate::arch::pti::switch_to(&addrsp.table, None);
+        return;
+    }
+    let percpu = PercpuBlock::current();
+    let state = &wrapper.asid;
+
+    let mut id = state.id.load(Ordering::Acquire);
+    let generation = GENERATION.load(Ordering::Acquire);
+    if id >> ID_BITS != generation || percpu.asid_generation.get() != generation {
+        let mut pool = POOL.lock();
+        let mut generation = GENERATION.load(Ordering::Acquire);
+
+        id = state.id.load(Ordering::Acquire);
+        if id >> ID_BITS != generation {
+            let asid = match pool.take_free() {
+                Some(asid) => asid,
+                None => {
+                    generation = pool.rollover();
+                    pool.take_free()
+                        .expect("no address space ID after rollover")
+                }
+            };
+            // Entries tagged with the old ID are flushed as part of the rollover. Cleared before
+            // other CPUs can see the new ID and skip the pool.
+            state.ran_on.atomic_clear_all();
+            state.stale_on.atomic_clear_all();
+            id = generation << ID_BITS | u64::from(asid);
+            state.id.store(id, Ordering::Release);
+        }
+        if percpu.asid_generation.get() != generation {
+            invalidate_all_asids();
+            percpu.asid_generation.set(generation);
+        }
+    }
+
+    let cpu = percpu.cpu_id;
+    let flush = state.stale_on.atomic_contains(cpu);
+    if flush {
+        state.stale_on.atomic_clear(cpu);
+    }
+    state.ran_on.atomic_set(cpu);
+
+    set_user_table(
+        addrsp.table.utable.table().phys(),
+        (id & ID_MASK) as u16,
+        flush,
+    );
+    #[cfg(all(feature = "pti", target_arch = "x86_64"))]
+    crate::arch::pti::switch_to(&addrsp.table, Some(((id & ID_MASK) as u16, flush)));
+}
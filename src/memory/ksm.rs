@@ -0,0 +1,263 @@
+//! Kernel samepage merging.
+//!
+//! Address spaces opt in through their `ksm` proc: handle, after which the `[ksm]` kernel thread
+//! periodically scans their private anonymous grants, and merges pages with identical contents
+//! into a single frame, shared copy-on-write as if the pages had been inherited through a fork.
+//!
+//! Merged frames are kept in the stable tree, indexed by a checksum of their contents. The tree
+//! holds a reference of its own to each frame, so that it is never writable, and drops it once no
+//! mapping is left. Pages whose checksum changed since the previous pass are skipped, as they are
+//! likely to be written again soon. Otherwise, a page is merged into a stable frame with the same
+//! contents, or, if an earlier page with the same checksum was seen during the current pass, put
+//! in the stable tree itself, so that the earlier page can be merged into it during the next pass.
+
+use alloc::{
+    collections::{BTreeMap, BTreeSet},
+    sync::Arc,
+    vec::Vec,
+};
+use core::{
+    slice,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use crate::{
+    common::try_alloc::try_push,
+    context::{
+        self,
+        memory::AddrSpaceWrapper,
+        process::{new_process, ProcessInfo},
+    },
+    paging::{Page, VirtualAddress},
+    scheme::SchemeNamespace,
+    sync::{Mutex, MutexGuard, WaitCondition},
+    syscall::error::Result,
+    time,
+};
+
+use super::{
+    deallocate_frame, get_page_info, Frame, PhysicalAddress, RefCount, RefKind, RmmA, RmmArch,
+    PAGE_SIZE,
+};
+
+/// Pages scanned at most while holding the lock of an address space.
+const BATCH_PAGES: usize = 256;
+/// Time between the end of a pass and the start of the next one.
+const PASS_INTERVAL: u128 = time::NANOS_PER_SEC / 5;
+
+/// Per-address space state, present if it opted in.
+#[derive(Debug, Default)]
+pub struct KsmState {
+    /// Checksums of the pages scanned during the current pass,
+    checksums: BTreeMap<Page, u64>,
+    /// and during the previous one.
+    previous: BTreeMap<Page, u64>,
+    /// Pages merged into a stable frame so far.
+    pub merged: usize,
+}
+
+impl KsmState {
+    fn start_pass(&mut self) {
+        self.previous = core::mem::take(&mut self.checksums);
+    }
+    /// Record the checksum of `page`, returning whether it is the same as during the previous pass.
+    pub fn update(&mut self, page: Page, checksum: u64) -> bool {
+        let previous = self.previous.remove(&page);
+        self.checksums.insert(page, checksum);
+        previous == Some(checksum)
+    }
+}
+
+pub struct Stats {
+    pub full_scans: AtomicUsize,
+    pub pages_scanned: AtomicUsize,
+    pub pages_merged: AtomicUsize,
+}
+pub static STATS: Stats = Stats {
+    full_scans: AtomicUsize::new(0),
+    pages_scanned: AtomicUsize::new(0),
+    pages_merged: AtomicUsize::new(0),
+};
+
+fn page_words(frame: Frame) -> &'static [u64] {
+    unsafe {
+        slice::from_raw_parts(
+            RmmA::phys_to_virt(frame.base()).data() as *const u64,
+            PAGE_SIZE / 8,
+        )
+    }
+}
+
+/// FNV-1a over the words of the page.
+pub fn checksum(frame: Frame) -> u64 {
+    page_words(frame)
+        .iter()
+        .fold(0xcbf2_9ce4_8422_2325, |hash, &word| {
+            (hash ^ word).wrapping_mul(0x0100_0000_01b3)
+        })
+}
+
+pub struct StableTree {
+    frames: BTreeSet<(u64, Frame)>,
+}
+
+impl StableTree {
+    fn with_checksum(&self, checksum: u64) -> impl Iterator<Item = Frame> + '_ {
+        self.frames
+            .range((checksum, Frame::containing(PhysicalAddress::new(0)))..)
+            .take_while(move |&&(c, _)| c == checksum)
+            .map(|&(_, frame)| frame)
+    }
+    pub fn contains_checksum(&self, checksum: u64) -> bool {
+        self.with_checksum(checksum).next().is_some()
+    }
+    /// Find a stable frame with the same contents as `frame`, which must not be writable.
+    pub fn find(&self, checksum: u64, frame: Frame) -> Option<Frame> {
+        let words = page_words(frame);
+        self.with_checksum(checksum)
+            .find(|&stable| page_words(stable) == words)
+    }
+    /// Add `frame` to the tree, taking a reference to it.
+    ///
+    /// The frame must be mapped by a single, read-only, page.
+    pub fn insert(&mut self, checksum: u64, frame: Frame) {
+        let info = get_page_info(frame).expect("allocated frame needs page info");
+        if info.add_ref(RefKind::Cow).is_ok() {
+            self.frames.insert((checksum, frame));
+        }
+    }
+    /// Release the frames no longer mapped anywhere.
+    fn prune(&mut self) {
+        self.frames.retain(|&(_, frame)| {
+            let info = get_page_info(frame).expect("allocated frame needs page info");
+            if info.refcount() != Some(RefCount::One) {
+                return true;
+            }
+            // No mapping is left to add a reference while the tree is locked.
+            let _ = info.remove_ref();
+            unsafe {
+                deallocate_frame(frame);
+            }
+            false
+        });
+    }
+}
+
+static STABLE: Mutex<StableTree> = Mutex::new(StableTree {
+    frames: BTreeSet::new(),
+});
+
+pub fn stable() -> MutexGuard<'static, StableTree> {
+    STABLE.lock()
+}
+
+/// Number of frames in the stable tree, and how many more pages are mapping them.
+pub fn sharing() -> (usize, usize) {
+    let stable = STABLE.lock();
+    let sharing = stable
+        .frames
+        .iter()
+        .map(|&(_, frame)| match get_page_info(frame).and_then(|i| i.refcount()) {
+            // Not counting the reference of the tree and the first mapping.
+            Some(RefCount::Cow(count)) => count.get().saturating_sub(2),
+            _ => 0,
+        })
+        .sum();
+    (stable.frames.len(), sharing)
+}
+
+/// Held while looking for address spaces that opted in, before waiting.
+static IDLE: Mutex<()> = Mutex::new(());
+static WAKE: WaitCondition = WaitCondition::new();
+
+/// Wake up the thread, after an address space opted in.
+pub fn wake() {
+    // Orders this against the thread checking for address spaces that opted in.
+    drop(IDLE.lock());
+    WAKE.notify();
+}
+
+fn opted_in() -> Vec<Arc<AddrSpaceWrapper>> {
+    let mut addr_spaces = Vec::<Arc<AddrSpaceWrapper>>::new();
+    for context_ref in context::contexts().iter().filter_map(|r| r.upgrade()) {
+        let Ok(addr_space) = context_ref.read().addr_space().cloned() else {
+            continue;
+        };
+        if addr_spaces.iter().any(|a| Arc::ptr_eq(a, &addr_space)) {
+            continue;
+        }
+        if try_push(&mut addr_spaces, addr_space).is_err() {
+            break;
+        }
+    }
+    addr_spaces.retain(|a| a.acquire_read().ksm.is_some());
+    addr_spaces
+}
+
+fn sleep(duration: u128) {
+    let current = context::current();
+    {
+        let mut context = current.write();
+        context.wake = Some(time::monotonic() + duration);
+        context.block("ksm");
+    }
+    context::switch();
+    current.write().wake = None;
+}
+
+extern "C" fn ksm_main() {
+    // Runs with interrupts disabled like the rest of the kernel, so it must yield by itself.
+    let mut unstable = BTreeSet::new();
+    loop {
+        let addr_spaces = {
+            let idle = IDLE.lock();
+            let addr_spaces = opted_in();
+            if addr_spaces.is_empty() {
+                WAKE.wait(idle, "ksm");
+                continue;
+            }
+            addr_spaces
+        };
+
+        STABLE.lock().prune();
+        unstable.clear();
+
+        for addr_space in addr_spaces {
+            match addr_space.acquire_write().ksm.as_mut() {
+                Some(state) => state.start_pass(),
+                None => continue,
+            }
+            let mut next = Some(Page::containing_address(VirtualAddress::new(0)));
+            while let Some(start) = next {
+                next = addr_space.ksm_scan(start, BATCH_PAGES, &mut unstable);
+                // Let the scanned contexts run in between.
+                context::switch();
+            }
+        }
+        STATS.full_scans.fetch_add(1, Ordering::Relaxed);
+
+        sleep(PASS_INTERVAL);
+    }
+}
+
+/// Spawn the `[ksm]` kernel thread.
+pub fn spawn() -> Result<()> {
+    let process = new_process(|pid| ProcessInfo {
+        pid,
+        pgid: pid,
+        ppid: pid,
+        session_id: pid,
+        ruid: 0,
+        rgid: 0,
+        euid: 0,
+        egid: 0,
+        rns: SchemeNamespace::new(0),
+        ens: SchemeNamespace::new(0),
+    })?;
+    let context_lock = context::spawn(false, process, ksm_main)?;
+
+    let mut context = context_lock.write();
+    context.status = context::Status::Runnable;
+    context.name = "[ksm]".into();
+    Ok(())
+}
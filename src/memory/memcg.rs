@@ -0,0 +1,424 @@
+//! Memory control groups.
+//!
+//! Memory groups form a hierarchy named by paths in the `memcg:` scheme, like `build/cc`, and
+//! limit the memory of the user grants of the contexts attached to them or to their descendants.
+//! Writing `attach <pid>` to a group moves every context of that process into it, and the threads
+//! and children they create stay there. Contexts outside of any group are not accounted.
+//!
+//! A frame allocated for a grant is charged to the group of the context allocating it, which is the
+//! faulting one for pages allocated on demand, to that group's parent, and so on up the hierarchy.
+//! It stays charged until it is freed, so the group is recorded in the [`PageInfo`] of the frame,
+//! in the word the allocator only uses for free frames. A charge fails if it would take any of
+//! these groups over its limit. Page faults then have pages of the group swapped out, if there is
+//! a swap provider, and wait for them to be written out before retrying, while other allocations
+//! fail with `ENOMEM`.
+//!
+//! The counters of the groups live in a fixed table rather than in [`MemGroup`], so that frames
+//! can be uncharged as they are freed without taking any lock. A removed group keeps its entry
+//! until the last frame charged to it is freed.
+
+use alloc::{collections::BTreeMap, string::String, sync::Arc, vec::Vec};
+use core::sync::atomic::{AtomicU16, AtomicU8, AtomicUsize, Ordering};
+
+use crate::{
+    common::try_alloc::{try_arc, try_push, try_string},
+    context::{
+        self,
+        memory::{AddrSpaceWrapper, PfError},
+        process::ProcessId,
+    },
+    percpu::PercpuBlock,
+    sync::RwLock,
+    syscall::error::{Error, Result, EBUSY, EEXIST, EINVAL, ENOENT, ENOSPC, ESRCH},
+    time,
+};
+
+use super::{get_page_info, swap, Frame, PAGE_SIZE};
+
+/// Index of a group in the table of counters.
+pub type MemcgId = u16;
+
+const MAX_GROUPS: usize = 256;
+
+const STATE_FREE: u8 = 0;
+const STATE_LIVE: u8 = 1;
+/// The group was removed, but frames are still charged to it.
+const STATE_REMOVED: u8 = 2;
+
+/// Pages evicted beyond the excess of a group, so that a fault does not reclaim again right away.
+const RECLAIM_BATCH: usize = 32;
+/// How long a fault waits for evicted pages to be written out, in steps of
+/// [`RECLAIM_WAIT_STEP`].
+const RECLAIM_WAIT_STEPS: usize = 10;
+const RECLAIM_WAIT_STEP: u128 = 10_000_000;
+
+struct Counters {
+    state: AtomicU8,
+    /// Index of the parent plus one, or zero for top-level groups.
+    parent: AtomicU16,
+    /// Pages charged to the group and its descendants.
+    usage: AtomicUsize,
+    limit: AtomicUsize,
+    peak: AtomicUsize,
+    /// Charges that failed because of the limit of this group.
+    failcnt: AtomicUsize,
+    /// Pages evicted because of the limit of this group.
+    reclaimed: AtomicUsize,
+}
+
+const FREE_COUNTERS: Counters = Counters {
+    state: AtomicU8::new(STATE_FREE),
+    parent: AtomicU16::new(0),
+    usage: AtomicUsize::new(0),
+    limit: AtomicUsize::new(usize::MAX),
+    peak: AtomicUsize::new(0),
+    failcnt: AtomicUsize::new(0),
+    reclaimed: AtomicUsize::new(0),
+};
+static TABLE: [Counters; MAX_GROUPS] = [FREE_COUNTERS; MAX_GROUPS];
+
+/// The groups, by path.
+static GROUPS: RwLock<BTreeMap<String, Arc<MemGroup>>> = RwLock::new(BTreeMap::new());
+
+/// The group `id` and its ancestors, from the bottom up.
+fn ancestors(id: MemcgId) -> impl Iterator<Item = (MemcgId, &'static Counters)> {
+    let mut next = Some(id);
+    core::iter::from_fn(move || {
+        let id = next?;
+        let counters = &TABLE[usize::from(id)];
+        next = counters.parent.load(Ordering::Relaxed).checked_sub(1);
+        Some((id, counters))
+    })
+}
+
+/// Free the entry of a removed group once nothing is charged to it anymore.
+fn try_release(counters: &Counters) {
+    if counters.usage.load(Ordering::Acquire) == 0 {
+        let _ = counters.state.compare_exchange(
+            STATE_REMOVED,
+            STATE_FREE,
+            Ordering::AcqRel,
+            Ordering::Relaxed,
+        );
+    }
+}
+
+/// Charge `pages` to `id` and its ancestors, unless the limit of one of them prevents it.
+fn try_charge(id: MemcgId, pages: usize) -> bool {
+    for (level, (_, counters)) in ancestors(id).enumerate() {
+        let usage = counters.usage.fetch_add(pages, Ordering::AcqRel) + pages;
+        if usage > counters.limit.load(Ordering::Relaxed) {
+            counters.failcnt.fetch_add(1, Ordering::Relaxed);
+            for (_, charged) in ancestors(id).take(level + 1) {
+                charged.usage.fetch_sub(pages, Ordering::AcqRel);
+            }
+            return false;
+        }
+        counters.peak.fetch_max(usage, Ordering::Relaxed);
+    }
+    true
+}
+
+/// Uncharge `pages` from `id` and its ancestors.
+fn uncharge_id(id: MemcgId, pages: usize) {
+    for (_, counters) in ancestors(id) {
+        if counters.usage.fetch_sub(pages, Ordering::AcqRel) == pages {
+            try_release(counters);
+        }
+    }
+}
+
+/// The group of the current context.
+pub fn current() -> Option<MemcgId> {
+    PercpuBlock::current().switch_internals.memcg.get()
+}
+
+/// Charge `pages` about to be allocated for a grant to the group of the current context, if any,
+/// returning that group.
+pub fn charge_current(pages: usize) -> core::result::Result<Option<MemcgId>, PfError> {
+    let Some(id) = current() else {
+        return Ok(None);
+    };
+    if !try_charge(id, pages) {
+        return Err(PfError::MemLimit);
+    }
+    Ok(Some(id))
+}
+
+/// Undo [`charge_current`] if the allocation failed.
+pub fn uncharge(memcg: Option<MemcgId>, pages: usize) {
+    if let Some(id) = memcg {
+        uncharge_id(id, pages);
+    }
+}
+
+/// Record that the `2^order` frames starting at `frame` are charged to `memcg`, so that they are
+/// uncharged when freed. Frames that may later be freed one by one must be tagged even if not
+/// charged, as the tails of a block may hold stale free list links.
+pub fn tag(frame: Frame, order: u32, memcg: Option<MemcgId>) {
+    let tag = memcg.map_or(0, |id| usize::from(id) + 1);
+    for i in 0..1 << order {
+        if let Some(info) = get_page_info(frame.next_by(i)) {
+            info.next.store(tag, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Uncharge the `2^order` frames starting at `frame` as they are freed.
+pub(super) fn uncharge_freed(frame: Frame, order: u32) {
+    let tagged = |i| {
+        get_page_info(frame.next_by(i))
+            .and_then(|info| info.next.swap(0, Ordering::Relaxed).checked_sub(1))
+            .map(|id| id as MemcgId)
+    };
+    // The frames of a block are either all tagged, or it was never charged.
+    let Some(id) = tagged(0) else {
+        return;
+    };
+    uncharge_id(id, 1);
+    for i in 1..1 << order {
+        if let Some(id) = tagged(i) {
+            uncharge_id(id, 1);
+        }
+    }
+}
+
+/// A group of contexts sharing a memory limit.
+#[derive(Debug)]
+pub struct MemGroup {
+    id: MemcgId,
+    path: String,
+    parent: Option<Arc<MemGroup>>,
+}
+
+impl MemGroup {
+    pub fn id(&self) -> MemcgId {
+        self.id
+    }
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+    fn counters(&self) -> &'static Counters {
+        &TABLE[usize::from(self.id)]
+    }
+    /// Whether this is the group `id` or one of its descendants.
+    pub fn is_within(&self, id: MemcgId) -> bool {
+        self.id == id
+            || self
+                .parent
+                .as_ref()
+                .is_some_and(|parent| parent.is_within(id))
+    }
+
+    /// The limit in bytes, or None if unlimited.
+    pub fn limit(&self) -> Option<usize> {
+        match self.counters().limit.load(Ordering::Relaxed) {
+            usize::MAX => None,
+            pages => Some(pages * PAGE_SIZE),
+        }
+    }
+    /// Set the limit in bytes, rounded down to pages. Pages already charged beyond the limit stay
+    /// charged, but no more can be charged until enough were freed.
+    pub fn set_limit(&self, limit: Option<usize>) {
+        let pages = limit.map_or(usize::MAX, |bytes| bytes / PAGE_SIZE);
+        self.counters().limit.store(pages, Ordering::Relaxed);
+    }
+
+    /// The group's usage, peak usage, failed charges and evicted pages, in that order, the first
+    /// two in bytes.
+    pub fn stats(&self) -> [usize; 4] {
+        let counters = self.counters();
+        [
+            counters.usage.load(Ordering::Relaxed) * PAGE_SIZE,
+            counters.peak.load(Ordering::Relaxed) * PAGE_SIZE,
+            counters.failcnt.load(Ordering::Relaxed),
+            counters.reclaimed.load(Ordering::Relaxed),
+        ]
+    }
+}
+
+impl Drop for MemGroup {
+    fn drop(&mut self) {
+        let counters = self.counters();
+        counters.state.store(STATE_REMOVED, Ordering::Release);
+        try_release(counters);
+    }
+}
+
+/// Create the group at `path`, whose parent must exist, if there is one.
+pub fn create(path: &str) -> Result<Arc<MemGroup>> {
+    if path.is_empty() || path.split('/').any(|name| name.is_empty()) {
+        return Err(Error::new(EINVAL));
+    }
+    let mut groups = GROUPS.write();
+    if groups.contains_key(path) {
+        return Err(Error::new(EEXIST));
+    }
+    let parent = match path.rsplit_once('/') {
+        Some((parent, _)) => Some(Arc::clone(groups.get(parent).ok_or(Error::new(ENOENT))?)),
+        None => None,
+    };
+
+    let id = TABLE
+        .iter()
+        .position(|counters| {
+            counters
+                .state
+                .compare_exchange(STATE_FREE, STATE_LIVE, Ordering::AcqRel, Ordering::Relaxed)
+                .is_ok()
+        })
+        .ok_or(Error::new(ENOSPC))? as MemcgId;
+    let counters = &TABLE[usize::from(id)];
+    counters.parent.store(
+        parent.as_ref().map_or(0, |parent| parent.id + 1),
+        Ordering::Relaxed,
+    );
+    counters.limit.store(usize::MAX, Ordering::Relaxed);
+    counters.peak.store(0, Ordering::Relaxed);
+    counters.failcnt.store(0, Ordering::Relaxed);
+    counters.reclaimed.store(0, Ordering::Relaxed);
+
+    // Dropping the group on failure frees the entry again.
+    let group = try_arc(MemGroup {
+        id,
+        path: try_string(path)?,
+        parent,
+    })?;
+    groups.insert(group.path.clone(), Arc::clone(&group));
+    Ok(group)
+}
+
+pub fn lookup(path: &str) -> Result<Arc<MemGroup>> {
+    GROUPS.read().get(path).cloned().ok_or(Error::new(ENOENT))
+}
+
+/// The paths of all groups, parents before their children.
+pub fn paths() -> Vec<String> {
+    GROUPS.read().keys().cloned().collect()
+}
+
+/// Remove the group at `path`, which must have neither children nor contexts attached.
+pub fn remove(path: &str) -> Result<()> {
+    let mut groups = GROUPS.write();
+    let group = groups.get(path).ok_or(Error::new(ENOENT))?;
+    if groups
+        .values()
+        .any(|other| other.parent.as_ref().is_some_and(|p| Arc::ptr_eq(p, group)))
+    {
+        return Err(Error::new(EBUSY));
+    }
+    for context_ref in context::contexts().iter().filter_map(|r| r.upgrade()) {
+        if context_ref
+            .read()
+            .memcg
+            .as_ref()
+            .is_some_and(|memcg| Arc::ptr_eq(memcg, group))
+        {
+            return Err(Error::new(EBUSY));
+        }
+    }
+    groups.remove(path);
+    Ok(())
+}
+
+/// Move every context of process `pid` into `group`.
+pub fn attach(group: &Arc<MemGroup>, pid: ProcessId) -> Result<()> {
+    let mut found = false;
+    for context_ref in context::contexts().iter().filter_map(|r| r.upgrade()) {
+        let mut context = context_ref.write();
+        if context.pid != pid {
+            continue;
+        }
+        context.memcg = Some(Arc::clone(group));
+        found = true;
+        drop(context);
+
+        // Other CPUs pick up the group when they next switch to the context.
+        if context::is_current(&context_ref) {
+            PercpuBlock::current()
+                .switch_internals
+                .memcg
+                .set(Some(group.id));
+        }
+    }
+    if found {
+        Ok(())
+    } else {
+        Err(Error::new(ESRCH))
+    }
+}
+
+fn sleep(duration: u128) {
+    let current = context::current();
+    {
+        let mut context = current.write();
+        context.wake = Some(time::monotonic() + duration);
+        context.block("memcg reclaim");
+    }
+    context::switch();
+    current.write().wake = None;
+}
+
+/// The group the current context is in or below that is at its limit, if any, and that limit in
+/// pages.
+pub fn full_for_current() -> Option<(MemcgId, usize)> {
+    ancestors(current()?)
+        .map(|(id, counters)| (id, counters, counters.limit.load(Ordering::Relaxed)))
+        .find(|(_, counters, limit)| counters.usage.load(Ordering::Relaxed) >= *limit)
+        .map(|(id, _, limit)| (id, limit))
+}
+
+/// Called when a page fault of the current context could not be charged, without the address
+/// space locked. Evicts pages of the group at its limit, and waits for them to be written out.
+/// Returns whether the access should be retried.
+pub fn reclaim_for_current() -> bool {
+    let Some(id) = current() else {
+        return false;
+    };
+    let Some((full_id, full)) = ancestors(id).find(|(_, counters)| {
+        counters.usage.load(Ordering::Relaxed) >= counters.limit.load(Ordering::Relaxed)
+    }) else {
+        // Pages were freed in the meantime.
+        return true;
+    };
+    if !swap::is_active() {
+        return false;
+    }
+    let mut addr_spaces = Vec::<Arc<AddrSpaceWrapper>>::new();
+    for context_ref in context::contexts().iter().filter_map(|r| r.upgrade()) {
+        let context = context_ref.read();
+        if !context
+            .memcg
+            .as_ref()
+            .is_some_and(|memcg| memcg.is_within(full_id))
+        {
+            continue;
+        }
+        let Ok(addr_space) = context.addr_space().cloned() else {
+            continue;
+        };
+        drop(context);
+        if addr_spaces.iter().any(|a| Arc::ptr_eq(a, &addr_space)) {
+            continue;
+        }
+        if try_push(&mut addr_spaces, addr_space).is_err() {
+            break;
+        }
+    }
+
+    let limit = full.limit.load(Ordering::Relaxed);
+    let excess = full.usage.load(Ordering::Relaxed).saturating_sub(limit);
+    let evicted = swap::swap_out_from(addr_spaces, excess + RECLAIM_BATCH);
+    full.reclaimed.fetch_add(evicted, Ordering::Relaxed);
+    if evicted == 0 {
+        return false;
+    }
+
+    for _ in 0..RECLAIM_WAIT_STEPS {
+        if full.usage.load(Ordering::Relaxed) < full.limit.load(Ordering::Relaxed) {
+            return true;
+        }
+        sleep(RECLAIM_WAIT_STEP);
+    }
+    false
+}
@@ -1,7 +1,15 @@
 //! # Memory management
 //! Some code was borrowed from [Phil Opp's Blog](http://os.phil-opp.com/allocating-frames.html)
 
+pub mod asid;
 mod kernel_mapper;
+pub mod ksm;
+pub mod memcg;
+pub mod oom;
+pub mod reclaim;
+pub mod slab;
+pub mod swap;
+pub mod writeback;
 
 use core::{
     cell::SyncUnsafeCell,
@@ -10,8 +18,8 @@ use core::{
     sync::atomic::{AtomicUsize, Ordering},
 };
 
+use arrayvec::ArrayVec;
 pub use kernel_mapper::KernelMapper;
-use spin::Mutex;
 
 pub use crate::paging::{PhysicalAddress, RmmA, RmmArch, PAGE_MASK, PAGE_SIZE};
 use crate::{
@@ -20,8 +28,17 @@ use crate::{
         memory::{AccessMode, PfError},
     },
     kernel_executable_offsets::{__usercopy_end, __usercopy_start},
+    numa::{self, NodeHint, NodeMask, MAX_NODE_COUNT},
     paging::{entry::EntryFlags, Page, PageFlags},
-    syscall::error::{Error, ENOMEM},
+    percpu::PercpuBlock,
+    sync::{
+        lockdep::{LockClass, LockMode, Tracked},
+        Mutex, MutexGuard,
+    },
+    syscall::{
+        error::{Error, ENOMEM},
+        flag::SIGSEGV,
+    },
 };
 use rmm::{BumpAllocator, FrameAllocator, FrameCount, FrameUsage, TableKind, VirtualAddress};
 
@@ -50,13 +67,21 @@ pub fn free_frames() -> usize {
 /// Get the number of frames used
 pub fn used_frames() -> usize {
     // TODO: Include bump allocator static pages?
-    FREELIST.lock().used_frames
+    lock_freelist().used_frames
 }
 pub fn total_frames() -> usize {
     // TODO: Include bump allocator static pages?
     sections().iter().map(|section| section.frames.len()).sum()
 }
 
+/// Hooks of caches that can give frames back, returning how many they freed.
+static SHRINKERS: &[fn() -> usize] = &[slab::shrink];
+
+/// Ask the caches to give the frames they keep around back, returning how many were freed.
+pub fn shrink_caches() -> usize {
+    SHRINKERS.iter().map(|shrink| shrink()).sum()
+}
+
 /// Allocate a range of frames
 pub fn allocate_p2frame(order: u32) -> Option<Frame> {
     allocate_p2frame_complex(order, (), None, order).map(|(f, _)| f)
@@ -64,22 +89,68 @@ pub fn allocate_p2frame(order: u32) -> Option<Frame> {
 pub fn allocate_frame() -> Option<Frame> {
     allocate_p2frame(0)
 }
-// TODO: Flags, strategy
+/// Allocate a frame, preferably (or if strict, only) from the nodes in `hint`.
+pub fn allocate_frame_on(hint: NodeHint) -> Option<Frame> {
+    allocate_p2frame_complex(0, (), Some(hint), 0).map(|(f, _)| f)
+}
+
+/// Find the smallest free block of at least `min_order` on any of `nodes`.
+fn find_free_on_nodes(
+    freelist: &FreeList,
+    nodes: NodeMask,
+    min_order: u32,
+) -> Option<(u32, Frame)> {
+    (min_order..ORDER_COUNT).find_map(|order| {
+        nodes
+            .iter()
+            .find_map(|node| freelist.for_nodes[usize::from(node.get())][order as usize])
+            .map(|frame| (order, frame))
+    })
+}
+
+// TODO: Flags
 pub fn allocate_p2frame_complex(
     _req_order: u32,
     _flags: (),
-    _strategy: Option<()>,
+    strategy: Option<NodeHint>,
     min_order: u32,
 ) -> Option<(Frame, usize)> {
-    let mut freelist = FREELIST.lock();
+    #[cfg(feature = "ktest")]
+    if crate::ktest::fault::should_fail(crate::ktest::fault::Site::FrameAlloc) {
+        return None;
+    }
 
-    let Some((frame_order, frame)) = freelist
-        .for_orders
-        .iter()
-        .enumerate()
-        .skip(min_order as usize)
-        .find_map(|(i, f)| f.map(|f| (i as u32, f)))
-    else {
+    let mut freelist = lock_freelist();
+
+    let hint = match strategy {
+        Some(hint) => hint,
+        // Without a hint, prefer the node of this CPU.
+        None if numa::node_count() > 1 => NodeHint {
+            nodes: NodeMask::single(numa::cpu_node(crate::cpu_id())),
+            strict: false,
+        },
+        None => NodeHint {
+            nodes: NodeMask::all(),
+            strict: false,
+        },
+    };
+    let found = match find_free_on_nodes(&freelist, hint.nodes, min_order) {
+        Some(found) => Some(found),
+        None if hint.strict => None,
+        None => find_free_on_nodes(&freelist, NodeMask::all(), min_order),
+    };
+    let Some((frame_order, frame)) = found else {
+        drop(freelist);
+
+        // Frames freed but still batched by this CPU may be enough.
+        if flush_free_batch() {
+            return allocate_p2frame_complex(_req_order, _flags, strategy, min_order);
+        }
+        // So may the frames caches keep around.
+        if shrink_caches() > 0 {
+            return allocate_p2frame_complex(_req_order, _flags, strategy, min_order);
+        }
+        lock_freelist().alloc_failures[min_order as usize] += 1;
         return None;
     };
 
@@ -88,6 +159,7 @@ pub fn allocate_p2frame_complex(
         .as_free()
         .expect("freelist frames must not be marked used!");
     let next_free = info.next();
+    let prev_free = info.prev();
     //log::info!("FREE {frame:?} ORDER {frame_order} NEXT_FREE {next_free:?}");
 
     debug_assert_eq!(
@@ -103,12 +175,19 @@ pub fn allocate_p2frame_complex(
             next.is_aligned_to_order(frame_order),
             "NEXT {next:?} UNALIGNED"
         );
-        f.set_prev(P2Frame::new(None, frame_order));
+        f.set_prev(prev_free);
     }
 
     debug_assert!(frame.is_aligned_to_order(frame_order));
     debug_assert_eq!(next_free.order(), frame_order);
-    freelist.for_orders[frame_order as usize] = next_free.frame();
+    if let Some(prev) = prev_free.frame() {
+        get_free_alloc_page_info(prev).set_next(next_free);
+    } else {
+        debug_assert_eq!(*freelist.head(frame, frame_order), Some(frame));
+        *freelist.head(frame, frame_order) = next_free.frame();
+    }
+    freelist.node_free_frames[usize::from(numa::frame_node(frame).get())] -= 1 << frame_order;
+    freelist.free_blocks[frame_order as usize] -= 1;
 
     // TODO: Is this LIFO cache optimal?
     //log::info!("MIN{min_order}FRAMEORD{frame_order}");
@@ -119,19 +198,20 @@ pub fn allocate_p2frame_complex(
         let hi = frame.next_by(order_page_count);
         //log::info!("SPLIT INTO {frame:?}:{hi:?} ORDER {order}");
 
-        debug_assert_eq!(freelist.for_orders[order as usize], None);
-
-        let hi_info = get_page_info(hi)
+        get_page_info(hi)
             .expect("sub-p2frame of split p2flame lacked PageInfo")
             .make_free(order);
         debug_assert!(!hi.is_aligned_to_order(frame_order));
         debug_assert!(hi.is_aligned_to_order(order));
-        hi_info.set_next(P2Frame::new(None, order));
-        hi_info.set_prev(P2Frame::new(None, order));
-        freelist.for_orders[order as usize] = Some(hi);
+
+        // The lower orders may be non-empty on other nodes, or if a frame on a particular node
+        // was requested.
+        freelist.push(hi, order);
+        freelist.free_blocks[order as usize] += 1;
     }
 
     freelist.used_frames += 1 << min_order;
+    reclaim::note_used(freelist.used_frames);
 
     info.mark_used();
     drop(freelist);
@@ -146,7 +226,22 @@ pub fn allocate_p2frame_complex(
 }
 
 pub unsafe fn deallocate_p2frame(orig_frame: Frame, order: u32) {
-    let mut freelist = FREELIST.lock();
+    deallocate_p2frame_locked(&mut lock_freelist(), orig_frame, order)
+}
+/// Deallocate several p2frames, taking the allocator lock only once.
+pub unsafe fn deallocate_p2frames(frames: &[(Frame, u32)]) {
+    if frames.is_empty() {
+        return;
+    }
+    let mut freelist = lock_freelist();
+
+    for &(frame, order) in frames {
+        deallocate_p2frame_locked(&mut freelist, frame, order);
+    }
+}
+unsafe fn deallocate_p2frame_locked(freelist: &mut FreeList, orig_frame: Frame, order: u32) {
+    memcg::uncharge_freed(orig_frame, order);
+
     let mut largest_order = order;
 
     let mut current = orig_frame;
@@ -190,17 +285,19 @@ pub unsafe fn deallocate_p2frame(orig_frame: Frame, order: u32) {
         if let Some(sib_prev) = sib_info.prev().frame() {
             get_free_alloc_page_info(sib_prev).set_next(sib_info.next());
         } else {
-            debug_assert_eq!(freelist.for_orders[merge_order as usize], Some(sibling));
+            debug_assert_eq!(*freelist.head(sibling, merge_order), Some(sibling));
             debug_assert!(sib_info
                 .next()
                 .frame()
                 .map_or(true, |f| f.is_aligned_to_order(merge_order)));
             debug_assert_eq!(sib_info.next().order(), merge_order);
-            freelist.for_orders[merge_order as usize] = sib_info.next().frame();
+            *freelist.head(sibling, merge_order) = sib_info.next().frame();
         }
         if let Some(sib_next) = sib_info.next().frame() {
             get_free_alloc_page_info(sib_next).set_prev(sib_info.prev());
         }
+        freelist.node_free_frames[usize::from(numa::frame_node(sibling).get())] -= 1 << merge_order;
+        freelist.free_blocks[merge_order as usize] -= 1;
 
         current = Frame::containing(PhysicalAddress::new(
             current.base().data() & !(PAGE_SIZE << merge_order),
@@ -212,18 +309,10 @@ pub unsafe fn deallocate_p2frame(orig_frame: Frame, order: u32) {
         .expect("freeing frame without PageInfo")
         .make_free(largest_order);
 
-    let new_head = current;
-    debug_assert!(new_head.is_aligned_to_order(largest_order));
+    debug_assert!(current.is_aligned_to_order(largest_order));
 
-    if let Some(old_head) = freelist.for_orders[largest_order as usize].replace(new_head) {
-        //log::info!("HEAD {:p} FREED {:p} BARRIER {:p}", get_page_info(old_head).unwrap(), get_page_info(frame).unwrap(), unsafe { ALLOCATOR_DATA.abs_off as *const u8 });
-        let old_head_info = get_free_alloc_page_info(old_head);
-        let new_head_info = get_free_alloc_page_info(new_head);
-
-        new_head_info.set_next(P2Frame::new(Some(old_head), largest_order));
-        new_head_info.set_prev(P2Frame::new(None, largest_order));
-        old_head_info.set_prev(P2Frame::new(Some(new_head), largest_order));
-    }
+    freelist.push(current, largest_order);
+    freelist.free_blocks[largest_order as usize] += 1;
 
     //log::info!("FREED {frame:?}+2^{order}");
     freelist.used_frames -= 1 << order;
@@ -233,6 +322,51 @@ pub unsafe fn deallocate_frame(frame: Frame) {
     deallocate_p2frame(frame, 0)
 }
 
+/// Number of p2frames a CPU collects before returning them to the allocator.
+const FREE_BATCH_SIZE: usize = 64;
+
+/// P2frames freed by a CPU but not yet returned to the allocator, so that freeing many frames,
+/// e.g. when unmapping large grants, only takes the allocator lock once per batch.
+#[derive(Default)]
+pub struct FreeBatch {
+    frames: ArrayVec<(Frame, u32), FREE_BATCH_SIZE>,
+}
+
+/// Deallocate a p2frame once the batch of the current CPU is full or flushed using
+/// [`flush_free_batch`].
+pub unsafe fn deallocate_p2frame_batched(frame: Frame, order: u32) {
+    // The batch can only be locked already if this CPU switched contexts while it was locked.
+    let Some(mut batch) = PercpuBlock::current().free_batch.try_lock() else {
+        return deallocate_p2frame(frame, order);
+    };
+    let full = if batch.frames.is_full() {
+        Some(mem::take(&mut batch.frames))
+    } else {
+        None
+    };
+    batch.frames.push((frame, order));
+    drop(batch);
+
+    if let Some(full) = full {
+        deallocate_p2frames(&full);
+    }
+}
+
+/// Return the p2frames batched by the current CPU to the allocator, returning whether there were
+/// any.
+pub fn flush_free_batch() -> bool {
+    let Some(mut batch) = PercpuBlock::current().free_batch.try_lock() else {
+        return false;
+    };
+    let frames = mem::take(&mut batch.frames);
+    drop(batch);
+
+    unsafe {
+        deallocate_p2frames(&frames);
+    }
+    !frames.is_empty()
+}
+
 // Helper function for quickly mapping device memory
 pub unsafe fn map_device_memory(addr: PhysicalAddress, len: usize) -> VirtualAddress {
     let mut mapper_lock = KernelMapper::lock();
@@ -255,7 +389,7 @@ pub unsafe fn map_device_memory(addr: PhysicalAddress, len: usize) -> VirtualAdd
     RmmA::phys_to_virt(addr)
 }
 
-const ORDER_COUNT: u32 = 11;
+pub const ORDER_COUNT: u32 = 11;
 const MAX_ORDER: u32 = ORDER_COUNT - 1;
 
 #[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
@@ -468,14 +602,191 @@ struct AllocatorData {
 }
 #[derive(Debug)]
 struct FreeList {
-    for_orders: [Option<Frame>; ORDER_COUNT as usize],
+    /// Head of the freelist of each node and order. A free block is on the freelist of the node
+    /// its first frame belongs to.
+    for_nodes: [[Option<Frame>; ORDER_COUNT as usize]; MAX_NODE_COUNT],
+    /// Number of frames in the freelists of each node.
+    node_free_frames: [usize; MAX_NODE_COUNT],
     used_frames: usize,
+    /// Length of each freelist.
+    free_blocks: [usize; ORDER_COUNT as usize],
+    /// Number of allocations of each order that found no free block.
+    alloc_failures: [usize; ORDER_COUNT as usize],
 }
 static FREELIST: Mutex<FreeList> = Mutex::new(FreeList {
-    for_orders: [None; ORDER_COUNT as usize],
+    for_nodes: [[None; ORDER_COUNT as usize]; MAX_NODE_COUNT],
+    node_free_frames: [0; MAX_NODE_COUNT],
     used_frames: 0,
+    free_blocks: [0; ORDER_COUNT as usize],
+    alloc_failures: [0; ORDER_COUNT as usize],
 });
 
+fn lock_freelist() -> Tracked<MutexGuard<'static, FreeList>> {
+    Tracked::acquire(
+        LockClass::FrameAllocator,
+        &FREELIST,
+        LockMode::Exclusive,
+        Mutex::lock,
+    )
+}
+
+impl FreeList {
+    fn head(&mut self, block: Frame, order: u32) -> &mut Option<Frame> {
+        &mut self.for_nodes[usize::from(numa::frame_node(block).get())][order as usize]
+    }
+    /// Add a free block to the front of the freelist of its node.
+    fn push(&mut self, block: Frame, order: u32) {
+        self.node_free_frames[usize::from(numa::frame_node(block).get())] += 1 << order;
+        let old_head = self.head(block, order).replace(block);
+        if let Some(old_head) = old_head {
+            get_free_alloc_page_info(old_head).set_prev(P2Frame::new(Some(block), order));
+        }
+        let info = get_free_alloc_page_info(block);
+        info.set_next(P2Frame::new(old_head, order));
+        info.set_prev(P2Frame::new(None, order));
+    }
+}
+
+/// Move the free blocks to the freelists of their nodes, once the NUMA topology is known.
+pub fn sort_freelists_by_node() {
+    let mut freelist = lock_freelist();
+    for order in 0..ORDER_COUNT {
+        let mut cursor = freelist.for_nodes[0][order as usize].take();
+        while let Some(block) = cursor {
+            cursor = get_free_alloc_page_info(block).next().frame();
+            freelist.node_free_frames[0] -= 1 << order;
+            freelist.push(block, order);
+        }
+    }
+}
+
+/// Number of free frames on each node, not counting those in per-CPU free batches.
+pub fn free_frames_per_node() -> [usize; MAX_NODE_COUNT] {
+    lock_freelist().node_free_frames
+}
+
+/// Snapshot of the free block size distribution of the frame allocator.
+pub struct FreeStats {
+    /// Number of free blocks of each order.
+    pub free_blocks: [usize; ORDER_COUNT as usize],
+    /// Number of allocations of each order that failed.
+    pub alloc_failures: [usize; ORDER_COUNT as usize],
+}
+
+pub fn free_stats() -> FreeStats {
+    let freelist = lock_freelist();
+    FreeStats {
+        free_blocks: freelist.free_blocks,
+        alloc_failures: freelist.alloc_failures,
+    }
+}
+
+impl FreeStats {
+    /// Number of free frames, not counting those in per-CPU free batches.
+    pub fn free_frames(&self) -> usize {
+        self.free_blocks
+            .iter()
+            .enumerate()
+            .map(|(order, count)| count << order)
+            .sum()
+    }
+    /// Number of free frames in blocks of at least `order`.
+    pub fn free_frames_at_least(&self, order: u32) -> usize {
+        self.free_blocks
+            .iter()
+            .enumerate()
+            .skip(order as usize)
+            .map(|(order, count)| count << order)
+            .sum()
+    }
+    /// Fragmentation index for allocations of `order`, from 0 to 1000, or None if such an
+    /// allocation would currently succeed.
+    ///
+    /// Values close to 0 mean an allocation fails due to lack of memory, while values close to
+    /// 1000 mean it fails due to fragmentation, and would succeed after compaction.
+    pub fn fragmentation_index(&self, order: u32) -> Option<usize> {
+        if self.free_blocks[order as usize..]
+            .iter()
+            .any(|&count| count != 0)
+        {
+            return None;
+        }
+        let blocks: usize = self.free_blocks.iter().sum();
+        if blocks == 0 {
+            return Some(0);
+        }
+        let requested = 1_usize << order;
+        Some(1000_usize.saturating_sub((1000 + self.free_frames() * 1000 / requested) / blocks))
+    }
+}
+
+/// Number of buckets of the reference count histogram of [`FrameStats`].
+pub const REFCOUNT_BUCKETS: usize = 8;
+
+/// Usage of every frame with a `PageInfo`, found by scanning them without locking the allocator,
+/// so that the counts may be slightly inconsistent. Frames in per-CPU free batches count as used.
+pub struct FrameStats {
+    /// Free frames of each node.
+    pub node_free: [usize; MAX_NODE_COUNT],
+    /// Used frames of each node.
+    pub node_used: [usize; MAX_NODE_COUNT],
+    /// Used frames by reference count, bucket `i` counting those referenced between `1 << i` and
+    /// `(1 << (i + 1)) - 1` times, and the last bucket the rest.
+    pub refcounts: [usize; REFCOUNT_BUCKETS],
+    /// Frames referenced more than once, mapped shared.
+    pub shared: usize,
+    /// Frames referenced more than once, mapped copy-on-write.
+    pub cow: usize,
+    /// Length of the longest run of physically contiguous free frames.
+    pub largest_free_run: usize,
+}
+
+pub fn frame_stats() -> FrameStats {
+    let mut stats = FrameStats {
+        node_free: [0; MAX_NODE_COUNT],
+        node_used: [0; MAX_NODE_COUNT],
+        refcounts: [0; REFCOUNT_BUCKETS],
+        shared: 0,
+        cow: 0,
+        largest_free_run: 0,
+    };
+    let mut run = 0;
+    let mut next_frame = None;
+
+    for section in sections() {
+        // Runs continue across sections only if they are adjacent.
+        if next_frame != Some(section.base) {
+            run = 0;
+        }
+        for (i, info) in section.frames.iter().enumerate() {
+            let node = usize::from(numa::frame_node(section.base.next_by(i)).get());
+            let refcount = match info.refcount() {
+                None => {
+                    stats.node_free[node] += 1;
+                    run += 1;
+                    stats.largest_free_run = stats.largest_free_run.max(run);
+                    continue;
+                }
+                Some(RefCount::One) => 1,
+                Some(RefCount::Shared(count)) => {
+                    stats.shared += 1;
+                    count.get()
+                }
+                Some(RefCount::Cow(count)) => {
+                    stats.cow += 1;
+                    count.get()
+                }
+            };
+            stats.node_used[node] += 1;
+            run = 0;
+            let bucket = (refcount.ilog2() as usize).min(REFCOUNT_BUCKETS - 1);
+            stats.refcounts[bucket] += 1;
+        }
+        next_frame = Some(section.base.next_by(section.frames.len()));
+    }
+    stats
+}
+
 pub struct Section {
     base: Frame,
     frames: &'static [PageInfo],
@@ -629,6 +940,7 @@ fn init_sections(mut allocator: BumpAllocator<RmmA>) {
     let mut first_pages: [Option<(Frame, &'static PageInfo)>; ORDER_COUNT as usize] =
         [None; ORDER_COUNT as usize];
     let mut last_pages = first_pages;
+    let mut free_blocks = [0; ORDER_COUNT as usize];
 
     let mut append_page = |page: Frame, info: &'static PageInfo, order| {
         let this_page = (page, info);
@@ -642,6 +954,7 @@ fn init_sections(mut allocator: BumpAllocator<RmmA>) {
         debug_assert_eq!(info.refcount.load(Ordering::Relaxed), 0);
 
         let last_page = last_pages[order as usize].replace(this_page);
+        free_blocks[order as usize] += 1;
 
         if let Some((last_frame, last_page_info)) = last_page {
             let last_info = last_page_info.as_free().unwrap();
@@ -735,7 +1048,16 @@ fn init_sections(mut allocator: BumpAllocator<RmmA>) {
         free.set_next(P2Frame::new(None, order as u32));
     }
 
-    FREELIST.lock().for_orders = first_pages.map(|pair| pair.map(|(frame, _)| frame));
+    let mut freelist = lock_freelist();
+    // The topology is not known yet, so everything starts on node 0.
+    freelist.for_nodes[0] = first_pages.map(|pair| pair.map(|(frame, _)| frame));
+    freelist.node_free_frames[0] = free_blocks
+        .iter()
+        .enumerate()
+        .map(|(order, count)| count << order)
+        .sum();
+    freelist.free_blocks = free_blocks;
+    drop(freelist);
 
     //debug_freelist();
     log::info!("Initial freelist consistent");
@@ -744,6 +1066,7 @@ fn init_sections(mut allocator: BumpAllocator<RmmA>) {
 #[cold]
 pub fn init_mm(allocator: BumpAllocator<RmmA>) {
     init_sections(allocator);
+    reclaim::init();
 
     unsafe {
         let the_frame = allocate_frame().expect("failed to allocate static zeroed frame");
@@ -951,7 +1274,8 @@ fn get_free_alloc_page_info(frame: Frame) -> PageInfoFree<'static> {
     i.as_free().unwrap() //.unwrap_or_else(|| panic!("expected frame to be free, but {frame:?} wasn't, in {i:?}"))
 }
 
-pub struct Segv;
+/// The signal raised by a page fault that could not be resolved.
+pub struct FaultSignal(pub usize);
 
 bitflags! {
     /// Arch-generic page fault flags, modeled after x86's error code.
@@ -976,7 +1300,16 @@ pub fn page_fault_handler(
     stack: &mut impl ArchIntCtx,
     code: GenericPfFlags,
     faulting_address: VirtualAddress,
-) -> Result<(), Segv> {
+) -> Result<(), FaultSignal> {
+    crate::trace::record(
+        crate::trace::Event::PageFault,
+        [
+            faulting_address.data() as u64,
+            code.bits().into(),
+            stack.ip() as u64,
+        ],
+    );
+
     let faulting_page = Page::containing_address(faulting_address);
 
     let usercopy_region = __usercopy_start()..__usercopy_end();
@@ -1002,16 +1335,33 @@ pub fn page_fault_handler(
     };
 
     if invalid_page_tables {
-        // TODO: Better error code than Segv?
-        return Err(Segv);
+        // TODO: Better error code than SIGSEGV?
+        return Err(FaultSignal(SIGSEGV));
     }
 
     if address_is_user && (caused_by_user || is_usercopy) {
-        match context::memory::try_correcting_page_tables(faulting_page, mode) {
-            Ok(()) => return Ok(()),
-            Err(PfError::Oom) => todo!("oom"),
-            Err(PfError::Segv | PfError::RecursionLimitExceeded) => (),
-            Err(PfError::NonfatalInternalError) => todo!(),
+        context::stats::count_page_fault();
+        match context::fault::handle(faulting_page, mode) {
+            Ok(_) => return Ok(()),
+            // Retry the access once the signal has been handled.
+            Err(fault) if caused_by_user && matches!(fault.error, PfError::Interrupted) => {
+                return Ok(())
+            }
+            // Retry the access once the OOM killer got memory back, or fail it like any other if
+            // nothing can be killed.
+            Err(fault)
+                if caused_by_user
+                    && matches!(fault.error, PfError::Oom | PfError::MemLimit)
+                    && oom::out_of_memory().is_ok() =>
+            {
+                return Ok(())
+            }
+            Err(fault) if caused_by_user => {
+                let info = fault.info(faulting_address.data());
+                context::signal::record_fault(info);
+                return Err(FaultSignal(info.sig));
+            }
+            Err(_) => (),
         }
     }
 
@@ -1020,7 +1370,7 @@ pub fn page_fault_handler(
         return Ok(());
     }
 
-    Err(Segv)
+    Err(FaultSignal(SIGSEGV))
 }
 static THE_ZEROED_FRAME: SyncUnsafeCell<Option<(Frame, &'static PageInfo)>> =
     SyncUnsafeCell::new(None);
@@ -1035,7 +1385,18 @@ pub fn the_zeroed_frame() -> (Frame, &'static PageInfo) {
 }
 
 pub fn init_frame(init_rc: RefCount) -> Result<Frame, PfError> {
-    let new_frame = allocate_frame().ok_or(PfError::Oom)?;
+    init_frame_on(init_rc, None)
+}
+/// Like [`init_frame`], but allocating according to a NUMA hint.
+pub fn init_frame_on(init_rc: RefCount, hint: Option<NodeHint>) -> Result<Frame, PfError> {
+    swap::check_watermark();
+    reclaim::check_watermark();
+
+    let new_frame = match hint {
+        Some(hint) => allocate_frame_on(hint),
+        None => allocate_frame(),
+    }
+    .ok_or(PfError::Oom)?;
     let page_info = get_page_info(new_frame).unwrap_or_else(|| {
         panic!(
             "all allocated frames need an associated page info, {:?} didn't",
@@ -1049,6 +1410,25 @@ pub fn init_frame(init_rc: RefCount) -> Result<Frame, PfError> {
 
     Ok(new_frame)
 }
+/// Like [`init_frame_on`], for the memory of user grants, which is charged to the memory group of
+/// the current context.
+pub fn init_user_frame(init_rc: RefCount, hint: Option<NodeHint>) -> Result<Frame, PfError> {
+    let memcg = memcg::charge_current(1)?;
+    match init_frame_on(init_rc, hint) {
+        Ok(frame) => {
+            memcg::tag(frame, 0, memcg);
+            // Tags left by a previous user would otherwise be seen by the next one mapping the
+            // frame as tagged memory.
+            #[cfg(target_arch = "aarch64")]
+            crate::mte::zero_tags(frame);
+            Ok(frame)
+        }
+        Err(err) => {
+            memcg::uncharge(memcg, 1);
+            Err(err)
+        }
+    }
+}
 #[derive(Debug)]
 pub struct TheFrameAllocator;
 
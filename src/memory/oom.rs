@@ -0,0 +1,232 @@
+//! # OOM killer
+//!
+//! When a page fault finds no frame left and reclaiming freed none, or its memory group is at its
+//! limit with nothing left to evict, the kernel kills a process to get memory back, rather than
+//! panicking. The victim is the process of the context with the highest badness: the number of
+//! pages resident in its address space, plus its `oom_score_adj` in thousandths of all frames, or
+//! of the limit of the group. A context with an adjustment of 1000 is thus always picked first, and
+//! one with -1000 never is. Kernel threads, which have no user address space, and init are never
+//! picked either. Daemons the system cannot do without, such as the swap provider, should set
+//! their adjustment to -1000 through the `oom-score-adj` handle of their contexts in `proc:`.
+//!
+//! Only one process is killed at a time: until the victim has exited, faults that find no frame
+//! left wait for it rather than picking another, unless it takes longer than [`VICTIM_TIMEOUT`].
+//! If no process can be killed, which means the faulting one is exempt too, the fault fails with
+//! `SIGBUS` instead. Every decision is logged, and so recorded in the kernel log.
+
+use alloc::{
+    string::String,
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use syscall::SenderInfo;
+
+use crate::{
+    common::try_alloc::{try_push, try_string},
+    context::{
+        self,
+        memory::AddrSpaceWrapper,
+        process::{self, Process, ProcessId, ProcessStatus, INIT},
+    },
+    sync::{Mutex, RwLock},
+    syscall::{
+        error::{Error, Result, ENOMEM},
+        flag::SIGKILL,
+        process::{exit, send_signal, KillMode, KillTarget},
+    },
+    time,
+};
+
+use super::{
+    memcg::{self, MemcgId},
+    total_frames, PAGE_SIZE,
+};
+
+/// Adjustment exempting a context from the OOM killer.
+pub const OOM_SCORE_ADJ_MIN: i16 = -1000;
+/// Adjustment making a context the first one the OOM killer picks.
+pub const OOM_SCORE_ADJ_MAX: i16 = 1000;
+
+/// How long faults wait for a victim to exit before picking another one.
+const VICTIM_TIMEOUT: u128 = time::NANOS_PER_SEC;
+/// How long a fault waits before retrying, while there is a victim.
+const VICTIM_WAIT: u128 = time::NANOS_PER_SEC / 100;
+
+/// The last process killed, and when.
+static VICTIM: Mutex<Option<(Weak<RwLock<Process>>, u128)>> = Mutex::new(None);
+
+/// Processes killed so far.
+pub static KILLS: AtomicUsize = AtomicUsize::new(0);
+
+/// A context that could be killed.
+struct Candidate {
+    process: Arc<RwLock<Process>>,
+    pid: ProcessId,
+    name: String,
+    addr_space: Arc<AddrSpaceWrapper>,
+    adj: i16,
+}
+
+/// The badness of a context with `resident` pages and the adjustment `adj`, relative to `total`
+/// pages, or None if it is exempt.
+fn badness(resident: usize, adj: i16, total: usize) -> Option<usize> {
+    if adj <= OOM_SCORE_ADJ_MIN {
+        return None;
+    }
+    let adjustment = isize::from(adj) * (total / 1000) as isize;
+    // Contexts that can be killed always have some badness, however low their adjustment.
+    Some((resident as isize).saturating_add(adjustment).max(1) as usize)
+}
+
+/// The contexts that can be killed, within the memory group `scope` if there is one.
+fn candidates(scope: Option<MemcgId>) -> Vec<Candidate> {
+    let mut candidates = Vec::new();
+    for context_ref in context::contexts().iter().filter_map(|r| r.upgrade()) {
+        let context = context_ref.read();
+        if !context.userspace
+            || context.pid == INIT
+            || context.being_sigkilled
+            || context.oom_score_adj <= OOM_SCORE_ADJ_MIN
+        {
+            continue;
+        }
+        if let Some(scope) = scope
+            && !context
+                .memcg
+                .as_ref()
+                .is_some_and(|memcg| memcg.is_within(scope))
+        {
+            continue;
+        }
+        let (Ok(addr_space), Ok(name)) = (context.addr_space().cloned(), try_string(&context.name))
+        else {
+            continue;
+        };
+        let candidate = Candidate {
+            process: Arc::clone(&context.process),
+            pid: context.pid,
+            name,
+            addr_space,
+            adj: context.oom_score_adj,
+        };
+        if try_push(&mut candidates, candidate).is_err() {
+            break;
+        }
+    }
+    candidates
+}
+
+/// Pick the context with the highest badness, returning it with its badness and resident pages.
+fn select(scope: Option<MemcgId>, total: usize) -> Option<(Candidate, usize, usize)> {
+    // Threads share their address space, which is only walked once.
+    let mut resident = Vec::<(Arc<AddrSpaceWrapper>, usize)>::new();
+    let mut victim = None;
+    for candidate in candidates(scope) {
+        let pages = match resident
+            .iter()
+            .find(|(addr_space, _)| Arc::ptr_eq(addr_space, &candidate.addr_space))
+        {
+            Some(&(_, pages)) => pages,
+            None => {
+                let pages = candidate.addr_space.acquire_read().resident_pages();
+                let _ = try_push(&mut resident, (Arc::clone(&candidate.addr_space), pages));
+                pages
+            }
+        };
+        let Some(badness) = badness(pages, candidate.adj, total) else {
+            continue;
+        };
+        if victim
+            .as_ref()
+            .is_none_or(|&(_, highest, _)| badness > highest)
+        {
+            victim = Some((candidate, badness, pages));
+        }
+    }
+    victim
+}
+
+/// Whether the last victim has yet to exit, and was killed recently enough to wait for it.
+fn victim_pending() -> bool {
+    let victim = VICTIM.lock();
+    let Some((process, killed_at)) = victim.as_ref() else {
+        return false;
+    };
+    let Some(process) = process.upgrade() else {
+        return false;
+    };
+    !matches!(process.read().status, ProcessStatus::Exited(_))
+        && time::monotonic().saturating_sub(*killed_at) < VICTIM_TIMEOUT
+}
+
+fn sleep(duration: u128) {
+    let current = context::current();
+    {
+        let mut context = current.write();
+        context.wake = Some(time::monotonic() + duration);
+        context.block("oom");
+    }
+    context::switch();
+    current.write().wake = None;
+}
+
+/// Called when a page fault of the current context found no frame left and reclaiming freed
+/// none, or when its memory group is at its limit, without the address space locked. Kills the
+/// process with the highest badness, or waits for the last one killed to exit, after which the
+/// access is retried. Does not return if the current process is killed, and fails with ENOMEM if
+/// no process can be killed.
+pub fn out_of_memory() -> Result<()> {
+    let current = context::current();
+    if current.read().being_sigkilled {
+        exit(SIGKILL);
+    }
+    if victim_pending() {
+        sleep(VICTIM_WAIT);
+        return Ok(());
+    }
+
+    let (scope, total) = match memcg::full_for_current() {
+        Some((id, limit)) => (Some(id), limit),
+        None => (None, total_frames()),
+    };
+    let Some((victim, badness, resident)) = select(scope, total) else {
+        // Not even the current context, which may be init or exempt, so leave it to the caller.
+        log::error!(
+            "Out of memory, and no process can be killed: failing the fault of {}",
+            current.read().name
+        );
+        return Err(Error::new(ENOMEM));
+    };
+
+    KILLS.fetch_add(1, Ordering::Relaxed);
+    let within = match scope {
+        Some(_) => " in memory group",
+        None => "",
+    };
+    log::error!(
+        "Out of memory{}: killed process {} ({}), badness {}, {} KiB resident, oom_score_adj {}",
+        within,
+        victim.pid.get(),
+        victim.name,
+        badness,
+        resident * PAGE_SIZE / 1024,
+        victim.adj
+    );
+    *VICTIM.lock() = Some((Arc::downgrade(&victim.process), time::monotonic()));
+
+    let is_current = process::current().is_ok_and(|p| Arc::ptr_eq(&p, &victim.process));
+    if is_current {
+        exit(SIGKILL);
+    }
+    let _ = send_signal(
+        KillTarget::Process(victim.process),
+        SIGKILL,
+        KillMode::Idempotent,
+        false,
+        &mut false,
+        SenderInfo { pid: 0, ruid: 0 },
+    );
+    Ok(())
+}
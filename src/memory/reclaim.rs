@@ -0,0 +1,268 @@
+//! # Memory pressure
+//!
+//! Memory pressure is measured against watermarks on the number of free frames: it is low once
+//! fewer than 1/16 of all frames are free, medium below 1/32, and critical below 1/64. The frame
+//! allocator records the level as it allocates, and the `[kswapd]` kernel thread reclaims memory
+//! every 100 ms while there is pressure, aiming for twice the low watermark:
+//!
+//! - the slab caches and other shrinkers give back the frames they keep around,
+//! - pages marked with MADV_FREE that were not written to since are dropped,
+//! - from medium pressure on, shared file mappings are written back, so that the schemes providing
+//!   them can drop clean pages from their caches,
+//! - and the swap provider, if there is one, is asked to evict pages, see [`super::swap`].
+//!
+//! The frame allocator cannot wake `[kswapd]` up itself, as it may be called with the locks doing
+//! so takes held, so it only flags that the level rose, and [`check_watermark`] wakes it up from
+//! where user frames are allocated. Page faults that find no frame left do not wait for it, but
+//! reclaim what they can right away, see [`reclaim_direct`].
+//!
+//! Userspace is notified through the `memory:pressure` handle, which reads the current level and
+//! the counters, and triggers `EVENT_READ` whenever the level changes.
+
+use alloc::{sync::Arc, vec::Vec};
+use core::sync::atomic::{AtomicBool, AtomicU8, AtomicUsize, Ordering};
+
+use crate::{
+    common::try_alloc::try_push,
+    context::{
+        self,
+        memory::{AddrSpace, AddrSpaceWrapper},
+        process::{new_process, ProcessInfo},
+    },
+    scheme::{self, SchemeNamespace},
+    sync::{Mutex, WaitCondition},
+    syscall::error::Result,
+    time,
+};
+
+use super::{free_frames, shrink_caches, swap, total_frames, writeback};
+
+/// Pressure reaches each level above `None` once fewer than `total >> shift` frames are free.
+const LEVEL_SHIFTS: [u32; 3] = [4, 5, 6];
+
+/// Time between two reclaim passes, while there is pressure.
+const PASS_INTERVAL: u128 = time::NANOS_PER_SEC / 10;
+
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq, PartialOrd)]
+pub enum Pressure {
+    None = 0,
+    Low = 1,
+    Medium = 2,
+    Critical = 3,
+}
+
+impl Pressure {
+    fn from_raw(raw: u8) -> Self {
+        match raw {
+            0 => Self::None,
+            1 => Self::Low,
+            2 => Self::Medium,
+            _ => Self::Critical,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::None => "none",
+            Self::Low => "low",
+            Self::Medium => "medium",
+            Self::Critical => "critical",
+        }
+    }
+}
+
+/// Free frames below which each level above `None` is reached, set by [`init`].
+static WATERMARKS: [AtomicUsize; 3] = [const { AtomicUsize::new(0) }; 3];
+static TOTAL: AtomicUsize = AtomicUsize::new(0);
+
+/// The level last recorded by the frame allocator.
+static LEVEL: AtomicU8 = AtomicU8::new(Pressure::None as u8);
+/// The level last reported to userspace.
+static REPORTED: AtomicU8 = AtomicU8::new(Pressure::None as u8);
+/// Set when the level recorded by the frame allocator rose, until `[kswapd]` is woken up.
+static WAKE: AtomicBool = AtomicBool::new(false);
+
+static KSWAPD_LOCK: Mutex<()> = Mutex::new(());
+static KSWAPD: WaitCondition = WaitCondition::new();
+
+pub struct Stats {
+    pub passes: AtomicUsize,
+    pub frames_shrunk: AtomicUsize,
+    pub pages_lazyfreed: AtomicUsize,
+    pub events: AtomicUsize,
+}
+pub static STATS: Stats = Stats {
+    passes: AtomicUsize::new(0),
+    frames_shrunk: AtomicUsize::new(0),
+    pages_lazyfreed: AtomicUsize::new(0),
+    events: AtomicUsize::new(0),
+};
+
+fn level_for(free: usize) -> Pressure {
+    let reached = WATERMARKS
+        .iter()
+        .take_while(|watermark| free < watermark.load(Ordering::Relaxed))
+        .count();
+    Pressure::from_raw(reached as u8)
+}
+
+/// The current level.
+pub fn pressure() -> Pressure {
+    level_for(free_frames())
+}
+
+/// Frames `[kswapd]` is trying to free, or zero once there is no pressure.
+pub fn shortfall() -> usize {
+    let free = free_frames();
+    if level_for(free) == Pressure::None {
+        return 0;
+    }
+    (2 * WATERMARKS[0].load(Ordering::Relaxed)).saturating_sub(free)
+}
+
+/// Record the level for `used` frames in use. Called by the frame allocator with its lock held,
+/// so this must neither allocate nor block.
+pub(super) fn note_used(used: usize) {
+    let level = level_for(TOTAL.load(Ordering::Relaxed).saturating_sub(used));
+    let previous = Pressure::from_raw(LEVEL.swap(level as u8, Ordering::Relaxed));
+    if level > previous {
+        WAKE.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Wake `[kswapd]` up if the level rose since it last was. Called before allocating user frames.
+pub fn check_watermark() {
+    if !WAKE.load(Ordering::Relaxed) || !WAKE.swap(false, Ordering::Relaxed) {
+        return;
+    }
+    // Taking the lock orders this against `[kswapd]` checking the level, right before it waits.
+    drop(KSWAPD_LOCK.lock());
+    KSWAPD.notify();
+}
+
+/// Notify the `memory:pressure` handles if the level changed since they last were.
+fn report(level: Pressure) {
+    if REPORTED.swap(level as u8, Ordering::Relaxed) != level as u8 {
+        STATS.events.fetch_add(1, Ordering::Relaxed);
+        scheme::memory::trigger_pressure();
+    }
+}
+
+/// The address spaces of all contexts but the calling one, each once.
+pub(super) fn other_addr_spaces() -> Vec<Arc<AddrSpaceWrapper>> {
+    let current = AddrSpace::current().ok();
+    let mut addr_spaces = Vec::<Arc<AddrSpaceWrapper>>::new();
+    for context_ref in context::contexts().iter().filter_map(|r| r.upgrade()) {
+        let Ok(addr_space) = context_ref.read().addr_space().cloned() else {
+            continue;
+        };
+        if current
+            .as_ref()
+            .is_some_and(|c| Arc::ptr_eq(c, &addr_space))
+            || addr_spaces.iter().any(|a| Arc::ptr_eq(a, &addr_space))
+        {
+            continue;
+        }
+        if try_push(&mut addr_spaces, addr_space).is_err() {
+            break;
+        }
+    }
+    addr_spaces
+}
+
+/// Drop the clean pages marked with MADV_FREE, until the shortfall is made up for.
+fn reclaim_lazyfree() {
+    let mut target = shortfall();
+    for addr_space in other_addr_spaces() {
+        if target == 0 {
+            break;
+        }
+        let dropped = addr_space.reclaim_lazyfree(target);
+        STATS.pages_lazyfreed.fetch_add(dropped, Ordering::Relaxed);
+        target = target.saturating_sub(dropped);
+    }
+}
+
+/// Reclaim from the faulting context, when a page fault found no frame left, with its address space
+/// no longer locked. Returns whether frames were freed, so that the fault is worth retrying.
+pub fn reclaim_direct() -> bool {
+    let free = free_frames();
+    STATS
+        .frames_shrunk
+        .fetch_add(shrink_caches(), Ordering::Relaxed);
+    reclaim_lazyfree();
+    swap::request_reclaim();
+    free_frames() > free
+}
+
+fn reclaim_pass(level: Pressure) {
+    STATS
+        .frames_shrunk
+        .fetch_add(shrink_caches(), Ordering::Relaxed);
+    reclaim_lazyfree();
+    if level >= Pressure::Medium {
+        writeback::write_back_all();
+    }
+    swap::request_reclaim();
+    STATS.passes.fetch_add(1, Ordering::Relaxed);
+}
+
+fn sleep(duration: u128) {
+    let current = context::current();
+    {
+        let mut context = current.write();
+        context.wake = Some(time::monotonic() + duration);
+        context.block("kswapd");
+    }
+    context::switch();
+    current.write().wake = None;
+}
+
+extern "C" fn kswapd_main() {
+    loop {
+        let level = pressure();
+        report(level);
+        if level != Pressure::None {
+            reclaim_pass(level);
+            sleep(PASS_INTERVAL);
+            continue;
+        }
+
+        let guard = KSWAPD_LOCK.lock();
+        if pressure() == Pressure::None {
+            KSWAPD.wait(guard, "kswapd");
+        }
+    }
+}
+
+/// Set the watermarks, once the frame allocator knows how many frames there are.
+pub fn init() {
+    let total = total_frames();
+    TOTAL.store(total, Ordering::Relaxed);
+    for (watermark, shift) in WATERMARKS.iter().zip(LEVEL_SHIFTS) {
+        watermark.store(total >> shift, Ordering::Relaxed);
+    }
+}
+
+/// Spawn the `[kswapd]` kernel thread.
+pub fn spawn() -> Result<()> {
+    let process = new_process(|pid| ProcessInfo {
+        pid,
+        pgid: pid,
+        ppid: pid,
+        session_id: pid,
+        ruid: 0,
+        rgid: 0,
+        euid: 0,
+        egid: 0,
+        rns: SchemeNamespace::new(0),
+        ens: SchemeNamespace::new(0),
+    })?;
+    let context_lock = context::spawn(false, process, kswapd_main)?;
+
+    let mut context = context_lock.write();
+    context.status = context::Status::Runnable;
+    context.name = "[kswapd]".into();
+    Ok(())
+}
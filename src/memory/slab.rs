@@ -0,0 +1,337 @@
+//! # Slab caches
+//!
+//! Kernel objects allocated and freed all the time with the same layout, like contexts and file
+//! descriptions, are served by caches of their own rather than the general heap: the global
+//! allocator hands allocations whose layout is exactly the one of a cache to it. A cache carves
+//! slabs, blocks of frames from the frame allocator, into objects of its layout, and every CPU
+//! keeps a magazine of free objects in front of it, so that most allocations and frees neither
+//! search a free list nor take a lock shared with other CPUs.
+//!
+//! Slabs are naturally aligned, so the slab of an object is found by masking its address, and
+//! start with a header listing their free objects. Slabs left completely free are kept until the
+//! frame allocator runs out of frames and asks the caches to [`shrink`], which empties the
+//! magazines and gives the free slabs back.
+//!
+//! Grants, which live in the nodes of a B-tree, wait queue entries, which live in ring buffers,
+//! and the page info of frames, which is allocated in arrays at boot, are not allocated one at a
+//! time, so they are not served by caches.
+
+use core::{
+    alloc::Layout,
+    mem, ptr,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use crate::{
+    context::{file::FileDescription, Context},
+    memory::{allocate_p2frame, deallocate_p2frame, Frame},
+    paging::{PhysicalAddress, RmmA, RmmArch, PAGE_SIZE},
+    percpu::{percpu, PerCpu},
+    sync::{Mutex, RwLock, RwSpinlock},
+};
+
+/// Free objects a magazine holds at most. Half of them are moved at once from and to the slabs.
+const MAGAZINE_SIZE: usize = 16;
+
+/// Objects a slab holds at least, which determines its order.
+const MIN_OBJECTS: usize = 8;
+
+/// Set once every CPU can use its magazines.
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// A free object, linked into the free list of its slab.
+struct FreeObject {
+    next: *mut FreeObject,
+}
+
+/// The header at the start of a slab.
+struct Slab {
+    free: *mut FreeObject,
+    in_use: usize,
+    /// The next slab with free objects, while this one has some.
+    next: *mut Slab,
+}
+
+/// The free objects a CPU keeps in front of a cache.
+pub struct Magazine {
+    objects: [*mut u8; MAGAZINE_SIZE],
+    count: usize,
+}
+
+// Objects are owned by whoever holds the magazine.
+unsafe impl Send for Magazine {}
+
+impl Magazine {
+    pub const fn new() -> Self {
+        Self {
+            objects: [ptr::null_mut(); MAGAZINE_SIZE],
+            count: 0,
+        }
+    }
+
+    fn push(&mut self, object: *mut u8) {
+        self.objects[self.count] = object;
+        self.count += 1;
+    }
+
+    fn pop(&mut self) -> Option<*mut u8> {
+        self.count = self.count.checked_sub(1)?;
+        Some(self.objects[self.count])
+    }
+}
+
+/// The slabs of a cache with free objects, linked through their `next` field. Full slabs are not
+/// listed anywhere until one of their objects is freed.
+struct Depot {
+    partial: *mut Slab,
+}
+
+// Only points to slabs of the cache owning it.
+unsafe impl Send for Depot {}
+
+pub struct SlabCache {
+    layout: Layout,
+    /// Distance between objects.
+    stride: usize,
+    /// Offset of the first object, after the header.
+    first: usize,
+    order: u32,
+    depot: Mutex<Depot>,
+    magazines: &'static PerCpu<Mutex<Magazine>>,
+}
+
+const fn align_up(value: usize, align: usize) -> usize {
+    (value + align - 1) & !(align - 1)
+}
+
+impl SlabCache {
+    pub const fn new(layout: Layout, magazines: &'static PerCpu<Mutex<Magazine>>) -> Self {
+        let align = if layout.align() > mem::align_of::<FreeObject>() {
+            layout.align()
+        } else {
+            mem::align_of::<FreeObject>()
+        };
+        let size = if layout.size() > mem::size_of::<FreeObject>() {
+            layout.size()
+        } else {
+            mem::size_of::<FreeObject>()
+        };
+        let stride = align_up(size, align);
+        let first = align_up(mem::size_of::<Slab>(), align);
+        let mut order = 0;
+        while (PAGE_SIZE << order) < first + stride * MIN_OBJECTS {
+            order += 1;
+        }
+
+        Self {
+            layout,
+            stride,
+            first,
+            order,
+            depot: Mutex::new(Depot {
+                partial: ptr::null_mut(),
+            }),
+            magazines,
+        }
+    }
+
+    fn slab_size(&self) -> usize {
+        PAGE_SIZE << self.order
+    }
+
+    /// Allocate an object, or return null if no frames are left.
+    pub fn alloc(&self) -> *mut u8 {
+        let mut magazine = self.magazines.get().lock();
+        if magazine.count == 0 {
+            self.refill(&mut magazine);
+        }
+        magazine.pop().unwrap_or(ptr::null_mut())
+    }
+
+    /// Free an object allocated from this cache.
+    pub unsafe fn free(&self, object: *mut u8) {
+        let mut magazine = self.magazines.get().lock();
+        if magazine.count == MAGAZINE_SIZE {
+            let mut depot = self.depot.lock();
+            while magazine.count > MAGAZINE_SIZE / 2 {
+                let object = magazine.pop().unwrap();
+                self.release(&mut depot, object);
+            }
+        }
+        magazine.push(object);
+    }
+
+    /// Move half a magazine of objects from the slabs to `magazine`, allocating a slab if none
+    /// has free objects.
+    fn refill(&self, magazine: &mut Magazine) {
+        let mut depot = self.depot.lock();
+        if depot.partial.is_null() {
+            // The frame allocator may shrink the caches when it runs out.
+            drop(depot);
+            let Some(slab) = self.new_slab() else {
+                return;
+            };
+            depot = self.depot.lock();
+            unsafe { (*slab).next = depot.partial };
+            depot.partial = slab;
+        }
+
+        while magazine.count < MAGAZINE_SIZE / 2 {
+            let Some(slab) = (unsafe { depot.partial.as_mut() }) else {
+                break;
+            };
+            let object = slab.free;
+            slab.free = unsafe { (*object).next };
+            slab.in_use += 1;
+            if slab.free.is_null() {
+                depot.partial = slab.next;
+                slab.next = ptr::null_mut();
+            }
+            magazine.push(object.cast());
+        }
+    }
+
+    fn new_slab(&self) -> Option<*mut Slab> {
+        let frame = allocate_p2frame(self.order)?;
+        let base = unsafe { RmmA::phys_to_virt(frame.base()) }.data();
+
+        let mut free = ptr::null_mut();
+        let capacity = (self.slab_size() - self.first) / self.stride;
+        for index in (0..capacity).rev() {
+            let object = (base + self.first + index * self.stride) as *mut FreeObject;
+            unsafe { object.write(FreeObject { next: free }) };
+            free = object;
+        }
+
+        let slab = base as *mut Slab;
+        unsafe {
+            slab.write(Slab {
+                free,
+                in_use: 0,
+                next: ptr::null_mut(),
+            })
+        };
+        Some(slab)
+    }
+
+    /// Return an object to its slab.
+    unsafe fn release(&self, depot: &mut Depot, object: *mut u8) {
+        let slab = &mut *((object as usize & !(self.slab_size() - 1)) as *mut Slab);
+        if slab.free.is_null() {
+            slab.next = depot.partial;
+            depot.partial = slab;
+        }
+        let object = object.cast::<FreeObject>();
+        object.write(FreeObject { next: slab.free });
+        slab.free = object;
+        slab.in_use -= 1;
+    }
+
+    /// Empty the magazines, and give the slabs with no objects in use back to the frame allocator,
+    /// returning how many frames were freed. Locks held by this CPU are skipped rather than waited
+    /// for, as it may be allocating from the cache.
+    pub fn shrink(&self) -> usize {
+        for magazine in self.magazines.iter() {
+            let (Some(mut magazine), Some(mut depot)) =
+                (magazine.try_lock(), self.depot.try_lock())
+            else {
+                continue;
+            };
+            while let Some(object) = magazine.pop() {
+                unsafe { self.release(&mut depot, object) };
+            }
+        }
+
+        let mut empty = ptr::null_mut::<Slab>();
+        {
+            let Some(mut depot) = self.depot.try_lock() else {
+                return 0;
+            };
+            let mut link: *mut *mut Slab = &mut depot.partial;
+            while let Some(slab) = unsafe { (*link).as_mut() } {
+                if slab.in_use == 0 {
+                    unsafe { *link = slab.next };
+                    slab.next = empty;
+                    empty = slab;
+                } else {
+                    link = &mut slab.next;
+                }
+            }
+        }
+
+        let mut freed = 0;
+        while let Some(slab) = unsafe { empty.as_ref() } {
+            let next = slab.next;
+            let phys = PhysicalAddress::new(empty as usize - crate::PHYS_OFFSET);
+            unsafe { deallocate_p2frame(Frame::containing(phys), self.order) };
+            freed += 1 << self.order;
+            empty = next;
+        }
+        freed
+    }
+}
+
+/// The layout of the allocation of an [`Arc`](alloc::sync::Arc) of `T`, which stores two counts
+/// before the value.
+const fn arc_layout<T>() -> Layout {
+    let align = if mem::align_of::<T>() > mem::align_of::<usize>() {
+        mem::align_of::<T>()
+    } else {
+        mem::align_of::<usize>()
+    };
+    let offset = align_up(2 * mem::size_of::<usize>(), mem::align_of::<T>());
+    match Layout::from_size_align(align_up(offset + mem::size_of::<T>(), align), align) {
+        Ok(layout) => layout,
+        Err(_) => panic!("invalid Arc layout"),
+    }
+}
+
+percpu! {
+    static CONTEXT_MAGAZINES: Mutex<Magazine> = Mutex::new(Magazine::new());
+    static FILE_MAGAZINES: Mutex<Magazine> = Mutex::new(Magazine::new());
+}
+
+static CONTEXTS: SlabCache =
+    SlabCache::new(arc_layout::<RwSpinlock<Context>>(), &CONTEXT_MAGAZINES);
+static FILES: SlabCache = SlabCache::new(arc_layout::<RwLock<FileDescription>>(), &FILE_MAGAZINES);
+
+static CACHES: [&SlabCache; 2] = [&CONTEXTS, &FILES];
+
+/// The cache serving `layout`, once caches are enabled.
+fn cache_for(layout: Layout) -> Option<&'static SlabCache> {
+    if !ENABLED.load(Ordering::Acquire) {
+        return None;
+    }
+    CACHES.iter().copied().find(|cache| cache.layout == layout)
+}
+
+/// Allocate from the cache serving `layout`, if there is one and it has memory left.
+pub fn alloc(layout: Layout) -> Option<*mut u8> {
+    let object = cache_for(layout)?.alloc();
+    (!object.is_null()).then_some(object)
+}
+
+/// Free `ptr` to the cache it was allocated from, returning false if it came from the heap.
+pub unsafe fn free(ptr: *mut u8, layout: Layout) -> bool {
+    let Some(cache) = CACHES.iter().find(|cache| cache.layout == layout) else {
+        return false;
+    };
+    // Slabs are in the linear mapping of physical memory, below the heap. Objects of a cache's
+    // layout may still come from the heap, if they were allocated before caches were enabled or
+    // when the cache had no memory.
+    if ptr as usize >= crate::KERNEL_HEAP_OFFSET {
+        return false;
+    }
+    cache.free(ptr);
+    true
+}
+
+/// Give the free slabs of every cache back, returning how many frames were freed.
+pub fn shrink() -> usize {
+    CACHES.iter().map(|cache| cache.shrink()).sum()
+}
+
+/// Enable the caches, once the per-CPU variables of every CPU have been set up.
+pub fn init() {
+    ENABLED.store(true, Ordering::Release);
+}
@@ -0,0 +1,496 @@
+//! Swapping of private anonymous memory to a pluggable backing store.
+//!
+//! Once less than 1/64 of all frames is free, allocating a frame wakes up the registered
+//! [`SwapProvider`], which calls [`reclaim`] from a context that can block, and so does `[kswapd]`
+//! under memory pressure, see [`super::reclaim`]. Reclaiming evicts pages of private anonymous
+//! grants that are mapped by a single address space, until 1/32 of all frames is free again, or
+//! as many as `[kswapd]` is trying to free. An evicted page is unmapped, and the [`SwapEntry`] referencing its slot
+//! is kept in the [`SwapMap`] of the address space. The PTE itself is left empty, since the page
+//! mapper frees page tables that have no present entries left.
+//!
+//! Frames being written out or read back are kept in the swap cache, indexed by slot. A fault on a
+//! swapped out page takes (or copies) the frame from the cache if it is still there, or otherwise
+//! starts a read and waits for the provider to complete it, after which the access is retried.
+
+use alloc::{collections::BTreeMap, sync::Arc, vec::Vec};
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::{
+    common::try_alloc::{try_push, try_vec_filled},
+    context::memory::{copy_frame_to_frame_directly, AddrSpaceWrapper, PageSpan, PfError},
+    paging::Page,
+    sync::{Mutex, WaitCondition},
+    syscall::error::{Error, Result, EBUSY, ENOMEM},
+};
+
+use super::{
+    deallocate_frame, free_frames, get_page_info, init_user_frame, reclaim, total_frames, Frame,
+    RefCount,
+};
+
+/// Reclaim is requested once less than `total >> LOW_WATERMARK_SHIFT` frames are free,
+const LOW_WATERMARK_SHIFT: u32 = 6;
+/// and evicts pages until `total >> HIGH_WATERMARK_SHIFT` frames are free again.
+const HIGH_WATERMARK_SHIFT: u32 = 5;
+
+/// Index of a page-sized slot of the backing store.
+pub type SwapSlot = usize;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SwapRequest {
+    /// Write the contents of `frame` to `slot`, then call [`write_done`].
+    Write { slot: SwapSlot, frame: Frame },
+    /// Read `slot` into `frame`, then call [`read_done`].
+    Read { slot: SwapSlot, frame: Frame },
+}
+
+/// Backing store for swapped out pages.
+pub trait SwapProvider: Send + Sync {
+    /// Number of page-sized slots.
+    fn slot_count(&self) -> usize;
+    /// Start a request. The frame remains valid until the request has been completed.
+    fn submit(&self, request: SwapRequest);
+    /// Have [`reclaim`] called soon, from a context that can block. This is called when
+    /// allocating frames, so it must neither allocate frames nor block.
+    fn wake_reclaimer(&self);
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum CacheState {
+    Writing,
+    Reading,
+    /// The frame holds the contents of the slot, either read back, or because writing it failed.
+    Ready,
+    /// Reading the slot failed, and its contents are lost.
+    Failed,
+}
+
+struct CacheEntry {
+    frame: Frame,
+    state: CacheState,
+}
+
+struct Swap {
+    /// None once the provider has gone away, until the remaining entries are released.
+    provider: Option<Arc<dyn SwapProvider>>,
+    /// Number of [`SwapEntry`] references to each slot.
+    refcounts: Vec<u32>,
+    /// Where the search for a free slot starts.
+    next_slot: SwapSlot,
+    /// Frames with I/O in progress, or read back but not yet mapped.
+    cache: BTreeMap<SwapSlot, CacheEntry>,
+}
+
+impl Swap {
+    fn is_free(&self, slot: SwapSlot) -> bool {
+        self.refcounts[slot] == 0 && !self.cache.contains_key(&slot)
+    }
+}
+
+static SWAP: Mutex<Option<Swap>> = Mutex::new(None);
+/// Set while a provider is registered.
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+/// Set when the provider has been woken up, until it starts reclaiming.
+static RECLAIM_REQUESTED: AtomicBool = AtomicBool::new(false);
+/// Notified whenever a read completes.
+static READ_DONE: WaitCondition = WaitCondition::new();
+
+unsafe fn free_cached_frame(frame: Frame) {
+    let info = get_page_info(frame).expect("swap cache frame lacks page info");
+    if info.remove_ref().is_none() {
+        deallocate_frame(frame);
+    }
+}
+
+/// Register the backing store, failing if there already is one, or if pages swapped out to a
+/// previous one have not all been released yet.
+pub fn register(provider: Arc<dyn SwapProvider>) -> Result<()> {
+    let refcounts = try_vec_filled(0, provider.slot_count())?;
+
+    let mut swap = SWAP.lock();
+    if let Some(ref old) = *swap
+        && (old.provider.is_some() || old.refcounts.iter().any(|&rc| rc != 0))
+    {
+        return Err(Error::new(EBUSY));
+    }
+    *swap = Some(Swap {
+        provider: Some(provider),
+        refcounts,
+        next_slot: 0,
+        cache: BTreeMap::new(),
+    });
+    ACTIVE.store(true, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Unregister the backing store. Pages that were being written out are kept in memory, while
+/// reads that have not completed fail, as do all future swap-ins from it.
+pub fn unregister() {
+    ACTIVE.store(false, Ordering::Relaxed);
+
+    let mut freed = Vec::new();
+    {
+        let mut guard = SWAP.lock();
+        let Some(swap) = guard.as_mut() else {
+            return;
+        };
+        swap.provider = None;
+
+        let refcounts = &swap.refcounts;
+        swap.cache.retain(|&slot, entry| {
+            entry.state = match entry.state {
+                CacheState::Writing => CacheState::Ready,
+                CacheState::Reading => CacheState::Failed,
+                state => state,
+            };
+            if refcounts[slot] == 0 {
+                // Best effort, a failed push only leaks the frame.
+                let _ = try_push(&mut freed, entry.frame);
+                false
+            } else {
+                true
+            }
+        });
+    }
+    for frame in freed {
+        unsafe { free_cached_frame(frame) };
+    }
+    READ_DONE.notify();
+}
+
+/// Wake up the provider if free memory is running low. Called before allocating user frames.
+pub fn check_watermark() {
+    if !ACTIVE.load(Ordering::Relaxed) || free_frames() >= total_frames() >> LOW_WATERMARK_SHIFT {
+        return;
+    }
+    request_reclaim();
+}
+
+/// Wake up the provider to evict pages, if there is one, such as from `[kswapd]`.
+pub fn request_reclaim() {
+    if !ACTIVE.load(Ordering::Relaxed) || RECLAIM_REQUESTED.swap(true, Ordering::Relaxed) {
+        return;
+    }
+    // Frames are also allocated with the lock held, in which case the next allocation retries.
+    let provider = SWAP
+        .try_lock()
+        .and_then(|swap| swap.as_ref()?.provider.clone());
+    match provider {
+        Some(provider) => provider.wake_reclaimer(),
+        None => RECLAIM_REQUESTED.store(false, Ordering::Relaxed),
+    }
+}
+
+pub fn reclaim_requested() -> bool {
+    RECLAIM_REQUESTED.load(Ordering::Relaxed)
+}
+
+/// Whether a provider is registered, to which pages can be evicted.
+pub fn is_active() -> bool {
+    ACTIVE.load(Ordering::Relaxed)
+}
+
+/// Evict pages until enough frames are free again, returning how many were evicted.
+///
+/// The address space of the calling context is never evicted from, so that a provider serving
+/// requests from userspace does not end up waiting for itself.
+pub fn reclaim() -> usize {
+    RECLAIM_REQUESTED.store(false, Ordering::Relaxed);
+
+    let target = (total_frames() >> HIGH_WATERMARK_SHIFT)
+        .saturating_sub(free_frames())
+        .max(reclaim::shortfall());
+    if target == 0 {
+        return 0;
+    }
+
+    swap_out_from(reclaim::other_addr_spaces(), target)
+}
+
+/// Evict up to `target` pages from `addr_spaces`, in order, returning how many were evicted.
+pub fn swap_out_from(addr_spaces: Vec<Arc<AddrSpaceWrapper>>, target: usize) -> usize {
+    let mut evicted = 0;
+    for addr_space in addr_spaces {
+        if evicted >= target {
+            break;
+        }
+        evicted += addr_space.swap_out(target - evicted);
+    }
+    evicted
+}
+
+/// A reference to a slot holding the contents of a swapped out page, released when dropped.
+#[derive(Debug)]
+pub struct SwapEntry {
+    slot: SwapSlot,
+}
+
+impl SwapEntry {
+    pub fn slot(&self) -> SwapSlot {
+        self.slot
+    }
+    pub fn try_clone(&self) -> Result<Self> {
+        let mut guard = SWAP.lock();
+        let swap = guard.as_mut().expect("swap entry without swap state");
+        let refcount = &mut swap.refcounts[self.slot];
+        *refcount = refcount.checked_add(1).ok_or(Error::new(ENOMEM))?;
+        Ok(Self { slot: self.slot })
+    }
+}
+
+impl Drop for SwapEntry {
+    fn drop(&mut self) {
+        let frame = {
+            let mut guard = SWAP.lock();
+            let swap = guard.as_mut().expect("swap entry without swap state");
+            swap.refcounts[self.slot] -= 1;
+            if swap.refcounts[self.slot] != 0 {
+                return;
+            }
+            // Frames with I/O in progress are freed once it completes.
+            match swap.cache.get(&self.slot) {
+                Some(entry) if matches!(entry.state, CacheState::Ready | CacheState::Failed) => {
+                    swap.cache.remove(&self.slot).map(|entry| entry.frame)
+                }
+                _ => None,
+            }
+        };
+        if let Some(frame) = frame {
+            unsafe { free_cached_frame(frame) };
+        }
+    }
+}
+
+/// Reserve a slot for a page about to be evicted, or None if the backing store is full.
+pub fn reserve() -> Option<SwapEntry> {
+    let mut guard = SWAP.lock();
+    let swap = guard.as_mut()?;
+    swap.provider.as_ref()?;
+
+    let count = swap.refcounts.len();
+    let slot = (0..count)
+        .map(|i| (swap.next_slot + i) % count)
+        .find(|&slot| swap.is_free(slot))?;
+
+    swap.refcounts[slot] = 1;
+    swap.next_slot = (slot + 1) % count;
+    Some(SwapEntry { slot })
+}
+
+/// Start writing `frame`, which must no longer be mapped anywhere, to the reserved `slot`. The
+/// frame is owned by the swap cache from now on.
+pub fn write_out(slot: SwapSlot, frame: Frame) {
+    let mut guard = SWAP.lock();
+    let swap = guard.as_mut().expect("writing out without swap state");
+
+    let provider = swap.provider.clone();
+    let state = if provider.is_some() {
+        CacheState::Writing
+    } else {
+        CacheState::Ready
+    };
+    swap.cache.insert(slot, CacheEntry { frame, state });
+
+    if let Some(provider) = provider {
+        provider.submit(SwapRequest::Write { slot, frame });
+    }
+}
+
+/// Get a frame with the contents of the swapped out page referenced by `entry`, which the caller
+/// then owns. If it first needs to be read from the backing store, the read is started and None
+/// is returned, and the caller should wait using [`wait_for_read`] before retrying.
+pub fn swap_in(entry: &SwapEntry) -> Result<Option<Frame>, PfError> {
+    let mut guard = SWAP.lock();
+    let swap = guard.as_mut().expect("swap entry without swap state");
+    let slot = entry.slot;
+
+    match swap.cache.get(&slot).map(|cached| (cached.state, cached.frame)) {
+        // The last reference can simply take the frame.
+        Some((CacheState::Ready, frame)) if swap.refcounts[slot] == 1 => {
+            swap.cache.remove(&slot);
+            Ok(Some(frame))
+        }
+        // The frame is still needed by the other references, or by the provider writing it.
+        Some((CacheState::Ready | CacheState::Writing, cached_frame)) => {
+            let frame = init_user_frame(RefCount::One, None)?;
+            unsafe { copy_frame_to_frame_directly(frame, cached_frame) };
+            Ok(Some(frame))
+        }
+        Some((CacheState::Reading, _)) => Ok(None),
+        Some((CacheState::Failed, _)) => {
+            log::warn!("Accessing page lost to failed swap-in from slot {}", slot);
+            Err(PfError::Segv)
+        }
+        None => {
+            let Some(provider) = swap.provider.clone() else {
+                log::warn!("Accessing page swapped out to unregistered provider");
+                return Err(PfError::Segv);
+            };
+            let frame = init_user_frame(RefCount::One, None)?;
+            swap.cache.insert(
+                slot,
+                CacheEntry {
+                    frame,
+                    state: CacheState::Reading,
+                },
+            );
+            provider.submit(SwapRequest::Read { slot, frame });
+            Ok(None)
+        }
+    }
+}
+
+/// Return a frame obtained from [`swap_in`] that could not be mapped.
+pub fn undo_swap_in(entry: &SwapEntry, frame: Frame) {
+    {
+        let mut guard = SWAP.lock();
+        let swap = guard.as_mut().expect("swap entry without swap state");
+        if !swap.cache.contains_key(&entry.slot) {
+            swap.cache.insert(
+                entry.slot,
+                CacheEntry {
+                    frame,
+                    state: CacheState::Ready,
+                },
+            );
+            return;
+        }
+    }
+    unsafe { free_cached_frame(frame) };
+}
+
+/// Wait until the read of `slot` has completed. Returns false if interrupted by a signal.
+pub fn wait_for_read(slot: SwapSlot) -> bool {
+    let guard = SWAP.lock();
+    let reading = guard
+        .as_ref()
+        .and_then(|swap| swap.cache.get(&slot))
+        .is_some_and(|cached| cached.state == CacheState::Reading);
+    if !reading {
+        return true;
+    }
+    READ_DONE.wait(guard, "swap-in")
+}
+
+/// Complete a [`SwapRequest::Write`]. If it failed, the page is kept in memory instead.
+pub fn write_done(slot: SwapSlot, result: Result<()>) {
+    let frame = {
+        let mut guard = SWAP.lock();
+        let Some(swap) = guard.as_mut() else {
+            return;
+        };
+        match swap.cache.get_mut(&slot) {
+            Some(cached) if cached.state == CacheState::Writing => match result {
+                Ok(()) => swap.cache.remove(&slot).map(|cached| cached.frame),
+                Err(err) => {
+                    log::warn!("Failed to swap out to slot {}: {}", slot, err);
+                    cached.state = CacheState::Ready;
+                    if swap.refcounts[slot] != 0 {
+                        return;
+                    }
+                    swap.cache.remove(&slot).map(|cached| cached.frame)
+                }
+            },
+            _ => None,
+        }
+    };
+    if let Some(frame) = frame {
+        unsafe { free_cached_frame(frame) };
+    }
+}
+
+/// Complete a [`SwapRequest::Read`], filling its frame using `fill` unless the read failed. If
+/// `fill` fails, the request remains pending.
+pub fn read_done(
+    slot: SwapSlot,
+    result: Result<()>,
+    fill: impl FnOnce(Frame) -> Result<()>,
+) -> Result<()> {
+    let frame = {
+        let guard = SWAP.lock();
+        match guard.as_ref().and_then(|swap| swap.cache.get(&slot)) {
+            Some(cached) if cached.state == CacheState::Reading => cached.frame,
+            _ => return Ok(()),
+        }
+    };
+    // The frame cannot go away while the read is pending, and is not yet visible to anything
+    // else, so it is filled without holding the lock.
+    let state = match result {
+        Ok(()) => {
+            fill(frame)?;
+            CacheState::Ready
+        }
+        Err(err) => {
+            log::warn!("Failed to swap in from slot {}: {}", slot, err);
+            CacheState::Failed
+        }
+    };
+
+    let freed = {
+        let mut guard = SWAP.lock();
+        let swap = guard.as_mut().expect("swap state disappeared during read");
+        let cached = swap
+            .cache
+            .get_mut(&slot)
+            .expect("swap cache entry disappeared during read");
+        cached.state = state;
+        if swap.refcounts[slot] == 0 {
+            swap.cache.remove(&slot).map(|cached| cached.frame)
+        } else {
+            None
+        }
+    };
+    if let Some(frame) = freed {
+        unsafe { free_cached_frame(frame) };
+    }
+    READ_DONE.notify();
+    Ok(())
+}
+
+/// The swap entries of the swapped out pages of an address space.
+#[derive(Debug, Default)]
+pub struct SwapMap {
+    entries: BTreeMap<Page, SwapEntry>,
+}
+
+impl SwapMap {
+    pub const fn new() -> Self {
+        Self {
+            entries: BTreeMap::new(),
+        }
+    }
+    pub fn contains(&self, page: Page) -> bool {
+        self.entries.contains_key(&page)
+    }
+    pub fn intersects(&self, span: PageSpan) -> bool {
+        !span.is_empty() && self.entries.range(span.base..span.end()).next().is_some()
+    }
+    /// Insert the entry of an evicted page, releasing any stale entry it replaces.
+    pub fn insert(&mut self, page: Page, entry: SwapEntry) {
+        self.entries.insert(page, entry);
+    }
+    pub fn remove(&mut self, page: Page) -> Option<SwapEntry> {
+        self.entries.remove(&page)
+    }
+    /// Remove the entries within `span`, which are released when the returned map is dropped.
+    pub fn remove_span(&mut self, span: PageSpan) -> SwapMap {
+        let mut removed = self.entries.split_off(&span.base);
+        let mut after = removed.split_off(&span.end());
+        self.entries.append(&mut after);
+        SwapMap { entries: removed }
+    }
+    /// Insert the entries of `moved`, which was removed at `from`, at the same offsets from `to`.
+    pub fn insert_moved(&mut self, moved: SwapMap, from: Page, to: Page) {
+        for (page, entry) in moved.entries {
+            self.insert(to.next_by(page.offset_from(from)), entry);
+        }
+    }
+    /// Clone the map for a forked address space, whose pages then share the slots.
+    pub fn try_clone(&self) -> Result<Self> {
+        let mut new = Self::new();
+        for (&page, entry) in &self.entries {
+            new.entries.insert(page, entry.try_clone()?);
+        }
+        Ok(new)
+    }
+}
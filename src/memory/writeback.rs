@@ -0,0 +1,178 @@
+//! Writeback of shared file mappings.
+//!
+//! The pages of `MAP_SHARED` file mappings are frames borrowed from the scheme providing the file,
+//! usually from its page cache, so that writes to them are seen by the scheme right away. What the
+//! scheme cannot see is which pages were written, and need to be written back to storage. The
+//! kernel finds them from the dirty bits of the page table entries, and sends the scheme an msync
+//! request for each range of the file that was written, with the same arguments as munmap: the
+//! file, the size, the `MSYNC_*` flags and the offset.
+//!
+//! This happens every [`WRITEBACK_INTERVAL`] from the `[writeback]` work queue, from `[kswapd]`
+//! under memory pressure, when msync is called through the [`ADDRSPACE_OP_MSYNC`] operation of a
+//! `proc:` address space handle, and as the mapping is unmapped, where the munmap request has
+//! `NEEDS_SYNC` set if it was written to.
+//! Without dirty bits, any resident page of a writable mapping is assumed to be written, and there
+//! is no periodic writeback.
+
+use alloc::{sync::Arc, vec::Vec};
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::{
+    common::try_alloc::try_push,
+    context::{
+        self,
+        memory::{self, AddrSpaceWrapper, GrantFileRef, PageSpan, Provider},
+    },
+    paging::{Page, VirtualAddress},
+    scheme,
+    syscall::error::{Error, Result, EINVAL, ENODEV},
+    time,
+    workqueue::{Work, WorkQueue},
+};
+
+use super::PAGE_SIZE;
+
+/// Operation of `proc:` address space handles, followed by the address, the length and the flags.
+pub const ADDRSPACE_OP_MSYNC: usize = 4;
+
+/// Return once the writeback was scheduled by the scheme.
+pub const MSYNC_ASYNC: usize = 1 << 0;
+/// Accepted for compatibility, as the pages are those of the scheme already.
+pub const MSYNC_INVALIDATE: usize = 1 << 1;
+/// Return once the data was written back by the scheme.
+pub const MSYNC_SYNC: usize = 1 << 2;
+
+/// Time between two passes of periodic writeback.
+const WRITEBACK_INTERVAL: u128 = 5 * time::NANOS_PER_SEC;
+
+static WRITEBACK_QUEUE: WorkQueue = WorkQueue::new();
+static WRITEBACK_WORK: Work = Work::new(writeback_pass);
+
+/// A range of a file that was written to through a shared mapping.
+#[derive(Debug)]
+pub struct DirtyRange {
+    /// The file, and the offset of the range.
+    pub file_ref: GrantFileRef,
+    pub size: usize,
+}
+
+pub struct Stats {
+    pub passes: AtomicUsize,
+    pub pages_written: AtomicUsize,
+    pub errors: AtomicUsize,
+}
+pub static STATS: Stats = Stats {
+    passes: AtomicUsize::new(0),
+    pages_written: AtomicUsize::new(0),
+    errors: AtomicUsize::new(0),
+};
+
+/// Ask the schemes providing `ranges` to write them back, returning the first error.
+pub fn write_back(ranges: Vec<DirtyRange>, flags: usize) -> Result<()> {
+    let mut result = Ok(());
+    for DirtyRange { file_ref, size } in ranges {
+        let GrantFileRef {
+            description,
+            base_offset,
+        } = file_ref;
+        let (scheme_id, number) = match description.read() {
+            ref desc => (desc.scheme, desc.number),
+        };
+
+        let msync_result = scheme::schemes()
+            .get(scheme_id)
+            .cloned()
+            .ok_or(Error::new(ENODEV))
+            .and_then(|scheme| scheme.kmsync(number, base_offset, size, flags));
+
+        match msync_result {
+            Ok(()) => {
+                STATS
+                    .pages_written
+                    .fetch_add(size / PAGE_SIZE, Ordering::Relaxed);
+            }
+            Err(err) => {
+                STATS.errors.fetch_add(1, Ordering::Relaxed);
+                if result.is_ok() {
+                    result = Err(err);
+                }
+            }
+        }
+
+        // The mapping may have been unmapped in the meantime.
+        if let Ok(fd) = Arc::try_unwrap(description) {
+            let _ = fd.into_inner().try_close();
+        }
+    }
+    result
+}
+
+/// Write back the pages of shared file mappings in `span` of `addr_space`, written to since the
+/// last time.
+pub fn msync(addr_space: &AddrSpaceWrapper, span: PageSpan, flags: usize) -> Result<()> {
+    if flags & !(MSYNC_ASYNC | MSYNC_INVALIDATE | MSYNC_SYNC) != 0
+        || flags & (MSYNC_ASYNC | MSYNC_SYNC) == MSYNC_ASYNC | MSYNC_SYNC
+    {
+        return Err(Error::new(EINVAL));
+    }
+    write_back(addr_space.harvest_dirty_fmap(span)?, flags)
+}
+
+/// Address spaces with shared file mappings.
+fn with_fmaps() -> Vec<Arc<AddrSpaceWrapper>> {
+    let mut addr_spaces = Vec::<Arc<AddrSpaceWrapper>>::new();
+    for context_ref in context::contexts().iter().filter_map(|r| r.upgrade()) {
+        let Ok(addr_space) = context_ref.read().addr_space().cloned() else {
+            continue;
+        };
+        if addr_spaces.iter().any(|a| Arc::ptr_eq(a, &addr_space)) {
+            continue;
+        }
+        if try_push(&mut addr_spaces, addr_space).is_err() {
+            break;
+        }
+    }
+    addr_spaces.retain(|a| {
+        a.acquire_read()
+            .grants
+            .iter()
+            .any(|(_, info)| matches!(info.provider, Provider::FmapBorrowed { .. }))
+    });
+    addr_spaces
+}
+
+/// Ask the schemes to write back every range written to since the previous pass.
+pub fn write_back_all() {
+    let user_span = PageSpan::new(
+        Page::containing_address(VirtualAddress::new(0)),
+        crate::USER_END_OFFSET / PAGE_SIZE,
+    );
+    for addr_space in with_fmaps() {
+        // The pages stay dirty until the next pass.
+        let ranges = match addr_space.harvest_dirty_fmap(user_span) {
+            Ok(ranges) => ranges,
+            Err(_) => continue,
+        };
+        drop(addr_space);
+        if let Err(err) = write_back(ranges, MSYNC_ASYNC) {
+            log::warn!("writeback failed: {:?}", err);
+        }
+    }
+    STATS.passes.fetch_add(1, Ordering::Relaxed);
+}
+
+fn writeback_pass() {
+    write_back_all();
+    WRITEBACK_QUEUE.schedule_delayed(&WRITEBACK_WORK, WRITEBACK_INTERVAL);
+}
+
+/// Spawn the `[writeback]` work queue, and start periodic writeback on it, where pages have dirty
+/// bits.
+pub fn spawn() -> Result<()> {
+    if !memory::has_dirty_bits() {
+        return Ok(());
+    }
+    WRITEBACK_QUEUE.spawn("[writeback]".into(), None)?;
+    WRITEBACK_QUEUE.schedule_delayed(&WRITEBACK_WORK, WRITEBACK_INTERVAL);
+    Ok(())
+}
@@ -0,0 +1,166 @@
+//! Speculative execution mitigations.
+//!
+//! The `speculation` module of the architecture detects which vulnerabilities the CPU is affected
+//! by and which controls it has against them, and applies the enabled [`Mitigation`]s:
+//!
+//! - `ibrs` restricts indirect branch speculation, so that branch targets trained in userspace are
+//!   not used by the kernel. The kernel is not built with retpolines, so this is its only
+//!   protection against Spectre variant 2.
+//! - `ibpb` flushes the branch predictors when a CPU switches to another address space, so that
+//!   processes cannot train the branches of each other.
+//! - `ssbd` disables speculative store bypass.
+//!
+//! The mitigations the CPU supports are enabled at boot if it is affected, unless they are costly,
+//! and none is with `MITIGATIONS=off` in the environment. Root changes them at runtime through
+//! `sys:mitigations`, which also reports the status of every vulnerability, but cannot disable them
+//! when the kernel is locked down for confidentiality. CPUs apply a change the next time they switch
+//! contexts.
+
+use core::{
+    str,
+    sync::atomic::{AtomicU32, AtomicU8, Ordering},
+};
+
+use crate::{
+    lockdown,
+    percpu::PercpuBlock,
+    speculation,
+    syscall::error::{Error, Result, EINVAL, ENODEV},
+};
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Vulnerability {
+    /// Reading kernel memory from userspace (Meltdown).
+    Meltdown,
+    /// Branch target injection (Spectre variant 2).
+    SpectreV2,
+    /// Speculative store bypass (Spectre variant 4).
+    SpecStoreBypass,
+}
+
+impl Vulnerability {
+    pub const ALL: [Self; 3] = [Self::Meltdown, Self::SpectreV2, Self::SpecStoreBypass];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Meltdown => "meltdown",
+            Self::SpectreV2 => "spectre_v2",
+            Self::SpecStoreBypass => "spec_store_bypass",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Status {
+    NotAffected,
+    /// Mitigated by what is named.
+    Mitigated(&'static str),
+    Vulnerable,
+    /// The architecture cannot tell.
+    Unknown,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[repr(u8)]
+pub enum Mitigation {
+    Ibrs,
+    Ibpb,
+    Ssbd,
+}
+
+impl Mitigation {
+    pub const ALL: [Self; 3] = [Self::Ibrs, Self::Ibpb, Self::Ssbd];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Ibrs => "ibrs",
+            Self::Ibpb => "ibpb",
+            Self::Ssbd => "ssbd",
+        }
+    }
+    fn bit(self) -> u8 {
+        1 << self as u8
+    }
+}
+
+/// The enabled mitigations, by bit.
+static ENABLED: AtomicU8 = AtomicU8::new(0);
+/// Incremented when the enabled mitigations change, for the CPUs to apply them. Starts at 1, so
+/// that every CPU applies them when it first switches contexts.
+static GENERATION: AtomicU32 = AtomicU32::new(1);
+
+pub fn enabled(mitigation: Mitigation) -> bool {
+    ENABLED.load(Ordering::Relaxed) & mitigation.bit() != 0
+}
+
+pub fn supported(mitigation: Mitigation) -> bool {
+    speculation::supported(mitigation)
+}
+
+pub fn status(vulnerability: Vulnerability) -> Status {
+    speculation::status(vulnerability)
+}
+
+/// Enable the mitigations recommended for the CPU, unless disabled with `MITIGATIONS=off`.
+pub fn init(env: &[u8]) {
+    for line in str::from_utf8(env).unwrap_or("").lines() {
+        if line == "MITIGATIONS=off" {
+            log::warn!("Speculative execution mitigations disabled");
+            return;
+        }
+    }
+    let mut enabled = 0;
+    for mitigation in Mitigation::ALL {
+        if supported(mitigation) && speculation::recommended(mitigation) {
+            enabled |= mitigation.bit();
+        }
+    }
+    ENABLED.store(enabled, Ordering::Relaxed);
+    GENERATION.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Called on every context switch, to apply the enabled mitigations if they changed.
+pub fn switch(percpu: &PercpuBlock) {
+    let generation = GENERATION.load(Ordering::Relaxed);
+    if percpu.mitigations_generation.get() != generation {
+        unsafe { speculation::apply() };
+        percpu.mitigations_generation.set(generation);
+    }
+}
+
+/// Called when this CPU switches to another address space.
+pub fn switch_addr_space() {
+    if enabled(Mitigation::Ibpb) {
+        unsafe { speculation::flush_branch_predictors() };
+    }
+}
+
+/// Handle a command written to `sys:mitigations`, `<mitigation> on` or `<mitigation> off`.
+pub fn command(command: &str) -> Result<()> {
+    let (name, value) = command.trim().split_once(' ').ok_or(Error::new(EINVAL))?;
+    let mitigation = Mitigation::ALL
+        .into_iter()
+        .find(|mitigation| mitigation.name() == name)
+        .ok_or(Error::new(EINVAL))?;
+    let on = match value.trim() {
+        "on" => true,
+        "off" => false,
+        _ => return Err(Error::new(EINVAL)),
+    };
+    if !supported(mitigation) {
+        return Err(Error::new(ENODEV));
+    }
+    if on {
+        ENABLED.fetch_or(mitigation.bit(), Ordering::Relaxed);
+    } else {
+        lockdown::check(lockdown::Reason::Mitigations)?;
+        ENABLED.fetch_and(!mitigation.bit(), Ordering::Relaxed);
+    }
+    GENERATION.fetch_add(1, Ordering::Relaxed);
+    log::info!(
+        "Mitigation {} {}",
+        mitigation.name(),
+        if on { "enabled" } else { "disabled" }
+    );
+    Ok(())
+}
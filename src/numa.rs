@@ -0,0 +1,353 @@
+//! # NUMA topology and memory placement
+//!
+//! The topology maps CPUs and ranges of physical memory to nodes, and is read from the ACPI SRAT,
+//! or from the `numa-node-id` properties of the device tree. Without that information, everything
+//! belongs to node 0, and the placement policies below have no effect.
+//!
+//! The frame allocator keeps separate freelists for each node. Allocations without a hint come
+//! from the node of the allocating CPU, while pages of user grants are placed according to the
+//! memory policy of the context touching them, or of their address space.
+
+use core::sync::atomic::{AtomicU8, AtomicUsize, Ordering};
+
+use alloc::{sync::Arc, vec::Vec};
+use spin::Once;
+
+use crate::{
+    context::{self, Context},
+    cpu_set::{LogicalCpuId, LogicalCpuSet, MAX_CPU_COUNT},
+    memory::Frame,
+    paging::{Page, PhysicalAddress},
+    percpu::PercpuBlock,
+    sync::RwSpinlock,
+    syscall::error::{Error, Result, EINVAL},
+};
+
+/// Maximum number of nodes, limited by the width of [`NodeMask`].
+pub const MAX_NODE_COUNT: usize = 64;
+
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Hash, PartialOrd, Ord)]
+pub struct NodeId(u8);
+
+impl NodeId {
+    pub const fn new(inner: u8) -> Self {
+        Self(inner)
+    }
+    pub const fn get(self) -> u8 {
+        self.0
+    }
+}
+
+/// A set of nodes.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct NodeMask(u64);
+
+impl NodeMask {
+    pub const fn single(node: NodeId) -> Self {
+        Self(1 << node.0)
+    }
+    /// All nodes that exist.
+    pub fn all() -> Self {
+        Self(match node_count() {
+            MAX_NODE_COUNT => u64::MAX,
+            count => (1 << count) - 1,
+        })
+    }
+    /// Create a mask from raw bits, failing if it contains nodes that do not exist.
+    pub fn from_bits(bits: u64) -> Option<Self> {
+        (bits & !Self::all().0 == 0).then_some(Self(bits))
+    }
+    pub const fn bits(self) -> u64 {
+        self.0
+    }
+    pub const fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+    pub const fn contains(self, node: NodeId) -> bool {
+        self.0 & (1 << node.0) != 0
+    }
+    pub fn first(self) -> Option<NodeId> {
+        (!self.is_empty()).then(|| NodeId(self.0.trailing_zeros() as u8))
+    }
+    pub fn iter(self) -> impl Iterator<Item = NodeId> {
+        let mut bits = self.0;
+        core::iter::from_fn(move || {
+            let node = (bits != 0).then(|| NodeId(bits.trailing_zeros() as u8))?;
+            bits &= bits - 1;
+            Some(node)
+        })
+    }
+    /// The `n`th node in the set, modulo the number of nodes.
+    pub fn nth_wrapping(self, n: usize) -> Option<NodeId> {
+        let count = self.0.count_ones() as usize;
+        if count == 0 {
+            return None;
+        }
+        let mut bits = self.0;
+        for _ in 0..n % count {
+            bits &= bits - 1;
+        }
+        Some(NodeId(bits.trailing_zeros() as u8))
+    }
+}
+
+/// A range of physical memory local to a node.
+#[derive(Clone, Copy, Debug)]
+pub struct MemoryAffinity {
+    pub base: PhysicalAddress,
+    pub size: usize,
+    pub node: NodeId,
+}
+
+/// A CPU local to a node, identified by its hardware ID (the APIC ID on x86, and on aarch64, the
+/// ACPI processor UID or the low 32 bits of the MPIDR from the device tree).
+#[derive(Clone, Copy, Debug)]
+pub struct CpuAffinity {
+    pub hw_id: u32,
+    pub node: NodeId,
+}
+
+struct Topology {
+    node_count: usize,
+    /// Sorted by base address.
+    memory: Vec<MemoryAffinity>,
+    cpus: Vec<CpuAffinity>,
+}
+
+static TOPOLOGY: Once<Topology> = Once::new();
+
+static CPU_NODES: [AtomicU8; MAX_CPU_COUNT as usize] = {
+    const NODE_ZERO: AtomicU8 = AtomicU8::new(0);
+    [NODE_ZERO; MAX_CPU_COUNT as usize]
+};
+static NODE_CPUS: [LogicalCpuSet; MAX_NODE_COUNT] = {
+    const EMPTY: LogicalCpuSet = LogicalCpuSet::empty();
+    [EMPTY; MAX_NODE_COUNT]
+};
+
+/// Register the topology, before any APs are started.
+pub fn init(node_count: usize, mut memory: Vec<MemoryAffinity>, cpus: Vec<CpuAffinity>) {
+    assert!(node_count <= MAX_NODE_COUNT);
+
+    memory.sort_unstable_by_key(|area| area.base);
+
+    let topology = TOPOLOGY.call_once(|| Topology {
+        node_count,
+        memory,
+        cpus,
+    });
+    // Free blocks were all put on node 0 until now.
+    crate::memory::sort_freelists_by_node();
+
+    log::info!("NUMA: {} nodes", topology.node_count);
+    for area in &topology.memory {
+        log::info!(
+            "NUMA: node {} memory {:#x}..{:#x}",
+            area.node.get(),
+            area.base.data(),
+            area.base.data() + area.size
+        );
+    }
+}
+
+/// Number of nodes, which is 1 if the topology is unknown.
+pub fn node_count() -> usize {
+    TOPOLOGY.get().map_or(1, |topology| topology.node_count)
+}
+
+/// The node local to a physical address.
+pub fn phys_node(phys: PhysicalAddress) -> NodeId {
+    let Some(topology) = TOPOLOGY.get() else {
+        return NodeId(0);
+    };
+    let idx = topology
+        .memory
+        .partition_point(|area| area.base <= phys)
+        .checked_sub(1);
+
+    idx.map(|idx| topology.memory[idx])
+        .filter(|area| phys.data() - area.base.data() < area.size)
+        .map_or(NodeId(0), |area| area.node)
+}
+pub fn frame_node(frame: Frame) -> NodeId {
+    phys_node(frame.base())
+}
+
+/// The node of a CPU by hardware ID, as found in the topology.
+pub fn hw_cpu_node(hw_id: u32) -> NodeId {
+    TOPOLOGY
+        .get()
+        .and_then(|topology| topology.cpus.iter().find(|cpu| cpu.hw_id == hw_id))
+        .map_or(NodeId(0), |cpu| cpu.node)
+}
+
+/// Record the node of a logical CPU, as it is brought up.
+pub fn set_cpu_node(cpu: LogicalCpuId, node: NodeId) {
+    let old = CPU_NODES[cpu.get() as usize].swap(node.0, Ordering::Relaxed);
+    NODE_CPUS[usize::from(old)].atomic_clear(cpu);
+    NODE_CPUS[usize::from(node.0)].atomic_set(cpu);
+    crate::vdso::set_cpu_node(cpu, node);
+}
+pub fn cpu_node(cpu: LogicalCpuId) -> NodeId {
+    NodeId(CPU_NODES[cpu.get() as usize].load(Ordering::Relaxed))
+}
+/// Whether any online CPU in `set` is local to `node`.
+pub fn node_has_cpu_in(node: NodeId, set: &LogicalCpuSet) -> bool {
+    NODE_CPUS[usize::from(node.0)]
+        .to_raw()
+        .iter()
+        .zip(set.to_raw())
+        .zip(crate::hotplug::offline_mask())
+        .any(|((a, b), offline)| a & b & !offline != 0)
+}
+
+/// Where to allocate frames from, passed to the frame allocator.
+#[derive(Clone, Copy, Debug)]
+pub struct NodeHint {
+    pub nodes: NodeMask,
+    /// Fail rather than fall back to other nodes.
+    pub strict: bool,
+}
+
+/// Memory placement policy of a context or address space, used when allocating new pages for
+/// user grants.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum MemPolicy {
+    /// Allocate from any node, typically the first with free memory.
+    #[default]
+    Default,
+    /// Only allocate from the given nodes.
+    Bind(NodeMask),
+    /// Spread pages over the given nodes, by virtual address.
+    Interleave(NodeMask),
+    /// Prefer the given node, but fall back to others.
+    Preferred(NodeId),
+}
+
+pub const MPOL_DEFAULT: usize = 0;
+pub const MPOL_BIND: usize = 1;
+pub const MPOL_INTERLEAVE: usize = 2;
+pub const MPOL_PREFERRED: usize = 3;
+
+impl MemPolicy {
+    pub fn from_raw(mode: usize, nodes: u64) -> Result<Self> {
+        let nodes = NodeMask::from_bits(nodes).ok_or(Error::new(EINVAL))?;
+
+        Ok(match mode {
+            MPOL_DEFAULT if nodes.is_empty() => Self::Default,
+            MPOL_BIND if !nodes.is_empty() => Self::Bind(nodes),
+            MPOL_INTERLEAVE if !nodes.is_empty() => Self::Interleave(nodes),
+            MPOL_PREFERRED if nodes.bits().count_ones() == 1 => {
+                Self::Preferred(nodes.first().ok_or(Error::new(EINVAL))?)
+            }
+            _ => return Err(Error::new(EINVAL)),
+        })
+    }
+    pub fn to_raw(self) -> (usize, u64) {
+        match self {
+            Self::Default => (MPOL_DEFAULT, 0),
+            Self::Bind(nodes) => (MPOL_BIND, nodes.bits()),
+            Self::Interleave(nodes) => (MPOL_INTERLEAVE, nodes.bits()),
+            Self::Preferred(node) => (MPOL_PREFERRED, NodeMask::single(node).bits()),
+        }
+    }
+    /// This policy, or `other` if this is the default policy.
+    pub fn or(self, other: Self) -> Self {
+        match self {
+            Self::Default => other,
+            policy => policy,
+        }
+    }
+    /// The allocation hint for a new page mapped at `page`.
+    pub fn hint(self, page: Page) -> Option<NodeHint> {
+        if node_count() <= 1 {
+            return None;
+        }
+        match self {
+            Self::Default => None,
+            Self::Bind(nodes) => Some(NodeHint {
+                nodes,
+                strict: true,
+            }),
+            Self::Interleave(nodes) => Some(NodeHint {
+                nodes: NodeMask::single(
+                    nodes.nth_wrapping(page.start_address().data() / crate::memory::PAGE_SIZE)?,
+                ),
+                strict: false,
+            }),
+            Self::Preferred(node) => Some(NodeHint {
+                nodes: NodeMask::single(node),
+                strict: false,
+            }),
+        }
+    }
+}
+
+/// The memory policy of the current context, mirrored in its
+/// [`ContextSwitchPercpu`](crate::context::switch::ContextSwitchPercpu).
+pub fn current_policy() -> MemPolicy {
+    PercpuBlock::current().switch_internals.mempolicy.get()
+}
+
+/// Set the memory policy of `context`.
+pub fn set_context_policy(context: &Arc<RwSpinlock<Context>>, policy: MemPolicy) {
+    context.write().mempolicy = policy;
+    if context::is_current(context) {
+        PercpuBlock::current()
+            .switch_internals
+            .mempolicy
+            .set(policy);
+    }
+}
+
+/// Tracks which node most of the memory of an address space was allocated from.
+#[derive(Debug)]
+pub struct HomeNode {
+    // Node index plus one, or zero if unknown.
+    home: AtomicU8,
+    allocated: [AtomicUsize; MAX_NODE_COUNT],
+}
+
+/// Allocation counts are halved when one reaches this, so that the home node can move.
+const HOME_DECAY_THRESHOLD: usize = 1 << 16;
+
+impl HomeNode {
+    pub const fn new() -> Self {
+        const ZERO: AtomicUsize = AtomicUsize::new(0);
+        Self {
+            home: AtomicU8::new(0),
+            allocated: [ZERO; MAX_NODE_COUNT],
+        }
+    }
+    pub fn get(&self) -> Option<NodeId> {
+        match self.home.load(Ordering::Relaxed) {
+            0 => None,
+            n => Some(NodeId(n - 1)),
+        }
+    }
+    pub fn set(&self, node: Option<NodeId>) {
+        self.home
+            .store(node.map_or(0, |node| node.0 + 1), Ordering::Relaxed);
+    }
+    /// Account a frame newly allocated for the address space.
+    pub fn account(&self, frame: Frame) {
+        if node_count() <= 1 {
+            return;
+        }
+        let node = frame_node(frame);
+        let count = self.allocated[usize::from(node.0)].fetch_add(1, Ordering::Relaxed) + 1;
+
+        if count >= HOME_DECAY_THRESHOLD {
+            for allocated in &self.allocated {
+                let _ =
+                    allocated.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |c| Some(c / 2));
+            }
+        }
+        let is_new_home = self.get().map_or(true, |home| {
+            home != node && self.allocated[usize::from(home.0)].load(Ordering::Relaxed) < count
+        });
+        if is_new_home {
+            self.set(Some(node));
+        }
+    }
+}
@@ -1,16 +1,34 @@
 use core::{
-    cell::{Cell, RefCell},
-    sync::atomic::{AtomicBool, AtomicPtr, Ordering},
+    cell::{Cell, RefCell, UnsafeCell},
+    sync::atomic::{AtomicPtr, AtomicU32, AtomicU8, Ordering},
 };
 
+#[cfg(debug_assertions)]
+use core::sync::atomic::AtomicBool;
+
 use alloc::sync::{Arc, Weak};
 use rmm::Arch;
 use syscall::PtraceFlags;
 
 use crate::{
-    context::{empty_cr3, memory::AddrSpaceWrapper, switch::ContextSwitchPercpu},
+    context::{
+        empty_cr3,
+        memory::{AddrSpaceWrapper, TlbRanges},
+        switch::ContextSwitchPercpu,
+    },
     cpu_set::{LogicalCpuId, MAX_CPU_COUNT},
+    cpufreq::PercpuCpufreq,
+    entropy::InterruptEntropy,
+    idle::PercpuIdle,
+    irq_stats::IrqStats,
+    kernel_executable_offsets::{__percpu_end, __percpu_start},
+    memory::FreeBatch,
+    paging::{RmmA, PAGE_SIZE},
+    perf::PercpuPerf,
     ptrace::Session,
+    sync::{rcu::PercpuRcu, Mutex},
+    timer::TimerWheel,
+    workqueue::WorkQueue,
 };
 
 #[cfg(feature = "syscall_debug")]
@@ -21,15 +39,54 @@ pub struct PercpuBlock {
     /// A unique immutable number that identifies the current CPU - used for scheduling
     pub cpu_id: LogicalCpuId,
 
+    /// Offset of the copies of the [`PerCpu`] variables of this CPU from their templates.
+    pub vars_offset: usize,
+
     /// Context management
     pub switch_internals: ContextSwitchPercpu,
 
     pub current_addrsp: RefCell<Option<Arc<AddrSpaceWrapper>>>,
     pub new_addrsp_tmp: Cell<Option<Arc<AddrSpaceWrapper>>>,
-    pub wants_tlb_shootdown: AtomicBool,
+    pub tlb_mailbox: TlbMailbox,
+    /// The `tlb_generation` of the current address space that the TLB of this CPU reflects.
+    pub tlb_generation: Cell<u64>,
+    /// The generation of address space IDs whose rollover this CPU has flushed its TLB for.
+    pub asid_generation: Cell<u64>,
+    #[cfg(debug_assertions)]
+    pub wants_backtrace: AtomicBool,
+    #[cfg(debug_assertions)]
+    pub lockdep: crate::sync::lockdep::PercpuLockdep,
+
+    /// Read-side critical sections of this CPU.
+    pub rcu: PercpuRcu,
+
+    /// Frames freed on this CPU, not yet returned to the allocator.
+    pub free_batch: Mutex<FreeBatch>,
+
+    /// Timers firing on this CPU.
+    pub timers: Mutex<TimerWheel>,
+
+    /// Work queued on this CPU, run by its `[kworker/N]` thread.
+    pub workqueue: WorkQueue,
+
+    /// Interrupts dispatched on this CPU.
+    pub irq_stats: IrqStats,
+
+    /// Timing of the interrupts of this CPU, for the entropy pool.
+    pub interrupt_entropy: InterruptEntropy,
+
+    /// Idle state residency of this CPU.
+    pub idle: PercpuIdle,
+
+    /// Hardware performance counters of this CPU.
+    pub perf: PercpuPerf,
+
+    /// Frequency scaling state of this CPU.
+    pub cpufreq: PercpuCpufreq,
+
+    /// The generation of the mitigations applied on this CPU.
+    pub mitigations_generation: Cell<u32>,
 
-    // TODO: Put mailbox queues here, e.g. for TLB shootdown? Just be sure to 128-byte align it
-    // first to avoid cache invalidation.
     #[cfg(feature = "profiling")]
     pub profiling: Option<&'static crate::profiling::RingBuffer>,
 
@@ -54,58 +111,234 @@ pub unsafe fn init_tlb_shootdown(id: LogicalCpuId, block: *mut PercpuBlock) {
 
 // PercpuBlock::current() is implemented somewhere in the arch-specific modules
 
-#[cfg(not(feature = "multi_core"))]
-pub fn shootdown_tlb_ipi(_target: Option<LogicalCpuId>) {}
+/// Returns the percpu block of another CPU, if it has been initialized.
+pub fn get(id: LogicalCpuId) -> Option<&'static PercpuBlock> {
+    unsafe {
+        ALL_PERCPU_BLOCKS[id.get() as usize]
+            .load(Ordering::Acquire)
+            .as_ref()
+    }
+}
 
-#[cfg(feature = "multi_core")]
-pub fn shootdown_tlb_ipi(target: Option<LogicalCpuId>) {
-    if let Some(target) = target {
-        let my_percpublock = PercpuBlock::current();
-        assert_ne!(target, my_percpublock.cpu_id);
-
-        let Some(percpublock) = (unsafe {
-            ALL_PERCPU_BLOCKS[target.get() as usize]
-                .load(Ordering::Acquire)
-                .as_ref()
-        }) else {
-            log::warn!("Trying to TLB shootdown a CPU that doesn't exist or isn't initialized.");
-            return;
-        };
-        while percpublock
-            .wants_tlb_shootdown
-            .swap(true, Ordering::Release)
-            == true
-        {
-            // Load is faster than CAS or on x86, LOCK BTS
-            while percpublock.wants_tlb_shootdown.load(Ordering::Relaxed) == true {
-                my_percpublock.maybe_handle_tlb_shootdown();
-                core::hint::spin_loop();
-            }
-        }
+/// A variable of which each CPU has its own copy, declared with [`percpu!`], so that CPUs updating
+/// it never share its cache lines.
+///
+/// The declared static is only the template of the copies, which are made when each CPU
+/// initializes its percpu block, and found at a fixed offset from it for all variables of a CPU.
+/// It must not be used before then.
+pub struct PerCpu<T> {
+    template: UnsafeCell<T>,
+}
 
-        crate::ipi::ipi_single(crate::ipi::IpiKind::Tlb, target);
-    } else {
-        for id in 0..crate::cpu_count() {
-            // TODO: Optimize: use global counter and percpu ack counters, send IPI using
-            // destination shorthand "all CPUs".
-            shootdown_tlb_ipi(Some(LogicalCpuId::new(id)));
+// Each CPU has its own copy, and only shared ones are handed out to other CPUs.
+unsafe impl<T> Sync for PerCpu<T> {}
+
+impl<T> PerCpu<T> {
+    #[doc(hidden)]
+    pub const fn new(value: T) -> Self {
+        Self {
+            template: UnsafeCell::new(value),
         }
     }
+
+    fn copy_at(&self, offset: usize) -> *const T {
+        (self.template.get() as usize).wrapping_add(offset) as *const T
+    }
+
+    /// The copy of this CPU. As with [`PercpuBlock::current`], it is only the copy of the CPU
+    /// running the context until it switches.
+    #[inline(always)]
+    pub fn get(&self) -> &T {
+        unsafe { &*self.copy_at(PercpuBlock::current_vars_offset()) }
+    }
 }
+
+impl<T: Sync> PerCpu<T> {
+    /// The copy of another CPU, if it has been initialized.
+    pub fn get_for(&self, cpu: LogicalCpuId) -> Option<&T> {
+        get(cpu).map(|percpu| unsafe { &*self.copy_at(percpu.vars_offset) })
+    }
+
+    /// The copies of all initialized CPUs, for instance to add up counters.
+    pub fn iter(&self) -> impl Iterator<Item = &T> + '_ {
+        (0..crate::cpu_count()).filter_map(|id| self.get_for(LogicalCpuId::new(id)))
+    }
+}
+
+/// Declare statics of type [`PerCpu`], with the given initial value for each CPU.
+macro_rules! percpu {
+    ($($(#[$attr:meta])* $vis:vis static $name:ident: $ty:ty = $init:expr;)*) => {$(
+        $(#[$attr])*
+        #[link_section = ".percpu"]
+        $vis static $name: $crate::percpu::PerCpu<$ty> = $crate::percpu::PerCpu::new($init);
+    )*};
+}
+pub(crate) use percpu;
+
+/// Copy the templates of the per-CPU variables for a new CPU, returning the offset of the copies.
+fn init_vars() -> usize {
+    let size = __percpu_end() - __percpu_start();
+    if size == 0 {
+        return 0;
+    }
+    let order = size
+        .div_ceil(PAGE_SIZE)
+        .next_power_of_two()
+        .trailing_zeros();
+    let frame =
+        crate::memory::allocate_p2frame(order).expect("failed to allocate per-CPU variables");
+    let virt = unsafe { RmmA::phys_to_virt(frame.base()) }.data();
+    unsafe {
+        core::ptr::copy_nonoverlapping(__percpu_start() as *const u8, virt as *mut u8, size);
+    }
+    virt.wrapping_sub(__percpu_start())
+}
+
+#[cfg(not(target_arch = "x86_64"))]
 impl PercpuBlock {
-    pub fn maybe_handle_tlb_shootdown(&self) {
-        if self.wants_tlb_shootdown.swap(false, Ordering::Relaxed) == false {
-            return;
+    #[inline(always)]
+    pub fn current_vars_offset() -> usize {
+        Self::current().vars_offset
+    }
+}
+
+/// TLB invalidations of an address space, sent to another CPU running it.
+pub struct TlbShootdown {
+    pub ranges: TlbRanges,
+    /// The `tlb_generation` of the address space once the ranges are invalidated.
+    pub generation: u64,
+    /// The `tlb_ack` of the address space, incremented once handled. The sender keeps the address
+    /// space alive until then.
+    pub ack: *const AtomicU32,
+}
+
+const MAILBOX_EMPTY: u8 = 0;
+const MAILBOX_WRITING: u8 = 1;
+const MAILBOX_READY: u8 = 2;
+const MAILBOX_TAKING: u8 = 3;
+
+/// Holds the pending TLB shootdown of a CPU. A single slot is enough, since senders hold the lock
+/// of the address space until every target has handled it, and a CPU only runs one address space
+/// at a time.
+pub struct TlbMailbox {
+    state: AtomicU8,
+    shootdown: UnsafeCell<Option<TlbShootdown>>,
+}
+impl TlbMailbox {
+    fn new() -> Self {
+        Self {
+            state: AtomicU8::new(MAILBOX_EMPTY),
+            shootdown: UnsafeCell::new(None),
         }
+    }
+    /// Take the pending shootdown, if any. Only one caller succeeds, even if the TLB IPI
+    /// interrupts another one.
+    fn take(&self) -> Option<TlbShootdown> {
+        self.state
+            .compare_exchange(
+                MAILBOX_READY,
+                MAILBOX_TAKING,
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            )
+            .ok()?;
+        let shootdown = unsafe { (*self.shootdown.get()).take() };
+        self.state.store(MAILBOX_EMPTY, Ordering::Release);
+        shootdown
+    }
+}
+
+#[cfg(not(feature = "multi_core"))]
+pub fn shootdown_tlb_ipi(_target: LogicalCpuId, _shootdown: TlbShootdown) {}
+
+#[cfg(feature = "multi_core")]
+pub fn shootdown_tlb_ipi(target: LogicalCpuId, shootdown: TlbShootdown) {
+    let my_percpublock = PercpuBlock::current();
+    assert_ne!(target, my_percpublock.cpu_id);
 
-        // TODO: Finer-grained flush
+    let Some(percpublock) = get(target) else {
+        log::warn!("Trying to TLB shootdown a CPU that doesn't exist or isn't initialized.");
+        unsafe { (*shootdown.ack).fetch_add(1, Ordering::Release) };
+        return;
+    };
+    let mailbox = &percpublock.tlb_mailbox;
+    while mailbox
+        .state
+        .compare_exchange_weak(
+            MAILBOX_EMPTY,
+            MAILBOX_WRITING,
+            Ordering::Acquire,
+            Ordering::Relaxed,
+        )
+        .is_err()
+    {
+        // The target may be waiting for this CPU to handle a shootdown of its own.
+        my_percpublock.maybe_handle_tlb_shootdown();
+        core::hint::spin_loop();
+    }
+    unsafe {
+        *mailbox.shootdown.get() = Some(shootdown);
+    }
+    mailbox.state.store(MAILBOX_READY, Ordering::Release);
+
+    crate::ipi::ipi_single(crate::ipi::IpiKind::Tlb, target);
+}
+/// Ask another CPU to print its backtrace, which is done from an NMI so that it also works if it
+/// is spinning with interrupts disabled.
+#[cfg(debug_assertions)]
+pub fn request_backtrace(target: LogicalCpuId) {
+    let Some(percpublock) = (unsafe {
+        ALL_PERCPU_BLOCKS[target.get() as usize]
+            .load(Ordering::Acquire)
+            .as_ref()
+    }) else {
+        return;
+    };
+    percpublock.wants_backtrace.store(true, Ordering::Release);
+    crate::ipi::ipi_nmi(target);
+}
+impl PercpuBlock {
+    /// Print a backtrace if requested by [`request_backtrace`], returning whether it was.
+    #[cfg(debug_assertions)]
+    pub fn maybe_print_backtrace(&self, ip: usize) -> bool {
+        if !self.wants_backtrace.swap(false, Ordering::Acquire) {
+            return false;
+        }
+        println!("BACKTRACE: CPU {}, interrupted at {:#x}", self.cpu_id, ip);
         unsafe {
-            crate::paging::RmmA::invalidate_all();
+            crate::panic::symbol_trace(ip);
+            crate::panic::stack_trace();
         }
+        true
+    }
+
+    pub fn maybe_handle_tlb_shootdown(&self) {
+        let Some(shootdown) = self.tlb_mailbox.take() else {
+            return;
+        };
 
-        if let Some(ref addrsp) = &*self.current_addrsp.borrow() {
-            addrsp.tlb_ack.fetch_add(1, Ordering::Release);
+        // If the address space is no longer running here, its TLB entries are flushed when
+        // switching back to it, so there is nothing to do until then.
+        let is_current = self
+            .current_addrsp
+            .borrow()
+            .as_ref()
+            .is_some_and(|addrsp| core::ptr::eq(&addrsp.tlb_ack, shootdown.ack));
+
+        let local = self.tlb_generation.get();
+        if is_current && local < shootdown.generation {
+            unsafe {
+                if local + 1 == shootdown.generation {
+                    shootdown.ranges.invalidate();
+                } else {
+                    // Missed the ranges of an earlier shootdown.
+                    crate::context::memory::TlbRanges::invalidate_all();
+                }
+            }
+            self.tlb_generation.set(shootdown.generation);
         }
+
+        unsafe { (*shootdown.ack).fetch_add(1, Ordering::Release) };
     }
 }
 pub unsafe fn switch_arch_hook() {
@@ -139,7 +372,14 @@ pub unsafe fn switch_arch_hook() {
         let next = next_addrsp.acquire_read();
 
         next.used_by.atomic_set(percpu.cpu_id);
-        next.table.utable.make_current();
+        crate::memory::asid::switch_to(next_addrsp, &next);
+        if !retain_pgtbl {
+            crate::mitigations::switch_addr_space();
+        }
+        // Any TLB entries of the previous generations are flushed by now.
+        percpu
+            .tlb_generation
+            .set(next_addrsp.tlb_generation.load(Ordering::Relaxed));
     } else {
         crate::paging::RmmA::set_table(rmm::TableKind::User, empty_cr3());
     }
@@ -148,10 +388,27 @@ impl PercpuBlock {
     pub fn init(cpu_id: LogicalCpuId) -> Self {
         Self {
             cpu_id,
+            vars_offset: init_vars(),
             switch_internals: Default::default(),
             current_addrsp: RefCell::new(None),
             new_addrsp_tmp: Cell::new(None),
-            wants_tlb_shootdown: AtomicBool::new(false),
+            tlb_mailbox: TlbMailbox::new(),
+            tlb_generation: Cell::new(0),
+            asid_generation: Cell::new(0),
+            #[cfg(debug_assertions)]
+            wants_backtrace: AtomicBool::new(false),
+            #[cfg(debug_assertions)]
+            lockdep: crate::sync::lockdep::PercpuLockdep::new(),
+            rcu: PercpuRcu::new(),
+            free_batch: Mutex::new(FreeBatch::default()),
+            timers: Mutex::new(TimerWheel::new()),
+            workqueue: WorkQueue::new(),
+            irq_stats: IrqStats::new(),
+            interrupt_entropy: InterruptEntropy::new(),
+            idle: PercpuIdle::default(),
+            perf: PercpuPerf::default(),
+            cpufreq: PercpuCpufreq::default(),
+            mitigations_generation: Cell::new(0),
             ptrace_flags: Cell::new(Default::default()),
             ptrace_session: RefCell::new(None),
             inside_syscall: Cell::new(false),
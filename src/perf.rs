@@ -0,0 +1,180 @@
+//! Hardware performance counters, counting events such as cycles and retired instructions per CPU
+//! and per context.
+//!
+//! Counting is enabled through `sys:perf`, after which every CPU starts its counters on its next
+//! context switch, and on every switch adds what they counted to the previous context and to the
+//! totals of the CPU. The architecture specific `pmu` modules program the counters.
+//!
+//! On x86_64 with the `profiling` feature, another counter can raise the NMIs recording the
+//! samples of the profiler every given number of cycles, rather than the profiling CPU sending
+//! them periodically.
+
+use core::{
+    cell::Cell,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+};
+
+#[cfg(all(feature = "profiling", target_arch = "x86_64"))]
+use core::sync::atomic::AtomicU32;
+
+use crate::{
+    context::Context,
+    percpu::PercpuBlock,
+    pmu,
+    syscall::error::{Error, Result, EINVAL, ENODEV},
+};
+
+#[cfg(all(feature = "profiling", target_arch = "x86_64"))]
+use crate::syscall::error::EOPNOTSUPP;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum PerfEvent {
+    Cycles,
+    Instructions,
+    /// Last level cache misses where available, otherwise L1 data cache refills.
+    CacheMisses,
+}
+
+pub const EVENT_COUNT: usize = 3;
+
+impl PerfEvent {
+    pub const ALL: [Self; EVENT_COUNT] = [Self::Cycles, Self::Instructions, Self::CacheMisses];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Cycles => "cycles",
+            Self::Instructions => "instructions",
+            Self::CacheMisses => "cache_misses",
+        }
+    }
+}
+
+/// Number of times each event occurred, indexed by [`PerfEvent`].
+pub type PerfCounts = [u64; EVENT_COUNT];
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Cycles between two samples, or zero if the profiler is not sampling using a counter.
+#[cfg(all(feature = "profiling", target_arch = "x86_64"))]
+static SAMPLE_PERIOD: AtomicU32 = AtomicU32::new(0);
+
+/// The counter state of a CPU.
+#[derive(Default)]
+pub struct PercpuPerf {
+    /// Whether the counters of this CPU are counting.
+    running: Cell<bool>,
+    /// The period the sampling counter of this CPU raises NMIs with, or zero.
+    #[cfg(all(feature = "profiling", target_arch = "x86_64"))]
+    sample_period: Cell<u32>,
+    /// Events counted on this CPU, whichever context ran.
+    totals: [AtomicU64; EVENT_COUNT],
+}
+
+impl PercpuPerf {
+    pub fn totals(&self) -> PerfCounts {
+        self.totals
+            .each_ref()
+            .map(|total| total.load(Ordering::Relaxed))
+    }
+}
+
+/// The events the counters of this system can count.
+pub fn supported() -> impl Iterator<Item = PerfEvent> {
+    PerfEvent::ALL
+        .into_iter()
+        .filter(|&event| pmu::supported(event))
+}
+
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Called on every context switch, with the previous context still locked, to charge it what the
+/// counters of this CPU counted, and to start or stop them.
+pub fn switch(percpu: &PercpuBlock, prev: &mut Context) {
+    let state = &percpu.perf;
+    let enabled = ENABLED.load(Ordering::Relaxed);
+
+    if state.running.get() {
+        let mut counts = [0; EVENT_COUNT];
+        unsafe {
+            pmu::read_and_reset(&mut counts);
+        }
+        for ((total, context), count) in state.totals.iter().zip(&mut prev.perf_counts).zip(counts)
+        {
+            total.fetch_add(count, Ordering::Relaxed);
+            *context += count;
+        }
+        if !enabled {
+            unsafe {
+                pmu::stop();
+            }
+            state.running.set(false);
+        }
+    } else if enabled {
+        unsafe {
+            pmu::start();
+        }
+        state.running.set(true);
+    }
+
+    #[cfg(all(feature = "profiling", target_arch = "x86_64"))]
+    {
+        let period = SAMPLE_PERIOD.load(Ordering::Relaxed);
+        if period != state.sample_period.get() {
+            unsafe {
+                if period == 0 {
+                    pmu::stop_sampling();
+                } else {
+                    pmu::start_sampling(period);
+                }
+            }
+            state.sample_period.set(period);
+        }
+    }
+}
+
+/// Called by the NMI handler, to rearm the sampling counter if it raised the NMI.
+#[cfg(all(feature = "profiling", target_arch = "x86_64"))]
+pub fn handle_sample_nmi() {
+    let period = PercpuBlock::current().perf.sample_period.get();
+    if period != 0 {
+        unsafe {
+            pmu::handle_sample_overflow(period);
+        }
+    }
+}
+
+#[cfg(all(feature = "profiling", target_arch = "x86_64"))]
+pub fn sample_period() -> u32 {
+    SAMPLE_PERIOD.load(Ordering::Relaxed)
+}
+
+/// Handle a command written to `sys:perf`: `enable` or `disable` counting, or `sample <cycles>`
+/// to make the profiler sample every given number of cycles, or not using a counter if zero.
+pub fn command(command: &str) -> Result<()> {
+    match command.trim().split_once(' ') {
+        None if command.trim() == "enable" => {
+            if supported().next().is_none() {
+                return Err(Error::new(ENODEV));
+            }
+            ENABLED.store(true, Ordering::Relaxed);
+        }
+        None if command.trim() == "disable" => ENABLED.store(false, Ordering::Relaxed),
+        #[cfg(all(feature = "profiling", target_arch = "x86_64"))]
+        Some(("sample", period)) => {
+            let period = period
+                .trim()
+                .parse::<u32>()
+                .ok()
+                .filter(|&period| period < 1 << 31)
+                .ok_or(Error::new(EINVAL))?;
+            if period != 0 && !pmu::can_sample() {
+                return Err(Error::new(EOPNOTSUPP));
+            }
+            SAMPLE_PERIOD.store(period, Ordering::Relaxed);
+        }
+        _ => return Err(Error::new(EINVAL)),
+    }
+    Ok(())
+}
@@ -1,22 +1,42 @@
+//! Sampling profiler. A dedicated CPU periodically sends the other CPUs an NMI, which records the
+//! interrupted stack into the ring buffer of the CPU, to be read from `debug:profiling-<cpu>`.
+//!
+//! Each sample is a header word, holding the number of kernel frames in its upper 32 bits and the
+//! number of user frames in the lower ones, followed by the TSC and the frames, innermost first.
+//! Samples taken in user mode only have user frames, found by following the frame pointers of the
+//! user stack. `debug:profiling-folded-<cpu>` instead reads the samples as folded stacks, one line
+//! per sample with the kernel functions symbolized, as taken by flamegraph tools.
+
 use core::{
     cell::UnsafeCell,
+    fmt::Write,
     mem::size_of,
     sync::atomic::{AtomicBool, AtomicPtr, AtomicU32, AtomicUsize, Ordering},
 };
 
-use alloc::boxed::Box;
+use alloc::{boxed::Box, string::String};
 
 use crate::{
+    arch::consts::USER_END_OFFSET,
+    context::huge_page,
     cpu_set::LogicalCpuId,
     idt::Idt,
     interrupt,
     interrupt::{irq::aux_timer, InterruptStack},
+    ksyms,
+    memory::{TheFrameAllocator, PAGE_SIZE},
+    paging::{PageMapper, RmmA, RmmArch, TableKind, VirtualAddress},
     percpu::PercpuBlock,
     syscall::{error::*, usercopy::UserSliceWo},
 };
 
 const N: usize = 16 * 1024 * 1024;
 
+/// Frames recorded per sample, from either the kernel or the user stack.
+const MAX_FRAMES: usize = 32;
+/// The header and the TSC.
+const SAMPLE_HEADER_LEN: usize = 2;
+
 pub const HARDCODED_CPU_COUNT: u32 = 4;
 
 pub const PROFILER_CPU: LogicalCpuId = LogicalCpuId::new(HARDCODED_CPU_COUNT);
@@ -62,6 +82,16 @@ impl RingBuffer {
             [&self.buf[head..tail], &[]]
         }
     }
+    /// Append `slice` if it fits entirely, so that readers never see part of a sample.
+    pub unsafe fn push(&self, slice: &[usize]) -> bool {
+        let free: usize = self.sender_owned().iter().map(|words| words.len()).sum();
+        // A full buffer would look empty.
+        if free <= slice.len() {
+            return false;
+        }
+        self.extend(slice);
+        true
+    }
     pub unsafe fn extend(&self, mut slice: &[usize]) -> usize {
         let mut n = 0;
         for mut sender_slice in self.sender_owned() {
@@ -112,14 +142,17 @@ pub fn serio_command(index: usize, data: u8) {
     }
 }
 
+fn buffer(cpu_num: LogicalCpuId) -> Result<Option<&'static RingBuffer>> {
+    let buf = BUFS
+        .get(cpu_num.get() as usize)
+        .ok_or(Error::new(EBADFD))?
+        .load(Ordering::Relaxed);
+    Ok(unsafe { buf.as_ref() })
+}
+
 pub fn drain_buffer(cpu_num: LogicalCpuId, buf: UserSliceWo) -> Result<usize> {
     unsafe {
-        let Some(src) = BUFS
-            .get(cpu_num.get() as usize)
-            .ok_or(Error::new(EBADFD))?
-            .load(Ordering::Relaxed)
-            .as_ref()
-        else {
+        let Some(src) = buffer(cpu_num)? else {
             return Ok(0);
         };
         let byte_slices = src.peek().map(|words| {
@@ -143,6 +176,65 @@ pub fn drain_buffer(cpu_num: LogicalCpuId, buf: UserSliceWo) -> Result<usize> {
     }
 }
 
+/// Read the `i`th word of the readable part of a ring buffer.
+fn word(words: [&[usize]; 2], i: usize) -> Option<usize> {
+    match words[0].get(i) {
+        Some(&word) => Some(word),
+        None => words[1].get(i - words[0].len()).copied(),
+    }
+}
+
+/// Read the samples of a CPU as folded stacks, from the outermost frame to the innermost, each
+/// followed by a count of one. Only whole lines are read, and at least one must fit in `buf`.
+pub fn drain_folded(cpu_num: LogicalCpuId, buf: UserSliceWo) -> Result<usize> {
+    let Some(src) = buffer(cpu_num)? else {
+        return Ok(0);
+    };
+    let mut line = String::new();
+    let mut written = 0;
+
+    loop {
+        let words = unsafe { src.peek() };
+        let Some(header) = word(words, 0) else {
+            break;
+        };
+        let (kernel_len, user_len) = (header >> 32, header & 0xffff_ffff);
+        let user_start = SAMPLE_HEADER_LEN + kernel_len;
+        let len = user_start + user_len;
+
+        line.clear();
+        for i in (user_start..len).rev() {
+            let _ = write!(line, "{:#x};", word(words, i).unwrap_or(0));
+        }
+        for i in (SAMPLE_HEADER_LEN..user_start).rev() {
+            let pc = word(words, i).unwrap_or(0);
+            // All but the innermost frame are return addresses, which may be past the end of a
+            // function ending with a call.
+            let lookup_pc = if i == SAMPLE_HEADER_LEN { pc } else { pc - 1 };
+            let _ = match ksyms::lookup(lookup_pc) {
+                Some(symbol) => write!(line, "{:#};", symbol.demangled()),
+                None => write!(line, "{:#x};", pc),
+            };
+        }
+        line.pop();
+        line.push_str(" 1\n");
+
+        let Some(dst) = buf.advance(written).and_then(|dst| dst.limit(line.len())) else {
+            if written == 0 {
+                return Err(Error::new(EINVAL));
+            }
+            break;
+        };
+        dst.copy_from_slice(line.as_bytes())?;
+        written += line.len();
+        unsafe {
+            src.advance(len);
+        }
+    }
+
+    Ok(written)
+}
+
 pub unsafe fn nmi_handler(stack: &InterruptStack) {
     let Some(profiling) = crate::percpu::PercpuBlock::current().profiling else {
         return;
@@ -150,12 +242,15 @@ pub unsafe fn nmi_handler(stack: &InterruptStack) {
     if !IS_PROFILING.load(Ordering::Relaxed) {
         return;
     }
-    if stack.iret.cs & 0b00 == 0b11 {
+
+    let mut buf = [0_usize; SAMPLE_HEADER_LEN + MAX_FRAMES];
+    let frames = &mut buf[SAMPLE_HEADER_LEN..];
+    let (kernel_len, user_len) = if stack.iret.cs & 0b11 == 0b11 {
         profiling.nmi_ucount.store(
             profiling.nmi_ucount.load(Ordering::Relaxed) + 1,
             Ordering::Relaxed,
         );
-        return;
+        (0, user_frames(stack, frames))
     } else if stack.iret.rflags & (1 << 9) != 0 {
         // Interrupts were enabled, i.e. we were in kmain, so ignore.
         return;
@@ -164,17 +259,21 @@ pub unsafe fn nmi_handler(stack: &InterruptStack) {
             profiling.nmi_kcount.load(Ordering::Relaxed) + 1,
             Ordering::Relaxed,
         );
+        (kernel_frames(stack, frames), 0)
     };
 
-    let mut buf = [0_usize; 32];
-    buf[0] = stack.iret.rip & !(1 << 63);
+    buf[0] = kernel_len << 32 | user_len;
     buf[1] = x86::time::rdtsc() as usize;
+    let _ = profiling.push(&buf[..SAMPLE_HEADER_LEN + kernel_len + user_len]);
+}
 
+/// Follow the frame pointers of the interrupted kernel code, within the kernel stacks and text.
+unsafe fn kernel_frames(stack: &InterruptStack, frames: &mut [usize]) -> usize {
+    frames[0] = stack.iret.rip;
     let mut bp = stack.preserved.rbp;
+    let mut len = 1;
 
-    let mut len = 2;
-
-    for i in 2..32 {
+    while len < frames.len() {
         if bp < crate::PHYS_OFFSET || bp.saturating_add(16) >= crate::PHYS_OFFSET + crate::PML4_SIZE
         {
             break;
@@ -187,12 +286,43 @@ pub unsafe fn nmi_handler(stack: &InterruptStack) {
         {
             break;
         }
-        buf[i] = ip;
-
-        len = i + 1;
+        frames[len] = ip;
+        len += 1;
     }
+    len
+}
 
-    let _ = profiling.extend(&buf[..len]);
+/// Follow the frame pointers of the interrupted user code. Its stack is read through the current
+/// page tables, as page faults cannot be handled in an NMI.
+unsafe fn user_frames(stack: &InterruptStack, frames: &mut [usize]) -> usize {
+    let mapper = PageMapper::current(TableKind::User, TheFrameAllocator);
+    let read = |addr: usize| {
+        if addr % size_of::<usize>() != 0 || addr.checked_add(size_of::<usize>())? > USER_END_OFFSET
+        {
+            return None;
+        }
+        let phys = huge_page::translate(&mapper, VirtualAddress::new(addr & !(PAGE_SIZE - 1)))?;
+        let virt = RmmA::phys_to_virt(phys).data() + addr % PAGE_SIZE;
+        Some((virt as *const usize).read())
+    };
+
+    frames[0] = stack.iret.rip;
+    let mut bp = stack.preserved.rbp;
+    let mut len = 1;
+
+    while len < frames.len() {
+        let Some(ip) = read(bp.wrapping_add(8)).filter(|&ip| ip != 0) else {
+            break;
+        };
+        frames[len] = ip;
+        len += 1;
+        // Stacks grow downwards, so this also stops at loops.
+        match read(bp) {
+            Some(next) if next > bp => bp = next,
+            _ => break,
+        }
+    }
+    len
 }
 pub unsafe fn init() {
     let percpu = PercpuBlock::current();
@@ -3,18 +3,108 @@
 //! of the scheme.
 
 use crate::{
-    context::{self, process::ProcessId},
+    context::{
+        self,
+        caps::{self, Capabilities},
+        process::{self, Process, ProcessId},
+    },
     event,
     percpu::PercpuBlock,
     scheme::GlobalSchemes,
-    sync::WaitCondition,
+    sync::{Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard, WaitCondition},
     syscall::{data::PtraceEvent, error::*, flag::*, ptrace_event},
 };
 
 use alloc::{collections::VecDeque, sync::Arc};
-use core::cmp;
+use core::{
+    cmp, str,
+    sync::atomic::{AtomicU8, Ordering},
+};
 use hashbrown::hash_map::{Entry, HashMap};
-use spin::{Mutex, Once, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use spin::Once;
+
+//  ____
+// / ___|  ___ ___  _ __   ___
+// \___ \ / __/ _ \| '_ \ / _ \
+//  ___) | (_| (_) | |_) |  __/
+// |____/ \___\___/| .__/ \___|
+//                 |_|
+
+/// Which processes may trace others, in the style of the YAMA `ptrace_scope` sysctl, selected at
+/// boot using `PTRACE_SCOPE=<level>` in the environment.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Scope {
+    /// Any process of the same user or group may be traced.
+    Classic = 0,
+    /// Only descendants may be traced, unless the tracer has the `PTRACE` capability.
+    Restricted = 1,
+    /// Only contexts with the `PTRACE` capability may trace other processes.
+    Admin = 2,
+    /// No process may trace another, whatever its capabilities.
+    None = 3,
+}
+
+static SCOPE: AtomicU8 = AtomicU8::new(Scope::Restricted as u8);
+
+pub fn init_scope(env: &[u8]) {
+    for line in str::from_utf8(env).unwrap_or("").lines() {
+        let mut parts = line.splitn(2, '=');
+        let name = parts.next().unwrap_or("");
+        let value = parts.next().unwrap_or("");
+
+        if name == "PTRACE_SCOPE" {
+            match value.parse::<u8>() {
+                Ok(level @ 0..=3) => SCOPE.store(level, Ordering::Relaxed),
+                _ => log::warn!("Invalid ptrace scope {:?}", value),
+            }
+        }
+    }
+}
+
+pub fn scope() -> Scope {
+    match SCOPE.load(Ordering::Relaxed) {
+        0 => Scope::Classic,
+        1 => Scope::Restricted,
+        2 => Scope::Admin,
+        _ => Scope::None,
+    }
+}
+
+/// Check whether the process `tracer`, with the given credentials, may trace or otherwise access
+/// the internals of another process, `tracee`. Contexts with [`Capabilities::PTRACE`] may trace any.
+pub fn check_attach(tracer: &Process, tracee: &Process, uid: u32, gid: u32) -> Result<()> {
+    let privileged = caps::has(Capabilities::PTRACE);
+
+    match scope() {
+        Scope::None => return Err(Error::new(EPERM)),
+        Scope::Admin if !privileged => return Err(Error::new(EPERM)),
+        _ if privileged => return Ok(()),
+        _ => (),
+    }
+
+    // Do we own the process?
+    if uid != tracee.euid && gid != tracee.egid {
+        return Err(Error::new(EPERM));
+    }
+
+    if scope() == Scope::Restricted {
+        // Is it a subprocess of us? In the future, a capability could bypass this check.
+        match process::ancestors(&*process::PROCESSES.read(), tracee.ppid)
+            .find(|&(pid, _context)| pid == tracer.pid)
+        {
+            Some((id, context)) => {
+                // Paranoid sanity check, as ptrace security holes
+                // wouldn't be fun
+                assert_eq!(id, tracer.pid);
+                assert_eq!(id, context.read().pid);
+            }
+            None => return Err(Error::new(EPERM)),
+        }
+    }
+
+    Ok(())
+}
 
 //  ____                _
 // / ___|  ___  ___ ___(_) ___  _ __  ___
@@ -197,6 +287,11 @@ pub fn send_event(event: PtraceEvent) -> Option<()> {
 // |____/|_|  \___|\__,_|_|\_\ .__/ \___/|_|_| |_|\__|___/
 //                           |_|
 
+/// Stop when a hardware watchpoint set through `regs/debug` is hit. This uses a bit of
+/// `PTRACE_STOP_MASK` that has no constant in the syscall crate yet.
+pub const PTRACE_STOP_WATCHPOINT: PtraceFlags =
+    PtraceFlags::from_bits_retain(0x0000_0000_0000_0040);
+
 #[derive(Debug, Clone, Copy)]
 pub(crate) struct Breakpoint {
     reached: bool,
@@ -6,7 +6,7 @@ use core::{
 
 use alloc::{boxed::Box, collections::BTreeMap};
 
-use spin::{Mutex, Once, RwLock};
+use spin::Once;
 use syscall::{
     dirent::{DirEntry, DirentBuf, DirentKind},
     EIO,
@@ -16,7 +16,7 @@ use crate::{
     acpi::{RxsdtEnum, RXSDT_ENUM},
     context::file::InternalFlags,
     event,
-    sync::WaitCondition,
+    sync::{Mutex, RwLock, WaitCondition},
 };
 
 use crate::syscall::{
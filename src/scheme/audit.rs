@@ -0,0 +1,163 @@
+use alloc::{collections::BTreeMap, string::String};
+use core::{
+    mem::size_of,
+    str,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use crate::{
+    audit::{self, Record, Rule},
+    context::{file::InternalFlags, process::ProcessId},
+    event,
+    sync::RwLock,
+    syscall::{
+        error::*,
+        flag::{EventFlags, EVENT_READ, O_NONBLOCK},
+        usercopy::{UserSliceRo, UserSliceWo},
+    },
+};
+
+use super::{CallerCtx, GlobalSchemes, KernelScheme, OpenResult};
+
+#[derive(Clone, Copy)]
+enum Handle {
+    Rules,
+    /// The log, opened by the process `pid`, whose syscalls are not audited.
+    Log {
+        pid: ProcessId,
+    },
+}
+
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+// Using BTreeMap as hashbrown doesn't have a const constructor.
+static HANDLES: RwLock<BTreeMap<usize, Handle>> = RwLock::new(BTreeMap::new());
+
+/// `audit:` - syscall auditing
+///
+/// Writing `add <rule>` or `del <rule>` to `audit:rules` installs or removes a rule, like
+/// `add syscall=5 result=err`, and `clear` removes all of them. Reading it lists the rules, one per
+/// line. Reads of `audit:log` return whole [`Record`]s of the audited syscalls, blocking until
+/// there is one unless the handle is non-blocking.
+pub struct AuditScheme;
+
+fn handle(id: usize) -> Result<Handle> {
+    HANDLES.read().get(&id).copied().ok_or(Error::new(EBADF))
+}
+
+/// Whether `pid` has the log open.
+pub fn is_reader(pid: ProcessId) -> bool {
+    HANDLES
+        .read()
+        .values()
+        .any(|handle| matches!(handle, Handle::Log { pid: reader } if *reader == pid))
+}
+
+/// Notify the log handles, when a record is added.
+pub fn trigger_events() {
+    for (&id, handle) in HANDLES.read().iter() {
+        if let Handle::Log { .. } = handle {
+            event::trigger(GlobalSchemes::Audit.scheme_id(), id, EVENT_READ);
+        }
+    }
+}
+
+impl KernelScheme for AuditScheme {
+    fn kopen(&self, path: &str, _flags: usize, ctx: CallerCtx) -> Result<OpenResult> {
+        if ctx.uid != 0 {
+            return Err(Error::new(EPERM));
+        }
+
+        let handle = match path.trim_matches('/') {
+            "rules" => Handle::Rules,
+            "log" => Handle::Log {
+                pid: ProcessId::new(ctx.pid),
+            },
+            _ => return Err(Error::new(ENOENT)),
+        };
+
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        HANDLES.write().insert(id, handle);
+
+        Ok(OpenResult::SchemeLocal(id, InternalFlags::empty()))
+    }
+
+    fn fcntl(&self, _id: usize, _cmd: usize, _arg: usize) -> Result<usize> {
+        Ok(0)
+    }
+
+    fn fevent(&self, id: usize, _flags: EventFlags) -> Result<EventFlags> {
+        Ok(match handle(id)? {
+            Handle::Log { .. } if audit::has_records() => EVENT_READ,
+            _ => EventFlags::empty(),
+        })
+    }
+
+    fn close(&self, id: usize) -> Result<()> {
+        HANDLES
+            .write()
+            .remove(&id)
+            .ok_or(Error::new(EBADF))
+            .and(Ok(()))
+    }
+
+    fn kread(&self, id: usize, buf: UserSliceWo, flags: u32, _stored_flags: u32) -> Result<usize> {
+        match handle(id)? {
+            Handle::Rules => {
+                let mut text = String::new();
+                for rule in audit::rules() {
+                    text += &format!("{}\n", rule);
+                }
+                buf.copy_common_bytes_from_slice(text.as_bytes())
+            }
+            Handle::Log { .. } => {
+                let block = flags & O_NONBLOCK as u32 == 0;
+                let records = audit::read(buf.len() / size_of::<Record>(), block)?;
+                if records.is_empty() && block {
+                    return Ok(0);
+                } else if records.is_empty() {
+                    return Err(Error::new(EAGAIN));
+                }
+                let bytes = unsafe {
+                    core::slice::from_raw_parts(
+                        records.as_ptr().cast::<u8>(),
+                        records.len() * size_of::<Record>(),
+                    )
+                };
+                buf.copy_common_bytes_from_slice(bytes)
+            }
+        }
+    }
+
+    fn kwrite(
+        &self,
+        id: usize,
+        buf: UserSliceRo,
+        _flags: u32,
+        _stored_flags: u32,
+    ) -> Result<usize> {
+        if let Handle::Log { .. } = handle(id)? {
+            return Err(Error::new(EBADF));
+        }
+
+        let mut tmp = [0_u8; 128];
+        let byte_count = buf.copy_common_bytes_to_slice(&mut tmp)?;
+        let text = str::from_utf8(&tmp[..byte_count]).map_err(|_| Error::new(EINVAL))?;
+        let (command, rule) = text.trim().split_once(' ').unwrap_or((text.trim(), ""));
+        match command {
+            "add" => audit::add_rule(Rule::parse(rule)?)?,
+            "del" => audit::remove_rule(Rule::parse(rule)?)?,
+            "clear" => audit::clear_rules(),
+            _ => return Err(Error::new(EINVAL)),
+        }
+
+        Ok(byte_count)
+    }
+
+    fn kfpath(&self, id: usize, buf: UserSliceWo) -> Result<usize> {
+        let path = match handle(id)? {
+            Handle::Rules => "audit:rules",
+            Handle::Log { .. } => "audit:log",
+        };
+        buf.copy_common_bytes_from_slice(path.as_bytes())
+    }
+}
@@ -1,12 +1,11 @@
 use core::sync::atomic::{AtomicUsize, Ordering};
-use spin::RwLock;
 
 use crate::{
     arch::debug::Writer,
     devices::graphical_debug,
     event,
     scheme::*,
-    sync::WaitQueue,
+    sync::{RwLock, WaitQueue},
     syscall::{
         flag::{EventFlags, EVENT_READ, O_NONBLOCK},
         usercopy::{UserSliceRo, UserSliceWo},
@@ -21,6 +20,9 @@ static INPUT: WaitQueue<u8> = WaitQueue::new();
 #[derive(Clone, Copy)]
 struct Handle {
     num: usize,
+    /// Read the profiling samples as folded stacks.
+    #[cfg(feature = "profiling")]
+    folded: bool,
 }
 
 // Using BTreeMap as hashbrown doesn't have a const constructor.
@@ -28,6 +30,9 @@ static HANDLES: RwLock<BTreeMap<usize, Handle>> = RwLock::new(BTreeMap::new());
 
 /// Add to the input queue
 pub fn debug_input(data: u8) {
+    #[cfg(feature = "debugger")]
+    crate::debugger::serial_input(data, |c| INPUT.send(c));
+    #[cfg(not(feature = "debugger"))]
     INPUT.send(data);
 }
 
@@ -48,6 +53,9 @@ enum SpecialFds {
 
     #[cfg(feature = "profiling")]
     CtlProfiling = !0 - 3,
+
+    #[cfg(all(feature = "debugger", any(target_arch = "x86", target_arch = "x86_64")))]
+    CtlWatchpoint = !0 - 4,
 }
 
 impl KernelScheme for DebugScheme {
@@ -56,6 +64,9 @@ impl KernelScheme for DebugScheme {
             return Err(Error::new(EPERM));
         }
 
+        #[cfg(feature = "profiling")]
+        let folded = path.starts_with("profiling-folded-");
+
         let num = match path {
             "" => SpecialFds::Default as usize,
 
@@ -70,17 +81,37 @@ impl KernelScheme for DebugScheme {
 
             #[cfg(feature = "profiling")]
             p if p.starts_with("profiling-") => {
-                path[10..].parse().map_err(|_| Error::new(ENOENT))?
+                crate::lockdown::check(crate::lockdown::Reason::KernelProfiling)?;
+                p.strip_prefix("profiling-folded-")
+                    .unwrap_or(&p[10..])
+                    .parse()
+                    .map_err(|_| Error::new(ENOENT))?
             }
 
             #[cfg(feature = "profiling")]
-            "ctl-profiling" => SpecialFds::CtlProfiling as usize,
+            "ctl-profiling" => {
+                crate::lockdown::check(crate::lockdown::Reason::KernelProfiling)?;
+                SpecialFds::CtlProfiling as usize
+            }
+
+            #[cfg(all(feature = "debugger", any(target_arch = "x86", target_arch = "x86_64")))]
+            "ctl-watchpoint" => {
+                crate::lockdown::check(crate::lockdown::Reason::KernelDebugger)?;
+                SpecialFds::CtlWatchpoint as usize
+            }
 
             _ => return Err(Error::new(ENOENT)),
         };
 
         let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
-        HANDLES.write().insert(id, Handle { num });
+        HANDLES.write().insert(
+            id,
+            Handle {
+                num,
+                #[cfg(feature = "profiling")]
+                folded,
+            },
+        );
 
         Ok(OpenResult::SchemeLocal(id, InternalFlags::empty()))
     }
@@ -126,12 +157,19 @@ impl KernelScheme for DebugScheme {
             return Err(Error::new(EBADF));
         }
 
+        #[cfg(all(feature = "debugger", any(target_arch = "x86", target_arch = "x86_64")))]
+        if handle.num == SpecialFds::CtlWatchpoint as usize {
+            return Err(Error::new(EBADF));
+        }
+
         #[cfg(feature = "profiling")]
         if handle.num != SpecialFds::Default as usize {
-            return crate::profiling::drain_buffer(
-                crate::cpu_set::LogicalCpuId::new(handle.num as u32),
-                buf,
-            );
+            let cpu = crate::cpu_set::LogicalCpuId::new(handle.num as u32);
+            return if handle.folded {
+                crate::profiling::drain_folded(cpu, buf)
+            } else {
+                crate::profiling::drain_buffer(cpu, buf)
+            };
         }
 
         INPUT.receive_into_user(buf, flags & O_NONBLOCK as u32 == 0, "DebugScheme::read")
@@ -165,6 +203,11 @@ impl KernelScheme for DebugScheme {
             return Ok(1);
         }
 
+        #[cfg(all(feature = "debugger", any(target_arch = "x86", target_arch = "x86_64")))]
+        if handle.num == SpecialFds::CtlWatchpoint as usize {
+            return ctl_watchpoint(buf);
+        }
+
         if handle.num != SpecialFds::Default as usize
             && handle.num != SpecialFds::NoPreserve as usize
         {
@@ -206,3 +249,41 @@ impl KernelScheme for DebugScheme {
         Ok(byte_count)
     }
 }
+
+/// Handle a command written to `debug:ctl-watchpoint`. Accepted commands are
+/// `write <hex address> <length>`, `readwrite <hex address> <length>`, and `clear <slot>`.
+#[cfg(all(feature = "debugger", any(target_arch = "x86", target_arch = "x86_64")))]
+fn ctl_watchpoint(buf: UserSliceRo) -> Result<usize> {
+    use crate::arch::watchpoint::{self, WatchKind};
+
+    let mut tmp = [0_u8; 64];
+    let byte_count = buf.copy_common_bytes_to_slice(&mut tmp)?;
+    let cmd = core::str::from_utf8(&tmp[..byte_count]).map_err(|_| Error::new(EINVAL))?;
+    let mut words = cmd.split_whitespace();
+
+    let parse_addr = |word: Option<&str>| {
+        word.and_then(|w| usize::from_str_radix(w.trim_start_matches("0x"), 16).ok())
+            .ok_or(Error::new(EINVAL))
+    };
+    let parse_dec = |word: Option<&str>| {
+        word.and_then(|w| w.parse::<usize>().ok())
+            .ok_or(Error::new(EINVAL))
+    };
+
+    match words.next() {
+        Some(kind @ ("write" | "readwrite")) => {
+            let kind = if kind == "write" {
+                WatchKind::Write
+            } else {
+                WatchKind::ReadWrite
+            };
+            let addr = parse_addr(words.next())?;
+            let len = parse_dec(words.next())?;
+            watchpoint::arm(addr, len, kind)?;
+        }
+        Some("clear") => watchpoint::disarm(parse_dec(words.next())?)?,
+        _ => return Err(Error::new(EINVAL)),
+    }
+
+    Ok(byte_count)
+}
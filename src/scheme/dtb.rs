@@ -1,12 +1,13 @@
 use core::sync::atomic::{self, AtomicUsize};
 
 use alloc::{boxed::Box, collections::BTreeMap};
-use spin::{Once, RwLock};
+use spin::Once;
 
 use super::{CallerCtx, KernelScheme, OpenResult};
 use crate::{
     dtb::DTB_BINARY,
     scheme::InternalFlags,
+    sync::RwLock,
     syscall::{
         data::Stat,
         error::*,
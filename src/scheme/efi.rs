@@ -0,0 +1,276 @@
+use core::sync::atomic::{self, AtomicUsize};
+
+use alloc::{collections::BTreeMap, string::String, vec::Vec};
+
+use syscall::{
+    dirent::{DirEntry, DirentBuf, DirentKind},
+    EIO,
+};
+
+use crate::{
+    context::file::InternalFlags,
+    efi::{self, Guid},
+    sync::RwLock,
+};
+
+use crate::syscall::{
+    data::Stat,
+    error::{Error, Result, EACCES, EBADF, EINVAL, EISDIR, ENODEV, ENOENT, ENOTDIR},
+    flag::{EventFlags, MODE_CHR, MODE_DIR, MODE_FILE, O_ACCMODE, O_CREAT, O_DIRECTORY, O_RDONLY, O_STAT},
+    usercopy::{UserSliceRo, UserSliceWo},
+};
+
+use super::{CallerCtx, KernelScheme, OpenResult};
+
+/// A scheme exposing EFI runtime services, needed for e.g. boot order management.
+///
+/// Variables are accessed as `vars/<name>-<guid>`, in the efivarfs format: the 32-bit attributes
+/// followed by the data. A variable is written at once, and writing no data deletes it. Writing
+/// `cold`, `warm` or `shutdown` to `reset` resets the system using the firmware.
+pub struct EfiScheme;
+
+#[derive(Clone)]
+enum HandleKind {
+    TopLevel,
+    Vars,
+    Var { name: Vec<u16>, guid: Guid },
+    Reset,
+}
+
+static HANDLES: RwLock<BTreeMap<usize, HandleKind>> = RwLock::new(BTreeMap::new());
+static NEXT_FD: AtomicUsize = AtomicUsize::new(0);
+
+/// Split `<name>-<guid>` into the UTF-16 name and the GUID.
+fn parse_var(path: &str) -> Option<(Vec<u16>, Guid)> {
+    const GUID_LEN: usize = 36;
+
+    let name_len = path.len().checked_sub(GUID_LEN + 1)?;
+    let (name, guid) = path.split_at_checked(name_len)?;
+    let guid = Guid::parse(guid.strip_prefix('-')?)?;
+    if name.is_empty() {
+        return None;
+    }
+    Some((name.encode_utf16().collect(), guid))
+}
+
+fn var_path(name: &[u16], guid: &Guid) -> String {
+    format!("{}-{}", String::from_utf16_lossy(name), guid)
+}
+
+impl KernelScheme for EfiScheme {
+    fn kopen(&self, path: &str, flags: usize, ctx: CallerCtx) -> Result<OpenResult> {
+        let path = path.trim_start_matches('/');
+
+        if ctx.uid != 0 {
+            return Err(Error::new(EACCES));
+        }
+        if !efi::available() {
+            return Err(Error::new(ENODEV));
+        }
+
+        let is_dir = |kind: &HandleKind| matches!(kind, HandleKind::TopLevel | HandleKind::Vars);
+        let kind = match path {
+            "" => HandleKind::TopLevel,
+            "vars" => HandleKind::Vars,
+            "reset" => HandleKind::Reset,
+            _ => {
+                let (name, guid) = path
+                    .strip_prefix("vars/")
+                    .and_then(parse_var)
+                    .ok_or(Error::new(ENOENT))?;
+                if flags & O_CREAT != O_CREAT {
+                    efi::read_variable(&name, &guid)?;
+                }
+                HandleKind::Var { name, guid }
+            }
+        };
+
+        if flags & O_STAT != O_STAT {
+            if is_dir(&kind) {
+                if flags & O_DIRECTORY != O_DIRECTORY {
+                    return Err(Error::new(EISDIR));
+                }
+                if flags & O_ACCMODE != O_RDONLY {
+                    return Err(Error::new(EACCES));
+                }
+            } else if flags & O_DIRECTORY == O_DIRECTORY {
+                return Err(Error::new(ENOTDIR));
+            }
+        }
+
+        let fd = NEXT_FD.fetch_add(1, atomic::Ordering::Relaxed);
+        HANDLES.write().insert(fd, kind);
+
+        Ok(OpenResult::SchemeLocal(fd, InternalFlags::POSITIONED))
+    }
+    fn fsize(&self, id: usize) -> Result<u64> {
+        let kind = HANDLES.read().get(&id).ok_or(Error::new(EBADF))?.clone();
+
+        Ok(match kind {
+            HandleKind::Var { name, guid } => {
+                let (_, data) = efi::read_variable(&name, &guid)?;
+                4 + data.len() as u64
+            }
+            _ => 0,
+        })
+    }
+    fn fevent(&self, id: usize, _flags: EventFlags) -> Result<EventFlags> {
+        if !HANDLES.read().contains_key(&id) {
+            return Err(Error::new(EBADF));
+        }
+        Ok(EventFlags::empty())
+    }
+    fn close(&self, id: usize) -> Result<()> {
+        if HANDLES.write().remove(&id).is_none() {
+            return Err(Error::new(EBADF));
+        }
+        Ok(())
+    }
+    fn kreadoff(
+        &self,
+        id: usize,
+        dst_buf: UserSliceWo,
+        offset: u64,
+        _flags: u32,
+        _stored_flags: u32,
+    ) -> Result<usize> {
+        let kind = HANDLES.read().get(&id).ok_or(Error::new(EBADF))?.clone();
+
+        let HandleKind::Var { name, guid } = kind else {
+            return Err(Error::new(match kind {
+                HandleKind::Reset => EINVAL,
+                _ => EISDIR,
+            }));
+        };
+        let Ok(offset) = usize::try_from(offset) else {
+            return Ok(0);
+        };
+
+        let (attributes, data) = efi::read_variable(&name, &guid)?;
+        let mut contents = Vec::with_capacity(4 + data.len());
+        contents.extend_from_slice(&attributes.to_ne_bytes());
+        contents.extend_from_slice(&data);
+
+        let src_buf = contents.get(offset..).unwrap_or(&[]);
+        dst_buf.copy_common_bytes_from_slice(src_buf)
+    }
+    fn kwriteoff(
+        &self,
+        id: usize,
+        buf: UserSliceRo,
+        offset: u64,
+        _flags: u32,
+        _stored_flags: u32,
+    ) -> Result<usize> {
+        let kind = HANDLES.read().get(&id).ok_or(Error::new(EBADF))?.clone();
+
+        match kind {
+            HandleKind::Var { name, guid } => {
+                // Variables are written atomically by the firmware, so partial writes cannot be
+                // supported.
+                if offset != 0 || buf.len() < 4 {
+                    return Err(Error::new(EINVAL));
+                }
+                let mut contents = Vec::new();
+                contents.resize(buf.len(), 0);
+                buf.copy_to_slice(&mut contents)?;
+
+                let (attributes, data) = contents.split_at(4);
+                let attributes = u32::from_ne_bytes(attributes.try_into().unwrap());
+                efi::set_variable(&name, &guid, attributes, data)?;
+                Ok(contents.len())
+            }
+            HandleKind::Reset => {
+                let mut command = [0_u8; 16];
+                let len = buf.copy_common_bytes_to_slice(&mut command)?;
+                let kind = match core::str::from_utf8(&command[..len]).map(str::trim) {
+                    Ok("cold") => efi::RESET_COLD,
+                    Ok("warm") => efi::RESET_WARM,
+                    Ok("shutdown") => efi::RESET_SHUTDOWN,
+                    _ => return Err(Error::new(EINVAL)),
+                };
+                ::log::logger().flush();
+                efi::reset_system(kind)?;
+                Ok(len)
+            }
+            HandleKind::TopLevel | HandleKind::Vars => Err(Error::new(EISDIR)),
+        }
+    }
+    fn getdents(
+        &self,
+        id: usize,
+        buf: UserSliceWo,
+        header_size: u16,
+        opaque: u64,
+    ) -> Result<usize> {
+        let kind = HANDLES.read().get(&id).ok_or(Error::new(EBADF))?.clone();
+
+        let mut buf = DirentBuf::new(buf, header_size).ok_or(Error::new(EIO))?;
+        match kind {
+            HandleKind::TopLevel => {
+                if opaque == 0 {
+                    buf.entry(DirEntry {
+                        kind: DirentKind::Directory,
+                        name: "vars",
+                        inode: 0,
+                        next_opaque_id: 1,
+                    })?;
+                }
+                if opaque <= 1 {
+                    buf.entry(DirEntry {
+                        kind: DirentKind::CharDev,
+                        name: "reset",
+                        inode: 0,
+                        next_opaque_id: u64::MAX,
+                    })?;
+                }
+            }
+            HandleKind::Vars => {
+                let names = efi::variable_names()?;
+                for (i, (name, guid)) in names.iter().enumerate().skip(opaque as usize) {
+                    buf.entry(DirEntry {
+                        kind: DirentKind::Regular,
+                        name: &var_path(name, guid),
+                        inode: 0,
+                        next_opaque_id: i as u64 + 1,
+                    })?;
+                }
+            }
+            _ => return Err(Error::new(ENOTDIR)),
+        }
+        Ok(buf.finalize())
+    }
+    fn kfpath(&self, id: usize, buf: UserSliceWo) -> Result<usize> {
+        let kind = HANDLES.read().get(&id).ok_or(Error::new(EBADF))?.clone();
+
+        let path = match kind {
+            HandleKind::TopLevel => String::from("kernel.efi:"),
+            HandleKind::Vars => String::from("kernel.efi:vars"),
+            HandleKind::Var { name, guid } => format!("kernel.efi:vars/{}", var_path(&name, &guid)),
+            HandleKind::Reset => String::from("kernel.efi:reset"),
+        };
+        buf.copy_common_bytes_from_slice(path.as_bytes())
+    }
+    fn kfstat(&self, id: usize, buf: UserSliceWo) -> Result<()> {
+        let kind = HANDLES.read().get(&id).ok_or(Error::new(EBADF))?.clone();
+
+        let stat = match kind {
+            HandleKind::TopLevel | HandleKind::Vars => Stat {
+                st_mode: MODE_DIR | 0o700,
+                ..Default::default()
+            },
+            HandleKind::Var { .. } => Stat {
+                st_mode: MODE_FILE | 0o600,
+                st_size: self.fsize(id).unwrap_or(0),
+                ..Default::default()
+            },
+            HandleKind::Reset => Stat {
+                st_mode: MODE_CHR | 0o200,
+                ..Default::default()
+            },
+        };
+        buf.copy_exactly(&stat)?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,114 @@
+//! Random bytes from the entropy pool, see [`crate::entropy`].
+//!
+//! Reading any handle of `getrandom:` fills the buffer, like `getrandom` with no flags. The pool is
+//! seeded before userspace starts, so reads never block. Writing mixes the data into the pool, which
+//! is only allowed for root.
+
+use alloc::collections::BTreeMap;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::{
+    context::file::InternalFlags,
+    entropy,
+    sync::RwLock,
+    syscall::{
+        data::Stat,
+        error::*,
+        flag::{EventFlags, MODE_CHR, O_ACCMODE, O_RDONLY},
+        usercopy::{UserSliceRo, UserSliceWo},
+    },
+};
+
+use super::{CallerCtx, KernelScheme, OpenResult};
+
+/// Bytes generated and copied at once.
+const CHUNK_SIZE: usize = 256;
+
+pub struct GetrandomScheme;
+
+static NEXT_ID: AtomicUsize = AtomicUsize::new(1);
+/// Whether each handle may be written to.
+static HANDLES: RwLock<BTreeMap<usize, bool>> = RwLock::new(BTreeMap::new());
+
+impl KernelScheme for GetrandomScheme {
+    fn kopen(&self, path: &str, flags: usize, ctx: CallerCtx) -> Result<OpenResult> {
+        if !path.trim_matches('/').is_empty() {
+            return Err(Error::new(ENOENT));
+        }
+        let writable = flags & O_ACCMODE != O_RDONLY;
+        if writable && ctx.uid != 0 {
+            return Err(Error::new(EACCES));
+        }
+
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        HANDLES.write().insert(id, writable);
+
+        Ok(OpenResult::SchemeLocal(id, InternalFlags::empty()))
+    }
+
+    fn fevent(&self, id: usize, _flags: EventFlags) -> Result<EventFlags> {
+        HANDLES
+            .read()
+            .get(&id)
+            .ok_or(Error::new(EBADF))
+            .and(Ok(EventFlags::empty()))
+    }
+
+    fn close(&self, id: usize) -> Result<()> {
+        HANDLES
+            .write()
+            .remove(&id)
+            .ok_or(Error::new(EBADF))
+            .and(Ok(()))
+    }
+
+    fn kread(&self, id: usize, buf: UserSliceWo, _flags: u32, _stored_flags: u32) -> Result<usize> {
+        if !HANDLES.read().contains_key(&id) {
+            return Err(Error::new(EBADF));
+        }
+
+        let mut bytes = [0; CHUNK_SIZE];
+        for chunk in buf.in_variable_chunks(CHUNK_SIZE) {
+            entropy::fill(&mut bytes);
+            chunk.copy_common_bytes_from_slice(&bytes)?;
+        }
+        bytes.fill(0);
+        Ok(buf.len())
+    }
+
+    fn kwrite(
+        &self,
+        id: usize,
+        buf: UserSliceRo,
+        _flags: u32,
+        _stored_flags: u32,
+    ) -> Result<usize> {
+        if !*HANDLES.read().get(&id).ok_or(Error::new(EBADF))? {
+            return Err(Error::new(EBADF));
+        }
+
+        let mut bytes = [0; CHUNK_SIZE];
+        for chunk in buf.in_variable_chunks(CHUNK_SIZE) {
+            let byte_count = chunk.copy_common_bytes_to_slice(&mut bytes)?;
+            entropy::add(&bytes[..byte_count]);
+        }
+        Ok(buf.len())
+    }
+
+    fn kfpath(&self, id: usize, buf: UserSliceWo) -> Result<usize> {
+        if !HANDLES.read().contains_key(&id) {
+            return Err(Error::new(EBADF));
+        }
+        buf.copy_common_bytes_from_slice(b"getrandom:")
+    }
+
+    fn kfstat(&self, id: usize, buf: UserSliceWo) -> Result<()> {
+        if !HANDLES.read().contains_key(&id) {
+            return Err(Error::new(EBADF));
+        }
+        buf.copy_exactly(&Stat {
+            st_mode: MODE_CHR | 0o644,
+            ..Default::default()
+        })
+    }
+}
@@ -7,21 +7,25 @@ use core::{
     sync::atomic::{AtomicUsize, Ordering},
 };
 
-use alloc::{collections::BTreeMap, string::String, vec::Vec};
+use alloc::{borrow::Cow, collections::BTreeMap, string::String, vec::Vec};
 
-use spin::{Mutex, Once, RwLock};
+use spin::Once;
 use syscall::dirent::{DirEntry, DirentBuf, DirentKind};
 
 use crate::context::file::InternalFlags;
 
 use super::{CallerCtx, GlobalSchemes, OpenResult};
+#[cfg(target_arch = "aarch64")]
+use crate::arch::device::irqchip;
 #[cfg(any(target_arch = "x86_64", target_arch = "x86"))]
 use crate::arch::interrupt::{available_irqs_iter, irq::acknowledge, is_reserved, set_reserved};
 #[cfg(any(target_arch = "aarch64", target_arch = "riscv64"))]
 use crate::dtb::irqchip::{acknowledge, available_irqs_iter, is_reserved, set_reserved, IRQ_CHIP};
 use crate::{
+    context::caps::{self, Capabilities},
     cpu_set::LogicalCpuId,
     event,
+    sync::{Mutex, RwLock},
     syscall::{
         data::Stat,
         error::*,
@@ -52,10 +56,12 @@ const INO_TOPLEVEL: u64 = 0x8002_0000_0000_0000;
 const INO_AVAIL: u64 = 0x8000_0000_0000_0000;
 const INO_BSP: u64 = 0x8001_0000_0000_0000;
 const INO_PHANDLE: u64 = 0x8003_0000_0000_0000;
+const INO_MSI: u64 = 0x8004_0000_0000_0000;
 
 /// Add to the input queue
 #[no_mangle]
 pub extern "C" fn irq_trigger(irq: u8) {
+    crate::trace::record(crate::trace::Event::Irq, [irq.into(), 0, 0]);
     COUNTS.lock()[irq as usize] += 1;
 
     for (fd, _) in HANDLES
@@ -70,7 +76,21 @@ pub extern "C" fn irq_trigger(irq: u8) {
 
 #[allow(dead_code)]
 enum Handle {
-    Irq { ack: AtomicUsize, irq: u8 },
+    Irq {
+        ack: AtomicUsize,
+        irq: u8,
+        /// The name of the context that opened the handle.
+        owner: Cow<'static, str>,
+    },
+    /// An IRQ allocated for MSI, by opening `irq:msi` or `irq:msi-<hex device ID>` with O_CREAT.
+    /// The path of the handle contains the message address and data to program into the device.
+    Msi {
+        ack: AtomicUsize,
+        irq: u8,
+        owner: Cow<'static, str>,
+        address: u64,
+        data: u32,
+    },
     Avail(LogicalCpuId),
     TopLevel,
     Phandle(u8, Vec<u8>),
@@ -79,12 +99,37 @@ enum Handle {
 impl Handle {
     fn as_irq_handle<'a>(&'a self) -> Option<(&'a AtomicUsize, u8)> {
         match self {
-            &Self::Irq { ref ack, irq } => Some((ack, irq)),
+            &Self::Irq { ref ack, irq } | &Self::Msi { ref ack, irq, .. } => Some((ack, irq)),
             _ => None,
         }
     }
 }
 
+/// The names of the contexts that have `irq` open.
+pub fn owners(irq: u8) -> Vec<Cow<'static, str>> {
+    HANDLES
+        .read()
+        .values()
+        .filter_map(|handle| match handle {
+            Handle::Irq {
+                irq: handle_irq,
+                owner,
+                ..
+            }
+            | Handle::Msi {
+                irq: handle_irq,
+                owner,
+                ..
+            } if *handle_irq == irq => Some(owner.clone()),
+            _ => None,
+        })
+        .collect()
+}
+
+fn owner() -> Cow<'static, str> {
+    crate::context::current().read().name.clone()
+}
+
 static NEXT_FD: AtomicUsize = AtomicUsize::new(1);
 static CPUS: Once<Vec<u8>> = Once::new();
 
@@ -92,24 +137,11 @@ pub struct IrqScheme;
 
 impl IrqScheme {
     pub fn init() {
+        // The CPUs started from the MADT, including those only described by x2APIC entries.
         #[cfg(all(feature = "acpi", any(target_arch = "x86", target_arch = "x86_64")))]
-        let cpus = {
-            use crate::acpi::madt::*;
-
-            match unsafe { MADT.as_ref() } {
-                Some(madt) => madt
-                    .iter()
-                    .filter_map(|entry| match entry {
-                        MadtEntry::LocalApic(apic) => Some(apic.processor),
-                        _ => None,
-                    })
-                    .collect::<Vec<_>>(),
-                None => {
-                    log::warn!("no MADT found, defaulting to 1 CPU");
-                    vec![0]
-                }
-            }
-        };
+        let cpus = (0..crate::cpu_count())
+            .filter_map(|id| u8::try_from(id).ok())
+            .collect::<Vec<_>>();
         #[cfg(not(all(feature = "acpi", any(target_arch = "x86", target_arch = "x86_64"))))]
         let cpus = vec![0];
 
@@ -132,6 +164,7 @@ impl IrqScheme {
                     Handle::Irq {
                         ack: AtomicUsize::new(0),
                         irq: irq_number,
+                        owner: owner(),
                     },
                     InternalFlags::empty(),
                 )
@@ -150,6 +183,7 @@ impl IrqScheme {
                     Handle::Irq {
                         ack: AtomicUsize::new(0),
                         irq: irq_number,
+                        owner: owner(),
                     },
                     InternalFlags::empty(),
                 )
@@ -196,6 +230,7 @@ impl IrqScheme {
                 Handle::Irq {
                     ack: AtomicUsize::new(0),
                     irq: irq_number as u8,
+                    owner: owner(),
                 },
                 InternalFlags::empty(),
             )
@@ -203,6 +238,33 @@ impl IrqScheme {
     }
 }
 
+#[cfg(target_arch = "aarch64")]
+fn open_msi(device_id: u32, flags: usize) -> Result<(Handle, InternalFlags)> {
+    if flags & O_CREAT == 0 {
+        return Err(Error::new(EINVAL));
+    }
+    let message = irqchip::allocate_msi(device_id).ok_or(Error::new(ENOSPC))?;
+
+    // IRQ queues are only tracked for the lower IRQ numbers.
+    let Some(irq) = u8::try_from(message.virq)
+        .ok()
+        .filter(|irq| *irq < TOTAL_IRQ_COUNT)
+    else {
+        irqchip::free_msi(message.virq);
+        return Err(Error::new(ENOSPC));
+    };
+    Ok((
+        Handle::Msi {
+            ack: AtomicUsize::new(0),
+            irq,
+            owner: owner(),
+            address: message.address,
+            data: message.data,
+        },
+        InternalFlags::empty(),
+    ))
+}
+
 const fn irq_to_vector(irq: u8) -> u8 {
     irq + 32
 }
@@ -211,8 +273,8 @@ const fn vector_to_irq(vector: u8) -> u8 {
 }
 
 impl crate::scheme::KernelScheme for IrqScheme {
-    fn kopen(&self, path: &str, flags: usize, ctx: CallerCtx) -> Result<OpenResult> {
-        if ctx.uid != 0 {
+    fn kopen(&self, path: &str, flags: usize, _ctx: CallerCtx) -> Result<OpenResult> {
+        if !caps::has(Capabilities::IRQ) {
             return Err(Error::new(EACCES));
         }
 
@@ -241,10 +303,29 @@ impl crate::scheme::KernelScheme for IrqScheme {
                 }
             }
 
+            #[cfg(target_arch = "aarch64")]
+            if irqchip::has_msi() {
+                writeln!(bytes, "msi").unwrap();
+            }
+
             (Handle::TopLevel, InternalFlags::POSITIONED)
         } else {
             if path_str == "bsp" {
                 (Handle::Bsp, InternalFlags::empty())
+            } else if cfg!(target_arch = "aarch64")
+                && (path_str == "msi" || path_str.starts_with("msi-"))
+            {
+                #[cfg(target_arch = "aarch64")]
+                {
+                    // The device ID, which the ITS of a GICv3 tells devices apart by.
+                    let device_id = match path_str.strip_prefix("msi-") {
+                        Some(id) => u32::from_str_radix(id, 16).or(Err(Error::new(ENOENT)))?,
+                        None => 0,
+                    };
+                    open_msi(device_id, flags)?
+                }
+                #[cfg(not(target_arch = "aarch64"))]
+                unreachable!()
             } else if path_str.starts_with("cpu-") {
                 let path_str = &path_str[4..];
                 let cpu_id = u8::from_str_radix(&path_str[..2], 16).or(Err(Error::new(ENOENT)))?;
@@ -290,6 +371,7 @@ impl crate::scheme::KernelScheme for IrqScheme {
                         Handle::Irq {
                             ack: AtomicUsize::new(0),
                             irq: plain_irq_number,
+                            owner: owner(),
                         },
                         InternalFlags::empty(),
                     )
@@ -379,16 +461,19 @@ impl crate::scheme::KernelScheme for IrqScheme {
     }
 
     fn close(&self, id: usize) -> Result<()> {
-        let handles_guard = HANDLES.read();
-        let handle = handles_guard.get(&id).ok_or(Error::new(EBADF))?;
+        let handle = HANDLES.write().remove(&id).ok_or(Error::new(EBADF))?;
 
-        if let &Handle::Irq {
-            irq: handle_irq, ..
-        } = handle
-        {
-            if handle_irq > BASE_IRQ_COUNT {
-                set_reserved(LogicalCpuId::BSP, irq_to_vector(handle_irq), false);
+        match handle {
+            Handle::Irq {
+                irq: handle_irq, ..
+            } => {
+                if handle_irq > BASE_IRQ_COUNT {
+                    set_reserved(LogicalCpuId::BSP, irq_to_vector(handle_irq), false);
+                }
             }
+            #[cfg(target_arch = "aarch64")]
+            Handle::Msi { irq, .. } => irqchip::free_msi(irq.into()),
+            _ => (),
         }
         Ok(())
     }
@@ -406,6 +491,12 @@ impl crate::scheme::KernelScheme for IrqScheme {
             &Handle::Irq {
                 irq: handle_irq,
                 ack: ref handle_ack,
+                ..
+            }
+            | &Handle::Msi {
+                irq: handle_irq,
+                ack: ref handle_ack,
+                ..
             } => {
                 if buffer.len() < mem::size_of::<usize>() {
                     return Err(Error::new(EINVAL));
@@ -442,6 +533,17 @@ impl crate::scheme::KernelScheme for IrqScheme {
                 st_nlink: 1,
                 ..Default::default()
             },
+            Handle::Msi {
+                irq: handle_irq, ..
+            } => Stat {
+                st_mode: MODE_CHR | 0o600,
+                st_size: mem::size_of::<usize>() as u64,
+                st_blocks: 1,
+                st_blksize: mem::size_of::<usize>() as u32,
+                st_ino: INO_MSI | u64::from(handle_irq),
+                st_nlink: 1,
+                ..Default::default()
+            },
             Handle::Bsp => Stat {
                 st_mode: MODE_CHR | 0o400,
                 st_size: mem::size_of::<usize>() as u64,
@@ -482,6 +584,9 @@ impl crate::scheme::KernelScheme for IrqScheme {
 
         let scheme_path = match handle {
             Handle::Irq { irq, .. } => format!("irq:{}", irq),
+            Handle::Msi {
+                irq, address, data, ..
+            } => format!("irq:msi/{}/{:#x}/{:#x}", irq, address, data),
             Handle::Bsp => format!("irq:bsp"),
             Handle::Avail(cpu_id) => format!("irq:cpu-{:2x}", cpu_id.get()),
             Handle::Phandle(phandle, _) => format!("irq:phandle-{}", phandle),
@@ -507,6 +612,12 @@ impl crate::scheme::KernelScheme for IrqScheme {
             Handle::Irq {
                 irq: handle_irq,
                 ack: ref handle_ack,
+                ..
+            }
+            | Handle::Msi {
+                irq: handle_irq,
+                ack: ref handle_ack,
+                ..
             } => {
                 if buffer.len() < mem::size_of::<usize>() {
                     return Err(Error::new(EINVAL));
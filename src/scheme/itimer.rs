@@ -1,41 +1,263 @@
-use alloc::collections::BTreeMap;
+//! Interval timers, backing `timer_create` and `setitimer`.
+//!
+//! Opening `itimer:<clock>` creates a timer, and closing it deletes it. The timer is notified
+//! through the event queue by default, or by sending a signal to the process that opened it, with
+//! `itimer:<clock>/<signal>`, or for real-time signals `itimer:<clock>/<signal>/<value>`, in which
+//! case the value is queued with the signal.
+//!
+//! Writing an `ITimerSpec` arms the timer to expire after `it_value`, and then every
+//! `it_interval` if it is not zero, or disarms it if `it_value` is zero. Reading an `ITimerSpec`
+//! returns the time left until the next expiry and the interval. Reading a `u64` instead returns
+//! the number of expirations since the last time, which is what an event on the handle notifies,
+//! or for signals, one more than the overrun count.
+//!
+//! Timers are kept in the timer wheel of the CPU that armed them, and thus only relative to the
+//! monotonic clock, which is also what `CLOCK_REALTIME` timers are when armed with relative times.
+
+use alloc::{
+    collections::BTreeMap,
+    sync::{Arc, Weak},
+};
 use core::{
-    mem, str,
+    mem,
     sync::atomic::{AtomicUsize, Ordering},
 };
-use spin::RwLock;
 
 use crate::{
-    context::file::InternalFlags,
+    context::{
+        self,
+        file::InternalFlags,
+        process::{self, Process},
+        signal,
+    },
+    event,
+    sync::{Mutex, RwLock},
     syscall::{
-        data::ITimerSpec,
+        data::{ITimerSpec, TimeSpec},
         error::*,
-        flag::{EventFlags, CLOCK_MONOTONIC, CLOCK_REALTIME},
+        flag::{EventFlags, CLOCK_MONOTONIC, CLOCK_REALTIME, EVENT_READ},
+        process::{send_signal, KillMode, KillTarget},
         usercopy::{UserSliceRo, UserSliceWo},
     },
+    time,
+    timer::{self, Timer},
 };
 
-use super::{CallerCtx, KernelScheme, OpenResult};
+use super::{CallerCtx, GlobalSchemes, KernelScheme, OpenResult};
+use ::syscall::{RtSigInfo, SenderInfo};
+
+/// Signal code of signals sent by timers, as in `siginfo_t::si_code`.
+const SI_TIMER: i32 = -2;
+/// The first real-time signal, which are queued.
+const SIGRTMIN: usize = 33;
+
 pub struct ITimerScheme;
 
 static NEXT_ID: AtomicUsize = AtomicUsize::new(1);
 // Using BTreeMap as hashbrown doesn't have a const constructor.
-static HANDLES: RwLock<BTreeMap<usize, usize>> = RwLock::new(BTreeMap::new());
+static TIMERS: Mutex<BTreeMap<usize, ITimer>> = Mutex::new(BTreeMap::new());
+
+enum Notify {
+    Event,
+    Signal {
+        process: Weak<RwLock<Process>>,
+        sig: usize,
+        value: usize,
+    },
+}
+
+struct ITimer {
+    clock: usize,
+    notify: Notify,
+    /// Monotonic time of the next expiry, if armed.
+    deadline: Option<u128>,
+    interval: u128,
+    /// Increased whenever the timer is set, so that the wheel entries of earlier settings are
+    /// ignored, as they cannot be removed.
+    generation: u64,
+    /// Expirations since they were last read.
+    expirations: u64,
+}
+
+fn nanos(spec: &TimeSpec) -> Result<u128> {
+    if spec.tv_sec < 0 || !(0..time::NANOS_PER_SEC as i32).contains(&spec.tv_nsec) {
+        return Err(Error::new(EINVAL));
+    }
+    Ok(spec.tv_sec as u128 * time::NANOS_PER_SEC + spec.tv_nsec as u128)
+}
+
+fn timespec(nanos: u128) -> TimeSpec {
+    TimeSpec {
+        tv_sec: (nanos / time::NANOS_PER_SEC) as i64,
+        tv_nsec: (nanos % time::NANOS_PER_SEC) as i32,
+    }
+}
+
+fn create(clock: usize, notify: Notify) -> usize {
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    TIMERS.lock().insert(
+        id,
+        ITimer {
+            clock,
+            notify,
+            deadline: None,
+            interval: 0,
+            generation: 0,
+            expirations: 0,
+        },
+    );
+    id
+}
+
+/// Arm timer `id` to expire in `value` nanoseconds, then every `interval` nanoseconds if it is not
+/// zero, or disarm it if `value` is zero.
+pub fn set(id: usize, value: u128, interval: u128) -> Result<()> {
+    let (deadline, generation) = {
+        let mut timers = TIMERS.lock();
+        let timer = timers.get_mut(&id).ok_or(Error::new(EBADF))?;
+        timer.generation += 1;
+        timer.interval = interval;
+        timer.deadline = (value != 0).then(|| time::monotonic() + value);
+        (timer.deadline, timer.generation)
+    };
+    if let Some(deadline) = deadline {
+        timer::add(deadline, Timer::ITimer { id, generation });
+    }
+    Ok(())
+}
+
+/// The time left until timer `id` expires, and its interval.
+pub fn get(id: usize) -> Result<(u128, u128)> {
+    let timers = TIMERS.lock();
+    let timer = timers.get(&id).ok_or(Error::new(EBADF))?;
+    let left = timer.deadline.map_or(0, |deadline| {
+        // An armed timer reports at least a nanosecond, even if its expiry is being handled.
+        deadline.saturating_sub(time::monotonic()).max(1)
+    });
+    Ok((left, timer.interval))
+}
+
+/// Take the number of expirations of timer `id` since the last time.
+pub fn take_expirations(id: usize) -> Result<u64> {
+    let mut timers = TIMERS.lock();
+    let timer = timers.get_mut(&id).ok_or(Error::new(EBADF))?;
+    Ok(mem::take(&mut timer.expirations))
+}
+
+/// Called by the timer wheel once a deadline of timer `id` is reached.
+pub fn fire(id: usize, generation: u64, now: u128) {
+    let (sig, process, value) = {
+        let mut timers = TIMERS.lock();
+        let Some(timer) = timers.get_mut(&id) else {
+            return;
+        };
+        let Some(deadline) = timer.deadline else {
+            return;
+        };
+        if timer.generation != generation || now < deadline {
+            return;
+        }
+
+        let mut expirations = 1;
+        timer.deadline = if timer.interval != 0 {
+            // Expiries missed while the CPU could not take the interrupt count as overruns.
+            let missed = (now - deadline) / timer.interval;
+            expirations += missed as u64;
+            let next = deadline + (missed + 1) * timer.interval;
+            timer::add(next, Timer::ITimer { id, generation });
+            Some(next)
+        } else {
+            None
+        };
+        timer.expirations = timer.expirations.saturating_add(expirations);
+
+        match timer.notify {
+            Notify::Event => (0, Weak::new(), 0),
+            Notify::Signal {
+                ref process,
+                sig,
+                value,
+            } => (sig, process.clone(), value),
+        }
+    };
+
+    if sig == 0 {
+        event::trigger(GlobalSchemes::ITimer.scheme_id(), id, EVENT_READ);
+        return;
+    }
+    let Some(process) = process.upgrade() else {
+        return;
+    };
+    let mode = if sig >= SIGRTMIN {
+        KillMode::Queued(RtSigInfo {
+            arg: value,
+            code: SI_TIMER,
+            uid: 0,
+            pid: 0,
+        })
+    } else {
+        KillMode::Idempotent
+    };
+
+    let mut killed_self = false;
+    // The expirations are still counted when the queue is full.
+    let _ = send_signal(
+        KillTarget::Process(process),
+        sig,
+        mode,
+        false,
+        &mut killed_self,
+        SenderInfo { pid: 0, ruid: 0 },
+    );
+    if killed_self && context::current().read().userspace {
+        signal::signal_handler();
+    }
+}
 
 impl KernelScheme for ITimerScheme {
     fn kopen(&self, path: &str, _flags: usize, _ctx: CallerCtx) -> Result<OpenResult> {
-        let clock = path.parse::<usize>().or(Err(Error::new(ENOENT)))?;
+        let mut parts = path.split('/');
+        let clock = parts
+            .next()
+            .and_then(|clock| clock.parse::<usize>().ok())
+            .ok_or(Error::new(ENOENT))?;
 
         match clock {
             CLOCK_REALTIME => (),
             CLOCK_MONOTONIC => (),
+            time::CLOCK_BOOTTIME => (),
             _ => return Err(Error::new(ENOENT)),
         }
 
-        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
-        HANDLES.write().insert(id, clock);
+        let notify = match parts.next() {
+            None => Notify::Event,
+            Some(sig) => {
+                let sig = sig.parse::<usize>().map_err(|_| Error::new(ENOENT))?;
+                let value = match parts.next() {
+                    Some(value) if sig >= SIGRTMIN => {
+                        value.parse::<usize>().map_err(|_| Error::new(ENOENT))?
+                    }
+                    Some(_) => return Err(Error::new(EINVAL)),
+                    None => 0,
+                };
+                if sig == 0 || sig > 64 {
+                    return Err(Error::new(EINVAL));
+                }
+                Notify::Signal {
+                    process: Arc::downgrade(&process::current()?),
+                    sig,
+                    value,
+                }
+            }
+        };
+        if parts.next().is_some() {
+            return Err(Error::new(ENOENT));
+        }
 
-        Ok(OpenResult::SchemeLocal(id, InternalFlags::empty()))
+        Ok(OpenResult::SchemeLocal(
+            create(clock, notify),
+            InternalFlags::empty(),
+        ))
     }
 
     fn fcntl(&self, _id: usize, _cmd: usize, _arg: usize) -> Result<usize> {
@@ -43,39 +265,39 @@ impl KernelScheme for ITimerScheme {
     }
 
     fn fevent(&self, id: usize, _flags: EventFlags) -> Result<EventFlags> {
-        let handles = HANDLES.read();
-        handles
-            .get(&id)
-            .ok_or(Error::new(EBADF))
-            .and(Ok(EventFlags::empty()))
+        let timers = TIMERS.lock();
+        let timer = timers.get(&id).ok_or(Error::new(EBADF))?;
+        Ok(if timer.expirations != 0 {
+            EVENT_READ
+        } else {
+            EventFlags::empty()
+        })
     }
 
     fn fsync(&self, id: usize) -> Result<()> {
-        HANDLES.read().get(&id).ok_or(Error::new(EBADF)).and(Ok(()))
+        TIMERS.lock().get(&id).ok_or(Error::new(EBADF)).and(Ok(()))
     }
 
     fn close(&self, id: usize) -> Result<()> {
-        HANDLES
-            .write()
+        TIMERS
+            .lock()
             .remove(&id)
             .ok_or(Error::new(EBADF))
             .and(Ok(()))
     }
     fn kread(&self, id: usize, buf: UserSliceWo, _flags: u32, _stored_flags: u32) -> Result<usize> {
-        let _clock = {
-            let handles = HANDLES.read();
-            *handles.get(&id).ok_or(Error::new(EBADF))?
-        };
-
-        let mut specs_read = 0;
-
-        for current_chunk in buf.in_exact_chunks(mem::size_of::<ITimerScheme>()) {
-            current_chunk.copy_exactly(&ITimerSpec::default())?;
-
-            specs_read += 1;
+        if buf.len() == mem::size_of::<u64>() {
+            buf.copy_exactly(&take_expirations(id)?.to_ne_bytes())?;
+            return Ok(mem::size_of::<u64>());
         }
 
-        Ok(specs_read * mem::size_of::<ITimerSpec>())
+        let (left, interval) = get(id)?;
+        buf.copy_exactly(&ITimerSpec {
+            it_interval: timespec(interval),
+            it_value: timespec(left),
+        })?;
+
+        Ok(mem::size_of::<ITimerSpec>())
     }
 
     fn kwrite(
@@ -85,28 +307,28 @@ impl KernelScheme for ITimerScheme {
         _flags: u32,
         _stored_flags: u32,
     ) -> Result<usize> {
-        let _clock = {
-            let handles = HANDLES.read();
-            *handles.get(&id).ok_or(Error::new(EBADF))?
-        };
-
-        let mut specs_written = 0;
-
-        for chunk in buf.in_exact_chunks(mem::size_of::<ITimerSpec>()) {
-            let time = unsafe { chunk.read_exact::<ITimerSpec>()? };
-
-            println!("{}: {:?}", specs_written, time);
-            specs_written += 1;
+        if buf.len() != mem::size_of::<ITimerSpec>() {
+            return Err(Error::new(EINVAL));
         }
+        let spec = unsafe { buf.read_exact::<ITimerSpec>()? };
+
+        set(id, nanos(&spec.it_value)?, nanos(&spec.it_interval)?)?;
 
-        Ok(specs_written * mem::size_of::<ITimerSpec>())
+        Ok(mem::size_of::<ITimerSpec>())
     }
     fn kfpath(&self, id: usize, buf: UserSliceWo) -> Result<usize> {
-        let clock = {
-            let handles = HANDLES.read();
-            *handles.get(&id).ok_or(Error::new(EBADF))?
+        let path = {
+            let timers = TIMERS.lock();
+            let timer = timers.get(&id).ok_or(Error::new(EBADF))?;
+            match timer.notify {
+                Notify::Event => format!("itimer:{}", timer.clock),
+                Notify::Signal { sig, value, .. } if sig >= SIGRTMIN => {
+                    format!("itimer:{}/{}/{}", timer.clock, sig, value)
+                }
+                Notify::Signal { sig, .. } => format!("itimer:{}/{}", timer.clock, sig),
+            }
         };
 
-        buf.copy_common_bytes_from_slice(format!("time:{}", clock).as_bytes())
+        buf.copy_common_bytes_from_slice(path.as_bytes())
     }
 }
@@ -0,0 +1,248 @@
+use core::sync::atomic::{self, AtomicUsize};
+
+use alloc::{collections::BTreeMap, string::String};
+
+use syscall::{
+    dirent::{DirEntry, DirentBuf, DirentKind},
+    EIO,
+};
+
+use crate::{
+    context::{
+        caps::{self, Capabilities},
+        file::InternalFlags,
+    },
+    kexec::{self, Part, Slot},
+    sync::RwLock,
+};
+
+use crate::syscall::{
+    data::Stat,
+    error::{Error, Result, EACCES, EBADF, EINVAL, EISDIR, ENODEV, ENOENT, ENOTDIR},
+    flag::{EventFlags, MODE_CHR, MODE_DIR, MODE_FILE, O_ACCMODE, O_DIRECTORY, O_RDONLY, O_STAT},
+    usercopy::{UserSliceRo, UserSliceWo},
+};
+
+use super::{CallerCtx, KernelScheme, OpenResult};
+
+/// A scheme for loading kernels to boot from the running one, see [`crate::kexec`].
+///
+/// The image to reboot into is loaded by writing `reboot/kernel`, and optionally `reboot/initfs`
+/// and `reboot/env`, and the crash kernel likewise in `crash/`. Each file is written at once, and
+/// writing no data unloads it. Writing `reboot` to `exec` then reboots into the loaded kernel,
+/// after giving the processes the same grace period as a normal reboot.
+pub struct KexecScheme;
+
+#[derive(Clone, Copy)]
+enum HandleKind {
+    TopLevel,
+    Slot(Slot),
+    Part(Slot, Part),
+    Exec,
+}
+
+static HANDLES: RwLock<BTreeMap<usize, HandleKind>> = RwLock::new(BTreeMap::new());
+static NEXT_FD: AtomicUsize = AtomicUsize::new(0);
+
+const SLOTS: [(Slot, &str); 2] = [(Slot::Reboot, "reboot"), (Slot::Crash, "crash")];
+const PARTS: [(Part, &str); 3] = [
+    (Part::Kernel, "kernel"),
+    (Part::Initfs, "initfs"),
+    (Part::Env, "env"),
+];
+
+fn slot_name(slot: Slot) -> &'static str {
+    SLOTS
+        .iter()
+        .find(|(s, _)| *s == slot)
+        .map_or("", |(_, name)| name)
+}
+
+fn part_name(part: Part) -> &'static str {
+    PARTS
+        .iter()
+        .find(|(p, _)| *p == part)
+        .map_or("", |(_, name)| name)
+}
+
+fn parse_path(path: &str) -> Option<HandleKind> {
+    if path.is_empty() {
+        return Some(HandleKind::TopLevel);
+    }
+    if path == "exec" {
+        return Some(HandleKind::Exec);
+    }
+    let (slot, part) = match path.split_once('/') {
+        Some((slot, part)) => (slot, Some(part)),
+        None => (path, None),
+    };
+    let slot = SLOTS.iter().find(|(_, name)| *name == slot)?.0;
+    match part {
+        None => Some(HandleKind::Slot(slot)),
+        Some(part) => Some(HandleKind::Part(
+            slot,
+            PARTS.iter().find(|(_, name)| *name == part)?.0,
+        )),
+    }
+}
+
+impl KernelScheme for KexecScheme {
+    fn kopen(&self, path: &str, flags: usize, _ctx: CallerCtx) -> Result<OpenResult> {
+        let path = path.trim_matches('/');
+
+        if !caps::has(Capabilities::REBOOT) {
+            return Err(Error::new(EACCES));
+        }
+        if !kexec::available() {
+            return Err(Error::new(ENODEV));
+        }
+
+        let kind = parse_path(path).ok_or(Error::new(ENOENT))?;
+        let is_dir = matches!(kind, HandleKind::TopLevel | HandleKind::Slot(_));
+
+        if flags & O_STAT != O_STAT {
+            if is_dir {
+                if flags & O_DIRECTORY != O_DIRECTORY {
+                    return Err(Error::new(EISDIR));
+                }
+                if flags & O_ACCMODE != O_RDONLY {
+                    return Err(Error::new(EACCES));
+                }
+            } else if flags & O_DIRECTORY == O_DIRECTORY {
+                return Err(Error::new(ENOTDIR));
+            }
+        }
+
+        let fd = NEXT_FD.fetch_add(1, atomic::Ordering::Relaxed);
+        HANDLES.write().insert(fd, kind);
+
+        Ok(OpenResult::SchemeLocal(fd, InternalFlags::POSITIONED))
+    }
+    fn fsize(&self, id: usize) -> Result<u64> {
+        let kind = *HANDLES.read().get(&id).ok_or(Error::new(EBADF))?;
+
+        Ok(match kind {
+            HandleKind::Part(slot, part) => kexec::loaded_len(slot, part) as u64,
+            _ => 0,
+        })
+    }
+    fn fevent(&self, id: usize, _flags: EventFlags) -> Result<EventFlags> {
+        if !HANDLES.read().contains_key(&id) {
+            return Err(Error::new(EBADF));
+        }
+        Ok(EventFlags::empty())
+    }
+    fn close(&self, id: usize) -> Result<()> {
+        if HANDLES.write().remove(&id).is_none() {
+            return Err(Error::new(EBADF));
+        }
+        Ok(())
+    }
+    fn kwriteoff(
+        &self,
+        id: usize,
+        buf: UserSliceRo,
+        offset: u64,
+        _flags: u32,
+        _stored_flags: u32,
+    ) -> Result<usize> {
+        let kind = *HANDLES.read().get(&id).ok_or(Error::new(EBADF))?;
+
+        match kind {
+            HandleKind::Part(slot, part) => {
+                // Loading validates and places the whole file, so partial writes cannot be
+                // supported.
+                if offset != 0 {
+                    return Err(Error::new(EINVAL));
+                }
+                let len = buf.len();
+                kexec::load(slot, part, len, |dst| buf.copy_to_slice(dst))?;
+                Ok(len)
+            }
+            HandleKind::Exec => {
+                let mut command = [0_u8; 16];
+                let len = buf.copy_common_bytes_to_slice(&mut command)?;
+                match core::str::from_utf8(&command[..len]).map(str::trim) {
+                    Ok("reboot") if kexec::is_loaded(Slot::Reboot) => crate::shutdown::kexec(),
+                    Ok("reboot") => Err(Error::new(ENOENT)),
+                    _ => Err(Error::new(EINVAL)),
+                }
+            }
+            HandleKind::TopLevel | HandleKind::Slot(_) => Err(Error::new(EISDIR)),
+        }
+    }
+    fn getdents(
+        &self,
+        id: usize,
+        buf: UserSliceWo,
+        header_size: u16,
+        opaque: u64,
+    ) -> Result<usize> {
+        let kind = *HANDLES.read().get(&id).ok_or(Error::new(EBADF))?;
+
+        let mut buf = DirentBuf::new(buf, header_size).ok_or(Error::new(EIO))?;
+        match kind {
+            HandleKind::TopLevel => {
+                let entries = SLOTS
+                    .iter()
+                    .map(|&(_, name)| (DirentKind::Directory, name))
+                    .chain([(DirentKind::CharDev, "exec")]);
+                for (i, (kind, name)) in entries.enumerate().skip(opaque as usize) {
+                    buf.entry(DirEntry {
+                        kind,
+                        name,
+                        inode: 0,
+                        next_opaque_id: i as u64 + 1,
+                    })?;
+                }
+            }
+            HandleKind::Slot(_) => {
+                for (i, &(_, name)) in PARTS.iter().enumerate().skip(opaque as usize) {
+                    buf.entry(DirEntry {
+                        kind: DirentKind::Regular,
+                        name,
+                        inode: 0,
+                        next_opaque_id: i as u64 + 1,
+                    })?;
+                }
+            }
+            _ => return Err(Error::new(ENOTDIR)),
+        }
+        Ok(buf.finalize())
+    }
+    fn kfpath(&self, id: usize, buf: UserSliceWo) -> Result<usize> {
+        let kind = *HANDLES.read().get(&id).ok_or(Error::new(EBADF))?;
+
+        let path = match kind {
+            HandleKind::TopLevel => String::from("kexec:"),
+            HandleKind::Slot(slot) => format!("kexec:{}", slot_name(slot)),
+            HandleKind::Part(slot, part) => {
+                format!("kexec:{}/{}", slot_name(slot), part_name(part))
+            }
+            HandleKind::Exec => String::from("kexec:exec"),
+        };
+        buf.copy_common_bytes_from_slice(path.as_bytes())
+    }
+    fn kfstat(&self, id: usize, buf: UserSliceWo) -> Result<()> {
+        let kind = *HANDLES.read().get(&id).ok_or(Error::new(EBADF))?;
+
+        let stat = match kind {
+            HandleKind::TopLevel | HandleKind::Slot(_) => Stat {
+                st_mode: MODE_DIR | 0o700,
+                ..Default::default()
+            },
+            HandleKind::Part(..) => Stat {
+                st_mode: MODE_FILE | 0o200,
+                st_size: self.fsize(id).unwrap_or(0),
+                ..Default::default()
+            },
+            HandleKind::Exec => Stat {
+                st_mode: MODE_CHR | 0o200,
+                ..Default::default()
+            },
+        };
+        buf.copy_exactly(&stat)?;
+
+        Ok(())
+    }
+}
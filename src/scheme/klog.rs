@@ -0,0 +1,130 @@
+use alloc::collections::BTreeMap;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::{
+    context::file::InternalFlags,
+    event, klog,
+    sync::RwLock,
+    syscall::{
+        error::*,
+        flag::{EventFlags, EVENT_READ, O_NONBLOCK},
+        usercopy::{UserSliceRo, UserSliceWo},
+    },
+};
+
+use super::{CallerCtx, GlobalSchemes, KernelScheme, OpenResult};
+
+#[derive(Clone, Copy)]
+struct Handle {
+    /// Position of the next record to read.
+    pos: u64,
+    /// Only records of this level or more severe are read.
+    max_level: log::Level,
+}
+
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+// Using BTreeMap as hashbrown doesn't have a const constructor.
+static HANDLES: RwLock<BTreeMap<usize, Handle>> = RwLock::new(BTreeMap::new());
+
+/// `klog:` - reads kernel log records
+///
+/// Opening `klog:` reads every record still in the ring, `klog:<level>` only those of `level`
+/// or more severe, like `klog:warn`. Reads return whole records, blocking until there is one
+/// unless the handle is non-blocking. Writing `clear` drops all records.
+pub struct KlogScheme;
+
+fn handle(id: usize) -> Result<Handle> {
+    HANDLES.read().get(&id).copied().ok_or(Error::new(EBADF))
+}
+
+/// Notify the handles with records to read, when readers are woken up.
+pub fn trigger_events() {
+    for (&id, handle) in HANDLES.read().iter() {
+        if klog::has_records(handle.pos) {
+            event::trigger(GlobalSchemes::Klog.scheme_id(), id, EVENT_READ);
+        }
+    }
+}
+
+impl KernelScheme for KlogScheme {
+    fn kopen(&self, path: &str, _flags: usize, ctx: CallerCtx) -> Result<OpenResult> {
+        if ctx.uid != 0 {
+            return Err(Error::new(EPERM));
+        }
+
+        let max_level = match path.trim_matches('/') {
+            "" => log::Level::Trace,
+            level => level.parse().map_err(|_| Error::new(ENOENT))?,
+        };
+
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        HANDLES.write().insert(id, Handle { pos: 0, max_level });
+
+        Ok(OpenResult::SchemeLocal(id, InternalFlags::empty()))
+    }
+
+    fn fcntl(&self, _id: usize, _cmd: usize, _arg: usize) -> Result<usize> {
+        Ok(0)
+    }
+
+    fn fevent(&self, id: usize, _flags: EventFlags) -> Result<EventFlags> {
+        Ok(if klog::has_records(handle(id)?.pos) {
+            EVENT_READ
+        } else {
+            EventFlags::empty()
+        })
+    }
+
+    fn close(&self, id: usize) -> Result<()> {
+        HANDLES
+            .write()
+            .remove(&id)
+            .ok_or(Error::new(EBADF))
+            .and(Ok(()))
+    }
+
+    fn kread(&self, id: usize, buf: UserSliceWo, flags: u32, _stored_flags: u32) -> Result<usize> {
+        let Handle { mut pos, max_level } = handle(id)?;
+        let block = flags & O_NONBLOCK as u32 == 0;
+
+        let records = klog::read(&mut pos, max_level as u8, buf.len(), block)?;
+        if records.is_empty() && block {
+            return Ok(0);
+        } else if records.is_empty() {
+            return Err(Error::new(EAGAIN));
+        }
+        buf.copy_common_bytes_from_slice(&records)?;
+
+        if let Some(handle) = HANDLES.write().get_mut(&id) {
+            handle.pos = pos;
+        }
+        Ok(records.len())
+    }
+
+    fn kwrite(
+        &self,
+        id: usize,
+        buf: UserSliceRo,
+        _flags: u32,
+        _stored_flags: u32,
+    ) -> Result<usize> {
+        handle(id)?;
+
+        let mut tmp = [0_u8; 16];
+        let byte_count = buf.copy_common_bytes_to_slice(&mut tmp)?;
+        if tmp[..byte_count].trim_ascii() != b"clear" {
+            return Err(Error::new(EINVAL));
+        }
+        klog::clear();
+
+        Ok(byte_count)
+    }
+
+    fn kfpath(&self, id: usize, buf: UserSliceWo) -> Result<usize> {
+        let path = match handle(id)?.max_level {
+            log::Level::Trace => format!("klog:"),
+            level => format!("klog:{}", level.as_str().to_ascii_lowercase()),
+        };
+        buf.copy_common_bytes_from_slice(path.as_bytes())
+    }
+}
@@ -0,0 +1,184 @@
+use alloc::{collections::BTreeMap, string::String, sync::Arc};
+use core::{
+    fmt::Write,
+    str,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use crate::{
+    context::{file::InternalFlags, process::ProcessId},
+    memory::memcg::{self, MemGroup},
+    sync::RwLock,
+    syscall::{
+        error::*,
+        flag::{O_ACCMODE, O_CREAT, O_EXCL, O_RDONLY},
+        usercopy::{UserSliceRo, UserSliceWo},
+    },
+};
+
+use super::{CallerCtx, KernelScheme, OpenResult};
+
+enum Handle {
+    TopLevel,
+    Group {
+        group: Arc<MemGroup>,
+        /// Set if opened for writing.
+        writable: bool,
+    },
+}
+
+/// Longest command accepted by the groups.
+const MAX_COMMAND_LEN: usize = 64;
+
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+// Using BTreeMap as hashbrown doesn't have a const constructor.
+static HANDLES: RwLock<BTreeMap<usize, Handle>> = RwLock::new(BTreeMap::new());
+
+/// `memcg:` - creates memory groups, and reads and sets their limits
+///
+/// Reading `memcg:` lists the groups, one path per line. Opening `memcg:<path>` with `O_CREAT`
+/// creates a group, below its parent if the path has several components, and unlinking it removes
+/// it again once it has neither children nor contexts. Reading a group returns its usage, limit,
+/// peak usage, failed charges and evicted pages, one `<name> <value>` per line. Writing
+/// `limit <bytes>` or `limit max` sets its limit, and `attach <pid>` moves a process into it, both
+/// only by root.
+pub struct MemcgScheme;
+
+fn group_text(group: &MemGroup) -> String {
+    let [usage, peak, failcnt, reclaimed] = group.stats();
+    let mut text = String::new();
+    let _ = writeln!(text, "usage {}", usage);
+    let _ = match group.limit() {
+        Some(limit) => writeln!(text, "limit {}", limit),
+        None => writeln!(text, "limit max"),
+    };
+    let _ = writeln!(text, "peak {}", peak);
+    let _ = writeln!(text, "failcnt {}", failcnt);
+    let _ = writeln!(text, "reclaimed {}", reclaimed);
+    text
+}
+
+fn command(group: &Arc<MemGroup>, command: &str) -> Result<()> {
+    let (action, arg) = command.trim().split_once(' ').ok_or(Error::new(EINVAL))?;
+    let arg = arg.trim();
+    match action {
+        "limit" => group.set_limit(match arg {
+            "max" => None,
+            bytes => Some(bytes.parse().map_err(|_| Error::new(EINVAL))?),
+        }),
+        "attach" => {
+            let pid = arg.parse::<usize>().map_err(|_| Error::new(EINVAL))?;
+            memcg::attach(group, ProcessId::from(pid))?;
+        }
+        _ => return Err(Error::new(EINVAL)),
+    }
+    Ok(())
+}
+
+impl KernelScheme for MemcgScheme {
+    fn kopen(&self, path: &str, flags: usize, ctx: CallerCtx) -> Result<OpenResult> {
+        let path = path.trim_matches('/');
+        let writable = flags & O_ACCMODE != O_RDONLY;
+        if (writable || flags & O_CREAT == O_CREAT) && ctx.uid != 0 {
+            return Err(Error::new(EACCES));
+        }
+
+        let handle = if path.is_empty() {
+            if writable {
+                return Err(Error::new(EISDIR));
+            }
+            Handle::TopLevel
+        } else {
+            let group = if flags & O_CREAT == O_CREAT {
+                match memcg::create(path) {
+                    Err(err) if err.errno == EEXIST && flags & O_EXCL != O_EXCL => {
+                        memcg::lookup(path)?
+                    }
+                    result => result?,
+                }
+            } else {
+                memcg::lookup(path)?
+            };
+            Handle::Group { group, writable }
+        };
+
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        HANDLES.write().insert(id, handle);
+        Ok(OpenResult::SchemeLocal(id, InternalFlags::POSITIONED))
+    }
+
+    fn close(&self, id: usize) -> Result<()> {
+        HANDLES.write().remove(&id).ok_or(Error::new(EBADF))?;
+        Ok(())
+    }
+
+    fn unlink(&self, path: &str, ctx: CallerCtx) -> Result<()> {
+        if ctx.uid != 0 {
+            return Err(Error::new(EACCES));
+        }
+        memcg::remove(path.trim_matches('/'))
+    }
+
+    fn kreadoff(
+        &self,
+        id: usize,
+        buf: UserSliceWo,
+        pos: u64,
+        _flags: u32,
+        _stored_flags: u32,
+    ) -> Result<usize> {
+        let text = match HANDLES.read().get(&id).ok_or(Error::new(EBADF))? {
+            Handle::TopLevel => {
+                let mut text = String::new();
+                for path in memcg::paths() {
+                    let _ = writeln!(text, "{}", path);
+                }
+                text
+            }
+            Handle::Group { group, .. } => group_text(group),
+        };
+        let Some(avail) = usize::try_from(pos)
+            .ok()
+            .and_then(|pos| text.as_bytes().get(pos..))
+        else {
+            return Ok(0);
+        };
+        buf.copy_common_bytes_from_slice(avail)
+    }
+
+    fn kwriteoff(
+        &self,
+        id: usize,
+        buf: UserSliceRo,
+        _pos: u64,
+        _flags: u32,
+        _stored_flags: u32,
+    ) -> Result<usize> {
+        let group = match HANDLES.read().get(&id).ok_or(Error::new(EBADF))? {
+            Handle::Group {
+                group,
+                writable: true,
+            } => Arc::clone(group),
+            _ => return Err(Error::new(EBADF)),
+        };
+        if buf.len() > MAX_COMMAND_LEN {
+            return Err(Error::new(EINVAL));
+        }
+        let mut bytes = [0; MAX_COMMAND_LEN];
+        let bytes = &mut bytes[..buf.len()];
+        buf.copy_to_slice(bytes)?;
+        command(
+            &group,
+            str::from_utf8(bytes).map_err(|_| Error::new(EINVAL))?,
+        )?;
+        Ok(bytes.len())
+    }
+
+    fn kfpath(&self, id: usize, buf: UserSliceWo) -> Result<usize> {
+        let path = match HANDLES.read().get(&id).ok_or(Error::new(EBADF))? {
+            Handle::TopLevel => format!("memcg:"),
+            Handle::Group { group, .. } => format!("memcg:{}", group.path()),
+        };
+        buf.copy_common_bytes_from_slice(path.as_bytes())
+    }
+}
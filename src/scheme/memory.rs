@@ -1,14 +1,21 @@
 use core::num::NonZeroUsize;
 
-use alloc::{sync::Arc, vec::Vec};
+use alloc::{string::String, sync::Arc, vec::Vec};
+use core::{fmt::Write, sync::atomic::Ordering};
 use rmm::PhysicalAddress;
 
 use crate::{
     context::{
+        caps::{self, Capabilities},
         file::InternalFlags,
         memory::{handle_notify_files, AddrSpace, AddrSpaceWrapper, Grant, PageSpan},
     },
-    memory::{free_frames, used_frames, Frame, PAGE_SIZE},
+    event, lockdown,
+    memory::{
+        frame_stats, free_frames, free_stats, oom, reclaim, used_frames, Frame, ORDER_COUNT,
+        PAGE_SIZE, REFCOUNT_BUCKETS,
+    },
+    numa,
     paging::VirtualAddress,
 };
 
@@ -17,11 +24,11 @@ use crate::paging::entry::EntryFlags;
 use crate::syscall::{
     data::{Map, StatVfs},
     error::*,
-    flag::MapFlags,
+    flag::{MapFlags, EVENT_READ},
     usercopy::UserSliceWo,
 };
 
-use super::{CallerCtx, KernelScheme, OpenResult};
+use super::{CallerCtx, GlobalSchemes, KernelScheme, OpenResult};
 
 pub struct MemoryScheme;
 
@@ -31,6 +38,13 @@ pub struct MemoryScheme;
 enum HandleTy {
     Allocated = 0,
     PhysBorrow = 1,
+    /// Read-only table of the free block distribution of the frame allocator, one line per order.
+    Stats = 2,
+    /// Read-only summary of the usage of all frames, by node and by reference count.
+    Frames = 3,
+    /// Read-only memory pressure level and reclaim counters, triggering `EVENT_READ` when the
+    /// level changes.
+    Pressure = 4,
 }
 #[repr(u8)]
 #[derive(Clone, Copy, Debug, PartialEq)]
@@ -45,6 +59,13 @@ bitflags! {
     struct HandleFlags: u16 {
         // TODO: below 32 bits?
         const PHYS_CONTIGUOUS = 1;
+        /// The mapping grows downwards when the page right below it is accessed, like
+        /// MAP_GROWSDOWN. Faults in the gap below consume it, but a guard page is always kept.
+        const GROWSDOWN = 2;
+        /// A growsdown stack, with a region of the address space reserved right below it, which
+        /// it can grow into up to the guard page at the bottom. The size of the region is set by
+        /// the `stack-window` proc: handle of the address space.
+        const STACK = 4;
     }
 }
 
@@ -53,6 +74,9 @@ fn from_raw(raw: u32) -> Option<(HandleTy, MemoryType, HandleFlags)> {
         match raw & 0xFF {
             0 => HandleTy::Allocated,
             1 => HandleTy::PhysBorrow,
+            2 => HandleTy::Stats,
+            3 => HandleTy::Frames,
+            4 => HandleTy::Pressure,
 
             _ => return None,
         },
@@ -73,6 +97,8 @@ impl MemoryScheme {
         addr_space: &Arc<AddrSpaceWrapper>,
         map: &Map,
         is_phys_contiguous: bool,
+        growsdown: bool,
+        stack: bool,
     ) -> Result<usize> {
         let span = PageSpan::validate_nonempty(VirtualAddress::new(map.address), map.size)
             .ok_or(Error::new(EINVAL))?;
@@ -80,32 +106,55 @@ impl MemoryScheme {
 
         let mut notify_files = Vec::new();
 
-        if is_phys_contiguous && map.flags.contains(MapFlags::MAP_SHARED) {
+        if (is_phys_contiguous || growsdown || stack) && map.flags.contains(MapFlags::MAP_SHARED) {
             // TODO: Should this be supported?
             return Err(Error::new(EOPNOTSUPP));
         }
 
-        let page = addr_space.acquire_write().mmap(
+        let mut guard = addr_space.acquire_write();
+        let mut requested_base = (map.address != 0).then_some(span.base);
+        let mut flags = map.flags;
+        let reserve = if stack { guard.stack_window } else { 0 };
+
+        if stack && !flags.intersects(MapFlags::MAP_FIXED | MapFlags::MAP_FIXED_NOREPLACE) {
+            // Place the stack right above a free region large enough for its reservation.
+            let total = page_count
+                .get()
+                .checked_add(reserve)
+                .ok_or(Error::new(ENOMEM))?;
+            let free = guard.find_free(total).ok_or(Error::new(ENOMEM))?;
+            requested_base = Some(free.base.next_by(reserve));
+            flags |= MapFlags::MAP_FIXED_NOREPLACE;
+        }
+
+        let page = guard.mmap(
             &addr_space,
-            (map.address != 0).then_some(span.base),
+            requested_base,
             page_count,
-            map.flags,
+            flags,
             &mut notify_files,
             |dst_page, flags, mapper, flusher| {
                 let span = PageSpan::new(dst_page, page_count.get());
                 if is_phys_contiguous {
                     Ok(Grant::zeroed_phys_contiguous(span, flags, mapper, flusher)?)
                 } else {
-                    Ok(Grant::zeroed(
+                    let mut grant = Grant::zeroed(
                         span,
                         flags,
                         mapper,
                         flusher,
                         map.flags.contains(MapFlags::MAP_SHARED),
-                    )?)
+                    )?;
+                    if stack {
+                        grant.info.set_stack(reserve)?;
+                    } else if growsdown {
+                        grant.info.set_growsdown()?;
+                    }
+                    Ok(grant)
                 }
             },
         )?;
+        drop(guard);
 
         handle_notify_files(notify_files);
 
@@ -124,6 +173,10 @@ impl MemoryScheme {
             return Err(Error::new(EINVAL));
         }
 
+        if crate::startup::memory::overlaps_ram(physical_address, size) {
+            lockdown::check(lockdown::Reason::PhysicalMemory)?;
+        }
+
         if size % PAGE_SIZE != 0 {
             log::warn!(
                 "physmap size {} is not multiple of PAGE_SIZE {}",
@@ -177,12 +230,31 @@ impl MemoryScheme {
     }
 }
 impl KernelScheme for MemoryScheme {
-    fn kopen(&self, path: &str, _flags: usize, ctx: CallerCtx) -> Result<OpenResult> {
+    fn kopen(&self, path: &str, _flags: usize, _ctx: CallerCtx) -> Result<OpenResult> {
         if path.len() > 64 {
             return Err(Error::new(ENOENT));
         }
         let path = path.trim_start_matches('/');
 
+        if path == "stats" {
+            return Ok(OpenResult::SchemeLocal(
+                HandleTy::Stats as usize,
+                InternalFlags::POSITIONED,
+            ));
+        }
+        if path == "frames" {
+            return Ok(OpenResult::SchemeLocal(
+                HandleTy::Frames as usize,
+                InternalFlags::POSITIONED,
+            ));
+        }
+        if path == "pressure" {
+            return Ok(OpenResult::SchemeLocal(
+                HandleTy::Pressure as usize,
+                InternalFlags::POSITIONED,
+            ));
+        }
+
         let (before_memty, memty_str) = path.split_once('@').unwrap_or((path, ""));
         let (before_ty, type_str) = memty_str.split_once('?').unwrap_or((memty_str, ""));
 
@@ -206,6 +278,8 @@ impl KernelScheme for MemoryScheme {
             .filter_map(|ty_str| match ty_str {
                 //"32" => HandleFlags::BELOW_4G,
                 "phys_contiguous" => Some(Some(HandleFlags::PHYS_CONTIGUOUS)),
+                "growsdown" => Some(Some(HandleFlags::GROWSDOWN)),
+                "stack" => Some(Some(HandleFlags::STACK)),
                 "" => None,
                 _ => Some(None),
             })
@@ -213,8 +287,8 @@ impl KernelScheme for MemoryScheme {
             .ok_or(Error::new(ENOENT))?;
 
         // TODO: Support arches with other default memory types?
-        if ctx.uid != 0
-            && (!flags.is_empty()
+        if !caps::has(Capabilities::PHYS_MEM)
+            && (!(flags - HandleFlags::GROWSDOWN - HandleFlags::STACK).is_empty()
                 || !matches!(
                     (handle_ty, mem_ty),
                     (HandleTy::Allocated, MemoryType::Writeback)
@@ -246,10 +320,34 @@ impl KernelScheme for MemoryScheme {
                 addr_space,
                 map,
                 flags.contains(HandleFlags::PHYS_CONTIGUOUS),
+                flags.contains(HandleFlags::GROWSDOWN),
+                flags.contains(HandleFlags::STACK),
             ),
             HandleTy::PhysBorrow => Self::physmap(map.offset, map.size, map.flags, mem_ty),
+            HandleTy::Stats | HandleTy::Frames | HandleTy::Pressure => Err(Error::new(EBADF)),
         }
     }
+    fn kreadoff(
+        &self,
+        id: usize,
+        buf: UserSliceWo,
+        offset: u64,
+        _flags: u32,
+        _stored_flags: u32,
+    ) -> Result<usize> {
+        let text = match u32::try_from(id).ok().and_then(from_raw) {
+            Some((HandleTy::Stats, _, _)) => stats_text(),
+            Some((HandleTy::Frames, _, _)) => frames_text(),
+            Some((HandleTy::Pressure, _, _)) => pressure_text(),
+            _ => return Err(Error::new(EBADF)),
+        };
+
+        let avail = usize::try_from(offset)
+            .ok()
+            .and_then(|o| text.as_bytes().get(o..))
+            .unwrap_or(&[]);
+        buf.copy_common_bytes_from_slice(avail)
+    }
     fn kfstatvfs(&self, _file: usize, dst: UserSliceWo) -> Result<()> {
         let used = used_frames() as u64;
         let free = free_frames() as u64;
@@ -265,3 +363,85 @@ impl KernelScheme for MemoryScheme {
         Ok(())
     }
 }
+
+/// Notify the `memory:pressure` handles that the level changed. All of them share the same id.
+pub fn trigger_pressure() {
+    event::trigger(
+        GlobalSchemes::Memory.scheme_id(),
+        HandleTy::Pressure as usize,
+        EVENT_READ,
+    );
+}
+
+fn pressure_text() -> String {
+    let stats = &reclaim::STATS;
+    let mut text = String::new();
+    let _ = writeln!(text, "level: {}", reclaim::pressure().as_str());
+    let _ = writeln!(text, "free: {}", free_frames());
+    let _ = writeln!(text, "passes: {}", stats.passes.load(Ordering::Relaxed));
+    let _ = writeln!(
+        text,
+        "frames_shrunk: {}",
+        stats.frames_shrunk.load(Ordering::Relaxed)
+    );
+    let _ = writeln!(
+        text,
+        "pages_lazyfreed: {}",
+        stats.pages_lazyfreed.load(Ordering::Relaxed)
+    );
+    let _ = writeln!(text, "oom_kills: {}", oom::KILLS.load(Ordering::Relaxed));
+    let _ = writeln!(text, "events: {}", stats.events.load(Ordering::Relaxed));
+    text
+}
+
+fn stats_text() -> String {
+    // Columns: order, free blocks, free frames in blocks of at least this order, failed
+    // allocations, and the fragmentation index (0-1000), or -1 if an allocation would succeed.
+    let stats = free_stats();
+    let mut text = String::new();
+    for order in 0..ORDER_COUNT {
+        let _ = writeln!(
+            text,
+            "{}\t{}\t{}\t{}\t{}",
+            order,
+            stats.free_blocks[order as usize],
+            stats.free_frames_at_least(order),
+            stats.alloc_failures[order as usize],
+            stats
+                .fragmentation_index(order)
+                .map_or(-1, |index| index as isize),
+        );
+    }
+    text
+}
+
+fn frames_text() -> String {
+    let stats = frame_stats();
+    let free: usize = stats.node_free.iter().sum();
+    let used: usize = stats.node_used.iter().sum();
+
+    let mut text = String::new();
+    let _ = writeln!(text, "free: {}", free);
+    let _ = writeln!(text, "used: {}", used);
+    let _ = writeln!(text, "largest_free_run: {}", stats.largest_free_run);
+    for node in 0..numa::node_count() {
+        let _ = writeln!(
+            text,
+            "node{}: free {} used {}",
+            node, stats.node_free[node], stats.node_used[node]
+        );
+    }
+    for (bucket, count) in stats.refcounts.iter().enumerate() {
+        let min = 1_usize << bucket;
+        if bucket == REFCOUNT_BUCKETS - 1 {
+            let _ = writeln!(text, "refcount {}+: {}", min, count);
+        } else if min == 1 {
+            let _ = writeln!(text, "refcount 1: {}", count);
+        } else {
+            let _ = writeln!(text, "refcount {}-{}: {}", min, 2 * min - 1, count);
+        }
+    }
+    let _ = writeln!(text, "shared: {}", stats.shared);
+    let _ = writeln!(text, "cow: {}", stats.cow);
+    text
+}
@@ -12,7 +12,7 @@ use alloc::{boxed::Box, collections::BTreeMap, string::ToString, sync::Arc, vec:
 use core::{hash::BuildHasherDefault, sync::atomic::AtomicUsize};
 use hashbrown::{hash_map::DefaultHashBuilder, HashMap};
 use indexmap::IndexMap;
-use spin::{Once, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use spin::Once;
 use syscall::{EventFlags, MunmapFlags, SendFdFlags};
 
 use crate::{
@@ -20,6 +20,7 @@ use crate::{
         file::{FileDescription, InternalFlags},
         memory::AddrSpaceWrapper,
     },
+    sync::{RwLock, RwLockReadGuard, RwLockWriteGuard},
     syscall::{
         error::*,
         usercopy::{UserSliceRo, UserSliceWo},
@@ -30,11 +31,14 @@ use crate::{
 use self::acpi::AcpiScheme;
 #[cfg(dtb)]
 use self::dtb::DtbScheme;
+#[cfg(target_arch = "x86_64")]
+use self::{efi::EfiScheme, kexec::KexecScheme};
 
 use self::{
-    debug::DebugScheme, event::EventScheme, irq::IrqScheme, itimer::ITimerScheme,
+    audit::AuditScheme, debug::DebugScheme, event::EventScheme, getrandom::GetrandomScheme,
+    irq::IrqScheme, itimer::ITimerScheme, klog::KlogScheme, memcg::MemcgScheme,
     memory::MemoryScheme, pipe::PipeScheme, proc::ProcScheme, root::RootScheme, serio::SerioScheme,
-    sys::SysScheme, time::TimeScheme, user::UserScheme,
+    swap::SwapScheme, sys::SysScheme, time::TimeScheme, trace::TraceScheme, user::UserScheme,
 };
 
 /// When compiled with the "acpi" feature - `acpi:` - allows drivers to read a limited set of ACPI tables.
@@ -43,18 +47,38 @@ pub mod acpi;
 #[cfg(dtb)]
 pub mod dtb;
 
+/// `audit:` - syscall auditing rules and records, for security monitoring daemons
+pub mod audit;
+
 /// `debug:` - provides access to serial console
 pub mod debug;
 
+/// `kernel.efi:` - allows reading and writing EFI variables, and resetting using the firmware
+#[cfg(target_arch = "x86_64")]
+pub mod efi;
+
 /// `event:` - allows reading of `Event`s which are registered using `fevent`
 pub mod event;
 
+/// `getrandom:` - random bytes from the kernel entropy pool
+pub mod getrandom;
+
 /// `irq:` - allows userspace handling of IRQs
 pub mod irq;
 
-/// `itimer:` - support for getitimer and setitimer
+/// `itimer:` - interval timers, for timer_create and setitimer
 pub mod itimer;
 
+/// `kexec:` - loads kernels to reboot into, or to start when panicking
+#[cfg(target_arch = "x86_64")]
+pub mod kexec;
+
+/// `klog:` - reads kernel log records, for dmesg and journal daemons
+pub mod klog;
+
+/// `memcg:` - memory groups, limiting the memory of the contexts attached to them
+pub mod memcg;
+
 /// `memory:` - a scheme for accessing physical memory
 pub mod memory;
 
@@ -70,12 +94,18 @@ pub mod root;
 /// `serio:` - provides access to ps/2 devices
 pub mod serio;
 
+/// `swap:` - lets a userspace daemon provide the backing store for swapped out pages
+pub mod swap;
+
 /// `sys:` - system information, such as the context list and scheme list
 pub mod sys;
 
 /// `time:` - allows reading time, setting timeouts and getting events when they are met
 pub mod time;
 
+/// `trace:` - streams kernel trace records
+pub mod trace;
+
 /// A wrapper around userspace schemes, tightly dependent on `root`
 pub mod user;
 
@@ -143,6 +173,12 @@ impl SchemeList {
                 Sys,
                 ProcFull,
                 ProcRestricted,
+                Trace,
+                Swap,
+                Klog,
+                Memcg,
+                Getrandom,
+                Audit,
             ]);
 
             #[cfg(feature = "acpi")]
@@ -150,6 +186,9 @@ impl SchemeList {
 
             #[cfg(dtb)]
             insert_globals(&[Dtb]);
+
+            #[cfg(target_arch = "x86_64")]
+            insert_globals(&[Efi, Kexec]);
         }
 
         list.new_null();
@@ -185,6 +224,8 @@ impl SchemeList {
         .unwrap();
         self.insert_global(ns, "event", GlobalSchemes::Event)
             .unwrap();
+        self.insert_global(ns, "getrandom", GlobalSchemes::Getrandom)
+            .unwrap();
         self.insert_global(ns, "itimer", GlobalSchemes::ITimer)
             .unwrap();
         self.insert_global(ns, "memory", GlobalSchemes::Memory)
@@ -212,6 +253,13 @@ impl SchemeList {
             self.insert_global(ns, "kernel.acpi", GlobalSchemes::Acpi)
                 .unwrap();
         }
+        #[cfg(target_arch = "x86_64")]
+        {
+            self.insert_global(ns, "kernel.efi", GlobalSchemes::Efi)
+                .unwrap();
+            self.insert_global(ns, "kexec", GlobalSchemes::Kexec)
+                .unwrap();
+        }
         self.insert_global(ns, "debug", GlobalSchemes::Debug)
             .unwrap();
         self.insert_global(ns, "irq", GlobalSchemes::Irq).unwrap();
@@ -221,6 +269,14 @@ impl SchemeList {
             .unwrap();
         self.insert_global(ns, "serio", GlobalSchemes::Serio)
             .unwrap();
+        self.insert_global(ns, "trace", GlobalSchemes::Trace)
+            .unwrap();
+        self.insert_global(ns, "swap", GlobalSchemes::Swap).unwrap();
+        self.insert_global(ns, "klog", GlobalSchemes::Klog).unwrap();
+        self.insert_global(ns, "memcg", GlobalSchemes::Memcg)
+            .unwrap();
+        self.insert_global(ns, "audit", GlobalSchemes::Audit)
+            .unwrap();
     }
 
     pub fn make_ns(
@@ -252,6 +308,26 @@ impl SchemeList {
         Ok(to)
     }
 
+    /// Create a namespace with the schemes of `from`, which diverges from it afterwards.
+    pub fn clone_ns(&mut self, from: SchemeNamespace) -> Result<SchemeNamespace> {
+        let names = self
+            .names
+            .get(&from)
+            .ok_or(Error::new(ENODEV))?
+            .iter()
+            .map(|(name, &id)| (name.clone(), id))
+            .collect::<Vec<_>>();
+
+        // The new namespace keeps its own root scheme, for registering schemes in it.
+        let to = self.new_ns();
+        let to_names = self.names.get_mut(&to).expect("namespace was just created");
+        for (name, id) in names {
+            to_names.entry(name).or_insert(id);
+        }
+
+        Ok(to)
+    }
+
     pub fn iter_name(&self, ns: SchemeNamespace) -> SchemeIter {
         SchemeIter {
             inner: self.names.get(&ns).map(|names| names.iter()),
@@ -398,6 +474,10 @@ pub trait KernelScheme: Send + Sync + 'static {
     fn kfunmap(&self, number: usize, offset: usize, size: usize, flags: MunmapFlags) -> Result<()> {
         Err(Error::new(EOPNOTSUPP))
     }
+    /// Write back `size` bytes at `offset` of a shared file mapping, which were written to.
+    fn kmsync(&self, number: usize, offset: usize, size: usize, flags: usize) -> Result<()> {
+        Ok(())
+    }
 
     fn kdup(&self, old_id: usize, buf: UserSliceRo, _caller: CallerCtx) -> Result<OpenResult> {
         Err(Error::new(EOPNOTSUPP))
@@ -535,14 +615,25 @@ pub enum GlobalSchemes {
     Sys,
     ProcFull,
     ProcRestricted,
+    Trace,
+    Swap,
+    Klog,
+    Memcg,
+    Getrandom,
+    Audit,
 
     #[cfg(feature = "acpi")]
     Acpi,
 
     #[cfg(dtb)]
     Dtb,
+
+    #[cfg(target_arch = "x86_64")]
+    Efi,
+    #[cfg(target_arch = "x86_64")]
+    Kexec,
 }
-pub const MAX_GLOBAL_SCHEMES: usize = 16;
+pub const MAX_GLOBAL_SCHEMES: usize = 32;
 
 const _: () = {
     assert!(1 + core::mem::variant_count::<GlobalSchemes>() < MAX_GLOBAL_SCHEMES);
@@ -576,10 +667,20 @@ impl core::ops::Deref for GlobalSchemes {
             Self::Sys => &SysScheme,
             Self::ProcFull => &ProcScheme::<true>,
             Self::ProcRestricted => &ProcScheme::<false>,
+            Self::Trace => &TraceScheme,
+            Self::Swap => &SwapScheme,
+            Self::Klog => &KlogScheme,
+            Self::Memcg => &MemcgScheme,
+            Self::Getrandom => &GetrandomScheme,
+            Self::Audit => &AuditScheme,
             #[cfg(feature = "acpi")]
             Self::Acpi => &AcpiScheme,
             #[cfg(dtb)]
             Self::Dtb => &DtbScheme,
+            #[cfg(target_arch = "x86_64")]
+            Self::Efi => &EfiScheme,
+            #[cfg(target_arch = "x86_64")]
+            Self::Kexec => &KexecScheme,
         }
     }
 }
@@ -5,15 +5,13 @@ use alloc::{
     sync::Arc,
 };
 
-use spin::{Mutex, RwLock};
-
 use crate::{
     context::file::InternalFlags,
     event,
-    sync::WaitCondition,
+    sync::{Mutex, RwLock, WaitCondition},
     syscall::{
         data::Stat,
-        error::{Error, Result, EAGAIN, EBADF, EINTR, EINVAL, ENOENT, EPIPE},
+        error::{Error, Result, EAGAIN, EBADF, EINTR, EINVAL, ENOENT, ENOMEM, EPIPE},
         flag::{EventFlags, EVENT_READ, EVENT_WRITE, MODE_FIFO, O_NONBLOCK},
         usercopy::{UserSliceRo, UserSliceWo},
     },
@@ -234,6 +232,9 @@ impl KernelScheme for PipeScheme {
 
             let mut bytes_written = 0;
 
+            vec.try_reserve(bytes_to_write)
+                .map_err(|_| Error::new(ENOMEM))?;
+
             // TODO: Modify VecDeque so that the unwritten portions can be accessed directly?
             for (idx, chunk) in src_buf.in_variable_chunks(TMPBUF_SIZE).enumerate() {
                 let chunk_byte_count = match chunk.copy_common_bytes_to_slice(&mut tmp_buf) {
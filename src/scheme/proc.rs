@@ -2,15 +2,34 @@ use crate::{
     arch::paging::{Page, VirtualAddress},
     context::{
         self,
+        caps::{self, Capabilities},
         context::{HardBlockedReason, SignalState},
         file::{FileDescriptor, InternalFlags},
-        memory::{handle_notify_files, AddrSpaceWrapper, Grant, PageSpan},
+        group::{self, ContextGroup},
+        memory::{
+            check_wx, handle_notify_files, AddrSpaceWrapper, Grant, PageSpan, ADDRSPACE_OP_MADVISE,
+            ADDRSPACE_OP_MKEXEC, ADDRSPACE_OP_MLOCK, ADDRSPACE_OP_MUNLOCK, GRANT_LABEL_MAX,
+        },
         process::{self, Process, ProcessId, ProcessInfo, ProcessStatus},
+        rlimit::{self, Rlimit},
+        rt,
+        signal::{self, KernelSignals, SigAction},
+        stats,
+        userfault::{
+            Userfault, USERFAULT_COPY, USERFAULT_REGISTER, USERFAULT_UNREGISTER, USERFAULT_WAKE,
+            USERFAULT_ZEROPAGE,
+        },
         Context, Status,
     },
-    memory::PAGE_SIZE,
-    ptrace,
-    scheme::{self, FileHandle, KernelScheme},
+    memory::{
+        ksm::{self, KsmState},
+        oom::{OOM_SCORE_ADJ_MAX, OOM_SCORE_ADJ_MIN},
+        writeback::{self, ADDRSPACE_OP_MSYNC},
+        PAGE_SIZE,
+    },
+    numa, ptrace,
+    scheme::{self, FileHandle, KernelScheme, SchemeNamespace},
+    sync::{RwLock, RwSpinlock, WaitCondition},
     syscall::{
         self,
         data::{GrantDesc, Map, PtraceEvent, SenderInfo, SetSighandlerData, Stat},
@@ -22,7 +41,9 @@ use crate::{
 };
 
 use super::{CallerCtx, GlobalSchemes, KernelSchemes, OpenResult};
-use ::syscall::{SigProcControl, Sigcontrol};
+#[cfg(any(target_arch = "aarch64", target_arch = "x86_64"))]
+use crate::arch::debug_regs::DebugRegisters;
+use ::syscall::{RtSigInfo, SigProcControl, Sigcontrol};
 use alloc::{
     boxed::Box,
     collections::{btree_map::Entry, BTreeMap},
@@ -36,8 +57,6 @@ use core::{
     slice, str,
     sync::atomic::{AtomicUsize, Ordering},
 };
-use spin::RwLock;
-use spinning_top::RwSpinlock;
 
 fn read_from(dst: UserSliceWo, src: &[u8], offset: u64) -> Result<usize> {
     let avail_src = usize::try_from(offset)
@@ -94,6 +113,8 @@ enum RegsKind {
     Float,
     Int,
     Env,
+    #[cfg(any(target_arch = "aarch64", target_arch = "x86_64"))]
+    Debug,
 }
 #[derive(Clone)]
 enum ProcHandle {
@@ -114,7 +135,7 @@ enum ProcHandle {
 #[derive(Clone)]
 enum ContextHandle {
     Status, // writing usize::MAX causes exit
-    Signal, // writing sends signal
+    Signal, // writing sends signal, either u32 sig or [sig, tgid] optionally followed by RtSigInfo
 
     Regs(RegsKind),
     Name,
@@ -149,8 +170,93 @@ enum ContextHandle {
     // directory.
     OpenViaDup,
     SchedAffinity,
+    /// Nice level of the context, as an isize from -20 to 19. Lowering it requires
+    /// [`Capabilities::SYS_NICE`].
+    SchedNice,
+    /// Scheduling policy of the context and its real-time priority, as two usizes: 0 for normal
+    /// with priority 0, or 1 for FIFO and 2 for round robin with a priority from 1 to 99. Setting
+    /// a real-time policy requires [`Capabilities::SYS_NICE`].
+    SchedPolicy,
+    /// CPU bandwidth limit of the context, as a quota and a period in nanoseconds, the quota being
+    /// `usize::MAX` if unlimited. Setting it places the context in a fresh group, leaving the
+    /// group it was in, and the contexts it shared that with, unchanged.
+    CpuMax,
+    /// Timeout in nanoseconds for blocking scheme calls made by the context, or zero if none.
+    SchemeTimeout,
+    /// Writing a signal number, a handler, a mask, flags and a restorer registers the handler with
+    /// the kernel, for contexts without a `sighandler`. Reading returns those of every signal.
+    #[cfg(any(target_arch = "aarch64", target_arch = "x86_64"))]
+    Sigaction,
+    /// Writing how (0 to block, 1 to unblock, 2 to set) and a mask changes the signals blocked for
+    /// handlers registered with the kernel. Reading returns the blocked and the pending signals.
+    #[cfg(any(target_arch = "aarch64", target_arch = "x86_64"))]
+    Sigprocmask,
+    /// Writing the address of the frame passed to a handler registered with the kernel, from the
+    /// context itself, restores the state it saved once the write returns.
+    #[cfg(any(target_arch = "aarch64", target_arch = "x86_64"))]
+    Sigreturn,
+    /// Writing zero disables the randomized placement of grants in address spaces the context
+    /// creates afterwards, and a nonzero usize enables it again.
+    Aslr,
+    /// Writing a nonzero usize exempts the context from the W^X policy, which requires
+    /// [`Capabilities::ALLOW_WX`], and zero subjects it to the policy again. Reading returns
+    /// whether it is exempt.
+    AllowWx,
+    /// Reading returns the effective capabilities of the context as a usize bitmask, and writing
+    /// one drops every capability not in it from both the effective and permitted sets, so that it
+    /// cannot be regained.
+    Caps,
+    /// Badness adjustment of the context for the OOM killer, as an isize from -1000, which
+    /// exempts it, to 1000. Lowering it requires [`Capabilities::SYS_RESOURCE`].
+    OomScoreAdj,
+    /// Writing a resource, soft limit and hard limit sets that resource limit. Reading returns
+    /// the soft and hard limit of every resource, indexed by resource.
+    Rlimit,
+    /// Writing a mode and node mask sets the memory policy of the context, which applies to pages
+    /// of address spaces without a policy of their own. Reading returns the mode and node mask.
+    ThreadMemPolicy,
+    /// Reading returns, as u64s, the syscalls, page faults, voluntary and involuntary switches of
+    /// the context, followed by the scheme, bytes read and bytes written for each scheme it used.
+    Stats,
+    /// Writing a first port, a port count and whether to allow access changes the ports the
+    /// context may access, which new threads and children inherit. Reading returns the TSS I/O
+    /// bitmap, where a set bit denies access.
+    #[cfg(target_arch = "x86_64")]
+    IoPerm,
+    /// Writing a code and a value sets or gets the FS or GS base of the context, like `arch_prctl`
+    /// on Linux, see [`crate::context::ARCH_SET_FS`]. Reading returns flags for what userspace may
+    /// do itself, such as [`crate::context::HWCAP2_FSGSBASE`].
+    #[cfg(target_arch = "x86_64")]
+    ArchPrctl,
+    /// Writing a mode, [`crate::mte::MTE_TCF_NONE`] or [`crate::mte::MTE_TCF_SYNC`], and a mask of
+    /// tags for IRG to exclude sets the tag checking of the context. Reading returns both.
+    #[cfg(target_arch = "aarch64")]
+    Mte,
 
     MmapMinAddr(Arc<AddrSpaceWrapper>),
+    /// Size in bytes of the region reserved below new stack grants, for them to grow into.
+    StackWindow(Arc<AddrSpaceWrapper>),
+    MemPolicy(Arc<AddrSpaceWrapper>),
+    /// Writing a nonzero usize opts the address space into page merging, and zero opts it out,
+    /// leaving pages already merged as they are. Reading returns whether it is opted in, followed
+    /// by the number of pages merged so far.
+    Ksm(Arc<AddrSpaceWrapper>),
+    /// Writing a nonzero usize makes executable grants mapped or protected from then on guarded
+    /// pages, for branch target identification, and zero stops it. Reading returns whether it is
+    /// set.
+    #[cfg(target_arch = "aarch64")]
+    Bti(Arc<AddrSpaceWrapper>),
+    /// Reading at an offset collects and clears the dirty bits of the pages starting at that
+    /// address, one bit per page.
+    DirtyBits(Arc<AddrSpaceWrapper>),
+    /// Writing a base address and size, followed by the label, labels the grants in that range.
+    GrantLabel(Arc<AddrSpaceWrapper>),
+    /// Human-readable list of grants, one per line.
+    Maps(Arc<AddrSpaceWrapper>),
+    Userfault {
+        addrspace: Arc<AddrSpaceWrapper>,
+        userfault: Arc<Userfault>,
+    },
 }
 #[derive(Clone)]
 enum Handle {
@@ -197,6 +303,15 @@ impl Handle {
             }
         )
     }
+    fn needs_caps(&self) -> Capabilities {
+        match self {
+            Self::Context {
+                kind: ContextHandle::CpuMax,
+                ..
+            } => Capabilities::SYS_NICE,
+            _ => Capabilities::empty(),
+        }
+    }
 }
 impl Handle {
     fn continue_ignored_children(&mut self) -> Option<()> {
@@ -290,6 +405,8 @@ impl<const FULL: bool> ProcScheme<FULL> {
             "regs/float" => (ContextHandle::Regs(RegsKind::Float), false),
             "regs/int" => (ContextHandle::Regs(RegsKind::Int), false),
             "regs/env" => (ContextHandle::Regs(RegsKind::Env), false),
+            #[cfg(any(target_arch = "aarch64", target_arch = "x86_64"))]
+            "regs/debug" => (ContextHandle::Regs(RegsKind::Debug), false),
             "name" => (ContextHandle::Name, true),
             "sighandler" => (ContextHandle::Sighandler, false),
             "start" => (ContextHandle::Start, false),
@@ -303,7 +420,66 @@ impl<const FULL: bool> ProcScheme<FULL> {
                 )),
                 false,
             ),
+            "stack-window" => (
+                ContextHandle::StackWindow(Arc::clone(
+                    context
+                        .read()
+                        .addr_space()
+                        .map_err(|_| Error::new(ENOENT))?,
+                )),
+                false,
+            ),
+            "mempolicy" => (
+                ContextHandle::MemPolicy(Arc::clone(
+                    context
+                        .read()
+                        .addr_space()
+                        .map_err(|_| Error::new(ENOENT))?,
+                )),
+                false,
+            ),
+            "ksm" => (
+                ContextHandle::Ksm(Arc::clone(
+                    context
+                        .read()
+                        .addr_space()
+                        .map_err(|_| Error::new(ENOENT))?,
+                )),
+                false,
+            ),
+            "maps" => (
+                ContextHandle::Maps(Arc::clone(
+                    context
+                        .read()
+                        .addr_space()
+                        .map_err(|_| Error::new(ENOENT))?,
+                )),
+                true,
+            ),
             "sched-affinity" => (ContextHandle::SchedAffinity, true),
+            "sched-nice" => (ContextHandle::SchedNice, false),
+            "sched-policy" => (ContextHandle::SchedPolicy, false),
+            "cpu-max" => (ContextHandle::CpuMax, false),
+            "scheme-timeout" => (ContextHandle::SchemeTimeout, false),
+            "aslr" => (ContextHandle::Aslr, false),
+            "allow-wx" => (ContextHandle::AllowWx, false),
+            "caps" => (ContextHandle::Caps, false),
+            "oom-score-adj" => (ContextHandle::OomScoreAdj, false),
+            #[cfg(any(target_arch = "aarch64", target_arch = "x86_64"))]
+            "sigaction" => (ContextHandle::Sigaction, false),
+            #[cfg(any(target_arch = "aarch64", target_arch = "x86_64"))]
+            "sigprocmask" => (ContextHandle::Sigprocmask, false),
+            #[cfg(any(target_arch = "aarch64", target_arch = "x86_64"))]
+            "sigreturn" => (ContextHandle::Sigreturn, false),
+            "rlimit" => (ContextHandle::Rlimit, false),
+            "thread-mempolicy" => (ContextHandle::ThreadMemPolicy, false),
+            "stats" => (ContextHandle::Stats, false),
+            #[cfg(target_arch = "x86_64")]
+            "ioperm" => (ContextHandle::IoPerm, false),
+            #[cfg(target_arch = "x86_64")]
+            "arch-prctl" => (ContextHandle::ArchPrctl, false),
+            #[cfg(target_arch = "aarch64")]
+            "mte" => (ContextHandle::Mte, false),
             "status" => (ContextHandle::Status, false),
             "signal" => (ContextHandle::Signal, false),
             _ => return Ok(None),
@@ -394,6 +570,18 @@ impl<const FULL: bool> ProcScheme<FULL> {
             }
         };
 
+        // Children are set up by their parent through this scheme, before being started, which
+        // must be allowed regardless of the ptrace scope.
+        let not_yet_started = match handle {
+            Handle::Context { ref context, .. } => matches!(
+                context.read().status,
+                context::Status::HardBlocked {
+                    reason: HardBlockedReason::NotYetStarted,
+                }
+            ),
+            Handle::Process { .. } => false,
+        };
+
         {
             let target = target.read();
 
@@ -401,35 +589,20 @@ impl<const FULL: bool> ProcScheme<FULL> {
                 return Err(Error::new(ESRCH));
             }
 
-            // Unless root, check security
-            if handle.needs_child_process() && uid != 0 && gid != 0 {
+            // Check security, according to the ptrace scope
+            if handle.needs_child_process() {
                 let current = process::current()?;
                 let current = current.read();
 
-                // Are we the process?
-                if target.pid != current.pid {
-                    // Do we own the process?
-                    if uid != target.euid && gid != target.egid {
-                        return Err(Error::new(EPERM));
-                    }
-
-                    // Is it a subprocess of us? In the future, a capability could
-                    // bypass this check.
-                    match process::ancestors(&*process::PROCESSES.read(), target.ppid)
-                        .find(|&(pid, _context)| pid == current.pid)
-                    {
-                        Some((id, context)) => {
-                            // Paranoid sanity check, as ptrace security holes
-                            // wouldn't be fun
-                            assert_eq!(id, current.pid);
-                            assert_eq!(id, context.read().pid);
-                        }
-                        None => return Err(Error::new(EPERM)),
-                    }
+                // Are we the process, or its parent setting it up?
+                let is_new_child = target.ppid == current.pid && not_yet_started;
+                if target.pid != current.pid && !is_new_child {
+                    ptrace::check_attach(&current, &target, uid, gid)?;
                 }
             } else if handle.needs_root() && (uid != 0 || gid != 0) {
                 return Err(Error::new(EPERM));
             }
+            caps::check(handle.needs_caps())?;
 
             let filetable_opt = match handle {
                 Handle::Context {
@@ -513,6 +686,14 @@ impl<const FULL: bool> KernelScheme for ProcScheme<FULL> {
             OpenTy::Ctxt(new_child()?)
         } else if pid_str == "new-thread" {
             OpenTy::Ctxt(new_thread()?)
+        } else if pid_str == "new-vfork" {
+            OpenTy::Ctxt(new_vfork_child()?)
+        } else if let Some(flags) = pid_str.strip_prefix("new-clone") {
+            let flags = match flags {
+                "" => CloneFlags::empty(),
+                flags => CloneFlags::parse(flags.strip_prefix('=').ok_or(Error::new(ENOENT))?)?,
+            };
+            OpenTy::Ctxt(new_clone(flags)?)
         } else if !FULL {
             return Err(Error::new(EACCES));
         } else {
@@ -554,11 +735,25 @@ impl<const FULL: bool> KernelScheme for ProcScheme<FULL> {
                         new_ip,
                     },
             } => {
+                // A context replacing its own address space is executing a new program, whereas
+                // others are set up by their parent after a fork.
+                let exec = context::is_current(&context);
+                let is_root = process::current()?.read().euid == 0;
                 let _ = try_stop_context(context, |context: &mut Context| {
                     let regs = context.regs_mut().ok_or(Error::new(EBADFD))?;
                     regs.set_instr_pointer(new_ip);
                     regs.set_stack_pointer(new_sp);
 
+                    if exec && let Some(ksig) = context.ksig.as_mut() {
+                        ksig.reset_handlers();
+                    }
+                    if exec && !is_root {
+                        caps::keep(context, Capabilities::empty());
+                    }
+                    #[cfg(any(target_arch = "aarch64", target_arch = "x86_64"))]
+                    if exec {
+                        context.reset_current_for_exec();
+                    }
                     Ok(context.set_addr_space(Some(new)))
                 })?;
                 let _ = ptrace::send_event(crate::syscall::ptrace_event!(
@@ -567,10 +762,34 @@ impl<const FULL: bool> KernelScheme for ProcScheme<FULL> {
                 ));
             }
             Handle::Context {
-                kind: ContextHandle::AddrSpace { addrspace } | ContextHandle::MmapMinAddr(addrspace),
+                kind:
+                    ContextHandle::AddrSpace { addrspace }
+                    | ContextHandle::MmapMinAddr(addrspace)
+                    | ContextHandle::StackWindow(addrspace)
+                    | ContextHandle::MemPolicy(addrspace)
+                    | ContextHandle::Ksm(addrspace)
+                    | ContextHandle::DirtyBits(addrspace)
+                    | ContextHandle::GrantLabel(addrspace)
+                    | ContextHandle::Maps(addrspace),
                 ..
             } => drop(addrspace),
 
+            Handle::Context {
+                kind:
+                    ContextHandle::Userfault {
+                        addrspace,
+                        userfault,
+                    },
+                ..
+            } => {
+                // Faults in the unregistered ranges are resolved by the kernel from now on.
+                let mut guard = addrspace.acquire_write();
+                guard
+                    .userfault
+                    .retain(|(_, other)| !Arc::ptr_eq(other, &userfault));
+                userfault.resolved.notify();
+            }
+
             Handle::Context {
                 kind: ContextHandle::AwaitingFiletableChange { new_ft },
                 context,
@@ -691,7 +910,9 @@ impl<const FULL: bool> KernelScheme for ProcScheme<FULL> {
         };
 
         match handle {
-            Handle::Context { context, kind } => kind.kreadoff(id, context, buf, offset),
+            Handle::Context { context, kind } => {
+                kind.kreadoff(id, context, buf, offset, read_flags)
+            }
             Handle::Process { process, kind } => {
                 kind.kreadoff(id, process, buf, offset, read_flags)
             }
@@ -747,6 +968,8 @@ impl<const FULL: bool> KernelScheme for ProcScheme<FULL> {
                     ContextHandle::Regs(RegsKind::Float) => "regs/float",
                     ContextHandle::Regs(RegsKind::Int) => "regs/int",
                     ContextHandle::Regs(RegsKind::Env) => "regs/env",
+                    #[cfg(any(target_arch = "aarch64", target_arch = "x86_64"))]
+                    ContextHandle::Regs(RegsKind::Debug) => "regs/debug",
                     ContextHandle::Name => "name",
                     ContextHandle::Sighandler => "sighandler",
                     ContextHandle::Filetable { .. } => "filetable",
@@ -755,7 +978,39 @@ impl<const FULL: bool> KernelScheme for ProcScheme<FULL> {
                     ContextHandle::CurrentFiletable => "current-filetable",
                     ContextHandle::OpenViaDup => "open-via-dup",
                     ContextHandle::MmapMinAddr(_) => "mmap-min-addr",
+                    ContextHandle::StackWindow(_) => "stack-window",
+                    ContextHandle::MemPolicy(_) => "mempolicy",
+                    ContextHandle::Ksm(_) => "ksm",
+                    #[cfg(target_arch = "aarch64")]
+                    ContextHandle::Bti(_) => "bti",
+                    ContextHandle::DirtyBits(_) => "dirty",
+                    ContextHandle::GrantLabel(_) => "label",
+                    ContextHandle::Maps(_) => "maps",
+                    ContextHandle::Userfault { .. } => "userfault",
                     ContextHandle::SchedAffinity => "sched-affinity",
+                    ContextHandle::SchedNice => "sched-nice",
+                    ContextHandle::SchedPolicy => "sched-policy",
+                    ContextHandle::CpuMax => "cpu-max",
+                    ContextHandle::SchemeTimeout => "scheme-timeout",
+                    ContextHandle::Aslr => "aslr",
+                    ContextHandle::AllowWx => "allow-wx",
+                    ContextHandle::Caps => "caps",
+                    ContextHandle::OomScoreAdj => "oom-score-adj",
+                    #[cfg(any(target_arch = "aarch64", target_arch = "x86_64"))]
+                    ContextHandle::Sigaction => "sigaction",
+                    #[cfg(any(target_arch = "aarch64", target_arch = "x86_64"))]
+                    ContextHandle::Sigprocmask => "sigprocmask",
+                    #[cfg(any(target_arch = "aarch64", target_arch = "x86_64"))]
+                    ContextHandle::Sigreturn => "sigreturn",
+                    ContextHandle::Rlimit => "rlimit",
+                    ContextHandle::ThreadMemPolicy => "thread-mempolicy",
+                    ContextHandle::Stats => "stats",
+                    #[cfg(target_arch = "x86_64")]
+                    ContextHandle::IoPerm => "ioperm",
+                    #[cfg(target_arch = "x86_64")]
+                    ContextHandle::ArchPrctl => "arch-prctl",
+                    #[cfg(target_arch = "aarch64")]
+                    ContextHandle::Mte => "mte",
 
                     _ => return Err(Error::new(EOPNOTSUPP)),
                 }
@@ -869,13 +1124,32 @@ impl<const FULL: bool> KernelScheme for ProcScheme<FULL> {
                 let kind = match buf {
                     // TODO: Better way to obtain new empty address spaces, perhaps using SYS_OPEN. But
                     // in that case, what scheme?
-                    b"empty" => ContextHandle::AddrSpace {
-                        addrspace: AddrSpaceWrapper::new()?,
-                    },
+                    b"empty" => {
+                        let addrspace = AddrSpaceWrapper::new()?;
+                        if !context.read().aslr {
+                            let mut guard = addrspace.acquire_write();
+                            guard.mmap_base = guard.mmap_min;
+                        }
+                        ContextHandle::AddrSpace { addrspace }
+                    }
                     b"exclusive" => ContextHandle::AddrSpace {
                         addrspace: addrspace.try_clone()?,
                     },
                     b"mmap-min-addr" => ContextHandle::MmapMinAddr(Arc::clone(addrspace)),
+                    b"stack-window" => ContextHandle::StackWindow(Arc::clone(addrspace)),
+                    b"mempolicy" => ContextHandle::MemPolicy(Arc::clone(addrspace)),
+                    b"ksm" => ContextHandle::Ksm(Arc::clone(addrspace)),
+                    #[cfg(target_arch = "aarch64")]
+                    b"bti" if crate::pauth::bti_supported() => {
+                        ContextHandle::Bti(Arc::clone(addrspace))
+                    }
+                    b"dirty" => ContextHandle::DirtyBits(Arc::clone(addrspace)),
+                    b"label" => ContextHandle::GrantLabel(Arc::clone(addrspace)),
+                    b"maps" => ContextHandle::Maps(Arc::clone(addrspace)),
+                    b"userfault" => ContextHandle::Userfault {
+                        addrspace: Arc::clone(addrspace),
+                        userfault: Userfault::new()?,
+                    },
 
                     _ if buf.starts_with(GRANT_FD_PREFIX) => {
                         let string = core::str::from_utf8(&buf[GRANT_FD_PREFIX.len()..])
@@ -920,9 +1194,34 @@ extern "C" fn clone_handler() {
     // usermode.
 }
 
+/// Copy everything a new thread or child inherits from `current` to `new_context`.
+fn inherit(new_context: &mut Context, current: &Context) {
+    new_context.group = current.group.clone();
+    new_context.memcg = current.memcg.clone();
+    new_context.nice = current.nice;
+    new_context.sched_policy = current.sched_policy;
+    new_context.rlimits = current.rlimits.inherit();
+    new_context.mempolicy = current.mempolicy;
+    new_context.aslr = current.aslr;
+    new_context.allow_wx = current.allow_wx;
+    new_context.caps = current.caps;
+    new_context.caps_permitted = current.caps_permitted;
+    new_context.oom_score_adj = current.oom_score_adj;
+    new_context.ksig = current.ksig.as_ref().map(|ksig| Box::new(ksig.inherit()));
+    #[cfg(any(target_arch = "aarch64", target_arch = "x86_64"))]
+    new_context.arch.inherit(&current.arch);
+}
+
 fn new_thread() -> Result<Arc<RwSpinlock<Context>>> {
     let current_process = process::current()?;
-    context::spawn(true, current_process, clone_handler)
+    let new_context = context::spawn(true, current_process, clone_handler)?;
+    {
+        let current = context::current();
+        let current = current.read();
+        inherit(&mut new_context.write(), &current);
+    }
+
+    Ok(new_context)
 }
 
 fn new_child() -> Result<Arc<RwSpinlock<Context>>> {
@@ -935,6 +1234,11 @@ fn new_child() -> Result<Arc<RwSpinlock<Context>>> {
         })?;
         context::spawn(true, new_process, clone_handler)?
     };
+    {
+        let current = context::current();
+        let current = current.read();
+        inherit(&mut new_context.write(), &current);
+    }
 
     if ptrace::send_event(crate::syscall::ptrace_event!(
         PTRACE_EVENT_CLONE,
@@ -952,6 +1256,182 @@ fn new_child() -> Result<Arc<RwSpinlock<Context>>> {
 
     Ok(new_context)
 }
+/// Create a child that borrows the current address space instead of copying it. Starting the
+/// child blocks the caller until the child has switched to a new address space or exited.
+fn new_vfork_child() -> Result<Arc<RwSpinlock<Context>>> {
+    let addr_space = Arc::clone(context::current().read().addr_space()?);
+    let vfork_done = Arc::try_new(WaitCondition::new()).map_err(|_| Error::new(ENOMEM))?;
+
+    let new_context = new_child()?;
+    {
+        let mut context = new_context.write();
+        let _ = context.set_addr_space(Some(addr_space));
+        context.vfork_done = Some(vfork_done);
+    }
+
+    Ok(new_context)
+}
+bitflags! {
+    /// What a context opened with `proc:new-clone=<flags>` shares with the caller, as a comma
+    /// separated list of the names below. Everything else is copied.
+    ///
+    /// The signal actions of contexts handling their signals in userspace live in the process
+    /// control page of the address space, and are thus shared exactly when it is, and the working
+    /// directory is kept by userspace.
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub struct CloneFlags: u32 {
+        /// Share the address space rather than copying it.
+        const VM = 1 << 0;
+        /// Share the file table rather than copying it.
+        const FILES = 1 << 1;
+        /// Share the signal actions registered with the kernel rather than copying them, which
+        /// requires `VM`.
+        const SIGHAND = 1 << 2;
+        /// Create a thread of the current process rather than a child process, which requires
+        /// `VM` and `SIGHAND`.
+        const THREAD = 1 << 3;
+        /// Block the caller when starting the child, until it replaces the shared address space or
+        /// exits, which requires `VM`.
+        const VFORK = 1 << 4;
+        /// Give the child a new scheme namespace with the schemes of the caller, rather than
+        /// sharing its namespace, so that the schemes it registers or removes are its own. This
+        /// requires the `NAMESPACE` capability.
+        const NEWNS = 1 << 5;
+    }
+}
+
+impl CloneFlags {
+    const NAMES: [(&'static str, Self); 6] = [
+        ("vm", Self::VM),
+        ("files", Self::FILES),
+        ("sighand", Self::SIGHAND),
+        ("thread", Self::THREAD),
+        ("vfork", Self::VFORK),
+        ("newns", Self::NEWNS),
+    ];
+
+    pub fn parse(names: &str) -> Result<Self> {
+        let mut flags = Self::empty();
+        for name in names.split(',').filter(|name| !name.is_empty()) {
+            let (_, flag) = Self::NAMES
+                .iter()
+                .find(|(other, _)| *other == name)
+                .ok_or(Error::new(EINVAL))?;
+            flags |= *flag;
+        }
+        flags.validate()?;
+        Ok(flags)
+    }
+
+    fn validate(self) -> Result<()> {
+        let requires = |flag: Self, required: Self| !self.contains(flag) || self.contains(required);
+        let valid = requires(Self::SIGHAND, Self::VM)
+            && requires(Self::THREAD, Self::VM | Self::SIGHAND)
+            && requires(Self::VFORK, Self::VM)
+            && !self.contains(Self::THREAD | Self::VFORK)
+            && !self.contains(Self::THREAD | Self::NEWNS);
+        if valid {
+            Ok(())
+        } else {
+            Err(Error::new(EINVAL))
+        }
+    }
+}
+
+/// What a clone gets from the current context, each part shared with it or copied as the
+/// [`CloneFlags`] say.
+pub struct CloneParts {
+    pub addr_space: Arc<AddrSpaceWrapper>,
+    pub files: Arc<RwLock<Vec<Option<FileDescriptor>>>>,
+    pub ksig: Option<Box<KernelSignals>>,
+    /// The new namespace with `NEWNS`, otherwise the clone stays in that of its process.
+    pub namespace: Option<SchemeNamespace>,
+}
+
+impl CloneParts {
+    /// Share or copy the parts of a context, with `ksig` already shared with it and `ens` being
+    /// the namespace of its process. Whether the caller may create a namespace is left to it.
+    pub fn new(
+        flags: CloneFlags,
+        addr_space: Arc<AddrSpaceWrapper>,
+        files: Arc<RwLock<Vec<Option<FileDescriptor>>>>,
+        ksig: Option<KernelSignals>,
+        ens: SchemeNamespace,
+    ) -> Result<Self> {
+        let namespace = if flags.contains(CloneFlags::NEWNS) {
+            Some(scheme::schemes_mut().clone_ns(ens)?)
+        } else {
+            None
+        };
+        let addr_space = if flags.contains(CloneFlags::VM) {
+            addr_space
+        } else {
+            addr_space.try_clone()?
+        };
+        let files = if flags.contains(CloneFlags::FILES) {
+            files
+        } else {
+            let copy = files.read().clone();
+            Arc::try_new(RwLock::new(copy)).map_err(|_| Error::new(ENOMEM))?
+        };
+        let ksig = ksig.map(|ksig| {
+            Box::new(if flags.contains(CloneFlags::SIGHAND) {
+                ksig
+            } else {
+                ksig.inherit()
+            })
+        });
+
+        Ok(Self {
+            addr_space,
+            files,
+            ksig,
+            namespace,
+        })
+    }
+}
+
+/// Create a context sharing the components in `flags` with the current one, and copying the
+/// others.
+fn new_clone(flags: CloneFlags) -> Result<Arc<RwSpinlock<Context>>> {
+    // Checked first, as it is the only step that can fail for lack of permission.
+    if flags.contains(CloneFlags::NEWNS) && !caps::has(Capabilities::NAMESPACE) {
+        return Err(Error::new(EACCES));
+    }
+
+    let (addr_space, files, ksig) = {
+        let current = context::current();
+        let current = current.read();
+        (
+            Arc::clone(current.addr_space()?),
+            Arc::clone(&current.files),
+            current.ksig.as_ref().map(|ksig| ksig.share()),
+        )
+    };
+    let ens = process::current()?.read().ens;
+    let parts = CloneParts::new(flags, addr_space, files, ksig, ens)?;
+
+    let new_context = if flags.contains(CloneFlags::THREAD) {
+        new_thread()?
+    } else if flags.contains(CloneFlags::VFORK) {
+        new_vfork_child()?
+    } else {
+        new_child()?
+    };
+    {
+        let mut context = new_context.write();
+        let _ = context.set_addr_space(Some(parts.addr_space));
+        context.files = parts.files;
+        context.ksig = parts.ksig;
+        if let Some(namespace) = parts.namespace {
+            let mut process = context.process.write();
+            process.info.rns = namespace;
+            process.info.ens = namespace;
+        }
+    }
+
+    Ok(new_context)
+}
 fn extract_scheme_number(fd: usize) -> Result<(KernelSchemes, usize)> {
     let (scheme_id, number) = match &*context::current()
         .read()
@@ -1061,7 +1541,7 @@ impl ProcHandle {
                     .map_err(|_| Error::new(EINVAL))?;
 
                 match attr {
-                    Attr::Uid => process.write().euid = id,
+                    Attr::Uid => caps::set_euid(&mut process.write(), id),
                     Attr::Gid => process.write().egid = id,
                 }
                 Ok(buf.len())
@@ -1199,6 +1679,7 @@ impl ContextHandle {
                         if !flags.contains(MapFlags::MAP_FIXED) {
                             return Err(Error::new(EOPNOTSUPP));
                         }
+                        check_wx("mmap", page, flags)?;
 
                         let (scheme, number) = extract_scheme_number(fd)?;
 
@@ -1228,6 +1709,102 @@ impl ContextHandle {
 
                         addrspace.mprotect(PageSpan::new(page, page_count), flags)?;
                     }
+                    ADDRSPACE_OP_MKEXEC => {
+                        let (page, page_count) =
+                            crate::syscall::validate_region(next()??, next()??)?;
+
+                        addrspace.make_executable(PageSpan::new(page, page_count))?;
+                    }
+                    ADDRSPACE_OP_MSYNC => {
+                        let (page, page_count) =
+                            crate::syscall::validate_region(next()??, next()??)?;
+                        let flags = next()??;
+
+                        writeback::msync(&addrspace, PageSpan::new(page, page_count), flags)?;
+                    }
+                    ADDRSPACE_OP_MADVISE => {
+                        let (page, page_count) =
+                            crate::syscall::validate_region(next()??, next()??)?;
+                        let advice = next()??;
+
+                        addrspace.madvise(PageSpan::new(page, page_count), advice)?;
+                    }
+                    ADDRSPACE_OP_MLOCK => {
+                        let (page, page_count) =
+                            crate::syscall::validate_region(next()??, next()??)?;
+
+                        addrspace.mlock(PageSpan::new(page, page_count))?;
+                    }
+                    ADDRSPACE_OP_MUNLOCK => {
+                        let (page, page_count) =
+                            crate::syscall::validate_region(next()??, next()??)?;
+
+                        addrspace.munlock(PageSpan::new(page, page_count))?;
+                    }
+                    #[cfg(target_arch = "aarch64")]
+                    crate::context::memory::ADDRSPACE_OP_MTE => {
+                        let (page, page_count) =
+                            crate::syscall::validate_region(next()??, next()??)?;
+
+                        addrspace.set_tagged(PageSpan::new(page, page_count))?;
+                    }
+                    _ => return Err(Error::new(EINVAL)),
+                }
+                Ok(words_read * mem::size_of::<usize>())
+            }
+            Self::Userfault {
+                addrspace,
+                userfault,
+            } => {
+                let mut chunks = buf.usizes();
+                let mut words_read = 0;
+                let mut next = || {
+                    words_read += 1;
+                    chunks.next().ok_or(Error::new(EINVAL))
+                };
+
+                match next()?? {
+                    USERFAULT_REGISTER => {
+                        let (page, page_count) =
+                            crate::syscall::validate_region(next()??, next()??)?;
+                        addrspace
+                            .acquire_write()
+                            .userfault
+                            .push((PageSpan::new(page, page_count), userfault));
+                    }
+                    USERFAULT_UNREGISTER => {
+                        let (page, page_count) =
+                            crate::syscall::validate_region(next()??, next()??)?;
+                        let span = PageSpan::new(page, page_count);
+
+                        let mut guard = addrspace.acquire_write();
+                        guard.userfault.retain(|(other_span, other)| {
+                            !(Arc::ptr_eq(other, &userfault) && other_span.intersects(span))
+                        });
+                        userfault.resolved.notify();
+                    }
+                    USERFAULT_COPY => {
+                        let dst = next()??;
+                        let src = next()??;
+                        let (page, page_count) = crate::syscall::validate_region(dst, next()??)?;
+
+                        for (i, page) in PageSpan::new(page, page_count).pages().enumerate() {
+                            let src = UserSliceRo::ro(src + i * PAGE_SIZE, PAGE_SIZE)?;
+                            addrspace.userfault_fill(&userfault, page, Some(src))?;
+                        }
+                    }
+                    USERFAULT_ZEROPAGE => {
+                        let (page, page_count) =
+                            crate::syscall::validate_region(next()??, next()??)?;
+
+                        for page in PageSpan::new(page, page_count).pages() {
+                            addrspace.userfault_fill(&userfault, page, None)?;
+                        }
+                    }
+                    USERFAULT_WAKE => {
+                        let _guard = addrspace.acquire_write();
+                        userfault.resolved.notify();
+                    }
                     _ => return Err(Error::new(EINVAL)),
                 }
                 Ok(words_read * mem::size_of::<usize>())
@@ -1270,6 +1847,16 @@ impl ContextHandle {
                     write_env_regs(context, regs)?;
                     Ok(mem::size_of::<EnvRegisters>())
                 }
+                #[cfg(any(target_arch = "aarch64", target_arch = "x86_64"))]
+                RegsKind::Debug => {
+                    let regs = unsafe { buf.read_exact::<DebugRegisters>()? };
+                    if context::is_current(&context) {
+                        context::current().write().write_current_debug_regs(regs)?;
+                    } else {
+                        try_stop_context(context, |context| context.write_debug_regs(regs))?;
+                    }
+                    Ok(mem::size_of::<DebugRegisters>())
+                }
             },
             ContextHandle::Name => {
                 // TODO: What limit?
@@ -1335,15 +1922,31 @@ impl ContextHandle {
 
                 Ok(mem::size_of::<SetSighandlerData>())
             }
-            ContextHandle::Start => match context.write().status {
-                ref mut status @ Status::HardBlocked {
-                    reason: HardBlockedReason::NotYetStarted,
-                } => {
-                    *status = Status::Runnable;
-                    Ok(buf.len())
+            ContextHandle::Start => {
+                let vfork_done = {
+                    let mut context = context.write();
+                    match context.status {
+                        ref mut status @ Status::HardBlocked {
+                            reason: HardBlockedReason::NotYetStarted,
+                        } => *status = Status::Runnable,
+                        _ => return Err(Error::new(EINVAL)),
+                    }
+                    context.vfork_done.clone()
+                };
+
+                // A vfork child borrows our address space, so wait until it has either replaced
+                // it or exited. The child's lock is held while registering as a waiter, so the
+                // notification cannot be missed.
+                if let Some(vfork_done) = vfork_done {
+                    loop {
+                        let guard = context.write();
+                        if guard.vfork_done.is_none() || !vfork_done.wait(guard, "vfork") {
+                            break;
+                        }
+                    }
                 }
-                _ => return Err(Error::new(EINVAL)),
-            },
+                Ok(buf.len())
+            }
             ContextHandle::Filetable { .. } | ContextHandle::NewFiletable { .. } => {
                 Err(Error::new(EBADF))
             }
@@ -1429,13 +2032,347 @@ impl ContextHandle {
                 addrspace.acquire_write().mmap_min = val;
                 Ok(mem::size_of::<usize>())
             }
+            Self::StackWindow(ref addrspace) => {
+                let val = buf.read_usize()?;
+                if val % PAGE_SIZE != 0 || val > crate::USER_END_OFFSET {
+                    return Err(Error::new(EINVAL));
+                }
+                addrspace.acquire_write().stack_window = val / PAGE_SIZE;
+                Ok(mem::size_of::<usize>())
+            }
+            Self::GrantLabel(ref addrspace) => {
+                let len = buf.len();
+                let (header, label) = buf
+                    .split_at(2 * mem::size_of::<usize>())
+                    .ok_or(Error::new(EINVAL))?;
+                let mut args = header.usizes();
+                let base = args.next().ok_or(Error::new(EINVAL))??;
+                let size = args.next().ok_or(Error::new(EINVAL))??;
+                let (page, page_count) = crate::syscall::validate_region(base, size)?;
+
+                if label.len() > GRANT_LABEL_MAX {
+                    return Err(Error::new(ENAMETOOLONG));
+                }
+                let mut bytes = [0_u8; GRANT_LABEL_MAX];
+                let bytes = &mut bytes[..label.len()];
+                label.copy_to_slice(bytes)?;
+                let label = str::from_utf8(bytes).map_err(|_| Error::new(EINVAL))?;
+
+                // An empty label removes any existing one.
+                addrspace.set_label(
+                    PageSpan::new(page, page_count),
+                    (!label.is_empty()).then(|| Arc::from(label)),
+                )?;
+                Ok(len)
+            }
+            Self::MemPolicy(ref addrspace) => {
+                let mut args = buf.usizes();
+                let mode = args.next().ok_or(Error::new(EINVAL))??;
+                let nodes = args.next().ok_or(Error::new(EINVAL))??;
+
+                let policy = numa::MemPolicy::from_raw(mode, nodes as u64)?;
+                addrspace.acquire_write().mempolicy = policy;
+
+                // Let the policy decide where the context is placed, until enough memory has been
+                // allocated under it.
+                let home = match policy {
+                    numa::MemPolicy::Bind(nodes) => nodes.first(),
+                    numa::MemPolicy::Preferred(node) => Some(node),
+                    numa::MemPolicy::Default | numa::MemPolicy::Interleave(_) => None,
+                };
+                if home.is_some() {
+                    addrspace.home_node.set(home);
+                }
+                Ok(2 * mem::size_of::<usize>())
+            }
+            #[cfg(target_arch = "aarch64")]
+            Self::Bti(ref addrspace) => {
+                addrspace.acquire_write().bti = buf.read_usize()? != 0;
+                Ok(mem::size_of::<usize>())
+            }
+            Self::Ksm(ref addrspace) => {
+                let enable = buf.read_usize()? != 0;
+                {
+                    let mut guard = addrspace.acquire_write();
+                    match (enable, &guard.ksm) {
+                        (true, None) => guard.ksm = Some(KsmState::default()),
+                        (false, Some(_)) => guard.ksm = None,
+                        _ => (),
+                    }
+                }
+                if enable {
+                    ksm::wake();
+                }
+                Ok(mem::size_of::<usize>())
+            }
             Self::SchedAffinity => {
                 let mask = unsafe { buf.read_exact::<crate::cpu_set::RawMask>()? };
 
-                context.write().sched_affinity.override_from(&mask);
+                // The context could never run again if no online CPU is allowed.
+                let mut affinity = crate::cpu_set::LogicalCpuSet::empty();
+                affinity.override_from(&mask);
+                if !affinity.iter_mut().any(|id| id.get() < crate::cpu_count()) {
+                    return Err(Error::new(EINVAL));
+                }
+
+                if let Some(cpu_id) = context.write().set_sched_affinity(&mask) {
+                    // The context is running on a CPU it is no longer allowed on.
+                    if cpu_id == crate::cpu_id() {
+                        context::switch();
+                    } else {
+                        crate::ipi::ipi_single(crate::ipi::IpiKind::Switch, cpu_id);
+                    }
+                }
 
                 Ok(mem::size_of_val(&mask))
             }
+            Self::SchedNice => {
+                let nice = buf.read_usize()? as isize;
+                if !(context::switch::NICE_MIN as isize..=context::switch::NICE_MAX as isize)
+                    .contains(&nice)
+                {
+                    return Err(Error::new(EINVAL));
+                }
+                let nice = nice as context::switch::Nice;
+
+                // Checked before locking the context, which may be the current one.
+                let privileged = caps::has(Capabilities::SYS_NICE);
+                let mut context = context.write();
+                if nice < context.nice && !privileged {
+                    return Err(Error::new(EPERM));
+                }
+                context.nice = nice;
+
+                // A runnable context that was given a higher priority may now preempt the one
+                // running on its CPU.
+                if !context.running
+                    && context.status.is_runnable()
+                    && let Some(cpu_id) = context.cpu_id
+                {
+                    context::switch::preempt_for(cpu_id, &context);
+                }
+
+                Ok(mem::size_of::<usize>())
+            }
+            Self::SchedPolicy => {
+                let mut args = buf.usizes();
+                let policy = args.next().ok_or(Error::new(EINVAL))??;
+                let priority = args.next().ok_or(Error::new(EINVAL))??;
+                let policy = rt::SchedPolicy::from_raw(policy, priority)?;
+                if policy != rt::SchedPolicy::Normal && !caps::has(Capabilities::SYS_NICE) {
+                    return Err(Error::new(EPERM));
+                }
+
+                let mut context = context.write();
+                context.sched_policy = policy;
+
+                if !context.running
+                    && context.status.is_runnable()
+                    && let Some(cpu_id) = context.cpu_id
+                {
+                    context::switch::preempt_for(cpu_id, &context);
+                }
+
+                Ok(2 * mem::size_of::<usize>())
+            }
+            Self::CpuMax => {
+                let mut args = buf.usizes();
+                let quota = args.next().ok_or(Error::new(EINVAL))??;
+                let period = args.next().ok_or(Error::new(EINVAL))??;
+
+                let quota = if quota == usize::MAX {
+                    group::QUOTA_MAX
+                } else {
+                    quota as u64
+                };
+
+                let group = ContextGroup::new()?;
+                group.cpu.set_limit(quota, period as u64)?;
+                context.write().group = Some(group);
+
+                Ok(2 * mem::size_of::<usize>())
+            }
+            #[cfg(target_arch = "x86_64")]
+            Self::IoPerm => {
+                let mut args = buf.usizes();
+                let from = args.next().ok_or(Error::new(EINVAL))??;
+                let count = args.next().ok_or(Error::new(EINVAL))??;
+                let allowed = args.next().ok_or(Error::new(EINVAL))?? != 0;
+
+                if allowed {
+                    caps::check(Capabilities::IO_PORT)?;
+                    crate::lockdown::check(crate::lockdown::Reason::PortIo)?;
+                }
+                context.write().set_io_permission(from, count, allowed)?;
+
+                Ok(3 * mem::size_of::<usize>())
+            }
+            #[cfg(target_arch = "x86_64")]
+            Self::ArchPrctl => {
+                use crate::context::{
+                    ARCH_GET_FS, ARCH_GET_GS, ARCH_SET_FS, ARCH_SET_GS, ARCH_SHSTK_DISABLE,
+                    ARCH_SHSTK_ENABLE, ARCH_SHSTK_LOCK, ARCH_SHSTK_STATUS,
+                };
+
+                let mut args = buf.usizes();
+                let code = args.next().ok_or(Error::new(EINVAL))??;
+                let value = args.next().ok_or(Error::new(EINVAL))??;
+
+                match code {
+                    ARCH_SHSTK_ENABLE | ARCH_SHSTK_DISABLE | ARCH_SHSTK_LOCK => {
+                        write_cet(context, code, value)?;
+                        return Ok(2 * mem::size_of::<usize>());
+                    }
+                    ARCH_SHSTK_STATUS => {
+                        let features = context.read().arch.cet.features();
+                        UserSliceWo::new(value, mem::size_of::<usize>())?.write_usize(features)?;
+                        return Ok(2 * mem::size_of::<usize>());
+                    }
+                    _ => (),
+                }
+
+                let mut regs = read_env_regs(Arc::clone(&context))?;
+                match code {
+                    ARCH_SET_FS => regs.fsbase = value as u64,
+                    ARCH_SET_GS => regs.gsbase = value as u64,
+                    ARCH_GET_FS | ARCH_GET_GS => {
+                        let base = if code == ARCH_GET_FS {
+                            regs.fsbase
+                        } else {
+                            regs.gsbase
+                        };
+                        UserSliceWo::new(value, mem::size_of::<usize>())?
+                            .write_usize(base as usize)?;
+                        return Ok(2 * mem::size_of::<usize>());
+                    }
+                    _ => return Err(Error::new(EINVAL)),
+                }
+                write_env_regs(context, regs)?;
+
+                Ok(2 * mem::size_of::<usize>())
+            }
+            #[cfg(target_arch = "aarch64")]
+            Self::Mte => {
+                let mut args = buf.usizes();
+                let mode = args.next().ok_or(Error::new(EINVAL))??;
+                let exclude = args.next().ok_or(Error::new(EINVAL))??;
+                let state = crate::mte::MteState::from_user(mode, exclude)?;
+
+                if context::is_current(&context) {
+                    context::current().write().write_current_mte(state);
+                } else {
+                    try_stop_context(context, |context| {
+                        context.write_mte(state);
+                        Ok(())
+                    })?;
+                }
+                Ok(2 * mem::size_of::<usize>())
+            }
+            Self::SchemeTimeout => {
+                let nanos = buf.read_usize()?;
+                context.write().scheme_timeout = (nanos != 0).then_some(nanos as u128);
+                Ok(mem::size_of::<usize>())
+            }
+            Self::Aslr => {
+                context.write().aslr = buf.read_usize()? != 0;
+                Ok(mem::size_of::<usize>())
+            }
+            Self::AllowWx => {
+                let allow = buf.read_usize()? != 0;
+                if allow && !caps::has(Capabilities::ALLOW_WX) {
+                    return Err(Error::new(EPERM));
+                }
+                context.write().allow_wx = allow;
+                Ok(mem::size_of::<usize>())
+            }
+            Self::Caps => {
+                let keep = Capabilities::from_bits_truncate(buf.read_usize()? as u32);
+                caps::keep(&mut context.write(), keep);
+                Ok(mem::size_of::<usize>())
+            }
+            Self::OomScoreAdj => {
+                let adj = buf.read_usize()? as isize;
+                if !(OOM_SCORE_ADJ_MIN as isize..=OOM_SCORE_ADJ_MAX as isize).contains(&adj) {
+                    return Err(Error::new(EINVAL));
+                }
+                let adj = adj as i16;
+
+                let privileged = caps::has(Capabilities::SYS_RESOURCE);
+                let mut context = context.write();
+                if adj < context.oom_score_adj && !privileged {
+                    return Err(Error::new(EPERM));
+                }
+                context.oom_score_adj = adj;
+                Ok(mem::size_of::<usize>())
+            }
+            #[cfg(any(target_arch = "aarch64", target_arch = "x86_64"))]
+            Self::Sigaction => {
+                let mut args = buf.usizes();
+                let sig = args.next().ok_or(Error::new(EINVAL))??;
+                let action = SigAction {
+                    handler: args.next().ok_or(Error::new(EINVAL))??,
+                    mask: args.next().ok_or(Error::new(EINVAL))?? as u64,
+                    flags: args.next().ok_or(Error::new(EINVAL))??,
+                    restorer: args.next().ok_or(Error::new(EINVAL))??,
+                };
+
+                let mut context = context.write();
+                if context.sig.is_some() {
+                    return Err(Error::new(EBUSY));
+                }
+                let ksig = context
+                    .ksig
+                    .get_or_insert_with(|| Box::new(KernelSignals::new()));
+                if !ksig.set_action(sig, action) {
+                    return Err(Error::new(EINVAL));
+                }
+                Ok(5 * mem::size_of::<usize>())
+            }
+            #[cfg(any(target_arch = "aarch64", target_arch = "x86_64"))]
+            Self::Sigprocmask => {
+                let mut args = buf.usizes();
+                let how = args.next().ok_or(Error::new(EINVAL))??;
+                let mask = args.next().ok_or(Error::new(EINVAL))?? as u64;
+
+                let mut context = context.write();
+                let ksig = context.ksig.as_mut().ok_or(Error::new(EINVAL))?;
+                if !ksig.set_blocked(how, mask) {
+                    return Err(Error::new(EINVAL));
+                }
+                // Signals it unblocked are delivered when the write returns.
+                Ok(2 * mem::size_of::<usize>())
+            }
+            #[cfg(any(target_arch = "aarch64", target_arch = "x86_64"))]
+            Self::Sigreturn => {
+                if !context::is_current(&context) {
+                    return Err(Error::new(EPERM));
+                }
+                let frame = signal::read_frame(buf.read_usize()?)?;
+                context
+                    .write()
+                    .ksig
+                    .as_mut()
+                    .ok_or(Error::new(EINVAL))?
+                    .sigreturn = Some(frame);
+                Ok(mem::size_of::<usize>())
+            }
+            Self::Rlimit => {
+                let mut args = buf.usizes();
+                let resource = args.next().ok_or(Error::new(EINVAL))??;
+                let cur = args.next().ok_or(Error::new(EINVAL))??;
+                let max = args.next().ok_or(Error::new(EINVAL))??;
+
+                rlimit::set(&context, resource, Rlimit { cur, max })?;
+                Ok(3 * mem::size_of::<usize>())
+            }
+            Self::ThreadMemPolicy => {
+                let mut args = buf.usizes();
+                let mode = args.next().ok_or(Error::new(EINVAL))??;
+                let nodes = args.next().ok_or(Error::new(EINVAL))??;
+
+                numa::set_context_policy(&context, numa::MemPolicy::from_raw(mode, nodes as u64)?);
+                Ok(2 * mem::size_of::<usize>())
+            }
             ContextHandle::Status => {
                 let mut args = buf.usizes();
 
@@ -1487,7 +2424,7 @@ impl ContextHandle {
                     Ok(mem::size_of::<usize>())
                 }
             }
-            ContextHandle::Signal => {
+            ContextHandle::Signal if buf.len() == 4 => {
                 let me = {
                     let p = process::current()?;
                     let p = p.read();
@@ -1512,6 +2449,25 @@ impl ContextHandle {
                     Ok(4)
                 }
             }
+            ContextHandle::Signal => {
+                let (header, info) = buf
+                    .split_at(2 * mem::size_of::<usize>())
+                    .ok_or(Error::new(EINVAL))?;
+                let mut words = header.usizes();
+                let mut next = || words.next().ok_or(Error::new(EINVAL));
+                let sig = next()??;
+                let tgid = ProcessId::new(next()??);
+
+                let mode = match info.len() {
+                    0 => KillMode::Idempotent,
+                    len if len == mem::size_of::<RtSigInfo>() => {
+                        KillMode::Queued(unsafe { info.read_exact::<RtSigInfo>()? })
+                    }
+                    _ => return Err(Error::new(EINVAL)),
+                };
+                crate::syscall::process::tgkill(tgid, context, sig, mode)?;
+                Ok(buf.len())
+            }
             Self::OpenViaDup
             | Self::AwaitingAddrSpaceChange { .. }
             | Self::AwaitingFiletableChange { .. } => Err(Error::new(EBADF)),
@@ -1523,6 +2479,7 @@ impl ContextHandle {
         context: Arc<RwSpinlock<Context>>,
         buf: UserSliceWo,
         offset: u64,
+        read_flags: u32,
     ) -> Result<usize> {
         match self {
             ContextHandle::Regs(kind) => {
@@ -1530,6 +2487,8 @@ impl ContextHandle {
                     float: FloatRegisters,
                     int: IntRegisters,
                     env: EnvRegisters,
+                    #[cfg(any(target_arch = "aarch64", target_arch = "x86_64"))]
+                    debug: DebugRegisters,
                 }
 
                 let (output, size) = match kind {
@@ -1566,6 +2525,13 @@ impl ContextHandle {
                         },
                         mem::size_of::<EnvRegisters>(),
                     ),
+                    #[cfg(any(target_arch = "aarch64", target_arch = "x86_64"))]
+                    RegsKind::Debug => (
+                        Output {
+                            debug: context.read().read_debug_regs(),
+                        },
+                        mem::size_of::<DebugRegisters>(),
+                    ),
                 };
 
                 let src_buf =
@@ -1616,11 +2582,283 @@ impl ContextHandle {
                 buf.write_usize(addrspace.acquire_read().mmap_min)?;
                 Ok(mem::size_of::<usize>())
             }
+            ContextHandle::StackWindow(ref addrspace) => {
+                buf.write_usize(addrspace.acquire_read().stack_window * PAGE_SIZE)?;
+                Ok(mem::size_of::<usize>())
+            }
+            ContextHandle::DirtyBits(ref addrspace) => {
+                // Harvest in chunks, since the address space must not be locked when copying to
+                // userspace.
+                let mut bitmap = [0_u8; 512];
+                let mut address = usize::try_from(offset).map_err(|_| Error::new(EINVAL))?;
+                let len = buf.len();
+
+                for chunk in buf.in_variable_chunks(bitmap.len()) {
+                    let bitmap = &mut bitmap[..chunk.len()];
+                    let (page, page_count) =
+                        crate::syscall::validate_region(address, bitmap.len() * 8 * PAGE_SIZE)?;
+
+                    addrspace.harvest_dirty(PageSpan::new(page, page_count), bitmap)?;
+                    chunk.copy_from_slice(bitmap)?;
+                    address += page_count * PAGE_SIZE;
+                }
+                Ok(len)
+            }
+            ContextHandle::Maps(ref addrspace) => {
+                use core::fmt::Write;
+
+                let mut data = String::new();
+                for (base, info) in addrspace.acquire_read().grants.iter() {
+                    let flags = info.grant_flags();
+                    let perm = |flag, c| if flags.contains(flag) { c } else { '-' };
+                    let start = base.start_address().data();
+
+                    writeln!(
+                        data,
+                        "{:016x}-{:016x} {}{}{}{} {:08x} {}",
+                        start,
+                        start + info.page_count() * PAGE_SIZE,
+                        perm(GrantFlags::GRANT_READ, 'r'),
+                        perm(GrantFlags::GRANT_WRITE, 'w'),
+                        perm(GrantFlags::GRANT_EXEC, 'x'),
+                        if flags.contains(GrantFlags::GRANT_SHARED) {
+                            's'
+                        } else {
+                            'p'
+                        },
+                        info.file_ref().map_or(0, |f| f.base_offset),
+                        info.label().unwrap_or(""),
+                    )
+                    .unwrap();
+                }
+                read_from(buf, data.as_bytes(), offset)
+            }
+            ContextHandle::MemPolicy(ref addrspace) => {
+                let (mode, nodes) = addrspace.acquire_read().mempolicy.to_raw();
+                let home = addrspace
+                    .home_node
+                    .get()
+                    .map_or(usize::MAX, |node| node.get().into());
+
+                let mut chunks = buf.in_exact_chunks(mem::size_of::<usize>());
+                for value in [mode, nodes as usize, home] {
+                    chunks
+                        .next()
+                        .ok_or(Error::new(EINVAL))?
+                        .write_usize(value)?;
+                }
+                Ok(3 * mem::size_of::<usize>())
+            }
+            #[cfg(target_arch = "aarch64")]
+            ContextHandle::Bti(ref addrspace) => {
+                buf.write_usize(usize::from(addrspace.acquire_read().bti))?;
+                Ok(mem::size_of::<usize>())
+            }
+            ContextHandle::Ksm(ref addrspace) => {
+                let (enabled, merged) = addrspace
+                    .acquire_read()
+                    .ksm
+                    .as_ref()
+                    .map_or((0, 0), |state| (1, state.merged));
+
+                let mut chunks = buf.in_exact_chunks(mem::size_of::<usize>());
+                for value in [enabled, merged] {
+                    chunks
+                        .next()
+                        .ok_or(Error::new(EINVAL))?
+                        .write_usize(value)?;
+                }
+                Ok(2 * mem::size_of::<usize>())
+            }
+            ContextHandle::Userfault { ref userfault, .. } => userfault.events.receive_into_user(
+                buf,
+                (read_flags as usize) & O_NONBLOCK != O_NONBLOCK,
+                "userfault read",
+            ),
             ContextHandle::SchedAffinity => {
                 let mask = context.read().sched_affinity.to_raw();
 
                 buf.copy_exactly(crate::cpu_set::mask_as_bytes(&mask))?;
                 Ok(mem::size_of_val(&mask))
+            }
+            #[cfg(target_arch = "x86_64")]
+            ContextHandle::IoPerm => {
+                let Ok(offset) = usize::try_from(offset) else {
+                    return Ok(0);
+                };
+                let context = context.read();
+                let bitmap = context.io_permission_bitmap();
+                buf.copy_common_bytes_from_slice(bitmap.get(offset..).unwrap_or(&[]))
+            }
+            #[cfg(target_arch = "x86_64")]
+            ContextHandle::ArchPrctl => {
+                use crate::context::{fsgsbase_enabled, HWCAP2_FSGSBASE};
+
+                let hwcap2 = if fsgsbase_enabled() {
+                    HWCAP2_FSGSBASE
+                } else {
+                    0
+                };
+                buf.write_usize(hwcap2)?;
+                Ok(mem::size_of::<usize>())
+            }
+            #[cfg(target_arch = "aarch64")]
+            ContextHandle::Mte => {
+                let state = context.read().arch.mte.to_user();
+
+                let mut chunks = buf.in_exact_chunks(mem::size_of::<usize>());
+                for value in state {
+                    chunks
+                        .next()
+                        .ok_or(Error::new(EINVAL))?
+                        .write_usize(value)?;
+                }
+                Ok(2 * mem::size_of::<usize>())
+            }
+            ContextHandle::SchedNice => {
+                let nice = context.read().nice;
+                buf.write_usize(nice as isize as usize)?;
+                Ok(mem::size_of::<usize>())
+            }
+            ContextHandle::SchedPolicy => {
+                let (policy, priority) = context.read().sched_policy.into_raw();
+                let mut chunks = buf.in_exact_chunks(mem::size_of::<usize>());
+                chunks
+                    .next()
+                    .ok_or(Error::new(EINVAL))?
+                    .write_usize(policy)?;
+                chunks
+                    .next()
+                    .ok_or(Error::new(EINVAL))?
+                    .write_usize(priority)?;
+                Ok(2 * mem::size_of::<usize>())
+            }
+            ContextHandle::SchemeTimeout => {
+                let nanos = context.read().scheme_timeout.unwrap_or(0);
+                buf.write_usize(nanos.try_into().unwrap_or(usize::MAX))?;
+                Ok(mem::size_of::<usize>())
+            }
+            ContextHandle::Aslr => {
+                buf.write_usize(context.read().aslr.into())?;
+                Ok(mem::size_of::<usize>())
+            }
+            ContextHandle::AllowWx => {
+                buf.write_usize(context.read().allow_wx.into())?;
+                Ok(mem::size_of::<usize>())
+            }
+            ContextHandle::Caps => {
+                buf.write_usize(context.read().caps.bits() as usize)?;
+                Ok(mem::size_of::<usize>())
+            }
+            ContextHandle::OomScoreAdj => {
+                let adj = context.read().oom_score_adj;
+                buf.write_usize(adj as isize as usize)?;
+                Ok(mem::size_of::<usize>())
+            }
+            #[cfg(any(target_arch = "aarch64", target_arch = "x86_64"))]
+            ContextHandle::Sigaction => {
+                let actions = context
+                    .read()
+                    .ksig
+                    .as_ref()
+                    .map_or([SigAction::default(); 64], |ksig| *ksig.actions.lock());
+
+                let mut chunks = buf.in_exact_chunks(mem::size_of::<usize>());
+                let mut written = 0;
+                for action in actions.iter() {
+                    for value in [
+                        action.handler,
+                        action.mask as usize,
+                        action.flags,
+                        action.restorer,
+                    ] {
+                        let Some(chunk) = chunks.next() else {
+                            return Ok(written);
+                        };
+                        chunk.write_usize(value)?;
+                        written += mem::size_of::<usize>();
+                    }
+                }
+                Ok(written)
+            }
+            #[cfg(any(target_arch = "aarch64", target_arch = "x86_64"))]
+            ContextHandle::Sigprocmask => {
+                let (blocked, pending) = context
+                    .read()
+                    .ksig
+                    .as_ref()
+                    .map_or((0, 0), |ksig| (ksig.blocked, ksig.pending));
+
+                let mut chunks = buf.in_exact_chunks(mem::size_of::<usize>());
+                for value in [blocked, pending] {
+                    chunks
+                        .next()
+                        .ok_or(Error::new(EINVAL))?
+                        .write_usize(value as usize)?;
+                }
+                Ok(2 * mem::size_of::<usize>())
+            }
+            ContextHandle::ThreadMemPolicy => {
+                let (mode, nodes) = context.read().mempolicy.to_raw();
+
+                let mut chunks = buf.in_exact_chunks(mem::size_of::<usize>());
+                for value in [mode, nodes as usize] {
+                    chunks
+                        .next()
+                        .ok_or(Error::new(EINVAL))?
+                        .write_usize(value)?;
+                }
+                Ok(2 * mem::size_of::<usize>())
+            }
+            ContextHandle::Stats => {
+                let stats = stats::snapshot(&context.read());
+
+                let mut raw = Vec::with_capacity(4 + 3 * stats.io.len());
+                raw.extend([
+                    stats.syscalls,
+                    stats.page_faults,
+                    stats.voluntary_switches,
+                    stats.involuntary_switches,
+                ]);
+                for io in &stats.io {
+                    raw.extend([io.scheme.get() as u64, io.read, io.written]);
+                }
+                let bytes = unsafe {
+                    slice::from_raw_parts(raw.as_ptr().cast::<u8>(), mem::size_of_val(&*raw))
+                };
+                read_from(buf, bytes, offset)
+            }
+            ContextHandle::Rlimit => {
+                let raw = context.read().rlimits.to_raw();
+                let bytes = unsafe {
+                    slice::from_raw_parts(raw.as_ptr().cast::<u8>(), mem::size_of_val(&raw))
+                };
+                read_from(buf, bytes, offset)
+            }
+            ContextHandle::CpuMax => {
+                let (quota, period) = context
+                    .read()
+                    .group
+                    .as_ref()
+                    .map_or((group::QUOTA_MAX, group::DEFAULT_PERIOD), |group| {
+                        group.cpu.limit()
+                    });
+                let quota = if quota == group::QUOTA_MAX {
+                    usize::MAX
+                } else {
+                    quota as usize
+                };
+
+                let mut chunks = buf.in_exact_chunks(mem::size_of::<usize>());
+                chunks
+                    .next()
+                    .ok_or(Error::new(EINVAL))?
+                    .write_usize(quota)?;
+                chunks
+                    .next()
+                    .ok_or(Error::new(EINVAL))?
+                    .write_usize(period as usize)?;
+                Ok(2 * mem::size_of::<usize>())
             } // TODO: Replace write() with SYS_DUP_FORWARD.
 
             // TODO: Find a better way to switch address spaces, since they also require switching
@@ -1646,3 +2884,47 @@ fn read_env_regs(context: Arc<RwSpinlock<Context>>) -> Result<EnvRegisters> {
         try_stop_context(context, |context| context.read_env_regs())
     }
 }
+
+/// Apply an `ARCH_SHSTK_*` code to `context`, first mapping the shadow stack it enables, if any.
+#[cfg(target_arch = "x86_64")]
+fn write_cet(context: Arc<RwSpinlock<Context>>, code: usize, features: usize) -> Result<()> {
+    let (needs_shadow_stack, addr_space) = {
+        let context = context.read();
+        (
+            context.arch.cet.needs_shadow_stack(code, features)?,
+            context.addr_space().ok().map(Arc::clone),
+        )
+    };
+    let shadow_stack = if needs_shadow_stack {
+        let page_count = NonZeroUsize::new(crate::cet::SHADOW_STACK_PAGES).unwrap();
+        let addr_space = addr_space.as_ref().ok_or(Error::new(ESRCH))?;
+        Some(addr_space.mmap_shadow_stack(page_count)?)
+    } else {
+        None
+    };
+    let unmap = |span: Option<PageSpan>| {
+        if let (Some(addr_space), Some(span)) = (&addr_space, span) {
+            addr_space.munmap_shadow_stack(span);
+        }
+    };
+
+    let result = if context::is_current(&context) {
+        context::current()
+            .write()
+            .write_current_cet(code, features, shadow_stack)
+    } else {
+        try_stop_context(context, |context| {
+            context.write_cet(code, features, shadow_stack)
+        })
+    };
+    match result {
+        Ok(unused) => {
+            unmap(unused);
+            Ok(())
+        }
+        Err(err) => {
+            unmap(shadow_stack);
+            Err(err)
+        }
+    }
+}
@@ -1,22 +1,23 @@
-use alloc::{boxed::Box, string::ToString, sync::Arc};
+use alloc::{boxed::Box, sync::Arc};
 use core::{
     str,
     sync::atomic::{AtomicUsize, Ordering},
 };
 use hashbrown::HashMap;
-use spin::RwLock;
 use syscall::{
     dirent::{DirEntry, DirentBuf, DirentKind},
     O_FSYNC,
 };
 
 use crate::{
+    common::try_alloc::{try_arc, try_string, try_vec_from_slice},
     context::{self, file::InternalFlags, process},
     scheme::{
         self,
         user::{UserInner, UserScheme},
         SchemeId, SchemeNamespace,
     },
+    sync::RwLock,
     syscall::{
         data::Stat,
         error::*,
@@ -71,7 +72,7 @@ impl KernelScheme for RootScheme {
             let id = self.next_id.fetch_add(1, Ordering::Relaxed);
 
             let inner = {
-                let path_box = path.to_string().into_boxed_str();
+                let path_box = try_string(path)?.into_boxed_str();
                 let mut schemes = scheme::schemes_mut();
 
                 let v2 = flags & O_FSYNC == O_FSYNC;
@@ -112,7 +113,7 @@ impl KernelScheme for RootScheme {
             self.handles.write().insert(id, Handle::List { ens });
             Ok(OpenResult::SchemeLocal(id, InternalFlags::POSITIONED))
         } else {
-            let inner = Arc::new(path.as_bytes().to_vec().into_boxed_slice());
+            let inner = try_arc(try_vec_from_slice(path.as_bytes())?.into_boxed_slice())?;
 
             let id = self.next_id.fetch_add(1, Ordering::Relaxed);
             self.handles.write().insert(id, Handle::File(inner));
@@ -5,12 +5,10 @@ use core::{
     sync::atomic::{AtomicUsize, Ordering},
 };
 
-use spin::RwLock;
-
 use crate::{
     event,
     scheme::*,
-    sync::WaitQueue,
+    sync::{RwLock, WaitQueue},
     syscall::{
         flag::{EventFlags, EVENT_READ, O_NONBLOCK},
         usercopy::UserSliceWo,
@@ -0,0 +1,271 @@
+use alloc::{collections::BTreeMap, sync::Arc, vec::Vec};
+use core::{
+    mem::size_of,
+    slice,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use crate::{
+    context::file::InternalFlags,
+    memory::{
+        swap::{self, SwapProvider, SwapRequest},
+        Frame, RmmA, RmmArch, PAGE_SIZE,
+    },
+    sync::{Mutex, RwLock, WaitQueue},
+    syscall::{
+        error::*,
+        flag::O_NONBLOCK,
+        usercopy::{UserSliceRo, UserSliceWo},
+    },
+};
+
+use super::{CallerCtx, KernelScheme, OpenResult};
+
+/// Write the page following the header to the slot.
+pub const SWAP_OP_WRITE: u32 = 1;
+/// Read the slot into the page following the header.
+pub const SWAP_OP_READ: u32 = 2;
+
+/// Header of the requests read from a `swap:` handle, and of the completions written back to it.
+///
+/// Write requests are followed by the page to write, and completed with just the header. Read
+/// requests consist of the header only, and are completed with the header followed by the page
+/// read, unless they failed.
+#[derive(Clone, Copy, Debug, Default)]
+#[repr(C)]
+pub struct SwapHeader {
+    pub op: u32,
+    /// Zero, or the errno a completion failed with.
+    pub status: u32,
+    pub slot: u64,
+}
+
+const HEADER_SIZE: usize = size_of::<SwapHeader>();
+
+/// Forwards the requests of the swap subsystem to a userspace daemon, which stores the pages on a
+/// block device of its choice.
+struct SchemeProvider {
+    slot_count: usize,
+    requests: WaitQueue<SwapRequest>,
+    /// Requests read by the daemon, but not yet completed.
+    in_flight: Mutex<Vec<SwapRequest>>,
+}
+
+impl SwapProvider for SchemeProvider {
+    fn slot_count(&self) -> usize {
+        self.slot_count
+    }
+    fn submit(&self, request: SwapRequest) {
+        self.requests.send(request);
+    }
+    fn wake_reclaimer(&self) {
+        // Taking the queue lock orders this against the daemon checking for a reclaim request,
+        // right before it starts waiting.
+        drop(self.requests.inner.lock());
+        self.requests.condition.notify();
+    }
+}
+
+impl SchemeProvider {
+    fn next_request(&self, block: bool) -> Result<SwapRequest> {
+        loop {
+            let inner = self.requests.inner.lock();
+            if let Some(&request) = inner.front() {
+                return Ok(request);
+            }
+            if swap::reclaim_requested() {
+                drop(inner);
+                swap::reclaim();
+                continue;
+            }
+            if !block {
+                return Err(Error::new(EAGAIN));
+            }
+            if !self.requests.condition.wait(inner, "swap request") {
+                return Err(Error::new(EINTR));
+            }
+        }
+    }
+}
+
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+// Using BTreeMap as hashbrown doesn't have a const constructor.
+static HANDLES: RwLock<BTreeMap<usize, Arc<SchemeProvider>>> = RwLock::new(BTreeMap::new());
+
+/// `swap:` - lets a userspace daemon provide the backing store for swapped out pages
+///
+/// Opening `swap:<slots>` registers the daemon as the backing store, with room for `slots` pages,
+/// until the handle is closed. The daemon must not be swapped out itself, which is ensured by
+/// never evicting pages from the address space of the context reclaiming them, i.e. the one
+/// reading requests.
+pub struct SwapScheme;
+
+fn handle(id: usize) -> Result<Arc<SchemeProvider>> {
+    HANDLES.read().get(&id).cloned().ok_or(Error::new(EBADF))
+}
+
+fn page_bytes(frame: Frame) -> *mut u8 {
+    RmmA::phys_to_virt(frame.base()).data() as *mut u8
+}
+
+impl KernelScheme for SwapScheme {
+    fn kopen(&self, path: &str, _flags: usize, ctx: CallerCtx) -> Result<OpenResult> {
+        if ctx.uid != 0 {
+            return Err(Error::new(EPERM));
+        }
+        let slot_count = path
+            .trim_matches('/')
+            .parse::<usize>()
+            .ok()
+            .filter(|&count| count > 0)
+            .ok_or(Error::new(EINVAL))?;
+
+        let provider = Arc::new(SchemeProvider {
+            slot_count,
+            requests: WaitQueue::new(),
+            in_flight: Mutex::new(Vec::new()),
+        });
+        swap::register(provider.clone())?;
+
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        HANDLES.write().insert(id, provider);
+
+        Ok(OpenResult::SchemeLocal(id, InternalFlags::empty()))
+    }
+
+    fn close(&self, id: usize) -> Result<()> {
+        HANDLES.write().remove(&id).ok_or(Error::new(EBADF))?;
+        swap::unregister();
+        Ok(())
+    }
+
+    fn kread(
+        &self,
+        id: usize,
+        buf: UserSliceWo,
+        fcntl_flags: u32,
+        _stored_flags: u32,
+    ) -> Result<usize> {
+        let provider = handle(id)?;
+        if buf.len() < HEADER_SIZE + PAGE_SIZE {
+            return Err(Error::new(EINVAL));
+        }
+        let request = provider.next_request(fcntl_flags & O_NONBLOCK as u32 == 0)?;
+
+        let (header, frame) = match request {
+            SwapRequest::Write { slot, frame } => (
+                SwapHeader {
+                    op: SWAP_OP_WRITE,
+                    status: 0,
+                    slot: slot as u64,
+                },
+                Some(frame),
+            ),
+            SwapRequest::Read { slot, .. } => (
+                SwapHeader {
+                    op: SWAP_OP_READ,
+                    status: 0,
+                    slot: slot as u64,
+                },
+                None,
+            ),
+        };
+        let header_bytes = unsafe {
+            slice::from_raw_parts((&header as *const SwapHeader).cast::<u8>(), HEADER_SIZE)
+        };
+        buf.limit(HEADER_SIZE)
+            .expect("checked length")
+            .copy_exactly(header_bytes)?;
+
+        let mut len = HEADER_SIZE;
+        if let Some(frame) = frame {
+            // The frame stays in the swap cache until the write is completed.
+            let page = unsafe { slice::from_raw_parts(page_bytes(frame), PAGE_SIZE) };
+            buf.advance(HEADER_SIZE)
+                .and_then(|b| b.limit(PAGE_SIZE))
+                .expect("checked length")
+                .copy_exactly(page)?;
+            len += PAGE_SIZE;
+        }
+
+        // Only handed out once copied, so that the request is retried if that failed.
+        let mut in_flight = provider.in_flight.lock();
+        let mut requests = provider.requests.inner.lock();
+        if requests.front() == Some(&request) {
+            requests.pop_front();
+            in_flight.push(request);
+        }
+        Ok(len)
+    }
+
+    fn kwrite(
+        &self,
+        id: usize,
+        buf: UserSliceRo,
+        _flags: u32,
+        _stored_flags: u32,
+    ) -> Result<usize> {
+        let provider = handle(id)?;
+
+        let mut header = SwapHeader::default();
+        let header_bytes = unsafe {
+            slice::from_raw_parts_mut((&mut header as *mut SwapHeader).cast::<u8>(), HEADER_SIZE)
+        };
+        buf.limit(HEADER_SIZE)
+            .ok_or(Error::new(EINVAL))?
+            .copy_to_slice(header_bytes)?;
+
+        let slot = usize::try_from(header.slot).map_err(|_| Error::new(EINVAL))?;
+        let result = match header.status {
+            0 => Ok(()),
+            errno => Err(Error::new(errno as i32)),
+        };
+
+        let request = {
+            let mut in_flight = provider.in_flight.lock();
+            let index = in_flight
+                .iter()
+                .position(|request| match (*request, header.op) {
+                    (SwapRequest::Write { slot: s, .. }, SWAP_OP_WRITE)
+                    | (SwapRequest::Read { slot: s, .. }, SWAP_OP_READ) => s == slot,
+                    _ => false,
+                })
+                .ok_or(Error::new(EINVAL))?;
+            in_flight.swap_remove(index)
+        };
+
+        match request {
+            SwapRequest::Write { slot, .. } => {
+                swap::write_done(slot, result);
+                Ok(HEADER_SIZE)
+            }
+            SwapRequest::Read { slot, .. } => {
+                let data = buf
+                    .advance(HEADER_SIZE)
+                    .and_then(|b| b.limit(PAGE_SIZE))
+                    .filter(|b| b.len() == PAGE_SIZE || result.is_err());
+                let fill = |frame| {
+                    let data = data.ok_or(Error::new(EINVAL))?;
+                    let page = unsafe { slice::from_raw_parts_mut(page_bytes(frame), PAGE_SIZE) };
+                    data.copy_to_slice(page)
+                };
+                let len = if result.is_ok() {
+                    HEADER_SIZE + PAGE_SIZE
+                } else {
+                    HEADER_SIZE
+                };
+                if let Err(err) = swap::read_done(slot, result, fill) {
+                    provider.in_flight.lock().push(request);
+                    return Err(err);
+                }
+                Ok(len)
+            }
+        }
+    }
+
+    fn kfpath(&self, id: usize, buf: UserSliceWo) -> Result<usize> {
+        let provider = handle(id)?;
+        let path = format!("swap:{}", provider.slot_count);
+        buf.copy_common_bytes_from_slice(path.as_bytes())
+    }
+}
@@ -0,0 +1,30 @@
+use alloc::{string::String, vec::Vec};
+use core::{fmt::Write, str};
+
+use crate::{
+    devices::console::{self, OUTPUTS},
+    syscall::error::{Error, Result, EINVAL},
+};
+
+pub fn resource() -> Result<Vec<u8>> {
+    let mut string = String::new();
+    for &(output, name) in OUTPUTS {
+        let state = if console::enabled(output) {
+            "enabled"
+        } else {
+            "disabled"
+        };
+        let _ = writeln!(string, "{}: {}", name, state);
+    }
+    // Without a framebuffer, there is nothing to scroll back.
+    if let Ok(view) = console::scroll(0) {
+        let _ = writeln!(string, "scrolled back: {}", view);
+    }
+    Ok(string.into_bytes())
+}
+
+/// Enable or disable an output, with `enable <output>` or `disable <output>`, or scroll the
+/// framebuffer with `scroll <rows>` or `scroll end`.
+pub fn write(command: &[u8]) -> Result<()> {
+    console::command(str::from_utf8(command).map_err(|_| Error::new(EINVAL))?)
+}
@@ -7,7 +7,7 @@ use crate::{context, paging::PAGE_SIZE, syscall::error::Result};
 
 pub fn resource() -> Result<Vec<u8>> {
     let mut string = format!(
-        "{:<6}{:<6}{:<6}{:<6}{:<6}{:<6}{:<6}{:<6}{:<6}{:<6}{:<6}{:<6}{:<11}{:<12}{:<8}{}\n",
+        "{:<6}{:<6}{:<6}{:<6}{:<6}{:<6}{:<6}{:<6}{:<6}{:<6}{:<6}{:<6}{:<4}{:<11}{:<12}{:<8}{}\n",
         "PID",
         "PGID",
         "PPID",
@@ -20,6 +20,7 @@ pub fn resource() -> Result<Vec<u8>> {
         "ENS",
         "STAT",
         "CPU",
+        "NI",
         "AFFINITY",
         "TIME",
         "MEM",
@@ -103,7 +104,7 @@ pub fn resource() -> Result<Vec<u8>> {
             let process = context.process.read();
 
             string.push_str(&format!(
-                "{:<6}{:<6}{:<6}{:<6}{:<6}{:<6}{:<6}{:<6}{:<6}{:<6}{:<6}{:<6}{:<11}{:<12}{:<8}{}\n",
+                "{:<6}{:<6}{:<6}{:<6}{:<6}{:<6}{:<6}{:<6}{:<6}{:<6}{:<6}{:<6}{:<4}{:<11}{:<12}{:<8}{}\n",
                 context.pid.get(),
                 process.pgid.get(),
                 process.ppid.get(),
@@ -116,6 +117,7 @@ pub fn resource() -> Result<Vec<u8>> {
                 process.ens.get(),
                 stat_string,
                 cpu_string,
+                context.nice,
                 affinity,
                 cpu_time_string,
                 memory_string,
@@ -1,15 +1,40 @@
 use alloc::vec::Vec;
+use core::{fmt::Write, str};
 
 use crate::{
+    cpu_set::LogicalCpuId,
     device::cpu::cpu_info,
-    syscall::error::{Error, Result, EIO},
+    hotplug,
+    syscall::error::{Error, Result, EINVAL, EIO},
 };
 
 pub fn resource() -> Result<Vec<u8>> {
     let mut string = format!("CPUs: {}\n", crate::cpu_count());
 
+    let mut offline = hotplug::offline_cpus().peekable();
+    if offline.peek().is_some() {
+        string.push_str("Offline:");
+        for cpu in offline {
+            let _ = write!(string, " {}", cpu.get());
+        }
+        string.push('\n');
+    }
+
     match cpu_info(&mut string) {
         Ok(()) => Ok(string.into_bytes()),
         Err(_) => Err(Error::new(EIO)),
     }
 }
+
+/// Take a CPU offline or bring it back, with `offline <id>` or `online <id>`.
+pub fn write(command: &[u8]) -> Result<()> {
+    let command = str::from_utf8(command).map_err(|_| Error::new(EINVAL))?;
+    let (action, cpu) = command.trim().split_once(' ').ok_or(Error::new(EINVAL))?;
+    let cpu = LogicalCpuId::new(cpu.trim().parse().map_err(|_| Error::new(EINVAL))?);
+
+    match action {
+        "offline" => hotplug::offline(cpu),
+        "online" => hotplug::online(cpu),
+        _ => Err(Error::new(EINVAL)),
+    }
+}
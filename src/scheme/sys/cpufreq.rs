@@ -0,0 +1,53 @@
+use alloc::{string::String, vec::Vec};
+use core::{fmt::Write, str};
+
+use crate::{
+    cpu_set::LogicalCpuId,
+    cpufreq::{self, Governor},
+    syscall::error::{Error, Result, EINVAL},
+};
+
+pub fn resource() -> Result<Vec<u8>> {
+    let mut string = String::new();
+    let Some(driver) = cpufreq::driver() else {
+        let _ = writeln!(string, "driver: none");
+        return Ok(string.into_bytes());
+    };
+    let _ = writeln!(string, "driver: {}", driver.name());
+    let _ = writeln!(string, "governor: {}", cpufreq::governor().name());
+    let _ = write!(string, "governors:");
+    for governor in Governor::ALL {
+        let _ = write!(string, " {}", governor.name());
+    }
+    let _ = writeln!(string);
+
+    let _ = writeln!(
+        string,
+        "{:<6}{:>8}{:>8}{:>8}{:>16}",
+        "CPU", "MIN", "MAX", "LEVEL", "FREQ (kHz)"
+    );
+    for id in 0..crate::cpu_count() {
+        let Some(percpu) = crate::percpu::get(LogicalCpuId::new(id)) else {
+            continue;
+        };
+        let Some((min, max, level)) = percpu.cpufreq.levels() else {
+            continue;
+        };
+        let _ = write!(string, "{:<6}{:>8}{:>8}{:>8}", id, min, max, level);
+        match driver.frequency(level) {
+            Some(khz) => {
+                let _ = writeln!(string, "{:>16}", khz);
+            }
+            None => {
+                let _ = writeln!(string, "{:>16}", "-");
+            }
+        }
+    }
+
+    Ok(string.into_bytes())
+}
+
+/// Choose the governor with `governor <name>`.
+pub fn write(command: &[u8]) -> Result<()> {
+    cpufreq::command(str::from_utf8(command).map_err(|_| Error::new(EINVAL))?)
+}
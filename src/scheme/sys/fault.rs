@@ -0,0 +1,11 @@
+use crate::{context::fault::FaultKind, syscall::error::Result};
+use alloc::{string::String, vec::Vec};
+use core::fmt::Write;
+
+pub fn resource() -> Result<Vec<u8>> {
+    let mut string = String::new();
+    for kind in FaultKind::ALL {
+        let _ = writeln!(string, "{}: {}", kind.as_str(), kind.count());
+    }
+    Ok(string.into_bytes())
+}
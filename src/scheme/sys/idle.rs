@@ -0,0 +1,32 @@
+use alloc::{string::String, vec::Vec};
+use core::fmt::Write;
+
+use crate::{cpu_set::LogicalCpuId, idle, syscall::error::Result, time};
+
+pub fn resource() -> Result<Vec<u8>> {
+    let mut string = String::new();
+    let _ = writeln!(
+        string,
+        "{:<6}{:<8}{:>16}{:>20}",
+        "CPU", "STATE", "USAGE", "TIME (us)"
+    );
+
+    for id in 0..crate::cpu_count() {
+        let Some(percpu) = crate::percpu::get(LogicalCpuId::new(id)) else {
+            continue;
+        };
+        for (i, state) in idle::states().iter().enumerate() {
+            let (usage, residency) = percpu.idle.stats(i);
+            let _ = writeln!(
+                string,
+                "{:<6}{:<8}{:>16}{:>20}",
+                id,
+                state.name,
+                usage,
+                u128::from(residency) * 1_000_000 / time::NANOS_PER_SEC
+            );
+        }
+    }
+
+    Ok(string.into_bytes())
+}
@@ -1,10 +1,20 @@
-use crate::{context, scheme, syscall::error::Result};
-use alloc::{string::String, vec::Vec};
+use crate::{
+    context::{self, process, stats},
+    scheme,
+    syscall::error::Result,
+};
+use alloc::{collections::BTreeMap, string::String, vec::Vec};
 use core::fmt::Write;
 
 pub fn resource() -> Result<Vec<u8>> {
     let mut string = String::new();
 
+    let scheme_ns = process::current()?.read().ens;
+    let scheme_names = scheme::schemes()
+        .iter_name(scheme_ns)
+        .map(|(name, scheme_id)| (*scheme_id, name.clone()))
+        .collect::<BTreeMap<_, _>>();
+
     {
         let mut rows = Vec::new();
         {
@@ -15,6 +25,7 @@ pub fn resource() -> Result<Vec<u8>> {
                     context.pid,
                     context.name.clone(),
                     context.files.read().clone(),
+                    stats::snapshot(&context),
                 ));
             }
         }
@@ -24,6 +35,30 @@ pub fn resource() -> Result<Vec<u8>> {
             let name = &row.1;
             let _ = writeln!(string, "{}: {}", id, name);
 
+            let stats = &row.3;
+            let (read, written) = stats.total_io();
+            let _ = writeln!(
+                string,
+                "  syscalls {} faults {} switches {}/{} read {} written {}",
+                stats.syscalls,
+                stats.page_faults,
+                stats.voluntary_switches,
+                stats.involuntary_switches,
+                read,
+                written
+            );
+            for io in stats.io.iter() {
+                match scheme_names.get(&io.scheme) {
+                    Some(scheme_name) => {
+                        let _ = write!(string, "  {:>12}", scheme_name);
+                    }
+                    None => {
+                        let _ = write!(string, "  {:>12}", io.scheme.get());
+                    }
+                }
+                let _ = writeln!(string, ": read {} written {}", io.read, io.written);
+            }
+
             for (fd, f) in row.2.iter().enumerate() {
                 let file = match *f {
                     None => continue,
@@ -1,16 +1,36 @@
 use alloc::{string::String, vec::Vec};
 use core::fmt::Write;
 
-use crate::syscall::error::Result;
+use crate::{irq_stats, syscall::error::Result};
 
 pub fn resource() -> Result<Vec<u8>> {
     let mut string = String::new();
 
-    {
-        let counts = crate::scheme::irq::COUNTS.lock();
-        for (i, count) in counts.iter().enumerate() {
-            let _ = writeln!(string, "{}: {}", i, count);
+    let _ = write!(string, "{:>4}", "IRQ");
+    for cpu in 0..crate::cpu_count() {
+        let _ = write!(string, " {:>10}", format!("CPU{}", cpu));
+    }
+    let _ = writeln!(string, " {:>16} HANDLER", "LAST (ns)");
+
+    for irq in 0..=u8::MAX {
+        let handler = irq_stats::handler(irq);
+        let owners = crate::scheme::irq::owners(irq);
+        let (counts, last) = irq_stats::snapshot(irq);
+        if counts.iter().all(|&count| count == 0) && handler.is_none() && owners.is_empty() {
+            continue;
+        }
+
+        let _ = write!(string, "{:>4}", irq);
+        for count in counts {
+            let _ = write!(string, " {:>10}", count);
+        }
+        let _ = write!(string, " {:>16} ", last);
+        match handler {
+            Some(name) => string.push_str(name),
+            None if owners.is_empty() => string.push('-'),
+            None => string.push_str(&owners.join(",")),
         }
+        string.push('\n');
     }
 
     Ok(string.into_bytes())
@@ -0,0 +1,29 @@
+use crate::{
+    context::memory::CowStats,
+    memory::ksm::{self, STATS},
+    syscall::error::Result,
+};
+use alloc::vec::Vec;
+use core::sync::atomic::Ordering;
+
+pub fn resource() -> Result<Vec<u8>> {
+    let (pages_shared, pages_sharing) = ksm::sharing();
+    Ok(format!(
+        "full_scans: {}\npages_scanned: {}\npages_merged: {}\npages_shared: {}\npages_sharing: {}\n",
+        STATS.full_scans.load(Ordering::Relaxed),
+        STATS.pages_scanned.load(Ordering::Relaxed),
+        STATS.pages_merged.load(Ordering::Relaxed),
+        pages_shared,
+        pages_sharing,
+    )
+    .into_bytes())
+}
+
+pub fn cow_resource() -> Result<Vec<u8>> {
+    let [shared, copied, reused, zero_mapped] = CowStats::total();
+    Ok(format!(
+        "shared: {}\ncopied: {}\nreused: {}\nzero_mapped: {}\n",
+        shared, copied, reused, zero_mapped,
+    )
+    .into_bytes())
+}
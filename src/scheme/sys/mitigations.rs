@@ -0,0 +1,36 @@
+use alloc::{string::String, vec::Vec};
+use core::{fmt::Write, str};
+
+use crate::{
+    mitigations::{self, Mitigation, Status, Vulnerability},
+    syscall::error::{Error, Result, EINVAL},
+};
+
+pub fn resource() -> Result<Vec<u8>> {
+    let mut string = String::new();
+    for vulnerability in Vulnerability::ALL {
+        let _ = write!(string, "{}: ", vulnerability.name());
+        let _ = match mitigations::status(vulnerability) {
+            Status::NotAffected => writeln!(string, "not affected"),
+            Status::Mitigated(by) => writeln!(string, "mitigated ({})", by),
+            Status::Vulnerable => writeln!(string, "vulnerable"),
+            Status::Unknown => writeln!(string, "unknown"),
+        };
+    }
+    for mitigation in Mitigation::ALL {
+        let state = if !mitigations::supported(mitigation) {
+            "unsupported"
+        } else if mitigations::enabled(mitigation) {
+            "on"
+        } else {
+            "off"
+        };
+        let _ = writeln!(string, "{}: {}", mitigation.name(), state);
+    }
+    Ok(string.into_bytes())
+}
+
+/// Enable or disable a mitigation with `<mitigation> on` or `<mitigation> off`.
+pub fn write(command: &[u8]) -> Result<()> {
+    mitigations::command(str::from_utf8(command).map_err(|_| Error::new(EINVAL))?)
+}
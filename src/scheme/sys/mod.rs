@@ -7,40 +7,61 @@ use core::{
     str,
     sync::atomic::{AtomicUsize, Ordering},
 };
-use spin::RwLock;
 
 #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
 use crate::arch::interrupt;
 use crate::{
     context::file::InternalFlags,
+    sync::RwLock,
     syscall::{
         data::Stat,
-        error::{Error, Result, EBADF, ENOENT},
-        flag::{MODE_DIR, MODE_FILE},
-        usercopy::UserSliceWo,
+        error::{Error, Result, EACCES, EBADF, EINVAL, ENOENT},
+        flag::{MODE_DIR, MODE_FILE, O_ACCMODE, O_RDONLY},
+        usercopy::{UserSliceRo, UserSliceWo},
     },
 };
 
 use super::{CallerCtx, KernelScheme, OpenResult};
 
 mod block;
+mod console;
 mod context;
 mod cpu;
+mod cpufreq;
 mod exe;
+mod fault;
+mod idle;
 mod iostat;
 mod irq;
+mod ksm;
 mod log;
+mod mitigations;
+mod numa;
+mod perf;
+mod power;
+mod sched_rt;
 mod scheme;
 mod scheme_num;
 mod syscall;
 mod uname;
+mod writeback;
 
 enum Handle {
     TopLevel,
-    Resource { path: &'static str, data: Vec<u8> },
+    Resource {
+        path: &'static str,
+        data: Vec<u8>,
+        /// Set if opened for writing.
+        write: Option<SysWriteFn>,
+    },
 }
 
 type SysFn = fn() -> Result<Vec<u8>>;
+/// Handles a command written to a file, which must be written in one go.
+type SysWriteFn = fn(&[u8]) -> Result<()>;
+
+/// Longest command accepted by the writable files.
+const MAX_COMMAND_LEN: usize = 256;
 
 /// System information scheme
 pub struct SysScheme;
@@ -50,16 +71,31 @@ static HANDLES: RwLock<BTreeMap<usize, Handle>> = RwLock::new(BTreeMap::new());
 
 const FILES: &[(&'static str, SysFn)] = &[
     ("block", block::resource),
+    ("console", console::resource),
     ("context", context::resource),
+    ("cow", ksm::cow_resource),
     ("cpu", cpu::resource),
+    ("cpufreq", cpufreq::resource),
     ("exe", exe::resource),
+    ("fault", fault::resource),
+    ("idle", idle::resource),
     ("iostat", iostat::resource),
     ("irq", irq::resource),
+    ("ksm", ksm::resource),
+    ("lockdown", || {
+        Ok(Vec::from(format!("{}\n", crate::lockdown::level().name())))
+    }),
     ("log", log::resource),
+    ("mitigations", mitigations::resource),
+    ("numa", numa::resource),
+    ("perf", perf::resource),
+    ("power", power::resource),
+    ("sched_rt", sched_rt::resource),
     ("scheme", scheme::resource),
     ("scheme_num", scheme_num::resource),
     ("syscall", syscall::resource),
     ("uname", uname::resource),
+    ("writeback", writeback::resource),
     ("env", || Ok(Vec::from(crate::init_env()))),
     #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
     ("spurious_irq", interrupt::irq::spurious_irq_resource),
@@ -72,8 +108,19 @@ const FILES: &[(&'static str, SysFn)] = &[
     */
 ];
 
+/// The files that can also be written to, by root.
+const WRITABLE: &[(&'static str, SysWriteFn)] = &[
+    ("console", console::write),
+    ("cpu", cpu::write),
+    ("cpufreq", cpufreq::write),
+    ("mitigations", mitigations::write),
+    ("perf", perf::write),
+    ("power", power::write),
+    ("sched_rt", sched_rt::write),
+];
+
 impl KernelScheme for SysScheme {
-    fn kopen(&self, path: &str, _flags: usize, _ctx: CallerCtx) -> Result<OpenResult> {
+    fn kopen(&self, path: &str, flags: usize, ctx: CallerCtx) -> Result<OpenResult> {
         let path = path.trim_matches('/');
 
         if path.is_empty() {
@@ -85,6 +132,18 @@ impl KernelScheme for SysScheme {
             //Have to iterate to get the path without allocation
             for entry in FILES.iter() {
                 if &entry.0 == &path {
+                    let write = if flags & O_ACCMODE == O_RDONLY {
+                        None
+                    } else {
+                        let (_, write) = WRITABLE
+                            .iter()
+                            .find(|(name, _)| *name == path)
+                            .ok_or(Error::new(EACCES))?;
+                        if ctx.uid != 0 {
+                            return Err(Error::new(EACCES));
+                        }
+                        Some(*write)
+                    };
                     let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
                     let data = entry.1()?;
                     HANDLES.write().insert(
@@ -92,6 +151,7 @@ impl KernelScheme for SysScheme {
                         Handle::Resource {
                             path: entry.0,
                             data,
+                            write,
                         },
                     );
                     return Ok(OpenResult::SchemeLocal(id, InternalFlags::POSITIONED));
@@ -150,6 +210,29 @@ impl KernelScheme for SysScheme {
             }
         }
     }
+    fn kwriteoff(
+        &self,
+        id: usize,
+        buffer: UserSliceRo,
+        _pos: u64,
+        _flags: u32,
+        _stored_flags: u32,
+    ) -> Result<usize> {
+        let write = match HANDLES.read().get(&id).ok_or(Error::new(EBADF))? {
+            Handle::Resource {
+                write: Some(write), ..
+            } => *write,
+            _ => return Err(Error::new(EBADF)),
+        };
+        if buffer.len() > MAX_COMMAND_LEN {
+            return Err(Error::new(EINVAL));
+        }
+        let mut command = [0; MAX_COMMAND_LEN];
+        let command = &mut command[..buffer.len()];
+        buffer.copy_to_slice(command)?;
+        write(command)?;
+        Ok(command.len())
+    }
     fn getdents(
         &self,
         id: usize,
@@ -179,8 +262,12 @@ impl KernelScheme for SysScheme {
 
     fn kfstat(&self, id: usize, buf: UserSliceWo) -> Result<()> {
         let stat = match HANDLES.read().get(&id).ok_or(Error::new(EBADF))? {
-            Handle::Resource { data, .. } => Stat {
-                st_mode: 0o444 | MODE_FILE,
+            Handle::Resource { path, data, .. } => Stat {
+                st_mode: if WRITABLE.iter().any(|(name, _)| name == path) {
+                    0o644
+                } else {
+                    0o444
+                } | MODE_FILE,
                 st_uid: 0,
                 st_gid: 0,
                 st_size: data.len() as u64,
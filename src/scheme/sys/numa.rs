@@ -0,0 +1,22 @@
+use alloc::vec::Vec;
+use core::fmt::Write;
+
+use crate::{
+    memory::{free_frames_per_node, PAGE_SIZE},
+    numa,
+    syscall::error::Result,
+};
+
+pub fn resource() -> Result<Vec<u8>> {
+    let free = free_frames_per_node();
+    let mut string = format!("nodes: {}\n", numa::node_count());
+    for (node, free) in free.iter().take(numa::node_count()).enumerate() {
+        let _ = writeln!(
+            string,
+            "node {}: {} KiB free",
+            node,
+            free * PAGE_SIZE / 1024
+        );
+    }
+    Ok(string.into_bytes())
+}
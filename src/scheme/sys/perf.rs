@@ -0,0 +1,65 @@
+use alloc::{string::String, vec::Vec};
+use core::{fmt::Write, str};
+
+use crate::{
+    context,
+    cpu_set::LogicalCpuId,
+    perf::{self, PerfEvent},
+    syscall::error::{Error, Result, EINVAL},
+};
+
+pub fn resource() -> Result<Vec<u8>> {
+    let mut string = String::new();
+
+    let _ = write!(string, "supported:");
+    for event in perf::supported() {
+        let _ = write!(string, " {}", event.name());
+    }
+    let _ = writeln!(string, "\nenabled: {}", perf::is_enabled());
+    #[cfg(all(feature = "profiling", target_arch = "x86_64"))]
+    let _ = writeln!(string, "sample_period: {}", perf::sample_period());
+
+    let _ = write!(string, "\n{:<6}", "CPU");
+    for event in PerfEvent::ALL {
+        let _ = write!(string, " {:>20}", event.name());
+    }
+    string.push('\n');
+    for id in 0..crate::cpu_count() {
+        let Some(percpu) = crate::percpu::get(LogicalCpuId::new(id)) else {
+            continue;
+        };
+        let _ = write!(string, "{:<6}", id);
+        for count in percpu.perf.totals() {
+            let _ = write!(string, " {:>20}", count);
+        }
+        string.push('\n');
+    }
+
+    let _ = write!(string, "\n{:<6}", "PID");
+    for event in PerfEvent::ALL {
+        let _ = write!(string, " {:>20}", event.name());
+    }
+    let _ = writeln!(string, " NAME");
+    {
+        let contexts = context::contexts();
+        for context_ref in contexts.iter().filter_map(|r| r.upgrade()) {
+            let context = context_ref.read();
+            if context.perf_counts.iter().all(|&count| count == 0) {
+                continue;
+            }
+            let _ = write!(string, "{:<6}", context.pid.get());
+            for count in context.perf_counts {
+                let _ = write!(string, " {:>20}", count);
+            }
+            let _ = writeln!(string, " {}", context.name);
+        }
+    }
+
+    Ok(string.into_bytes())
+}
+
+/// Start or stop counting with `enable` or `disable`, or make the profiler sample every given
+/// number of cycles with `sample <cycles>`.
+pub fn write(command: &[u8]) -> Result<()> {
+    perf::command(str::from_utf8(command).map_err(|_| Error::new(EINVAL))?)
+}
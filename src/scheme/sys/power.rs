@@ -0,0 +1,27 @@
+use alloc::vec::Vec;
+use core::str;
+
+use crate::{
+    suspend,
+    syscall::error::{Error, Result, EINVAL},
+};
+
+/// The sleep states that can be entered, `mem` being suspend to RAM.
+pub fn resource() -> Result<Vec<u8>> {
+    Ok(if suspend::supported() {
+        b"mem\n".to_vec()
+    } else {
+        Vec::new()
+    })
+}
+
+/// Suspend the system with `mem`, returning once it woke up.
+pub fn write(command: &[u8]) -> Result<()> {
+    match str::from_utf8(command)
+        .map_err(|_| Error::new(EINVAL))?
+        .trim()
+    {
+        "mem" => suspend::suspend(),
+        _ => Err(Error::new(EINVAL)),
+    }
+}
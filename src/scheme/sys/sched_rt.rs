@@ -0,0 +1,22 @@
+use alloc::vec::Vec;
+use core::str;
+
+use crate::{
+    context::rt,
+    syscall::error::{Error, Result, EINVAL},
+};
+
+pub fn resource() -> Result<Vec<u8>> {
+    let (period, runtime, rr_timeslice, throttled) = rt::settings();
+    Ok(format!(
+        "period: {} us\nruntime: {} us\nrr_timeslice: {} us\nthrottled: {}\n",
+        period, runtime, rr_timeslice, throttled
+    )
+    .into_bytes())
+}
+
+/// Set the period, the runtime of real-time contexts in every period, or the timeslice of round
+/// robin contexts, with `period <us>`, `runtime <us>` or `rr_timeslice <us>`.
+pub fn write(command: &[u8]) -> Result<()> {
+    rt::command(str::from_utf8(command).map_err(|_| Error::new(EINVAL))?)
+}
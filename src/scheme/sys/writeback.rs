@@ -0,0 +1,13 @@
+use crate::{memory::writeback::STATS, syscall::error::Result};
+use alloc::vec::Vec;
+use core::sync::atomic::Ordering;
+
+pub fn resource() -> Result<Vec<u8>> {
+    Ok(format!(
+        "passes: {}\npages_written: {}\nerrors: {}\n",
+        STATS.passes.load(Ordering::Relaxed),
+        STATS.pages_written.load(Ordering::Relaxed),
+        STATS.errors.load(Ordering::Relaxed),
+    )
+    .into_bytes())
+}
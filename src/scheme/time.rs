@@ -1,19 +1,26 @@
-use alloc::collections::BTreeMap;
+use alloc::{collections::BTreeMap, sync::Arc, vec::Vec};
 use core::{
-    mem, str,
+    mem,
+    num::NonZeroUsize,
+    str,
     sync::atomic::{AtomicUsize, Ordering},
 };
-use spin::RwLock;
 
 use crate::{
-    context::{file::InternalFlags, timeout},
+    context::{
+        file::InternalFlags,
+        memory::{handle_notify_files, AddrSpaceWrapper, Grant, PageSpan},
+        timeout,
+    },
+    memory::PAGE_SIZE,
+    sync::RwLock,
     syscall::{
-        data::TimeSpec,
+        data::{Map, TimeSpec},
         error::*,
-        flag::{EventFlags, CLOCK_MONOTONIC, CLOCK_REALTIME},
-        usercopy::{UserSliceRo, UserSliceWo},
+        flag::{EventFlags, MapFlags, CLOCK_MONOTONIC, CLOCK_REALTIME},
+        usercopy::{validate_region, UserSliceRo, UserSliceWo},
     },
-    time,
+    time, vdso,
 };
 
 use super::{CallerCtx, GlobalSchemes, KernelScheme, OpenResult};
@@ -22,15 +29,23 @@ static NEXT_ID: AtomicUsize = AtomicUsize::new(1);
 // Using BTreeMap as hashbrown doesn't have a const constructor.
 static HANDLES: RwLock<BTreeMap<usize, usize>> = RwLock::new(BTreeMap::new());
 
+/// Stored instead of a clock for handles to the vDSO page, which can only be mapped.
+const VDSO: usize = usize::MAX;
+
 pub struct TimeScheme;
 
 impl KernelScheme for TimeScheme {
     fn kopen(&self, path: &str, _flags: usize, _ctx: CallerCtx) -> Result<OpenResult> {
-        let clock = path.parse::<usize>().map_err(|_| Error::new(ENOENT))?;
+        let clock = match path {
+            "vdso" => VDSO,
+            _ => path.parse::<usize>().map_err(|_| Error::new(ENOENT))?,
+        };
 
         match clock {
             CLOCK_REALTIME => (),
             CLOCK_MONOTONIC => (),
+            time::CLOCK_BOOTTIME => (),
+            VDSO => (),
             _ => return Err(Error::new(ENOENT)),
         }
 
@@ -73,6 +88,7 @@ impl KernelScheme for TimeScheme {
             let arch_time = match clock {
                 CLOCK_REALTIME => time::realtime(),
                 CLOCK_MONOTONIC => time::monotonic(),
+                time::CLOCK_BOOTTIME => time::boottime(),
                 _ => return Err(Error::new(EINVAL)),
             };
             let time = TimeSpec {
@@ -95,6 +111,9 @@ impl KernelScheme for TimeScheme {
         _stored_flags: u32,
     ) -> Result<usize> {
         let clock = *HANDLES.read().get(&id).ok_or(Error::new(EBADF))?;
+        if clock == VDSO {
+            return Err(Error::new(EBADF));
+        }
 
         let mut bytes_written = 0;
 
@@ -111,7 +130,55 @@ impl KernelScheme for TimeScheme {
     fn kfpath(&self, id: usize, buf: UserSliceWo) -> Result<usize> {
         let clock = *HANDLES.read().get(&id).ok_or(Error::new(EBADF))?;
 
-        let scheme_path = format!("time:{}", clock).into_bytes();
+        let scheme_path = match clock {
+            VDSO => "time:vdso".into(),
+            _ => format!("time:{}", clock),
+        }
+        .into_bytes();
         buf.copy_common_bytes_from_slice(&scheme_path)
     }
+    fn kfmap(
+        &self,
+        id: usize,
+        addr_space: &Arc<AddrSpaceWrapper>,
+        map: &Map,
+        _consume: bool,
+    ) -> Result<usize> {
+        if *HANDLES.read().get(&id).ok_or(Error::new(EBADF))? != VDSO {
+            return Err(Error::new(EBADF));
+        }
+        // Shared with every process, and with the kernel.
+        if map.offset != 0 || map.size != PAGE_SIZE {
+            return Err(Error::new(EINVAL));
+        }
+        if map
+            .flags
+            .intersects(MapFlags::PROT_WRITE | MapFlags::PROT_EXEC)
+        {
+            return Err(Error::new(EACCES));
+        }
+        let (requested_page, _) = validate_region(map.address, map.size)?;
+        let requested_base = (map.address != 0).then_some(requested_page);
+
+        let mut notify_files = Vec::new();
+        let base = addr_space.acquire_write().mmap(
+            addr_space,
+            requested_base,
+            NonZeroUsize::MIN,
+            map.flags,
+            &mut notify_files,
+            |page, page_flags, mapper, flusher| {
+                Grant::physmap(
+                    vdso::frame(),
+                    PageSpan::new(page, 1),
+                    page_flags,
+                    mapper,
+                    flusher,
+                )
+            },
+        )?;
+        handle_notify_files(notify_files);
+
+        Ok(base.start_address().data())
+    }
 }
@@ -0,0 +1,129 @@
+use alloc::{collections::BTreeMap, string::String, vec::Vec};
+use core::{
+    fmt::Write,
+    str,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use crate::{
+    context::file::InternalFlags,
+    cpu_set::LogicalCpuId,
+    sync::RwLock,
+    syscall::{
+        error::*,
+        usercopy::{UserSliceRo, UserSliceWo},
+    },
+    trace::{self, Event},
+};
+
+use super::{CallerCtx, KernelScheme, OpenResult};
+
+#[derive(Clone, Copy)]
+enum Handle {
+    /// Reading lists the enabled events and the number of lost records of each CPU. Writing a
+    /// whitespace separated list of event names replaces the enabled events.
+    Ctl,
+    /// Reading drains whole trace records from the buffer of the CPU, returning zero if empty.
+    Cpu(LogicalCpuId),
+}
+
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+// Using BTreeMap as hashbrown doesn't have a const constructor.
+static HANDLES: RwLock<BTreeMap<usize, Handle>> = RwLock::new(BTreeMap::new());
+
+/// `trace:` - streams kernel trace records
+pub struct TraceScheme;
+
+fn handle(id: usize) -> Result<Handle> {
+    HANDLES.read().get(&id).copied().ok_or(Error::new(EBADF))
+}
+
+impl KernelScheme for TraceScheme {
+    fn kopen(&self, path: &str, _flags: usize, ctx: CallerCtx) -> Result<OpenResult> {
+        if ctx.uid != 0 {
+            return Err(Error::new(EPERM));
+        }
+        crate::lockdown::check(crate::lockdown::Reason::KernelTracing)?;
+
+        let handle = match path.trim_matches('/') {
+            "ctl" => Handle::Ctl,
+            cpu => {
+                let id = cpu.parse::<u32>().map_err(|_| Error::new(ENOENT))?;
+                if id >= crate::cpu_count() {
+                    return Err(Error::new(ENOENT));
+                }
+                Handle::Cpu(LogicalCpuId::new(id))
+            }
+        };
+
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        HANDLES.write().insert(id, handle);
+
+        Ok(OpenResult::SchemeLocal(id, InternalFlags::empty()))
+    }
+
+    fn close(&self, id: usize) -> Result<()> {
+        HANDLES
+            .write()
+            .remove(&id)
+            .ok_or(Error::new(EBADF))
+            .and(Ok(()))
+    }
+
+    fn kread(&self, id: usize, buf: UserSliceWo, _flags: u32, _stored_flags: u32) -> Result<usize> {
+        match handle(id)? {
+            Handle::Ctl => {
+                let mut string = String::from("enabled:");
+                for event in Event::ALL {
+                    if trace::is_enabled(event) {
+                        let _ = write!(string, " {}", event.name());
+                    }
+                }
+                string.push_str("\nlost:");
+                for id in 0..crate::cpu_count() {
+                    let lost = trace::buffer(LogicalCpuId::new(id)).map_or(0, |b| b.lost());
+                    let _ = write!(string, " {}", lost);
+                }
+                string.push('\n');
+
+                buf.copy_common_bytes_from_slice(string.as_bytes())
+            }
+            Handle::Cpu(cpu_id) => match trace::buffer(cpu_id) {
+                Some(buffer) => buffer.drain(buf),
+                None => Ok(0),
+            },
+        }
+    }
+
+    fn kwrite(
+        &self,
+        id: usize,
+        buf: UserSliceRo,
+        _flags: u32,
+        _stored_flags: u32,
+    ) -> Result<usize> {
+        let Handle::Ctl = handle(id)? else {
+            return Err(Error::new(EBADF));
+        };
+
+        let mut tmp = [0_u8; 128];
+        let byte_count = buf.copy_common_bytes_to_slice(&mut tmp)?;
+        let names = str::from_utf8(&tmp[..byte_count]).map_err(|_| Error::new(EINVAL))?;
+
+        let events = names
+            .split_whitespace()
+            .map(|name| Event::from_name(name).ok_or(Error::new(EINVAL)))
+            .collect::<Result<Vec<_>>>()?;
+        trace::set_enabled(events)?;
+
+        Ok(byte_count)
+    }
+
+    fn kfpath(&self, id: usize, buf: UserSliceWo) -> Result<usize> {
+        let path = match handle(id)? {
+            Handle::Ctl => format!("trace:ctl"),
+            Handle::Cpu(cpu_id) => format!("trace:{}", cpu_id.get()),
+        };
+        buf.copy_common_bytes_from_slice(path.as_bytes())
+    }
+}
@@ -11,8 +11,6 @@ use core::{
     usize,
 };
 use slab::Slab;
-use spin::{Mutex, RwLock};
-use spinning_top::RwSpinlock;
 use syscall::{
     schemev2::{Cqe, CqeOpcode, Opcode, Sqe, SqeFlags},
     FobtainFdFlags, MunmapFlags, SendFdFlags, F_SETFL, KSMSG_CANCEL, MAP_FIXED_NOREPLACE,
@@ -24,6 +22,7 @@ use crate::{
         self,
         context::HardBlockedReason,
         file::{FileDescription, FileDescriptor, InternalFlags},
+        huge_page,
         memory::{
             AddrSpace, AddrSpaceWrapper, BorrowedFmapSource, Grant, GrantFileRef, MmapMode,
             PageSpan, DANGLING,
@@ -34,7 +33,7 @@ use crate::{
     memory::Frame,
     paging::{Page, VirtualAddress, PAGE_SIZE},
     scheme::SchemeId,
-    sync::WaitQueue,
+    sync::{Mutex, RwLock, RwSpinlock, WaitQueue},
     syscall::{
         data::{Map, Packet},
         error::*,
@@ -42,6 +41,7 @@ use crate::{
         number::*,
         usercopy::{UserSlice, UserSliceRo, UserSliceWo},
     },
+    time,
 };
 
 use super::{CallerCtx, FileHandle, KernelScheme, OpenResult};
@@ -84,6 +84,10 @@ const ONE: NonZeroUsize = match NonZeroUsize::new(1) {
     None => unreachable!(),
 };
 
+/// Time in nanoseconds a scheme has to respond to a canceled call, before the caller stops
+/// waiting for it.
+const SCHEME_CANCEL_TIMEOUT: u128 = time::NANOS_PER_SEC;
+
 enum ParsedCqe {
     TriggerFevent {
         number: usize,
@@ -286,10 +290,21 @@ impl UserInner {
         }
 
         let current_context = context::current();
+        let deadline = current_context
+            .read()
+            .scheme_timeout
+            .map(|timeout| time::monotonic() + timeout);
+        // Set once the call has been canceled, after which the scheme only has a limited time to
+        // respond.
+        let mut cancel_deadline = None;
 
         {
             let mut states = self.states.lock();
-            current_context.write().block("UserScheme::call");
+            let mut context = current_context.write();
+            context.block("UserScheme::call");
+            context.wake = deadline;
+            drop(context);
+
             states[sqe.tag as usize] = State::Waiting {
                 context: Arc::downgrade(&current_context),
                 fd,
@@ -297,7 +312,7 @@ impl UserInner {
 
                 // This is the part that the scheme handler will deallocate when responding. It
                 // starts as empty, so the caller can unmap it (optimal for TLB), but is populated
-                // the caller is interrupted by SIGKILL.
+                // if the call is abandoned before the scheme has responded.
                 callee_responsible: PageSpan::empty(),
             };
         }
@@ -308,54 +323,58 @@ impl UserInner {
         loop {
             context::switch();
 
-            let mut states = self.states.lock();
-
-            let mut eintr_if_sigkill = |callee_responsible: &mut PageSpan| {
-                // If SIGKILL was found without waiting for scheme, EINTR directly. In that
-                // case, data loss doesn't matter.
-                if context::current().read().being_sigkilled {
-                    // Callee must deallocate memory, rather than the caller. This is less optimal
-                    // for TLB, but we don't really have any other choice. The scheme must be able
-                    // to access the borrowed memory until it has responded to the request.
-                    *callee_responsible = core::mem::replace(caller_responsible, PageSpan::empty());
+            current_context.write().wake = None;
+            let now = time::monotonic();
 
-                    Err(Error::new(EINTR))
-                } else {
-                    Ok(())
-                }
-            };
+            let mut states = self.states.lock();
 
             match states.get_mut(sqe.tag as usize) {
                 // invalid state
                 None => return Err(Error::new(EBADFD)),
                 Some(o) => match mem::replace(o, State::Placeholder) {
-                    // signal wakeup while awaiting cancelation
                     State::Waiting {
-                        canceling: true,
-                        mut callee_responsible,
-                        context,
+                        canceling,
                         fd,
+                        context,
+                        callee_responsible,
                     } => {
-                        let maybe_eintr = eintr_if_sigkill(&mut callee_responsible);
-                        *o = State::Waiting {
-                            canceling: true,
-                            callee_responsible,
-                            context,
-                            fd,
+                        // Give up waiting for the scheme if the context is being killed, if the
+                        // deadline has passed, or if the scheme ignored the cancellation. In the
+                        // first case, data loss doesn't matter, and in the others the caller
+                        // cannot know whether the request was carried out, similar to EINTR
+                        // returned by the scheme itself.
+                        let abandon = if current_context.read().being_sigkilled {
+                            Some(EINTR)
+                        } else if deadline.is_some_and(|deadline| now >= deadline) {
+                            Some(ETIMEDOUT)
+                        } else if cancel_deadline.is_some_and(|deadline| now >= deadline) {
+                            Some(EINTR)
+                        } else {
+                            None
                         };
-                        drop(states);
-                        maybe_eintr?;
 
-                        context::current().write().block("UserInner::call");
-                    }
-                    // spurious wakeup
-                    State::Waiting {
-                        canceling: false,
-                        fd,
-                        context,
-                        mut callee_responsible,
-                    } => {
-                        let maybe_eintr = eintr_if_sigkill(&mut callee_responsible);
+                        if let Some(errno) = abandon {
+                            // Callee must deallocate memory, rather than the caller. This is less
+                            // optimal for TLB, but we don't really have any other choice. The
+                            // scheme must be able to access the borrowed memory until it has
+                            // responded to the request. Dropping the context makes the eventual
+                            // response free the state rather than wake us up.
+                            *o = State::Waiting {
+                                canceling: true,
+                                fd,
+                                context: Weak::new(),
+                                callee_responsible: mem::replace(
+                                    caller_responsible,
+                                    PageSpan::empty(),
+                                ),
+                            };
+                            drop(states);
+                            if !canceling {
+                                self.cancel(sqe.tag);
+                            }
+                            return Err(Error::new(errno));
+                        }
+
                         *o = State::Waiting {
                             canceling: true,
                             fd,
@@ -363,18 +382,26 @@ impl UserInner {
                             callee_responsible,
                         };
 
+                        // Any wakeup other than the response is treated as a signal, which
+                        // cancels the call. Later wakeups while awaiting the cancellation are
+                        // ignored.
+                        if !canceling {
+                            cancel_deadline = Some(now + SCHEME_CANCEL_TIMEOUT);
+                        }
+
+                        // Block before releasing the states lock, so a response cannot be missed.
+                        let mut context = current_context.write();
+                        context.block("UserInner::call");
+                        context.wake = match (deadline, cancel_deadline) {
+                            (Some(a), Some(b)) => Some(a.min(b)),
+                            (a, b) => a.or(b),
+                        };
+                        drop(context);
                         drop(states);
-                        maybe_eintr?;
-
-                        // TODO: Is this too dangerous when the states lock is held?
-                        self.todo.send(Sqe {
-                            opcode: Opcode::Cancel as u8,
-                            sqe_flags: SqeFlags::ONEWAY,
-                            tag: sqe.tag,
-                            ..Default::default()
-                        });
-                        event::trigger(self.root_id, self.handle_id, EVENT_READ);
-                        context::current().write().block("UserInner::call");
+
+                        if !canceling {
+                            self.cancel(sqe.tag);
+                        }
                     }
 
                     // invalid state
@@ -391,6 +418,15 @@ impl UserInner {
             }
         }
     }
+    fn cancel(&self, tag: u32) {
+        self.todo.send(Sqe {
+            opcode: Opcode::Cancel as u8,
+            sqe_flags: SqeFlags::ONEWAY,
+            tag,
+            ..Default::default()
+        });
+        event::trigger(self.root_id, self.handle_id, EVENT_READ);
+    }
 
     /// Map a readable structure to the scheme's userspace and return the
     /// pointer
@@ -534,10 +570,7 @@ impl UserInner {
 
         let mut dst_space = dst_space_lock.acquire_write();
 
-        let free_span = dst_space
-            .grants
-            .find_free(dst_space.mmap_min, page_count)
-            .ok_or(Error::new(ENOMEM))?;
+        let free_span = dst_space.find_free(page_count).ok_or(Error::new(ENOMEM))?;
 
         let head = if !head_part_of_buf.is_empty() {
             // FIXME: Signal context can probably recursively use head/tail.
@@ -829,8 +862,20 @@ impl UserInner {
                     });
                 }
 
+                Opcode::Msync => {
+                    return Ok(Packet {
+                        id: u64::from(sqe.tag) + 1,
+                        pid: sqe.caller as usize,
+                        a: KSMSG_MSYNC,
+                        b: sqe.args[0] as usize,         // fd
+                        c: sqe.args[1] as usize,         // size
+                        d: sqe.args[2] as usize,         // flags
+                        uid: sqe.args[3] as u32,         // offset lo
+                        gid: (sqe.args[3] >> 32) as u32, // offset hi
+                    });
+                }
+
                 Opcode::Mremap => SYS_MREMAP,
-                Opcode::Msync => KSMSG_MSYNC,
 
                 Opcode::Cancel => {
                     return Ok(Packet {
@@ -1024,12 +1069,11 @@ impl UserInner {
 
                 let context = context.upgrade().ok_or(Error::new(ESRCH))?;
 
-                let (frame, _) = AddrSpace::current()?
-                    .acquire_read()
-                    .table
-                    .utable
-                    .translate(base_addr)
-                    .ok_or(Error::new(EFAULT))?;
+                let frame = huge_page::translate(
+                    &AddrSpace::current()?.acquire_read().table.utable,
+                    base_addr,
+                )
+                .ok_or(Error::new(EFAULT))?;
 
                 let mut context = context.write();
                 match context.status {
@@ -1692,6 +1736,23 @@ impl KernelScheme for UserScheme {
             Response::Fd(_) => Err(Error::new(EIO)),
         }
     }
+    fn kmsync(&self, number: usize, offset: usize, size: usize, flags: usize) -> Result<()> {
+        let inner = self.inner.upgrade().ok_or(Error::new(ENODEV))?;
+
+        let ctx = process::current()?.read().caller_ctx();
+        let res = inner.call_extended(
+            ctx,
+            None,
+            Opcode::Msync,
+            [number, size, flags, offset],
+            &mut PageSpan::empty(),
+        )?;
+
+        match res {
+            Response::Regular(_, _) => Ok(()),
+            Response::Fd(_) => Err(Error::new(EIO)),
+        }
+    }
     fn ksendfd(
         &self,
         number: usize,
@@ -0,0 +1,112 @@
+//! Orderly reboot and power-off.
+//!
+//! All other processes are sent SIGTERM and given a grace period to exit, so that daemons can
+//! flush their state. The architecture then halts the other CPUs and tries its reset or power-off
+//! methods in turn, from the most to the least graceful.
+
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use syscall::{SenderInfo, SIGTERM};
+
+use crate::{
+    context::{
+        self,
+        process::{self, ProcessStatus, PROCESSES},
+    },
+    syscall::process::{send_signal, KillMode, KillTarget},
+    time,
+};
+
+/// Time processes are given to exit after SIGTERM.
+const GRACE_PERIOD: u128 = 2 * time::NANOS_PER_SEC;
+/// Interval at which the processes are checked during the grace period.
+const POLL_INTERVAL: u128 = 10_000_000;
+
+static SHUTTING_DOWN: AtomicBool = AtomicBool::new(false);
+
+pub fn reboot() -> ! {
+    prepare("Rebooting");
+    unsafe { crate::stop::kreset() }
+}
+
+pub fn poweroff() -> ! {
+    prepare("Powering off");
+    unsafe { crate::stop::kstop() }
+}
+
+/// Reboot into the kernel loaded through `kexec:`.
+#[cfg(target_arch = "x86_64")]
+pub fn kexec() -> ! {
+    prepare("Rebooting into the loaded kernel");
+    unsafe { crate::kexec::exec() }
+}
+
+fn prepare(what: &str) {
+    if SHUTTING_DOWN.swap(true, Ordering::SeqCst) {
+        // Another context is already shutting down, which will never return here.
+        loop {
+            sleep(POLL_INTERVAL);
+        }
+    }
+    log::info!("{}", what);
+
+    terminate_processes();
+
+    ::log::logger().flush();
+}
+
+/// Send SIGTERM to all processes except the current one, and wait until they have exited or the
+/// grace period has passed.
+fn terminate_processes() {
+    let current_pid = process::current().map(|process| process.read().pid).ok();
+    let sender = SenderInfo { pid: 0, ruid: 0 };
+
+    let targets = PROCESSES
+        .read()
+        .iter()
+        .filter(|(pid, _)| Some(**pid) != current_pid)
+        .map(|(_, process)| process.clone())
+        .collect::<Vec<_>>();
+
+    for process in &targets {
+        let mut killed_self = false;
+        let _ = send_signal(
+            KillTarget::Process(process.clone()),
+            SIGTERM,
+            KillMode::Idempotent,
+            false,
+            &mut killed_self,
+            sender,
+        );
+    }
+
+    let deadline = time::monotonic() + GRACE_PERIOD;
+    while time::monotonic() < deadline {
+        let remaining = targets
+            .iter()
+            .filter(|process| {
+                !matches!(
+                    process.read().status,
+                    ProcessStatus::Exiting | ProcessStatus::Exited(_)
+                )
+            })
+            .count();
+        if remaining == 0 {
+            return;
+        }
+        sleep(POLL_INTERVAL);
+    }
+    log::warn!("Not all processes exited within the shutdown grace period");
+}
+
+fn sleep(duration: u128) {
+    {
+        let current = context::current();
+        let mut context = current.write();
+        context.wake = Some(time::monotonic() + duration);
+        context.block("shutdown");
+    }
+    context::switch();
+    context::current().write().wake = None;
+}
@@ -1,5 +1,5 @@
 use crate::{
-    arch::{consts::KERNEL_OFFSET, paging::entry::EntryFlags, rmm::page_flags, CurrentRmmArch},
+    arch::{paging::entry::EntryFlags, rmm::page_flags, CurrentRmmArch},
     memory::PAGE_SIZE,
     startup::memory::BootloaderMemoryKind::Null,
 };
@@ -32,7 +32,7 @@ pub enum BootloaderMemoryKind {
 // Keep synced with OsMemoryEntry in bootloader
 #[derive(Clone, Copy, Debug)]
 #[repr(C, packed(8))]
-struct BootloaderMemoryEntry {
+pub struct BootloaderMemoryEntry {
     pub base: u64,
     pub size: u64,
     pub kind: BootloaderMemoryKind,
@@ -143,6 +143,23 @@ fn align_down(x: usize) -> usize {
     x / PAGE_SIZE * PAGE_SIZE
 }
 
+/// Whether any part of the physical range is RAM, including the kernel image, rather than device
+/// memory or firmware reserved memory.
+pub fn overlaps_ram(base: usize, size: usize) -> bool {
+    let end = base.saturating_add(size);
+    unsafe { &*core::ptr::addr_of!(MEMORY_MAP) }
+        .iter()
+        .filter(|entry| {
+            matches!(
+                entry.kind,
+                BootloaderMemoryKind::Free
+                    | BootloaderMemoryKind::Reclaim
+                    | BootloaderMemoryKind::Kernel
+            )
+        })
+        .any(|entry| entry.start < end && base < entry.end)
+}
+
 pub fn register_memory_region(base: usize, size: usize, kind: BootloaderMemoryKind) {
     if kind != Null && size != 0 {
         log::debug!("Registering {:?} memory {:X} size {:X}", kind, base, size);
@@ -150,6 +167,57 @@ pub fn register_memory_region(base: usize, size: usize, kind: BootloaderMemoryKi
     }
 }
 
+/// The memory areas described by the bootloader, page aligned, without those registered by the
+/// kernel itself.
+#[cfg(target_arch = "x86_64")]
+pub fn bootloader_areas() -> impl Iterator<Item = BootloaderMemoryEntry> {
+    unsafe { &*core::ptr::addr_of!(MEMORY_MAP) }
+        .iter()
+        .filter(|entry| {
+            matches!(
+                entry.kind,
+                BootloaderMemoryKind::Free
+                    | BootloaderMemoryKind::Reclaim
+                    | BootloaderMemoryKind::Reserved
+            )
+        })
+        .map(|entry| BootloaderMemoryEntry {
+            base: entry.start as u64,
+            size: (entry.end - entry.start) as u64,
+            kind: entry.kind,
+        })
+}
+
+/// Take `size` bytes of free memory, at the highest page aligned address not otherwise reserved,
+/// away from the frame allocator. The memory stays mapped in the physmap. Must be called before
+/// [`init`].
+#[cfg(target_arch = "x86_64")]
+pub fn reserve_free(size: usize) -> Option<usize> {
+    let size = align_up(size);
+    let map = unsafe { &*core::ptr::addr_of!(MEMORY_MAP) };
+    let base = map
+        .free()
+        .filter_map(|area| {
+            let mut end = area.end;
+            loop {
+                let start = end.checked_sub(size)?;
+                if start < area.start {
+                    return None;
+                }
+                match map
+                    .non_free()
+                    .find(|reservation| reservation.start < end && start < reservation.end)
+                {
+                    Some(reservation) => end = reservation.start,
+                    None => return Some(start),
+                }
+            }
+        })
+        .max()?;
+    register_memory_region(base, size, BootloaderMemoryKind::IdentityMap);
+    Some(base)
+}
+
 pub fn register_bootloader_areas(areas_base: usize, areas_size: usize) {
     let bootloader_areas = unsafe {
         slice::from_raw_parts(
@@ -297,6 +365,8 @@ unsafe fn map_memory<A: Arch>(areas: &[MemoryArea], mut bump_allocator: &mut Bum
             let phys = area.base.add(i * PAGE_SIZE);
             let virt = A::phys_to_virt(phys);
             let flags = page_flags::<A>(virt);
+            #[cfg(target_arch = "aarch64")]
+            let flags = crate::mte::linear_flags(flags);
             let flush = mapper
                 .map_phys(virt, phys, flags)
                 .expect("failed to map frame");
@@ -307,10 +377,10 @@ unsafe fn map_memory<A: Arch>(areas: &[MemoryArea], mut bump_allocator: &mut Bum
     let kernel_area = MEMORY_MAP.kernel().unwrap();
     let kernel_base = kernel_area.start;
     let kernel_size = kernel_area.end - kernel_area.start;
-    // Map kernel at KERNEL_OFFSET and identity map too
+    // Map kernel at KERNEL_OFFSET, or wherever it was moved, and identity map too
     for i in 0..kernel_size / A::PAGE_SIZE {
         let phys = PhysicalAddress::new(kernel_base + i * PAGE_SIZE);
-        let virt = VirtualAddress::new(KERNEL_OFFSET + i * PAGE_SIZE);
+        let virt = VirtualAddress::new(crate::kaslr::kernel_offset() + i * PAGE_SIZE);
         let flags = page_flags::<A>(virt);
         let flush = mapper
             .map_phys(virt, phys, flags)
@@ -0,0 +1,121 @@
+//! # Suspend to RAM
+//!
+//! Writing `mem` to `sys:power` suspends the system. Userspace contexts other than the writer stop
+//! being scheduled, and the APs are taken offline, after which each parked AP saves its state and
+//! powers off through the architecture specific `sleep` module. The BSP then records the clocks
+//! and enters ACPI S3 or PSCI SYSTEM_SUSPEND, with interrupts disabled.
+//!
+//! On waking up, the BSP restores its devices and clocks, starts the APs again, which return to
+//! their park loop, and brings back online those that were online before, after which the frozen
+//! contexts run again.
+
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use crate::{
+    context::{self, Context},
+    cpu_set::{LogicalCpuId, LogicalCpuSet},
+    hotplug, interrupt, sleep,
+    syscall::error::{Error, Result, EBUSY, EOPNOTSUPP},
+    time,
+};
+
+/// Set while the system is being suspended.
+static SUSPENDING: AtomicBool = AtomicBool::new(false);
+/// Address of the context suspending the system while userspace is frozen, or 0.
+static FREEZER: AtomicUsize = AtomicUsize::new(0);
+/// Parked APs requested to save their state and power off.
+static SUSPEND_APS: LogicalCpuSet = LogicalCpuSet::empty();
+/// Number of APs that saved their state since the suspend started.
+static APS_DOWN: AtomicUsize = AtomicUsize::new(0);
+
+pub fn supported() -> bool {
+    sleep::supported()
+}
+
+/// Whether `context` must not be scheduled, as userspace is frozen while the system is suspended.
+pub fn is_frozen(context: &Context) -> bool {
+    let freezer = FREEZER.load(Ordering::Relaxed);
+    freezer != 0 && context.userspace && context as *const Context as usize != freezer
+}
+
+/// Suspend the system to RAM, returning once it woke up.
+pub fn suspend() -> Result<()> {
+    if !sleep::supported() {
+        return Err(Error::new(EOPNOTSUPP));
+    }
+    if SUSPENDING.swap(true, Ordering::Acquire) {
+        return Err(Error::new(EBUSY));
+    }
+
+    let current = context::current();
+    FREEZER.store(
+        &*current.read() as *const Context as usize,
+        Ordering::Relaxed,
+    );
+
+    // Going offline moves this context to the BSP if it ran on an AP.
+    let was_online: Vec<LogicalCpuId> = (1..crate::cpu_count())
+        .map(LogicalCpuId::new)
+        .filter(|&cpu| !hotplug::is_offline(cpu))
+        .collect();
+    for &cpu in &was_online {
+        if let Err(err) = hotplug::offline(cpu) {
+            log::warn!("CPU {} cannot go offline for suspend: {:?}", cpu, err);
+        }
+    }
+
+    let aps: Vec<LogicalCpuId> = (1..crate::cpu_count())
+        .map(LogicalCpuId::new)
+        .filter(|&cpu| hotplug::is_offline(cpu))
+        .collect();
+    APS_DOWN.store(0, Ordering::Relaxed);
+    for &cpu in &aps {
+        SUSPEND_APS.atomic_set(cpu);
+        crate::stop::unpark(cpu);
+    }
+    while APS_DOWN.load(Ordering::Acquire) < aps.len() {
+        interrupt::pause();
+    }
+
+    log::info!("Suspending to RAM");
+    let result = unsafe {
+        interrupt::disable();
+        time::suspend();
+        let result = sleep::enter();
+        time::resume();
+        for &cpu in &aps {
+            sleep::restart_ap(cpu);
+        }
+        interrupt::enable();
+        result
+    };
+    log::info!("Resumed from suspend");
+
+    for &cpu in &was_online {
+        if let Err(err) = hotplug::online(cpu) {
+            log::warn!("CPU {} cannot go online after suspend: {:?}", cpu, err);
+        }
+    }
+    FREEZER.store(0, Ordering::Relaxed);
+    SUSPENDING.store(false, Ordering::Release);
+
+    result
+}
+
+/// Called by the architecture specific `sleep` module on an AP, once it saved its state and is
+/// about to power off.
+pub fn ap_down() {
+    APS_DOWN.fetch_add(1, Ordering::Release);
+}
+
+/// Called by the park loop of this AP with interrupts disabled, to power it off if the system is
+/// being suspended, returning once it is started again.
+pub unsafe fn maybe_suspend_ap(cpu: LogicalCpuId) {
+    if !SUSPEND_APS.atomic_contains(cpu) {
+        return;
+    }
+    SUSPEND_APS.atomic_clear(cpu);
+    sleep::suspend_ap();
+    time::resume_ap();
+}
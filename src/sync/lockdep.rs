@@ -0,0 +1,509 @@
+//! Lock dependency validator, checking the order in which the kernel acquires its most widely used
+//! locks, to report potential deadlocks before they happen.
+//!
+//! Locks are grouped into [`LockClass`]es, such as all address space locks. Acquiring a lock of one
+//! class while holding one of another records a dependency between the classes, and a new
+//! dependency closing a cycle means that CPUs taking the locks in different orders can deadlock,
+//! even if they have not yet. Locks acquired by IRQ handlers must also never be held with
+//! interrupts enabled, as a handler could then spin on a lock held by the code it interrupted.
+//! Either is reported once, along with where the locks involved were first acquired.
+//!
+//! Only debug builds record anything, once [`init`] is called after every CPU has its percpu
+//! block. In release builds, a [`Tracked`] guard is just the guard of the lock.
+
+use core::ops::{Deref, DerefMut};
+
+#[cfg(debug_assertions)]
+use core::{
+    cell::Cell,
+    sync::atomic::{AtomicBool, AtomicU8, AtomicUsize, Ordering},
+};
+
+#[cfg(debug_assertions)]
+use crate::percpu::PercpuBlock;
+
+/// The groups of locks whose acquisition order is validated.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum LockClass {
+    /// The lock serializing updates of the list of all contexts.
+    Contexts,
+    /// The lock of an address space.
+    AddrSpace,
+    /// The free lists of the frame allocator.
+    FrameAllocator,
+}
+
+/// How a lock is acquired.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum LockMode {
+    /// Along with other readers.
+    Shared,
+    /// Excluding other readers, or other upgradeable readers.
+    Exclusive,
+}
+
+/// A lock guard whose lock is tracked by the validator for as long as it is held. Dereferences to
+/// the data protected by the lock, like the guard itself.
+pub struct Tracked<G> {
+    guard: G,
+    #[cfg(debug_assertions)]
+    held: Held,
+}
+
+impl<G> Tracked<G> {
+    /// Acquire `lock`, a lock of `class`, using `acquire`.
+    #[inline(always)]
+    pub fn acquire<'a, L>(
+        class: LockClass,
+        lock: &'a L,
+        mode: LockMode,
+        acquire: impl FnOnce(&'a L) -> G,
+    ) -> Self {
+        #[cfg(debug_assertions)]
+        let held = Held::acquire(class, lock as *const L as usize, mode);
+        #[cfg(not(debug_assertions))]
+        let _ = (class, mode);
+
+        Self {
+            guard: acquire(lock),
+            #[cfg(debug_assertions)]
+            held,
+        }
+    }
+    /// Convert the guard while the lock stays held, such as when upgrading it.
+    #[inline(always)]
+    pub fn map<H>(this: Self, f: impl FnOnce(G) -> H) -> Tracked<H> {
+        Tracked {
+            guard: f(this.guard),
+            #[cfg(debug_assertions)]
+            held: this.held,
+        }
+    }
+}
+
+impl<G: Deref> Deref for Tracked<G> {
+    type Target = G::Target;
+
+    #[inline(always)]
+    fn deref(&self) -> &G::Target {
+        &*self.guard
+    }
+}
+impl<G: DerefMut> DerefMut for Tracked<G> {
+    #[inline(always)]
+    fn deref_mut(&mut self) -> &mut G::Target {
+        &mut *self.guard
+    }
+}
+
+/// Marks the current CPU as running an IRQ handler for as long as it exists. Created on entry to
+/// the handlers of hardware interrupts and IPIs.
+pub struct IrqScope(());
+
+impl IrqScope {
+    #[inline(always)]
+    pub fn enter() -> Self {
+        #[cfg(debug_assertions)]
+        if ACTIVE.load(Ordering::Relaxed) {
+            let depth = &PercpuBlock::current().lockdep.irq_depth;
+            depth.set(depth.get() + 1);
+        }
+        Self(())
+    }
+}
+
+#[cfg(debug_assertions)]
+impl Drop for IrqScope {
+    #[inline(always)]
+    fn drop(&mut self) {
+        if ACTIVE.load(Ordering::Relaxed) {
+            let depth = &PercpuBlock::current().lockdep.irq_depth;
+            depth.set(depth.get().saturating_sub(1));
+        }
+    }
+}
+
+#[cfg(debug_assertions)]
+const CLASS_COUNT: usize = 3;
+
+#[cfg(debug_assertions)]
+impl LockClass {
+    const ALL: [Self; CLASS_COUNT] = [Self::Contexts, Self::AddrSpace, Self::FrameAllocator];
+
+    fn name(self) -> &'static str {
+        match self {
+            Self::Contexts => "contexts list",
+            Self::AddrSpace => "address space",
+            Self::FrameAllocator => "frame allocator",
+        }
+    }
+}
+
+#[cfg(debug_assertions)]
+impl LockMode {
+    fn bit(self) -> u8 {
+        match self {
+            Self::Shared => 1 << 0,
+            Self::Exclusive => 1 << 1,
+        }
+    }
+}
+
+/// Number of return addresses recorded for every acquisition.
+#[cfg(debug_assertions)]
+const PCS: usize = 4;
+
+/// Maximum number of tracked locks a CPU can hold at once. Further locks are not tracked.
+#[cfg(debug_assertions)]
+const MAX_HELD: usize = 8;
+
+/// Whether [`init`] was called, before which CPUs may not have percpu blocks yet.
+#[cfg(debug_assertions)]
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+
+/// Where a dependency or usage of a class was first seen.
+#[cfg(debug_assertions)]
+struct Site {
+    /// Return addresses of the code acquiring the lock, innermost first, all zero if not seen.
+    pcs: [AtomicUsize; PCS],
+}
+
+#[cfg(debug_assertions)]
+impl Site {
+    const INIT: Self = Self {
+        pcs: [const { AtomicUsize::new(0) }; PCS],
+    };
+
+    fn is_set(&self) -> bool {
+        self.pcs[0].load(Ordering::Relaxed) != 0
+    }
+    /// Record `pcs` unless already set, returning whether this was the first time.
+    fn record(&self, pcs: &[usize; PCS]) -> bool {
+        // Failed unwinds still mark the site as seen.
+        if self.pcs[0]
+            .compare_exchange(0, pcs[0].max(1), Ordering::Relaxed, Ordering::Relaxed)
+            .is_err()
+        {
+            return false;
+        }
+        for (slot, &pc) in self.pcs[1..].iter().zip(&pcs[1..]) {
+            slot.store(pc, Ordering::Relaxed);
+        }
+        true
+    }
+    fn print(&self) {
+        for pc in &self.pcs {
+            print_pc(pc.load(Ordering::Relaxed));
+        }
+    }
+}
+
+/// For every pair of classes, where a lock of the second was first acquired while holding one of
+/// the first.
+#[cfg(debug_assertions)]
+static DEPENDENCIES: [[Site; CLASS_COUNT]; CLASS_COUNT] =
+    [const { [const { Site::INIT }; CLASS_COUNT] }; CLASS_COUNT];
+
+/// How the locks of a class are acquired in one of the two situations that must not overlap.
+#[cfg(debug_assertions)]
+struct IrqUsage {
+    /// The [`LockMode::bit`]s of the modes seen.
+    modes: AtomicU8,
+    /// Where each mode was first seen, indexed by the number of the bit.
+    sites: [Site; 2],
+}
+
+#[cfg(debug_assertions)]
+impl IrqUsage {
+    const INIT: Self = Self {
+        modes: AtomicU8::new(0),
+        sites: [Site::INIT, Site::INIT],
+    };
+
+    fn record(&self, mode: LockMode, pcs: &[usize; PCS]) {
+        if self.modes.fetch_or(mode.bit(), Ordering::Relaxed) & mode.bit() == 0 {
+            self.sites[mode.bit().trailing_zeros() as usize].record(pcs);
+        }
+    }
+    fn print(&self) {
+        for site in self.sites.iter().filter(|site| site.is_set()) {
+            site.print();
+        }
+    }
+}
+
+/// Per class, how its locks are acquired by IRQ handlers.
+#[cfg(debug_assertions)]
+static IN_IRQ: [IrqUsage; CLASS_COUNT] = [const { IrqUsage::INIT }; CLASS_COUNT];
+/// Per class, how its locks are acquired with interrupts enabled, outside of IRQ handlers.
+#[cfg(debug_assertions)]
+static IRQS_ENABLED: [IrqUsage; CLASS_COUNT] = [const { IrqUsage::INIT }; CLASS_COUNT];
+#[cfg(debug_assertions)]
+static IRQ_REPORTED: [AtomicBool; CLASS_COUNT] = [const { AtomicBool::new(false) }; CLASS_COUNT];
+
+#[cfg(debug_assertions)]
+#[derive(Clone, Copy)]
+struct HeldLock {
+    class: LockClass,
+    /// Address of the lock, telling apart the locks of a class.
+    addr: usize,
+    mode: LockMode,
+    pcs: [usize; PCS],
+}
+
+/// The tracked locks held by a CPU, and whether it is running an IRQ handler.
+#[cfg(debug_assertions)]
+pub struct PercpuLockdep {
+    /// Only changed by the CPU itself, with IRQ handlers that interrupt it releasing the locks they
+    /// acquire before returning.
+    held: [Cell<Option<HeldLock>>; MAX_HELD],
+    held_count: Cell<usize>,
+    /// Number of nested IRQ handlers running on this CPU. Exchanged with that of the context on
+    /// context switches, as IRQ handlers may switch contexts.
+    pub irq_depth: Cell<usize>,
+}
+
+#[cfg(debug_assertions)]
+impl PercpuLockdep {
+    pub const fn new() -> Self {
+        Self {
+            held: [const { Cell::new(None) }; MAX_HELD],
+            held_count: Cell::new(0),
+            irq_depth: Cell::new(0),
+        }
+    }
+}
+
+/// Releases the tracked lock when dropped, after the guard of the lock.
+#[cfg(debug_assertions)]
+struct Held {
+    class: LockClass,
+    addr: usize,
+    active: bool,
+}
+
+#[cfg(debug_assertions)]
+impl Held {
+    #[inline(never)]
+    fn acquire(class: LockClass, addr: usize, mode: LockMode) -> Self {
+        let active = ACTIVE.load(Ordering::Relaxed);
+        if active {
+            let mut pcs = [0; PCS];
+            crate::unwind::return_addresses(&mut pcs);
+            check_acquire(HeldLock {
+                class,
+                addr,
+                mode,
+                pcs,
+            });
+        }
+        Self {
+            class,
+            addr,
+            active,
+        }
+    }
+}
+
+#[cfg(debug_assertions)]
+impl Drop for Held {
+    fn drop(&mut self) {
+        if !self.active {
+            return;
+        }
+        let state = &PercpuBlock::current().lockdep;
+        let count = state.held_count.get();
+
+        // Locks are not necessarily released in the reverse order they were acquired.
+        let Some(index) = (0..count).rev().find(|&i| {
+            state.held[i]
+                .get()
+                .is_some_and(|held| held.class == self.class && held.addr == self.addr)
+        }) else {
+            return;
+        };
+        for i in index..count - 1 {
+            state.held[i].set(state.held[i + 1].get());
+        }
+        state.held[count - 1].set(None);
+        state.held_count.set(count - 1);
+    }
+}
+
+/// Start validating, once every CPU has its percpu block.
+#[cfg(debug_assertions)]
+pub fn init() {
+    ACTIVE.store(true, Ordering::Relaxed);
+}
+
+#[cfg(debug_assertions)]
+fn check_acquire(lock: HeldLock) {
+    let state = &PercpuBlock::current().lockdep;
+    let count = state.held_count.get();
+
+    // Nesting locks of the same class, such as the address spaces a page fault copies between,
+    // cannot be ordered by class, and only acquiring the same lock twice is reported.
+    for held in state.held[..count].iter().filter_map(Cell::get) {
+        if held.class != lock.class {
+            add_dependency(&held, &lock);
+        } else if held.addr == lock.addr
+            && (held.mode == LockMode::Exclusive || lock.mode == LockMode::Exclusive)
+        {
+            println!(
+                "LOCKDEP: CPU {} acquiring {} lock {:#x} it already holds",
+                crate::cpu_id(),
+                lock.class.name(),
+                lock.addr
+            );
+            print_pcs(&held.pcs);
+            print_stack_trace();
+        }
+    }
+
+    check_irq_safety(state, &lock);
+
+    if count < MAX_HELD {
+        state.held[count].set(Some(lock));
+        state.held_count.set(count + 1);
+    }
+}
+
+#[cfg(debug_assertions)]
+fn add_dependency(held: &HeldLock, lock: &HeldLock) {
+    if !DEPENDENCIES[held.class as usize][lock.class as usize].record(&lock.pcs) {
+        return;
+    }
+
+    // The new dependency closes a cycle if locks of the held class were already acquired while
+    // holding one of the new class, directly or through other classes.
+    let mut path = [lock.class; CLASS_COUNT];
+    let Some(len) = find_path(lock.class, held.class, &mut 0, &mut path, 0) else {
+        return;
+    };
+
+    println!(
+        "LOCKDEP: possible deadlock on CPU {}, acquiring {} while holding {}",
+        crate::cpu_id(),
+        lock.class.name(),
+        held.class.name()
+    );
+    for pair in path[..len].windows(2) {
+        println!(
+            "  {} -> {} first acquired at",
+            pair[0].name(),
+            pair[1].name()
+        );
+        DEPENDENCIES[pair[0] as usize][pair[1] as usize].print();
+    }
+    println!("  {} acquired at", held.class.name());
+    print_pcs(&held.pcs);
+    println!(
+        "  {} -> {} acquired at",
+        held.class.name(),
+        lock.class.name()
+    );
+    print_stack_trace();
+}
+
+/// Find a chain of dependencies from `from` to `to`, storing the classes along it in `path`
+/// starting at `len`, and returning the length of the path.
+#[cfg(debug_assertions)]
+fn find_path(
+    from: LockClass,
+    to: LockClass,
+    visited: &mut u32,
+    path: &mut [LockClass; CLASS_COUNT],
+    len: usize,
+) -> Option<usize> {
+    path[len] = from;
+    if from == to {
+        return Some(len + 1);
+    }
+    *visited |= 1 << from as u32;
+
+    LockClass::ALL.into_iter().find_map(|next| {
+        if *visited & (1 << next as u32) != 0
+            || !DEPENDENCIES[from as usize][next as usize].is_set()
+        {
+            return None;
+        }
+        find_path(next, to, visited, path, len + 1)
+    })
+}
+
+#[cfg(debug_assertions)]
+fn check_irq_safety(state: &PercpuLockdep, lock: &HeldLock) {
+    let class = lock.class as usize;
+    if state.irq_depth.get() > 0 {
+        IN_IRQ[class].record(lock.mode, &lock.pcs);
+    } else if crate::interrupt::enabled() {
+        IRQS_ENABLED[class].record(lock.mode, &lock.pcs);
+    } else {
+        return;
+    }
+
+    // Shared acquisitions only conflict with exclusive ones.
+    let exclusive = LockMode::Exclusive.bit();
+    let in_irq = IN_IRQ[class].modes.load(Ordering::Relaxed);
+    let irqs_enabled = IRQS_ENABLED[class].modes.load(Ordering::Relaxed);
+    let conflict = (in_irq != 0 && irqs_enabled & exclusive != 0)
+        || (in_irq & exclusive != 0 && irqs_enabled != 0);
+    if !conflict || IRQ_REPORTED[class].swap(true, Ordering::Relaxed) {
+        return;
+    }
+
+    println!(
+        "LOCKDEP: {} lock acquired both in IRQ handlers and with interrupts enabled, on CPU {}",
+        lock.class.name(),
+        crate::cpu_id()
+    );
+    println!("  in IRQ handlers first at");
+    IN_IRQ[class].print();
+    println!("  with interrupts enabled first at");
+    IRQS_ENABLED[class].print();
+}
+
+/// Check that no tracked lock is held across a context switch, as the lock would then be released
+/// by another context, or on another CPU. Forgets the locks after reporting them.
+#[cfg(debug_assertions)]
+pub fn check_switch() {
+    if !ACTIVE.load(Ordering::Relaxed) {
+        return;
+    }
+    let state = &PercpuBlock::current().lockdep;
+    let count = state.held_count.replace(0);
+
+    for held in state.held[..count].iter().filter_map(|held| held.take()) {
+        println!(
+            "LOCKDEP: CPU {} switching contexts while holding {} lock {:#x}, acquired at",
+            crate::cpu_id(),
+            held.class.name(),
+            held.addr
+        );
+        print_pcs(&held.pcs);
+    }
+}
+
+#[cfg(debug_assertions)]
+fn print_pc(pc: usize) {
+    if pc > 1 {
+        println!("    {:>016x}", pc);
+        unsafe {
+            crate::panic::symbol_trace(pc);
+        }
+    }
+}
+
+#[cfg(debug_assertions)]
+fn print_pcs(pcs: &[usize; PCS]) {
+    for &pc in pcs {
+        print_pc(pc);
+    }
+}
+
+#[cfg(debug_assertions)]
+fn print_stack_trace() {
+    unsafe {
+        crate::panic::stack_trace();
+    }
+}
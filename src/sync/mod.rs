@@ -1,5 +1,16 @@
-pub use self::{wait_condition::WaitCondition, wait_map::WaitMap, wait_queue::WaitQueue};
+pub use self::{
+    spinlock::{
+        ArcRwSpinlockWriteGuard, Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockUpgradableGuard,
+        RwLockWriteGuard, RwSpinlock,
+    },
+    wait_condition::WaitCondition,
+    wait_map::WaitMap,
+    wait_queue::WaitQueue,
+};
 
+pub mod lockdep;
+pub mod rcu;
+pub mod spinlock;
 pub mod wait_condition;
 pub mod wait_map;
 pub mod wait_queue;
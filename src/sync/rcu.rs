@@ -0,0 +1,202 @@
+//! Read-copy-update.
+//!
+//! Data read far more often than it changes, like the list of all contexts, is published through
+//! an [`Rcu`] pointer. Readers enter a read-side critical section, which never blocks nor spins, and
+//! use the version they found for as long as they are in it. Writers, serialized by a lock of their
+//! own, publish a modified copy, and the previous version is only dropped once every reader that
+//! could still be using it has left its section.
+//!
+//! Grace periods are tracked with a global epoch. A CPU entering a section records the epoch at the
+//! time, and values are retired with the epoch at which they were unpublished. Reclamation runs from
+//! delayed work, which advances the epoch and drops the values retired before the oldest epoch
+//! recorded by a CPU that is still in a section.
+//!
+//! Sections are per-CPU, so a context must leave them before switching, like spinlocks.
+//!
+//! The grants of an address space are not published this way. Their most frequent readers, page
+//! faults, update them and the page tables under the same lock, and copying them on every `mmap`
+//! would cost more than the readers would save.
+
+use alloc::{boxed::Box, vec::Vec};
+use core::{
+    cell::Cell,
+    marker::PhantomData,
+    ops::Deref,
+    ptr,
+    sync::atomic::{fence, AtomicPtr, AtomicU64, Ordering},
+};
+
+use crate::{
+    cpu_set::LogicalCpuId,
+    percpu::PercpuBlock,
+    sync::Mutex,
+    time,
+    workqueue::{schedule_delayed_work, Work},
+};
+
+/// Time between retiring a value and trying to drop it.
+const GRACE_PERIOD: u128 = time::NANOS_PER_SEC / 100;
+
+/// Epoch recorded by a CPU outside of any section.
+const QUIESCENT: u64 = 0;
+
+static EPOCH: AtomicU64 = AtomicU64::new(QUIESCENT + 1);
+
+/// The read-side state of a CPU.
+pub struct PercpuRcu {
+    nesting: Cell<usize>,
+    /// The epoch at which the outermost section was entered, or `QUIESCENT`.
+    epoch: AtomicU64,
+}
+
+impl PercpuRcu {
+    pub const fn new() -> Self {
+        Self {
+            nesting: Cell::new(0),
+            epoch: AtomicU64::new(QUIESCENT),
+        }
+    }
+}
+
+/// A read-side critical section, left when dropped.
+pub struct RcuReadGuard {
+    /// Must be dropped on the CPU it was entered on.
+    _not_send: PhantomData<*const ()>,
+}
+
+/// Enter a read-side critical section. Sections can be nested.
+pub fn read_lock() -> RcuReadGuard {
+    let rcu = &PercpuBlock::current().rcu;
+    let nesting = rcu.nesting.get();
+    if nesting == 0 {
+        rcu.epoch
+            .store(EPOCH.load(Ordering::Relaxed), Ordering::Relaxed);
+        // Orders the store before reading any published pointer, against `reclaim`.
+        fence(Ordering::SeqCst);
+    }
+    rcu.nesting.set(nesting + 1);
+    RcuReadGuard {
+        _not_send: PhantomData,
+    }
+}
+
+impl Drop for RcuReadGuard {
+    fn drop(&mut self) {
+        let rcu = &PercpuBlock::current().rcu;
+        // Already zero if it was reported by `check_switch`.
+        let nesting = rcu.nesting.get().saturating_sub(1);
+        rcu.nesting.set(nesting);
+        if nesting == 0 {
+            rcu.epoch.store(QUIESCENT, Ordering::Release);
+        }
+    }
+}
+
+/// A value retired from an [`Rcu`] pointer, and when.
+struct Retired {
+    epoch: u64,
+    value: Box<dyn Send>,
+}
+
+static RETIRED: Mutex<Vec<Retired>> = Mutex::new(Vec::new());
+static RECLAIM: Work = Work::new(reclaim);
+
+/// Drop `value` once the readers that could be using it have left their sections.
+pub fn defer_drop<T: Send + 'static>(value: T) {
+    // Read after the value was unpublished.
+    let epoch = EPOCH.load(Ordering::SeqCst);
+    RETIRED.lock().push(Retired {
+        epoch,
+        value: Box::new(value),
+    });
+    schedule_delayed_work(&RECLAIM, GRACE_PERIOD);
+}
+
+/// Advance the epoch, and drop the retired values no reader can be using anymore.
+pub fn reclaim() {
+    let epoch = EPOCH.fetch_add(1, Ordering::SeqCst) + 1;
+    fence(Ordering::SeqCst);
+
+    let oldest_reader = (0..crate::cpu_count())
+        .filter_map(|id| crate::percpu::get(LogicalCpuId::new(id)))
+        .map(|percpu| percpu.rcu.epoch.load(Ordering::Acquire))
+        .filter(|&epoch| epoch != QUIESCENT)
+        .fold(epoch, u64::min);
+
+    let expired = {
+        let mut retired = RETIRED.lock();
+        let (expired, waiting) = core::mem::take(&mut *retired)
+            .into_iter()
+            .partition::<Vec<_>, _>(|retired| retired.epoch < oldest_reader);
+        *retired = waiting;
+        if !retired.is_empty() {
+            schedule_delayed_work(&RECLAIM, GRACE_PERIOD);
+        }
+        expired
+    };
+    // Dropped without the lock, as they may retire values themselves.
+    drop(expired);
+}
+
+/// A pointer to the current version of a value, which writers replace as a whole.
+pub struct Rcu<T: 'static> {
+    ptr: AtomicPtr<T>,
+    /// The version published first, which is not on the heap.
+    initial: &'static T,
+}
+
+impl<T: Send + Sync> Rcu<T> {
+    pub const fn new(initial: &'static T) -> Self {
+        Self {
+            ptr: AtomicPtr::new(ptr::from_ref(initial).cast_mut()),
+            initial,
+        }
+    }
+
+    /// The current version, valid until the returned reference is dropped.
+    pub fn read(&self) -> RcuRef<T> {
+        let guard = read_lock();
+        let value = unsafe { &*self.ptr.load(Ordering::Acquire) };
+        RcuRef {
+            _guard: guard,
+            value,
+        }
+    }
+
+    /// Publish `value`, and retire the previous version. Writers must be serialized.
+    pub fn replace(&self, value: Box<T>) {
+        let old = self.ptr.swap(Box::into_raw(value), Ordering::AcqRel);
+        if !ptr::eq(old, self.initial) {
+            defer_drop(unsafe { Box::from_raw(old) });
+        }
+    }
+}
+
+/// A version of the value of an [`Rcu`] pointer, along with the section keeping it alive.
+pub struct RcuRef<T: 'static> {
+    _guard: RcuReadGuard,
+    /// Only valid until the guard is dropped.
+    value: &'static T,
+}
+
+impl<T: 'static> Deref for RcuRef<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.value
+    }
+}
+
+/// Check that no section is held across a context switch, as it would then be left by another
+/// context. Forgets the section after reporting it.
+#[cfg(debug_assertions)]
+pub fn check_switch() {
+    let rcu = &PercpuBlock::current().rcu;
+    if rcu.nesting.replace(0) != 0 {
+        println!(
+            "RCU: CPU {} switching contexts inside a read-side critical section",
+            crate::cpu_id()
+        );
+        rcu.epoch.store(QUIESCENT, Ordering::Release);
+    }
+}
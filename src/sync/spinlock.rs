@@ -0,0 +1,415 @@
+//! Spinlocks, used for locks that are held across short critical sections only, such as those of
+//! contexts. [`Mutex`] and [`RwLock`] stand in for those of the `spin` crate throughout the kernel,
+//! except for the locks of the debug output, through which the reports below are printed.
+//!
+//! In debug builds, a lock records which CPU and context last acquired it for writing, and from
+//! where, and the same for the last reader. A CPU spinning for longer than [`SPIN_TIMEOUT`] prints
+//! that information and its own backtrace, and asks the owner CPU to print its backtrace too, so
+//! that deadlocks result in a report rather than a silent hang. Owners are only recorded once
+//! [`init`] is called, as that needs the percpu block of the CPU.
+
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use lock_api::{
+    GuardSend, RawMutex, RawRwLock, RawRwLockDowngrade, RawRwLockUpgrade, RawRwLockUpgradeDowngrade,
+};
+
+pub type Mutex<T> = lock_api::Mutex<RawSpinMutex, T>;
+pub type MutexGuard<'a, T> = lock_api::MutexGuard<'a, RawSpinMutex, T>;
+
+pub type RwSpinlock<T> = lock_api::RwLock<RawRwSpinlock, T>;
+pub type ArcRwSpinlockWriteGuard<T> = lock_api::ArcRwLockWriteGuard<RawRwSpinlock, T>;
+
+/// [`RwSpinlock`], under the name of the `spin` crate lock it replaces.
+pub type RwLock<T> = RwSpinlock<T>;
+pub type RwLockReadGuard<'a, T> = lock_api::RwLockReadGuard<'a, RawRwSpinlock, T>;
+pub type RwLockWriteGuard<'a, T> = lock_api::RwLockWriteGuard<'a, RawRwSpinlock, T>;
+pub type RwLockUpgradableGuard<'a, T> = lock_api::RwLockUpgradableReadGuard<'a, RawRwSpinlock, T>;
+
+const WRITER: usize = 1;
+/// Held by the one reader allowed to upgrade to a writer, which keeps new readers out.
+const UPGRADABLE: usize = 2;
+/// Readers are counted in the remaining bits.
+const READER: usize = 4;
+
+/// Number of attempts after which the spinning CPU reports the lock as stuck, which is at least a
+/// few seconds on current hardware.
+#[cfg(debug_assertions)]
+pub const SPIN_TIMEOUT: usize = 1 << 28;
+
+/// Whether [`init`] was called, before which CPUs may not have percpu blocks yet.
+#[cfg(debug_assertions)]
+static RECORD_OWNERS: AtomicBool = AtomicBool::new(false);
+
+/// Start recording lock owners, once every CPU has its percpu block.
+#[cfg(debug_assertions)]
+pub fn init() {
+    RECORD_OWNERS.store(true, Ordering::Relaxed);
+}
+
+/// Spin until `try_lock` succeeds, calling `report` if that takes too long in debug builds.
+#[inline]
+#[cfg_attr(not(debug_assertions), allow(unused_variables))]
+fn spin_until(mut try_lock: impl FnMut() -> bool, report: impl Fn()) {
+    #[cfg(debug_assertions)]
+    let mut spins = 0_usize;
+
+    while !try_lock() {
+        core::hint::spin_loop();
+
+        #[cfg(debug_assertions)]
+        {
+            spins += 1;
+            if spins == SPIN_TIMEOUT {
+                report();
+            }
+        }
+    }
+}
+
+pub struct RawSpinMutex {
+    locked: AtomicBool,
+    #[cfg(debug_assertions)]
+    owner: Owner,
+}
+
+unsafe impl RawMutex for RawSpinMutex {
+    const INIT: Self = Self {
+        locked: AtomicBool::new(false),
+        #[cfg(debug_assertions)]
+        owner: Owner::INIT,
+    };
+
+    type GuardMarker = GuardSend;
+
+    #[inline]
+    fn lock(&self) {
+        spin_until(|| self.try_lock(), || self.report_timeout());
+    }
+
+    #[inline]
+    fn try_lock(&self) -> bool {
+        let locked = self
+            .locked
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok();
+
+        #[cfg(debug_assertions)]
+        if locked {
+            self.owner.set();
+        }
+        locked
+    }
+
+    #[inline]
+    unsafe fn unlock(&self) {
+        #[cfg(debug_assertions)]
+        self.owner.clear();
+
+        self.locked.store(false, Ordering::Release);
+    }
+
+    #[inline]
+    fn is_locked(&self) -> bool {
+        self.locked.load(Ordering::Relaxed)
+    }
+}
+
+pub struct RawRwSpinlock {
+    state: AtomicUsize,
+    /// The writer, or the upgradable reader.
+    #[cfg(debug_assertions)]
+    owner: Owner,
+    /// The last reader, which may have released the lock since.
+    #[cfg(debug_assertions)]
+    reader: Owner,
+}
+
+impl RawRwSpinlock {
+    #[inline]
+    fn spin_until(&self, try_lock: impl FnMut() -> bool) {
+        spin_until(try_lock, || self.report_timeout());
+    }
+}
+
+unsafe impl RawRwLock for RawRwSpinlock {
+    const INIT: Self = Self {
+        state: AtomicUsize::new(0),
+        #[cfg(debug_assertions)]
+        owner: Owner::INIT,
+        #[cfg(debug_assertions)]
+        reader: Owner::INIT,
+    };
+
+    type GuardMarker = GuardSend;
+
+    #[inline]
+    fn lock_shared(&self) {
+        self.spin_until(|| self.try_lock_shared());
+    }
+
+    #[inline]
+    fn try_lock_shared(&self) -> bool {
+        let state = self.state.load(Ordering::Relaxed);
+        let locked = state & (WRITER | UPGRADABLE) == 0
+            && self
+                .state
+                .compare_exchange(state, state + READER, Ordering::Acquire, Ordering::Relaxed)
+                .is_ok();
+
+        #[cfg(debug_assertions)]
+        if locked {
+            self.reader.set();
+        }
+        locked
+    }
+
+    #[inline]
+    unsafe fn unlock_shared(&self) {
+        self.state.fetch_sub(READER, Ordering::Release);
+    }
+
+    #[inline]
+    fn lock_exclusive(&self) {
+        self.spin_until(|| self.try_lock_exclusive());
+    }
+
+    #[inline]
+    fn try_lock_exclusive(&self) -> bool {
+        let locked = self
+            .state
+            .compare_exchange(0, WRITER, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok();
+
+        #[cfg(debug_assertions)]
+        if locked {
+            self.owner.set();
+        }
+        locked
+    }
+
+    #[inline]
+    unsafe fn unlock_exclusive(&self) {
+        #[cfg(debug_assertions)]
+        self.owner.clear();
+
+        self.state.fetch_and(!WRITER, Ordering::Release);
+    }
+
+    #[inline]
+    fn is_locked(&self) -> bool {
+        self.state.load(Ordering::Relaxed) != 0
+    }
+
+    #[inline]
+    fn is_locked_exclusive(&self) -> bool {
+        self.state.load(Ordering::Relaxed) & WRITER != 0
+    }
+}
+
+unsafe impl RawRwLockUpgrade for RawRwSpinlock {
+    #[inline]
+    fn lock_upgradable(&self) {
+        self.spin_until(|| self.try_lock_upgradable());
+    }
+
+    #[inline]
+    fn try_lock_upgradable(&self) -> bool {
+        let state = self.state.load(Ordering::Relaxed);
+        let locked = state & (WRITER | UPGRADABLE) == 0
+            && self
+                .state
+                .compare_exchange(
+                    state,
+                    state | UPGRADABLE,
+                    Ordering::Acquire,
+                    Ordering::Relaxed,
+                )
+                .is_ok();
+
+        #[cfg(debug_assertions)]
+        if locked {
+            self.owner.set();
+        }
+        locked
+    }
+
+    #[inline]
+    unsafe fn unlock_upgradable(&self) {
+        #[cfg(debug_assertions)]
+        self.owner.clear();
+
+        self.state.fetch_and(!UPGRADABLE, Ordering::Release);
+    }
+
+    #[inline]
+    unsafe fn upgrade(&self) {
+        self.spin_until(|| unsafe { self.try_upgrade() });
+    }
+
+    #[inline]
+    unsafe fn try_upgrade(&self) -> bool {
+        // The owner stays the same.
+        self.state
+            .compare_exchange(UPGRADABLE, WRITER, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok()
+    }
+}
+
+// Downgrades trade the bit of the writer or upgradable reader for another in a single addition,
+// leaving the readers counted alongside an upgradable reader alone.
+unsafe impl RawRwLockDowngrade for RawRwSpinlock {
+    #[inline]
+    unsafe fn downgrade(&self) {
+        #[cfg(debug_assertions)]
+        self.owner.clear();
+
+        self.state.fetch_add(READER - WRITER, Ordering::Release);
+    }
+}
+
+unsafe impl RawRwLockUpgradeDowngrade for RawRwSpinlock {
+    #[inline]
+    unsafe fn downgrade_upgradable(&self) {
+        #[cfg(debug_assertions)]
+        self.owner.clear();
+
+        self.state.fetch_add(READER - UPGRADABLE, Ordering::Release);
+    }
+
+    #[inline]
+    unsafe fn downgrade_to_upgradable(&self) {
+        self.state.fetch_add(UPGRADABLE - WRITER, Ordering::Release);
+    }
+}
+
+/// Number of return addresses recorded when acquiring a lock, enough to skip the lock methods.
+#[cfg(debug_assertions)]
+const OWNER_PCS: usize = 6;
+
+/// A CPU and context that acquired a lock.
+#[cfg(debug_assertions)]
+struct Owner {
+    /// Logical CPU ID plus one, or zero if unlocked.
+    cpu: AtomicUsize,
+    context: AtomicUsize,
+    /// Return addresses of the code that acquired the lock, innermost first.
+    pcs: [AtomicUsize; OWNER_PCS],
+}
+
+#[cfg(debug_assertions)]
+impl Owner {
+    const INIT: Self = {
+        const ZERO: AtomicUsize = AtomicUsize::new(0);
+        Self {
+            cpu: ZERO,
+            context: ZERO,
+            pcs: [ZERO; OWNER_PCS],
+        }
+    };
+
+    #[inline(always)]
+    fn set(&self) {
+        if !RECORD_OWNERS.load(Ordering::Relaxed) {
+            return;
+        }
+        let percpu = crate::percpu::PercpuBlock::current();
+
+        let mut pcs = [0; OWNER_PCS];
+        crate::unwind::return_addresses(&mut pcs);
+        for (slot, pc) in self.pcs.iter().zip(pcs) {
+            slot.store(pc, Ordering::Relaxed);
+        }
+        self.context
+            .store(percpu.switch_internals.context_addr(), Ordering::Relaxed);
+        self.cpu
+            .store(percpu.cpu_id.get() as usize + 1, Ordering::Relaxed);
+    }
+    fn clear(&self) {
+        self.cpu.store(0, Ordering::Relaxed);
+    }
+
+    /// Print the owner as `role`, returning its CPU if it was recorded.
+    #[cold]
+    fn report(&self, role: &str) -> Option<crate::cpu_set::LogicalCpuId> {
+        let cpu = self.cpu.load(Ordering::Relaxed);
+        if cpu == 0 {
+            return None;
+        }
+        println!(
+            "{}: CPU {}, CID {:#x}",
+            role,
+            cpu - 1,
+            self.context.load(Ordering::Relaxed)
+        );
+        for pc in &self.pcs {
+            let pc = pc.load(Ordering::Relaxed);
+            if pc != 0 {
+                println!("  ACQUIRED AT {:>016x}", pc);
+                unsafe {
+                    crate::panic::symbol_trace(pc);
+                }
+            }
+        }
+        Some(crate::cpu_set::LogicalCpuId::new(cpu as u32 - 1))
+    }
+}
+
+/// Report that the current CPU is stuck on `lock`, with `owners` printing those of the lock and
+/// returning the CPU to ask for a backtrace.
+#[cfg(debug_assertions)]
+#[cold]
+fn report_timeout<T>(lock: &T, owners: impl FnOnce() -> Option<crate::cpu_set::LogicalCpuId>) {
+    if RECORD_OWNERS.load(Ordering::Relaxed) {
+        println!(
+            "SPINLOCK TIMEOUT: CPU {}, CID {:#x} spinning on {:p}",
+            crate::cpu_id(),
+            crate::percpu::PercpuBlock::current()
+                .switch_internals
+                .context_addr(),
+            lock
+        );
+    } else {
+        println!("SPINLOCK TIMEOUT: spinning on {:p}", lock);
+    }
+    let owner_cpu = owners();
+    unsafe {
+        crate::panic::stack_trace();
+    }
+
+    // Owners are only recorded once the current CPU can be told apart.
+    if let Some(owner_cpu) = owner_cpu
+        && owner_cpu != crate::cpu_id()
+    {
+        crate::percpu::request_backtrace(owner_cpu);
+    }
+}
+
+impl RawSpinMutex {
+    #[cold]
+    fn report_timeout(&self) {
+        #[cfg(debug_assertions)]
+        report_timeout(self, || self.owner.report("HELD BY"));
+    }
+}
+
+impl RawRwSpinlock {
+    #[cold]
+    fn report_timeout(&self) {
+        #[cfg(debug_assertions)]
+        report_timeout(self, || {
+            let state = self.state.load(Ordering::Relaxed);
+            let mut owner_cpu = None;
+            if state & WRITER != 0 {
+                owner_cpu = self.owner.report("HELD BY");
+            } else if state & UPGRADABLE != 0 {
+                owner_cpu = self.owner.report("HELD UPGRADABLE BY");
+            }
+            if state >= READER {
+                println!("HELD BY: {} readers", state / READER);
+                owner_cpu = owner_cpu.or(self.reader.report("LAST READ BY"));
+            }
+            owner_cpu
+        });
+    }
+}
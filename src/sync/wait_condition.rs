@@ -2,10 +2,11 @@ use alloc::{
     sync::{Arc, Weak},
     vec::Vec,
 };
-use spin::Mutex;
-use spinning_top::RwSpinlock;
 
-use crate::context::{self, Context};
+use crate::{
+    context::{self, Context},
+    sync::{Mutex, RwSpinlock},
+};
 
 #[derive(Debug)]
 pub struct WaitCondition {
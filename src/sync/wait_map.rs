@@ -1,8 +1,7 @@
 use alloc::collections::BTreeMap;
 use core::mem;
-use spin::Mutex;
 
-use crate::sync::WaitCondition;
+use crate::sync::{Mutex, WaitCondition};
 
 #[derive(Debug)]
 pub struct WaitMap<K, V> {
@@ -1,17 +1,15 @@
 use alloc::sync::Arc;
 
 use crate::{
-    context::{self, process},
+    context::{
+        self,
+        caps::{self, Capabilities},
+        huge_page,
+    },
+    lockdown,
     paging::VirtualAddress,
-    syscall::error::{Error, Result, EFAULT, EPERM},
+    syscall::error::{Error, Result, EFAULT},
 };
-fn enforce_root() -> Result<()> {
-    if process::current()?.read().euid != 0 {
-        return Err(Error::new(EPERM));
-    }
-    Ok(())
-}
-
 #[cfg(not(any(target_arch = "x86", target_arch = "x86_64")))]
 pub fn iopl(_level: usize) -> Result<usize> {
     Err(Error::new(syscall::error::ENOSYS))
@@ -19,7 +17,10 @@ pub fn iopl(_level: usize) -> Result<usize> {
 
 #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
 pub fn iopl(level: usize) -> Result<usize> {
-    enforce_root()?;
+    caps::check(Capabilities::IO_PORT)?;
+    if level >= 3 {
+        lockdown::check(lockdown::Reason::PortIo)?;
+    }
 
     context::current()
         .write()
@@ -29,17 +30,16 @@ pub fn iopl(level: usize) -> Result<usize> {
 }
 
 pub fn virttophys(virtual_address: usize) -> Result<usize> {
-    enforce_root()?;
+    caps::check(Capabilities::PHYS_MEM)?;
 
     let addr_space = Arc::clone(context::current().read().addr_space()?);
     let addr_space = addr_space.acquire_read();
 
-    match addr_space
-        .table
-        .utable
-        .translate(VirtualAddress::new(virtual_address))
-    {
-        Some((physical_address, _)) => Ok(physical_address.data()),
+    match huge_page::translate(
+        &addr_space.table.utable,
+        VirtualAddress::new(virtual_address),
+    ) {
+        Some(physical_address) => Ok(physical_address.data()),
         None => Err(Error::new(EFAULT)),
     }
 }
@@ -1,17 +1,18 @@
 //! Filesystem syscalls
 use alloc::{sync::Arc, vec::Vec};
 use redox_path::RedoxPath;
-use spin::RwLock;
 
 use crate::{
+    common::try_alloc::{try_arc, try_vec_filled},
     context::{
         self,
         file::{FileDescription, FileDescriptor, InternalFlags},
-        memory::{AddrSpace, PageSpan},
-        process,
+        memory::{check_wx, handle_notify_files, AddrSpace, PageSpan},
+        process, stats,
     },
     paging::{Page, VirtualAddress, PAGE_SIZE},
     scheme::{self, CallerCtx, FileHandle, KernelScheme, OpenResult},
+    sync::RwLock,
     syscall::{data::Stat, error::*, flag::*},
 };
 
@@ -41,10 +42,10 @@ pub fn file_op_generic_ext<T>(
     op(&*scheme, file.description, desc)
 }
 pub fn copy_path_to_buf(raw_path: UserSliceRo, max_len: usize) -> Result<alloc::string::String> {
-    let mut path_buf = vec![0_u8; max_len];
-    if raw_path.len() > path_buf.len() {
+    if raw_path.len() > max_len {
         return Err(Error::new(ENAMETOOLONG));
     }
+    let mut path_buf = try_vec_filled(0_u8, raw_path.len())?;
     let path_len = raw_path.copy_common_bytes_to_slice(&mut path_buf)?;
     path_buf.truncate(path_len);
     alloc::string::String::from_utf8(path_buf).map_err(|_| Error::new(EINVAL))
@@ -98,13 +99,16 @@ pub fn open(raw_path: UserSliceRo, flags: usize) -> Result<FileHandle> {
 
         match scheme.kopen(reference.as_ref(), flags, CallerCtx { uid, gid, pid })? {
             OpenResult::SchemeLocal(number, internal_flags) => {
-                Arc::new(RwLock::new(FileDescription {
+                try_arc(RwLock::new(FileDescription {
                     scheme: scheme_id,
                     number,
                     offset: 0,
                     flags: (flags & !O_CLOEXEC) as u32,
                     internal_flags,
                 }))
+                .inspect_err(|_| {
+                    let _ = scheme.close(number);
+                })?
             }
             OpenResult::External(desc) => desc,
         }
@@ -201,13 +205,16 @@ fn duplicate_file(fd: FileHandle, user_buf: UserSliceRo) -> Result<FileDescripto
 
             match scheme.kdup(description.number, user_buf, caller_ctx)? {
                 OpenResult::SchemeLocal(number, internal_flags) => {
-                    Arc::new(RwLock::new(FileDescription {
+                    try_arc(RwLock::new(FileDescription {
                         offset: 0,
                         internal_flags,
                         scheme: description.scheme,
                         number,
                         flags: description.flags,
                     }))
+                    .inspect_err(|_| {
+                        let _ = scheme.close(number);
+                    })?
                 }
                 OpenResult::External(desc) => desc,
             }
@@ -453,6 +460,10 @@ pub fn funmap(virtual_address: usize, length: usize) -> Result<usize> {
     Ok(0)
 }
 
+/// Resize the mapping in place, failing with `ENOMEM` rather than moving it. This uses a bit of
+/// `MremapFlags` that has no constant in the syscall crate yet.
+pub const MREMAP_NO_MOVE: MremapFlags = MremapFlags::from_bits_retain(1 << 3);
+
 pub fn mremap(
     old_address: usize,
     old_size: usize,
@@ -474,9 +485,10 @@ pub fn mremap(
     let old_base = Page::containing_address(VirtualAddress::new(old_address));
     let new_base = Page::containing_address(VirtualAddress::new(new_address));
 
-    let mremap_flags = MremapFlags::from_bits_truncate(flags);
+    let mremap_flags = MremapFlags::from_bits_retain(flags);
     let prot_flags = MapFlags::from_bits_truncate(flags)
         & (MapFlags::PROT_READ | MapFlags::PROT_WRITE | MapFlags::PROT_EXEC);
+    check_wx("mremap", old_base, prot_flags)?;
 
     let map_flags = if mremap_flags.contains(MremapFlags::FIXED_REPLACE) {
         MapFlags::MAP_FIXED
@@ -491,14 +503,36 @@ pub fn mremap(
     let new_page_count = new_size.div_ceil(PAGE_SIZE);
     let requested_dst_base = Some(new_base).filter(|_| new_address != 0);
 
+    let mut notify_files = Vec::new();
+
+    // Unless a different address is required, try to avoid copying the page table entries.
+    let fixed = mremap_flags.contains(MremapFlags::FIXED);
+    if !fixed || new_base == old_base {
+        let resized =
+            addr_space.resize_in_place(src_span, new_page_count, prot_flags, &mut notify_files);
+        handle_notify_files(notify_files);
+
+        match resized {
+            Ok(true) => return Ok(old_address),
+            // Something else is mapped right after the grants, so they can only be moved.
+            Ok(false) | Err(Error { errno: EEXIST }) => (),
+            Err(err) => return Err(err),
+        }
+        if mremap_flags.contains(MREMAP_NO_MOVE) || fixed {
+            return Err(Error::new(ENOMEM));
+        }
+        notify_files = Vec::new();
+    }
+
     let base = addr_space.r#move(
         None,
         src_span,
         requested_dst_base,
         new_page_count,
         map_flags,
-        &mut Vec::new(),
+        &mut notify_files,
     )?;
+    handle_notify_files(notify_files);
 
     Ok(base.start_address().data())
 }
@@ -553,6 +587,7 @@ pub fn sys_read(fd: FileHandle, buf: UserSliceWo) -> Result<usize> {
             desc,
         ))
     })?;
+    stats::account_io(desc.scheme, bytes_read, 0);
     if desc.internal_flags.contains(InternalFlags::POSITIONED) {
         match desc_arc.write().offset {
             ref mut offset => *offset = offset.saturating_add(bytes_read as u64),
@@ -573,6 +608,7 @@ pub fn sys_write(fd: FileHandle, buf: UserSliceRo) -> Result<usize> {
             desc,
         ))
     })?;
+    stats::account_io(desc.scheme, 0, bytes_written);
     if desc.internal_flags.contains(InternalFlags::POSITIONED) {
         match desc_arc.write().offset {
             ref mut offset => *offset = offset.saturating_add(bytes_written as u64),
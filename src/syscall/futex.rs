@@ -2,58 +2,77 @@
 //! Futex or Fast Userspace Mutex is "a method for waiting until a certain condition becomes true."
 //!
 //! For more information about futexes, please read [this](https://eli.thegreenplace.net/2018/basics-of-futexes/) blog post, and the [futex(2)](http://man7.org/linux/man-pages/man2/futex.2.html) man page
-use alloc::{
-    collections::VecDeque,
-    sync::{Arc, Weak},
-};
+//!
+//! Waiters are kept in a fixed table of buckets, hashed by the key of the futex word they wait
+//! on. Within a bucket, they are ordered by nice level, and then by arrival, so that wakeups go
+//! to the highest priority waiters first. This is also where priority inheritance would hook in,
+//! by boosting the owner of a lock to the nice level of the first waiter of its futex.
+use alloc::{sync::Arc, vec::Vec};
 use core::sync::atomic::{AtomicU32, Ordering};
 use rmm::Arch;
-use spin::RwLock;
-use spinning_top::RwSpinlock;
 use syscall::EINTR;
 
 use crate::{
     context::{
-        self,
-        memory::{AddrSpace, AddrSpaceWrapper},
+        self, huge_page,
+        memory::{AddrSpace, AddrSpaceWrapper, Provider},
+        switch::Nice,
         Context,
     },
     memory::PhysicalAddress,
     paging::{Page, VirtualAddress},
+    sync::{Mutex, RwSpinlock},
     time,
 };
 
 use crate::syscall::{
     data::TimeSpec,
-    error::{Error, Result, EAGAIN, EFAULT, EINVAL, ETIMEDOUT},
-    flag::{FUTEX_WAIT, FUTEX_WAIT64, FUTEX_WAKE},
+    error::{Error, Result, EAGAIN, EFAULT, EINVAL, ENOMEM, ETIMEDOUT},
+    flag::{FUTEX_REQUEUE, FUTEX_WAIT, FUTEX_WAIT64, FUTEX_WAKE},
 };
 
 use super::usercopy::UserSlice;
 
-type FutexList = VecDeque<FutexEntry>;
-
-pub struct FutexEntry {
-    // Physical address, required if synchronizing across address spaces (necessitates MAP_SHARED
-    // since CoW would invalidate this address).
-    target_physaddr: PhysicalAddress,
-    // Virtual address, required if synchronizing across the same address space, if the memory is
-    // CoW.
-    // TODO: FUTEX_REQUEUE
-    target_virtaddr: VirtualAddress,
-    // Context to wake up, and compare address spaces.
+/// Identifies a futex word.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum FutexKey {
+    /// A word in memory shared between address spaces, by physical frame and offset, so that it
+    /// can be waited on from any address space mapping it.
+    Shared(PhysicalAddress),
+    /// A word in private memory, by address space and virtual address, which unlike its physical
+    /// address stays the same when a copy-on-write page is copied.
+    Private {
+        addr_space: usize,
+        addr: VirtualAddress,
+    },
+}
+
+struct Waiter {
     context_lock: Arc<RwSpinlock<Context>>,
-    // address space to check against if virt matches but not phys
-    addr_space: Weak<AddrSpaceWrapper>,
+    nice: Nice,
+    /// Key the waiter is queued under. Only changed by requeueing, with both buckets locked.
+    key: Mutex<FutexKey>,
 }
 
+type Bucket = Vec<Arc<Waiter>>;
+
+const BUCKET_SHIFT: u32 = 8;
+const BUCKET_COUNT: usize = 1 << BUCKET_SHIFT;
+
 // TODO: Process-private futexes? In that case, put the futex table in each AddrSpace, or just
 // implement that fully in userspace. Although futex is probably the best API for process-shared
 // POSIX synchronization primitives, a local hash table and wait-for-thread kernel APIs (e.g.
 // lwp_park/lwp_unpark from NetBSD) could be a simpler replacement.
-//
-// TODO: Use an actual hash table.
-static FUTEXES: RwLock<FutexList> = RwLock::new(FutexList::new());
+static FUTEXES: [Mutex<Bucket>; BUCKET_COUNT] = [const { Mutex::new(Vec::new()) }; BUCKET_COUNT];
+
+fn bucket_index(key: FutexKey) -> usize {
+    let (a, b) = match key {
+        FutexKey::Shared(phys) => (0, phys.data()),
+        FutexKey::Private { addr_space, addr } => (addr_space, addr.data()),
+    };
+    let hash = (a as u64 ^ (b as u64).rotate_left(17)).wrapping_mul(0x9E37_79B9_7F4A_7C15);
+    (hash >> (64 - BUCKET_SHIFT)) as usize
+}
 
 fn validate_and_translate_virt(space: &AddrSpace, addr: VirtualAddress) -> Option<PhysicalAddress> {
     // TODO: Move this elsewhere!
@@ -64,21 +83,85 @@ fn validate_and_translate_virt(space: &AddrSpace, addr: VirtualAddress) -> Optio
     let page = Page::containing_address(addr);
     let off = addr.data() - page.start_address().data();
 
-    let (frame, _) = space.table.utable.translate(page.start_address())?;
+    let frame = huge_page::translate(&space.table.utable, page.start_address())?;
 
     Some(frame.add(off))
 }
 
-pub fn futex(addr: usize, op: usize, val: usize, val2: usize, _addr2: usize) -> Result<usize> {
+/// Translate `addr`, returning the key of the futex word there, and its physical address.
+fn key_of(
+    addr_space: &Arc<AddrSpaceWrapper>,
+    space: &AddrSpace,
+    addr: VirtualAddress,
+) -> Result<(FutexKey, PhysicalAddress)> {
+    let phys = validate_and_translate_virt(space, addr).ok_or(Error::new(EFAULT))?;
+    let (_, info) = space
+        .grants
+        .contains(Page::containing_address(addr))
+        .ok_or(Error::new(EFAULT))?;
+
+    let key = match info.provider {
+        Provider::Allocated { .. } => FutexKey::Private {
+            addr_space: Arc::as_ptr(addr_space) as usize,
+            addr,
+        },
+        _ => FutexKey::Shared(phys),
+    };
+    Ok((key, phys))
+}
+
+/// Remove up to `count` waiters on `key` from `bucket`, in order, passing each to `f`.
+fn take_waiters(bucket: &mut Bucket, key: FutexKey, count: usize, mut f: impl FnMut(Arc<Waiter>)) {
+    let mut taken = 0;
+    let mut i = 0;
+    while i < bucket.len() && taken < count {
+        if *bucket[i].key.lock() != key {
+            i += 1;
+            continue;
+        }
+        f(bucket.remove(i));
+        taken += 1;
+    }
+}
+
+fn insert_waiter(bucket: &mut Bucket, waiter: Arc<Waiter>) -> Result<()> {
+    let index = bucket.partition_point(|other| other.nice <= waiter.nice);
+    bucket.try_reserve(1).map_err(|_| Error::new(ENOMEM))?;
+    bucket.insert(index, waiter);
+    Ok(())
+}
+
+/// Remove `waiter` from the bucket it is queued in, returning whether it was still queued.
+fn dequeue(waiter: &Arc<Waiter>) -> bool {
+    loop {
+        let key = *waiter.key.lock();
+        let mut bucket = FUTEXES[bucket_index(key)].lock();
+        // Requeued in the meantime.
+        if *waiter.key.lock() != key {
+            continue;
+        }
+        return match bucket.iter().position(|other| Arc::ptr_eq(other, waiter)) {
+            Some(i) => {
+                bucket.remove(i);
+                true
+            }
+            None => false,
+        };
+    }
+}
+
+pub fn futex(addr: usize, op: usize, val: usize, val2: usize, addr2: usize) -> Result<usize> {
     let current_addrsp = AddrSpace::current()?;
 
     // Keep the address space locked so we can safely read from the physical address. Unlock it
     // before context switching.
     let addr_space_guard = current_addrsp.acquire_read();
 
-    let target_virtaddr = VirtualAddress::new(addr);
-    let target_physaddr = validate_and_translate_virt(&*addr_space_guard, target_virtaddr)
-        .ok_or(Error::new(EFAULT))?;
+    let (key, target_physaddr) = key_of(
+        &current_addrsp,
+        &addr_space_guard,
+        VirtualAddress::new(addr),
+    )?;
 
     match op {
         // TODO: FUTEX_WAIT_MULTIPLE?
@@ -86,12 +169,26 @@ pub fn futex(addr: usize, op: usize, val: usize, val2: usize, _addr2: usize) ->
             let timeout_opt = UserSlice::ro(val2, core::mem::size_of::<TimeSpec>())?
                 .none_if_null()
                 .map(|buf| unsafe { buf.read_exact::<TimeSpec>() })
-                .transpose()?;
+                .transpose()?
+                .map(|TimeSpec { tv_sec, tv_nsec }| {
+                    tv_sec as u128 * time::NANOS_PER_SEC + tv_nsec as u128
+                });
+
+            let context_lock = context::current();
+            let waiter = Arc::try_new(Waiter {
+                nice: context_lock.read().nice,
+                context_lock: Arc::clone(&context_lock),
+                key: Mutex::new(key),
+            })
+            .map_err(|_| Error::new(ENOMEM))?;
 
             {
-                let mut futexes = FUTEXES.write();
+                let mut bucket = FUTEXES[bucket_index(key)].lock();
 
-                let context_lock = context::current();
+                // On systems where virtual memory is not abundant, we might instead add an
+                // atomic usercopy function.
+                let accessible_addr =
+                    unsafe { crate::paging::RmmA::phys_to_virt(target_physaddr) }.data();
 
                 let (fetched, expected) = if op == FUTEX_WAIT {
                     // Must be aligned, otherwise it could cross a page boundary and mess up the
@@ -100,11 +197,6 @@ pub fn futex(addr: usize, op: usize, val: usize, val2: usize, _addr2: usize) ->
                         return Err(Error::new(EINVAL));
                     }
 
-                    // On systems where virtual memory is not abundant, we might instead add an
-                    // atomic usercopy function.
-                    let accessible_addr =
-                        unsafe { crate::paging::RmmA::phys_to_virt(target_physaddr) }.data();
-
                     (
                         u64::from(unsafe {
                             (*(accessible_addr as *const AtomicU32)).load(Ordering::SeqCst)
@@ -121,9 +213,9 @@ pub fn futex(addr: usize, op: usize, val: usize, val2: usize, _addr2: usize) ->
                             return Err(Error::new(EINVAL));
                         }
                         (
-                            u64::from(unsafe {
-                                (*(addr as *const AtomicU64)).load(Ordering::SeqCst)
-                            }),
+                            unsafe {
+                                (*(accessible_addr as *const AtomicU64)).load(Ordering::SeqCst)
+                            },
                             val as u64,
                         )
                     }
@@ -136,60 +228,84 @@ pub fn futex(addr: usize, op: usize, val: usize, val2: usize, _addr2: usize) ->
                     return Err(Error::new(EAGAIN));
                 }
 
-                {
-                    let mut context = context_lock.write();
+                insert_waiter(&mut bucket, Arc::clone(&waiter))?;
 
-                    context.wake = timeout_opt.map(|TimeSpec { tv_sec, tv_nsec }| {
-                        tv_sec as u128 * time::NANOS_PER_SEC + tv_nsec as u128
-                    });
-                    if let Some((tctl, pctl, _)) = context.sigcontrol() {
-                        if tctl.currently_pending_unblocked(pctl) != 0 {
-                            return Err(Error::new(EINTR));
-                        }
+                let mut context = context_lock.write();
+                if let Some((tctl, pctl, _)) = context.sigcontrol() {
+                    if tctl.currently_pending_unblocked(pctl) != 0 {
+                        drop(context);
+                        bucket.retain(|other| !Arc::ptr_eq(other, &waiter));
+                        return Err(Error::new(EINTR));
                     }
-
-                    context.block("futex");
                 }
-
-                futexes.push_back(FutexEntry {
-                    target_physaddr,
-                    target_virtaddr,
-                    context_lock,
-                    addr_space: Arc::downgrade(&current_addrsp),
-                });
+                context.wake = timeout_opt;
+                context.block("futex");
             }
 
             drop(addr_space_guard);
 
             context::switch();
 
-            if timeout_opt.is_some() {
-                context::current().write().wake = None;
+            context_lock.write().wake = None;
+
+            // Still queued if woken up by the timeout or a signal, rather than by a wakeup.
+            if !dequeue(&waiter) {
+                Ok(0)
+            } else if timeout_opt.is_some_and(|deadline| time::monotonic() >= deadline) {
                 Err(Error::new(ETIMEDOUT))
             } else {
-                Ok(0)
+                Err(Error::new(EINTR))
             }
         }
         FUTEX_WAKE => {
             let mut woken = 0;
+            take_waiters(&mut FUTEXES[bucket_index(key)].lock(), key, val, |waiter| {
+                waiter.context_lock.write().unblock();
+                woken += 1;
+            });
 
-            {
-                let mut futexes = FUTEXES.write();
+            Ok(woken)
+        }
+        // Wake up to `val` waiters, and move up to `val2` of the remaining ones to `addr2`.
+        FUTEX_REQUEUE => {
+            let (key2, _) = key_of(
+                &current_addrsp,
+                &addr_space_guard,
+                VirtualAddress::new(addr2),
+            )?;
+            drop(addr_space_guard);
 
-                let mut i = 0;
+            let (from, to) = (bucket_index(key), bucket_index(key2));
+            // Lock both buckets in a consistent order.
+            let (mut first, mut second) = if from <= to {
+                let first = FUTEXES[from].lock();
+                let second = (from != to).then(|| FUTEXES[to].lock());
+                (first, second)
+            } else {
+                let second = FUTEXES[to].lock();
+                (FUTEXES[from].lock(), Some(second))
+            };
 
-                // TODO: Use something like retain, once it is possible to tell it when to stop iterating...
-                while i < futexes.len() && woken < val {
-                    if futexes[i].target_physaddr != target_physaddr
-                        && (futexes[i].target_virtaddr != target_virtaddr
-                            || !Arc::downgrade(&current_addrsp).ptr_eq(&futexes[i].addr_space))
-                    {
-                        i += 1;
-                        continue;
-                    }
-                    futexes[i].context_lock.write().unblock();
-                    futexes.swap_remove_back(i);
-                    woken += 1;
+            let mut woken = 0;
+            take_waiters(&mut first, key, val, |waiter| {
+                waiter.context_lock.write().unblock();
+                woken += 1;
+            });
+
+            for _ in 0..val2 {
+                let Some(i) = first.iter().position(|waiter| *waiter.key.lock() == key) else {
+                    break;
+                };
+                let waiter = first.remove(i);
+                *waiter.key.lock() = key2;
+
+                let target = match second {
+                    Some(ref mut second) => &mut **second,
+                    None => &mut *first,
+                };
+                if insert_waiter(target, Arc::clone(&waiter)).is_err() {
+                    // Woken up spuriously instead.
+                    waiter.context_lock.write().unblock();
                 }
             }
 
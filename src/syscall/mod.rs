@@ -24,10 +24,19 @@ use self::{
     usercopy::UserSlice,
 };
 
-use crate::percpu::PercpuBlock;
+use crate::{
+    audit,
+    percpu::PercpuBlock,
+    trace::{self, Event},
+};
 
 use crate::{
-    context::{memory::AddrSpace, process::ProcessId},
+    context::{
+        memory::{check_wx, AddrSpace},
+        process::ProcessId,
+        stats,
+    },
+    paging::{Page, VirtualAddress},
     scheme::{memory::MemoryScheme, FileHandle, SchemeNamespace},
 };
 
@@ -78,20 +87,24 @@ pub fn syscall(a: usize, b: usize, c: usize, d: usize, e: usize, f: usize) -> us
                             .ok_or(Error::new(EINVAL))?,
                     )
                 };
-                scheme.kwriteoff(
+                let bytes_written = scheme.kwriteoff(
                     desc.number,
                     UserSlice::ro(c, d)?,
                     e as u64,
                     flags.map_or(desc.flags, |f| desc.rw_flags(f)),
                     desc.flags,
-                )
+                )?;
+                stats::account_io(desc.scheme, 0, bytes_written);
+                Ok(bytes_written)
             }),
             SYS_WRITE => sys_write(fd, UserSlice::ro(c, d)?),
             SYS_FMAP => {
                 let addrspace = AddrSpace::current()?;
                 let map = unsafe { UserSlice::ro(c, d)?.read_exact::<Map>()? };
+                let page = Page::containing_address(VirtualAddress::new(map.address));
+                check_wx("mmap", page, map.flags)?;
                 if b == !0 {
-                    MemoryScheme::fmap_anonymous(&addrspace, &map, false)
+                    MemoryScheme::fmap_anonymous(&addrspace, &map, false, false, false)
                 } else {
                     file_op_generic(fd, |scheme, number| {
                         scheme.kfmap(number, &addrspace, &map, false)
@@ -125,13 +138,15 @@ pub fn syscall(a: usize, b: usize, c: usize, d: usize, e: usize, f: usize) -> us
                             .ok_or(Error::new(EINVAL))?,
                     )
                 };
-                scheme.kreadoff(
+                let bytes_read = scheme.kreadoff(
                     desc.number,
                     UserSlice::wo(c, d)?,
                     e as u64,
                     flags.map_or(desc.flags, |f| desc.rw_flags(f)),
                     desc.flags,
-                )
+                )?;
+                stats::account_io(desc.scheme, bytes_read, 0);
+                Ok(bytes_read)
             }),
             SYS_READ => sys_read(fd, UserSlice::wo(c, d)?),
             SYS_FPATH => file_op_generic(fd, |scheme, number| {
@@ -221,7 +236,10 @@ pub fn syscall(a: usize, b: usize, c: usize, d: usize, e: usize, f: usize) -> us
             SYS_GETGID => getgid(),
             SYS_GETNS => getns(),
             SYS_GETUID => getuid(),
-            SYS_MPROTECT => mprotect(b, c, MapFlags::from_bits_truncate(d)).map(|()| 0),
+            SYS_MPROTECT => MapFlags::from_bits(d)
+                .ok_or(Error::new(EINVAL))
+                .and_then(|flags| mprotect(b, c, flags))
+                .map(|()| 0),
             SYS_MKNS => mkns(UserSlice::ro(
                 b,
                 c.checked_mul(core::mem::size_of::<[usize; 2]>())
@@ -240,15 +258,21 @@ pub fn syscall(a: usize, b: usize, c: usize, d: usize, e: usize, f: usize) -> us
     }
 
     PercpuBlock::current().inside_syscall.set(true);
+    stats::count_syscall();
 
     #[cfg(feature = "syscall_debug")]
     debug_start([a, b, c, d, e, f]);
 
+    trace::record(Event::SyscallEnter, [a as u64, b as u64, c as u64]);
+
     let result = inner(a, b, c, d, e, f);
 
     #[cfg(feature = "syscall_debug")]
     debug_end([a, b, c, d, e, f], result);
 
+    trace::record(Event::SyscallExit, [a as u64, Error::mux(result) as u64, 0]);
+    audit::syscall([a, b, c, d, e, f], result);
+
     let percpu = PercpuBlock::current();
     percpu.inside_syscall.set(false);
 
@@ -1,7 +1,7 @@
 use alloc::vec::Vec;
 
 use crate::{
-    context::process,
+    context::{caps, process},
     scheme::{self, SchemeNamespace},
     syscall::error::*,
 };
@@ -223,7 +223,7 @@ pub fn setreuid(ruid: u32, euid: u32) -> Result<()> {
     }
 
     if seteuid {
-        process.euid = euid;
+        caps::set_euid(&mut process, euid);
     }
 
     Ok(())
@@ -1,14 +1,13 @@
 use alloc::{collections::VecDeque, sync::Arc, vec::Vec};
 use core::{mem, num::NonZeroUsize, sync::atomic::Ordering};
-use spinning_top::RwSpinlock;
 use syscall::{
     sig_bit, RtSigInfo, SenderInfo, SIGCHLD, SIGKILL, SIGSTOP, SIGTERM, SIGTSTP, SIGTTIN, SIGTTOU,
 };
 
 use rmm::Arch;
-use spin::RwLock;
 
 use crate::context::{
+    caps::{self, Capabilities},
     memory::{AddrSpace, Grant, PageSpan},
     process::{self, Process, ProcessId, ProcessInfo, ProcessStatus},
     Context, ContextRef, WaitpidKey,
@@ -18,6 +17,7 @@ use crate::{
     context, interrupt,
     paging::{Page, VirtualAddress, PAGE_SIZE},
     ptrace,
+    sync::{RwLock, RwSpinlock},
     syscall::{
         error::*,
         flag::{
@@ -34,15 +34,19 @@ use super::usercopy::UserSliceWo;
 pub fn exit_this_context() -> ! {
     let close_files;
     let addrspace_opt;
+    #[cfg(target_arch = "x86_64")]
+    let shadow_stack;
 
     let context_lock = context::current();
     {
         let mut context = context_lock.write();
         close_files = Arc::try_unwrap(mem::take(&mut context.files))
             .map_or_else(|_| Vec::new(), RwLock::into_inner);
-        addrspace_opt = context
-            .set_addr_space(None)
-            .and_then(|a| Arc::try_unwrap(a).ok());
+        addrspace_opt = context.set_addr_space(None).map(Arc::try_unwrap);
+        #[cfg(target_arch = "x86_64")]
+        {
+            shadow_stack = context.arch.cet.take_shadow_stack();
+        }
         drop(context.syscall_head.take());
         drop(context.syscall_tail.take());
     }
@@ -53,10 +57,17 @@ pub fn exit_this_context() -> ! {
             let _ = file.close();
         }
     }
+    // The other threads keep the address space, but not the shadow stack of this one.
+    #[cfg(target_arch = "x86_64")]
+    if let (Some(Err(addrspace)), Some(span)) = (&addrspace_opt, shadow_stack) {
+        addrspace.munmap_shadow_stack(span);
+    }
     drop(addrspace_opt);
     // TODO: Should status == Status::HardBlocked be handled differently?
     context_lock.write().status = context::Status::Dead;
-    let _ = context::contexts_mut().remove(&ContextRef(context_lock));
+    // Dead contexts are never picked, so if the list cannot be copied for lack of memory, this one
+    // is only leaked.
+    let _ = context::update_contexts(|contexts| Ok(contexts.remove(&ContextRef(context_lock))));
     context::switch();
     unreachable!();
 }
@@ -209,8 +220,9 @@ pub fn send_signal(
         return Err(Error::new(EINVAL));
     }
 
-    let sig_group = (sig - 1) / 32;
-    let sig_idx = sig - 1;
+    // Only used when sig != 0.
+    let sig_group = sig.saturating_sub(1) / 32;
+    let sig_idx = sig.saturating_sub(1);
 
     let (context_lock, process_lock) = match target {
         KillTarget::Thread(ref c) => (Arc::clone(&c), Arc::clone(&c.read().process)),
@@ -298,9 +310,13 @@ pub fn send_signal(
         let mut context_guard = context_lock.write();
         if sig == SIGSTOP
             || (matches!(sig, SIGTTIN | SIGTTOU | SIGTSTP)
-                && context_guard
+                && (context_guard
                     .sigcontrol()
-                    .map_or(false, |(_, proc, _)| proc.signal_will_stop(sig)))
+                    .map_or(false, |(_, proc, _)| proc.signal_will_stop(sig))
+                    || context_guard
+                        .ksig
+                        .as_ref()
+                        .is_some_and(|ksig| ksig.will_stop(sig))))
         {
             context_guard.status = context::Status::Blocked;
             drop(context_guard);
@@ -342,8 +358,28 @@ pub fn send_signal(
         if let Some((tctl, pctl, sigst)) = context_guard.sigcontrol()
             && !pctl.signal_will_ign(sig, is_sigchld_to_parent)
         {
+            if let KillMode::Queued(arg) = mode {
+                if sig_group != 1 || sig_idx < 32 || sig_idx >= 64 {
+                    return SendResult::Invalid;
+                }
+                let rtidx = sig_idx - 32;
+                //log::info!("QUEUEING {arg:?} RTIDX {rtidx}");
+                if rtidx >= sigst.rtqs.len() {
+                    sigst.rtqs.resize_with(rtidx + 1, VecDeque::new);
+                }
+                let rtq = sigst.rtqs.get_mut(rtidx).unwrap();
+
+                // TODO: configurable limit?
+                if rtq.len() > 32 {
+                    return SendResult::FullQ;
+                }
+
+                rtq.push_back(arg);
+            }
             match target {
                 KillTarget::Thread(_) => {
+                    // The signal is only pending for this thread, so it will only be delivered
+                    // once this thread unmasks it, even if other threads have it unmasked.
                     tctl.sender_infos[sig_idx].store(sender.raw(), Ordering::Relaxed);
 
                     let _was_new = tctl.word[sig_group].fetch_or(sig_bit(sig), Ordering::Release);
@@ -354,24 +390,7 @@ pub fn send_signal(
                 }
                 KillTarget::Process(proc) => {
                     match mode {
-                        KillMode::Queued(arg) => {
-                            if sig_group != 1 || sig_idx < 32 || sig_idx >= 64 {
-                                return SendResult::Invalid;
-                            }
-                            let rtidx = sig_idx - 32;
-                            //log::info!("QUEUEING {arg:?} RTIDX {rtidx}");
-                            if rtidx >= sigst.rtqs.len() {
-                                sigst.rtqs.resize_with(rtidx + 1, VecDeque::new);
-                            }
-                            let rtq = sigst.rtqs.get_mut(rtidx).unwrap();
-
-                            // TODO: configurable limit?
-                            if rtq.len() > 32 {
-                                return SendResult::FullQ;
-                            }
-
-                            rtq.push_back(arg);
-                        }
+                        KillMode::Queued(_) => (),
                         KillMode::Idempotent => {
                             if pctl.pending.load(Ordering::Acquire) & sig_bit(sig) != 0 {
                                 // If already pending, do not send this signal. While possible that
@@ -410,6 +429,16 @@ pub fn send_signal(
                 }
             }
             SendResult::Succeeded
+        } else if context_guard.sig.is_none()
+            && let Some(ksig) = context_guard.ksig.as_mut()
+        {
+            // Signals with handlers registered with the kernel are not queued, and are sent to
+            // the first thread for the whole process. Those sent to the current context are
+            // delivered when the syscall returns, so it need not be told to check.
+            if ksig.queue(sig) {
+                context_guard.unblock();
+            }
+            SendResult::Succeeded
         } else {
             // Discard signals if sighandler is unset. This includes both special contexts such
             // as bootstrap, and child processes or threads that have not yet been started.
@@ -490,10 +519,10 @@ pub fn kill(pid: ProcessId, sig: usize, mode: KillMode) -> Result<usize> {
         ruid: current_ruid,
     };
 
-    if current_euid == 0 && pid.get() == 1 {
+    if pid.get() == 1 && caps::has(Capabilities::REBOOT) {
         match sig {
-            SIGTERM => unsafe { crate::stop::kreset() },
-            SIGKILL => unsafe { crate::stop::kstop() },
+            SIGTERM => crate::shutdown::reboot(),
+            SIGKILL => crate::shutdown::poweroff(),
             _ => return Ok(0), // error?
         }
     }
@@ -587,6 +616,48 @@ pub fn kill(pid: ProcessId, sig: usize, mode: KillMode) -> Result<usize> {
     }
 }
 
+/// Send a signal to one thread of the process `tgid`. Unlike signals sent to a process, which
+/// can be handled by any thread not masking them, the signal stays pending for that thread until
+/// it unmasks it.
+pub fn tgkill(
+    tgid: ProcessId,
+    thread: Arc<RwSpinlock<Context>>,
+    sig: usize,
+    mode: KillMode,
+) -> Result<usize> {
+    let sender = {
+        let process_lock = process::current()?;
+        let process = process_lock.read();
+        SenderInfo {
+            pid: process.pid.get().try_into().unwrap_or(0),
+            ruid: process.ruid,
+        }
+    };
+
+    // Catch threads that have exited, or threads of another process that were passed by mistake.
+    let process_lock = Arc::clone(&thread.read().process);
+    if process_lock.read().pid != tgid || matches!(thread.read().status, context::Status::Dead) {
+        return Err(Error::new(ESRCH));
+    }
+
+    let mut killed_self = false;
+    send_signal(
+        KillTarget::Thread(thread),
+        sig,
+        mode,
+        false,
+        &mut killed_self,
+        sender,
+    )?;
+
+    if killed_self {
+        // Inform userspace it should check its own mask
+        Err(Error::new(EINTR))
+    } else {
+        Ok(0)
+    }
+}
+
 pub fn mprotect(address: usize, size: usize, flags: MapFlags) -> Result<()> {
     // println!("mprotect {:#X}, {}, {:#X}", address, size, flags);
 
@@ -887,7 +958,7 @@ pub unsafe fn bootstrap_mem(bootstrap: &crate::Bootstrap) -> &'static [u8] {
 pub fn sigdequeue(out: UserSliceWo, sig_idx: u32) -> Result<()> {
     let current = context::current();
     let mut current = current.write();
-    let Some((_tctl, pctl, st)) = current.sigcontrol() else {
+    let Some((tctl, pctl, st)) = current.sigcontrol() else {
         return Err(Error::new(ESRCH));
     };
     if sig_idx >= 32 {
@@ -903,6 +974,8 @@ pub fn sigdequeue(out: UserSliceWo, sig_idx: u32) -> Result<()> {
     if q.is_empty() {
         pctl.pending
             .fetch_and(!(1 << (32 + sig_idx as usize)), Ordering::Relaxed);
+        // The signal may also have been queued for this thread only.
+        tctl.word[1].fetch_and(!sig_bit(33 + sig_idx as usize), Ordering::Relaxed);
     }
     out.copy_exactly(&front)?;
     Ok(())
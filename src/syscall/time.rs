@@ -14,6 +14,7 @@ pub fn clock_gettime(clock: usize, buf: UserSliceWo) -> Result<()> {
     let arch_time = match clock {
         CLOCK_REALTIME => time::realtime(),
         CLOCK_MONOTONIC => time::monotonic(),
+        time::CLOCK_BOOTTIME => time::boottime(),
         _ => return Err(Error::new(EINVAL)),
     };
 
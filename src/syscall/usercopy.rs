@@ -1,3 +1,16 @@
+//! Access to user memory.
+//!
+//! The kernel only reads and writes user memory through [`UserSlice`], whose copies are done by
+//! the `arch_copy_from_user` and `arch_copy_to_user` functions of each architecture. Outside of
+//! them, user pages are inaccessible to the kernel where the CPU supports it: SMAP on x86_64, PAN
+//! on aarch64, and SUM being clear on riscv64. The copy functions open an access window for the
+//! duration of the copy, with STAC/CLAC, unprivileged loads and stores, or by setting SUM.
+//!
+//! They are placed in the `.usercopy-fns` section, and a page fault there that cannot be resolved
+//! is recovered by the exception handler, which makes the function return nonzero, turned into
+//! EFAULT here. Any other kernel fault on a user address is a bug. The rest of the kernel accesses
+//! user memory through the physmap, after looking up the frame in the address space, like futexes.
+
 use syscall::dirent::Buffer;
 
 use crate::{
@@ -99,6 +112,11 @@ impl<const WRITE: bool> UserSlice<true, WRITE> {
             return Err(Error::new(EINVAL));
         }
 
+        #[cfg(feature = "ktest")]
+        if crate::ktest::fault::should_fail(crate::ktest::fault::Site::UserCopy) {
+            return Err(Error::new(EFAULT));
+        }
+
         if unsafe { arch_copy_from_user(slice.as_mut_ptr() as usize, self.base, self.len) } == 0 {
             Ok(())
         } else {
@@ -163,6 +181,11 @@ impl<const READ: bool> UserSlice<READ, true> {
             return Err(Error::new(EINVAL));
         }
 
+        #[cfg(feature = "ktest")]
+        if crate::ktest::fault::should_fail(crate::ktest::fault::Site::UserCopy) {
+            return Err(Error::new(EFAULT));
+        }
+
         if unsafe { arch_copy_to_user(self.base, slice.as_ptr() as usize, self.len) } == 0 {
             Ok(())
         } else {
@@ -1,17 +1,86 @@
-use spin::Mutex;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::sync::Mutex;
 
 pub const NANOS_PER_SEC: u128 = 1_000_000_000;
 
+/// Monotonic clock that also counts time spent suspended, not yet defined by the syscall crate.
+pub const CLOCK_BOOTTIME: usize = 7;
+
 // TODO: seqlock?
 /// Kernel start time, measured in nanoseconds since Unix epoch
 pub static START: Mutex<u128> = Mutex::new(0);
 /// Kernel up time, measured in nanoseconds since `START_TIME`
 pub static OFFSET: Mutex<u128> = Mutex::new(0);
 
+/// Added to the architectural monotonic clock, so that it continues from where it stopped when a
+/// suspend resets the hardware counters.
+static RESUME_OFFSET: AtomicU64 = AtomicU64::new(0);
+/// Total time spent suspended, in nanoseconds, which is counted by `CLOCK_BOOTTIME` but not by
+/// `CLOCK_MONOTONIC`.
+static SUSPENDED: AtomicU64 = AtomicU64::new(0);
+/// Monotonic and persistent clock readings taken right before suspending.
+static SUSPEND_POINT: Mutex<Option<(u128, Option<u128>)>> = Mutex::new(None);
+
 pub fn monotonic() -> u128 {
-    crate::arch::time::monotonic_absolute()
+    crate::arch::time::monotonic_absolute() + u128::from(RESUME_OFFSET.load(Ordering::Relaxed))
+}
+
+/// The time added to the architectural monotonic clock after resuming, and the total time spent
+/// suspended, in nanoseconds.
+pub fn offsets() -> (u64, u64) {
+    (
+        RESUME_OFFSET.load(Ordering::Relaxed),
+        SUSPENDED.load(Ordering::Relaxed),
+    )
+}
+
+pub fn boottime() -> u128 {
+    monotonic() + u128::from(SUSPENDED.load(Ordering::Relaxed))
 }
 
 pub fn realtime() -> u128 {
     *START.lock() + monotonic()
 }
+
+/// Record the state of the clocks, right before the system is suspended to RAM or disk.
+pub fn suspend() {
+    *SUSPEND_POINT.lock() = Some((monotonic(), crate::arch::time::persistent_clock()));
+}
+
+/// Restore the clocks after resuming from suspend, on the BSP before the other CPUs are brought
+/// back up, which call [`resume_ap`] instead.
+///
+/// The monotonic clock continues from its value at suspend, while the time slept, as measured by
+/// the persistent clock, is added to `CLOCK_BOOTTIME` and the realtime clock. Timers on those
+/// clocks that expired while suspended are fired.
+pub unsafe fn resume() {
+    let Some((mono_before, persistent_before)) = SUSPEND_POINT.lock().take() else {
+        return;
+    };
+
+    crate::arch::time::resume();
+
+    let mono_now = monotonic();
+    if mono_now < mono_before {
+        RESUME_OFFSET.fetch_add((mono_before - mono_now) as u64, Ordering::Relaxed);
+    }
+
+    match (persistent_before, crate::arch::time::persistent_clock()) {
+        (Some(before), Some(now)) => {
+            let slept = now.saturating_sub(before);
+            SUSPENDED.fetch_add(slept as u64, Ordering::Relaxed);
+            *START.lock() = now.saturating_sub(monotonic());
+            log::info!("Resumed after {} ms suspended", slept / 1_000_000);
+        }
+        _ => log::warn!("No persistent clock, time spent suspended is not accounted for"),
+    }
+    crate::vdso::update();
+
+    crate::context::timeout::trigger();
+}
+
+/// Restore the clocks of an AP brought back up after resuming from suspend.
+pub unsafe fn resume_ap() {
+    crate::arch::time::resume_ap();
+}
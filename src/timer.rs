@@ -0,0 +1,273 @@
+//! Per-CPU timers, and the tick-less one-shot timer mode.
+//!
+//! Each CPU keeps a timer wheel of the deadlines it must act on: contexts sleeping on it,
+//! registered timeouts, interval timers, and delayed work. Until the architecture switches to one-shot mode, the
+//! periodic timer interrupt fires them on every tick. In one-shot mode, the timer of each CPU is
+//! instead armed for the earliest of the next wheel deadline, the end of the current slice, and
+//! the end of the period of a throttled group, so that an idle CPU sleeps until there is something
+//! to do.
+//!
+//! Deadlines are in nanoseconds of the monotonic clock.
+
+use alloc::{sync::Weak, vec::Vec};
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::{
+    common::try_alloc::try_push,
+    context::{self, Context},
+    cpu_set::LogicalCpuId,
+    hotplug,
+    percpu::PercpuBlock,
+    sync::RwSpinlock,
+    syscall::error::Result,
+    time,
+    workqueue::{self, Work, WorkQueue},
+};
+
+/// Each slot of the wheel covers 2^20 ns, about a millisecond.
+const SLOT_SHIFT: u32 = 20;
+/// Number of slots, covering a little over a tenth of a second.
+const SLOT_COUNT: usize = 128;
+/// Longest time a CPU sleeps without a timer interrupt, in case a deadline was missed.
+const MAX_IDLE: u128 = time::NANOS_PER_SEC;
+
+static ONESHOT: AtomicBool = AtomicBool::new(false);
+
+/// Whether the timer interrupt is only taken when a deadline is due.
+pub fn oneshot() -> bool {
+    ONESHOT.load(Ordering::Relaxed)
+}
+
+/// Called by the architecture on the BSP, before the other CPUs start scheduling, once it stopped
+/// the periodic timer, and can arm the timer of each CPU through `arch::time::arm_oneshot`.
+pub fn enable_oneshot() {
+    ONESHOT.store(true, Ordering::Relaxed);
+}
+
+pub enum Timer {
+    /// Wake up a context sleeping until its `wake` time.
+    Wake(Weak<RwSpinlock<Context>>),
+    /// Trigger the registered timeouts that expired.
+    Timeouts,
+    /// Expire an interval timer of the `itimer:` scheme, if it was not set again since.
+    ITimer { id: usize, generation: u64 },
+    /// Queue delayed work, see [`crate::workqueue`].
+    Work {
+        queue: &'static WorkQueue,
+        work: &'static Work,
+    },
+}
+
+impl Timer {
+    fn fire(self, now: u128) {
+        match self {
+            Timer::Wake(context) => {
+                let Some(context) = context.upgrade() else {
+                    return;
+                };
+                let mut context = context.write();
+                // The context may have been woken up early, and be sleeping again since.
+                if context.status.is_soft_blocked() && context.wake.is_some_and(|wake| now >= wake)
+                {
+                    context.wake = None;
+                    context.unblock();
+                }
+            }
+            Timer::Timeouts => context::timeout::trigger(),
+            Timer::ITimer { id, generation } => crate::scheme::itimer::fire(id, generation, now),
+            Timer::Work { queue, work } => workqueue::fire(queue, work),
+        }
+    }
+}
+
+struct Entry {
+    deadline: u128,
+    timer: Timer,
+}
+
+fn slot_of(deadline: u128) -> u64 {
+    (deadline >> SLOT_SHIFT) as u64
+}
+
+pub struct TimerWheel {
+    /// Absolute slot the wheel is at. The slots hold the entries of the next `SLOT_COUNT`
+    /// absolute slots from it, while those due later are kept in `overflow`.
+    current: u64,
+    slots: [Vec<Entry>; SLOT_COUNT],
+    overflow: Vec<Entry>,
+    /// Number of entries in `slots`.
+    queued: usize,
+}
+
+impl TimerWheel {
+    pub const fn new() -> Self {
+        Self {
+            current: 0,
+            slots: [const { Vec::new() }; SLOT_COUNT],
+            overflow: Vec::new(),
+            queued: 0,
+        }
+    }
+
+    fn insert(&mut self, entry: Entry) -> Result<()> {
+        let slot = slot_of(entry.deadline).max(self.current);
+        if slot - self.current < SLOT_COUNT as u64 {
+            try_push(&mut self.slots[slot as usize % SLOT_COUNT], entry)?;
+            self.queued += 1;
+        } else {
+            try_push(&mut self.overflow, entry)?;
+        }
+        Ok(())
+    }
+
+    /// Move the overflowing entries that now fit in the wheel.
+    fn cascade(&mut self) {
+        let mut i = 0;
+        while i < self.overflow.len() {
+            let slot = slot_of(self.overflow[i].deadline).max(self.current);
+            if slot - self.current < SLOT_COUNT as u64 {
+                let target = &mut self.slots[slot as usize % SLOT_COUNT];
+                // Otherwise left in the overflow, which is checked as well.
+                if target.try_reserve(1).is_err() {
+                    i += 1;
+                    continue;
+                }
+                target.push(self.overflow.swap_remove(i));
+                self.queued += 1;
+            } else {
+                i += 1;
+            }
+        }
+    }
+
+    /// Remove an entry due at `now`, advancing the wheel up to it.
+    fn pop_expired(&mut self, now: u128) -> Option<Timer> {
+        let now_slot = slot_of(now);
+        loop {
+            let slot = &mut self.slots[self.current as usize % SLOT_COUNT];
+            if let Some(i) = slot.iter().position(|entry| entry.deadline <= now) {
+                self.queued -= 1;
+                return Some(slot.swap_remove(i).timer);
+            }
+            if self.current >= now_slot {
+                break;
+            }
+            // Every entry of a past slot is due, so the current slot is empty here.
+            if self.queued == 0 {
+                self.current = now_slot;
+                self.cascade();
+            } else {
+                self.current += 1;
+                if self.current % SLOT_COUNT as u64 == 0 {
+                    self.cascade();
+                }
+            }
+        }
+        let i = self
+            .overflow
+            .iter()
+            .position(|entry| entry.deadline <= now)?;
+        Some(self.overflow.swap_remove(i).timer)
+    }
+
+    /// Remove all entries.
+    fn drain(&mut self) -> Vec<Entry> {
+        self.queued = 0;
+        self.slots
+            .iter_mut()
+            .flat_map(|slot| slot.drain(..))
+            .chain(self.overflow.drain(..))
+            .collect()
+    }
+
+    /// The earliest deadline in the wheel.
+    fn next_deadline(&self) -> Option<u128> {
+        let in_slots = (0..SLOT_COUNT)
+            .map(|i| &self.slots[(self.current as usize + i) % SLOT_COUNT])
+            .find(|slot| !slot.is_empty())
+            .and_then(|slot| slot.iter().map(|entry| entry.deadline).min());
+        let in_overflow = self.overflow.iter().map(|entry| entry.deadline).min();
+        match (in_slots, in_overflow) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        }
+    }
+}
+
+/// Add a timer firing on this CPU once `deadline` is reached.
+///
+/// If the wheel cannot grow, the timer is dropped. Sleeping contexts are still woken up by the
+/// scheduler checking their wake time, and timeouts by the next interrupt, at most `MAX_IDLE`
+/// later in one-shot mode.
+pub fn add(deadline: u128, timer: Timer) {
+    queue(deadline, timer);
+    program();
+}
+
+/// Like [`add`], without arming the timer, for the scheduler which does so itself.
+pub fn queue(deadline: u128, timer: Timer) {
+    let _ = PercpuBlock::current()
+        .timers
+        .lock()
+        .insert(Entry { deadline, timer });
+}
+
+/// Fire the timers of this CPU that are due.
+pub fn run_expired() {
+    let percpu = PercpuBlock::current();
+    let now = time::monotonic();
+    // The wheel is unlocked while firing, as waking up a context may add timers.
+    loop {
+        let Some(timer) = percpu.timers.lock().pop_expired(now) else {
+            break;
+        };
+        timer.fire(now);
+    }
+}
+
+/// Move the timers of this CPU to `target`, as it is going offline, and make it rearm its timer.
+pub fn migrate(target: LogicalCpuId) {
+    let Some(target_percpu) = crate::percpu::get(target) else {
+        return;
+    };
+    let entries = PercpuBlock::current().timers.lock().drain();
+    {
+        let mut timers = target_percpu.timers.lock();
+        for entry in entries {
+            let _ = timers.insert(entry);
+        }
+    }
+    context::switch::resched(target);
+}
+
+/// Arm the one-shot timer of this CPU for its next deadline, if in one-shot mode.
+pub fn program() {
+    if !oneshot() {
+        return;
+    }
+    // Stays disarmed while parked.
+    if hotplug::is_offline(PercpuBlock::current().cpu_id) {
+        return;
+    }
+    unsafe {
+        crate::arch::time::arm_oneshot(next_deadline());
+    }
+}
+
+/// The earliest time this CPU has to do something at, at most `MAX_IDLE` from now.
+pub fn next_deadline() -> u128 {
+    [
+        PercpuBlock::current().timers.lock().next_deadline(),
+        context::switch::next_deadline(),
+    ]
+    .into_iter()
+    .flatten()
+    .fold(time::monotonic() + MAX_IDLE, u128::min)
+}
+
+/// Handle the one-shot timer interrupt of this CPU, after it was acknowledged.
+pub fn interrupt() {
+    run_expired();
+    context::switch::slice_timer();
+    program();
+}
@@ -0,0 +1,153 @@
+//! Kernel event tracing.
+//!
+//! Static tracepoints in the syscall, context switch, IRQ and page fault paths record binary
+//! [`Record`]s into a ring buffer of the CPU they occur on, which profilers stream through the
+//! `trace:` scheme. Each event is enabled separately, and a disabled tracepoint only costs a
+//! relaxed load. The buffers are allocated the first time tracing is enabled.
+
+use core::sync::atomic::{AtomicPtr, AtomicU32, Ordering};
+
+use alloc::boxed::Box;
+
+use crate::{
+    cpu_set::{LogicalCpuId, MAX_CPU_COUNT},
+    percpu::PercpuBlock,
+    syscall::error::Result,
+};
+
+mod ring;
+
+pub use self::ring::RingBuffer;
+
+/// Number of records in the buffer of each CPU.
+const RECORDS_PER_CPU: usize = 8192;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[repr(u16)]
+pub enum Event {
+    /// Arguments are the syscall number and its first two arguments.
+    SyscallEnter = 1,
+    /// Arguments are the syscall number and its return value.
+    SyscallExit = 2,
+    /// The record context is the previous context. Arguments are the address and the process ID
+    /// of the next context.
+    Switch = 3,
+    /// Arguments are the IRQ number.
+    Irq = 4,
+    /// Arguments are the faulting address, the generic page fault flags, and the instruction
+    /// pointer.
+    PageFault = 5,
+}
+
+impl Event {
+    pub const ALL: [Event; 5] = [
+        Event::SyscallEnter,
+        Event::SyscallExit,
+        Event::Switch,
+        Event::Irq,
+        Event::PageFault,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Event::SyscallEnter => "syscall-enter",
+            Event::SyscallExit => "syscall-exit",
+            Event::Switch => "switch",
+            Event::Irq => "irq",
+            Event::PageFault => "page-fault",
+        }
+    }
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|event| event.name() == name)
+    }
+    fn bit(self) -> u32 {
+        1 << self as u16
+    }
+}
+
+/// A trace record, as read from the `trace:` scheme.
+#[derive(Clone, Copy, Debug, Default)]
+#[repr(C)]
+pub struct Record {
+    /// Monotonic time in nanoseconds.
+    pub time: u64,
+    /// The [`Event`] discriminant.
+    pub event: u16,
+    pub cpu: u16,
+    pub _rsvd: u32,
+    /// Address of the context running when the event occurred, or zero if none.
+    pub context: u64,
+    pub args: [u64; 3],
+}
+
+/// Bitmask of the enabled events.
+static ENABLED: AtomicU32 = AtomicU32::new(0);
+
+const NULL: AtomicPtr<RingBuffer> = AtomicPtr::new(core::ptr::null_mut());
+static BUFS: [AtomicPtr<RingBuffer>; MAX_CPU_COUNT as usize] = [NULL; MAX_CPU_COUNT as usize];
+
+pub fn buffer(cpu_id: LogicalCpuId) -> Option<&'static RingBuffer> {
+    unsafe {
+        BUFS.get(cpu_id.get() as usize)?
+            .load(Ordering::Acquire)
+            .as_ref()
+    }
+}
+
+pub fn is_enabled(event: Event) -> bool {
+    ENABLED.load(Ordering::Relaxed) & event.bit() != 0
+}
+
+/// Record an event on the current CPU, if it is enabled.
+#[inline]
+pub fn record(event: Event, args: [u64; 3]) {
+    if is_enabled(event) {
+        record_slow(event, args);
+    }
+}
+
+#[cold]
+fn record_slow(event: Event, args: [u64; 3]) {
+    let percpu = PercpuBlock::current();
+    let Some(buffer) = buffer(percpu.cpu_id) else {
+        return;
+    };
+    buffer.push(Record {
+        time: crate::time::monotonic() as u64,
+        event: event as u16,
+        cpu: percpu.cpu_id.get() as u16,
+        _rsvd: 0,
+        context: percpu.switch_internals.context_addr() as u64,
+        args,
+    });
+}
+
+/// Replace the set of enabled events, allocating the buffers of all CPUs if needed.
+pub fn set_enabled(events: impl IntoIterator<Item = Event>) -> Result<()> {
+    let mask = events.into_iter().fold(0, |mask, event| mask | event.bit());
+
+    if mask != 0 {
+        for id in 0..crate::cpu_count() {
+            let slot = &BUFS[id as usize];
+            if !slot.load(Ordering::Acquire).is_null() {
+                continue;
+            }
+            let buffer = Box::into_raw(Box::new(RingBuffer::new(RECORDS_PER_CPU)?));
+            // Buffers are never freed, since tracepoints may be using them concurrently.
+            if slot
+                .compare_exchange(
+                    core::ptr::null_mut(),
+                    buffer,
+                    Ordering::AcqRel,
+                    Ordering::Acquire,
+                )
+                .is_err()
+            {
+                drop(unsafe { Box::from_raw(buffer) });
+            }
+        }
+    }
+
+    ENABLED.store(mask, Ordering::Relaxed);
+    Ok(())
+}
@@ -0,0 +1,120 @@
+use alloc::{boxed::Box, vec::Vec};
+use core::{
+    cell::UnsafeCell,
+    mem::size_of,
+    slice,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use crate::sync::Mutex;
+
+use crate::syscall::{
+    error::{Error, Result, ENOMEM},
+    usercopy::UserSliceWo,
+};
+
+use super::Record;
+
+struct Slot {
+    /// One more than the position of the record stored here, once it has been fully written.
+    seq: AtomicUsize,
+    record: UnsafeCell<Record>,
+}
+
+/// A ring buffer of trace records, written by the CPU owning it and drained by readers on any CPU.
+///
+/// Writers reserve a position by advancing the tail, which tolerates tracepoints in interrupt
+/// handlers nesting inside other tracepoints. A record only becomes visible to readers once its
+/// sequence number has been published. When the buffer is full, new records are dropped and
+/// counted as lost, so that readers never observe a record being overwritten.
+pub struct RingBuffer {
+    /// Position of the next record to be read.
+    head: AtomicUsize,
+    /// Position of the next record to be written.
+    tail: AtomicUsize,
+    lost: AtomicUsize,
+    slots: Box<[Slot]>,
+    /// Serializes readers, which advance the head.
+    reader: Mutex<()>,
+}
+
+unsafe impl Sync for RingBuffer {}
+
+impl RingBuffer {
+    pub fn new(capacity: usize) -> Result<Self> {
+        let mut slots = Vec::new();
+        slots
+            .try_reserve_exact(capacity)
+            .map_err(|_| Error::new(ENOMEM))?;
+        slots.extend((0..capacity).map(|_| Slot {
+            seq: AtomicUsize::new(0),
+            record: UnsafeCell::new(Record::default()),
+        }));
+
+        Ok(Self {
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+            lost: AtomicUsize::new(0),
+            slots: slots.into_boxed_slice(),
+            reader: Mutex::new(()),
+        })
+    }
+
+    /// Number of records dropped since the buffer was created, because it was full.
+    pub fn lost(&self) -> usize {
+        self.lost.load(Ordering::Relaxed)
+    }
+
+    pub fn push(&self, record: Record) {
+        let mut tail = self.tail.load(Ordering::Relaxed);
+        loop {
+            if tail.wrapping_sub(self.head.load(Ordering::Acquire)) >= self.slots.len() {
+                self.lost.fetch_add(1, Ordering::Relaxed);
+                return;
+            }
+            match self.tail.compare_exchange_weak(
+                tail,
+                tail.wrapping_add(1),
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => break,
+                Err(current) => tail = current,
+            }
+        }
+
+        let slot = &self.slots[tail % self.slots.len()];
+        unsafe {
+            slot.record.get().write(record);
+        }
+        slot.seq.store(tail.wrapping_add(1), Ordering::Release);
+    }
+
+    /// Copy as many whole records as are available and fit into `buf`, removing them from the
+    /// buffer. Returns the number of bytes copied, which is zero if the buffer is empty.
+    pub fn drain(&self, buf: UserSliceWo) -> Result<usize> {
+        let _guard = self.reader.lock();
+
+        let mut head = self.head.load(Ordering::Relaxed);
+        let mut copied = 0;
+
+        for chunk in buf.in_exact_chunks(size_of::<Record>()) {
+            let slot = &self.slots[head % self.slots.len()];
+            if slot.seq.load(Ordering::Acquire) != head.wrapping_add(1) {
+                break;
+            }
+            // The writer cannot reuse the slot before the head is advanced past it.
+            let record = unsafe { slot.record.get().read() };
+            let bytes = unsafe {
+                slice::from_raw_parts(&record as *const Record as *const u8, size_of::<Record>())
+            };
+            chunk.copy_exactly(bytes)?;
+
+            head = head.wrapping_add(1);
+            self.head.store(head, Ordering::Release);
+            copied += size_of::<Record>();
+        }
+
+        Ok(copied)
+    }
+}
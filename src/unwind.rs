@@ -0,0 +1,737 @@
+//! # Kernel stack unwinding
+//!
+//! Backtraces are computed from the DWARF call frame information in `.eh_frame`, which the kernel
+//! is built with (`default-uwtable` in the target specifications). Unlike following the chain of
+//! frame pointers, this also works through functions that do not set up a frame pointer, such as
+//! leaf functions and assembly annotated with CFI directives. Code without unwind information
+//! falls back to the frame pointer.
+//!
+//! On x86_64, the interrupt and exception entry points record the return address of the call into
+//! their handler in `.interrupt_frames`, which lets unwinding continue through the saved
+//! [`InterruptStack`](crate::arch::interrupt::InterruptStack) into the interrupted code.
+
+use arrayvec::ArrayVec;
+use core::slice;
+
+use crate::{
+    arch::{
+        consts::USER_END_OFFSET,
+        interrupt::trace::{
+            current_regs, DWARF_FP, DWARF_RA, DWARF_REG_COUNT, DWARF_SP, FRAME_RECORD,
+        },
+    },
+    context::context::KSTACK_SIZE,
+    kernel_executable_offsets::*,
+    paging::{PageMapper, VirtualAddress},
+};
+
+/// Location of the saved frame pointer and return address relative to the frame pointer, and the
+/// caller's stack pointer, for frames without unwind information.
+pub struct FrameRecord {
+    pub saved_fp: isize,
+    pub ra: isize,
+    pub cfa: isize,
+}
+
+/// A frame of the call stack.
+#[derive(Clone, Copy, Debug)]
+pub struct Frame {
+    pub pc: usize,
+    pub sp: usize,
+    /// The frame was interrupted at `pc`, rather than calling a function returning to `pc`.
+    pub interrupted: bool,
+}
+
+#[derive(Clone, Copy)]
+struct Regs([Option<usize>; DWARF_REG_COUNT]);
+
+impl Regs {
+    fn get(&self, reg: u16) -> Option<usize> {
+        self.0.get(usize::from(reg)).copied().flatten()
+    }
+    fn set(&mut self, reg: u16, value: Option<usize>) {
+        if let Some(slot) = self.0.get_mut(usize::from(reg)) {
+            *slot = value;
+        }
+    }
+}
+
+/// Iterator over the frames of the current kernel stack, innermost first.
+pub struct Unwinder<'a> {
+    mapper: &'a PageMapper,
+    regs: Regs,
+    pc: usize,
+    interrupted: bool,
+    done: bool,
+}
+
+impl<'a> Unwinder<'a> {
+    /// Start unwinding from the caller, which must be marked `#[inline(never)]` for its own frame
+    /// to be correctly described.
+    #[inline(always)]
+    pub fn new(mapper: &'a PageMapper) -> Self {
+        let (pc, sp, fp) = current_regs();
+
+        let mut regs = Regs([None; DWARF_REG_COUNT]);
+        regs.set(DWARF_SP, Some(sp));
+        regs.set(DWARF_FP, Some(fp));
+
+        Self {
+            mapper,
+            regs,
+            pc,
+            // The PC is exact, and not a return address.
+            interrupted: true,
+            done: false,
+        }
+    }
+
+    /// Start unwinding a kernel stack other than the current one, right after a call, with `sp`
+    /// pointing to the return address pushed by it, e.g. from the registers saved when switching
+    /// contexts.
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub fn from_call(mapper: &'a PageMapper, sp: usize, fp: usize) -> Self {
+        let mut regs = Regs([None; DWARF_REG_COUNT]);
+        regs.set(DWARF_SP, Some(sp + core::mem::size_of::<usize>()));
+        regs.set(DWARF_FP, Some(fp));
+
+        let mut this = Self {
+            mapper,
+            regs,
+            pc: 0,
+            interrupted: false,
+            done: false,
+        };
+        match this.read(sp) {
+            Some(pc) => this.pc = pc,
+            None => this.done = true,
+        }
+        this
+    }
+
+    /// Read a word from the kernel stack, if it is mapped.
+    fn read(&self, addr: usize) -> Option<usize> {
+        let virt = VirtualAddress::new(addr);
+        if addr < USER_END_OFFSET
+            || !(addr as *const usize).is_aligned()
+            || self.mapper.translate(virt).is_none()
+        {
+            return None;
+        }
+        Some(unsafe { (addr as *const usize).read() })
+    }
+
+    fn step(&mut self) -> Option<()> {
+        if !(__text_start()..__text_end()).contains(&self.pc) {
+            return None;
+        }
+        if !self.interrupted && self.step_interrupt().is_some() {
+            return Some(());
+        }
+        // Return addresses point after the call, which may be the start of the next function.
+        let lookup_pc = if self.interrupted {
+            self.pc
+        } else {
+            self.pc - 1
+        };
+
+        let old_sp = self.regs.get(DWARF_SP)?;
+        let found =
+            find_fde(lookup_pc).and_then(|(cie, fde)| self.step_dwarf(&cie, &fde, lookup_pc));
+        if found.is_none() {
+            self.step_frame_pointer()?;
+        }
+
+        // Guard against loops, as stacks grow downwards.
+        if self.regs.get(DWARF_SP)? <= old_sp {
+            return None;
+        }
+        self.interrupted = false;
+        Some(())
+    }
+
+    fn step_dwarf(&mut self, cie: &Cie, fde: &Fde, pc: usize) -> Option<()> {
+        let initial = execute(cie, cie.instructions, Row::default(), None, usize::MAX, 0)?;
+        let row = execute(
+            cie,
+            fde.instructions,
+            initial,
+            Some(&initial),
+            pc,
+            fde.pc_begin,
+        )?;
+
+        let cfa = self
+            .regs
+            .get(row.cfa_reg?)?
+            .checked_add_signed(row.cfa_offset)?;
+        let mut regs = self.regs;
+        for (reg, rule) in row.rules.iter().enumerate() {
+            let value = match *rule {
+                Rule::SameValue => continue,
+                Rule::Undefined => None,
+                Rule::Offset(offset) => self.read(cfa.checked_add_signed(offset)?),
+                Rule::ValOffset(offset) => Some(cfa.checked_add_signed(offset)?),
+                Rule::Register(other) => self.regs.get(other),
+            };
+            regs.set(reg as u16, value);
+        }
+        regs.set(DWARF_SP, Some(cfa));
+
+        // An undefined return address marks the outermost frame.
+        self.pc = regs.get(cie.ra_reg)?;
+        self.regs = regs;
+        Some(())
+    }
+
+    fn step_frame_pointer(&mut self) -> Option<()> {
+        let fp = self.regs.get(DWARF_FP)?;
+        let pc = self.read(fp.checked_add_signed(FRAME_RECORD.ra)?)?;
+        let saved_fp = self.read(fp.checked_add_signed(FRAME_RECORD.saved_fp)?)?;
+
+        self.regs = Regs([None; DWARF_REG_COUNT]);
+        self.regs
+            .set(DWARF_SP, Some(fp.checked_add_signed(FRAME_RECORD.cfa)?));
+        self.regs.set(DWARF_FP, Some(saved_fp));
+        self.regs.set(DWARF_RA, Some(pc));
+        self.pc = pc;
+        Some(())
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    fn step_interrupt(&mut self) -> Option<()> {
+        use crate::arch::interrupt::InterruptStack;
+        use core::mem;
+
+        let start = __interrupt_frames_start() as *const usize;
+        let len = (__interrupt_frames_end() - __interrupt_frames_start()) / mem::size_of::<usize>();
+        let return_addrs = unsafe { slice::from_raw_parts(start, len) };
+        if !return_addrs.contains(&self.pc) {
+            return None;
+        }
+
+        // The entry point called its handler with a pointer to the stack.
+        let stack_addr = self.regs.get(DWARF_SP)?;
+        let last_word = stack_addr + mem::size_of::<InterruptStack>() - mem::size_of::<usize>();
+        self.read(stack_addr)?;
+        self.read(last_word)?;
+        let stack = unsafe { &*(stack_addr as *const InterruptStack) };
+
+        for (reg, value) in stack.dwarf_regs().into_iter().enumerate() {
+            self.regs.set(reg as u16, Some(value));
+        }
+        self.pc = stack.iret.rip;
+        self.interrupted = true;
+        Some(())
+    }
+    #[cfg(not(target_arch = "x86_64"))]
+    fn step_interrupt(&mut self) -> Option<()> {
+        None
+    }
+}
+
+/// Fill `addrs` with the return addresses of the function this is inlined into and its callers,
+/// by following frame pointers, returning how many were found. Unlike [`Unwinder`], this is cheap
+/// enough for hot paths.
+///
+/// Without a lock on the kernel page tables, which may be held while taking the locks calling this,
+/// nothing tells whether an address is mapped. Frame records are therefore only read within the
+/// naturally aligned block of [`KSTACK_SIZE`] holding the current stack, which kernel and backup
+/// interrupt stacks fill entirely, and the walk stops at the first return address outside the
+/// kernel text. The frame pointer of userspace, which the entry code leaves in place, can thus at
+/// most point it elsewhere in the current stack.
+#[inline(always)]
+pub fn return_addresses(addrs: &mut [usize]) -> usize {
+    let (_, sp, mut fp) = current_regs();
+    let stack = sp & !(KSTACK_SIZE - 1)..(sp | (KSTACK_SIZE - 1)).saturating_add(1);
+    let read = |addr: usize| {
+        (addr >= USER_END_OFFSET && (addr as *const usize).is_aligned() && stack.contains(&addr))
+            .then(|| unsafe { (addr as *const usize).read() })
+    };
+    for (i, slot) in addrs.iter_mut().enumerate() {
+        let Some(addr) = fp
+            .checked_add_signed(FRAME_RECORD.ra)
+            .and_then(read)
+            .filter(|addr| (__text_start()..__text_end()).contains(addr))
+        else {
+            return i;
+        };
+        *slot = addr;
+        match fp.checked_add_signed(FRAME_RECORD.saved_fp).and_then(read) {
+            Some(next) => fp = next,
+            None => return i + 1,
+        }
+    }
+    addrs.len()
+}
+
+impl Iterator for Unwinder<'_> {
+    type Item = Frame;
+
+    fn next(&mut self) -> Option<Frame> {
+        if self.done {
+            return None;
+        }
+        let frame = Frame {
+            pc: self.pc,
+            sp: self.regs.get(DWARF_SP)?,
+            interrupted: self.interrupted,
+        };
+        if self.step().is_none() {
+            self.done = true;
+        }
+        Some(frame)
+    }
+}
+
+const DW_EH_PE_OMIT: u8 = 0xff;
+const DW_EH_PE_PCREL: u8 = 0x10;
+const DW_EH_PE_INDIRECT: u8 = 0x80;
+
+/// Cursor into `.eh_frame`.
+#[derive(Clone, Copy)]
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes<const N: usize>(&mut self) -> Option<[u8; N]> {
+        let bytes = self.data.get(self.pos..self.pos.checked_add(N)?)?;
+        self.pos += N;
+        bytes.try_into().ok()
+    }
+    fn u8(&mut self) -> Option<u8> {
+        self.bytes::<1>().map(|[b]| b)
+    }
+    fn u16(&mut self) -> Option<u16> {
+        self.bytes().map(u16::from_le_bytes)
+    }
+    fn u32(&mut self) -> Option<u32> {
+        self.bytes().map(u32::from_le_bytes)
+    }
+    fn u64(&mut self) -> Option<u64> {
+        self.bytes().map(u64::from_le_bytes)
+    }
+    fn uleb128(&mut self) -> Option<u64> {
+        let mut value = 0_u64;
+        let mut shift = 0;
+        loop {
+            let byte = self.u8()?;
+            if shift < 64 {
+                value |= u64::from(byte & 0x7f) << shift;
+            }
+            shift += 7;
+            if byte & 0x80 == 0 {
+                return Some(value);
+            }
+        }
+    }
+    fn sleb128(&mut self) -> Option<i64> {
+        let mut value = 0_i64;
+        let mut shift = 0;
+        loop {
+            let byte = self.u8()?;
+            if shift < 64 {
+                value |= i64::from(byte & 0x7f) << shift;
+            }
+            shift += 7;
+            if byte & 0x80 == 0 {
+                if shift < 64 && byte & 0x40 != 0 {
+                    value |= -1 << shift;
+                }
+                return Some(value);
+            }
+        }
+    }
+    fn reg(&mut self) -> Option<u16> {
+        self.uleb128()?.try_into().ok()
+    }
+    fn skip(&mut self, len: usize) -> Option<()> {
+        let pos = self.pos.checked_add(len)?;
+        (pos <= self.data.len()).then(|| self.pos = pos)
+    }
+    fn address(&self) -> usize {
+        self.data.as_ptr() as usize + self.pos
+    }
+    /// Read a pointer with the given `DW_EH_PE_*` encoding.
+    fn pointer(&mut self, encoding: u8) -> Option<usize> {
+        if encoding == DW_EH_PE_OMIT {
+            return None;
+        }
+        let field_address = self.address();
+        let value = match encoding & 0x0f {
+            0x00 => usize::from_le_bytes(self.bytes()?),
+            0x01 => self.uleb128()? as usize,
+            0x02 => self.u16()? as usize,
+            0x03 => self.u32()? as usize,
+            0x04 => self.u64()? as usize,
+            0x09 => self.sleb128()? as usize,
+            0x0a => self.u16()? as i16 as usize,
+            0x0b => self.u32()? as i32 as usize,
+            0x0c => self.u64()? as usize,
+            _ => return None,
+        };
+        let value = match encoding & 0x70 {
+            0 => value,
+            DW_EH_PE_PCREL => field_address.wrapping_add(value),
+            // Text and data relative encodings are not used by static executables.
+            _ => return None,
+        };
+        if encoding & DW_EH_PE_INDIRECT != 0 {
+            return None;
+        }
+        Some(value)
+    }
+}
+
+/// Common Information Entry, shared by the FDEs of a compilation unit.
+struct Cie<'a> {
+    code_align: usize,
+    data_align: isize,
+    ra_reg: u16,
+    fde_encoding: u8,
+    has_augmentation_data: bool,
+    instructions: &'a [u8],
+}
+
+/// Frame Description Entry, which describes the frames of one function.
+struct Fde<'a> {
+    pc_begin: usize,
+    instructions: &'a [u8],
+}
+
+fn eh_frame() -> &'static [u8] {
+    let start = __eh_frame_start();
+    unsafe { slice::from_raw_parts(start as *const u8, __eh_frame_end() - start) }
+}
+
+/// Read the length of an entry, returning the ID field and the end of the entry.
+fn entry(data: &[u8], pos: usize) -> Option<(Reader<'_>, usize)> {
+    let mut reader = Reader { data, pos };
+    let len = match reader.u32()? {
+        0xffff_ffff => reader.u64()?.try_into().ok()?,
+        len => len as usize,
+    };
+    let end = reader.pos.checked_add(len)?;
+    (end <= data.len()).then_some((reader, end))
+}
+
+impl<'a> Cie<'a> {
+    fn parse(data: &'a [u8], pos: usize) -> Option<Self> {
+        let (mut r, end) = entry(data, pos)?;
+        if r.u32()? != 0 {
+            return None;
+        }
+        let version = r.u8()?;
+        if !matches!(version, 1 | 3) {
+            return None;
+        }
+        let aug_start = r.pos;
+        while r.u8()? != 0 {}
+        let augmentation = &data[aug_start..r.pos - 1];
+
+        let code_align = r.uleb128()? as usize;
+        let data_align = r.sleb128()? as isize;
+        let ra_reg = if version == 1 {
+            r.u8()?.into()
+        } else {
+            r.reg()?
+        };
+
+        let mut fde_encoding = 0;
+        let has_augmentation_data = augmentation.first() == Some(&b'z');
+        if has_augmentation_data {
+            let len = r.uleb128()? as usize;
+            let mut aug = Reader {
+                data: &data[..r.pos.checked_add(len)?.min(end)],
+                pos: r.pos,
+            };
+            for c in &augmentation[1..] {
+                match c {
+                    b'L' => {
+                        aug.u8()?;
+                    }
+                    b'P' => {
+                        let encoding = aug.u8()?;
+                        aug.pointer(encoding & !DW_EH_PE_INDIRECT)?;
+                    }
+                    b'R' => fde_encoding = aug.u8()?,
+                    // Signal frames, and pointer authentication or memory tagging keys.
+                    b'S' | b'B' | b'G' => (),
+                    _ => break,
+                }
+            }
+            r.skip(len)?;
+        } else if !augmentation.is_empty() {
+            return None;
+        }
+
+        Some(Self {
+            code_align,
+            data_align,
+            ra_reg,
+            fde_encoding,
+            has_augmentation_data,
+            instructions: data.get(r.pos..end)?,
+        })
+    }
+}
+
+/// Find the FDE covering `pc`, by a linear search, since this is only used for backtraces.
+fn find_fde(pc: usize) -> Option<(Cie<'static>, Fde<'static>)> {
+    let data = eh_frame();
+    let mut pos = 0;
+
+    while pos < data.len() {
+        let (mut r, end) = entry(data, pos)?;
+        // A zero length entry terminates the section.
+        if end == r.pos {
+            break;
+        }
+        let id_pos = r.pos;
+        let cie_offset = r.u32()? as usize;
+
+        if cie_offset != 0
+            && let Some(cie) = id_pos
+                .checked_sub(cie_offset)
+                .and_then(|cie_pos| Cie::parse(data, cie_pos))
+            && let Some(pc_begin) = r.pointer(cie.fde_encoding)
+            && let Some(pc_range) = r.pointer(cie.fde_encoding & 0x0f)
+            && (pc_begin..pc_begin.wrapping_add(pc_range)).contains(&pc)
+        {
+            if cie.has_augmentation_data {
+                let len = r.uleb128()? as usize;
+                r.skip(len)?;
+            }
+            let fde = Fde {
+                pc_begin,
+                instructions: data.get(r.pos..end)?,
+            };
+            return Some((cie, fde));
+        }
+        pos = end;
+    }
+    None
+}
+
+#[derive(Clone, Copy, Default)]
+enum Rule {
+    #[default]
+    SameValue,
+    Undefined,
+    /// Saved at the given offset from the CFA.
+    Offset(isize),
+    /// The CFA plus the given offset.
+    ValOffset(isize),
+    Register(u16),
+}
+
+#[derive(Clone, Copy)]
+struct Row {
+    /// The register the CFA is relative to, or `None` if it is given by an (unsupported)
+    /// expression.
+    cfa_reg: Option<u16>,
+    cfa_offset: isize,
+    rules: [Rule; DWARF_REG_COUNT],
+}
+
+impl Default for Row {
+    fn default() -> Self {
+        Self {
+            cfa_reg: Some(DWARF_SP),
+            cfa_offset: 0,
+            rules: [Rule::SameValue; DWARF_REG_COUNT],
+        }
+    }
+}
+
+impl Row {
+    fn set(&mut self, reg: u16, rule: Rule) {
+        // Registers not tracked, such as vector registers, are not needed to find the caller.
+        if let Some(slot) = self.rules.get_mut(usize::from(reg)) {
+            *slot = rule;
+        }
+    }
+    fn get(&self, reg: u16) -> Rule {
+        self.rules
+            .get(usize::from(reg))
+            .copied()
+            .unwrap_or_default()
+    }
+}
+
+/// Run the call frame instructions until the row for `pc` is found.
+fn execute(
+    cie: &Cie,
+    instructions: &[u8],
+    mut row: Row,
+    initial: Option<&Row>,
+    pc: usize,
+    mut loc: usize,
+) -> Option<Row> {
+    let mut r = Reader {
+        data: instructions,
+        pos: 0,
+    };
+    let mut remembered = ArrayVec::<Row, 8>::new();
+
+    let factored = |offset: u64| (offset as isize).checked_mul(cie.data_align);
+    let factored_sf = |offset: i64| (offset as isize).checked_mul(cie.data_align);
+
+    while r.pos < instructions.len() {
+        let op = r.u8()?;
+        let advance = match (op >> 6, op & 0x3f) {
+            // DW_CFA_advance_loc
+            (1, delta) => Some(usize::from(delta)),
+            // DW_CFA_offset
+            (2, reg) => {
+                row.set(reg.into(), Rule::Offset(factored(r.uleb128()?)?));
+                None
+            }
+            // DW_CFA_restore
+            (3, reg) => {
+                row.set(reg.into(), initial?.get(reg.into()));
+                None
+            }
+            (_, op) => match op {
+                // DW_CFA_nop
+                0x00 => None,
+                // DW_CFA_set_loc
+                0x01 => {
+                    loc = r.pointer(cie.fde_encoding)?;
+                    if loc > pc {
+                        return Some(row);
+                    }
+                    None
+                }
+                // DW_CFA_advance_loc1, 2, 4
+                0x02 => Some(usize::from(r.u8()?)),
+                0x03 => Some(usize::from(r.u16()?)),
+                0x04 => Some(r.u32()? as usize),
+                // DW_CFA_offset_extended
+                0x05 => {
+                    let reg = r.reg()?;
+                    row.set(reg, Rule::Offset(factored(r.uleb128()?)?));
+                    None
+                }
+                // DW_CFA_restore_extended
+                0x06 => {
+                    let reg = r.reg()?;
+                    row.set(reg, initial?.get(reg));
+                    None
+                }
+                // DW_CFA_undefined
+                0x07 => {
+                    row.set(r.reg()?, Rule::Undefined);
+                    None
+                }
+                // DW_CFA_same_value
+                0x08 => {
+                    row.set(r.reg()?, Rule::SameValue);
+                    None
+                }
+                // DW_CFA_register
+                0x09 => {
+                    let reg = r.reg()?;
+                    row.set(reg, Rule::Register(r.reg()?));
+                    None
+                }
+                // DW_CFA_remember_state
+                0x0a => {
+                    remembered.try_push(row).ok()?;
+                    None
+                }
+                // DW_CFA_restore_state, which keeps the CFA
+                0x0b => {
+                    let (cfa_reg, cfa_offset) = (row.cfa_reg, row.cfa_offset);
+                    row = remembered.pop()?;
+                    row.cfa_reg = cfa_reg;
+                    row.cfa_offset = cfa_offset;
+                    None
+                }
+                // DW_CFA_def_cfa
+                0x0c => {
+                    row.cfa_reg = Some(r.reg()?);
+                    row.cfa_offset = r.uleb128()? as isize;
+                    None
+                }
+                // DW_CFA_def_cfa_register
+                0x0d => {
+                    row.cfa_reg = Some(r.reg()?);
+                    None
+                }
+                // DW_CFA_def_cfa_offset
+                0x0e => {
+                    row.cfa_offset = r.uleb128()? as isize;
+                    None
+                }
+                // DW_CFA_def_cfa_expression
+                0x0f => {
+                    let len = r.uleb128()? as usize;
+                    r.skip(len)?;
+                    row.cfa_reg = None;
+                    None
+                }
+                // DW_CFA_expression, DW_CFA_val_expression
+                0x10 | 0x16 => {
+                    let reg = r.reg()?;
+                    let len = r.uleb128()? as usize;
+                    r.skip(len)?;
+                    row.set(reg, Rule::Undefined);
+                    None
+                }
+                // DW_CFA_offset_extended_sf
+                0x11 => {
+                    let reg = r.reg()?;
+                    row.set(reg, Rule::Offset(factored_sf(r.sleb128()?)?));
+                    None
+                }
+                // DW_CFA_def_cfa_sf
+                0x12 => {
+                    row.cfa_reg = Some(r.reg()?);
+                    row.cfa_offset = factored_sf(r.sleb128()?)?;
+                    None
+                }
+                // DW_CFA_def_cfa_offset_sf
+                0x13 => {
+                    row.cfa_offset = factored_sf(r.sleb128()?)?;
+                    None
+                }
+                // DW_CFA_val_offset
+                0x14 => {
+                    let reg = r.reg()?;
+                    row.set(reg, Rule::ValOffset(factored(r.uleb128()?)?));
+                    None
+                }
+                // DW_CFA_val_offset_sf
+                0x15 => {
+                    let reg = r.reg()?;
+                    row.set(reg, Rule::ValOffset(factored_sf(r.sleb128()?)?));
+                    None
+                }
+                // DW_CFA_AARCH64_negate_ra_state, the kernel does not sign return addresses.
+                0x2d => None,
+                // DW_CFA_GNU_args_size
+                0x2e => {
+                    r.uleb128()?;
+                    None
+                }
+                // DW_CFA_GNU_negative_offset_extended
+                0x2f => {
+                    let reg = r.reg()?;
+                    row.set(reg, Rule::Offset(factored(r.uleb128()?)?.checked_neg()?));
+                    None
+                }
+                _ => return None,
+            },
+        };
+        if let Some(delta) = advance {
+            loc = loc.checked_add(delta.checked_mul(cie.code_align)?)?;
+            if loc > pc {
+                break;
+            }
+        }
+    }
+    Some(row)
+}
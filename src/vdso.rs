@@ -0,0 +1,159 @@
+//! # vDSO data page
+//!
+//! A page of kernel data that any process can map read-only, by `fmap`ping `time:vdso`, to read
+//! the clocks and the CPU it runs on without entering the kernel. As programs are loaded by
+//! userspace, the kernel only provides the data, and the C library reads it as follows.
+//!
+//! The fields are updated under a sequence count, which is odd while an update is in progress. A
+//! reader loads `seq`, retrying while it is odd, reads the fields it needs, and retries if `seq`
+//! has changed in the meantime. Then, unless `clock_mode` is [`VDSO_CLOCK_NONE`], in which case
+//! the clocks must be read from the `time:` scheme instead,
+//!
+//! ```text
+//! CLOCK_MONOTONIC = base_nanos + (counter - base_counter) * 1_000_000_000 / frequency
+//! CLOCK_REALTIME = CLOCK_MONOTONIC + realtime_offset
+//! CLOCK_BOOTTIME = CLOCK_MONOTONIC + boottime_offset
+//! ```
+//!
+//! in nanoseconds, with 128-bit intermediate results, where the counter is read with `RDTSC` for
+//! [`VDSO_CLOCK_TSC`] and from `CNTVCT_EL0` for [`VDSO_CLOCK_CNTVCT`]. This is the same computation
+//! as the kernel's, so that the results match those of the syscalls exactly.
+//!
+//! If `flags` contains [`VDSO_CPU_TSC_AUX`], `RDTSCP` and `RDPID` return the logical CPU id, and
+//! `cpu_nodes` has the NUMA node of each CPU, together making up `getcpu`.
+
+use core::sync::atomic::{fence, AtomicU32, AtomicU64, AtomicU8, Ordering};
+
+use crate::{
+    cpu_set::{LogicalCpuId, MAX_CPU_COUNT},
+    memory::{Frame, KernelMapper, PAGE_SIZE},
+    numa::NodeId,
+    paging::VirtualAddress,
+    sync::Mutex,
+    time,
+};
+
+/// Version of the layout of [`VdsoData`], increased whenever it changes incompatibly.
+pub const VDSO_VERSION: u32 = 1;
+
+/// No counter can be read from userspace.
+pub const VDSO_CLOCK_NONE: u32 = 0;
+/// The invariant TSC.
+pub const VDSO_CLOCK_TSC: u32 = 1;
+/// The virtual count of the generic timer.
+pub const VDSO_CLOCK_CNTVCT: u32 = 2;
+
+/// `IA32_TSC_AUX` contains the logical CPU id.
+pub const VDSO_CPU_TSC_AUX: u32 = 1 << 0;
+
+#[repr(C)]
+pub struct VdsoData {
+    pub seq: AtomicU32,
+    pub version: AtomicU32,
+    pub clock_mode: AtomicU32,
+    pub flags: AtomicU32,
+    pub base_counter: AtomicU64,
+    pub base_nanos: AtomicU64,
+    /// Counter frequency in Hz.
+    pub frequency: AtomicU64,
+    pub realtime_offset: AtomicU64,
+    pub boottime_offset: AtomicU64,
+    pub cpu_nodes: [AtomicU8; MAX_CPU_COUNT as usize],
+}
+
+/// The counter the monotonic clock is computed from, if userspace can read it.
+pub struct VdsoClock {
+    pub mode: u32,
+    pub base_counter: u64,
+    /// Monotonic time at `base_counter`, not including the time added after resuming.
+    pub base_nanos: u64,
+    pub frequency: u64,
+}
+
+// Alone in its page, as the whole page is mapped into userspace.
+#[repr(C, align(4096))]
+struct VdsoPage(VdsoData);
+
+const _: () = assert!(core::mem::size_of::<VdsoPage>() == PAGE_SIZE);
+
+static VDSO: VdsoPage = VdsoPage(VdsoData {
+    seq: AtomicU32::new(0),
+    version: AtomicU32::new(VDSO_VERSION),
+    clock_mode: AtomicU32::new(VDSO_CLOCK_NONE),
+    flags: AtomicU32::new(0),
+    base_counter: AtomicU64::new(0),
+    base_nanos: AtomicU64::new(0),
+    frequency: AtomicU64::new(0),
+    realtime_offset: AtomicU64::new(0),
+    boottime_offset: AtomicU64::new(0),
+    cpu_nodes: [const { AtomicU8::new(0) }; MAX_CPU_COUNT as usize],
+});
+
+/// Serializes updates, as readers only support one writer.
+static UPDATE_LOCK: Mutex<()> = Mutex::new(());
+
+pub fn data() -> &'static VdsoData {
+    &VDSO.0
+}
+
+/// The frame of the page, to be mapped into userspace.
+pub fn frame() -> Frame {
+    let (phys, _) = KernelMapper::lock()
+        .translate(VirtualAddress::new(&VDSO as *const VdsoPage as usize))
+        .expect("vDSO page not mapped");
+    Frame::containing(phys)
+}
+
+/// Refresh the clock parameters, whenever the clock source or the offsets of the clocks change.
+pub fn update() {
+    let _guard = UPDATE_LOCK.lock();
+    let data = data();
+
+    let clock = crate::arch::time::vdso_clock();
+    let realtime_offset = *time::START.lock();
+    let (resume_offset, suspended) = time::offsets();
+
+    let flags = cpu_flags();
+
+    data.seq.fetch_add(1, Ordering::Relaxed);
+    fence(Ordering::Release);
+
+    match clock {
+        Some(clock) => {
+            data.clock_mode.store(clock.mode, Ordering::Relaxed);
+            data.base_counter
+                .store(clock.base_counter, Ordering::Relaxed);
+            data.base_nanos
+                .store(clock.base_nanos + resume_offset, Ordering::Relaxed);
+            data.frequency.store(clock.frequency, Ordering::Relaxed);
+        }
+        None => data.clock_mode.store(VDSO_CLOCK_NONE, Ordering::Relaxed),
+    }
+    data.realtime_offset
+        .store(realtime_offset as u64, Ordering::Relaxed);
+    data.boottime_offset.store(suspended, Ordering::Relaxed);
+    data.flags.store(flags, Ordering::Relaxed);
+
+    data.seq.fetch_add(1, Ordering::Release);
+}
+
+pub fn set_cpu_node(cpu: LogicalCpuId, node: NodeId) {
+    data().cpu_nodes[cpu.get() as usize].store(node.get(), Ordering::Relaxed);
+}
+
+#[cfg(target_arch = "x86_64")]
+fn cpu_flags() -> u32 {
+    // Set to the CPU id by arch::misc::init.
+    let has_rdtscp = crate::cpuid::cpuid()
+        .get_extended_processor_and_feature_identifiers()
+        .map_or(false, |feats| feats.has_rdtscp());
+    if has_rdtscp {
+        VDSO_CPU_TSC_AUX
+    } else {
+        0
+    }
+}
+#[cfg(not(target_arch = "x86_64"))]
+fn cpu_flags() -> u32 {
+    0
+}
@@ -0,0 +1,226 @@
+//! # Work queues
+//!
+//! Work that cannot be done where it is noticed, such as in an interrupt handler, or while holding
+//! a spinlock, is deferred to a kernel thread by queueing a [`Work`] item on a work queue. Every CPU
+//! has its own queue, run by its `[kworker/N]` thread, which [`schedule_work`] queues on. Other
+//! queues are ordered: they are run by a single thread of their own, so that their items run one at
+//! a time in the order they were queued, like the `[writeback]` one.
+//!
+//! Items are statics, linked into the queue they are on, so that queueing never allocates and can
+//! be done from interrupt context. An item is pending from the time it is scheduled until its
+//! function starts, and scheduling it again meanwhile does nothing, so that however many times it
+//! is scheduled, it runs at least once after that. Delayed work is queued by a timer of the CPU it
+//! was scheduled on, see [`crate::timer`].
+//!
+//! The worker of a CPU going offline is released from it like the other contexts, and runs on
+//! another CPU until it comes back, so that its queue is still run.
+
+use alloc::{borrow::Cow, format, vec::Vec};
+use core::{
+    ptr,
+    sync::atomic::{AtomicBool, AtomicPtr, Ordering},
+};
+
+use crate::{
+    common::try_alloc::try_push,
+    context::{
+        self,
+        process::{new_process, ProcessInfo},
+    },
+    cpu_set::{LogicalCpuId, LogicalCpuSet},
+    percpu::PercpuBlock,
+    scheme::SchemeNamespace,
+    sync::{Mutex, WaitCondition},
+    syscall::error::Result,
+    time,
+    timer::{self, Timer},
+};
+
+/// A function to run from a work queue.
+pub struct Work {
+    func: fn(),
+    pending: AtomicBool,
+    /// The next item on the queue this one is on.
+    next: AtomicPtr<Work>,
+}
+
+impl Work {
+    pub const fn new(func: fn()) -> Self {
+        Self {
+            func,
+            pending: AtomicBool::new(false),
+            next: AtomicPtr::new(ptr::null_mut()),
+        }
+    }
+}
+
+/// Pending items, oldest first, linked through their `next` field.
+struct WorkList {
+    head: *const Work,
+    tail: *const Work,
+}
+
+// Only points to statics.
+unsafe impl Send for WorkList {}
+
+impl WorkList {
+    fn push(&mut self, work: &'static Work) {
+        work.next.store(ptr::null_mut(), Ordering::Relaxed);
+        match unsafe { self.tail.as_ref() } {
+            Some(tail) => tail
+                .next
+                .store(work as *const Work as *mut Work, Ordering::Relaxed),
+            None => self.head = work,
+        }
+        self.tail = work;
+    }
+
+    fn pop(&mut self) -> Option<&'static Work> {
+        let work = unsafe { self.head.as_ref()? };
+        self.head = work.next.load(Ordering::Relaxed);
+        if self.head.is_null() {
+            self.tail = ptr::null();
+        }
+        Some(work)
+    }
+}
+
+pub struct WorkQueue {
+    list: Mutex<WorkList>,
+    condition: WaitCondition,
+}
+
+impl WorkQueue {
+    pub const fn new() -> Self {
+        Self {
+            list: Mutex::new(WorkList {
+                head: ptr::null(),
+                tail: ptr::null(),
+            }),
+            condition: WaitCondition::new(),
+        }
+    }
+
+    /// Append an item that was marked pending, and wake up the worker.
+    fn queue(&self, work: &'static Work) {
+        self.list.lock().push(work);
+        self.condition.notify();
+    }
+
+    /// Queue `work`, returning false if it was pending already.
+    pub fn schedule(&self, work: &'static Work) -> bool {
+        if work.pending.swap(true, Ordering::AcqRel) {
+            return false;
+        }
+        self.queue(work);
+        true
+    }
+
+    /// Queue `work` on this queue once `delay` nanoseconds have passed, returning false if it was
+    /// pending already.
+    pub fn schedule_delayed(&'static self, work: &'static Work, delay: u128) -> bool {
+        if delay == 0 {
+            return self.schedule(work);
+        }
+        if work.pending.swap(true, Ordering::AcqRel) {
+            return false;
+        }
+        timer::add(time::monotonic() + delay, Timer::Work { queue: self, work });
+        true
+    }
+
+    /// Run the items queued so far, and those queued while they run.
+    pub fn run_pending(&self) {
+        loop {
+            let Some(work) = self.list.lock().pop() else {
+                break;
+            };
+            // Scheduling it again from here on runs it again.
+            work.pending.store(false, Ordering::Release);
+            (work.func)();
+        }
+    }
+
+    /// Spawn the thread running the queue, restricted to `cpu` if there is one.
+    pub fn spawn(&'static self, name: Cow<'static, str>, cpu: Option<LogicalCpuId>) -> Result<()> {
+        let process = new_process(|pid| ProcessInfo {
+            pid,
+            pgid: pid,
+            ppid: pid,
+            session_id: pid,
+            ruid: 0,
+            rgid: 0,
+            euid: 0,
+            egid: 0,
+            rns: SchemeNamespace::new(0),
+            ens: SchemeNamespace::new(0),
+        })?;
+        let context_lock = context::spawn(false, process, worker_main)?;
+        try_push(
+            &mut WORKERS.lock(),
+            (context_lock.as_ref() as *const _ as usize, self),
+        )?;
+
+        let mut context = context_lock.write();
+        if let Some(cpu) = cpu {
+            context.sched_affinity = LogicalCpuSet::empty();
+            context.sched_affinity.atomic_set(cpu);
+        }
+        context.status = context::Status::Runnable;
+        context.name = name;
+        Ok(())
+    }
+}
+
+/// The queue run by each worker thread, by the address of its context.
+static WORKERS: Mutex<Vec<(usize, &'static WorkQueue)>> = Mutex::new(Vec::new());
+
+extern "C" fn worker_main() {
+    let current = context::current();
+    let id = current.as_ref() as *const _ as usize;
+    drop(current);
+    let queue = WORKERS
+        .lock()
+        .iter()
+        .find(|(worker, _)| *worker == id)
+        .map(|&(_, queue)| queue)
+        .expect("worker spawned without a queue");
+
+    loop {
+        queue.run_pending();
+        let list = queue.list.lock();
+        if list.head.is_null() {
+            queue.condition.wait(list, "workqueue");
+        }
+    }
+}
+
+/// Queue `work` on the queue of this CPU, returning false if it was pending already.
+pub fn schedule_work(work: &'static Work) -> bool {
+    PercpuBlock::current().workqueue.schedule(work)
+}
+
+/// Queue `work` on the queue of this CPU once `delay` nanoseconds have passed, returning false if
+/// it was pending already.
+pub fn schedule_delayed_work(work: &'static Work, delay: u128) -> bool {
+    PercpuBlock::current()
+        .workqueue
+        .schedule_delayed(work, delay)
+}
+
+/// Called by the timer of delayed work.
+pub fn fire(queue: &'static WorkQueue, work: &'static Work) {
+    queue.queue(work);
+}
+
+/// Spawn the `[kworker/N]` thread of this CPU. Work queued before is run once it starts.
+pub fn spawn_worker() {
+    let percpu = PercpuBlock::current();
+    let cpu = percpu.cpu_id;
+    if let Err(err) = percpu
+        .workqueue
+        .spawn(format!("[kworker/{}]", cpu.get()).into(), Some(cpu))
+    {
+        log::warn!("failed to spawn the worker of CPU {}: {:?}", cpu, err);
+    }
+}